@@ -1,20 +1,55 @@
 use std::fmt;
+use std::process::Stdio;
 
 use async_trait::async_trait;
 use nixrs::path_info::ValidPathInfo;
-use nixrs::store::legacy_worker::{LegacyStore, LegacyStoreBuilder, LegacyStoreClient};
-use nixrs::store::{copy_paths, BuildMode};
+use nixrs::store::daemon::{DaemonStore, DaemonStoreClient, QueryMissingResult, TrustedFlag};
 use nixrs::store::{
-    BasicDerivation, BuildResult, CheckSignaturesFlag, DerivedPath, Error, RepairFlag, Store,
-    SubstituteFlag,
+    compute_fs_closure_slow, BasicDerivation, BuildMode, BuildResult, CheckSignaturesFlag,
+    DerivedPath, Error, RepairFlag, Store,
 };
-use nixrs::store_path::{StoreDir, StoreDirProvider, StorePath, StorePathSet};
-use tokio::process::{ChildStdin, ChildStdout};
+use nixrs::store_path::{StoreDir, StoreDirProvider, StorePath};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
 
+use crate::layers::LayerCache;
+
+/// A `nix-daemon --stdio` process and a client talking to it.
+#[derive(Debug)]
+pub struct DaemonProcess {
+    child: Child,
+    pub client: DaemonStoreClient<ChildStdout, ChildStdin>,
+}
+
+impl DaemonProcess {
+    pub async fn spawn(mut command: Command, host: &str) -> Result<DaemonProcess, Error> {
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit());
+        let mut child = command.spawn()?;
+        let reader = child.stdout.take().unwrap();
+        let writer = child.stdin.take().unwrap();
+        let client = DaemonStoreClient::builder()
+            .host(host)
+            .connect(reader, writer)
+            .await?;
+        Ok(DaemonProcess { child, client })
+    }
+
+    /// Closes the connection and waits for the daemon to exit.
+    pub async fn close(mut self) -> Result<(), Error> {
+        self.client.close().await?;
+        drop(self.client);
+        self.child.wait().await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
 pub struct CachedStore {
-    cache: LegacyStoreClient<ChildStdout, ChildStdin>,
-    builder: Option<LegacyStoreClient<ChildStdout, ChildStdin>>,
-    write_allowed: bool,
+    cache: DaemonProcess,
+    builder: Option<DaemonProcess>,
+    layers: LayerCache,
     docker_bin: String,
 }
 
@@ -22,20 +57,15 @@ impl CachedStore {
     pub async fn connect(
         store_uri: String,
         docker_bin: String,
-        nix_store_bin: String,
-        write_allowed: bool,
+        nix_daemon_bin: String,
     ) -> Result<CachedStore, Error> {
-        let mut b = LegacyStoreBuilder::new(nix_store_bin);
-        b.command_mut().env("NIX_REMOTE", store_uri).arg("--serve");
-        b.host("cache");
-        if write_allowed {
-            b.command_mut().arg("--write");
-        }
-        let cache = b.connect().await?;
+        let mut command = Command::new(nix_daemon_bin);
+        command.env("NIX_REMOTE", store_uri).arg("--stdio");
+        let cache = DaemonProcess::spawn(command, "cache").await?;
         Ok(CachedStore {
             builder: None,
             cache,
-            write_allowed,
+            layers: LayerCache::new(docker_bin.clone()),
             docker_bin,
         })
     }
@@ -43,7 +73,7 @@ impl CachedStore {
 
 impl StoreDirProvider for CachedStore {
     fn store_dir(&self) -> StoreDir {
-        self.cache.store_dir()
+        self.cache.client.store_dir()
     }
 }
 
@@ -51,29 +81,24 @@ impl StoreDirProvider for CachedStore {
 impl Store for CachedStore {
     async fn query_path_info(&mut self, path: &StorePath) -> Result<Option<ValidPathInfo>, Error> {
         if let Some(builder) = self.builder.as_mut() {
-            match builder.query_path_info(path).await {
-                Ok(Some(ret)) => Ok(Some(ret)),
-                Ok(None) => self.cache.query_path_info(path).await,
-                Err(err) => Err(err),
+            if let Some(info) = builder.client.query_path_info(path).await? {
+                return Ok(Some(info));
             }
-        } else {
-            self.cache.query_path_info(path).await
         }
+        self.cache.client.query_path_info(path).await
     }
+
     async fn nar_from_path<W: tokio::io::AsyncWrite + fmt::Debug + Send + Unpin>(
         &mut self,
         path: &StorePath,
         sink: W,
     ) -> Result<(), Error> {
         if let Some(builder) = self.builder.as_mut() {
-            if builder.query_path_info(path).await?.is_some() {
-                builder.nar_from_path(path, sink).await
-            } else {
-                self.cache.nar_from_path(path, sink).await
+            if builder.client.is_valid_path(path).await? {
+                return builder.client.nar_from_path(path, sink).await;
             }
-        } else {
-            self.cache.nar_from_path(path, sink).await
         }
+        self.cache.client.nar_from_path(path, sink).await
     }
 
     async fn add_to_store<R: tokio::io::AsyncRead + fmt::Debug + Send + Unpin>(
@@ -84,67 +109,43 @@ impl Store for CachedStore {
         check_sigs: CheckSignaturesFlag,
     ) -> Result<(), Error> {
         self.cache
+            .client
             .add_to_store(info, source, repair, check_sigs)
             .await
     }
 
-    async fn build_paths(
-        &mut self,
-        _drv_paths: &[DerivedPath],
-        _build_mode: BuildMode,
-    ) -> Result<(), Error> {
-        Err(Error::Misc("Unsupported operation 'build_paths'".into()))
-    }
-
     async fn build_derivation(
         &mut self,
         drv_path: &StorePath,
         drv: &BasicDerivation,
         build_mode: BuildMode,
     ) -> Result<BuildResult, Error> {
-        //let store_dir = self.store_dir();
-        let inputs = self.cache.query_closure(&drv.input_srcs, false).await?;
-        let mut b = LegacyStoreBuilder::new(&self.docker_bin);
-        b.command_mut().args([
+        let inputs =
+            compute_fs_closure_slow(&mut self.cache.client, &drv.input_srcs, false).await?;
+        // The inputs are baked into an image that is reused by every build
+        // with the same input closure.
+        let image = self.layers.ensure(&mut self.cache.client, &inputs).await?;
+        let mut command = Command::new(&self.docker_bin);
+        command.args([
             "run",
             "-i",
+            "--rm",
             "--network",
             "none",
-            "--tmpfs",
-            "/nix/store:exec",
+            &image,
+            "nix-daemon",
+            "--stdio",
         ]);
-        for input in inputs.iter() {
-            b.command_mut()
-                .arg("-v")
-                .arg(&format!("/nix/store/{}:/nix/store/{}", input, input));
-        }
-        b.command_mut()
-            .args(["griff/nix-static", "nix-store", "--serve"]);
-        b.host("builder");
-        if self.write_allowed {
-            b.command_mut().arg("--write");
-        }
-
-        let mut builder = b.connect().await?;
-
-        copy_paths(&mut self.cache, &mut builder, &inputs).await?;
-        let result = builder.build_derivation(drv_path, drv, build_mode).await?;
+        let mut builder = DaemonProcess::spawn(command, "builder").await?;
+        let result = builder
+            .client
+            .build_derivation(drv_path, drv, build_mode)
+            .await?;
 
         if result.success() {
-            /*
-            let mut missing_paths = StorePathSet::new();
-            let output_paths = drv.outputs_and_opt_paths(&store_dir)?;
-            for (_output_name, (_, store_path)) in output_paths {
-                let path = store_path.expect("We should have just built the path");
-                if !self.cache.is_valid_path(&path).await? {
-                    missing_paths.insert(path);
-                }
+            if let Some(old) = self.builder.replace(builder) {
+                old.close().await?;
             }
-            if !missing_paths.is_empty() {
-                copy_paths(&mut builder, &mut self.cache, &missing_paths).await?;
-            }
-             */
-            self.builder = Some(builder);
         }
 
         Ok(result)
@@ -152,64 +153,40 @@ impl Store for CachedStore {
 }
 
 #[async_trait]
-impl LegacyStore for CachedStore {
-    /*
-    async fn query_path_infos(&mut self, paths: &StorePathSet) -> Result<BTreeSet<ValidPathInfo>, Error> {
-        if let Some(builder) = self.builder.as_mut() {
-            match builder.query_path_info(path).await {
-                Ok(ret) => Ok(ret),
-                Err(Error::InvalidPath(_)) => self.cache.query_path_info(path).await,
-                Err(err) => Err(err),
-            }
-        } else {
-            self.cache.query_path_info(path).await
-        }
+impl DaemonStore for CachedStore {
+    fn is_trusted_client(&self) -> Option<TrustedFlag> {
+        self.cache.client.is_trusted_client()
     }
-     */
 
-    async fn query_valid_paths_locked(
-        &mut self,
-        paths: &StorePathSet,
-        lock: bool,
-        maybe_substitute: SubstituteFlag,
-    ) -> Result<StorePathSet, Error> {
-        if let Some(builder) = self.builder.as_mut() {
-            let mut ret = builder
-                .query_valid_paths_locked(paths, lock, maybe_substitute)
-                .await?;
-            let mut local = self
-                .cache
-                .query_valid_paths_locked(paths, lock, maybe_substitute)
-                .await?;
-            ret.append(&mut local);
-            Ok(ret)
-        } else {
-            self.cache
-                .query_valid_paths_locked(paths, lock, maybe_substitute)
-                .await
-        }
+    async fn set_options(&mut self) -> Result<(), Error> {
+        self.cache.client.set_options().await
     }
 
-    async fn export_paths<W: tokio::io::AsyncWrite + fmt::Debug + Send + Unpin>(
-        &mut self,
-        paths: &StorePathSet,
-        sink: W,
-    ) -> Result<(), Error> {
-        self.cache.export_paths(paths, sink).await
+    async fn is_valid_path(&mut self, path: &StorePath) -> Result<bool, Error> {
+        if let Some(builder) = self.builder.as_mut() {
+            if builder.client.is_valid_path(path).await? {
+                return Ok(true);
+            }
+        }
+        self.cache.client.is_valid_path(path).await
     }
 
-    async fn import_paths<R: tokio::io::AsyncRead + fmt::Debug + Send + Unpin>(
+    async fn add_multiple_to_store<R: tokio::io::AsyncRead + fmt::Debug + Send + Unpin>(
         &mut self,
         source: R,
+        repair: RepairFlag,
+        check_sigs: CheckSignaturesFlag,
     ) -> Result<(), Error> {
-        self.cache.import_paths(source).await
+        self.cache
+            .client
+            .add_multiple_to_store(source, repair, check_sigs)
+            .await
     }
 
-    async fn query_closure(
+    async fn query_missing(
         &mut self,
-        paths: &StorePathSet,
-        include_outputs: bool,
-    ) -> Result<StorePathSet, Error> {
-        self.cache.query_closure(paths, include_outputs).await
+        targets: &[DerivedPath],
+    ) -> Result<QueryMissingResult, Error> {
+        self.cache.client.query_missing(targets).await
     }
 }
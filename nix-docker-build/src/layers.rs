@@ -0,0 +1,107 @@
+use std::process::Stdio;
+
+use nixrs::hash::{Algorithm, Context};
+use nixrs::store::{copy_paths, Error, Store};
+use nixrs::store_path::{StoreDir, StorePathSet};
+use tokio::process::Command;
+
+use crate::cached_store::DaemonProcess;
+
+const BASE_IMAGE: &str = "griff/nix-static";
+const REPOSITORY: &str = "nix-docker-build-inputs";
+
+/// Docker images with the input closure of builds in their store.
+///
+/// Images are tagged with a hash of the closure so a build whose inputs
+/// were seen before starts from the existing image instead of copying the
+/// inputs again.
+#[derive(Debug, Clone)]
+pub struct LayerCache {
+    docker_bin: String,
+    base_image: String,
+    repository: String,
+}
+
+impl LayerCache {
+    pub fn new(docker_bin: String) -> LayerCache {
+        LayerCache {
+            docker_bin,
+            base_image: BASE_IMAGE.into(),
+            repository: REPOSITORY.into(),
+        }
+    }
+
+    /// Tag of the image holding `closure`. Only depends on the paths in
+    /// the closure, which are content addressed themselves.
+    pub fn layer_tag(&self, store_dir: &StoreDir, closure: &StorePathSet) -> String {
+        let mut ctx = Context::new(Algorithm::SHA256);
+        for path in closure {
+            ctx.update(store_dir.print_path(path));
+            ctx.update(b"\n");
+        }
+        format!("{}:{}", self.repository, ctx.finish().encode_base32())
+    }
+
+    /// Makes sure there is an image with `closure` from `store` and
+    /// returns its tag.
+    pub async fn ensure<S>(&self, store: &mut S, closure: &StorePathSet) -> Result<String, Error>
+    where
+        S: Store,
+    {
+        let tag = self.layer_tag(&store.store_dir(), closure);
+        if self.image_exists(&tag).await? {
+            return Ok(tag);
+        }
+
+        let container = format!("{}-{}", self.repository, std::process::id());
+        let mut command = Command::new(&self.docker_bin);
+        command.args([
+            "run",
+            "-i",
+            "--network",
+            "none",
+            "--name",
+            &container,
+            &self.base_image,
+            "nix-daemon",
+            "--stdio",
+        ]);
+        let mut daemon = DaemonProcess::spawn(command, "layer").await?;
+        let copied = copy_paths(store, &mut daemon.client, closure).await;
+        daemon.close().await?;
+        if copied.is_ok() {
+            self.docker(&["commit", &container, &tag]).await?;
+        }
+        self.docker(&["rm", &container]).await?;
+        copied?;
+        Ok(tag)
+    }
+
+    async fn image_exists(&self, tag: &str) -> Result<bool, Error> {
+        let status = Command::new(&self.docker_bin)
+            .args(["image", "inspect", tag])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .await?;
+        Ok(status.success())
+    }
+
+    async fn docker(&self, args: &[&str]) -> Result<(), Error> {
+        let status = Command::new(&self.docker_bin)
+            .args(args)
+            .stdout(Stdio::null())
+            .status()
+            .await?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(Error::Misc(format!(
+                "'{} {}' failed with {}",
+                self.docker_bin,
+                args.join(" "),
+                status
+            )))
+        }
+    }
+}
@@ -2,7 +2,8 @@ use std::env;
 use std::fs::OpenOptions;
 use std::process::exit;
 
-//use nixrs_store::store::LegacyLocalStore;
+use nixrs::store::daemon::{DaemonServerBuilder, TrustedFlag};
+use nixrs::store::settings::BuildSettings;
 use simplelog as slog;
 use simplelog::LevelFilter;
 use simplelog::TermLogger;
@@ -10,6 +11,7 @@ use simplelog::WriteLogger;
 use slog::CombinedLogger;
 
 mod cached_store;
+mod layers;
 use cached_store::CachedStore;
 
 pub fn main() {
@@ -38,9 +40,9 @@ pub fn main() {
     let docker_bin = args
         .next()
         .expect("second argument should be docker binary");
-    let nix_store_bin = args
+    let nix_daemon_bin = args
         .next()
-        .expect("third argument should be nix-store binary");
+        .expect("third argument should be nix-daemon binary");
     let mut write_allowed = false;
     for argument in args {
         if argument == "--write" {
@@ -52,22 +54,23 @@ pub fn main() {
     }
     let source = tokio::io::stdin();
     let out = tokio::io::stdout();
-    let build_log = tokio::io::stderr();
+    let trusted = if write_allowed {
+        TrustedFlag::Trusted
+    } else {
+        TrustedFlag::NotTrusted
+    };
     let res = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
         .unwrap()
         .block_on(async move {
-            let store =
-                CachedStore::connect(store_uri, docker_bin, nix_store_bin, write_allowed).await?;
-            nixrs::store::legacy_worker::run_server_with_log(
-                source,
-                out,
-                store,
-                build_log,
-                write_allowed,
-            )
-            .await
+            let settings = BuildSettings::load()?;
+            let store = CachedStore::connect(store_uri, docker_bin, nix_daemon_bin).await?;
+            DaemonServerBuilder::new()
+                .trusted(trusted)
+                .settings(settings)
+                .serve(source, out, store)
+                .await
         });
 
     if let Err(e) = res {
@@ -2,7 +2,7 @@
 
 replace() {
     sed -e "s|@BASE_DIR@|$RESOLVE|g" \
-        -e "s|@NIX_STORE_CMD@|$NIX_STORE_CMD|g" \
+        -e "s|@NIX_DAEMON_CMD@|$NIX_DAEMON_CMD|g" \
         -e "s|@DOCKER@|$DOCKER|g" \
         "$DIR/$1" \
         > "$DIR/resolved/$1"
@@ -16,8 +16,8 @@ mkdir -p "$DIR/resolved"
 
 DOCKER="$(command -v docker)"
 echo "Docker: $DOCKER"
-NIX_STORE_CMD="$(command -v nix-store)"
-echo "nix-store: $NIX_STORE_CMD"
+NIX_DAEMON_CMD="$(command -v nix-daemon)"
+echo "nix-daemon: $NIX_DAEMON_CMD"
 SSHD="$(command -v sshd)"
 echo "SSHD: $SSHD"
 
@@ -0,0 +1,128 @@
+use std::path::{Path, PathBuf};
+
+use futures::TryStreamExt;
+use nixrs::store::daemon::{DaemonConnectionClient, DaemonStore, StoreUri, TrustedFlag};
+use nixrs::store::{
+    compute_fs_closure_slow, copy_paths_full, query_path_infos_json, Activity, CheckSignaturesFlag,
+    Error, RepairFlag, Store, SubstituteFlag, Verifier,
+};
+use nixrs::store_path::{StoreDir, StoreDirProvider, StorePath, StorePathSet};
+use tokio::io::AsyncWriteExt;
+use tracing::info;
+
+/// Turns arguments into store paths. Paths outside the store, like a
+/// `result` link, are followed first.
+fn parse_paths(store_dir: &StoreDir, paths: &[PathBuf]) -> Result<StorePathSet, Error> {
+    let mut ret = StorePathSet::new();
+    for path in paths {
+        ret.insert(parse_path(store_dir, path)?);
+    }
+    Ok(ret)
+}
+
+fn parse_path(store_dir: &StoreDir, path: &Path) -> Result<StorePath, Error> {
+    if !store_dir.is_in_store(path) {
+        let resolved = std::fs::canonicalize(path)?;
+        return Ok(store_dir.to_store_path(&resolved)?.0);
+    }
+    Ok(store_dir.to_store_path(path)?.0)
+}
+
+pub async fn ping(uri: &StoreUri, client: &mut DaemonConnectionClient) -> Result<(), Error> {
+    println!("Store URL: {}", uri);
+    if let Some(info) = client.handshake_info() {
+        if let Some(version) = info.nix_version.as_ref() {
+            println!("Version: {}", version);
+        }
+        println!("Protocol: {}", info.protocol());
+        match info.trusted {
+            Some(TrustedFlag::Trusted) => println!("Trusted: 1"),
+            Some(TrustedFlag::NotTrusted) => println!("Trusted: 0"),
+            None => {}
+        }
+        println!("Handshake: {:?}", info.duration);
+    }
+    Ok(())
+}
+
+pub async fn path_info(
+    client: &mut DaemonConnectionClient,
+    paths: &[PathBuf],
+    recursive: bool,
+    closure_size: bool,
+) -> Result<(), Error> {
+    let mut paths = parse_paths(&client.store_dir(), paths)?;
+    if recursive {
+        paths = compute_fs_closure_slow(client, &paths, false).await?;
+    }
+    let json = query_path_infos_json(client, &paths, closure_size).await?;
+    println!("{}", json);
+    Ok(())
+}
+
+pub async fn copy(
+    from: &mut DaemonConnectionClient,
+    to: &mut DaemonConnectionClient,
+    paths: &[PathBuf],
+    check_sigs: CheckSignaturesFlag,
+    repair: RepairFlag,
+) -> Result<(), Error> {
+    let store_dir = from.store_dir();
+    let paths = parse_paths(&store_dir, paths)?;
+    let closure = compute_fs_closure_slow(from, &paths, false).await?;
+    info!("copying {} paths", closure.len());
+    copy_paths_full(
+        from,
+        to,
+        &closure,
+        repair,
+        check_sigs,
+        SubstituteFlag::NoSubstitute,
+    )
+    .await
+}
+
+pub async fn cat_nar(client: &mut DaemonConnectionClient, path: &Path) -> Result<(), Error> {
+    let path = parse_path(&client.store_dir(), path)?;
+    let mut out = tokio::io::stdout();
+    client.nar_from_path(&path, &mut out).await?;
+    out.flush().await?;
+    Ok(())
+}
+
+/// Checks `paths` here, or the whole store on the daemon's side when there
+/// are none.
+pub async fn verify(
+    client: &mut DaemonConnectionClient,
+    paths: &[PathBuf],
+    check_contents: bool,
+    repair: RepairFlag,
+) -> Result<(), Error> {
+    if paths.is_empty() {
+        let errors = client.verify_store(check_contents, repair).await?;
+        if errors {
+            return Err(Error::Custom(1, "the store has errors".into()));
+        }
+        return Ok(());
+    }
+
+    let store_dir = client.store_dir();
+    let paths = parse_paths(&store_dir, paths)?;
+    let act = Activity::disabled();
+    let findings = Verifier::new(paths)
+        .check_contents(check_contents)
+        .run(client, &act);
+    futures::pin_mut!(findings);
+    let mut failed = 0;
+    while let Some(finding) = findings.try_next().await? {
+        eprintln!("{}", finding.describe(&store_dir));
+        failed += 1;
+    }
+    if failed > 0 {
+        return Err(Error::Custom(
+            1,
+            format!("{} problems found while verifying", failed),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,155 @@
+use std::path::PathBuf;
+use std::process::exit;
+
+use clap::{Parser, Subcommand};
+use nixrs::store::daemon::StoreUri;
+use nixrs::store::{CheckSignaturesFlag, Error, RepairFlag};
+use nixrs::store_path::StoreDir;
+use tracing::Level;
+
+mod commands;
+
+/// Work with Nix stores over the daemon protocol.
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// Store to use: `daemon`, `unix://<socket>` or `ssh-ng://[user@]host`,
+    /// optionally with settings like `?compress=true`.
+    #[arg(long, env = "NIX_REMOTE", default_value = "daemon", global = true)]
+    store: String,
+
+    #[arg(
+        long,
+        env = "NIX_STORE_DIR",
+        default_value = "/nix/store",
+        global = true
+    )]
+    store_dir: PathBuf,
+
+    #[arg(long, global = true)]
+    log_level: Option<Level>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Connect to the store and show what the daemon said in the handshake.
+    Ping,
+    /// Show the path info of store paths as JSON.
+    PathInfo {
+        /// Include the closure of the paths.
+        #[arg(long, short)]
+        recursive: bool,
+        /// Also show the total NAR size of the closure of each path.
+        #[arg(long, short = 'S')]
+        closure_size: bool,
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// Copy store paths and their closure from one store to another.
+    Copy {
+        /// Store to copy from. Defaults to `--store`.
+        #[arg(long)]
+        from: Option<String>,
+        /// Store to copy to. Defaults to `--store`.
+        #[arg(long)]
+        to: Option<String>,
+        /// Don't require the paths to be signed by a trusted key.
+        #[arg(long)]
+        no_check_sigs: bool,
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// Write the NAR serialisation of a store path to stdout.
+    CatNar { path: PathBuf },
+    /// Check store paths for missing references and, optionally, damaged
+    /// contents. Without paths the daemon verifies its whole store.
+    Verify {
+        #[arg(long)]
+        check_contents: bool,
+        /// Ask the daemon to repair what it finds broken.
+        #[arg(long)]
+        repair: bool,
+        paths: Vec<PathBuf>,
+    },
+}
+
+async fn run(cli: Cli) -> Result<(), Error> {
+    let store_dir = StoreDir::new(&cli.store_dir)?;
+    let open = |uri: &str| {
+        let store_dir = store_dir.clone();
+        let uri = uri.parse::<StoreUri>();
+        async move { uri?.connect(store_dir).await }
+    };
+    match cli.command {
+        Command::Ping => {
+            let uri: StoreUri = cli.store.parse()?;
+            let mut conn = uri.connect(store_dir.clone()).await?;
+            commands::ping(&uri, &mut conn.client).await?;
+            conn.close().await
+        }
+        Command::PathInfo {
+            recursive,
+            closure_size,
+            paths,
+        } => {
+            let mut conn = open(&cli.store).await?;
+            commands::path_info(&mut conn.client, &paths, recursive, closure_size).await?;
+            conn.close().await
+        }
+        Command::Copy {
+            from,
+            to,
+            no_check_sigs,
+            paths,
+        } => {
+            let mut from = open(from.as_deref().unwrap_or(&cli.store)).await?;
+            let mut to = open(to.as_deref().unwrap_or(&cli.store)).await?;
+            let check_sigs = CheckSignaturesFlag::from(!no_check_sigs);
+            commands::copy(
+                &mut from.client,
+                &mut to.client,
+                &paths,
+                check_sigs,
+                RepairFlag::NoRepair,
+            )
+            .await?;
+            from.close().await?;
+            to.close().await
+        }
+        Command::CatNar { path } => {
+            let mut conn = open(&cli.store).await?;
+            commands::cat_nar(&mut conn.client, &path).await?;
+            conn.close().await
+        }
+        Command::Verify {
+            check_contents,
+            repair,
+            paths,
+        } => {
+            let mut conn = open(&cli.store).await?;
+            commands::verify(&mut conn.client, &paths, check_contents, repair.into()).await?;
+            conn.close().await
+        }
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_max_level(cli.log_level.unwrap_or(Level::WARN))
+        .init();
+
+    let res = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(run(cli));
+    if let Err(err) = res {
+        eprintln!("error: {}", err);
+        exit(err.exit_code() as i32);
+    }
+}
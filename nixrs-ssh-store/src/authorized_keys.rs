@@ -0,0 +1,169 @@
+use thrussh_keys::key::PublicKey;
+use thrussh_keys::parse_public_key_base64;
+use tracing::warn;
+
+/// Options of an authorized key as `name` and optional `value` pairs.
+pub type KeyOptions = Vec<(String, Option<String>)>;
+
+/// A single entry of an OpenSSH `authorized_keys` file.
+///
+/// Each line has the form `[options] keytype base64-key [comment]` where
+/// options are a comma separated list of `name` or `name="value"`.
+#[derive(Debug)]
+pub struct AuthorizedKey {
+    pub options: KeyOptions,
+    pub key_type: String,
+    pub key: PublicKey,
+    pub comment: String,
+}
+
+impl AuthorizedKey {
+    /// Parse a single line. Returns `None` for blank lines and comments.
+    pub fn parse(line: &str) -> Result<Option<AuthorizedKey>, thrussh_keys::Error> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+        let (options, rest) = if is_key_type(first_word(line)) {
+            (Vec::new(), line)
+        } else {
+            split_options(line)?
+        };
+        let mut split = rest.splitn(3, |c: char| c.is_ascii_whitespace());
+        let key_type = split.next().unwrap_or_default();
+        let key = split.next().ok_or(thrussh_keys::Error::CouldNotReadKey)?;
+        let comment = split.next().unwrap_or_default().trim();
+        Ok(Some(AuthorizedKey {
+            options,
+            key_type: key_type.to_owned(),
+            key: parse_public_key_base64(key)?,
+            comment: comment.to_owned(),
+        }))
+    }
+
+    /// Whether the option `name` was given, with or without a value.
+    pub fn has_option(&self, name: &str) -> bool {
+        self.options
+            .iter()
+            .any(|(n, _)| n.eq_ignore_ascii_case(name))
+    }
+
+    /// The value of the last `name="value"` option.
+    pub fn option_value(&self, name: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .rev()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .and_then(|(_, v)| v.as_deref())
+    }
+}
+
+/// Parse the contents of an `authorized_keys` file.
+///
+/// Like `sshd`, lines that can't be parsed are skipped with a warning
+/// instead of failing the whole file.
+pub fn parse_authorized_keys(s: &str) -> Vec<AuthorizedKey> {
+    let mut keys = Vec::new();
+    for (idx, line) in s.lines().enumerate() {
+        match AuthorizedKey::parse(line) {
+            Ok(Some(key)) => keys.push(key),
+            Ok(None) => {}
+            Err(err) => warn!("skipping authorized_keys line {}: {}", idx + 1, err),
+        }
+    }
+    keys
+}
+
+fn first_word(s: &str) -> &str {
+    s.split(|c: char| c.is_ascii_whitespace())
+        .next()
+        .unwrap_or_default()
+}
+
+fn is_key_type(s: &str) -> bool {
+    s.starts_with("ssh-")
+        || s.starts_with("ecdsa-sha2-")
+        || s.starts_with("sk-ssh-")
+        || s.starts_with("sk-ecdsa-sha2-")
+}
+
+/// Split the leading options from a line, honouring double quoted values
+/// which may contain commas, whitespace and `\"`.
+fn split_options(line: &str) -> Result<(KeyOptions, &str), thrussh_keys::Error> {
+    let mut options = Vec::new();
+    let mut name = String::new();
+    let mut value: Option<String> = None;
+    let mut in_quotes = false;
+    let mut chars = line.char_indices();
+    while let Some((idx, c)) = chars.next() {
+        match (in_quotes, c) {
+            (true, '\\') => {
+                if let Some((_, next)) = chars.next() {
+                    if next != '"' {
+                        value.get_or_insert_with(String::new).push('\\');
+                    }
+                    value.get_or_insert_with(String::new).push(next);
+                }
+            }
+            (true, '"') => in_quotes = false,
+            (true, c) => value.get_or_insert_with(String::new).push(c),
+            (false, '"') if value.is_some() => in_quotes = true,
+            (false, '=') if value.is_none() => value = Some(String::new()),
+            (false, ',') => options.push((std::mem::take(&mut name), value.take())),
+            (false, c) if c.is_ascii_whitespace() => {
+                options.push((std::mem::take(&mut name), value.take()));
+                return Ok((options, line[idx..].trim_start()));
+            }
+            (false, c) => match value.as_mut() {
+                Some(value) => value.push(c),
+                None => name.push(c),
+            },
+        }
+    }
+    Err(thrussh_keys::Error::CouldNotReadKey)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIJdD7y3aLq454yWBdwLWbieU1ebz9/cu7/QEXn9OIeZJ";
+
+    #[test]
+    fn test_parse_plain_key() {
+        let key = AuthorizedKey::parse(&format!("ssh-ed25519 {} alice@example", KEY))
+            .unwrap()
+            .unwrap();
+        assert_eq!(key.key_type, "ssh-ed25519");
+        assert_eq!(key.comment, "alice@example");
+        assert!(key.options.is_empty());
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let line = format!(
+            "restrict,command=\"nix-store --serve, \\\"quoted\\\"\",from=\"10.0.0.1\" ssh-ed25519 {}",
+            KEY
+        );
+        let key = AuthorizedKey::parse(&line).unwrap().unwrap();
+        assert!(key.has_option("restrict"));
+        assert_eq!(
+            key.option_value("command"),
+            Some("nix-store --serve, \"quoted\"")
+        );
+        assert_eq!(key.option_value("from"), Some("10.0.0.1"));
+        assert_eq!(key.comment, "");
+    }
+
+    #[test]
+    fn test_parse_authorized_keys() {
+        let s = format!(
+            "# builders\n\nssh-ed25519 {0} one\nnot a key\nrestrict ssh-ed25519 {0} two\n",
+            KEY
+        );
+        let keys = parse_authorized_keys(&s);
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].comment, "one");
+        assert!(keys[1].has_option("restrict"));
+    }
+}
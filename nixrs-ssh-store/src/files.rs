@@ -0,0 +1,196 @@
+//! The `nixrs-files` SSH subsystem, which hands out NARs and build logs by
+//! store path over a channel of a connection that may also be running a
+//! daemon session, so fetching them doesn't need a session of its own.
+//!
+//! Each request is an op followed by a printed store path. The reply starts
+//! with a status: the NAR or log follows [`FOUND`], [`NOT_FOUND`] has
+//! nothing after it and [`FAILED`] has an error message. NARs are sent as
+//! they are, since they are self-delimiting, and logs as a string.
+
+use std::fmt;
+
+use nixrs::archive::copy_nar;
+use nixrs::io::{AsyncSink, AsyncSource};
+use nixrs::store::{Error, LogStore, Store};
+use nixrs::store_path::{StoreDir, StorePath};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::debug;
+
+/// Name of the subsystem clients ask for.
+pub const FILES_SUBSYSTEM: &str = "nixrs-files";
+
+/// Ask for the NAR of a path.
+pub const OP_NAR: u64 = 1;
+/// Ask for the build log of a derivation.
+pub const OP_BUILD_LOG: u64 = 2;
+
+pub const NOT_FOUND: u64 = 0;
+pub const FOUND: u64 = 1;
+pub const FAILED: u64 = 2;
+
+/// Answer requests read from `source` with `store` until the client closes
+/// the channel.
+///
+/// A store error is sent back and doesn't end the channel, unless it happens
+/// while a NAR is being sent and the reply can't be finished.
+pub async fn serve_files<S, R, W>(mut source: R, mut out: W, mut store: S) -> Result<(), Error>
+where
+    S: Store + LogStore + Send,
+    R: AsyncRead + fmt::Debug + Send + Unpin,
+    W: AsyncWrite + fmt::Debug + Send + Unpin,
+{
+    let store_dir = store.store_dir();
+    loop {
+        let op = match source.read_u64_le().await {
+            Ok(op) => op,
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        let path: StorePath = source.read_parsed(&store_dir).await?;
+        debug!(op, "Serving file for {}", store_dir.print_path(&path));
+        match op {
+            OP_NAR => match store.query_path_info(&path).await {
+                Ok(Some(_)) => {
+                    out.write_u64_le(FOUND).await?;
+                    store.nar_from_path(&path, &mut out).await?;
+                }
+                Ok(None) => out.write_u64_le(NOT_FOUND).await?,
+                Err(err) => {
+                    out.write_u64_le(FAILED).await?;
+                    out.write_string(err.to_string()).await?;
+                }
+            },
+            OP_BUILD_LOG => match store.get_build_log(&path).await {
+                Ok(Some(log)) => {
+                    out.write_u64_le(FOUND).await?;
+                    out.write_string(log).await?;
+                }
+                Ok(None) => out.write_u64_le(NOT_FOUND).await?,
+                Err(err) => {
+                    out.write_u64_le(FAILED).await?;
+                    out.write_string(err.to_string()).await?;
+                }
+            },
+            _ => {
+                out.write_u64_le(FAILED).await?;
+                out.write_string(format!("unknown file op {}", op)).await?;
+            }
+        }
+        out.flush().await?;
+    }
+}
+
+/// The client side of a [`FILES_SUBSYSTEM`] channel.
+#[derive(Debug)]
+pub struct FileClient<R, W> {
+    store_dir: StoreDir,
+    source: R,
+    out: W,
+}
+
+impl<R, W> FileClient<R, W>
+where
+    R: AsyncRead + fmt::Debug + Send + Unpin,
+    W: AsyncWrite + fmt::Debug + Send + Unpin,
+{
+    pub fn new(store_dir: StoreDir, source: R, out: W) -> FileClient<R, W> {
+        FileClient {
+            store_dir,
+            source,
+            out,
+        }
+    }
+
+    async fn request(&mut self, op: u64, path: &StorePath) -> Result<bool, Error> {
+        self.out.write_u64_le(op).await?;
+        self.out.write_printed(&self.store_dir, path).await?;
+        self.out.flush().await?;
+        match self.source.read_u64_le().await? {
+            FOUND => Ok(true),
+            NOT_FOUND => Ok(false),
+            FAILED => Err(Error::Misc(self.source.read_string().await?)),
+            status => Err(Error::Misc(format!("unknown file status {}", status))),
+        }
+    }
+
+    /// Write the NAR of `path` to `sink`. Returns `false` when the other
+    /// end doesn't have the path.
+    pub async fn nar_from_path<SW>(&mut self, path: &StorePath, sink: SW) -> Result<bool, Error>
+    where
+        SW: AsyncWrite + Unpin,
+    {
+        if !self.request(OP_NAR, path).await? {
+            return Ok(false);
+        }
+        copy_nar(&mut self.source, sink).await?;
+        Ok(true)
+    }
+
+    pub async fn get_build_log(&mut self, drv_path: &StorePath) -> Result<Option<String>, Error> {
+        if !self.request(OP_BUILD_LOG, drv_path).await? {
+            return Ok(None);
+        }
+        Ok(Some(self.source.read_string().await?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nixrs::archive::test_data;
+    use nixrs::hash;
+    use nixrs::path_info::ValidPathInfo;
+    use nixrs::store::{CheckSignaturesFlag, MemoryStore, RepairFlag};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_serve_files() {
+        let mut store = MemoryStore::new();
+        let mut nar = bytes::BytesMut::new();
+        for event in test_data::text_file() {
+            event.encode_into(&mut nar);
+        }
+        let mut info = ValidPathInfo::new(
+            StorePath::test_from_seed("hello"),
+            hash::digest(hash::Algorithm::SHA256, &nar),
+        );
+        info.nar_size = nar.len() as u64;
+        store
+            .add_to_store(
+                &info,
+                &nar[..],
+                RepairFlag::NoRepair,
+                CheckSignaturesFlag::NoCheckSigs,
+            )
+            .await
+            .unwrap();
+        let drv_path = StorePath::test_from_seed("hello.drv");
+        store
+            .add_build_log(&drv_path, "building hello")
+            .await
+            .unwrap();
+
+        let (client, server) = tokio::io::duplex(1024);
+        let (read, write) = tokio::io::split(server);
+        let server = tokio::spawn(serve_files(read, write, store));
+        let (read, write) = tokio::io::split(client);
+        let mut client = FileClient::new(StoreDir::default(), read, write);
+
+        let mut fetched = Vec::new();
+        assert!(client
+            .nar_from_path(&info.path, &mut fetched)
+            .await
+            .unwrap());
+        assert_eq!(fetched, &nar[..]);
+        let missing = StorePath::test_from_seed("missing");
+        assert!(!client.nar_from_path(&missing, Vec::new()).await.unwrap());
+        assert_eq!(
+            client.get_build_log(&drv_path).await.unwrap().as_deref(),
+            Some("building hello")
+        );
+        assert_eq!(client.get_build_log(&missing).await.unwrap(), None);
+
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+}
@@ -5,9 +5,12 @@ use std::future::Future;
 use self::io::ExtendedDataWrite;
 use nixrs::store::daemon::DaemonStore;
 use nixrs::store::legacy_worker::LegacyStore;
+use nixrs::store::{LogStore, Store};
 
 mod error;
 
+pub mod authorized_keys;
+pub mod files;
 pub mod io;
 pub mod server;
 
@@ -20,6 +23,12 @@ pub trait StoreProvider {
     type DaemonStore: DaemonStore + fmt::Debug + Send;
     type DaemonFuture: Future<Output = Result<Option<Self::DaemonStore>, Self::Error>> + Send;
 
+    /// Store for the [`files`] subsystem, which only reads NARs and build
+    /// logs from it.
+    type FileStore: Store + LogStore + fmt::Debug + Send;
+    type FileFuture: Future<Output = Result<Option<Self::FileStore>, Self::Error>> + Send;
+
     fn get_legacy_store(&self, stderr: ExtendedDataWrite) -> Self::LegacyFuture;
     fn get_daemon_store(&self) -> Self::DaemonFuture;
+    fn get_file_store(&self) -> Self::FileFuture;
 }
@@ -6,28 +6,95 @@ use std::sync::Arc;
 
 use futures::future::Ready;
 use futures::{Future, FutureExt};
+use nixrs::store::daemon::TrustedFlag;
+use nixrs::store::{PolicyStore, StorePolicy};
 use thrussh::server::Config;
 use thrussh::{
     server::{self, Handle},
     ChannelId, ChannelOpenFailure, CryptoVec,
 };
 use thrussh_keys::key::{KeyPair, PublicKey};
-use thrussh_keys::{decode_secret_key, key, parse_public_key_base64, PublicKeyBase64};
+use thrussh_keys::{
+    decode_secret_key, encode_pkcs8_pem, key, parse_public_key_base64, PublicKeyBase64,
+};
 use tokio::fs::File;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::select;
 use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info};
 
+use crate::authorized_keys::parse_authorized_keys;
+use crate::files::{serve_files, FILES_SUBSYSTEM};
 use crate::io::{ChannelRead, DataWrite, ExtendedDataWrite};
 use crate::StoreProvider;
 
+/// What a client authenticated with a given key is allowed to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserPermissions {
+    /// Trust level passed to the daemon protocol server.
+    pub trusted: TrustedFlag,
+    /// Whether `nix-store --serve --write` is allowed.
+    pub write_allowed: bool,
+    /// Which store operations are allowed, for both protocols.
+    pub policy: StorePolicy,
+}
+
+impl UserPermissions {
+    /// Trusted with write access.
+    pub fn trusted() -> UserPermissions {
+        UserPermissions {
+            trusted: TrustedFlag::Trusted,
+            write_allowed: true,
+            policy: StorePolicy::allow_all(),
+        }
+    }
+
+    /// Untrusted and only allowed to query and fetch paths.
+    pub fn restricted() -> UserPermissions {
+        UserPermissions {
+            trusted: TrustedFlag::NotTrusted,
+            write_allowed: false,
+            policy: StorePolicy::read_only(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct UserKey {
+    user: Option<String>,
+    permissions: UserPermissions,
+    /// Run instead of whatever the client asks for, like `command=` in
+    /// `authorized_keys`.
+    forced_command: Option<String>,
+}
+
+/// The keys allowed to log in, by their base64 encoding. The same key can
+/// be allowed for several users with different permissions.
+type UserKeys = HashMap<String, Vec<UserKey>>;
+
+/// Add `user_key` for `key`, replacing any earlier entry for the same user.
+fn insert_user_key(user_keys: &mut UserKeys, key: String, user_key: UserKey) {
+    let entries = user_keys.entry(key).or_default();
+    entries.retain(|entry| entry.user != user_key.user);
+    entries.push(user_key);
+}
+
+/// The entry for `key` that lets `user` log in. Entries for that user name
+/// win over ones for any user.
+fn find_user_key<'a>(user_keys: &'a UserKeys, key: &str, user: &str) -> Option<&'a UserKey> {
+    let entries = user_keys.get(key)?;
+    entries
+        .iter()
+        .find(|entry| entry.user.as_deref() == Some(user))
+        .or_else(|| entries.iter().find(|entry| entry.user.is_none()))
+}
+
 #[derive(Debug)]
 pub struct ServerConfig<S> {
     config: Config,
-    user_keys: HashMap<String, bool>,
+    user_keys: UserKeys,
     store_provider: S,
 }
 
@@ -84,6 +151,31 @@ impl<S> ServerConfig<S> {
         }
     }
 
+    /// Load the host key at `path`, generating a new ed25519 key and
+    /// writing it there first when the file doesn't exist yet. This keeps
+    /// the host key stable across restarts without any manual setup.
+    pub async fn load_or_generate_host_key(
+        &mut self,
+        path: impl AsRef<Path>,
+    ) -> Result<(), thrussh_keys::Error> {
+        let path = path.as_ref();
+        if !tokio::fs::try_exists(path).await? {
+            let key = KeyPair::generate_ed25519().ok_or(thrussh_keys::Error::CouldNotReadKey)?;
+            let mut pem = Vec::new();
+            encode_pkcs8_pem(&key, &mut pem)?;
+            let mut f = tokio::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(path)
+                .await?;
+            f.write_all(&pem).await?;
+            f.flush().await?;
+            info!("Generated host key {}", path.display());
+        }
+        self.load_host_key(path).await
+    }
+
     pub async fn load_host_keys(&mut self, config_dir: impl AsRef<Path>) {
         let config_dir = config_dir.as_ref();
         self.load_host_key(config_dir.join("ssh_host_ed25519_key"))
@@ -95,11 +187,65 @@ impl<S> ServerConfig<S> {
     }
 
     pub fn add_user_key(&mut self, key: PublicKey, write_allowed: bool) -> &mut Self {
-        self.user_keys
-            .insert(key.public_key_base64(), write_allowed);
+        let permissions = UserPermissions {
+            write_allowed,
+            ..UserPermissions::trusted()
+        };
+        self.add_user_key_with_permissions(key, None, permissions)
+    }
+
+    /// Allow `key` to log in with the given permissions. When `user` is
+    /// given the key is only accepted for that user name.
+    pub fn add_user_key_with_permissions(
+        &mut self,
+        key: PublicKey,
+        user: Option<String>,
+        permissions: UserPermissions,
+    ) -> &mut Self {
+        let user_key = UserKey {
+            user,
+            permissions,
+            forced_command: None,
+        };
+        insert_user_key(&mut self.user_keys, key.public_key_base64(), user_key);
         self
     }
 
+    /// Allow all the keys in an OpenSSH `authorized_keys` file.
+    ///
+    /// Keys get `permissions` unless they have the `restrict` option, in
+    /// which case they are only allowed untrusted read access. A
+    /// `command="..."` option replaces the command the client requests, so
+    /// a key can for example be pinned to `nix-store --serve`. Returns the
+    /// number of keys that were added.
+    pub async fn load_authorized_keys(
+        &mut self,
+        path: impl AsRef<Path>,
+        user: Option<&str>,
+        permissions: UserPermissions,
+    ) -> io::Result<usize> {
+        let keys = parse_authorized_keys(&load_file(path).await?);
+        let count = keys.len();
+        for authorized in keys {
+            let permissions = if authorized.has_option("restrict") {
+                UserPermissions::restricted()
+            } else {
+                permissions
+            };
+            let user_key = UserKey {
+                user: user.map(String::from),
+                permissions,
+                forced_command: authorized.option_value("command").map(String::from),
+            };
+            insert_user_key(
+                &mut self.user_keys,
+                authorized.key.public_key_base64(),
+                user_key,
+            );
+        }
+        Ok(count)
+    }
+
     pub async fn load_user_key(
         &mut self,
         path: impl AsRef<Path>,
@@ -149,7 +295,7 @@ struct ChannelMsg {
 #[derive(Clone)]
 pub struct ServerState<S> {
     config: Arc<Config>,
-    user_keys: Arc<HashMap<String, bool>>,
+    user_keys: Arc<UserKeys>,
     //serve_tx: mpsc::UnboundedSender<ChannelMsg>,
     store_provider: S,
     shutdown: CancellationToken,
@@ -304,12 +450,17 @@ where
     S: StoreProvider,
     S::Error: 'static,
 {
-    async fn run_legacy_command(self, write_allowed: bool) -> Result<(), anyhow::Error> {
+    async fn run_legacy_command(
+        self,
+        write_allowed: bool,
+        policy: StorePolicy,
+    ) -> Result<(), anyhow::Error> {
         if let Some(store) = self
             .store_provider
             .get_legacy_store(self.stderr.clone())
             .await?
         {
+            let store = PolicyStore::new(store, policy);
             select! {
                 res = nixrs::store::legacy_worker::run_server_with_log(self.stdin, self.stdout, store, self.stderr, write_allowed) => {
                     match res {
@@ -332,13 +483,18 @@ where
         }
     }
 
-    async fn run_daemon_command(self) -> Result<(), anyhow::Error> {
+    async fn run_daemon_command(
+        self,
+        trusted: TrustedFlag,
+        policy: StorePolicy,
+    ) -> Result<(), anyhow::Error> {
         if let Some(store) = self.store_provider.get_daemon_store().await? {
+            let store = PolicyStore::new(store, policy);
             let fut = Box::pin(nixrs::store::daemon::run_server(
                 self.stdin,
                 self.stdout,
                 store,
-                nixrs::store::daemon::TrustedFlag::Trusted,
+                trusted,
             ));
             select! {
                 res = fut => {
@@ -363,6 +519,34 @@ where
     }
 }
 
+impl<S> StoreCommand<S>
+where
+    S: StoreProvider,
+    S::Error: 'static,
+{
+    async fn run_file_command(self, policy: StorePolicy) -> Result<(), anyhow::Error> {
+        if let Some(store) = self.store_provider.get_file_store().await? {
+            let store = PolicyStore::new(store, policy);
+            select! {
+                res = serve_files(self.stdin, self.stdout, store) => {
+                    if let Err(err) = res {
+                        tracing::error!("Error serving files {:?}", err);
+                        return Err(err.into());
+                    }
+                }
+                _ = self.shutdown.cancelled() => {
+                    info!("Shutting down channel {:?}!", self.channel);
+                    Err(io::Error::new(io::ErrorKind::BrokenPipe, "Shutting down"))?;
+                }
+            }
+            Ok(())
+        } else {
+            info!("unsupported subsystem {:?}!", self.channel);
+            Err(io::Error::new(io::ErrorKind::NotFound, "unsupported subsystem").into())
+        }
+    }
+}
+
 async fn send_error(err_txt: String, mut handle: Handle, channel: ChannelId) {
     error!("{}", err_txt);
     handle
@@ -380,9 +564,9 @@ pub struct ServerHandler<S> {
     shutdown: CancellationToken,
     store_provider: S,
     channels: HashMap<ChannelId, ServerChannel>,
-    user_keys: Arc<HashMap<String, bool>>,
+    user_keys: Arc<UserKeys>,
     //serve_tx: mpsc::UnboundedSender<ChannelMsg>,
-    auth_user: Option<(String, bool)>,
+    auth_user: Option<(String, UserKey)>,
 }
 
 impl<S> server::Handler for ServerHandler<S>
@@ -413,8 +597,8 @@ where
     fn auth_publickey(mut self, user: &str, public_key: &key::PublicKey) -> Self::FutureAuth {
         debug!("Auth key {} {}", user, public_key.public_key_base64());
         let key = public_key.public_key_base64();
-        if let Some(write_allowed) = self.user_keys.get(&key) {
-            self.auth_user = Some((key, *write_allowed));
+        if let Some(user_key) = find_user_key(&self.user_keys, &key, user).cloned() {
+            self.auth_user = Some((key, user_key));
             self.finished_auth(server::Auth::Accept)
         } else {
             self.finished_auth(server::Auth::Reject)
@@ -562,13 +746,25 @@ where
                     stdin: source,
                 };
 
+                // Only authenticated clients get this far, but should one
+                // not have a key it gets the least access there is.
+                let permissions = self
+                    .auth_user
+                    .as_ref()
+                    .map(|(_, user_key)| user_key.permissions)
+                    .unwrap_or_else(UserPermissions::restricted);
+                let forced_command = self
+                    .auth_user
+                    .as_ref()
+                    .and_then(|(_, user_key)| user_key.forced_command.clone());
+                let data = forced_command.as_deref().map(str::as_bytes).unwrap_or(data);
+                let policy = permissions.policy;
+
                 if data == b"nix-store --serve --write" || data == b"nix-store --serve" {
-                    let mut write_allowed = data == b"nix-store --serve --write";
-                    if let Some((_, user_write_allowed)) = self.auth_user.as_ref() {
-                        write_allowed = write_allowed && *user_write_allowed;
-                    }
+                    let write_allowed =
+                        data == b"nix-store --serve --write" && permissions.write_allowed;
                     let join = tokio::task::spawn(async move {
-                        match cmd.run_legacy_command(write_allowed).await {
+                        match cmd.run_legacy_command(write_allowed, policy).await {
                             Ok(_) => Ok(()),
                             Err(err) => {
                                 let err_txt = format!("Exec failed {:?}", err);
@@ -579,8 +775,9 @@ where
                     });
                     ch.serve = Some(join);
                 } else if data == b"nix-daemon --stdio" {
+                    let trusted = permissions.trusted;
                     let join = tokio::task::spawn(async move {
-                        match cmd.run_daemon_command().await {
+                        match cmd.run_daemon_command(trusted, policy).await {
                             Ok(_) => Ok(()),
                             Err(err) => {
                                 let err_txt = format!("Exec failed {:?}", err);
@@ -667,12 +864,47 @@ where
     }
 
     fn subsystem_request(
-        self,
+        mut self,
         channel: ChannelId,
-        _name: &str,
+        name: &str,
         mut session: server::Session,
     ) -> Self::FutureUnit {
-        session.channel_failure(channel);
+        // Keys pinned to a command may only run that command.
+        let policy = match self.auth_user.as_ref() {
+            Some((_, user_key)) if user_key.forced_command.is_none() => user_key.permissions.policy,
+            _ => {
+                session.channel_failure(channel);
+                return self.finished(session);
+            }
+        };
+        if name != FILES_SUBSYSTEM {
+            session.channel_failure(channel);
+            return self.finished(session);
+        }
+        if let Some(ch) = self.channels.get_mut(&channel) {
+            if let Some(source) = ch.stdin.take() {
+                let handle = session.handle();
+                let cmd = StoreCommand {
+                    shutdown: self.shutdown.clone(),
+                    store_provider: self.store_provider.clone(),
+                    channel,
+                    stderr: ExtendedDataWrite::new(channel, 1, handle.clone()),
+                    stdout: DataWrite::new(channel, handle.clone()),
+                    stdin: source,
+                };
+                let join = tokio::task::spawn(async move {
+                    match cmd.run_file_command(policy).await {
+                        Ok(_) => Ok(()),
+                        Err(err) => {
+                            let err_txt = format!("Subsystem failed {:?}", err);
+                            send_error(err_txt, handle, channel).await;
+                            Err(err)
+                        }
+                    }
+                });
+                ch.serve = Some(join);
+            }
+        }
         self.finished(session)
     }
 }
@@ -682,3 +914,67 @@ struct ServerChannel {
     stdin: Option<ChannelRead>,
     serve: Option<JoinHandle<Result<(), anyhow::Error>>>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_key(user: Option<&str>, permissions: UserPermissions) -> UserKey {
+        UserKey {
+            user: user.map(String::from),
+            permissions,
+            forced_command: None,
+        }
+    }
+
+    #[test]
+    fn test_same_key_for_several_users() {
+        let mut user_keys = UserKeys::new();
+        let key = "AAAAC3NzaC1lZDI1NTE5AAAAIKey".to_string();
+        insert_user_key(
+            &mut user_keys,
+            key.clone(),
+            user_key(Some("admin"), UserPermissions::trusted()),
+        );
+        insert_user_key(
+            &mut user_keys,
+            key.clone(),
+            user_key(Some("cache"), UserPermissions::restricted()),
+        );
+
+        let admin = find_user_key(&user_keys, &key, "admin").unwrap();
+        assert_eq!(admin.permissions, UserPermissions::trusted());
+        let cache = find_user_key(&user_keys, &key, "cache").unwrap();
+        assert_eq!(cache.permissions, UserPermissions::restricted());
+        assert!(find_user_key(&user_keys, &key, "other").is_none());
+
+        insert_user_key(
+            &mut user_keys,
+            key.clone(),
+            user_key(None, UserPermissions::restricted()),
+        );
+        let other = find_user_key(&user_keys, &key, "other").unwrap();
+        assert_eq!(other.permissions, UserPermissions::restricted());
+        let admin = find_user_key(&user_keys, &key, "admin").unwrap();
+        assert_eq!(admin.permissions, UserPermissions::trusted());
+    }
+
+    #[test]
+    fn test_same_user_replaces_key() {
+        let mut user_keys = UserKeys::new();
+        let key = "AAAAC3NzaC1lZDI1NTE5AAAAIKey".to_string();
+        insert_user_key(
+            &mut user_keys,
+            key.clone(),
+            user_key(None, UserPermissions::trusted()),
+        );
+        insert_user_key(
+            &mut user_keys,
+            key.clone(),
+            user_key(None, UserPermissions::restricted()),
+        );
+        assert_eq!(user_keys[&key].len(), 1);
+        let entry = find_user_key(&user_keys, &key, "any").unwrap();
+        assert_eq!(entry.permissions, UserPermissions::restricted());
+    }
+}
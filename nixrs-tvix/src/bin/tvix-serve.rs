@@ -1,6 +1,7 @@
 use std::io;
 
 use clap::Parser;
+use nixrs::store::settings::{BuildSettings, WithSettings};
 use nixrs::store_path::StoreDir;
 use tracing::Level;
 use tracing_subscriber::prelude::*;
@@ -78,7 +79,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let out = tokio::io::stdout();
     let build_log = tokio::io::stderr();
 
+    let settings = BuildSettings::load()?;
     nixrs::store::legacy_worker::run_server_with_log(source, out, store, build_log, cli.write)
+        .with_settings(settings)
         .await?;
     Ok(())
 }
@@ -30,6 +30,8 @@ impl StoreProvider for TvixStoreProvider {
     type LegacyFuture = Ready<Result<Option<Self::LegacyStore>, Self::Error>>;
     type DaemonStore = FailStore;
     type DaemonFuture = Ready<Result<Option<Self::DaemonStore>, Self::Error>>;
+    type FileStore = FailStore;
+    type FileFuture = Ready<Result<Option<Self::FileStore>, Self::Error>>;
 
     fn get_legacy_store(
         &self,
@@ -48,6 +50,10 @@ impl StoreProvider for TvixStoreProvider {
     fn get_daemon_store(&self) -> Self::DaemonFuture {
         ready(Ok(None))
     }
+
+    fn get_file_store(&self) -> Self::FileFuture {
+        ready(Ok(None))
+    }
 }
 
 #[derive(Parser)]
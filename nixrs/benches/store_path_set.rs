@@ -0,0 +1,57 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use nixrs::store_path::{StorePath, StorePathSet, StorePathSetExt};
+
+/// A closure of `size` paths and the part of it a store already has, like
+/// the two sets copying paths starts out with.
+fn sets(size: usize) -> (StorePathSet, StorePathSet) {
+    let all: StorePathSet = (0..size)
+        .map(|i| StorePath::test_from_seed(&format!("path-{}", i)))
+        .collect();
+    let valid = all.iter().step_by(3).cloned().collect();
+    (all, valid)
+}
+
+fn difference(c: &mut Criterion) {
+    let (all, valid) = sets(100_000);
+    let mut group = c.benchmark_group("difference");
+    group.bench_function("collect", |b| {
+        b.iter(|| {
+            let missing: StorePathSet = all.difference(black_box(&valid)).cloned().collect();
+            missing
+        })
+    });
+    group.bench_function("difference_into", |b| {
+        b.iter(|| {
+            let mut missing = StorePathSet::new();
+            all.difference_into(black_box(&valid), &mut missing);
+            missing
+        })
+    });
+    group.bench_function("filter_clone", |b| {
+        b.iter_batched(
+            || all.clone(),
+            |missing| {
+                let missing: StorePathSet = missing
+                    .into_iter()
+                    .filter(|path| !valid.contains(path))
+                    .collect();
+                missing
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("retain_missing", |b| {
+        b.iter_batched(
+            || all.clone(),
+            |mut missing| {
+                missing.retain_missing(black_box(&valid));
+                missing
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, difference);
+criterion_main!(benches);
@@ -0,0 +1,40 @@
+//! Print the decoded daemon protocol traffic of a connection.
+//!
+//! ```text
+//! cargo run --example dissect -- capture.bin
+//! cargo run --example dissect -- client.bin daemon.bin
+//! ```
+//!
+//! A single file is read as a capture of direction tagged records, see
+//! `nixrs::store::daemon::dissect`. Two files are the raw bytes sent by the
+//! client and by the daemon.
+use std::process::exit;
+
+use nixrs::store::daemon::dissect::{dissect, split_capture};
+
+fn read(path: &str) -> Vec<u8> {
+    std::fs::read(path).unwrap_or_else(|err| {
+        eprintln!("could not read {}: {}", path, err);
+        exit(1)
+    })
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (client, daemon) = match args.as_slice() {
+        [capture] => split_capture(&read(capture)).unwrap_or_else(|err| {
+            eprintln!("invalid capture {}: {}", capture, err);
+            exit(1)
+        }),
+        [client, daemon] => (read(client), read(daemon)),
+        _ => {
+            eprintln!("usage: dissect <capture> | dissect <client> <daemon>");
+            exit(2)
+        }
+    };
+    let dissection = dissect(&client, &daemon);
+    print!("{}", dissection);
+    if dissection.error.is_some() {
+        exit(1);
+    }
+}
@@ -92,6 +92,7 @@ where
 
 pub struct DumpOptions<F> {
     use_case_hack: bool,
+    follow_symlinks: bool,
     filter: F,
 }
 
@@ -103,6 +104,7 @@ impl DumpOptions<All> {
         let use_case_hack = false;
         DumpOptions {
             use_case_hack,
+            follow_symlinks: false,
             filter: All,
         }
     }
@@ -124,10 +126,29 @@ impl<F> DumpOptions<F> {
         self
     }
 
+    /// Use `filter` to decide which paths are dumped, e.g. a
+    /// [`PathFilter`](super::PathFilter).
+    pub fn with_filter<G>(self, filter: G) -> DumpOptions<G> {
+        DumpOptions {
+            use_case_hack: self.use_case_hack,
+            follow_symlinks: self.follow_symlinks,
+            filter,
+        }
+    }
+
     pub fn use_case_hack(mut self, use_case_hack: bool) -> Self {
         self.use_case_hack = use_case_hack;
         self
     }
+
+    /// Dump what symlinks point to instead of the symlinks themselves.
+    ///
+    /// Symlinks that form a loop through a directory make the dump run
+    /// forever.
+    pub fn follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.follow_symlinks = follow_symlinks;
+        self
+    }
 }
 impl<F> DumpOptions<F> {
     pub fn dump<Fut, P>(self, path: P) -> impl Stream<Item = io::Result<NAREvent>>
@@ -187,7 +208,12 @@ where
                     }
                 };
                 let path = item.path;
-                let file_type = item.file_type;
+                let (file_type, metadata) = if options.follow_symlinks && item.file_type.is_symlink() {
+                    let metadata = tokio::fs::metadata(&path).await?;
+                    (metadata.file_type(), Some(metadata))
+                } else {
+                    (item.file_type, item.metadata)
+                };
                 if file_type.is_symlink() {
                     let target_p = read_link(&path).await?;
                     let target = target_p.to_str().ok_or_else(|| {
@@ -199,7 +225,7 @@ where
                     offset += event.encoded_size() as u64;
                     yield event;
                 } else if file_type.is_file() {
-                    let meta = if let Some(m) = item.metadata {
+                    let meta = if let Some(m) = metadata {
                         m
                     } else {
                         symlink_metadata(&path).await?
@@ -0,0 +1,290 @@
+use std::future::Future;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+
+use tokio::fs::{read_to_string, symlink_metadata};
+
+use super::Filter;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    include: bool,
+    dir_only: bool,
+    anchored: bool,
+    parts: Vec<String>,
+}
+
+impl Rule {
+    fn parse(pattern: &str, include: bool) -> Option<Rule> {
+        let mut pattern = pattern.trim_end();
+        if pattern.is_empty() {
+            return None;
+        }
+        let dir_only = pattern.ends_with('/');
+        pattern = pattern.trim_end_matches('/');
+        let anchored = pattern.contains('/');
+        let parts = pattern
+            .trim_start_matches('/')
+            .split('/')
+            .filter(|p| !p.is_empty())
+            .map(String::from)
+            .collect::<Vec<_>>();
+        if parts.is_empty() {
+            return None;
+        }
+        Some(Rule {
+            include,
+            dir_only,
+            anchored,
+            parts,
+        })
+    }
+
+    fn matches(&self, components: &[&str], is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            match_components(&self.parts, components)
+        } else {
+            components
+                .last()
+                .map(|name| match_glob(self.parts[0].as_bytes(), name.as_bytes()))
+                .unwrap_or(false)
+        }
+    }
+}
+
+fn match_components(parts: &[String], components: &[&str]) -> bool {
+    match parts.split_first() {
+        None => components.is_empty(),
+        Some((part, rest)) if part == "**" => {
+            (0..=components.len()).any(|skip| match_components(rest, &components[skip..]))
+        }
+        Some((part, rest)) => match components.split_first() {
+            Some((name, names)) => {
+                match_glob(part.as_bytes(), name.as_bytes()) && match_components(rest, names)
+            }
+            None => false,
+        },
+    }
+}
+
+/// Match a single path component against a glob with `*`, `?` and
+/// character classes like `[a-z]` or `[!0-9]`.
+fn match_glob(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| match_glob(rest, &name[skip..])),
+        Some((b'?', rest)) => !name.is_empty() && match_glob(rest, &name[1..]),
+        Some((b'[', rest)) => match (name.split_first(), rest.iter().position(|c| *c == b']')) {
+            (Some((c, names)), Some(end)) if end > 0 => {
+                let (negate, class) = match rest[..end].split_first() {
+                    Some((b'!' | b'^', class)) if !class.is_empty() => (true, class),
+                    _ => (false, &rest[..end]),
+                };
+                let mut found = false;
+                let mut i = 0;
+                while i < class.len() {
+                    if i + 2 < class.len() && class[i + 1] == b'-' {
+                        found |= class[i] <= *c && *c <= class[i + 2];
+                        i += 3;
+                    } else {
+                        found |= class[i] == *c;
+                        i += 1;
+                    }
+                }
+                found != negate && match_glob(&rest[end + 1..], names)
+            }
+            (Some((c, names)), _) => *c == b'[' && match_glob(rest, names),
+            (None, _) => false,
+        },
+        Some((b'\\', [escaped, rest @ ..])) => {
+            name.first() == Some(escaped) && match_glob(rest, &name[1..])
+        }
+        Some((p, rest)) => name.first() == Some(p) && match_glob(rest, &name[1..]),
+    }
+}
+
+/// A [`Filter`] for [`DumpOptions`](super::DumpOptions) built from rules in
+/// the style of `.gitignore`.
+///
+/// Patterns are matched against paths relative to the root of the dump.
+/// A pattern without a `/` matches the name of a file at any depth, other
+/// patterns match from the root and `**` matches any number of
+/// directories. A trailing `/` only matches directories. When several
+/// rules match a path the last one wins, and nothing in an excluded
+/// directory is dumped.
+#[derive(Debug, Clone)]
+pub struct PathFilter {
+    root: PathBuf,
+    rules: Vec<Rule>,
+    max_file_size: Option<u64>,
+}
+
+impl PathFilter {
+    /// A filter that includes everything below `root`.
+    pub fn new<P: Into<PathBuf>>(root: P) -> PathFilter {
+        PathFilter {
+            root: root.into(),
+            rules: Vec::new(),
+            max_file_size: None,
+        }
+    }
+
+    /// Leave out paths matching `pattern`.
+    pub fn exclude(mut self, pattern: &str) -> Self {
+        self.rules.extend(Rule::parse(pattern, false));
+        self
+    }
+
+    /// Dump paths matching `pattern` even when an earlier rule excludes
+    /// them.
+    pub fn include(mut self, pattern: &str) -> Self {
+        self.rules.extend(Rule::parse(pattern, true));
+        self
+    }
+
+    /// Add the rules of an ignore file in the format of `.gitignore`, where
+    /// `!` in front of a pattern includes it again.
+    pub fn ignore_rules(mut self, contents: &str) -> Self {
+        for line in contents.lines() {
+            if line.starts_with('#') {
+                continue;
+            }
+            self = match line.strip_prefix('!') {
+                Some(pattern) => self.include(pattern),
+                None => self.exclude(line.strip_prefix('\\').unwrap_or(line)),
+            };
+        }
+        self
+    }
+
+    /// Add the rules of the ignore file at `path`.
+    pub async fn ignore_file<P: AsRef<Path>>(self, path: P) -> io::Result<Self> {
+        let contents = read_to_string(path).await?;
+        Ok(self.ignore_rules(&contents))
+    }
+
+    /// Leave out regular files larger than `size` bytes.
+    pub fn max_file_size(mut self, size: u64) -> Self {
+        self.max_file_size = Some(size);
+        self
+    }
+
+    /// Whether `path`, relative to the root, is left out.
+    pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        let components: Vec<_> = path
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(name) => Some(name.to_string_lossy()),
+                _ => None,
+            })
+            .collect();
+        if components.is_empty() {
+            return false;
+        }
+        let components: Vec<&str> = components.iter().map(|c| c.as_ref()).collect();
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| rule.matches(&components, is_dir))
+            .map(|rule| !rule.include)
+            .unwrap_or(false)
+    }
+}
+
+impl Filter for PathFilter {
+    type Future = Pin<Box<dyn Future<Output = bool> + Send>>;
+
+    fn run(&self, path: &Path) -> Self::Future {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        let excluded_file = self.is_excluded(relative, false);
+        let excluded_dir = self.is_excluded(relative, true);
+        let max_file_size = self.max_file_size;
+        let path = path.to_owned();
+        Box::pin(async move {
+            if excluded_file == excluded_dir && max_file_size.is_none() {
+                return !excluded_file;
+            }
+            match symlink_metadata(&path).await {
+                Ok(metadata) if metadata.is_dir() => !excluded_dir,
+                Ok(metadata) => {
+                    let too_big =
+                        metadata.is_file() && max_file_size.is_some_and(|max| metadata.len() > max);
+                    !(excluded_file || too_big)
+                }
+                // Let the dump report the error.
+                Err(_) => true,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::archive::{DumpOptions, NAREvent};
+
+    #[test]
+    fn test_match_glob() {
+        assert!(match_glob(b"*.rs", b"lib.rs"));
+        assert!(!match_glob(b"*.rs", b"lib.rs.orig"));
+        assert!(match_glob(b"?.txt", b"a.txt"));
+        assert!(match_glob(b"[a-c]x", b"bx"));
+        assert!(!match_glob(b"[!a-c]x", b"bx"));
+        assert!(match_glob(b"\\*", b"*"));
+        assert!(!match_glob(b"\\*", b"a"));
+    }
+
+    #[test]
+    fn test_rules() {
+        let filter = PathFilter::new("/src")
+            .ignore_rules("# build output\ntarget/\n.git\n*.log\n!keep.log\n/docs/**/*.tmp\n");
+        assert!(filter.is_excluded(Path::new("target"), true));
+        assert!(!filter.is_excluded(Path::new("target"), false));
+        assert!(filter.is_excluded(Path::new("sub/.git"), true));
+        assert!(filter.is_excluded(Path::new("sub/build.log"), false));
+        assert!(!filter.is_excluded(Path::new("sub/keep.log"), false));
+        assert!(filter.is_excluded(Path::new("docs/a.tmp"), false));
+        assert!(filter.is_excluded(Path::new("docs/a/b/c.tmp"), false));
+        assert!(!filter.is_excluded(Path::new("other/docs/a.tmp"), false));
+        assert!(!filter.is_excluded(Path::new(""), true));
+    }
+
+    #[tokio::test]
+    async fn test_dump_with_filter() {
+        let dir = tempdir().unwrap();
+        let root = dir.path().join("src");
+        std::fs::create_dir_all(root.join(".git")).unwrap();
+        std::fs::create_dir_all(root.join("target/debug")).unwrap();
+        std::fs::write(root.join(".git/HEAD"), "ref").unwrap();
+        std::fs::write(root.join("target/debug/out"), "out").unwrap();
+        std::fs::write(root.join("big.bin"), vec![0u8; 100]).unwrap();
+        std::fs::write(root.join("lib.rs"), "fn main() {}").unwrap();
+
+        let filter = PathFilter::new(&root)
+            .exclude(".git/")
+            .exclude("/target")
+            .max_file_size(50);
+        let events = DumpOptions::new()
+            .use_case_hack(false)
+            .with_filter(filter)
+            .dump(&root)
+            .try_collect::<Vec<NAREvent>>()
+            .await
+            .unwrap();
+        let names: Vec<_> = events
+            .iter()
+            .filter_map(|e| match e {
+                NAREvent::DirectoryEntry { name } => Some(name.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(names, vec![bytes::Bytes::from_static(b"lib.rs")]);
+    }
+}
@@ -10,16 +10,22 @@ use tokio_util::codec::FramedWrite;
 mod case_hack;
 mod dump;
 mod encoder;
+mod filter;
 mod parser;
 mod restore;
+mod rewrite;
 #[cfg(any(test, feature = "test"))]
 pub mod test_data;
+mod visit;
 
 pub use case_hack::CaseHackStream;
 pub use dump::{dump, All, DumpOptions, Filter};
 pub use encoder::NAREncoder;
+pub use filter::PathFilter;
 pub use parser::parse_nar;
 pub use restore::{restore, NARRestorer};
+pub use rewrite::{RewriteStream, Rewrites};
+pub use visit::{parse_nar_visit, NarVisitor};
 
 pub const NAR_VERSION_MAGIC_1: &str = "nix-archive-1";
 pub const CASE_HACK_SUFFIX: &str = "~nix~case~hack~";
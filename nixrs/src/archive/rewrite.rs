@@ -0,0 +1,266 @@
+use std::collections::{BTreeMap, HashMap};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use bytes::{Bytes, BytesMut};
+use futures::Stream;
+use pin_project_lite::pin_project;
+
+use super::NAREvent;
+
+/// Byte string rewrites where every replacement has the same length as the
+/// string it replaces, so that the size of the NAR doesn't change.
+#[derive(Debug, Clone, Default)]
+pub struct Rewrites {
+    by_len: BTreeMap<usize, HashMap<Bytes, Bytes>>,
+    max_len: usize,
+}
+
+impl Rewrites {
+    pub fn new() -> Rewrites {
+        Default::default()
+    }
+
+    /// Add a rewrite of `from` to `to`.
+    ///
+    /// # Panics
+    ///
+    /// Panics when `from` and `to` differ in length or `from` is empty.
+    pub fn insert<F: Into<Bytes>, T: Into<Bytes>>(&mut self, from: F, to: T) {
+        let from = from.into();
+        let to = to.into();
+        assert!(!from.is_empty(), "cannot rewrite empty string");
+        assert_eq!(from.len(), to.len(), "rewrites must keep the length");
+        self.max_len = self.max_len.max(from.len());
+        self.by_len.entry(from.len()).or_default().insert(from, to);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_len.is_empty()
+    }
+
+    /// Rewrite all occurrences in `buf`. When several rewrites match at the
+    /// same position the longest wins.
+    pub fn apply(&self, buf: &mut [u8]) {
+        self.apply_prefix(buf, true);
+    }
+
+    /// Rewrite `buf` from the start up to where bytes that come after it
+    /// could change which rewrite matches, and return how far that is.
+    /// With `at_end` all of `buf` is rewritten. Rewriting the rest after
+    /// more bytes are added to it gives the same result as [`apply`] on
+    /// all of it at once.
+    ///
+    /// [`apply`]: Rewrites::apply
+    fn apply_prefix(&self, buf: &mut [u8], at_end: bool) -> usize {
+        let mut idx = 0;
+        while idx < buf.len() {
+            if !at_end && idx + self.max_len > buf.len() {
+                break;
+            }
+            let mut matched = 0;
+            for (len, rewrites) in self.by_len.iter().rev() {
+                if idx + len <= buf.len() {
+                    if let Some(to) = rewrites.get(&buf[idx..idx + len]) {
+                        buf[idx..idx + len].copy_from_slice(to);
+                        matched = *len;
+                        break;
+                    }
+                }
+            }
+            idx += matched.max(1);
+        }
+        idx
+    }
+
+    fn apply_bytes(&self, buf: Bytes) -> Bytes {
+        if self.is_empty() {
+            return buf;
+        }
+        let mut buf = BytesMut::from(&buf[..]);
+        self.apply(&mut buf);
+        buf.freeze()
+    }
+}
+
+impl<F: Into<Bytes>, T: Into<Bytes>> FromIterator<(F, T)> for Rewrites {
+    fn from_iter<I: IntoIterator<Item = (F, T)>>(iter: I) -> Self {
+        let mut ret = Rewrites::new();
+        for (from, to) in iter {
+            ret.insert(from, to);
+        }
+        ret
+    }
+}
+
+pin_project! {
+    /// Rewrites file contents, symlink targets and entry names of a NAR.
+    ///
+    /// Matches that span several [`NAREvent::Contents`] events of the same
+    /// file are found by holding back the bytes of each buffer that could
+    /// start one, so the buffers coming out may be split differently than
+    /// those going in. The result doesn't depend on how they are split.
+    pub struct RewriteStream<S> {
+        #[pin]
+        stream: S,
+        rewrites: Rewrites,
+        pending: BytesMut,
+        index: u64,
+    }
+}
+
+impl<Err, S: Stream<Item = Result<NAREvent, Err>>> RewriteStream<S> {
+    pub fn new(stream: S, rewrites: Rewrites) -> RewriteStream<S> {
+        RewriteStream {
+            stream,
+            rewrites,
+            pending: BytesMut::new(),
+            index: 0,
+        }
+    }
+}
+
+impl<Err, S: Stream<Item = Result<NAREvent, Err>>> Stream for RewriteStream<S> {
+    type Item = Result<NAREvent, Err>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            let item = match ready!(this.stream.as_mut().poll_next(cx)) {
+                Some(res) => res?,
+                None => return Poll::Ready(None),
+            };
+            let changed = match item {
+                NAREvent::Contents { total, index, buf } => {
+                    if index == 0 {
+                        this.pending.clear();
+                        *this.index = 0;
+                    }
+                    let last = index + buf.len() as u64 == total;
+                    // Only the bytes that were rewritten leave `pending`, so
+                    // nothing is scanned twice.
+                    this.pending.extend_from_slice(&buf);
+                    let done = this.rewrites.apply_prefix(this.pending, last);
+                    if done == 0 && !last {
+                        continue;
+                    }
+                    let buf = this.pending.split_to(done).freeze();
+                    let index = *this.index;
+                    *this.index += buf.len() as u64;
+                    NAREvent::Contents { total, index, buf }
+                }
+                NAREvent::SymlinkNode { target } => NAREvent::SymlinkNode {
+                    target: this.rewrites.apply_bytes(target),
+                },
+                NAREvent::DirectoryEntry { name } => NAREvent::DirectoryEntry {
+                    name: this.rewrites.apply_bytes(name),
+                },
+                re => re,
+            };
+            return Poll::Ready(Some(Ok(changed)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use futures::stream::{iter, TryStreamExt};
+
+    use super::*;
+
+    #[test]
+    fn test_apply_prefers_longest() {
+        let rewrites: Rewrites = [("ab", "xy"), ("abcd", "1234")].into_iter().collect();
+        let mut buf = b"abcdab-abc".to_vec();
+        rewrites.apply(&mut buf);
+        assert_eq!(buf, b"1234xy-xyc");
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_across_contents() {
+        let events = vec![
+            NAREvent::RegularNode {
+                executable: false,
+                size: 12,
+                offset: 0,
+            },
+            NAREvent::Contents {
+                total: 12,
+                index: 0,
+                buf: Bytes::from_static(b"hello wo"),
+            },
+            NAREvent::Contents {
+                total: 12,
+                index: 8,
+                buf: Bytes::from_static(b"rld!"),
+            },
+        ];
+        let rewrites: Rewrites = [("world", "earth")].into_iter().collect();
+        let stream = RewriteStream::new(iter(events.into_iter().map(Ok::<_, io::Error>)), rewrites);
+        let out: Vec<NAREvent> = stream.try_collect().await.unwrap();
+        let mut contents = Vec::new();
+        let mut next_index = 0;
+        for event in out {
+            if let NAREvent::Contents { total, index, buf } = event {
+                assert_eq!(total, 12);
+                assert_eq!(index, next_index);
+                next_index += buf.len() as u64;
+                contents.extend_from_slice(&buf);
+            }
+        }
+        assert_eq!(contents, b"hello earth!");
+    }
+
+    async fn rewrite_split(rewrites: &Rewrites, data: &[u8], splits: &[usize]) -> Vec<u8> {
+        let total = data.len() as u64;
+        let mut events = vec![NAREvent::RegularNode {
+            executable: false,
+            size: total,
+            offset: 0,
+        }];
+        let mut start = 0;
+        for end in splits.iter().copied().chain([data.len()]) {
+            if end > start {
+                events.push(NAREvent::Contents {
+                    total,
+                    index: start as u64,
+                    buf: Bytes::copy_from_slice(&data[start..end]),
+                });
+                start = end;
+            }
+        }
+        let stream = RewriteStream::new(
+            iter(events.into_iter().map(Ok::<_, io::Error>)),
+            rewrites.clone(),
+        );
+        let out: Vec<NAREvent> = stream.try_collect().await.unwrap();
+        let mut contents = Vec::new();
+        for event in out {
+            if let NAREvent::Contents { index, buf, .. } = event {
+                assert_eq!(index, contents.len() as u64);
+                contents.extend_from_slice(&buf);
+            }
+        }
+        contents
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_any_split() {
+        // Replacements that would match again if they were scanned twice.
+        let rewrites: Rewrites = [("ab", "ba"), ("ba", "cc"), ("abcd", "1234")]
+            .into_iter()
+            .collect();
+        let data = b"xabcdabababcba-ab-bab";
+        let mut expected = data.to_vec();
+        rewrites.apply(&mut expected);
+        assert_eq!(&expected, b"x1234bababaccc-ba-ccb");
+        for first in 0..=data.len() {
+            for second in first..=data.len() {
+                let out = rewrite_split(&rewrites, data, &[first, second]).await;
+                assert_eq!(out, expected, "split at {} and {}", first, second);
+            }
+        }
+    }
+}
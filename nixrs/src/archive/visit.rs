@@ -0,0 +1,219 @@
+use std::io;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::TryStreamExt;
+use tokio::io::AsyncRead;
+use tokio::pin;
+
+use super::{parse_nar, NAREvent};
+
+/// Receives the parts of a NAR in the order they appear in it.
+///
+/// File contents are handed over in small chunks as they are read, so a visitor that hashes or copies them never holds a whole file
+/// in memory. All methods do nothing by default.
+#[async_trait]
+pub trait NarVisitor: Send {
+    async fn regular(&mut self, _executable: bool, _size: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// The next chunk of the regular file last passed to
+    /// [`regular`](NarVisitor::regular).
+    async fn contents(&mut self, _buf: Bytes) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn symlink(&mut self, _target: Bytes) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn directory(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// The start of the entry `name` in the current directory. The node of
+    /// the entry follows and is closed by [`end_entry`](NarVisitor::end_entry).
+    async fn entry(&mut self, _name: Bytes) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn end_entry(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn end_directory(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<V: NarVisitor + ?Sized> NarVisitor for &mut V {
+    async fn regular(&mut self, executable: bool, size: u64) -> io::Result<()> {
+        (**self).regular(executable, size).await
+    }
+
+    async fn contents(&mut self, buf: Bytes) -> io::Result<()> {
+        (**self).contents(buf).await
+    }
+
+    async fn symlink(&mut self, target: Bytes) -> io::Result<()> {
+        (**self).symlink(target).await
+    }
+
+    async fn directory(&mut self) -> io::Result<()> {
+        (**self).directory().await
+    }
+
+    async fn entry(&mut self, name: Bytes) -> io::Result<()> {
+        (**self).entry(name).await
+    }
+
+    async fn end_entry(&mut self) -> io::Result<()> {
+        (**self).end_entry().await
+    }
+
+    async fn end_directory(&mut self) -> io::Result<()> {
+        (**self).end_directory().await
+    }
+}
+
+/// Parse the NAR in `source` and pass its parts to `visitor`.
+///
+/// The structure of the NAR is checked just like by [`parse_nar`] and the
+/// first error from either the NAR or the visitor is returned.
+pub async fn parse_nar_visit<R, V>(source: R, visitor: &mut V) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    V: NarVisitor + ?Sized,
+{
+    let events = parse_nar(source);
+    pin!(events);
+    while let Some(event) = events.try_next().await? {
+        match event {
+            NAREvent::Magic(_) => {}
+            NAREvent::RegularNode {
+                executable, size, ..
+            } => visitor.regular(executable, size).await?,
+            NAREvent::Contents { buf, .. } => visitor.contents(buf).await?,
+            NAREvent::SymlinkNode { target } => visitor.symlink(target).await?,
+            NAREvent::Directory => visitor.directory().await?,
+            NAREvent::DirectoryEntry { name } => visitor.entry(name).await?,
+            NAREvent::EndDirectoryEntry => visitor.end_entry().await?,
+            NAREvent::EndDirectory => visitor.end_directory().await?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bytes::BytesMut;
+
+    use super::*;
+    use crate::archive::{test_data, NAR_VERSION_MAGIC_1};
+
+    #[derive(Default)]
+    struct Counter {
+        files: usize,
+        symlinks: usize,
+        directories: usize,
+        bytes: u64,
+        max_chunk: usize,
+        depth: usize,
+    }
+
+    #[async_trait]
+    impl NarVisitor for Counter {
+        async fn regular(&mut self, _executable: bool, _size: u64) -> io::Result<()> {
+            self.files += 1;
+            Ok(())
+        }
+
+        async fn contents(&mut self, buf: Bytes) -> io::Result<()> {
+            self.bytes += buf.len() as u64;
+            self.max_chunk = self.max_chunk.max(buf.len());
+            Ok(())
+        }
+
+        async fn symlink(&mut self, _target: Bytes) -> io::Result<()> {
+            self.symlinks += 1;
+            Ok(())
+        }
+
+        async fn directory(&mut self) -> io::Result<()> {
+            self.directories += 1;
+            Ok(())
+        }
+
+        async fn entry(&mut self, _name: Bytes) -> io::Result<()> {
+            self.depth += 1;
+            Ok(())
+        }
+
+        async fn end_entry(&mut self) -> io::Result<()> {
+            self.depth -= 1;
+            Ok(())
+        }
+    }
+
+    fn encode(events: Vec<NAREvent>) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        for event in events {
+            event.encode_into(&mut buf);
+        }
+        buf.to_vec()
+    }
+
+    #[tokio::test]
+    async fn test_visit_dir() {
+        let events = test_data::dir_example();
+        let expected_bytes: u64 = events
+            .iter()
+            .map(|e| match e {
+                NAREvent::Contents { buf, .. } => buf.len() as u64,
+                _ => 0,
+            })
+            .sum();
+        let nar = encode(events);
+        let mut counter = Counter::default();
+        parse_nar_visit(&nar[..], &mut counter).await.unwrap();
+        assert!(counter.directories > 0);
+        assert!(counter.files > 0);
+        assert_eq!(counter.bytes, expected_bytes);
+        assert_eq!(counter.depth, 0);
+    }
+
+    #[tokio::test]
+    async fn test_visit_large_file_in_chunks() {
+        let size = 1024 * 1024;
+        let nar = encode(vec![
+            NAREvent::Magic(Arc::new(NAR_VERSION_MAGIC_1.to_owned())),
+            NAREvent::RegularNode {
+                executable: false,
+                size,
+                offset: 0,
+            },
+            NAREvent::Contents {
+                total: size,
+                index: 0,
+                buf: Bytes::from(vec![b'x'; size as usize]),
+            },
+        ]);
+        let mut counter = Counter::default();
+        parse_nar_visit(&nar[..], &mut counter).await.unwrap();
+        assert_eq!(counter.files, 1);
+        assert_eq!(counter.bytes, size);
+        assert!(counter.max_chunk < size as usize);
+    }
+
+    #[tokio::test]
+    async fn test_visit_invalid() {
+        let mut nar = encode(test_data::text_file());
+        nar.truncate(nar.len() - 8);
+        let mut counter = Counter::default();
+        assert!(parse_nar_visit(&nar[..], &mut counter).await.is_err());
+    }
+}
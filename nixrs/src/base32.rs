@@ -1,5 +1,10 @@
-use lazy_static::lazy_static;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use pin_project_lite::pin_project;
 use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 #[derive(Error, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
 #[error("invalid base32 string")]
@@ -21,15 +26,15 @@ pub const fn decoded_len(input_len: usize) -> usize {
 
 static BASE32_CHARS: &[u8; 32] = b"0123456789abcdfghijklmnpqrsvwxyz";
 
-lazy_static! {
-    static ref BASE32_CHARS_REVERSE: Box<[u8; 256]> = {
-        let mut xs = [0xffu8; 256];
-        for (n, c) in BASE32_CHARS.iter().enumerate() {
-            xs[*c as usize] = n as u8;
-        }
-        Box::new(xs)
-    };
-}
+static BASE32_CHARS_REVERSE: [u8; 256] = {
+    let mut xs = [0xffu8; 256];
+    let mut n = 0;
+    while n < BASE32_CHARS.len() {
+        xs[BASE32_CHARS[n] as usize] = n as u8;
+        n += 1;
+    }
+    xs
+};
 
 pub fn encode(input: &[u8]) -> String {
     let mut buf = vec![0; encoded_len(input.len())];
@@ -65,20 +70,30 @@ pub fn encode_into(input: &[u8], output: &mut [u8]) {
 }
 
 pub fn decode(input: &str) -> Result<Vec<u8>, BadBase32> {
-    let mut res = Vec::with_capacity(decoded_len(input.len()));
+    let mut res = vec![0; decoded_len(input.len())];
+    decode_into(input.as_bytes(), &mut res)?;
+    Ok(res)
+}
+
+/// Decode `input` into `output` which must be exactly
+/// [`decoded_len`] bytes long.
+pub fn decode_into(input: &[u8], output: &mut [u8]) -> Result<(), BadBase32> {
+    assert_eq!(decoded_len(input.len()), output.len());
 
     let mut nr_bits_left: usize = 0;
     let mut bits_left: u16 = 0;
+    let mut pos = 0;
 
-    for c in input.chars().rev() {
-        let b = BASE32_CHARS_REVERSE[c as usize];
+    for c in input.iter().rev() {
+        let b = BASE32_CHARS_REVERSE[*c as usize];
         if b == 0xff {
             return Err(BadBase32);
         }
         bits_left |= (b as u16) << nr_bits_left;
         nr_bits_left += 5;
         if nr_bits_left >= 8 {
-            res.push((bits_left & 0xff) as u8);
+            output[pos] = (bits_left & 0xff) as u8;
+            pos += 1;
             bits_left >>= 8;
             nr_bits_left -= 8;
         }
@@ -88,7 +103,141 @@ pub fn decode(input: &str) -> Result<Vec<u8>, BadBase32> {
         return Err(BadBase32);
     }
 
-    Ok(res)
+    Ok(())
+}
+
+pin_project! {
+    /// Writes the base32 encoding of everything written to it to `inner`.
+    ///
+    /// Nix base32 starts with the last input byte, so nothing is written
+    /// until the writer is shut down.
+    #[derive(Debug)]
+    pub struct Base32Writer<W> {
+        #[pin]
+        inner: W,
+        input: Vec<u8>,
+        encoded: Option<Vec<u8>>,
+        written: usize,
+    }
+}
+
+impl<W> Base32Writer<W> {
+    pub fn new(inner: W) -> Base32Writer<W> {
+        Base32Writer {
+            inner,
+            input: Vec::new(),
+            encoded: None,
+            written: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: AsyncWrite> AsyncWrite for Base32Writer<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        if this.encoded.is_some() {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::Other,
+                "write after shutdown",
+            )));
+        }
+        this.input.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        let input = &*this.input;
+        let encoded = this.encoded.get_or_insert_with(|| {
+            let mut buf = vec![0; encoded_len(input.len())];
+            encode_into(input, &mut buf);
+            buf
+        });
+        while *this.written < encoded.len() {
+            let n = ready!(this
+                .inner
+                .as_mut()
+                .poll_write(cx, &encoded[*this.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            *this.written += n;
+        }
+        this.inner.poll_shutdown(cx)
+    }
+}
+
+pin_project! {
+    /// Reads base32 from `inner` and returns the decoded bytes.
+    ///
+    /// Nix base32 ends with the first decoded byte, so all of `inner` is
+    /// read before the first byte is returned.
+    #[derive(Debug)]
+    pub struct Base32Reader<R> {
+        #[pin]
+        inner: R,
+        input: Vec<u8>,
+        decoded: Option<Vec<u8>>,
+        read: usize,
+    }
+}
+
+impl<R> Base32Reader<R> {
+    pub fn new(inner: R) -> Base32Reader<R> {
+        Base32Reader {
+            inner,
+            input: Vec::new(),
+            decoded: None,
+            read: 0,
+        }
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for Base32Reader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        while this.decoded.is_none() {
+            let mut chunk = [0u8; 1024];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(this.inner.as_mut().poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                // Allow a trailing newline like the one `echo` adds.
+                let end = this
+                    .input
+                    .iter()
+                    .rposition(|b| !b.is_ascii_whitespace())
+                    .map_or(0, |pos| pos + 1);
+                let input = &this.input[..end];
+                let mut decoded = vec![0; decoded_len(input.len())];
+                decode_into(input, &mut decoded)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                *this.decoded = Some(decoded);
+            } else {
+                this.input.extend_from_slice(chunk.filled());
+            }
+        }
+        let decoded = this.decoded.as_ref().unwrap();
+        let n = buf.remaining().min(decoded.len() - *this.read);
+        buf.put_slice(&decoded[*this.read..*this.read + n]);
+        *this.read += n;
+        Poll::Ready(Ok(()))
+    }
 }
 
 #[cfg(test)]
@@ -153,11 +302,73 @@ mod tests {
         assert_matches!(decode("2gs8"), Err(BadBase32));
     }
 
+    /// The straightforward decoder `decode` used to be.
+    fn decode_reference(input: &str) -> Result<Vec<u8>, BadBase32> {
+        let mut res = Vec::new();
+        let mut nr_bits_left: usize = 0;
+        let mut bits_left: u16 = 0;
+        for c in input.chars().rev() {
+            let b = BASE32_CHARS
+                .iter()
+                .position(|b| *b as char == c)
+                .ok_or(BadBase32)?;
+            bits_left |= (b as u16) << nr_bits_left;
+            nr_bits_left += 5;
+            if nr_bits_left >= 8 {
+                res.push((bits_left & 0xff) as u8);
+                bits_left >>= 8;
+                nr_bits_left -= 8;
+            }
+        }
+        if nr_bits_left > 0 && bits_left != 0 {
+            return Err(BadBase32);
+        }
+        Ok(res)
+    }
+
+    #[test]
+    fn test_decode_non_ascii() {
+        assert_matches!(decode("x0xf8v9fxf3jk8zln1cwlsrmhqvp0f8é"), Err(BadBase32));
+        assert_matches!(decode("\u{1f600}"), Err(BadBase32));
+    }
+
+    #[tokio::test]
+    async fn test_writer_and_reader() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let input = hex::decode("0839703786356bca59b0f4a32987eb2e6de43ae8").unwrap();
+        let mut writer = Base32Writer::new(Vec::new());
+        writer.write_all(&input[..7]).await.unwrap();
+        writer.write_all(&input[7..]).await.unwrap();
+        writer.shutdown().await.unwrap();
+        let encoded = writer.into_inner();
+        assert_eq!(encoded, b"x0xf8v9fxf3jk8zln1cwlsrmhqvp0f88");
+
+        let mut reader = Base32Reader::new(&b"x0xf8v9fxf3jk8zln1cwlsrmhqvp0f88\n"[..]);
+        let mut decoded = Vec::new();
+        reader.read_to_end(&mut decoded).await.unwrap();
+        assert_eq!(decoded, input);
+
+        let mut reader = Base32Reader::new(&b"xoxf8v9fxf3jk8zln1cwlsrmhqvp0f88"[..]);
+        let err = reader.read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
     proptest! {
 
         #[test]
         fn proptest_roundtrip(s: Vec<u8>) {
             prop_assert_eq!(&s, &decode(&encode(&s)).unwrap());
         }
+
+        #[test]
+        fn proptest_decode_matches_reference(s in "[0-9a-z]{0,64}") {
+            prop_assert_eq!(decode(&s), decode_reference(&s));
+        }
+
+        #[test]
+        fn proptest_decode_any_string(s: String) {
+            prop_assert_eq!(decode(&s), decode_reference(&s));
+        }
     }
 }
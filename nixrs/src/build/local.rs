@@ -0,0 +1,1175 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use bstr::ByteSlice;
+use bytes::BytesMut;
+use futures::StreamExt;
+use ring::rand::{self, SystemRandom};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader};
+use tokio::pin;
+use tracing::{debug, error, instrument, Instrument, Span};
+
+use crate::activity;
+use crate::archive::dump;
+use crate::hash::{Algorithm, Context, Hash};
+use crate::path_info::ValidPathInfo;
+use crate::store::activity::{Activity, ActivityBuilder, ActivityType, ResultType, RESULT_TARGET};
+use crate::store::daemon::{
+    BoxedDaemonStore, DaemonPath, DaemonServerBuilder, DaemonStore, GCOptions, GCResults,
+    QueryMissingResult, TrustedFlag,
+};
+use crate::store::error::Verbosity;
+use crate::store::settings::get_settings;
+#[cfg(unix)]
+use crate::store::Optimiser;
+use crate::store::{
+    compute_fs_closure_slow, register_valid_path, BasicDerivation, BuildMode, BuildResult,
+    BuildStatus, CheckSignaturesFlag, DerivationOutput, DerivedPath, DrvOutput, Error,
+    ExperimentalFeature, ExperimentalFeatures, Realisation, RepairFlag, RestrictedPaths,
+    RestrictedStore, Store, SubstituteFlag,
+};
+use crate::store_path::{
+    ContentAddress, ContentAddressMethod, FileIngestionMethod, StoreDir, StoreDirProvider,
+    StorePath, StorePathSet,
+};
+
+/// How builders run by [`LocalBuilder`] are isolated from the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sandbox {
+    /// Run the builder directly with a cleared environment.
+    Disabled,
+    /// Run the builder in fresh user, mount, pid, ipc and uts namespaces
+    /// using `unshare(1)`. Derivations that are not fixed-output also get
+    /// their own network namespace. The builder gets a private root with a
+    /// fresh `/proc` that only holds the closure of its inputs, its build
+    /// directory, a few devices and the paths given to
+    /// [`LocalBuilder::sandbox_paths`]. Only available on Linux.
+    UserNamespace,
+}
+
+type NewStoreFn = dyn Fn() -> BoxedDaemonStore<'static> + Send + Sync;
+
+#[derive(Clone)]
+struct RecursiveNix(Arc<NewStoreFn>);
+
+impl fmt::Debug for RecursiveNix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecursiveNix").finish_non_exhaustive()
+    }
+}
+
+/// Builds derivations on this machine and registers their outputs in
+/// `store`.
+///
+/// Outputs are written by the builder straight into the store directory so
+/// the store directory must be writable by the current user. After a
+/// successful build every output is serialised, scanned for references to
+/// its inputs and registered with [`register_valid_path`].
+#[derive(Debug)]
+pub struct LocalBuilder<S> {
+    store: S,
+    build_root: PathBuf,
+    sandbox: Sandbox,
+    sandbox_paths: Vec<PathBuf>,
+    recursive_nix: Option<RecursiveNix>,
+}
+
+impl<S> LocalBuilder<S> {
+    pub fn new(store: S) -> LocalBuilder<S> {
+        LocalBuilder {
+            store,
+            build_root: std::env::temp_dir(),
+            sandbox: Sandbox::Disabled,
+            sandbox_paths: Vec::new(),
+            recursive_nix: None,
+        }
+    }
+
+    /// Directory that temporary build directories are created in.
+    pub fn build_root<P: Into<PathBuf>>(mut self, build_root: P) -> Self {
+        self.build_root = build_root.into();
+        self
+    }
+
+    pub fn sandbox(mut self, sandbox: Sandbox) -> Self {
+        self.sandbox = sandbox;
+        self
+    }
+
+    /// Host paths that are available read-only at the same location in the
+    /// sandbox, on top of those in the `sandbox-paths` setting.
+    pub fn sandbox_paths<I, P>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        self.sandbox_paths = paths.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Turn on `recursive-nix`: derivations that require it get a daemon
+    /// socket in their build directory, named by `NIX_REMOTE`. Every
+    /// connection to it is served from a store made by `new_store`, wrapped
+    /// in a [`RestrictedStore`] that only shows the input closure of the
+    /// build and what the builder adds to it. The outputs may refer to the
+    /// added paths.
+    pub fn recursive_nix<F, R>(mut self, new_store: F) -> Self
+    where
+        F: Fn() -> R + Send + Sync + 'static,
+        R: DaemonStore + Send + 'static,
+    {
+        let new_store = move || BoxedDaemonStore::new(Box::new(new_store()));
+        self.recursive_nix = Some(RecursiveNix(Arc::new(new_store)));
+        self
+    }
+
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+}
+
+/// Find which of `candidates` are referenced from `nar` by looking for the
+/// hash part of their store paths.
+pub fn scan_for_references(nar: &[u8], candidates: &StorePathSet) -> StorePathSet {
+    candidates
+        .iter()
+        .filter(|path| nar.find(path.hash.to_string()).is_some())
+        .cloned()
+        .collect()
+}
+
+fn build_env(
+    store_dir: &StoreDir,
+    drv: &BasicDerivation,
+    build_dir: &Path,
+    socket: Option<&Path>,
+) -> Vec<(String, String)> {
+    let build_cores = get_settings(|s| s.build_cores);
+    let build_dir = build_dir.to_string_lossy().into_owned();
+    let mut env = vec![
+        ("PATH".to_owned(), "/path-not-set".to_owned()),
+        ("HOME".to_owned(), "/homeless-shelter".to_owned()),
+        ("NIX_STORE".to_owned(), store_dir.to_str().to_owned()),
+        ("NIX_BUILD_CORES".to_owned(), build_cores.to_string()),
+        ("NIX_BUILD_TOP".to_owned(), build_dir.clone()),
+        ("TMPDIR".to_owned(), build_dir.clone()),
+        ("TEMPDIR".to_owned(), build_dir.clone()),
+        ("TMP".to_owned(), build_dir.clone()),
+        ("TEMP".to_owned(), build_dir.clone()),
+        ("PWD".to_owned(), build_dir),
+        ("NIX_LOG_FD".to_owned(), "2".to_owned()),
+        ("TERM".to_owned(), "xterm-256color".to_owned()),
+    ];
+    if let Some(socket) = socket {
+        env.push((
+            "NIX_REMOTE".to_owned(),
+            format!("unix://{}", socket.display()),
+        ));
+    }
+    env.extend(drv.env.iter().cloned());
+    env
+}
+
+async fn log_lines<R: AsyncRead + Unpin>(reader: R, span: Span) {
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(message)) = lines.next_line().await {
+        let result_type: u64 = ResultType::BuildLogLine.into();
+        error!(target: RESULT_TARGET, parent: &span, result_type, message);
+    }
+}
+
+async fn dump_nar(path: &Path) -> Result<BytesMut, Error> {
+    let events = dump(path);
+    pin!(events);
+    let mut nar = BytesMut::new();
+    while let Some(event) = events.next().await {
+        event?.encode_into(&mut nar);
+    }
+    Ok(nar)
+}
+
+/// Create a new build directory for `name` in `build_root` that only the
+/// current user can enter. The name ends in random bytes so it can't be
+/// created ahead of the build by someone else, and an existing directory is
+/// an error rather than being reused.
+async fn create_build_dir(build_root: &Path, name: &str) -> Result<PathBuf, Error> {
+    let suffix: [u8; 16] = rand::generate(&SystemRandom::new())
+        .map_err(|_| Error::Misc("could not generate a build directory name".into()))?
+        .expose();
+    let build_dir = build_root.join(format!("nix-build-{}-{}", name, hex::encode(suffix)));
+    tokio::fs::create_dir_all(build_root).await?;
+    let mut builder = tokio::fs::DirBuilder::new();
+    #[cfg(unix)]
+    builder.mode(0o700);
+    builder.create(&build_dir).await?;
+    Ok(build_dir)
+}
+
+/// Remove whatever the builder left at `real_path` in the store directory.
+async fn remove_output(real_path: &Path) -> Result<(), Error> {
+    match tokio::fs::symlink_metadata(real_path).await {
+        Ok(meta) if meta.is_dir() => tokio::fs::remove_dir_all(real_path).await?,
+        Ok(_) => tokio::fs::remove_file(real_path).await?,
+        Err(_) => {}
+    }
+    Ok(())
+}
+
+fn hash_of(algorithm: Algorithm, data: &[u8]) -> Hash {
+    let mut ctx = Context::new(algorithm);
+    ctx.update(data);
+    ctx.finish()
+}
+
+/// Devices that sandboxed builders get from the host.
+const SANDBOX_DEVICES: &[&str] = &[
+    "/dev/full",
+    "/dev/null",
+    "/dev/random",
+    "/dev/urandom",
+    "/dev/zero",
+];
+
+/// Run by `sh` in the namespaces of a sandboxed build with the private root,
+/// `mount`, `unshare` and the build directory as its first arguments. Every
+/// following `rw:<path>` or `ro:<path>` argument up to `--` is bound onto
+/// the same path in the root, read-only for `ro:`. Then a fresh `/proc` is
+/// mounted and the rest of the arguments run with the private root as their
+/// root directory.
+const SANDBOX_SETUP: &str = r#"set -e
+root=$1 mount=$2 unshare=$3 wd=$4
+shift 4
+while [ "$1" != -- ]; do
+    path=${1#*:}
+    "$mount" --rbind "$path" "$root$path"
+    case $1 in
+    ro:*) "$mount" -o remount,bind,ro "$root$path" ;;
+    esac
+    shift
+done
+shift
+"$mount" -t proc proc "$root/proc"
+exec "$unshare" --root="$root" --wd="$wd" -- "$@"
+"#;
+
+/// Look up `name` in the `PATH` of this process, since builders get a
+/// `PATH` of their own.
+fn find_program(name: &str) -> Result<PathBuf, Error> {
+    std::env::var_os("PATH")
+        .and_then(|paths| {
+            std::env::split_paths(&paths)
+                .map(|dir| dir.join(name))
+                .find(|path| path.is_file())
+        })
+        .ok_or_else(|| Error::Misc(format!("could not find '{}' for the build sandbox", name)))
+}
+
+/// The private root a sandboxed builder runs in.
+///
+/// It is made next to the outputs in the store directory so they can be
+/// moved out of it with a rename once the build is done.
+struct Chroot {
+    root: PathBuf,
+    mount: PathBuf,
+    unshare: PathBuf,
+    /// Host paths bound into the root and whether they are writable.
+    binds: Vec<(PathBuf, bool)>,
+}
+
+impl Chroot {
+    async fn create(
+        root: PathBuf,
+        store_dir: &StoreDir,
+        build_dir: &Path,
+        inputs: &StorePathSet,
+        sandbox_paths: &[PathBuf],
+    ) -> Result<Chroot, Error> {
+        let mut binds = vec![(build_dir.to_owned(), true)];
+        binds.extend(
+            SANDBOX_DEVICES
+                .iter()
+                .map(PathBuf::from)
+                .filter(|dev| dev.exists())
+                .map(|dev| (dev, true)),
+        );
+        binds.extend(sandbox_paths.iter().map(|path| (path.clone(), false)));
+        binds.extend(
+            inputs
+                .iter()
+                .map(|path| (PathBuf::from(store_dir.print_path(path)), false)),
+        );
+        let chroot = Chroot {
+            root,
+            mount: find_program("mount")?,
+            unshare: find_program("unshare")?,
+            binds,
+        };
+
+        remove_output(&chroot.root).await?;
+        let mut builder = tokio::fs::DirBuilder::new();
+        #[cfg(unix)]
+        builder.mode(0o700);
+        builder.create(&chroot.root).await?;
+        // The store directory is writable so the builder can create its
+        // outputs.
+        tokio::fs::create_dir_all(chroot.path(Path::new(store_dir.to_str()))).await?;
+        tokio::fs::create_dir(chroot.root.join("proc")).await?;
+        for (path, _) in chroot.binds.iter() {
+            let target = chroot.path(path);
+            if tokio::fs::metadata(path).await?.is_dir() {
+                tokio::fs::create_dir_all(&target).await?;
+            } else {
+                if let Some(parent) = target.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                tokio::fs::File::create(&target).await?;
+            }
+        }
+        Ok(chroot)
+    }
+
+    /// Where the host path `path` is in the root.
+    fn path(&self, path: &Path) -> PathBuf {
+        self.root.join(path.strip_prefix("/").unwrap_or(path))
+    }
+
+    /// Move the outputs the builder wrote into the store directory, leaving
+    /// outputs that are already there alone.
+    async fn move_outputs(
+        &self,
+        store_dir: &StoreDir,
+        outputs: &StorePathSet,
+    ) -> Result<(), Error> {
+        for path in outputs {
+            let real_path = PathBuf::from(store_dir.print_path(path));
+            let built = self.path(&real_path);
+            if tokio::fs::symlink_metadata(&built).await.is_ok()
+                && tokio::fs::symlink_metadata(&real_path).await.is_err()
+            {
+                tokio::fs::rename(&built, &real_path).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn run_builder(
+    chroot: Option<&Chroot>,
+    store_dir: &StoreDir,
+    drv: &BasicDerivation,
+    build_dir: &Path,
+    socket: Option<&Path>,
+    network: bool,
+    span: Span,
+) -> Result<(ExitStatus, CpuTimes), Error> {
+    let mut cmd = match chroot {
+        None => Command::new(&drv.builder),
+        Some(chroot) => {
+            let mut cmd = Command::new(&chroot.unshare);
+            cmd.args([
+                "--user",
+                "--map-root-user",
+                "--mount",
+                "--pid",
+                "--ipc",
+                "--uts",
+                "--fork",
+            ]);
+            if !network {
+                cmd.arg("--net");
+            }
+            cmd.args(["--", "/bin/sh", "-c", SANDBOX_SETUP, "sh"])
+                .arg(&chroot.root)
+                .arg(&chroot.mount)
+                .arg(&chroot.unshare)
+                .arg(build_dir);
+            for (path, writable) in chroot.binds.iter() {
+                let mut arg = std::ffi::OsString::from(if *writable { "rw:" } else { "ro:" });
+                arg.push(path);
+                cmd.arg(arg);
+            }
+            cmd.arg("--").arg(&drv.builder);
+            cmd
+        }
+    };
+    cmd.args(&drv.arguments)
+        .env_clear()
+        .envs(build_env(store_dir, drv, build_dir, socket))
+        .current_dir(build_dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    let mut child = cmd.spawn()?;
+    let stdout = tokio::process::ChildStdout::from_std(child.stdout.take().unwrap())?;
+    let stderr = tokio::process::ChildStderr::from_std(child.stderr.take().unwrap())?;
+    let out = tokio::spawn(log_lines(stdout, span.clone()));
+    let err = tokio::spawn(log_lines(stderr, span));
+    let exit = wait_builder(child).await?;
+    out.await?;
+    err.await?;
+    Ok(exit)
+}
+
+/// Serve the daemon socket of a `recursive-nix` build at `socket` until the
+/// returned task is aborted.
+#[cfg(unix)]
+fn serve_recursive_nix(
+    socket: &Path,
+    recursive_nix: RecursiveNix,
+    paths: RestrictedPaths,
+) -> Result<tokio::task::JoinHandle<()>, Error> {
+    let listener = tokio::net::UnixListener::bind(socket)?;
+    Ok(tokio::spawn(async move {
+        let new_store = move || RestrictedStore::new((recursive_nix.0)(), paths.clone());
+        if let Err(err) = DaemonServerBuilder::new()
+            .serve_unix(listener, new_store)
+            .await
+        {
+            error!("recursive Nix daemon stopped: {}", err);
+        }
+    }))
+}
+
+#[cfg(not(unix))]
+fn serve_recursive_nix(
+    _socket: &Path,
+    _recursive_nix: RecursiveNix,
+    _paths: RestrictedPaths,
+) -> Result<tokio::task::JoinHandle<()>, Error> {
+    Err(Error::UnsupportedOperation(
+        "recursive Nix on this platform".into(),
+    ))
+}
+
+/// User and system CPU time used by a builder and the children it waited
+/// for.
+type CpuTimes = Option<(Duration, Duration)>;
+
+/// Kills the builder if the build is dropped before the builder exited.
+#[cfg(unix)]
+struct KillGuard {
+    pid: libc::pid_t,
+    reaped: Arc<std::sync::Mutex<bool>>,
+}
+
+#[cfg(unix)]
+impl Drop for KillGuard {
+    fn drop(&mut self) {
+        let reaped = self.reaped.lock().unwrap();
+        if !*reaped {
+            // SAFETY: the builder hasn't been reaped so its pid can't have
+            // been reused.
+            unsafe { libc::kill(self.pid, libc::SIGKILL) };
+        }
+    }
+}
+
+/// Wait for the builder with `wait4` so the CPU times are those of the
+/// builder alone and not of every child of this process.
+#[cfg(unix)]
+async fn wait_builder(child: std::process::Child) -> io::Result<(ExitStatus, CpuTimes)> {
+    use std::os::unix::process::ExitStatusExt;
+
+    let pid = child.id() as libc::pid_t;
+    let reaped = Arc::new(std::sync::Mutex::new(false));
+    let _guard = KillGuard {
+        pid,
+        reaped: reaped.clone(),
+    };
+    tokio::task::spawn_blocking(move || {
+        // Wait for the exit without reaping first so the guard can still
+        // kill the builder until the moment it is reaped.
+        loop {
+            // SAFETY: siginfo_t is plain data and waitid only writes to it.
+            let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+            let res = unsafe {
+                libc::waitid(
+                    libc::P_PID,
+                    pid as libc::id_t,
+                    &mut info,
+                    libc::WEXITED | libc::WNOWAIT,
+                )
+            };
+            if res == 0 {
+                break;
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+        let mut reaped = reaped.lock().unwrap();
+        let mut status = 0;
+        // SAFETY: rusage is plain data and wait4 only writes to it.
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        if unsafe { libc::wait4(pid, &mut status, 0, &mut usage) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        *reaped = true;
+        drop(child);
+        let time = |tv: libc::timeval| Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000);
+        Ok((
+            ExitStatus::from_raw(status),
+            Some((time(usage.ru_utime), time(usage.ru_stime))),
+        ))
+    })
+    .await?
+}
+
+#[cfg(not(unix))]
+async fn wait_builder(mut child: std::process::Child) -> io::Result<(ExitStatus, CpuTimes)> {
+    tokio::task::spawn_blocking(move || Ok((child.wait()?, None))).await?
+}
+
+impl<S> LocalBuilder<S>
+where
+    S: Store + Send,
+{
+    /// Serialise the output at `path` and work out its path info, or say why
+    /// the output is rejected.
+    async fn check_output(
+        &mut self,
+        path: &StorePath,
+        output: &DerivationOutput,
+        candidates: &StorePathSet,
+        deriver: &StorePath,
+    ) -> Result<Result<(ValidPathInfo, BytesMut), String>, Error> {
+        let store_dir = self.store.store_dir();
+        let real_path = PathBuf::from(store_dir.print_path(path));
+        if tokio::fs::symlink_metadata(&real_path).await.is_err() {
+            return Ok(Err(format!(
+                "builder failed to produce output path '{}'",
+                real_path.display()
+            )));
+        }
+        let nar = dump_nar(&real_path).await?;
+
+        let mut ca = None;
+        if let DerivationOutput::CAFixed(expected) = output {
+            let actual = match expected.method {
+                ContentAddressMethod::Fixed(FileIngestionMethod::Recursive) => {
+                    hash_of(expected.hash.algorithm(), &nar)
+                }
+                _ => hash_of(
+                    expected.hash.algorithm(),
+                    &tokio::fs::read(&real_path).await?,
+                ),
+            };
+            if actual != expected.hash {
+                return Ok(Err(format!(
+                    "hash mismatch in fixed-output derivation '{}':\n  specified: {}\n  got:       {}",
+                    real_path.display(),
+                    expected.hash.to_sri(),
+                    actual.to_sri()
+                )));
+            }
+            ca = Some(ContentAddress {
+                method: expected.method,
+                hash: actual,
+            });
+        }
+
+        let nar_hash = hash_of(Algorithm::SHA256, &nar);
+        let mut info = ValidPathInfo::new(path.clone(), nar_hash);
+        info.nar_size = nar.len() as u64;
+        info.references = scan_for_references(&nar, candidates);
+        info.deriver = Some(deriver.clone());
+        info.registration_time = SystemTime::now();
+        info.ultimate = true;
+        info.ca = ca;
+        Ok(Ok((info, nar)))
+    }
+
+    /// Register the outputs of a successful build. Every output is checked
+    /// before any is registered, so a rejected output never leaves the
+    /// others registered.
+    async fn register_outputs(
+        &mut self,
+        outputs: &[(StorePath, DerivationOutput)],
+        input_srcs: &StorePathSet,
+        added: StorePathSet,
+        deriver: &StorePath,
+        repair: RepairFlag,
+    ) -> Result<BuildResult, Error> {
+        let mut candidates = compute_fs_closure_slow(&mut self.store, input_srcs, false).await?;
+        candidates.extend(outputs.iter().map(|(path, _)| path.clone()));
+        candidates.extend(added);
+        let mut checked = Vec::with_capacity(outputs.len());
+        for (path, output) in outputs.iter() {
+            match self
+                .check_output(path, output, &candidates, deriver)
+                .await?
+            {
+                Ok(output) => checked.push(output),
+                Err(msg) => return Ok(BuildResult::new(BuildStatus::OutputRejected, msg)),
+            }
+        }
+        for (info, nar) in checked {
+            register_valid_path(&mut self.store, info, &nar[..], repair, None).await?;
+        }
+        Ok(BuildResult::new(BuildStatus::Built, String::new()))
+    }
+
+    /// Build `drv` on this machine.
+    #[instrument(skip(self, drv))]
+    pub async fn build(
+        &mut self,
+        drv_path: &StorePath,
+        drv: &BasicDerivation,
+        build_mode: BuildMode,
+    ) -> Result<BuildResult, Error> {
+        let store_dir = self.store.store_dir();
+        let drv_type = drv.drv_type()?;
+        if self.sandbox == Sandbox::UserNamespace && !cfg!(target_os = "linux") {
+            return Err(Error::UnsupportedOperation(
+                "user namespace sandbox on this platform".into(),
+            ));
+        }
+        let recursive_nix = if ExperimentalFeatures::required_by(drv)
+            .is_enabled(ExperimentalFeature::RecursiveNix)
+        {
+            let recursive_nix =
+                self.recursive_nix
+                    .clone()
+                    .ok_or(Error::MissingExperimentalFeature(
+                        ExperimentalFeature::RecursiveNix,
+                    ))?;
+            Some(recursive_nix)
+        } else {
+            None
+        };
+        let mut outputs = Vec::new();
+        for (name, (output, path)) in drv.outputs_and_opt_paths(&store_dir)? {
+            let path = path.ok_or_else(|| {
+                Error::UnsupportedOperation(format!(
+                    "building output '{}' of '{}' with unknown path",
+                    name,
+                    store_dir.print_path(drv_path)
+                ))
+            })?;
+            outputs.push((path, output));
+        }
+        let output_paths: StorePathSet = outputs.iter().map(|(path, _)| path.clone()).collect();
+        let valid = self
+            .store
+            .query_valid_paths(&output_paths, SubstituteFlag::NoSubstitute)
+            .await?;
+        if build_mode == BuildMode::Normal && valid == output_paths {
+            return Ok(BuildResult::new(BuildStatus::AlreadyValid, String::new()));
+        }
+        if build_mode != BuildMode::Normal && build_mode != BuildMode::Repair {
+            return Err(Error::RepairingOrCheckingNotSupported);
+        }
+        let repair = if build_mode == BuildMode::Repair {
+            RepairFlag::Repair
+        } else {
+            RepairFlag::NoRepair
+        };
+
+        let valid_inputs = self
+            .store
+            .query_valid_paths(&drv.input_srcs, SubstituteFlag::NoSubstitute)
+            .await?;
+        if let Some(missing) = drv.input_srcs.difference(&valid_inputs).next() {
+            return Ok(BuildResult::new(
+                BuildStatus::DependencyFailed,
+                format!("input '{}' is not valid", store_dir.print_path(missing)),
+            ));
+        }
+
+        let full_drv_path = store_dir.print_path(drv_path);
+        let act = activity!(
+            Verbosity::Info,
+            ActivityType::Build,
+            format!("building '{}'", full_drv_path),
+            field0 = full_drv_path,
+            field1 = "",
+            field2 = 1,
+            field3 = 1
+        );
+
+        if repair == RepairFlag::Repair {
+            // The builder writes straight to the output paths so the
+            // corrupted copies have to go first.
+            for path in output_paths.iter() {
+                remove_output(Path::new(&store_dir.print_path(path))).await?;
+            }
+        }
+
+        let build_dir = create_build_dir(&self.build_root, &drv.name).await?;
+        debug!("building {} in {}", full_drv_path, build_dir.display());
+
+        let socket = build_dir.join(".nix-socket");
+        let mut recursive = None;
+        if let Some(recursive_nix) = recursive_nix {
+            let inputs = compute_fs_closure_slow(&mut self.store, &drv.input_srcs, false).await?;
+            let paths = RestrictedPaths::new(inputs);
+            let server = serve_recursive_nix(&socket, recursive_nix, paths.clone())?;
+            recursive = Some((server, paths));
+        }
+
+        let chroot = match self.sandbox {
+            Sandbox::Disabled => None,
+            Sandbox::UserNamespace => {
+                let inputs =
+                    compute_fs_closure_slow(&mut self.store, &drv.input_srcs, false).await?;
+                let mut sandbox_paths = self.sandbox_paths.clone();
+                sandbox_paths.extend(
+                    get_settings(|s| s.sandbox_paths.clone())
+                        .into_iter()
+                        .map(PathBuf::from),
+                );
+                let root = PathBuf::from(format!("{}.chroot", full_drv_path));
+                let chroot =
+                    Chroot::create(root, &store_dir, &build_dir, &inputs, &sandbox_paths).await?;
+                Some(chroot)
+            }
+        };
+
+        let start_time = SystemTime::now();
+        let span = act.span.clone();
+        let network = !drv_type.is_sandboxed();
+        let exit = run_builder(
+            chroot.as_ref(),
+            &store_dir,
+            drv,
+            &build_dir,
+            recursive.as_ref().map(|_| socket.as_path()),
+            network,
+            span,
+        )
+        .instrument(act.span.clone())
+        .await;
+        let added = match recursive {
+            Some((server, paths)) => {
+                server.abort();
+                let _ = tokio::fs::remove_file(&socket).await;
+                paths.added()
+            }
+            None => StorePathSet::new(),
+        };
+        let stop_time = SystemTime::now();
+        if let Some(chroot) = chroot {
+            if matches!(&exit, Ok((status, _)) if status.success()) {
+                chroot.move_outputs(&store_dir, &output_paths).await?;
+            }
+            tokio::fs::remove_dir_all(&chroot.root).await?;
+        }
+        let keep_failed = get_settings(|s| s.keep_failed);
+        let mut cpu = None;
+        let res = match exit {
+            Ok((status, times)) if status.success() => {
+                cpu = times;
+                self.register_outputs(&outputs, &drv.input_srcs, added, drv_path, repair)
+                    .instrument(act.span.clone())
+                    .await
+            }
+            Ok((status, times)) => {
+                cpu = times;
+                Ok(BuildResult::new(
+                    BuildStatus::PermanentFailure,
+                    format!("builder for '{}' failed with {}", full_drv_path, status),
+                ))
+            }
+            Err(err) => Err(err),
+        };
+        let failed = !matches!(&res, Ok(result) if result.success());
+        if failed {
+            // Outputs that were not valid before the build are whatever
+            // the builder got to write before failing.
+            let built: StorePathSet = output_paths.difference(&valid).cloned().collect();
+            let registered = self
+                .store
+                .query_valid_paths(&built, SubstituteFlag::NoSubstitute)
+                .await?;
+            for path in built.difference(&registered) {
+                remove_output(Path::new(&store_dir.print_path(path))).await?;
+            }
+        }
+        let mut result = match res {
+            Ok(result) => result,
+            Err(err) => {
+                if !keep_failed {
+                    let _ = tokio::fs::remove_dir_all(&build_dir).await;
+                }
+                return Err(err);
+            }
+        };
+        result.times_built = 1;
+        result.start_time = start_time;
+        result.stop_time = stop_time;
+        result.cpu_user = cpu.map(|cpu| cpu.0);
+        result.cpu_system = cpu.map(|cpu| cpu.1);
+
+        if result.success() || !keep_failed {
+            tokio::fs::remove_dir_all(&build_dir).await?;
+        }
+        Ok(result)
+    }
+}
+
+impl<S: StoreDirProvider> StoreDirProvider for LocalBuilder<S> {
+    fn store_dir(&self) -> StoreDir {
+        self.store.store_dir()
+    }
+}
+
+#[async_trait]
+impl<S> Store for LocalBuilder<S>
+where
+    S: Store + Send,
+{
+    async fn query_valid_paths(
+        &mut self,
+        paths: &StorePathSet,
+        maybe_substitute: SubstituteFlag,
+    ) -> Result<StorePathSet, Error> {
+        self.store.query_valid_paths(paths, maybe_substitute).await
+    }
+
+    async fn query_path_info(&mut self, path: &StorePath) -> Result<Option<ValidPathInfo>, Error> {
+        self.store.query_path_info(path).await
+    }
+
+    async fn nar_from_path<W: AsyncWrite + fmt::Debug + Send + Unpin>(
+        &mut self,
+        path: &StorePath,
+        sink: W,
+    ) -> Result<(), Error> {
+        self.store.nar_from_path(path, sink).await
+    }
+
+    async fn add_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        info: &ValidPathInfo,
+        source: R,
+        repair: RepairFlag,
+        check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error> {
+        self.store
+            .add_to_store(info, source, repair, check_sigs)
+            .await
+    }
+
+    async fn build_derivation(
+        &mut self,
+        drv_path: &StorePath,
+        drv: &BasicDerivation,
+        build_mode: BuildMode,
+    ) -> Result<BuildResult, Error> {
+        self.build(drv_path, drv, build_mode).await
+    }
+}
+
+#[async_trait]
+impl<S> DaemonStore for LocalBuilder<S>
+where
+    S: DaemonStore + Send,
+{
+    fn is_trusted_client(&self) -> Option<TrustedFlag> {
+        self.store.is_trusted_client()
+    }
+
+    fn experimental_features(&self) -> ExperimentalFeatures {
+        let mut features = self.store.experimental_features();
+        if self.recursive_nix.is_some() {
+            features.enable(ExperimentalFeature::RecursiveNix);
+        }
+        features
+    }
+
+    async fn set_options(&mut self) -> Result<(), Error> {
+        self.store.set_options().await
+    }
+
+    async fn is_valid_path(&mut self, path: &StorePath) -> Result<bool, Error> {
+        self.store.is_valid_path(path).await
+    }
+
+    async fn add_multiple_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        source: R,
+        repair: RepairFlag,
+        check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error> {
+        self.store
+            .add_multiple_to_store(source, repair, check_sigs)
+            .await
+    }
+
+    async fn query_missing(
+        &mut self,
+        targets: &[DerivedPath],
+    ) -> Result<QueryMissingResult, Error> {
+        self.store.query_missing(targets).await
+    }
+
+    async fn add_build_log(&mut self, drv_path: &StorePath, log: &str) -> Result<(), Error> {
+        self.store.add_build_log(drv_path, log).await
+    }
+
+    async fn collect_garbage(&mut self, options: &GCOptions) -> Result<GCResults, Error> {
+        self.store.collect_garbage(options).await
+    }
+
+    async fn collect_garbage_streaming(
+        &mut self,
+        options: &GCOptions,
+        act: &Activity,
+    ) -> Result<GCResults, Error> {
+        self.store.collect_garbage_streaming(options, act).await
+    }
+
+    async fn query_realisation(&mut self, id: &DrvOutput) -> Result<Option<Realisation>, Error> {
+        self.store.query_realisation(id).await
+    }
+
+    async fn register_drv_output(&mut self, realisation: &Realisation) -> Result<(), Error> {
+        self.store.register_drv_output(realisation).await
+    }
+
+    async fn add_indirect_root(&mut self, path: &DaemonPath) -> Result<(), Error> {
+        self.store.add_indirect_root(path).await
+    }
+
+    async fn find_roots(&mut self) -> Result<BTreeMap<DaemonPath, StorePath>, Error> {
+        self.store.find_roots().await
+    }
+
+    async fn verify_store(
+        &mut self,
+        check_contents: bool,
+        repair: RepairFlag,
+    ) -> Result<bool, Error> {
+        self.store.verify_store(check_contents, repair).await
+    }
+
+    async fn repair_path(&mut self, path: &StorePath) -> Result<(), Error> {
+        self.store.repair_path(path).await
+    }
+
+    async fn substitute_paths(&mut self, paths: &StorePathSet) -> Result<(), Error> {
+        self.store.substitute_paths(paths).await
+    }
+
+    #[cfg(not(unix))]
+    async fn optimise_store(&mut self) -> Result<(), Error> {
+        self.store.optimise_store().await
+    }
+
+    /// Builders write straight into the store directory so it can be
+    /// optimised in place.
+    #[cfg(unix)]
+    async fn optimise_store(&mut self) -> Result<(), Error> {
+        let act = ActivityBuilder::new(
+            Verbosity::Info,
+            ActivityType::OptimiseStore,
+            "optimising store",
+        )
+        .start();
+        let stats = Optimiser::new(self.store_dir().to_str())
+            .optimise_store(&act)
+            .await?;
+        debug!(
+            "linked {} files, freeing {} bytes",
+            stats.files_linked, stats.bytes_freed
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::call_store::{call_all, CallStore, DAEMON_STORE_METHODS};
+    use crate::store_path::StorePath;
+
+    #[test]
+    fn test_scan_for_references() {
+        let a = StorePath::new_from_base_name("7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-a").unwrap();
+        let b = StorePath::new_from_base_name("ivz5kvk528akza21x33r8jn2wl8bpsw3-b").unwrap();
+        let mut candidates = StorePathSet::new();
+        candidates.insert(a.clone());
+        candidates.insert(b);
+        let nar = b"#!/bin/sh\nexec /nix/store/7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-a/bin/a\n";
+        let mut expected = StorePathSet::new();
+        expected.insert(a);
+        assert_eq!(scan_for_references(nar, &candidates), expected);
+    }
+
+    #[test]
+    fn test_build_env_recursive_nix() {
+        let drv = BasicDerivation {
+            outputs: Default::default(),
+            input_srcs: Default::default(),
+            platform: "x86_64-linux".into(),
+            builder: "/bin/sh".into(),
+            arguments: Vec::new(),
+            env: vec![("requiredSystemFeatures".into(), "recursive-nix".into())],
+            name: "hello".into(),
+        };
+        let build_dir = Path::new("/tmp/nix-build-hello");
+        let env = build_env(&StoreDir::default(), &drv, build_dir, None);
+        assert!(!env.iter().any(|(name, _)| name == "NIX_REMOTE"));
+        let socket = build_dir.join(".nix-socket");
+        let env = build_env(&StoreDir::default(), &drv, build_dir, Some(&socket));
+        assert!(env.contains(&(
+            "NIX_REMOTE".to_owned(),
+            "unix:///tmp/nix-build-hello/.nix-socket".to_owned()
+        )));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_wait_builder() {
+        let sleeping = Command::new("/bin/sh")
+            .args(["-c", "sleep 1; exit 3"])
+            .spawn()
+            .unwrap();
+        let busy = Command::new("/bin/sh")
+            .args(["-c", "i=0; while [ $i -lt 300000 ]; do i=$((i+1)); done"])
+            .spawn()
+            .unwrap();
+        let (sleeping, busy) = tokio::join!(wait_builder(sleeping), wait_builder(busy));
+        let (status, sleeping) = sleeping.unwrap();
+        assert_eq!(status.code(), Some(3));
+        let (status, busy) = busy.unwrap();
+        assert!(status.success());
+
+        // The busy builder finished while the other one was sleeping and
+        // is not counted for it.
+        let total = |(user, system): (Duration, Duration)| user + system;
+        assert!(total(sleeping.unwrap()) < total(busy.unwrap()));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_create_build_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = tempfile::tempdir().unwrap();
+        let first = create_build_dir(root.path(), "hello").await.unwrap();
+        let second = create_build_dir(root.path(), "hello").await.unwrap();
+        assert_ne!(first, second);
+        let name = first.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("nix-build-hello-"));
+        let mode = std::fs::metadata(&first).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+    }
+
+    /// Whether this machine lets unprivileged users make the namespaces of
+    /// the sandbox.
+    fn can_unshare() -> bool {
+        Command::new("unshare")
+            .args([
+                "--user",
+                "--map-root-user",
+                "--mount",
+                "--pid",
+                "--fork",
+                "true",
+            ])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .map(|status| status.success())
+            .unwrap_or(false)
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_build_sandboxed() {
+        if !can_unshare() {
+            eprintln!("skipping test_build_sandboxed, unshare is not available");
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let secret = dir.path().join("secret");
+        std::fs::write(&secret, "secret").unwrap();
+        let store_dir = StoreDir::new(dir.path().join("store")).unwrap();
+        std::fs::create_dir(store_dir.to_str()).unwrap();
+        let mut store = crate::store::MemoryStore::with_store_dir(store_dir.clone());
+
+        let input = StorePath::test_from_seed("input");
+        let input_path = store_dir.print_path(&input);
+        std::fs::write(&input_path, "hello\n").unwrap();
+        let nar = dump_nar(Path::new(&input_path)).await.unwrap();
+        let mut info = ValidPathInfo::new(input.clone(), hash_of(Algorithm::SHA256, &nar));
+        info.nar_size = nar.len() as u64;
+        register_valid_path(&mut store, info, &nar[..], RepairFlag::NoRepair, None)
+            .await
+            .unwrap();
+
+        let out = StorePath::test_from_seed("hello");
+        let script = format!(
+            "set -e
+            [ $$ = 1 ]
+            [ -e /proc/self/stat ]
+            [ ! -e {secret} ]
+            ! echo changed > {input}
+            read line < {input}
+            echo \"$line\" > $out",
+            secret = secret.display(),
+            input = input_path,
+        );
+        let drv = BasicDerivation {
+            outputs: [(
+                "out".to_owned(),
+                DerivationOutput::InputAddressed(out.clone()),
+            )]
+            .into_iter()
+            .collect(),
+            input_srcs: [input].into_iter().collect(),
+            platform: "x86_64-linux".into(),
+            builder: "/bin/sh".into(),
+            arguments: vec!["-c".into(), script],
+            env: vec![("out".into(), store_dir.print_path(&out))],
+            name: "hello".into(),
+        };
+        let system_paths = ["/bin", "/lib", "/lib64", "/usr"]
+            .into_iter()
+            .filter(|path| Path::new(path).exists());
+        let mut builder = LocalBuilder::new(store)
+            .build_root(dir.path().join("build"))
+            .sandbox(Sandbox::UserNamespace)
+            .sandbox_paths(system_paths);
+
+        let drv_path = StorePath::test_from_seed("hello.drv");
+        let result = builder
+            .build(&drv_path, &drv, BuildMode::Normal)
+            .await
+            .unwrap();
+        assert!(result.success(), "{}", result.error_msg);
+        let out_path = store_dir.print_path(&out);
+        assert_eq!(std::fs::read_to_string(&out_path).unwrap(), "hello\n");
+        assert!(!Path::new(&format!("{}.chroot", store_dir.print_path(&drv_path))).exists());
+        let mut store = builder.into_inner();
+        let info = store.query_path_info(&out).await.unwrap().unwrap();
+        assert_eq!(info.deriver, Some(drv_path));
+    }
+
+    #[tokio::test]
+    async fn test_forwards_daemon_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store_dir = StoreDir::new(dir.path()).unwrap();
+        let mut builder = LocalBuilder::new(CallStore::new(store_dir));
+        for (method, res) in call_all(&mut builder).await {
+            assert!(res.is_ok(), "{method}: {res:?}");
+        }
+        let store = builder.into_inner();
+        // The builder optimises the store directory itself.
+        let forwarded: Vec<_> = DAEMON_STORE_METHODS
+            .iter()
+            .copied()
+            .filter(|method| !cfg!(unix) || *method != "optimise_store")
+            .collect();
+        assert!(store.called_all(&forwarded));
+    }
+}
@@ -0,0 +1,243 @@
+use thiserror::Error;
+
+use crate::StringSet;
+
+#[derive(Error, Debug, PartialEq, Eq, Clone)]
+pub enum ParseMachineError {
+    #[error("bad machine specification: bad max jobs value '{0}'")]
+    BadMaxJobs(String),
+    #[error("bad machine specification: bad speed factor '{0}'")]
+    BadSpeedFactor(String),
+    #[error("bad machine specification: bad public host key '{0}'")]
+    BadPublicHostKey(String),
+    #[error("bad machine specification: expected at most 8 fields in '{0}'")]
+    TooManyFields(String),
+}
+
+/// A remote builder as described by a line in Nix's `machines` file.
+///
+/// Each line has up to 8 whitespace separated fields:
+/// `uri systems ssh-key max-jobs speed-factor supported-features mandatory-features public-host-key`
+/// where a missing field or `-` selects the default value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Machine {
+    pub store_uri: String,
+    pub systems: StringSet,
+    pub ssh_key: Option<String>,
+    pub max_jobs: u64,
+    pub speed_factor: f64,
+    pub supported_features: StringSet,
+    pub mandatory_features: StringSet,
+    pub ssh_public_host_key: Option<String>,
+}
+
+impl Machine {
+    pub fn new<U: Into<String>>(store_uri: U, systems: StringSet) -> Machine {
+        Machine {
+            store_uri: store_uri.into(),
+            systems,
+            ssh_key: None,
+            max_jobs: 1,
+            speed_factor: 1.0,
+            supported_features: StringSet::new(),
+            mandatory_features: StringSet::new(),
+            ssh_public_host_key: None,
+        }
+    }
+
+    /// Whether this machine can build for `system`. The `builtin` system is
+    /// supported everywhere.
+    pub fn system_supported(&self, system: &str) -> bool {
+        system == "builtin" || self.systems.contains(system)
+    }
+
+    /// Whether this machine supports all the given features.
+    pub fn all_supported(&self, features: &StringSet) -> bool {
+        features
+            .iter()
+            .all(|f| self.supported_features.contains(f) || self.mandatory_features.contains(f))
+    }
+
+    /// Whether the given features include all the features that this
+    /// machine requires a derivation to ask for.
+    pub fn mandatory_met(&self, features: &StringSet) -> bool {
+        self.mandatory_features.is_subset(features)
+    }
+
+    /// Whether a derivation for `system` that requires `features` can be
+    /// built on this machine.
+    pub fn can_build(&self, system: &str, features: &StringSet) -> bool {
+        self.max_jobs > 0
+            && self.system_supported(system)
+            && self.all_supported(features)
+            && self.mandatory_met(features)
+    }
+
+    /// Parse a single machine specification. Systems default to
+    /// `default_system` when not given.
+    pub fn parse(s: &str, default_system: &str) -> Result<Machine, ParseMachineError> {
+        let tokens: Vec<&str> = s.split_whitespace().collect();
+        if tokens.len() > 8 {
+            return Err(ParseMachineError::TooManyFields(s.into()));
+        }
+        let field = |idx: usize| {
+            tokens
+                .get(idx)
+                .copied()
+                .filter(|t| !t.is_empty() && *t != "-")
+        };
+        let set = |idx: usize| -> StringSet {
+            field(idx)
+                .map(|t| {
+                    t.split(',')
+                        .filter(|s| !s.is_empty())
+                        .map(String::from)
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+
+        let store_uri = match field(0) {
+            Some(uri) if uri.contains("://") || uri == "auto" || uri == "local" => uri.to_owned(),
+            Some(uri) => format!("ssh://{}", uri),
+            None => String::new(),
+        };
+        let mut systems = set(1);
+        if systems.is_empty() {
+            systems.insert(default_system.to_owned());
+        }
+        let ssh_key = field(2).map(String::from);
+        let max_jobs = match field(3) {
+            Some(t) => t
+                .parse()
+                .map_err(|_| ParseMachineError::BadMaxJobs(t.into()))?,
+            None => 1,
+        };
+        let speed_factor = match field(4) {
+            Some(t) => {
+                let factor: f64 = t
+                    .parse()
+                    .map_err(|_| ParseMachineError::BadSpeedFactor(t.into()))?;
+                if factor.is_nan() || factor <= 0.0 {
+                    return Err(ParseMachineError::BadSpeedFactor(t.into()));
+                }
+                factor
+            }
+            None => 1.0,
+        };
+        let mut supported_features = set(5);
+        let mandatory_features = set(6);
+        supported_features.extend(mandatory_features.iter().cloned());
+        let ssh_public_host_key = match field(7) {
+            Some(t) => {
+                base64::decode(t).map_err(|_| ParseMachineError::BadPublicHostKey(t.into()))?;
+                Some(t.to_owned())
+            }
+            None => None,
+        };
+        Ok(Machine {
+            store_uri,
+            systems,
+            ssh_key,
+            max_jobs,
+            speed_factor,
+            supported_features,
+            mandatory_features,
+            ssh_public_host_key,
+        })
+    }
+}
+
+/// Parse the contents of a `machines` file.
+///
+/// Machines are separated by newlines or `;` and everything after a `#` is
+/// a comment.
+pub fn parse_machines(s: &str, default_system: &str) -> Result<Vec<Machine>, ParseMachineError> {
+    let mut machines = Vec::new();
+    for line in s.split(['\n', ';']) {
+        let line = match line.find('#') {
+            Some(pos) => &line[..pos],
+            None => line,
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        machines.push(Machine::parse(line, default_system)?);
+    }
+    Ok(machines)
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::string_set;
+
+    #[test]
+    fn test_parse_machine_full() {
+        let m = Machine::parse(
+            "nix@scratchy.labs.cs.uu.nl i686-linux,x86_64-linux /home/nix/.ssh/id_scratchy 8 2 kvm,nixos-test big-parallel AAAAC3NzaC1lZDI1NTE5",
+            "aarch64-linux",
+        )
+        .unwrap();
+        assert_eq!(
+            m,
+            Machine {
+                store_uri: "ssh://nix@scratchy.labs.cs.uu.nl".into(),
+                systems: string_set!["i686-linux", "x86_64-linux"],
+                ssh_key: Some("/home/nix/.ssh/id_scratchy".into()),
+                max_jobs: 8,
+                speed_factor: 2.0,
+                supported_features: string_set!["kvm", "nixos-test", "big-parallel"],
+                mandatory_features: string_set!["big-parallel"],
+                ssh_public_host_key: Some("AAAAC3NzaC1lZDI1NTE5".into()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_machine_defaults() {
+        let m = Machine::parse("ssh-ng://builder - - - - kvm", "x86_64-linux").unwrap();
+        let mut expected = Machine::new("ssh-ng://builder", string_set!["x86_64-linux"]);
+        expected.supported_features = string_set!["kvm"];
+        assert_eq!(m, expected);
+    }
+
+    #[test]
+    fn test_parse_machine_bad() {
+        assert_eq!(
+            Machine::parse("builder x86_64-linux - many", ""),
+            Err(ParseMachineError::BadMaxJobs("many".into()))
+        );
+        assert_eq!(
+            Machine::parse("builder x86_64-linux - 1 0", ""),
+            Err(ParseMachineError::BadSpeedFactor("0".into()))
+        );
+    }
+
+    #[test]
+    fn test_parse_machines() {
+        let machines = parse_machines(
+            "# remote builders\nbuilder1 x86_64-linux ; builder2 aarch64-linux # arm\n\n",
+            "x86_64-linux",
+        )
+        .unwrap();
+        assert_eq!(machines.len(), 2);
+        assert_eq!(machines[0].store_uri, "ssh://builder1");
+        assert_eq!(machines[1].systems, string_set!["aarch64-linux"]);
+    }
+
+    #[test]
+    fn test_machine_can_build() {
+        let mut m = Machine::new("ssh://builder", string_set!["x86_64-linux"]);
+        m.supported_features = string_set!["kvm", "big-parallel"];
+        m.mandatory_features = string_set!["big-parallel"];
+        assert!(m.can_build("x86_64-linux", &string_set!["big-parallel"]));
+        assert!(m.can_build("builtin", &string_set!["kvm", "big-parallel"]));
+        assert!(!m.can_build("x86_64-linux", &string_set!["kvm"]));
+        assert!(!m.can_build("aarch64-linux", &string_set!["big-parallel"]));
+        assert!(!m.can_build("x86_64-linux", &string_set!["big-parallel", "cuda"]));
+    }
+}
@@ -0,0 +1,7 @@
+pub mod local;
+pub mod machines;
+pub mod scheduler;
+
+pub use local::{LocalBuilder, Sandbox};
+pub use machines::{parse_machines, Machine, ParseMachineError};
+pub use scheduler::{BuildScheduler, RemoteBuilder};
@@ -0,0 +1,580 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{Mutex, Notify};
+use tracing::{debug, instrument};
+
+use super::Machine;
+use crate::path_info::ValidPathInfo;
+use crate::store::{
+    compute_fs_closure_slow, copy_paths, BasicDerivation, BuildMode, BuildResult,
+    CheckSignaturesFlag, DerivationLoader, DerivedPath, Error, RepairFlag, SingleDerivedPath,
+    Store, SubstituteFlag,
+};
+use crate::store_path::{StoreDir, StoreDirProvider, StorePath, StorePathSet};
+use crate::StringSet;
+
+/// The features a derivation requires from the machine building it, taken
+/// from its `requiredSystemFeatures` attribute.
+pub fn required_features(drv: &BasicDerivation) -> StringSet {
+    drv.env
+        .iter()
+        .find(|(name, _)| name == "requiredSystemFeatures")
+        .map(|(_, value)| value.split_whitespace().map(String::from).collect())
+        .unwrap_or_default()
+}
+
+/// A configured remote builder and the store used to talk to it.
+///
+/// Builds on a single builder are sent over the same store connection one
+/// at a time, so configure a `RemoteBuilder` per connection when a machine
+/// should run several jobs at once.
+pub struct RemoteBuilder<S> {
+    machine: Machine,
+    store: Mutex<S>,
+    current_jobs: AtomicU64,
+}
+
+impl<S> RemoteBuilder<S> {
+    pub fn new(machine: Machine, store: S) -> RemoteBuilder<S> {
+        RemoteBuilder {
+            machine,
+            store: Mutex::new(store),
+            current_jobs: AtomicU64::new(0),
+        }
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    /// Number of builds currently scheduled on this builder.
+    pub fn current_jobs(&self) -> u64 {
+        self.current_jobs.load(Ordering::SeqCst)
+    }
+
+    fn load(&self) -> f64 {
+        self.current_jobs() as f64 / self.machine.speed_factor
+    }
+}
+
+struct JobGuard<'a, S> {
+    builder: &'a RemoteBuilder<S>,
+    notify: &'a Notify,
+}
+
+impl<S> Drop for JobGuard<'_, S> {
+    fn drop(&mut self) {
+        self.builder.current_jobs.fetch_sub(1, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+}
+
+struct Inner<L, S> {
+    local: Mutex<L>,
+    builders: Vec<RemoteBuilder<S>>,
+    job_finished: Notify,
+}
+
+/// Dispatches derivation builds to a set of remote builders.
+///
+/// Paths are queried from and added to the local store. When a derivation is
+/// built the closure of its inputs is copied from the local store to the
+/// least loaded builder that supports its system and features and the
+/// outputs are copied back afterwards. Builds wait for a slot when every
+/// suitable builder is busy.
+///
+/// The scheduler is cheap to clone and all clones share the same builders
+/// and load information.
+pub struct BuildScheduler<L, S> {
+    store_dir: StoreDir,
+    inner: Arc<Inner<L, S>>,
+}
+
+impl<L, S> Clone for BuildScheduler<L, S> {
+    fn clone(&self) -> Self {
+        BuildScheduler {
+            store_dir: self.store_dir.clone(),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<L, S> BuildScheduler<L, S>
+where
+    L: Store + Send + Unpin,
+    S: Store + Send,
+{
+    pub fn new(local: L, builders: Vec<RemoteBuilder<S>>) -> BuildScheduler<L, S> {
+        BuildScheduler {
+            store_dir: local.store_dir(),
+            inner: Arc::new(Inner {
+                local: Mutex::new(local),
+                builders,
+                job_finished: Notify::new(),
+            }),
+        }
+    }
+
+    pub fn builders(&self) -> &[RemoteBuilder<S>] {
+        &self.inner.builders
+    }
+
+    fn try_reserve(&self, system: &str, features: &StringSet) -> Option<JobGuard<'_, S>> {
+        let mut candidates: Vec<&RemoteBuilder<S>> = self
+            .inner
+            .builders
+            .iter()
+            .filter(|b| b.machine.can_build(system, features))
+            .collect();
+        candidates.sort_by(|a, b| {
+            a.load()
+                .partial_cmp(&b.load())
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(a.current_jobs().cmp(&b.current_jobs()))
+        });
+        for builder in candidates {
+            let max_jobs = builder.machine.max_jobs;
+            if builder
+                .current_jobs
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |jobs| {
+                    (jobs < max_jobs).then_some(jobs + 1)
+                })
+                .is_ok()
+            {
+                return Some(JobGuard {
+                    builder,
+                    notify: &self.inner.job_finished,
+                });
+            }
+        }
+        None
+    }
+
+    async fn reserve(
+        &self,
+        drv_path: &StorePath,
+        system: &str,
+        features: &StringSet,
+    ) -> Result<JobGuard<'_, S>, Error> {
+        if !self
+            .inner
+            .builders
+            .iter()
+            .any(|b| b.machine.can_build(system, features))
+        {
+            return Err(Error::NoMatchingBuilder(
+                self.store_dir.print_path(drv_path),
+                system.to_owned(),
+            ));
+        }
+        loop {
+            let notified = self.inner.job_finished.notified();
+            if let Some(guard) = self.try_reserve(system, features) {
+                return Ok(guard);
+            }
+            debug!("waiting for a free builder for {}", drv_path);
+            notified.await;
+        }
+    }
+
+    /// Build a derivation on one of the remote builders.
+    #[instrument(skip(self, drv), fields(system = %drv.platform))]
+    pub async fn schedule_derivation(
+        &self,
+        drv_path: &StorePath,
+        drv: &BasicDerivation,
+        build_mode: BuildMode,
+    ) -> Result<BuildResult, Error> {
+        let features = required_features(drv);
+        let guard = self.reserve(drv_path, &drv.platform, &features).await?;
+        let builder = guard.builder;
+        debug!(
+            "building {} on {}",
+            self.store_dir.print_path(drv_path),
+            builder.machine.store_uri
+        );
+
+        {
+            let mut local = self.inner.local.lock().await;
+            let inputs = compute_fs_closure_slow(&mut *local, &drv.input_srcs, false).await?;
+            let mut remote = builder.store.lock().await;
+            copy_paths(&mut *local, &mut *remote, &inputs).await?;
+        }
+
+        let result = {
+            let mut remote = builder.store.lock().await;
+            remote.build_derivation(drv_path, drv, build_mode).await?
+        };
+        drop(guard);
+
+        if result.success() {
+            let mut outputs: StorePathSet = result
+                .built_outputs
+                .values()
+                .map(|realisation| realisation.out_path.clone())
+                .collect();
+            for (_, out_path) in drv.outputs_and_opt_paths(&self.store_dir)?.into_values() {
+                outputs.extend(out_path);
+            }
+            let mut local = self.inner.local.lock().await;
+            let mut remote = builder.store.lock().await;
+            copy_paths(&mut *remote, &mut *local, &outputs).await?;
+        }
+        Ok(result)
+    }
+
+    /// Build a derivation and every derivation in its closure whose outputs
+    /// are not already valid in the local store.
+    ///
+    /// With `BuildMode::Check` only `drv_path` itself is rebuilt and checked,
+    /// its dependencies are only built when their outputs are missing.
+    #[instrument(skip(self))]
+    pub async fn schedule_closure(
+        &self,
+        drv_path: &StorePath,
+        build_mode: BuildMode,
+    ) -> Result<(), Error> {
+        let graph = {
+            let mut local = self.inner.local.lock().await;
+            DerivationLoader::new(&mut *local)
+                .load_closure(drv_path)
+                .await?
+        };
+        for path in graph.topo_sorted() {
+            let drv = graph.get(path).unwrap();
+            let mut outputs = StorePathSet::new();
+            for (name, output) in drv.basic.outputs.iter() {
+                let out_path = output
+                    .path(&self.store_dir, &drv.basic.name, name)?
+                    .ok_or_else(|| {
+                        Error::UnsupportedOperation(format!(
+                            "scheduling '{}' with floating outputs",
+                            self.store_dir.print_path(path)
+                        ))
+                    })?;
+                outputs.insert(out_path);
+            }
+            let valid = {
+                let mut local = self.inner.local.lock().await;
+                local
+                    .query_valid_paths(&outputs, SubstituteFlag::NoSubstitute)
+                    .await?
+            };
+            let mode = if build_mode == BuildMode::Check && path != drv_path {
+                BuildMode::Normal
+            } else {
+                build_mode
+            };
+            if valid == outputs && mode != BuildMode::Check {
+                continue;
+            }
+
+            let mut basic = drv.basic.clone();
+            for (input, wanted) in drv.input_drvs.iter() {
+                let input_drv = graph.get(input).unwrap();
+                for name in wanted {
+                    let output = input_drv.basic.outputs.get(name).ok_or_else(|| {
+                        Error::Misc(format!(
+                            "derivation '{}' has no output '{}'",
+                            self.store_dir.print_path(input),
+                            name
+                        ))
+                    })?;
+                    if let Some(out_path) =
+                        output.path(&self.store_dir, &input_drv.basic.name, name)?
+                    {
+                        basic.input_srcs.insert(out_path);
+                    }
+                }
+            }
+            let result = self.schedule_derivation(path, &basic, mode).await?;
+            if !result.success() {
+                return Err(Error::BuildFailed(
+                    self.store_dir.print_path(path),
+                    result.error_msg,
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<L, S> StoreDirProvider for BuildScheduler<L, S> {
+    fn store_dir(&self) -> StoreDir {
+        self.store_dir.clone()
+    }
+}
+
+#[async_trait]
+impl<L, S> Store for BuildScheduler<L, S>
+where
+    L: Store + Send + Unpin,
+    S: Store + Send,
+{
+    async fn query_valid_paths(
+        &mut self,
+        paths: &StorePathSet,
+        maybe_substitute: SubstituteFlag,
+    ) -> Result<StorePathSet, Error> {
+        let mut local = self.inner.local.lock().await;
+        local.query_valid_paths(paths, maybe_substitute).await
+    }
+
+    async fn query_path_info(&mut self, path: &StorePath) -> Result<Option<ValidPathInfo>, Error> {
+        let mut local = self.inner.local.lock().await;
+        local.query_path_info(path).await
+    }
+
+    async fn nar_from_path<W: AsyncWrite + fmt::Debug + Send + Unpin>(
+        &mut self,
+        path: &StorePath,
+        sink: W,
+    ) -> Result<(), Error> {
+        let mut local = self.inner.local.lock().await;
+        local.nar_from_path(path, sink).await
+    }
+
+    async fn add_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        info: &ValidPathInfo,
+        source: R,
+        repair: RepairFlag,
+        check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error> {
+        let mut local = self.inner.local.lock().await;
+        local.add_to_store(info, source, repair, check_sigs).await
+    }
+
+    async fn build_derivation(
+        &mut self,
+        drv_path: &StorePath,
+        drv: &BasicDerivation,
+        build_mode: BuildMode,
+    ) -> Result<BuildResult, Error> {
+        self.schedule_derivation(drv_path, drv, build_mode).await
+    }
+
+    async fn build_paths(
+        &mut self,
+        drv_paths: &[DerivedPath],
+        build_mode: BuildMode,
+    ) -> Result<(), Error> {
+        for path in drv_paths {
+            match path {
+                DerivedPath::Opaque(path) => {
+                    if self.query_path_info(path).await?.is_none() {
+                        return Err(Error::InvalidPath(self.store_dir.print_path(path)));
+                    }
+                }
+                DerivedPath::Built {
+                    drv_path: SingleDerivedPath::Opaque(drv_path),
+                    ..
+                } => self.schedule_closure(drv_path, build_mode).await?,
+                DerivedPath::Built { .. } => {
+                    return Err(Error::UnsupportedOperation(format!(
+                        "scheduling dynamic derivation '{}'",
+                        path.print(&self.store_dir)
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex as StdMutex;
+
+    use bytes::{Bytes, BytesMut};
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::archive::{NAREvent, NAR_VERSION_MAGIC_1};
+    use crate::hash;
+    use crate::store::{BuildStatus, MemoryStore};
+    use crate::string_set;
+
+    type Builds = Arc<StdMutex<Vec<(StorePath, BuildMode)>>>;
+
+    struct RecordingStore {
+        inner: MemoryStore,
+        builds: Builds,
+    }
+
+    impl StoreDirProvider for RecordingStore {
+        fn store_dir(&self) -> StoreDir {
+            self.inner.store_dir()
+        }
+    }
+
+    #[async_trait]
+    impl Store for RecordingStore {
+        async fn query_path_info(
+            &mut self,
+            path: &StorePath,
+        ) -> Result<Option<ValidPathInfo>, Error> {
+            self.inner.query_path_info(path).await
+        }
+
+        async fn nar_from_path<W: AsyncWrite + fmt::Debug + Send + Unpin>(
+            &mut self,
+            path: &StorePath,
+            sink: W,
+        ) -> Result<(), Error> {
+            self.inner.nar_from_path(path, sink).await
+        }
+
+        async fn add_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+            &mut self,
+            info: &ValidPathInfo,
+            source: R,
+            repair: RepairFlag,
+            check_sigs: CheckSignaturesFlag,
+        ) -> Result<(), Error> {
+            self.inner
+                .add_to_store(info, source, repair, check_sigs)
+                .await
+        }
+
+        async fn build_derivation(
+            &mut self,
+            drv_path: &StorePath,
+            drv: &BasicDerivation,
+            build_mode: BuildMode,
+        ) -> Result<BuildResult, Error> {
+            self.builds
+                .lock()
+                .unwrap()
+                .push((drv_path.clone(), build_mode));
+            let store_dir = self.store_dir();
+            for (_, out_path) in drv.outputs_and_opt_paths(&store_dir)?.into_values() {
+                add(&mut self.inner, &out_path.unwrap(), b"built").await;
+            }
+            Ok(BuildResult::new(BuildStatus::Built, String::new()))
+        }
+    }
+
+    async fn add(store: &mut MemoryStore, path: &StorePath, nar: &[u8]) {
+        let mut info = ValidPathInfo::new(path.clone(), hash::digest(hash::Algorithm::SHA256, nar));
+        info.nar_size = nar.len() as u64;
+        store
+            .add_to_store(
+                &info,
+                nar,
+                RepairFlag::NoRepair,
+                CheckSignaturesFlag::NoCheckSigs,
+            )
+            .await
+            .unwrap();
+    }
+
+    /// Adds a derivation named `name` with inputs `inputs` and returns
+    /// its path.
+    async fn add_drv(store: &mut MemoryStore, name: &str, inputs: &[&StorePath]) -> StorePath {
+        let store_dir = store.store_dir();
+        let out = store_dir.print_path(&StorePath::test_from_seed(name));
+        let inputs = inputs
+            .iter()
+            .map(|i| format!("(\"{}\",[\"out\"])", store_dir.print_path(i)))
+            .collect::<Vec<_>>()
+            .join(",");
+        let drv = format!(
+            "Derive([(\"out\",\"{}\",\"\",\"\")],[{}],[],\"x86_64-linux\",\"/bin/sh\",[],[(\"name\",\"{}\")])",
+            out, inputs, name
+        );
+        let size = drv.len() as u64;
+        let mut nar = BytesMut::new();
+        NAREvent::Magic(Arc::new(NAR_VERSION_MAGIC_1.into())).encode_into(&mut nar);
+        NAREvent::RegularNode {
+            executable: false,
+            size,
+            offset: 0,
+        }
+        .encode_into(&mut nar);
+        NAREvent::Contents {
+            total: size,
+            index: 0,
+            buf: Bytes::from(drv),
+        }
+        .encode_into(&mut nar);
+        let path = StorePath::test_from_seed(&format!("{}.drv", name));
+        add(store, &path, &nar).await;
+        path
+    }
+
+    fn scheduler(local: MemoryStore) -> (BuildScheduler<MemoryStore, RecordingStore>, Builds) {
+        let builds = Builds::default();
+        let remote = RecordingStore {
+            inner: MemoryStore::new(),
+            builds: builds.clone(),
+        };
+        let machine = Machine::new("test", string_set!["x86_64-linux"]);
+        let scheduler = BuildScheduler::new(local, vec![RemoteBuilder::new(machine, remote)]);
+        (scheduler, builds)
+    }
+
+    #[tokio::test]
+    async fn test_schedule_closure() {
+        let mut local = MemoryStore::new();
+        let dep = add_drv(&mut local, "dep", &[]).await;
+        let root = add_drv(&mut local, "root", &[&dep]).await;
+        let (scheduler, builds) = scheduler(local);
+
+        scheduler
+            .schedule_closure(&root, BuildMode::Normal)
+            .await
+            .unwrap();
+        assert_eq!(
+            *builds.lock().unwrap(),
+            vec![(dep, BuildMode::Normal), (root.clone(), BuildMode::Normal)]
+        );
+        let outputs: StorePathSet = [
+            StorePath::test_from_seed("dep"),
+            StorePath::test_from_seed("root"),
+        ]
+        .into_iter()
+        .collect();
+        let valid = scheduler
+            .clone()
+            .query_valid_paths(&outputs, SubstituteFlag::NoSubstitute)
+            .await
+            .unwrap();
+        assert_eq!(valid, outputs);
+
+        builds.lock().unwrap().clear();
+        scheduler
+            .schedule_closure(&root, BuildMode::Normal)
+            .await
+            .unwrap();
+        assert_eq!(*builds.lock().unwrap(), vec![]);
+    }
+
+    #[tokio::test]
+    async fn test_schedule_closure_check_root_only() {
+        let mut local = MemoryStore::new();
+        let dep = add_drv(&mut local, "dep", &[]).await;
+        let root = add_drv(&mut local, "root", &[&dep]).await;
+        let (scheduler, builds) = scheduler(local);
+
+        scheduler
+            .schedule_closure(&root, BuildMode::Check)
+            .await
+            .unwrap();
+        assert_eq!(
+            *builds.lock().unwrap(),
+            vec![(dep, BuildMode::Normal), (root.clone(), BuildMode::Check)]
+        );
+
+        builds.lock().unwrap().clear();
+        scheduler
+            .schedule_closure(&root, BuildMode::Check)
+            .await
+            .unwrap();
+        assert_eq!(*builds.lock().unwrap(), vec![(root, BuildMode::Check)]);
+    }
+}
@@ -0,0 +1,307 @@
+use std::path::Path;
+
+use tokio::process::Command;
+use tracing::debug;
+
+use super::{dump_nar, Fetcher, TempDir};
+use crate::archive::PathFilter;
+use crate::hash;
+use crate::store::{Error, Store};
+use crate::store_path::{FileIngestionMethod, StorePath};
+
+/// A git repository to fetch, like a `git` flake input.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GitInput {
+    pub url: String,
+    /// The branch or tag to fetch. `HEAD` is fetched when neither this nor
+    /// `rev` is set.
+    pub reference: Option<String>,
+    /// The commit to fetch, as a full 40 digit hash.
+    pub rev: Option<String>,
+    pub submodules: bool,
+}
+
+impl GitInput {
+    pub fn new<S: Into<String>>(url: S) -> GitInput {
+        GitInput {
+            url: url.into(),
+            ..Default::default()
+        }
+    }
+}
+
+/// The store path a git revision was fetched into.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GitFetchResult {
+    pub path: StorePath,
+    /// The commit that was fetched.
+    pub rev: String,
+    /// The commit time of `rev` in seconds since the epoch, when it is
+    /// known.
+    pub last_modified: Option<u64>,
+}
+
+async fn git(dir: &Path, args: &[&str]) -> Result<String, Error> {
+    debug!(?args, "running git");
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(Error::Misc(format!(
+            "running 'git {}' failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+impl Fetcher {
+    /// Make a shallow clone of `input` and add its files, without `.git`,
+    /// to `store` as a NAR.
+    pub async fn fetch_git<S>(
+        &self,
+        store: &mut S,
+        input: &GitInput,
+    ) -> Result<GitFetchResult, Error>
+    where
+        S: Store + ?Sized,
+    {
+        let dir = TempDir::new().await?;
+        let res = self.fetch_git_into(store, input, dir.path()).await;
+        dir.remove().await;
+        res
+    }
+
+    async fn fetch_git_into<S>(
+        &self,
+        store: &mut S,
+        input: &GitInput,
+        dir: &Path,
+    ) -> Result<GitFetchResult, Error>
+    where
+        S: Store + ?Sized,
+    {
+        if let Some(rev) = input.rev.as_deref() {
+            if !is_commit_hash(rev) {
+                return Err(Error::Misc(format!(
+                    "git revision '{}' is not a full commit hash",
+                    rev
+                )));
+            }
+        }
+        if let Some(reference) = input.reference.as_deref() {
+            check_reference(reference)?;
+        }
+        let wanted = input
+            .rev
+            .as_deref()
+            .or(input.reference.as_deref())
+            .unwrap_or("HEAD");
+        git(dir, &["init", "--quiet"]).await?;
+        git(
+            dir,
+            &["fetch", "--quiet", "--depth", "1", "--", &input.url, wanted],
+        )
+        .await?;
+        git(
+            dir,
+            &[
+                "-c",
+                "advice.detachedHead=false",
+                "checkout",
+                "--quiet",
+                "FETCH_HEAD",
+            ],
+        )
+        .await?;
+        if input.submodules {
+            git(
+                dir,
+                &[
+                    "submodule",
+                    "update",
+                    "--quiet",
+                    "--init",
+                    "--recursive",
+                    "--depth",
+                    "1",
+                ],
+            )
+            .await?;
+        }
+        let rev = git(dir, &["rev-parse", "HEAD"]).await?;
+        if let Some(expected) = input.rev.as_ref() {
+            if !rev.eq_ignore_ascii_case(expected) {
+                return Err(Error::Misc(format!(
+                    "fetching '{}' gave revision {} instead of {}",
+                    input.url, rev, expected
+                )));
+            }
+        }
+        let last_modified = git(dir, &["log", "-1", "--format=%ct", "HEAD"])
+            .await?
+            .parse()
+            .ok();
+
+        let filter = PathFilter::new(dir).exclude(".git");
+        let nar = dump_nar(dir.to_owned(), filter).await?;
+        let hash = hash::digest(self.hash_algo, &nar);
+        let name = self.name.clone().unwrap_or_else(|| "source".into());
+        let fetcher = Fetcher {
+            method: FileIngestionMethod::Recursive,
+            ..self.clone()
+        };
+        let url = git_url(&input.url)?;
+        let res = fetcher.add(store, &url, &name, hash, nar).await?;
+        Ok(GitFetchResult {
+            path: res.path,
+            rev,
+            last_modified,
+        })
+    }
+
+    /// Fetch `reference`, a branch, tag or commit, of a GitHub repository
+    /// through its tarball.
+    ///
+    /// The commit time isn't part of the tarball so `last_modified` is not
+    /// set.
+    #[cfg(feature = "compress-tools")]
+    pub async fn fetch_github<S>(
+        &self,
+        store: &mut S,
+        owner: &str,
+        repo: &str,
+        reference: &str,
+    ) -> Result<GitFetchResult, Error>
+    where
+        S: Store + ?Sized,
+    {
+        let repo_url = format!("https://github.com/{}/{}.git", owner, repo);
+        let rev = if is_commit_hash(reference) {
+            reference.to_owned()
+        } else {
+            check_reference(reference)?;
+            let dir = TempDir::new().await?;
+            let res = git(dir.path(), &["ls-remote", "--", &repo_url, reference]).await;
+            dir.remove().await;
+            res?.split_whitespace()
+                .next()
+                .map(String::from)
+                .ok_or_else(|| {
+                    Error::Misc(format!("'{}' has no reference '{}'", repo_url, reference))
+                })?
+        };
+        let url = format!(
+            "https://github.com/{}/{}/archive/{}.tar.gz",
+            owner, repo, rev
+        );
+        let res = self.fetch_tarball(store, url.as_str()).await?;
+        Ok(GitFetchResult {
+            path: res.path,
+            rev,
+            last_modified: None,
+        })
+    }
+}
+
+fn is_commit_hash(s: &str) -> bool {
+    s.len() == 40 && s.bytes().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Git would take a reference starting with `-` as an option.
+fn check_reference(reference: &str) -> Result<(), Error> {
+    if reference.starts_with('-') {
+        return Err(Error::Misc(format!(
+            "invalid git reference '{}'",
+            reference
+        )));
+    }
+    Ok(())
+}
+
+/// The URL to report in errors. Local paths are turned into `file` URLs.
+fn git_url(url: &str) -> Result<reqwest::Url, Error> {
+    match reqwest::Url::parse(url) {
+        Ok(url) => Ok(url),
+        Err(_) => reqwest::Url::from_file_path(url)
+            .map_err(|_| Error::Misc(format!("invalid git URL '{}'", url))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::store::MemoryStore;
+
+    #[test]
+    fn test_is_commit_hash() {
+        assert!(is_commit_hash("0123456789abcdef0123456789abcdef01234567"));
+        assert!(!is_commit_hash("main"));
+        assert!(!is_commit_hash("0123456"));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_git_rejects_options() {
+        let mut store = MemoryStore::new();
+        let fetcher = Fetcher::new().unwrap();
+
+        let mut input = GitInput::new("https://example.org/repo.git");
+        input.reference = Some("--upload-pack=touch pwned".into());
+        let err = fetcher.fetch_git(&mut store, &input).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid git reference '--upload-pack=touch pwned'"
+        );
+
+        let mut input = GitInput::new("https://example.org/repo.git");
+        input.rev = Some("0123456".into());
+        let err = fetcher.fetch_git(&mut store, &input).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "git revision '0123456' is not a full commit hash"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch_git_local() {
+        let repo = tempdir().unwrap();
+        let repo_dir = repo.path();
+        git(repo_dir, &["init", "--quiet"]).await.unwrap();
+        std::fs::write(repo_dir.join("README"), "hello").unwrap();
+        git(repo_dir, &["add", "README"]).await.unwrap();
+        git(
+            repo_dir,
+            &[
+                "-c",
+                "user.name=Test",
+                "-c",
+                "user.email=test@example.org",
+                "commit",
+                "--quiet",
+                "-m",
+                "initial",
+            ],
+        )
+        .await
+        .unwrap();
+        let rev = git(repo_dir, &["rev-parse", "HEAD"]).await.unwrap();
+
+        let mut store = MemoryStore::new();
+        let input = GitInput::new(format!("file://{}", repo_dir.display()));
+        let res = Fetcher::new()
+            .unwrap()
+            .fetch_git(&mut store, &input)
+            .await
+            .unwrap();
+        assert_eq!(res.rev, rev);
+        assert!(res.last_modified.is_some());
+        assert_eq!(res.path.name.name(), "source");
+        assert!(store.query_path_info(&res.path).await.unwrap().is_some());
+    }
+}
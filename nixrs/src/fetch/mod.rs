@@ -0,0 +1,366 @@
+//! Fetching URLs into content addressed store paths, like `fetchurl` and
+//! `nix-prefetch-url` do.
+
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use bytes::{Bytes, BytesMut};
+use futures::TryStreamExt;
+use reqwest::{Client, IntoUrl, Url};
+use tokio::fs;
+use tokio::pin;
+use tracing::debug;
+
+use crate::archive::{DumpOptions, Filter, NAREvent, NAR_VERSION_MAGIC_1};
+use crate::hash::{self, Hash};
+use crate::store::{add_ca_to_store, Error, RepairFlag, Store};
+use crate::store_path::{FileIngestionMethod, StorePath};
+
+mod git;
+
+pub use git::{GitFetchResult, GitInput};
+
+/// The store path a URL was fetched into and the hash of its contents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FetchResult {
+    pub path: StorePath,
+    /// The hash that makes up the content address of `path`. For a file
+    /// added with [`FileIngestionMethod::Flat`] this is the hash of the
+    /// file, otherwise it is the hash of the NAR.
+    pub hash: Hash,
+}
+
+/// Downloads files and tarballs into a store.
+#[derive(Debug, Clone)]
+pub struct Fetcher {
+    client: Client,
+    name: Option<String>,
+    expected: Option<Hash>,
+    hash_algo: hash::Algorithm,
+    method: FileIngestionMethod,
+    executable: bool,
+    repair: RepairFlag,
+}
+
+impl Fetcher {
+    pub fn new() -> Result<Fetcher, Error> {
+        let client = Client::builder().build()?;
+        Ok(Fetcher::with_client(client))
+    }
+
+    pub fn with_client(client: Client) -> Fetcher {
+        Fetcher {
+            client,
+            name: None,
+            expected: None,
+            hash_algo: hash::Algorithm::SHA256,
+            method: FileIngestionMethod::Flat,
+            executable: false,
+            repair: RepairFlag::NoRepair,
+        }
+    }
+
+    /// The name of the store path. By default it is the last segment of
+    /// the URL for files and `source` for tarballs.
+    pub fn name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Fail with [`Error::FetchHashMismatch`] unless the fetched contents
+    /// have this hash. It also sets the hash algorithm.
+    pub fn expected_hash(mut self, hash: Hash) -> Self {
+        self.hash_algo = hash.algorithm();
+        self.expected = Some(hash);
+        self
+    }
+
+    /// Like [`expected_hash`](Fetcher::expected_hash) with an SRI hash such
+    /// as `sha256-...`.
+    pub fn expected_sri(self, sri: &str) -> Result<Self, Error> {
+        let hash = Hash::parse_sri(sri).map_err(|err| Error::Misc(err.to_string()))?;
+        Ok(self.expected_hash(hash))
+    }
+
+    pub fn hash_algo(mut self, algo: hash::Algorithm) -> Self {
+        self.hash_algo = algo;
+        self
+    }
+
+    /// Add the file as a NAR instead of as a flat file.
+    pub fn recursive(mut self, recursive: bool) -> Self {
+        self.method = if recursive {
+            FileIngestionMethod::Recursive
+        } else {
+            FileIngestionMethod::Flat
+        };
+        self
+    }
+
+    /// Make the fetched file executable, which implies
+    /// [`recursive`](Fetcher::recursive).
+    pub fn executable(mut self, executable: bool) -> Self {
+        self.executable = executable;
+        if executable {
+            self.method = FileIngestionMethod::Recursive;
+        }
+        self
+    }
+
+    /// Add the result again even when the store already has the path,
+    /// replacing a corrupted copy.
+    pub fn repair(mut self, repair: RepairFlag) -> Self {
+        self.repair = repair;
+        self
+    }
+
+    async fn download(&self, url: &Url) -> Result<Bytes, Error> {
+        debug!(%url, "downloading");
+        let resp = self.client.get(url.clone()).send().await?;
+        let resp = resp.error_for_status()?;
+        Ok(resp.bytes().await?)
+    }
+
+    /// Download the file at `url` and add it to `store`.
+    pub async fn fetch_url<S, U>(&self, store: &mut S, url: U) -> Result<FetchResult, Error>
+    where
+        S: Store + ?Sized,
+        U: IntoUrl,
+    {
+        let url = url.into_url()?;
+        let name = match self.name.as_ref() {
+            Some(name) => name.clone(),
+            None => name_from_url(&url)?,
+        };
+        let data = self.download(&url).await?;
+        let nar = file_nar(data.clone(), self.executable);
+        let hash = match self.method {
+            FileIngestionMethod::Flat => hash::digest(self.hash_algo, &data),
+            FileIngestionMethod::Recursive => hash::digest(self.hash_algo, &nar),
+        };
+        self.add(store, &url, &name, hash, nar).await
+    }
+
+    /// Download the tarball at `url`, unpack it and add the result to
+    /// `store` as a NAR.
+    ///
+    /// When the tarball holds a single top-level directory, like most
+    /// source tarballs do, the contents of that directory are added.
+    #[cfg(feature = "compress-tools")]
+    pub async fn fetch_tarball<S, U>(&self, store: &mut S, url: U) -> Result<FetchResult, Error>
+    where
+        S: Store + ?Sized,
+        U: IntoUrl,
+    {
+        let url = url.into_url()?;
+        let name = self.name.clone().unwrap_or_else(|| "source".into());
+        let data = self.download(&url).await?;
+        let dir = TempDir::new().await?;
+        let res = async {
+            let root = unpack(&data, dir.path()).await?;
+            let nar = dump_nar(root, crate::archive::All).await?;
+            let hash = hash::digest(self.hash_algo, &nar);
+            let fetcher = Fetcher {
+                method: FileIngestionMethod::Recursive,
+                ..self.clone()
+            };
+            fetcher.add(store, &url, &name, hash, nar).await
+        }
+        .await;
+        dir.remove().await;
+        res
+    }
+
+    async fn add<S>(
+        &self,
+        store: &mut S,
+        url: &Url,
+        name: &str,
+        hash: Hash,
+        nar: Bytes,
+    ) -> Result<FetchResult, Error>
+    where
+        S: Store + ?Sized,
+    {
+        if let Some(expected) = self.expected {
+            if expected != hash {
+                return Err(Error::FetchHashMismatch(
+                    url.to_string(),
+                    expected.to_sri().to_string(),
+                    hash.to_sri().to_string(),
+                ));
+            }
+        }
+        let path =
+            add_ca_to_store(store, name, self.method, self.hash_algo, nar, self.repair).await?;
+        Ok(FetchResult { path, hash })
+    }
+}
+
+fn name_from_url(url: &Url) -> Result<String, Error> {
+    url.path_segments()
+        .and_then(|mut segments| segments.rfind(|s| !s.is_empty()))
+        .map(String::from)
+        .ok_or_else(|| Error::Misc(format!("cannot determine a name for '{}'", url)))
+}
+
+/// A NAR holding `data` as a single regular file.
+fn file_nar(data: Bytes, executable: bool) -> Bytes {
+    let size = data.len() as u64;
+    let mut events = vec![
+        NAREvent::Magic(Arc::new(NAR_VERSION_MAGIC_1.to_owned())),
+        NAREvent::RegularNode {
+            executable,
+            size,
+            offset: 0,
+        },
+    ];
+    if size > 0 {
+        events.push(NAREvent::Contents {
+            total: size,
+            index: 0,
+            buf: data,
+        });
+    }
+    let mut buf = BytesMut::new();
+    for event in events {
+        event.encode_into(&mut buf);
+    }
+    buf.freeze()
+}
+
+/// A directory below the temporary directory that the caller removes when
+/// it is done with it.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    async fn new() -> Result<TempDir, Error> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        let name = format!(
+            "nixrs-fetch-{}-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed),
+            nanos
+        );
+        let path = std::env::temp_dir().join(name);
+        fs::create_dir(&path).await?;
+        Ok(TempDir(path))
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+
+    async fn remove(self) {
+        let _ = fs::remove_dir_all(&self.0).await;
+    }
+}
+
+/// The NAR of `root` with the paths that `filter` accepts.
+async fn dump_nar<F, Fut>(root: PathBuf, filter: F) -> Result<Bytes, Error>
+where
+    F: Filter<Future = Fut>,
+    Fut: Future<Output = bool>,
+{
+    let events = DumpOptions::new()
+        .use_case_hack(false)
+        .with_filter(filter)
+        .dump(root);
+    pin!(events);
+    let mut buf = BytesMut::new();
+    while let Some(event) = events.try_next().await? {
+        event.encode_into(&mut buf);
+    }
+    Ok(buf.freeze())
+}
+
+/// Unpack the archive in `data` into `dir` and return the directory to
+/// add.
+#[cfg(feature = "compress-tools")]
+async fn unpack(data: &Bytes, dir: &Path) -> Result<PathBuf, Error> {
+    use compress_tools::tokio_support::uncompress_archive;
+    use compress_tools::Ownership;
+
+    uncompress_archive(std::io::Cursor::new(data.clone()), dir, Ownership::Ignore).await?;
+    let mut entries = fs::read_dir(dir).await?;
+    let mut only = None;
+    let mut count = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        count += 1;
+        only = Some(entry);
+    }
+    match only {
+        Some(entry) if count == 1 && entry.file_type().await?.is_dir() => Ok(entry.path()),
+        _ => Ok(dir.to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use super::*;
+    use crate::store::MemoryStore;
+    use crate::store_path::{ContentAddress, ContentAddressWithReferences, StoreDirProvider};
+
+    #[test]
+    fn test_name_from_url() {
+        let url = Url::parse("https://example.org/dist/hello-2.12.tar.gz").unwrap();
+        assert_eq!(name_from_url(&url).unwrap(), "hello-2.12.tar.gz");
+        let url = Url::parse("https://example.org/dist/").unwrap();
+        assert_eq!(name_from_url(&url).unwrap(), "dist");
+        let url = Url::parse("https://example.org").unwrap();
+        assert!(name_from_url(&url).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_add_flat() {
+        let mut store = MemoryStore::new();
+        let url = Url::parse("https://example.org/hello.txt").unwrap();
+        let data = Bytes::from_static(b"Hello world!");
+        let hash = hash::digest(hash::Algorithm::SHA256, &data);
+        let fetcher = Fetcher::new().unwrap().expected_hash(hash);
+        let res = fetcher
+            .add(
+                &mut store,
+                &url,
+                "hello.txt",
+                hash,
+                file_nar(data.clone(), false),
+            )
+            .await
+            .unwrap();
+        let ca = ContentAddress::fixed(FileIngestionMethod::Flat, hash);
+        let expected = store
+            .store_dir()
+            .make_fixed_output_path_from_ca(
+                "hello.txt",
+                &ContentAddressWithReferences::without_refs(ca),
+            )
+            .unwrap();
+        assert_eq!(res.path, expected);
+        assert_eq!(res.hash, hash);
+    }
+
+    #[tokio::test]
+    async fn test_add_hash_mismatch() {
+        let mut store = MemoryStore::new();
+        let url = Url::parse("https://example.org/hello.txt").unwrap();
+        let data = Bytes::from_static(b"Hello world!");
+        let fetcher = Fetcher::new()
+            .unwrap()
+            .expected_hash(hash::digest(hash::Algorithm::SHA256, "other"));
+        let hash = hash::digest(hash::Algorithm::SHA256, &data);
+        let res = fetcher
+            .add(&mut store, &url, "hello.txt", hash, file_nar(data, false))
+            .await;
+        assert_matches!(res, Err(Error::FetchHashMismatch(_, _, _)));
+    }
+}
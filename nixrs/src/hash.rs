@@ -1,12 +1,22 @@
 use std::convert::TryFrom;
 use std::convert::TryInto;
 use std::fmt;
+use std::future::poll_fn;
+use std::io;
+use std::pin::Pin;
 use std::str::FromStr;
+use std::task::{ready, Poll};
+
+use bytes::{Bytes, BytesMut};
 
 use derive_more::Display;
 use hex::FromHexError;
 use ring::digest;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::PollSender;
 
 use super::base32;
 
@@ -14,6 +24,7 @@ const MD5_SIZE: usize = 128 / 8;
 const SHA1_SIZE: usize = 160 / 8;
 const SHA256_SIZE: usize = 256 / 8;
 const SHA512_SIZE: usize = 512 / 8;
+const BLAKE3_SIZE: usize = 256 / 8;
 const LARGEST_ALGORITHM: Algorithm = Algorithm::SHA512;
 const MAX_SIZE: usize = LARGEST_ALGORITHM.size();
 
@@ -28,6 +39,8 @@ pub enum Algorithm {
     SHA256,
     #[display(fmt = "sha512")]
     SHA512,
+    #[display(fmt = "blake3")]
+    BLAKE3,
 }
 
 /// The default algorithm is currently SHA-256
@@ -46,6 +59,7 @@ impl Algorithm {
             Algorithm::SHA1 => SHA1_SIZE,
             Algorithm::SHA256 => SHA256_SIZE,
             Algorithm::SHA512 => SHA512_SIZE,
+            Algorithm::BLAKE3 => BLAKE3_SIZE,
         }
     }
 
@@ -108,6 +122,8 @@ impl FromStr for Algorithm {
             Ok(Algorithm::SHA1)
         } else if s.eq_ignore_ascii_case("md5") {
             Ok(Algorithm::MD5)
+        } else if s.eq_ignore_ascii_case("blake3") {
+            Ok(Algorithm::BLAKE3)
         } else {
             Err(UnknownAlgorithm(s.to_owned()))
         }
@@ -194,6 +210,7 @@ impl Hash {
                 Algorithm::SHA1 => &mut data[0..SHA1_SIZE],
                 Algorithm::SHA256 => &mut data[0..SHA256_SIZE],
                 Algorithm::SHA512 => &mut data[0..SHA512_SIZE],
+                Algorithm::BLAKE3 => &mut data[0..BLAKE3_SIZE],
             };
             hex::decode_to_slice(rest, slice)
                 .map_err(|err| ParseHashError::BadBase16Hash(rest.to_string(), err))?;
@@ -321,6 +338,10 @@ impl Hash {
     pub fn to_sri(&self) -> impl fmt::Display + '_ {
         SRIHash(self)
     }
+
+    pub fn to_sri_string(&self) -> String {
+        self.to_sri().to_string()
+    }
 }
 
 impl std::ops::Deref for Hash {
@@ -441,6 +462,53 @@ impl<'a> fmt::Display for SRIHash<'a> {
     }
 }
 
+/// A hash that is parsed and printed as a Subresource Integrity hash
+/// expression (`<type>-<base64>`), e.g. for the `hash` attribute of flake
+/// inputs and fetchers.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Sri(pub Hash);
+
+impl fmt::Display for Sri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.to_sri())
+    }
+}
+
+impl FromStr for Sri {
+    type Err = ParseHashError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Hash::parse_sri(s).map(Sri)
+    }
+}
+
+impl TryFrom<String> for Sri {
+    type Error = ParseHashError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Sri> for String {
+    fn from(value: Sri) -> Self {
+        value.to_string()
+    }
+}
+
+impl From<Hash> for Sri {
+    fn from(value: Hash) -> Self {
+        Sri(value)
+    }
+}
+
+impl From<Sri> for Hash {
+    fn from(value: Sri) -> Self {
+        value.0
+    }
+}
+
 /// Returns the digest of `data` using the given digest algorithm.
 ///
 /// ```
@@ -453,6 +521,8 @@ pub fn digest<B: AsRef<[u8]>>(algorithm: Algorithm, data: B) -> Hash {
     match algorithm {
         #[cfg(feature = "md5")]
         Algorithm::MD5 => Hash::new(Algorithm::MD5, md5::compute(data).as_ref()),
+        #[cfg(feature = "blake3")]
+        Algorithm::BLAKE3 => Hash::new(Algorithm::BLAKE3, blake3::hash(data.as_ref()).as_bytes()),
         _ => digest::digest(algorithm.digest_algorithm(), data.as_ref())
             .try_into()
             .unwrap(),
@@ -463,6 +533,8 @@ pub fn digest<B: AsRef<[u8]>>(algorithm: Algorithm, data: B) -> Hash {
 enum InnerContext {
     #[cfg(feature = "md5")]
     MD5(md5::Context),
+    #[cfg(feature = "blake3")]
+    BLAKE3(Box<blake3::Hasher>),
     Ring(digest::Context),
 }
 
@@ -492,6 +564,11 @@ impl Context {
         match algorithm {
             #[cfg(feature = "md5")]
             Algorithm::MD5 => Context(algorithm, InnerContext::MD5(md5::Context::new())),
+            #[cfg(feature = "blake3")]
+            Algorithm::BLAKE3 => Context(
+                algorithm,
+                InnerContext::BLAKE3(Box::new(blake3::Hasher::new())),
+            ),
             _ => Context(
                 algorithm,
                 InnerContext::Ring(digest::Context::new(algorithm.digest_algorithm())),
@@ -504,7 +581,12 @@ impl Context {
     pub fn update<D: AsRef<[u8]>>(&mut self, data: D) {
         let data = data.as_ref();
         match &mut self.1 {
+            #[cfg(feature = "md5")]
             InnerContext::MD5(ctx) => ctx.consume(data),
+            #[cfg(feature = "blake3")]
+            InnerContext::BLAKE3(ctx) => {
+                ctx.update(data);
+            }
             InnerContext::Ring(ctx) => ctx.update(data),
         }
     }
@@ -515,7 +597,10 @@ impl Context {
     /// [`Hash`]: struct@Hash
     pub fn finish(self) -> Hash {
         match self.1 {
+            #[cfg(feature = "md5")]
             InnerContext::MD5(ctx) => Hash::new(self.0, ctx.compute().as_ref()),
+            #[cfg(feature = "blake3")]
+            InnerContext::BLAKE3(ctx) => Hash::new(self.0, ctx.finalize().as_bytes()),
             InnerContext::Ring(ctx) => ctx.finish().try_into().unwrap(),
         }
     }
@@ -602,6 +687,112 @@ impl tokio::io::AsyncWrite for HashSink {
     }
 }
 
+/// Payloads larger than this are hashed with [`ParallelHashSink`] instead of
+/// on the task that receives them.
+pub const PARALLEL_HASH_THRESHOLD: u64 = 16 * 1024 * 1024;
+
+const PARALLEL_HASH_CHUNK: usize = 1024 * 1024;
+
+/// A hash sink that hashes on a blocking thread so the task writing to it
+/// can keep doing I/O while large payloads are hashed.
+///
+/// Data is handed over in order in chunks of 1 MiB with a few chunks in
+/// flight. The resulting hash is the same as with [`HashSink`].
+///
+/// Must be created inside a tokio runtime.
+#[derive(Debug)]
+pub struct ParallelHashSink {
+    sender: PollSender<Bytes>,
+    buf: BytesMut,
+    written: u64,
+    handle: JoinHandle<Hash>,
+}
+
+impl ParallelHashSink {
+    pub fn new(algorithm: Algorithm) -> ParallelHashSink {
+        let (sender, mut receiver) = mpsc::channel::<Bytes>(4);
+        let handle = tokio::task::spawn_blocking(move || {
+            let mut ctx = Context::new(algorithm);
+            while let Some(chunk) = receiver.blocking_recv() {
+                ctx.update(&chunk);
+            }
+            ctx.finish()
+        });
+        ParallelHashSink {
+            sender: PollSender::new(sender),
+            buf: BytesMut::with_capacity(PARALLEL_HASH_CHUNK),
+            written: 0,
+            handle,
+        }
+    }
+
+    fn poll_send_buf(&mut self, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        if self.buf.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        ready!(self.sender.poll_reserve(cx)).map_err(|_| hash_thread_gone())?;
+        let chunk = self.buf.split().freeze();
+        self.sender
+            .send_item(chunk)
+            .map_err(|_| hash_thread_gone())?;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Finalizes this sink and returns the hash and number of bytes written to the sink.
+    pub async fn finish(mut self) -> io::Result<(u64, Hash)> {
+        poll_fn(|cx| self.poll_send_buf(cx)).await?;
+        self.sender.close();
+        let hash = self
+            .handle
+            .await
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        Ok((self.written, hash))
+    }
+}
+
+fn hash_thread_gone() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "hashing thread stopped")
+}
+
+impl tokio::io::AsyncWrite for ParallelHashSink {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        if self.buf.len() >= PARALLEL_HASH_CHUNK {
+            ready!(self.poll_send_buf(cx))?;
+        }
+        let n = buf.len().min(PARALLEL_HASH_CHUNK - self.buf.len());
+        self.buf.extend_from_slice(&buf[..n]);
+        self.written += n as u64;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), io::Error>> {
+        self.poll_send_buf(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), io::Error>> {
+        self.poll_send_buf(cx)
+    }
+}
+
+#[cfg(any(test, feature = "test"))]
+impl Hash {
+    /// A hash that is derived from `seed` alone, for tests that need stable
+    /// hashes.
+    pub fn test_from_seed(algorithm: Algorithm, seed: &str) -> Hash {
+        digest(algorithm, format!("nixrs-test-seed:{}", seed))
+    }
+}
+
 #[cfg(any(test, feature = "test"))]
 pub mod proptest {
     use super::*;
@@ -616,7 +807,9 @@ pub mod proptest {
                 1 => Just(Algorithm::MD5),
                 2 => Just(Algorithm::SHA1),
                 5 => Just(Algorithm::SHA256),
-                2 => Just(Algorithm::SHA512)
+                2 => Just(Algorithm::SHA512),
+                #[cfg(feature="blake3")]
+                1 => Just(Algorithm::BLAKE3)
             ]
             .boxed()
         }
@@ -643,8 +836,40 @@ pub mod proptest {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use ::proptest::prelude::*;
     use pretty_assertions::assert_eq;
 
+    proptest! {
+        #[test]
+        fn proptest_sri_round_trip(hash in any::<Algorithm>().prop_flat_map(any_with::<Hash>)) {
+            let sri = hash.to_sri_string();
+            prop_assert_eq!(Hash::parse_sri(&sri).unwrap(), hash);
+            prop_assert_eq!(Hash::parse_any(&sri, Some(hash.algorithm())).unwrap(), hash);
+            prop_assert_eq!(sri.parse::<Hash>().unwrap(), hash);
+            prop_assert_eq!(sri.parse::<Sri>().unwrap(), Sri(hash));
+            prop_assert_eq!(Sri(hash).to_string(), sri);
+        }
+
+        #[test]
+        fn proptest_prefixed_round_trip(hash in any::<Algorithm>().prop_flat_map(any_with::<Hash>)) {
+            prop_assert_eq!(format!("{:x}", hash).parse::<Hash>().unwrap(), hash);
+            prop_assert_eq!(hash.to_string().parse::<Hash>().unwrap(), hash);
+            prop_assert_eq!(hash.to_base64().to_string().parse::<Hash>().unwrap(), hash);
+        }
+    }
+
+    #[test]
+    fn test_sri_serde() {
+        let sri = Sri(digest(Algorithm::SHA256, "abc"));
+        let json = serde_json::to_string(&sri).unwrap();
+        assert_eq!(
+            json,
+            "\"sha256-ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=\""
+        );
+        assert_eq!(serde_json::from_str::<Sri>(&json).unwrap(), sri);
+        assert!(serde_json::from_str::<Sri>("\"sha256:abc\"").is_err());
+    }
+
     /// digest
 
     fn test_hash(s1: &str, algo: Algorithm, base16: &str, base32: &str, base64: &str) {
@@ -720,6 +945,27 @@ mod tests {
         test_hash(s1, algo, base16, base32, base64);
     }
 
+    #[cfg(feature = "blake3")]
+    #[test]
+    fn test_known_blake3_hashes_1() {
+        // values taken from: https://github.com/BLAKE3-team/BLAKE3/blob/master/test_vectors/test_vectors.json
+        let s1 = "abc";
+        let algo = Algorithm::BLAKE3;
+        let base16 = "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85";
+        let base32 = "11cxppanr71mzl1xnyax8rccaj5milx2fx9vnvzk6la672nb6dv4";
+        let base64 = "ZDezrDhGUTP/tjt1JzqNtUjFWEZdedsD/TWcbNW9nYU=";
+        test_hash(s1, algo, base16, base32, base64);
+    }
+
+    #[cfg(feature = "blake3")]
+    #[test]
+    fn test_blake3_context() {
+        let mut ctx = Context::new(Algorithm::BLAKE3);
+        ctx.update("a");
+        ctx.update("bc");
+        assert_eq!(ctx.finish(), digest(Algorithm::BLAKE3, "abc"));
+    }
+
     #[test]
     fn test_known_sha1_hashes_1() {
         // values taken from: https://tools.ietf.org/html/rfc3174
@@ -866,4 +1112,18 @@ mod tests {
             "sha1:12345".parse::<Hash>()
         );
     }
+
+    #[tokio::test]
+    async fn test_parallel_hash_sink() {
+        use tokio::io::AsyncWriteExt;
+
+        let data: Vec<u8> = (0..3 * PARALLEL_HASH_CHUNK + 17)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let mut sink = ParallelHashSink::new(Algorithm::SHA256);
+        sink.write_all(&data).await.unwrap();
+        let (size, hash) = sink.finish().await.unwrap();
+        assert_eq!(size, data.len() as u64);
+        assert_eq!(hash, digest(Algorithm::SHA256, &data));
+    }
 }
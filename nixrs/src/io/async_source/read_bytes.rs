@@ -8,6 +8,8 @@ use bytes::Bytes;
 use bytes::BytesMut;
 use tokio::io::AsyncRead;
 
+use crate::io::ReadLimits;
+
 use super::read_exact::ReadExact;
 use super::read_int::ReadUsize;
 use super::read_padding::ReadPadding;
@@ -60,6 +62,10 @@ where
                         Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
                         Poll::Ready(Ok(v)) => v,
                     };
+                    let limits = ReadLimits::current();
+                    if let Err(err) = limits.check_string_len(len) {
+                        return Poll::Ready(Err(err));
+                    }
                     if len > limit {
                         return Poll::Ready(Err(io::Error::new(
                             io::ErrorKind::InvalidData,
@@ -71,7 +77,7 @@ where
                         *self = Self::Done(src);
                         return Poll::Ready(Ok(Bytes::new()));
                     }
-                    buffer.reserve(len);
+                    buffer.reserve(limits.initial_capacity(len));
                     *self = Self::ReadData(ReadExact::new(src, len, buffer));
                 }
                 Self::ReadData(mut reader) => {
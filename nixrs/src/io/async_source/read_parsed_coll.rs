@@ -7,6 +7,8 @@ use std::task::{Context, Poll};
 
 use tokio::io::AsyncRead;
 
+use crate::io::ReadLimits;
+
 use super::StateParse;
 
 use super::read_int::ReadUsize;
@@ -57,8 +59,12 @@ where
                         Poll::Ready(Err(err)) => return Poll::Ready(Err(err.into())),
                         Poll::Ready(Ok(v)) => v,
                     };
+                    let limits = ReadLimits::current();
+                    if let Err(err) = limits.check_collection_len(len) {
+                        return Poll::Ready(Err(err.into()));
+                    }
                     let src = reader.inner();
-                    let coll = C::with_capacity(len);
+                    let coll = C::with_capacity(limits.initial_capacity(len));
                     if len == 0 {
                         return Poll::Ready(Ok(coll));
                     } else {
@@ -7,6 +7,8 @@ use std::task::{Context, Poll};
 use bytes::{Bytes, BytesMut};
 use tokio::io::AsyncRead;
 
+use crate::io::ReadLimits;
+
 use super::read_exact::ReadExact;
 use super::read_int::ReadUsize;
 use super::read_padding::ReadPadding;
@@ -59,6 +61,10 @@ where
                         Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
                         Poll::Ready(Ok(v)) => v,
                     };
+                    let limits = ReadLimits::current();
+                    if let Err(err) = limits.check_string_len(len) {
+                        return Poll::Ready(Err(err));
+                    }
                     if len > limit {
                         return Poll::Ready(Err(io::Error::new(
                             io::ErrorKind::InvalidData,
@@ -70,7 +76,7 @@ where
                         *self = ReadString::Done(src);
                         return Poll::Ready(Ok(String::new()));
                     }
-                    buffer.reserve(len);
+                    buffer.reserve(limits.initial_capacity(len));
                     *self = ReadString::ReadData(ReadExact::new(src, len, buffer));
                 }
                 ReadString::ReadData(mut reader) => {
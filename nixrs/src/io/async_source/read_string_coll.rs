@@ -6,6 +6,8 @@ use std::task::{Context, Poll};
 
 use tokio::io::AsyncRead;
 
+use crate::io::ReadLimits;
+
 use super::read_int::ReadUsize;
 use super::read_string::ReadString;
 use super::CollectionRead;
@@ -47,8 +49,12 @@ where
                         Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
                         Poll::Ready(Ok(v)) => v,
                     };
+                    let limits = ReadLimits::current();
+                    if let Err(err) = limits.check_collection_len(len) {
+                        return Poll::Ready(Err(err));
+                    }
                     let src = reader.inner();
-                    let coll = C::with_capacity(len);
+                    let coll = C::with_capacity(limits.initial_capacity(len));
                     if len == 0 {
                         return Poll::Ready(Ok(coll));
                     } else {
@@ -0,0 +1,141 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use pin_project_lite::pin_project;
+use tokio::io::{AsyncBufRead, AsyncRead, ReadBuf};
+
+/// How many bytes a [`CountingReader`] has read so far. Unlike
+/// [`OffsetReader::offset`](super::OffsetReader::offset) this can be kept
+/// and looked at while the reader itself has been handed off, e.g. to
+/// report progress of an upload.
+#[derive(Debug, Clone, Default)]
+pub struct ByteCount(Arc<AtomicU64>);
+
+impl ByteCount {
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn add(&self, amt: usize) {
+        self.0.fetch_add(amt as u64, Ordering::Relaxed);
+    }
+}
+
+pin_project! {
+    #[derive(Debug)]
+    pub struct CountingReader<R> {
+        #[pin]
+        inner: R,
+        count: ByteCount,
+    }
+}
+
+impl<R> CountingReader<R> {
+    pub fn new(reader: R) -> (CountingReader<R>, ByteCount) {
+        let count = ByteCount::default();
+        let reader = CountingReader {
+            inner: reader,
+            count: count.clone(),
+        };
+        (reader, count)
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.get()
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for CountingReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let before = buf.filled().len();
+        ready!(this.inner.poll_read(cx, buf))?;
+        this.count.add(buf.filled().len() - before);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R: AsyncBufRead> AsyncBufRead for CountingReader<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        self.project().inner.poll_fill_buf(cx)
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.project();
+        this.count.add(amt);
+        this.inner.consume(amt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+
+    use super::*;
+
+    #[test]
+    fn test_count_polls() {
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let (mut reader, count) = CountingReader::new(&b"hello world"[..]);
+        let mut storage = [0u8; 5];
+
+        let mut buf = ReadBuf::new(&mut storage);
+        assert!(Pin::new(&mut reader)
+            .poll_read(&mut cx, &mut buf)
+            .is_ready());
+        assert_eq!(buf.filled(), b"hello");
+        assert_eq!(count.get(), 5);
+
+        // Bytes already in the buffer aren't counted again.
+        let mut buf = ReadBuf::new(&mut storage);
+        buf.advance(2);
+        assert!(Pin::new(&mut reader)
+            .poll_read(&mut cx, &mut buf)
+            .is_ready());
+        assert_eq!(count.get(), 8);
+        assert_eq!(reader.count(), 8);
+
+        let buf = match Pin::new(&mut reader).poll_fill_buf(&mut cx) {
+            Poll::Ready(Ok(buf)) => buf,
+            res => panic!("unexpected {:?}", res),
+        };
+        assert_eq!(buf, b"rld");
+        // Filling the buffer doesn't consume anything.
+        assert_eq!(count.get(), 8);
+        Pin::new(&mut reader).consume(2);
+        assert_eq!(count.get(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_count_handed_off() {
+        let data = b"line one\nline two\n".repeat(100);
+        let (reader, count) = CountingReader::new(std::io::Cursor::new(data.clone()));
+        let task = tokio::spawn(async move {
+            let mut lines = reader.lines();
+            let mut n = 0;
+            while lines.next_line().await.unwrap().is_some() {
+                n += 1;
+            }
+            n
+        });
+        assert_eq!(task.await.unwrap(), 200);
+        assert_eq!(count.get(), data.len() as u64);
+
+        let (mut reader, count) = CountingReader::new(&data[..]);
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).await.unwrap();
+        assert_eq!(count.get(), data.len() as u64);
+    }
+}
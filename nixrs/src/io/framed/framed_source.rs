@@ -1,10 +1,13 @@
+use std::future::Future;
 use std::io;
 use std::pin::Pin;
-use std::task::{ready, Poll};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
 
 use bytes::Buf;
 use pin_project_lite::pin_project;
-use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+use tokio::io::{AsyncRead, ReadBuf};
 use tracing::{debug, trace};
 
 #[derive(Debug)]
@@ -16,14 +19,49 @@ pub enum FramedSourceOp {
     Invalid,
 }
 
+/// Set when a [`FramedSource`] is dropped before its terminating frame was
+/// read, which leaves the rest of its frames on the connection to be
+/// mistaken for whatever comes next.
+#[derive(Debug, Clone, Default)]
+pub struct Poison(Arc<AtomicBool>);
+
+impl Poison {
+    pub fn is_poisoned(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    pub fn check(&self) -> io::Result<()> {
+        if self.is_poisoned() {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "framed data was not read to the end, connection is out of sync",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
 pin_project! {
     #[derive(Debug)]
     pub struct FramedSource<R> {
         state: FramedSourceOp,
         frame: usize,
+        poison: Option<Poison>,
         #[pin]
         reader: R,
     }
+
+    impl<R> PinnedDrop for FramedSource<R> {
+        fn drop(this: Pin<&mut Self>) {
+            if let Some(poison) = this.poison.as_ref() {
+                if !matches!(this.state, FramedSourceOp::Eof) {
+                    debug!(frame = this.frame, "Framed source dropped before the end");
+                    poison.0.store(true, Ordering::SeqCst);
+                }
+            }
+        }
+    }
 }
 
 impl<R: AsyncRead + Unpin> FramedSource<R> {
@@ -31,29 +69,65 @@ impl<R: AsyncRead + Unpin> FramedSource<R> {
         FramedSource {
             state: FramedSourceOp::Idle,
             frame: 0,
+            poison: None,
             reader,
         }
     }
 
-    pub async fn drain(mut self) -> io::Result<()> {
-        if let FramedSourceOp::Eof = self.state {
-            return Ok(());
+    /// Like [`new`](Self::new), but sets `poison` if dropped before all
+    /// frames have been read, be it by [`finish`](Self::finish) or
+    /// otherwise.
+    pub fn with_poison(reader: R, poison: &Poison) -> FramedSource<R> {
+        FramedSource {
+            state: FramedSourceOp::Idle,
+            frame: 0,
+            poison: Some(poison.clone()),
+            reader,
         }
+    }
+
+    /// Whether the terminating frame has been read.
+    pub fn is_finished(&self) -> bool {
+        matches!(self.state, FramedSourceOp::Eof)
+    }
+
+    /// Reads and discards whatever frames are left. Dropping the returned
+    /// future before it completes counts as not having read them.
+    pub fn finish(self) -> Drained<R> {
+        Drained { source: self }
+    }
+
+    pub async fn drain(self) -> io::Result<()> {
+        self.finish().await
+    }
+}
+
+/// Future returned by [`FramedSource::finish`].
+#[derive(Debug)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Drained<R> {
+    source: FramedSource<R>,
+}
+
+impl<R: AsyncRead + Unpin> Future for Drained<R> {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let source = &mut self.get_mut().source;
         let mut buf = [0u8; 65536];
-        loop {
-            let read = self.read(&mut buf).await?;
-            if read == 0 {
-                return Ok(());
-            }
-            trace!("Read drain {}", read);
+        while !source.is_finished() {
+            let mut read_buf = ReadBuf::new(&mut buf);
+            ready!(Pin::new(&mut *source).poll_read(cx, &mut read_buf))?;
+            trace!("Read drain {}", read_buf.filled().len());
         }
+        Poll::Ready(Ok(()))
     }
 }
 
 impl<R: AsyncRead> AsyncRead for FramedSource<R> {
     fn poll_read(
         self: Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
+        cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
         let mut this = self.project();
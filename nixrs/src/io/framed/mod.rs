@@ -10,7 +10,70 @@ mod tests {
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
     use crate::hash;
-    use crate::io::{FramedSink, FramedSource};
+    use crate::io::{FramedSink, FramedSource, Poison};
+
+    async fn framed(data: &[u8], trailer: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut sink = FramedSink::new(&mut buf);
+        for chunk in data.chunks(4) {
+            sink.write_all(chunk).await.unwrap();
+        }
+        sink.shutdown().await.unwrap();
+        buf.extend_from_slice(trailer);
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_finish_drains() {
+        let buf = framed(b"hello world", b"next").await;
+        let poison = Poison::default();
+        let mut reader = &buf[..];
+        let mut source = FramedSource::with_poison(&mut reader, &poison);
+        let mut start = [0u8; 5];
+        source.read_exact(&mut start).await.unwrap();
+        assert!(!source.is_finished());
+        source.finish().await.unwrap();
+        assert!(!poison.is_poisoned());
+        poison.check().unwrap();
+        assert_eq!(reader, b"next");
+    }
+
+    #[tokio::test]
+    async fn test_drop_poisons() {
+        let buf = framed(b"hello world", b"next").await;
+        let poison = Poison::default();
+        let mut reader = &buf[..];
+        let mut source = FramedSource::with_poison(&mut reader, &poison);
+        let mut start = [0u8; 5];
+        source.read_exact(&mut start).await.unwrap();
+        drop(source);
+        assert!(poison.is_poisoned());
+        assert_eq!(
+            poison.check().unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+
+        // Reading to the end is as good as finishing.
+        let poison = Poison::default();
+        let mut source = FramedSource::with_poison(&buf[..], &poison);
+        let mut all = Vec::new();
+        source.read_to_end(&mut all).await.unwrap();
+        drop(source);
+        assert_eq!(all, b"hello world");
+        assert!(!poison.is_poisoned());
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_finish_poisons() {
+        let buf = framed(b"hello world", b"").await;
+        let (mut writer, reader) = tokio::io::duplex(1024);
+        writer.write_all(&buf[..10]).await.unwrap();
+        let poison = Poison::default();
+        let source = FramedSource::with_poison(reader, &poison);
+        let res = tokio::time::timeout(std::time::Duration::from_millis(10), source.finish()).await;
+        assert!(res.is_err());
+        assert!(poison.is_poisoned());
+    }
 
     proptest! {
         #[test]
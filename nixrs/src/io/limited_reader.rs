@@ -0,0 +1,118 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use pin_project_lite::pin_project;
+use tokio::io::{AsyncRead, ReadBuf};
+
+pin_project! {
+    /// Fails reads with [`io::ErrorKind::InvalidData`] once more than
+    /// `limit` bytes would have been read.
+    ///
+    /// Unlike [`AsyncReadExt::take`](tokio::io::AsyncReadExt::take), which
+    /// ends the stream quietly, a peer sending too much can't go unnoticed.
+    #[derive(Debug)]
+    pub struct LimitedReader<R> {
+        #[pin]
+        inner: R,
+        limit: u64,
+        read: u64,
+    }
+}
+
+impl<R> LimitedReader<R> {
+    pub fn new(reader: R, limit: u64) -> LimitedReader<R> {
+        LimitedReader {
+            inner: reader,
+            limit,
+            read: 0,
+        }
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    /// Bytes that can still be read before hitting the limit.
+    pub fn remaining(&self) -> u64 {
+        self.limit - self.read
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for LimitedReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let before = buf.filled().len();
+        ready!(this.inner.poll_read(cx, buf))?;
+        let n = (buf.filled().len() - before) as u64;
+        if *this.read + n > *this.limit {
+            buf.set_filled(before);
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("read limit of {} bytes exceeded", this.limit),
+            )));
+        }
+        *this.read += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_exact_limit() {
+        let mut reader = LimitedReader::new(&b"hello"[..], 5);
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"hello");
+        assert_eq!(reader.remaining(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_over_limit() {
+        let mut reader = LimitedReader::new(&b"hello world"[..], 5);
+        let mut buf = Vec::new();
+        let err = reader.read_to_end(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "read limit of 5 bytes exceeded");
+    }
+
+    #[test]
+    fn test_limit_polls() {
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut reader = LimitedReader::new(&b"hello world"[..], 8);
+        let mut storage = [0u8; 4];
+
+        let mut buf = ReadBuf::new(&mut storage);
+        match Pin::new(&mut reader).poll_read(&mut cx, &mut buf) {
+            Poll::Ready(Ok(())) => {}
+            res => panic!("unexpected {:?}", res),
+        }
+        assert_eq!(buf.filled(), b"hell");
+        assert_eq!(reader.remaining(), 4);
+
+        let mut storage = [0u8; 8];
+        let mut buf = ReadBuf::new(&mut storage);
+        buf.put_slice(b"xy");
+        match Pin::new(&mut reader).poll_read(&mut cx, &mut buf) {
+            Poll::Ready(Err(err)) => assert_eq!(err.kind(), io::ErrorKind::InvalidData),
+            res => panic!("unexpected {:?}", res),
+        }
+        // Nothing past the limit is handed out, and what was there stays.
+        assert_eq!(buf.filled(), b"xy");
+        assert_eq!(reader.remaining(), 4);
+    }
+}
@@ -3,12 +3,18 @@ mod async_source;
 mod cancelled_reader;
 mod collection_read;
 mod collection_size;
+mod counting_reader;
 mod framed;
+mod limited_reader;
+mod mux;
 mod offset_reader;
+mod read_limits;
 mod state_display;
 mod state_parse;
 mod state_print;
 mod taken_stream;
+mod tee_reader;
+mod zstd_switch;
 
 pub use async_sink::AsyncSink;
 pub use async_source::{
@@ -18,13 +24,19 @@ pub use async_source::{
 pub use cancelled_reader::{CancelToken, CancelledReader};
 pub use collection_read::CollectionRead;
 pub use collection_size::CollectionSize;
+pub use counting_reader::{ByteCount, CountingReader};
 pub use framed::framed_sink::FramedSink;
-pub use framed::framed_source::FramedSource;
+pub use framed::framed_source::{Drained, FramedSource, Poison};
+pub use limited_reader::LimitedReader;
+pub use mux::{Mux, MuxChannel, MuxDriver, MuxSide, MAX_FRAME_LEN, WINDOW};
 pub use offset_reader::OffsetReader;
+pub use read_limits::{ReadLimits, WithReadLimits, WithReadLimitsFuture};
 pub use state_display::StateDisplay;
 pub use state_parse::StateParse;
 pub use state_print::StatePrint;
 pub use taken_stream::{TakenGuard, TakenStream, Taker};
+pub use tee_reader::TeeReader;
+pub use zstd_switch::{zstd_switch, ZstdSwitch, ZstdSwitchReader, ZstdSwitchWriter};
 
 pub(crate) const STATIC_PADDING: &[u8] = &[0u8; 8];
 
@@ -256,6 +268,31 @@ mod tests {
         assert_eq!(buf.len(), 56);
     }
 
+    #[tokio::test]
+    async fn test_read_limits() {
+        let limits = ReadLimits {
+            max_string_len: 4,
+            max_collection_len: 2,
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        buf.write_str("where").await.unwrap();
+        let res = async { (&buf[..]).read_string().await }
+            .with_read_limits(limits)
+            .await;
+        assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!((&buf[..]).read_string().await.unwrap(), "where");
+
+        let mut buf = Vec::new();
+        buf.write_string_coll(&vec!["a".to_string(), "b".to_string(), "c".to_string()])
+            .await
+            .unwrap();
+        let res: std::io::Result<Vec<String>> = async { (&buf[..]).read_string_coll().await }
+            .with_read_limits(limits)
+            .await;
+        assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+
     #[tokio::test]
     async fn test_write_printed() {
         let mut buf = Vec::new();
@@ -0,0 +1,663 @@
+//! Several byte streams, called channels, over one `AsyncRead` and
+//! `AsyncWrite` pair.
+//!
+//! Everything is sent in frames of a channel id and a length as 64-bit
+//! little endian words followed by that many bytes. A frame with length 0
+//! closes the channel in that direction. A channel is opened by sending its
+//! first frame. Ids opened by the [`MuxSide::Client`] are odd and ids
+//! opened by the [`MuxSide::Server`] are even, so both ends can open
+//! channels without agreeing on ids first.
+//!
+//! Every channel has a window of [`WINDOW`] bytes in each direction, which
+//! is how much may be sent on it before the other end has read any of it.
+//! The reader hands out more with credit frames, which have the top bit of
+//! the id set and the amount of credit as length and no data. So a channel
+//! that isn't read only holds up what is written to it and the other
+//! channels keep going. A channel opened while 16 others are waiting to
+//! be accepted is closed right away.
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll, Waker};
+
+use bytes::{Buf, Bytes};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::mpsc;
+use tracing::debug;
+
+/// Largest frame that is sent or accepted.
+pub const MAX_FRAME_LEN: usize = 64 * 1024;
+
+/// How many frames of [`MAX_FRAME_LEN`] make up a window, and how many
+/// opened channels are queued before they are accepted.
+const CHANNEL_QUEUE: usize = 16;
+
+/// How many bytes may be sent on a channel before the other end reads them.
+pub const WINDOW: u64 = (CHANNEL_QUEUE * MAX_FRAME_LEN) as u64;
+
+/// Set in the id of credit frames.
+const CREDIT: u64 = 1 << 63;
+
+/// Which end of the connection a [`Mux`] is, which decides the ids of the
+/// channels it opens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MuxSide {
+    Client,
+    Server,
+}
+
+impl MuxSide {
+    fn first_id(&self) -> u64 {
+        match self {
+            MuxSide::Client => 1,
+            MuxSide::Server => 2,
+        }
+    }
+
+    fn opened(&self, id: u64) -> bool {
+        id % 2 == self.first_id() % 2
+    }
+}
+
+#[derive(Debug)]
+struct Frame {
+    id: u64,
+    data: Bytes,
+}
+
+/// Flow control of a channel, shared by the channel and the driver.
+#[derive(Debug)]
+struct Flow {
+    /// Bytes this end may still send.
+    send_credit: u64,
+    /// Bytes the other end may still send.
+    recv_window: u64,
+    /// Channel waiting for credit.
+    writer: Option<Waker>,
+    /// The connection was closed, so no more credit is coming.
+    disconnected: bool,
+}
+
+impl Flow {
+    fn new() -> Arc<Mutex<Flow>> {
+        Arc::new(Mutex::new(Flow {
+            send_credit: WINDOW,
+            recv_window: WINDOW,
+            writer: None,
+            disconnected: false,
+        }))
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.writer.take() {
+            waker.wake();
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ChannelEntry {
+    /// `None` once the other end closed the channel.
+    tx: Option<mpsc::UnboundedSender<Bytes>>,
+    flow: Arc<Mutex<Flow>>,
+}
+
+type Channels = Arc<Mutex<HashMap<u64, ChannelEntry>>>;
+
+/// A new channel opened by the other end.
+type Incoming = (u64, mpsc::UnboundedReceiver<Bytes>, Arc<Mutex<Flow>>);
+
+/// Opens and accepts channels over a connection.
+///
+/// Nothing is sent or received unless the [`MuxDriver`] returned with it
+/// is polled. A channel that isn't read only stops the other end from
+/// writing to it once its [`WINDOW`] is used up. Frames waiting for the
+/// driver are only bounded by the windows, so each channel holds at most
+/// a [`WINDOW`] of them.
+#[derive(Debug)]
+pub struct Mux {
+    side: MuxSide,
+    next_id: u64,
+    channels: Channels,
+    out: mpsc::UnboundedSender<Frame>,
+    credits: mpsc::UnboundedSender<(u64, u64)>,
+    incoming: mpsc::Receiver<Incoming>,
+}
+
+impl Mux {
+    pub fn new<R, W>(reader: R, writer: W, side: MuxSide) -> (Mux, MuxDriver)
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        let channels: Channels = Default::default();
+        let (out, out_rx) = mpsc::unbounded_channel();
+        let (credits, credits_rx) = mpsc::unbounded_channel();
+        let (incoming_tx, incoming) = mpsc::channel(CHANNEL_QUEUE);
+        // Refusing channels doesn't keep the connection open, only the
+        // `Mux` and its channels do.
+        let read = read_frames(
+            reader,
+            side,
+            channels.clone(),
+            out.downgrade(),
+            credits.clone(),
+            incoming_tx,
+        );
+        let write = write_frames(writer, out_rx, credits_rx);
+        let driver = async move {
+            futures::try_join!(read, write)?;
+            Ok(())
+        };
+        let mux = Mux {
+            side,
+            next_id: side.first_id(),
+            channels,
+            out,
+            credits,
+            incoming,
+        };
+        (mux, MuxDriver(driver.boxed()))
+    }
+
+    pub fn side(&self) -> MuxSide {
+        self.side
+    }
+
+    /// Open a new channel. The other end sees it once something has been
+    /// written to it.
+    pub fn open(&mut self) -> MuxChannel {
+        let id = self.next_id;
+        self.next_id += 2;
+        let (tx, rx) = mpsc::unbounded_channel();
+        let flow = Flow::new();
+        let entry = ChannelEntry {
+            tx: Some(tx),
+            flow: flow.clone(),
+        };
+        self.channels.lock().unwrap().insert(id, entry);
+        self.channel(id, rx, flow)
+    }
+
+    /// The next channel opened by the other end, or `None` when the
+    /// connection is closed.
+    pub async fn accept(&mut self) -> Option<MuxChannel> {
+        let (id, rx, flow) = self.incoming.recv().await?;
+        Some(self.channel(id, rx, flow))
+    }
+
+    fn channel(
+        &self,
+        id: u64,
+        inbound: mpsc::UnboundedReceiver<Bytes>,
+        flow: Arc<Mutex<Flow>>,
+    ) -> MuxChannel {
+        MuxChannel {
+            id,
+            inbound,
+            buf: Bytes::new(),
+            unacked: 0,
+            flow,
+            channels: self.channels.clone(),
+            outbound: self.out.clone(),
+            credits: self.credits.clone(),
+            closed: false,
+        }
+    }
+}
+
+/// Moves the frames of a [`Mux`] and its channels over the connection.
+/// Completes when the other end closed the connection and the [`Mux`] and
+/// all its channels are dropped.
+pub struct MuxDriver(BoxFuture<'static, io::Result<()>>);
+
+impl Future for MuxDriver {
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.0.poll_unpin(cx)
+    }
+}
+
+impl fmt::Debug for MuxDriver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("MuxDriver").finish()
+    }
+}
+
+async fn read_frames<R>(
+    mut reader: R,
+    side: MuxSide,
+    channels: Channels,
+    out: mpsc::WeakUnboundedSender<Frame>,
+    credits: mpsc::UnboundedSender<(u64, u64)>,
+    incoming: mpsc::Sender<Incoming>,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut last_remote_id = 0;
+    let res = loop {
+        let id = match reader.read_u64_le().await {
+            Ok(id) => id,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break Ok(()),
+            Err(err) => break Err(err),
+        };
+        let len = reader.read_u64_le().await?;
+        if id & CREDIT != 0 {
+            if let Some(entry) = channels.lock().unwrap().get(&(id & !CREDIT)) {
+                let mut flow = entry.flow.lock().unwrap();
+                flow.send_credit = flow.send_credit.saturating_add(len);
+                flow.wake();
+            }
+            continue;
+        }
+        if len > MAX_FRAME_LEN as u64 {
+            break Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("mux frame is too long: {}", len),
+            ));
+        }
+        if len == 0 {
+            if let Some(entry) = channels.lock().unwrap().get_mut(&id) {
+                entry.tx = None;
+            }
+            continue;
+        }
+        let mut data = vec![0; len as usize];
+        reader.read_exact(&mut data).await?;
+
+        let entry = channels
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|entry| (entry.tx.clone(), entry.flow.clone()));
+        let (tx, flow) = match entry {
+            Some(entry) => entry,
+            None if !side.opened(id) && id > last_remote_id => {
+                last_remote_id = id;
+                let (tx, rx) = mpsc::unbounded_channel();
+                let flow = Flow::new();
+                let entry = ChannelEntry {
+                    tx: Some(tx.clone()),
+                    flow: flow.clone(),
+                };
+                channels.lock().unwrap().insert(id, entry);
+                // Waiting for the channel to be accepted would hold up the
+                // channels that are already open, so it is closed instead.
+                if incoming.try_send((id, rx, flow.clone())).is_err() {
+                    debug!(id, "Not accepting mux channel");
+                    channels.lock().unwrap().remove(&id);
+                    let _ = credits.send((id, len));
+                    if let Some(out) = out.upgrade() {
+                        let _ = out.send(Frame {
+                            id,
+                            data: Bytes::new(),
+                        });
+                    }
+                    continue;
+                }
+                (Some(tx), flow)
+            }
+            None => {
+                debug!(id, "Dropping frame for closed mux channel");
+                let _ = credits.send((id, len));
+                continue;
+            }
+        };
+        {
+            let mut flow = flow.lock().unwrap();
+            if len > flow.recv_window {
+                break Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("mux channel {} was sent more than its window", id),
+                ));
+            }
+            flow.recv_window -= len;
+        }
+        // Data nobody will read is credited back right away so the other
+        // end doesn't wait for it to be read.
+        let unread = match tx {
+            Some(tx) => tx.send(data.into()).is_err(),
+            None => true,
+        };
+        if unread {
+            let _ = credits.send((id, len));
+        }
+    };
+    // Make the channels see the end of the connection.
+    for (_, entry) in channels.lock().unwrap().drain() {
+        let mut flow = entry.flow.lock().unwrap();
+        flow.disconnected = true;
+        flow.wake();
+    }
+    res
+}
+
+/// Writes frames until the [`Mux`] and all its channels are dropped.
+/// Credit is sent ahead of queued frames since it is what lets the other
+/// end send more.
+async fn write_frames<W>(
+    mut writer: W,
+    mut frames: mpsc::UnboundedReceiver<Frame>,
+    mut credits: mpsc::UnboundedReceiver<(u64, u64)>,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let next = futures::future::poll_fn(|cx| {
+            if let Poll::Ready(Some((id, len))) = credits.poll_recv(cx) {
+                return Poll::Ready(Some((id | CREDIT, len, Bytes::new())));
+            }
+            frames
+                .poll_recv(cx)
+                .map(|frame| frame.map(|frame| (frame.id, frame.data.len() as u64, frame.data)))
+        })
+        .await;
+        let (id, len, data) = match next {
+            Some(next) => next,
+            None => break,
+        };
+        writer.write_u64_le(id).await?;
+        writer.write_u64_le(len).await?;
+        writer.write_all(&data).await?;
+        writer.flush().await?;
+    }
+    writer.shutdown().await
+}
+
+/// One of the streams of a [`Mux`].
+///
+/// Shutting down or dropping it closes the channel for writing. Reading
+/// gives end of file once the other end has done the same. Writing waits
+/// while the other end hasn't read the last [`WINDOW`] bytes.
+#[derive(Debug)]
+pub struct MuxChannel {
+    id: u64,
+    inbound: mpsc::UnboundedReceiver<Bytes>,
+    buf: Bytes,
+    /// Bytes read that the other end wasn't given credit for yet.
+    unacked: u64,
+    flow: Arc<Mutex<Flow>>,
+    channels: Channels,
+    outbound: mpsc::UnboundedSender<Frame>,
+    credits: mpsc::UnboundedSender<(u64, u64)>,
+    closed: bool,
+}
+
+impl MuxChannel {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Gives the other end credit for what was read once it adds up to
+    /// half a window, so a steady stream doesn't send credit for every
+    /// frame.
+    fn consumed(&mut self, len: usize) {
+        self.unacked += len as u64;
+        if self.unacked >= WINDOW / 2 {
+            self.grant();
+        }
+    }
+
+    fn grant(&mut self) {
+        if self.unacked > 0 {
+            self.flow.lock().unwrap().recv_window += self.unacked;
+            let _ = self.credits.send((self.id, self.unacked));
+            self.unacked = 0;
+        }
+    }
+
+    fn send(&self, data: Bytes) -> io::Result<()> {
+        let frame = Frame { id: self.id, data };
+        self.outbound.send(frame).map_err(|_| mux_gone())
+    }
+}
+
+fn mux_gone() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "mux connection is closed")
+}
+
+impl AsyncRead for MuxChannel {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.buf.is_empty() {
+            match ready!(self.inbound.poll_recv(cx)) {
+                Some(data) => self.buf = data,
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let len = self.buf.len().min(buf.remaining());
+        buf.put_slice(&self.buf[..len]);
+        self.buf.advance(len);
+        self.consumed(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for MuxChannel {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.closed {
+            return Poll::Ready(Err(mux_gone()));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let credit = {
+            let mut flow = self.flow.lock().unwrap();
+            if flow.send_credit == 0 {
+                if flow.disconnected {
+                    return Poll::Ready(Err(mux_gone()));
+                }
+                flow.writer = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            flow.send_credit
+        };
+        let len = buf.len().min(MAX_FRAME_LEN).min(credit as usize);
+        self.send(Bytes::copy_from_slice(&buf[..len]))?;
+        self.flow.lock().unwrap().send_credit -= len as u64;
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Frames are flushed by the driver as soon as they are written.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.closed {
+            self.send(Bytes::new())?;
+            self.closed = true;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxChannel {
+    fn drop(&mut self) {
+        if !self.closed {
+            // Queued behind the data, so it only fails once the driver is
+            // gone.
+            let _ = self.send(Bytes::new());
+        }
+        // What wasn't read is credited back, so the other end can finish
+        // writing what it was sending.
+        self.channels.lock().unwrap().remove(&self.id);
+        self.inbound.close();
+        self.unacked += self.buf.len() as u64;
+        while let Ok(data) = self.inbound.try_recv() {
+            self.unacked += data.len() as u64;
+        }
+        self.grant();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_parallel_channels() {
+        let (client, server) = tokio::io::duplex(1_000);
+        let (read, write) = tokio::io::split(client);
+        let (mut client, client_driver) = Mux::new(read, write, MuxSide::Client);
+        let (read, write) = tokio::io::split(server);
+        let (mut server, server_driver) = Mux::new(read, write, MuxSide::Server);
+        tokio::spawn(client_driver);
+        tokio::spawn(server_driver);
+
+        let mut first = client.open();
+        let mut second = client.open();
+        assert_eq!(first.id(), 1);
+        assert_eq!(second.id(), 3);
+        first.write_all(b"first").await.unwrap();
+        second.write_all(&[7u8; 100_000]).await.unwrap();
+        second.shutdown().await.unwrap();
+
+        let mut accepted = server.accept().await.unwrap();
+        assert_eq!(accepted.id(), 1);
+        let mut buf = [0u8; 5];
+        accepted.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"first");
+        accepted.write_all(b"reply").await.unwrap();
+        drop(accepted);
+
+        let mut accepted = server.accept().await.unwrap();
+        assert_eq!(accepted.id(), 3);
+        let mut buf = Vec::new();
+        accepted.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, vec![7u8; 100_000]);
+
+        let mut buf = Vec::new();
+        first.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"reply");
+    }
+
+    #[tokio::test]
+    async fn test_unread_channel_does_not_block_others() {
+        let (client, server) = tokio::io::duplex(1_000);
+        let (read, write) = tokio::io::split(client);
+        let (mut client, client_driver) = Mux::new(read, write, MuxSide::Client);
+        let (read, write) = tokio::io::split(server);
+        let (mut server, server_driver) = Mux::new(read, write, MuxSide::Server);
+        tokio::spawn(client_driver);
+        tokio::spawn(server_driver);
+
+        let len = 3 * WINDOW as usize;
+        let mut slow = client.open();
+        let mut fast = client.open();
+        let writer = tokio::spawn(async move {
+            slow.write_all(&vec![1u8; len]).await.unwrap();
+            slow.shutdown().await.unwrap();
+        });
+        let mut slow = server.accept().await.unwrap();
+
+        fast.write_all(b"fast").await.unwrap();
+        fast.shutdown().await.unwrap();
+        let mut fast = server.accept().await.unwrap();
+        let mut buf = Vec::new();
+        fast.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"fast");
+        assert!(!writer.is_finished());
+
+        let mut buf = Vec::new();
+        slow.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf.len(), len);
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_drop_after_full_window() {
+        let (client, server) = tokio::io::duplex(1_000);
+        let (read, write) = tokio::io::split(client);
+        let (mut client, client_driver) = Mux::new(read, write, MuxSide::Client);
+        let (read, write) = tokio::io::split(server);
+        let (mut server, server_driver) = Mux::new(read, write, MuxSide::Server);
+        tokio::spawn(client_driver);
+        tokio::spawn(server_driver);
+
+        let mut channel = client.open();
+        channel
+            .write_all(&vec![1u8; WINDOW as usize])
+            .await
+            .unwrap();
+        drop(channel);
+
+        let mut accepted = server.accept().await.unwrap();
+        let mut buf = Vec::new();
+        accepted.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf.len(), WINDOW as usize);
+    }
+
+    #[tokio::test]
+    async fn test_too_many_unaccepted_channels() {
+        let (client, server) = tokio::io::duplex(1_000);
+        let (read, write) = tokio::io::split(client);
+        let (mut client, client_driver) = Mux::new(read, write, MuxSide::Client);
+        let (read, write) = tokio::io::split(server);
+        let (mut server, server_driver) = Mux::new(read, write, MuxSide::Server);
+        tokio::spawn(client_driver);
+        tokio::spawn(server_driver);
+
+        let mut channels = Vec::new();
+        for _ in 0..=CHANNEL_QUEUE {
+            let mut channel = client.open();
+            channel.write_all(b"open").await.unwrap();
+            channels.push(channel);
+        }
+        let mut refused = channels.pop().unwrap();
+        let mut buf = Vec::new();
+        refused.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.is_empty());
+
+        for mut channel in channels {
+            let mut accepted = server.accept().await.unwrap();
+            assert_eq!(accepted.id(), channel.id());
+            let mut buf = [0u8; 4];
+            accepted.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"open");
+            accepted.write_all(b"ok").await.unwrap();
+            drop(accepted);
+            let mut buf = Vec::new();
+            channel.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, b"ok");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_window_exceeded() {
+        let mut input = Vec::new();
+        for _ in 0..=CHANNEL_QUEUE {
+            input.extend_from_slice(&1u64.to_le_bytes());
+            input.extend_from_slice(&(MAX_FRAME_LEN as u64).to_le_bytes());
+            input.extend_from_slice(&[0u8; MAX_FRAME_LEN]);
+        }
+        let (_mux, driver) = Mux::new(io::Cursor::new(input), tokio::io::sink(), MuxSide::Server);
+        let err = driver.await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_frame_too_long() {
+        let mut input = Vec::new();
+        input.extend_from_slice(&1u64.to_le_bytes());
+        input.extend_from_slice(&(MAX_FRAME_LEN as u64 + 1).to_le_bytes());
+        let (_mux, driver) = Mux::new(io::Cursor::new(input), tokio::io::sink(), MuxSide::Server);
+        let err = driver.await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,121 @@
+use std::cell::Cell;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use pin_project_lite::pin_project;
+
+/// Bounds on what the readers of [`AsyncSource`](super::AsyncSource) accept
+/// from a length prefix.
+///
+/// The limits in effect are set per task with
+/// [`with_read_limits`](WithReadLimits::with_read_limits) so that a server
+/// can bound how much memory each connection makes it allocate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadLimits {
+    /// Longest string or byte buffer that is read.
+    pub max_string_len: usize,
+    /// Largest number of items in a collection that is read.
+    pub max_collection_len: usize,
+    /// Most bytes or items allocated up front for a string or collection.
+    /// Longer ones grow as their data arrives.
+    pub initial_capacity: usize,
+    /// Most bytes a reader asks the peer for at a time, e.g. with
+    /// `STDERR_READ` in the daemon protocol.
+    pub max_buffer_capacity: usize,
+}
+
+impl ReadLimits {
+    pub const fn const_default() -> ReadLimits {
+        ReadLimits {
+            max_string_len: usize::MAX,
+            max_collection_len: usize::MAX,
+            initial_capacity: 64 * 1024,
+            max_buffer_capacity: 65_000,
+        }
+    }
+
+    /// The limits of the current task.
+    pub fn current() -> ReadLimits {
+        CURRENT_LIMITS
+            .try_with(|limits| limits.get())
+            .unwrap_or_else(|_| ReadLimits::const_default())
+    }
+
+    pub(crate) fn check_string_len(&self, len: usize) -> io::Result<()> {
+        if len > self.max_string_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("string is to long: {}", len),
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_collection_len(&self, len: usize) -> io::Result<()> {
+        if len > self.max_collection_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("collection is to long: {}", len),
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn initial_capacity(&self, len: usize) -> usize {
+        len.min(self.initial_capacity)
+    }
+}
+
+impl Default for ReadLimits {
+    fn default() -> Self {
+        ReadLimits::const_default()
+    }
+}
+
+thread_local! {
+    static CURRENT_LIMITS: Cell<ReadLimits> = Cell::new(ReadLimits::const_default());
+}
+
+struct ResetLimits(ReadLimits);
+
+impl Drop for ResetLimits {
+    fn drop(&mut self) {
+        let _ = CURRENT_LIMITS.try_with(|limits| limits.set(self.0));
+    }
+}
+
+pin_project! {
+    #[derive(Debug)]
+    #[must_use = "futures do nothing unless you `.await` or poll them"]
+    pub struct WithReadLimitsFuture<F> {
+        limits: ReadLimits,
+        #[pin]
+        inner: F,
+    }
+}
+
+impl<F: Future> Future for WithReadLimitsFuture<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let _reset = ResetLimits(CURRENT_LIMITS.with(|limits| limits.replace(*this.limits)));
+        this.inner.poll(cx)
+    }
+}
+
+pub trait WithReadLimits: Sized {
+    /// Use `limits` for all reads made while polling this future.
+    fn with_read_limits(self, limits: ReadLimits) -> WithReadLimitsFuture<Self>;
+}
+
+impl<F: Future> WithReadLimits for F {
+    fn with_read_limits(self, limits: ReadLimits) -> WithReadLimitsFuture<Self> {
+        WithReadLimitsFuture {
+            limits,
+            inner: self,
+        }
+    }
+}
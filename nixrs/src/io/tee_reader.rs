@@ -0,0 +1,221 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use bytes::{Buf, BytesMut};
+use pin_project_lite::pin_project;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// How many bytes [`TeeReader`] holds back for a slow sink before it stops
+/// reading.
+const MAX_PENDING: usize = 64 * 1024;
+
+pin_project! {
+    /// Writes everything read from `inner` to `sink` as well, e.g. to hash
+    /// a NAR with a [`HashSink`](crate::hash::HashSink) while a store reads
+    /// it.
+    ///
+    /// Reads are not held up by a sink that isn't ready: bytes it hasn't
+    /// taken yet are kept until it is, up to a limit after which reading
+    /// waits for the sink. End of file is only reported once the sink has
+    /// taken and flushed everything, so the sink is complete by the time
+    /// the reader is.
+    #[derive(Debug)]
+    pub struct TeeReader<R, W> {
+        #[pin]
+        inner: R,
+        #[pin]
+        sink: W,
+        pending: BytesMut,
+    }
+}
+
+impl<R, W> TeeReader<R, W> {
+    pub fn new(inner: R, sink: W) -> TeeReader<R, W> {
+        TeeReader {
+            inner,
+            sink,
+            pending: BytesMut::new(),
+        }
+    }
+
+    pub fn sink(&self) -> &W {
+        &self.sink
+    }
+
+    pub fn into_inner(self) -> (R, W) {
+        (self.inner, self.sink)
+    }
+}
+
+impl<R: AsyncRead, W: AsyncWrite> AsyncRead for TeeReader<R, W> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        // Give the sink what it didn't take last time.
+        while !this.pending.is_empty() {
+            match this.sink.as_mut().poll_write(cx, &this.pending[..])? {
+                Poll::Ready(0) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(n) => this.pending.advance(n),
+                Poll::Pending if this.pending.len() >= MAX_PENDING => return Poll::Pending,
+                Poll::Pending => break,
+            }
+        }
+
+        let before = buf.filled().len();
+        ready!(this.inner.poll_read(cx, buf))?;
+        let read = &buf.filled()[before..];
+        if read.is_empty() && buf.remaining() > 0 {
+            if !this.pending.is_empty() {
+                // Only reached when the sink is not ready, which has it wake
+                // us once it is.
+                buf.set_filled(before);
+                return Poll::Pending;
+            }
+            ready!(this.sink.as_mut().poll_flush(cx))?;
+            return Poll::Ready(Ok(()));
+        }
+        this.pending.extend_from_slice(read);
+        while !this.pending.is_empty() {
+            match this.sink.as_mut().poll_write(cx, &this.pending[..]) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(n)) => this.pending.advance(n),
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => break,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::hash::{digest, Algorithm, HashSink};
+
+    /// Every read and write is only ready the second time it is polled.
+    #[derive(Debug, Default)]
+    struct Slow {
+        chunks: VecDeque<Vec<u8>>,
+        written: Vec<u8>,
+        flushed: bool,
+        ready: bool,
+    }
+
+    impl Slow {
+        fn new(chunks: &[&[u8]]) -> Slow {
+            Slow {
+                chunks: chunks.iter().map(|c| c.to_vec()).collect(),
+                ..Default::default()
+            }
+        }
+
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+            if self.ready {
+                self.ready = false;
+                Poll::Ready(())
+            } else {
+                self.ready = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    impl AsyncRead for Slow {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            ready!(self.poll_ready(cx));
+            if let Some(mut chunk) = self.chunks.pop_front() {
+                let n = chunk.len().min(buf.remaining());
+                buf.put_slice(&chunk[..n]);
+                if n < chunk.len() {
+                    self.chunks.push_front(chunk.split_off(n));
+                }
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for Slow {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            ready!(self.poll_ready(cx));
+            let n = buf.len().min(3);
+            self.written.extend_from_slice(&buf[..n]);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.flushed = true;
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.poll_flush(cx)
+        }
+    }
+
+    #[test]
+    fn test_tee_polls() {
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut tee = TeeReader::new(Slow::new(&[b"hello ", b"world"]), Slow::default());
+        let mut tee = Pin::new(&mut tee);
+        let mut storage = [0u8; 16];
+        let mut buf = ReadBuf::new(&mut storage);
+
+        // The reader isn't ready the first time.
+        assert!(tee.as_mut().poll_read(&mut cx, &mut buf).is_pending());
+        assert!(tee.as_mut().poll_read(&mut cx, &mut buf).is_ready());
+        assert_eq!(buf.filled(), b"hello ");
+        // The sink wasn't ready either, so everything is still pending.
+        assert_eq!(tee.sink().written, b"");
+        assert_eq!(&tee.pending[..], b"hello ");
+
+        let mut rest = Vec::new();
+        while buf.filled().len() < 11 {
+            let _ = tee.as_mut().poll_read(&mut cx, &mut buf);
+        }
+        assert_eq!(buf.filled(), b"hello world");
+        // End of file waits for the sink to catch up.
+        loop {
+            let before = buf.filled().len();
+            match tee.as_mut().poll_read(&mut cx, &mut buf) {
+                Poll::Ready(Ok(())) if buf.filled().len() == before => break,
+                Poll::Ready(res) => res.unwrap(),
+                Poll::Pending => {}
+            }
+            rest.extend_from_slice(&buf.filled()[before..]);
+        }
+        assert!(rest.is_empty());
+        assert_eq!(tee.sink().written, b"hello world");
+        assert!(tee.sink().flushed);
+    }
+
+    #[tokio::test]
+    async fn test_tee_hash() {
+        let data = b"some NAR contents".repeat(10_000);
+        let mut tee = TeeReader::new(&data[..], HashSink::new(Algorithm::SHA256));
+        let mut read = Vec::new();
+        tee.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, data);
+        let (_, sink) = tee.into_inner();
+        let (size, hash) = sink.finish();
+        assert_eq!(size, data.len() as u64);
+        assert_eq!(hash, digest(Algorithm::SHA256, &data));
+    }
+}
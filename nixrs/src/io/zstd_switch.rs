@@ -0,0 +1,193 @@
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use async_compression::tokio::bufread::ZstdDecoder;
+use async_compression::tokio::write::ZstdEncoder;
+use tokio::io::{AsyncRead, AsyncWrite, BufReader, ReadBuf};
+
+/// Turns on zstd compression for both halves of a connection made with
+/// [`zstd_switch`].
+///
+/// Both ends have to switch at the same point in the stream, so this should
+/// only be enabled between messages, once everything sent before has been
+/// flushed and everything received before has been read.
+#[derive(Debug, Clone, Default)]
+pub struct ZstdSwitch(Arc<AtomicBool>);
+
+impl ZstdSwitch {
+    pub fn enable(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Wrap `reader` and `writer` so that they pass data through as it is until
+/// the returned [`ZstdSwitch`] is enabled, and read and write a zstd stream
+/// from then on.
+pub fn zstd_switch<R, W>(
+    reader: R,
+    writer: W,
+) -> (ZstdSwitchReader<R>, ZstdSwitchWriter<W>, ZstdSwitch)
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let switch = ZstdSwitch::default();
+    let reader = ZstdSwitchReader {
+        switch: switch.clone(),
+        inner: ReaderState::Plain(reader),
+    };
+    let writer = ZstdSwitchWriter {
+        switch: switch.clone(),
+        inner: WriterState::Plain(writer),
+    };
+    (reader, writer, switch)
+}
+
+enum ReaderState<R> {
+    Plain(R),
+    Zstd(Box<ZstdDecoder<BufReader<R>>>),
+    Switching,
+}
+
+pub struct ZstdSwitchReader<R> {
+    switch: ZstdSwitch,
+    inner: ReaderState<R>,
+}
+
+impl<R> fmt::Debug for ZstdSwitchReader<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ZstdSwitchReader")
+            .field("compressed", &matches!(self.inner, ReaderState::Zstd(_)))
+            .finish()
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ZstdSwitchReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if matches!(this.inner, ReaderState::Plain(_)) && this.switch.is_enabled() {
+            if let ReaderState::Plain(reader) =
+                std::mem::replace(&mut this.inner, ReaderState::Switching)
+            {
+                this.inner = ReaderState::Zstd(Box::new(ZstdDecoder::new(BufReader::new(reader))));
+            }
+        }
+        match &mut this.inner {
+            ReaderState::Plain(reader) => Pin::new(reader).poll_read(cx, buf),
+            ReaderState::Zstd(reader) => Pin::new(reader).poll_read(cx, buf),
+            ReaderState::Switching => unreachable!("switching reader to zstd"),
+        }
+    }
+}
+
+enum WriterState<W> {
+    Plain(W),
+    Zstd(Box<ZstdEncoder<W>>),
+    Switching,
+}
+
+pub struct ZstdSwitchWriter<W> {
+    switch: ZstdSwitch,
+    inner: WriterState<W>,
+}
+
+impl<W> fmt::Debug for ZstdSwitchWriter<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ZstdSwitchWriter")
+            .field("compressed", &matches!(self.inner, WriterState::Zstd(_)))
+            .finish()
+    }
+}
+
+impl<W> ZstdSwitchWriter<W> {
+    /// The writer that compressed or plain bytes end up in.
+    pub fn get_ref(&self) -> &W {
+        match &self.inner {
+            WriterState::Plain(writer) => writer,
+            WriterState::Zstd(writer) => writer.get_ref(),
+            WriterState::Switching => unreachable!("switching writer to zstd"),
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> ZstdSwitchWriter<W> {
+    fn inner(&mut self) -> Pin<&mut (dyn AsyncWrite + Unpin + '_)> {
+        if matches!(self.inner, WriterState::Plain(_)) && self.switch.is_enabled() {
+            if let WriterState::Plain(writer) =
+                std::mem::replace(&mut self.inner, WriterState::Switching)
+            {
+                self.inner = WriterState::Zstd(Box::new(ZstdEncoder::new(writer)));
+            }
+        }
+        match &mut self.inner {
+            WriterState::Plain(writer) => Pin::new(writer),
+            WriterState::Zstd(writer) => Pin::new(&mut **writer),
+            WriterState::Switching => unreachable!("switching writer to zstd"),
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for ZstdSwitchWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().inner().poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().inner().poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().inner().poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_switch_midstream() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (client_read, client_write) = tokio::io::split(client);
+        let (server_read, server_write) = tokio::io::split(server);
+        let (_, mut writer, client_switch) = zstd_switch(client_read, client_write);
+        let (mut reader, _, server_switch) = zstd_switch(server_read, server_write);
+
+        writer.write_all(b"plain ").await.unwrap();
+        writer.flush().await.unwrap();
+        let mut buf = [0u8; 6];
+        reader.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"plain ");
+
+        client_switch.enable();
+        server_switch.enable();
+        let data = b"compressed ".repeat(100);
+        writer.write_all(&data).await.unwrap();
+        writer.flush().await.unwrap();
+        let mut buf = vec![0u8; data.len()];
+        reader.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, data);
+        writer.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+}
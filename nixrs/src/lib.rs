@@ -2,11 +2,16 @@ use std::collections::BTreeSet;
 
 pub mod archive;
 pub mod base32;
+pub mod build;
 mod closure;
+#[cfg(feature = "fetch")]
+pub mod fetch;
 mod flag_enum;
 pub mod hash;
 pub mod io;
 mod num_enum;
+#[cfg(feature = "oci")]
+pub mod oci;
 pub mod path;
 pub mod path_info;
 pub mod signature;
@@ -15,6 +20,7 @@ pub mod store_path;
 pub mod tracing;
 
 pub use closure::compute_closure;
+pub use num_enum::OutOfRangeError;
 
 pub type StringSet = BTreeSet<String>;
 
@@ -1,3 +1,5 @@
+use thiserror::Error;
+
 pub trait NumEnum: Sized {
     type Rep: Sized;
     const REP_SIZE: usize = std::mem::size_of::<Self::Rep>();
@@ -6,7 +8,74 @@ pub trait NumEnum: Sized {
     fn members() -> Vec<(Self, Self::Rep)>;
 }
 
+/// The error for a value that doesn't match any member of a [`NumEnum`]
+/// declared with `#[enum_as(..)]`.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("{value} is not a valid {name}")]
+pub struct OutOfRangeError<T> {
+    pub name: &'static str,
+    pub value: T,
+}
+
+/// Declares an enum that is sent as a number.
+///
+/// Enums whose first variant holds the number (e.g. `Unknown(u64)`) turn
+/// every number into a member. Enums declared with `#[enum_as(u64)]`
+/// instead only implement `TryFrom` and fail with [`OutOfRangeError`] for
+/// numbers that aren't a member.
 macro_rules! num_enum {
+    (
+        #[enum_as($t:ty)]
+        $( #[$meta:meta] )*
+        $vis:vis enum $name:ident {
+            $($(#[$metai:meta])*
+            $i:ident = $v:literal),+$(,)?
+        }
+    ) => {
+        $( #[$meta] )*
+        $vis enum $name {
+            $( $(#[$metai])* $i ),+
+        }
+        impl $name {
+            #[allow(unused)]
+            pub fn value(&self) -> $t {
+                self.into()
+            }
+        }
+        impl TryFrom<$t> for $name {
+            type Error = $crate::num_enum::OutOfRangeError<$t>;
+            fn try_from(value: $t) -> Result<$name, Self::Error> {
+                match value {
+                    $($v => Ok($name::$i),)+
+                    value => Err($crate::num_enum::OutOfRangeError {
+                        name: stringify!($name),
+                        value,
+                    }),
+                }
+            }
+        }
+        impl From<$name> for $t {
+            fn from(value: $name) -> $t {
+                match value {
+                    $($name::$i => $v,)+
+                }
+            }
+        }
+        impl<'a> From<&'a $name> for $t {
+            fn from(value: &'a $name) -> $t {
+                match value {
+                    $($name::$i => $v,)+
+                }
+            }
+        }
+        impl $crate::num_enum::NumEnum for $name {
+            type Rep = $t;
+
+            fn members() -> Vec<($name, $t)> {
+                vec![$(($name::$i, $v)),+]
+            }
+        }
+    };
     (
         $( #[$meta:meta] )*
         $vis:vis enum $name:ident {
@@ -60,3 +129,48 @@ macro_rules! num_enum {
     }
 }
 pub(crate) use num_enum;
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Debug;
+
+    use super::*;
+    use crate::store::daemon::{GCAction, TrustLevel, WorkerProtoOp};
+    use crate::store::{BuildMode, BuildStatus};
+
+    fn assert_members<T>()
+    where
+        T: NumEnum + TryFrom<T::Rep> + Into<T::Rep> + Clone + PartialEq + Debug,
+        T::Rep: Copy + PartialEq + Debug,
+        <T as TryFrom<T::Rep>>::Error: Debug,
+    {
+        for (member, value) in T::members() {
+            assert_eq!(member.clone().into(), value);
+            assert_eq!(T::try_from(value).unwrap(), member);
+        }
+    }
+
+    #[test]
+    fn test_members_round_trip() {
+        assert_members::<BuildMode>();
+        assert_members::<BuildStatus>();
+        assert_members::<WorkerProtoOp>();
+        assert_members::<GCAction>();
+        assert_members::<TrustLevel>();
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        assert_eq!(BuildMode::from(13), BuildMode::Unknown(13));
+        let err = GCAction::try_from(4).unwrap_err();
+        assert_eq!(
+            err,
+            OutOfRangeError {
+                name: "GCAction",
+                value: 4
+            }
+        );
+        assert_eq!(err.to_string(), "4 is not a valid GCAction");
+        assert!(TrustLevel::try_from(3).is_err());
+    }
+}
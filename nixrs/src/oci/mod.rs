@@ -0,0 +1,498 @@
+//! Export a closure of store paths as an [OCI image layout].
+//!
+//! [OCI image layout]: https://github.com/opencontainers/image-spec/blob/main/image-layout.md
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::io::BufWriter;
+use tracing::debug;
+
+use crate::archive::parse_nar;
+use crate::hash::{self, Algorithm, Hash};
+use crate::store::{compute_fs_closure_slow, topo_sort_paths_slow, Error, Store};
+use crate::store_path::{StoreDir, StorePath, StorePathSet};
+
+mod tar;
+
+pub use tar::LayerWriter;
+
+pub const MEDIA_TYPE_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+pub const MEDIA_TYPE_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+pub const MEDIA_TYPE_CONFIG: &str = "application/vnd.oci.image.config.v1+json";
+pub const MEDIA_TYPE_LAYER: &str = "application/vnd.oci.image.layer.v1.tar";
+pub const ANNOTATION_REF_NAME: &str = "org.opencontainers.image.ref.name";
+
+/// Modification time of everything in the layers. Store paths have an
+/// mtime of 1 on disk too.
+pub const DEFAULT_MTIME: u64 = 1;
+/// Docker refuses images with more than 127 layers.
+pub const DEFAULT_MAX_LAYERS: usize = 100;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Descriptor {
+    pub media_type: String,
+    pub digest: String,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+}
+
+impl Descriptor {
+    fn new(media_type: &str, hash: &Hash, size: u64) -> Descriptor {
+        Descriptor {
+            media_type: media_type.into(),
+            digest: digest(hash),
+            size,
+            annotations: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageIndex {
+    pub schema_version: u32,
+    pub media_type: String,
+    pub manifests: Vec<Descriptor>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageManifest {
+    pub schema_version: u32,
+    pub media_type: String,
+    pub config: Descriptor,
+    pub layers: Vec<Descriptor>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ImageConfiguration {
+    pub created: String,
+    pub architecture: String,
+    pub os: String,
+    pub config: ContainerConfig,
+    pub rootfs: RootFs,
+    pub history: Vec<History>,
+}
+
+/// How to run a container from the image.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "PascalCase")]
+pub struct ContainerConfig {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub env: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub entrypoint: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cmd: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub working_dir: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RootFs {
+    #[serde(rename = "type")]
+    pub fs_type: String,
+    pub diff_ids: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct History {
+    pub created: String,
+    pub created_by: String,
+}
+
+/// Builds an OCI image from the closure of some store paths, without
+/// evaluating anything or needing `dockerTools`.
+///
+/// Every path in the closure gets a layer of its own with dependencies
+/// below the paths that reference them, so images that share paths share
+/// layers. When the closure has more than
+/// [`max_layers`](ImageBuilder::max_layers) paths the remaining ones end up
+/// together in the top layer. NARs are streamed straight into the layer
+/// tarballs and nothing in the image depends on when it was built, so the
+/// same closure always gives the same digests.
+///
+/// ```no_run
+/// # use nixrs::oci::ImageBuilder;
+/// # use nixrs::store::{Error, MemoryStore};
+/// # use nixrs::store_path::StorePathSet;
+/// # async fn export(store: &mut MemoryStore, roots: StorePathSet) -> Result<(), Error> {
+/// let manifest = ImageBuilder::new(roots)
+///     .entrypoint(vec!["/nix/store/...-hello/bin/hello".into()])
+///     .tag("hello:latest")
+///     .write_layout(store, "hello-image".as_ref())
+///     .await?;
+/// println!("wrote {}", manifest.digest);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ImageBuilder {
+    roots: StorePathSet,
+    config: ContainerConfig,
+    architecture: String,
+    os: String,
+    mtime: u64,
+    max_layers: usize,
+    tag: Option<String>,
+}
+
+impl ImageBuilder {
+    /// Image for the machine this is running on.
+    pub fn new(roots: StorePathSet) -> ImageBuilder {
+        ImageBuilder {
+            roots,
+            config: ContainerConfig::default(),
+            architecture: oci_architecture(std::env::consts::ARCH).into(),
+            os: "linux".into(),
+            mtime: DEFAULT_MTIME,
+            max_layers: DEFAULT_MAX_LAYERS,
+            tag: None,
+        }
+    }
+
+    /// Sets the platform from a Nix system like `aarch64-linux`.
+    pub fn system(mut self, system: &str) -> Self {
+        let (arch, os) = system.split_once('-').unwrap_or((system, "linux"));
+        self.architecture = oci_architecture(arch).into();
+        self.os = os.into();
+        self
+    }
+
+    /// Modification time of the files in the layers and creation time of
+    /// the image, in seconds since the epoch. Pass `SOURCE_DATE_EPOCH` here
+    /// to tie the image to a source release.
+    pub fn mtime(mut self, mtime: u64) -> Self {
+        self.mtime = mtime;
+        self
+    }
+
+    pub fn max_layers(mut self, max_layers: usize) -> Self {
+        self.max_layers = max_layers.max(1);
+        self
+    }
+
+    pub fn entrypoint(mut self, entrypoint: Vec<String>) -> Self {
+        self.config.entrypoint = entrypoint;
+        self
+    }
+
+    pub fn cmd(mut self, cmd: Vec<String>) -> Self {
+        self.config.cmd = cmd;
+        self
+    }
+
+    pub fn env(mut self, name: &str, value: &str) -> Self {
+        self.config.env.push(format!("{}={}", name, value));
+        self
+    }
+
+    pub fn working_dir(mut self, working_dir: &str) -> Self {
+        self.config.working_dir = Some(working_dir.into());
+        self
+    }
+
+    pub fn user(mut self, user: &str) -> Self {
+        self.config.user = Some(user.into());
+        self
+    }
+
+    pub fn label(mut self, name: &str, value: &str) -> Self {
+        self.config.labels.insert(name.into(), value.into());
+        self
+    }
+
+    /// Name of the image in `index.json`.
+    pub fn tag(mut self, tag: &str) -> Self {
+        self.tag = Some(tag.into());
+        self
+    }
+
+    /// Writes the image as an OCI image layout in `dir` and returns the
+    /// descriptor of its manifest. Blobs already in `dir` are kept but
+    /// `index.json` is replaced and only lists this image.
+    pub async fn write_layout<S>(&self, store: &mut S, dir: &Path) -> Result<Descriptor, Error>
+    where
+        S: Store + Send,
+    {
+        let blobs = dir.join("blobs").join("sha256");
+        fs::create_dir_all(&blobs).await?;
+        let closure = compute_fs_closure_slow(store, &self.roots, false).await?;
+        let sorted = topo_sort_paths_slow(store, &closure).await?;
+        let store_dir = store.store_dir();
+        let created = rfc3339(self.mtime);
+
+        let mut layers = Vec::new();
+        let mut diff_ids = Vec::new();
+        let mut history = Vec::new();
+        // Paths that don't get a layer of their own share the top one.
+        let own = if sorted.len() > self.max_layers {
+            self.max_layers - 1
+        } else {
+            sorted.len()
+        };
+        let mut groups: Vec<&[StorePath]> = sorted[..own].chunks(1).collect();
+        if own < sorted.len() {
+            groups.push(&sorted[own..]);
+        }
+        for paths in groups {
+            // Boxed since dumping and tarring a NAR at once makes for a big
+            // future.
+            let (hash, size) = Box::pin(self.write_layer(store, &store_dir, paths, &blobs)).await?;
+            layers.push(Descriptor::new(MEDIA_TYPE_LAYER, &hash, size));
+            diff_ids.push(digest(&hash));
+            history.push(History {
+                created: created.clone(),
+                created_by: paths
+                    .iter()
+                    .map(|path| store_dir.print_path(path))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            });
+        }
+        debug!("exported {} paths in {} layers", sorted.len(), layers.len());
+
+        let config = ImageConfiguration {
+            created,
+            architecture: self.architecture.clone(),
+            os: self.os.clone(),
+            config: self.config.clone(),
+            rootfs: RootFs {
+                fs_type: "layers".into(),
+                diff_ids,
+            },
+            history,
+        };
+        let config = write_json_blob(&blobs, MEDIA_TYPE_CONFIG, &config).await?;
+        let manifest = ImageManifest {
+            schema_version: 2,
+            media_type: MEDIA_TYPE_MANIFEST.into(),
+            config,
+            layers,
+        };
+        let mut manifest = write_json_blob(&blobs, MEDIA_TYPE_MANIFEST, &manifest).await?;
+        if let Some(tag) = &self.tag {
+            manifest
+                .annotations
+                .insert(ANNOTATION_REF_NAME.into(), tag.clone());
+        }
+
+        let index = ImageIndex {
+            schema_version: 2,
+            media_type: MEDIA_TYPE_INDEX.into(),
+            manifests: vec![manifest.clone()],
+        };
+        fs::write(dir.join("index.json"), to_json(&index)?).await?;
+        fs::write(dir.join("oci-layout"), br#"{"imageLayoutVersion":"1.0.0"}"#).await?;
+        Ok(manifest)
+    }
+
+    async fn write_layer<S>(
+        &self,
+        store: &mut S,
+        store_dir: &StoreDir,
+        paths: &[StorePath],
+        blobs: &Path,
+    ) -> Result<(Hash, u64), Error>
+    where
+        S: Store + Send,
+    {
+        let tmp = blobs.join(format!(".tmp-layer-{}", std::process::id()));
+        let file = fs::File::create(&tmp).await?;
+        let mut layer = LayerWriter::new(BufWriter::new(file), self.mtime);
+        let res = async {
+            let mut parent = Vec::new();
+            for component in store_dir.to_str().trim_matches('/').split('/') {
+                if !parent.is_empty() {
+                    parent.push(b'/');
+                }
+                parent.extend_from_slice(component.as_bytes());
+                layer.directory(&parent).await?;
+            }
+            for path in paths {
+                let root = format!("{}/{}", store_dir.to_str().trim_start_matches('/'), path);
+                let (reader, writer) = tokio::io::duplex(64 * 1024);
+                let (dumped, appended) = futures::join!(
+                    store.nar_from_path(path, writer),
+                    layer.append_nar(root.as_bytes(), parse_nar(reader))
+                );
+                dumped?;
+                appended?;
+            }
+            Ok::<_, Error>(())
+        }
+        .await;
+        if let Err(err) = res {
+            let _ = fs::remove_file(&tmp).await;
+            return Err(err);
+        }
+        let (_, size, hash) = layer.finish().await?;
+        fs::rename(&tmp, blobs.join(hash.encode_base16())).await?;
+        Ok((hash, size))
+    }
+}
+
+fn digest(hash: &Hash) -> String {
+    format!("{:x}", hash)
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<Vec<u8>, Error> {
+    serde_json::to_vec(value).map_err(|err| Error::Misc(err.to_string()))
+}
+
+async fn write_json_blob<T: Serialize>(
+    blobs: &Path,
+    media_type: &str,
+    value: &T,
+) -> Result<Descriptor, Error> {
+    let json = to_json(value)?;
+    let hash = hash::digest(Algorithm::SHA256, &json);
+    fs::write(blobs.join(hash.encode_base16()), &json).await?;
+    Ok(Descriptor::new(media_type, &hash, json.len() as u64))
+}
+
+/// OCI uses the architecture names of Go.
+fn oci_architecture(arch: &str) -> &str {
+    match arch {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "i686" | "x86" => "386",
+        "armv6l" | "armv7l" | "arm" => "arm",
+        "powerpc64le" => "ppc64le",
+        "mips64el" => "mips64le",
+        other => other,
+    }
+}
+
+/// `YYYY-MM-DDTHH:MM:SSZ` for seconds since the epoch.
+fn rfc3339(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+    // Civil date for a day count, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use super::*;
+    use crate::archive::test_data;
+    use crate::hash::Context;
+    use crate::path_info::ValidPathInfo;
+    use crate::store::{CheckSignaturesFlag, MemoryStore, RepairFlag};
+    use crate::store_path::StoreDirProvider;
+
+    async fn add(store: &mut MemoryStore, seed: &str, references: &[&StorePath]) -> StorePath {
+        let mut nar = BytesMut::new();
+        for event in test_data::dir_example() {
+            event.encode_into(&mut nar);
+        }
+        let mut ctx = Context::new(Algorithm::SHA256);
+        ctx.update(&nar);
+        let mut info = ValidPathInfo::new(StorePath::test_from_seed(seed), ctx.finish());
+        info.references = references.iter().map(|path| (*path).clone()).collect();
+        store
+            .add_to_store(
+                &info,
+                &nar[..],
+                RepairFlag::NoRepair,
+                CheckSignaturesFlag::NoCheckSigs,
+            )
+            .await
+            .unwrap();
+        info.path
+    }
+
+    fn read_json<T: serde::de::DeserializeOwned>(dir: &Path, descriptor: &Descriptor) -> T {
+        let hex = descriptor.digest.strip_prefix("sha256:").unwrap();
+        let data = std::fs::read(dir.join("blobs/sha256").join(hex)).unwrap();
+        assert_eq!(data.len() as u64, descriptor.size);
+        serde_json::from_slice(&data).unwrap()
+    }
+
+    #[test]
+    fn test_rfc3339() {
+        assert_eq!(rfc3339(1), "1970-01-01T00:00:01Z");
+        assert_eq!(rfc3339(1_697_253_889), "2023-10-14T03:24:49Z");
+    }
+
+    #[tokio::test]
+    async fn test_write_layout() {
+        let mut store = MemoryStore::new();
+        let lib = add(&mut store, "lib", &[]).await;
+        let bin = add(&mut store, "bin", &[&lib]).await;
+        let roots: StorePathSet = [bin.clone()].into_iter().collect();
+        let builder = ImageBuilder::new(roots)
+            .system("aarch64-linux")
+            .cmd(vec!["/bin/sh".into()])
+            .tag("test:latest");
+
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = builder.write_layout(&mut store, dir.path()).await.unwrap();
+        assert_eq!(manifest.annotations[ANNOTATION_REF_NAME], "test:latest");
+        let index: ImageIndex =
+            serde_json::from_slice(&std::fs::read(dir.path().join("index.json")).unwrap()).unwrap();
+        assert_eq!(index.manifests, vec![manifest.clone()]);
+
+        let image: ImageManifest = read_json(dir.path(), &manifest);
+        assert_eq!(image.layers.len(), 2);
+        let config: ImageConfiguration = read_json(dir.path(), &image.config);
+        assert_eq!(config.architecture, "arm64");
+        assert_eq!(config.created, "1970-01-01T00:00:01Z");
+        assert_eq!(config.config.cmd, vec!["/bin/sh".to_string()]);
+        assert_eq!(
+            config.history[0].created_by,
+            store.store_dir().print_path(&lib)
+        );
+        for (layer, diff_id) in image.layers.iter().zip(config.rootfs.diff_ids.iter()) {
+            assert_eq!(&layer.digest, diff_id);
+            let hex = layer.digest.strip_prefix("sha256:").unwrap();
+            let data = std::fs::read(dir.path().join("blobs/sha256").join(hex)).unwrap();
+            assert_eq!(hash::digest(Algorithm::SHA256, &data).encode_base16(), hex);
+        }
+
+        // Same closure, same image.
+        let other = tempfile::tempdir().unwrap();
+        let again = builder
+            .write_layout(&mut store, other.path())
+            .await
+            .unwrap();
+        assert_eq!(again, manifest);
+
+        let merged = builder
+            .max_layers(1)
+            .write_layout(&mut store, other.path())
+            .await
+            .unwrap();
+        let image: ImageManifest = read_json(other.path(), &merged);
+        assert_eq!(image.layers.len(), 1);
+    }
+}
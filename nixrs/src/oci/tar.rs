@@ -0,0 +1,288 @@
+use std::io;
+
+use futures::{Stream, StreamExt};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::pin;
+
+use crate::archive::NAREvent;
+use crate::hash::{Algorithm, Context, Hash};
+
+const BLOCK_SIZE: usize = 512;
+/// Largest size that fits in the 11 octal digits of a ustar header.
+const MAX_USTAR_SIZE: u64 = 0o77777777777;
+
+const REGULAR: u8 = b'0';
+const SYMLINK: u8 = b'2';
+const DIRECTORY: u8 = b'5';
+const PAX_HEADER: u8 = b'x';
+
+/// Writes an uncompressed tar stream of store paths to `writer` while
+/// computing its SHA-256 digest.
+///
+/// Every entry is owned by root and gets the same modification time so the
+/// same paths always give the same bytes. Names that don't fit in a ustar
+/// header are written as PAX extended headers.
+#[derive(Debug)]
+pub struct LayerWriter<W> {
+    writer: W,
+    ctx: Context,
+    size: u64,
+    mtime: u64,
+}
+
+impl<W> LayerWriter<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    pub fn new(writer: W, mtime: u64) -> LayerWriter<W> {
+        LayerWriter {
+            writer,
+            ctx: Context::new(Algorithm::SHA256),
+            size: 0,
+            mtime,
+        }
+    }
+
+    /// Adds a read-only directory entry for `path`.
+    pub async fn directory(&mut self, path: &[u8]) -> io::Result<()> {
+        let mut name = path.to_vec();
+        name.push(b'/');
+        self.header(&name, DIRECTORY, 0o555, 0, b"").await
+    }
+
+    /// Adds the NAR in `events` with its root at `root`.
+    pub async fn append_nar<S>(&mut self, root: &[u8], events: S) -> io::Result<()>
+    where
+        S: Stream<Item = io::Result<NAREvent>>,
+    {
+        pin!(events);
+        let mut path = root.to_vec();
+        let mut parents = Vec::new();
+        let mut remaining = 0;
+        while let Some(event) = events.next().await {
+            match event? {
+                NAREvent::Magic(_) => {}
+                NAREvent::Directory => self.directory(&path).await?,
+                NAREvent::DirectoryEntry { name } => {
+                    parents.push(path.len());
+                    path.push(b'/');
+                    path.extend_from_slice(&name);
+                }
+                NAREvent::EndDirectoryEntry => {
+                    if let Some(len) = parents.pop() {
+                        path.truncate(len);
+                    }
+                }
+                NAREvent::EndDirectory => {}
+                NAREvent::SymlinkNode { target } => {
+                    self.header(&path, SYMLINK, 0o777, 0, &target).await?
+                }
+                NAREvent::RegularNode {
+                    executable, size, ..
+                } => {
+                    let mode = if executable { 0o555 } else { 0o444 };
+                    self.header(&path, REGULAR, mode, size, b"").await?;
+                    remaining = size;
+                }
+                NAREvent::Contents { buf, .. } => {
+                    self.write(&buf).await?;
+                    remaining = remaining.saturating_sub(buf.len() as u64);
+                    if remaining == 0 {
+                        self.pad().await?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the end of archive marker and returns the writer, the size
+    /// of the archive and its digest.
+    pub async fn finish(mut self) -> io::Result<(W, u64, Hash)> {
+        self.write(&[0; 2 * BLOCK_SIZE]).await?;
+        self.writer.flush().await?;
+        Ok((self.writer, self.size, self.ctx.finish()))
+    }
+
+    async fn header(
+        &mut self,
+        path: &[u8],
+        kind: u8,
+        mode: u32,
+        size: u64,
+        link: &[u8],
+    ) -> io::Result<()> {
+        let mut records = Vec::new();
+        if path.len() > 100 {
+            pax_record(&mut records, "path", path);
+        }
+        if link.len() > 100 {
+            pax_record(&mut records, "linkpath", link);
+        }
+        if size > MAX_USTAR_SIZE {
+            pax_record(&mut records, "size", size.to_string().as_bytes());
+        }
+        if !records.is_empty() {
+            let block = ustar_header(
+                b"././@PaxHeader",
+                PAX_HEADER,
+                0o644,
+                records.len() as u64,
+                b"",
+                self.mtime,
+            );
+            self.write(&block).await?;
+            self.write(&records).await?;
+            self.pad().await?;
+        }
+        let block = ustar_header(path, kind, mode, size.min(MAX_USTAR_SIZE), link, self.mtime);
+        self.write(&block).await
+    }
+
+    /// Pads with zeros to the end of the current block.
+    async fn pad(&mut self) -> io::Result<()> {
+        let rem = (self.size % BLOCK_SIZE as u64) as usize;
+        if rem != 0 {
+            self.write(&[0; BLOCK_SIZE][rem..]).await?;
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.ctx.update(buf);
+        self.size += buf.len() as u64;
+        self.writer.write_all(buf).await
+    }
+}
+
+/// A PAX record is `<len> <key>=<value>\n` where `<len>` counts the whole
+/// record including its own digits.
+fn pax_record(out: &mut Vec<u8>, key: &str, value: &[u8]) {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+    out.extend_from_slice(format!("{} {}=", len, key).as_bytes());
+    out.extend_from_slice(value);
+    out.push(b'\n');
+}
+
+fn ustar_header(
+    path: &[u8],
+    kind: u8,
+    mode: u32,
+    size: u64,
+    link: &[u8],
+    mtime: u64,
+) -> [u8; BLOCK_SIZE] {
+    let mut block = [0u8; BLOCK_SIZE];
+    copy_truncated(&mut block[0..100], path);
+    octal(&mut block[100..108], mode as u64);
+    octal(&mut block[108..116], 0);
+    octal(&mut block[116..124], 0);
+    octal(&mut block[124..136], size);
+    octal(&mut block[136..148], mtime);
+    block[156] = kind;
+    copy_truncated(&mut block[157..257], link);
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+    copy_truncated(&mut block[265..297], b"root");
+    copy_truncated(&mut block[297..329], b"root");
+
+    // The checksum is computed with its own field set to spaces.
+    block[148..156].copy_from_slice(b"        ");
+    let checksum: u64 = block.iter().map(|b| *b as u64).sum();
+    block[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+    block
+}
+
+fn copy_truncated(field: &mut [u8], value: &[u8]) {
+    let len = value.len().min(field.len());
+    field[..len].copy_from_slice(&value[..len]);
+}
+
+/// Zero padded octal number followed by a NUL, filling `field`.
+fn octal(field: &mut [u8], value: u64) {
+    let n = field.len() - 1;
+    let digits = format!("{:0width$o}", value, width = n);
+    let start = digits.len() - n;
+    field[..n].copy_from_slice(&digits.as_bytes()[start..]);
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream::iter;
+
+    use super::*;
+    use crate::archive::test_data;
+
+    fn entries(tar: &[u8]) -> Vec<(Vec<u8>, u8, u64)> {
+        let mut ret = Vec::new();
+        let mut pos = 0;
+        while tar[pos..pos + BLOCK_SIZE].iter().any(|b| *b != 0) {
+            let block = &tar[pos..pos + BLOCK_SIZE];
+            let name_len = block[..100].iter().position(|b| *b == 0).unwrap_or(100);
+            let size = std::str::from_utf8(&block[124..135]).unwrap();
+            let size = u64::from_str_radix(size, 8).unwrap();
+            let checksum = std::str::from_utf8(&block[148..154]).unwrap();
+            let checksum = u64::from_str_radix(checksum, 8).unwrap();
+            let actual: u64 = block
+                .iter()
+                .enumerate()
+                .map(|(i, b)| {
+                    if (148..156).contains(&i) {
+                        32
+                    } else {
+                        *b as u64
+                    }
+                })
+                .sum();
+            assert_eq!(checksum, actual);
+            ret.push((block[..name_len].to_vec(), block[156], size));
+            pos += BLOCK_SIZE + (size as usize + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+        }
+        assert_eq!(tar.len(), pos + 2 * BLOCK_SIZE);
+        ret
+    }
+
+    #[tokio::test]
+    async fn test_append_nar() {
+        let mut layer = LayerWriter::new(Vec::new(), 1);
+        layer.directory(b"nix").await.unwrap();
+        let events = iter(test_data::dir_example().into_iter().map(Ok));
+        layer.append_nar(b"nix/out", events).await.unwrap();
+        let (tar, size, hash) = layer.finish().await.unwrap();
+        assert_eq!(size, tar.len() as u64);
+        assert_eq!(hash, crate::hash::digest(Algorithm::SHA256, &tar));
+
+        let names: Vec<_> = entries(&tar)
+            .into_iter()
+            .map(|(name, kind, size)| (String::from_utf8(name).unwrap(), kind, size))
+            .collect();
+        assert_eq!(names[0], ("nix/".to_string(), DIRECTORY, 0));
+        assert_eq!(names[1], ("nix/out/".to_string(), DIRECTORY, 0));
+        assert!(names
+            .iter()
+            .any(|(name, kind, size)| name == "nix/out/dir/more/Deep"
+                && *kind == REGULAR
+                && *size > 0));
+    }
+
+    #[tokio::test]
+    async fn test_long_names() {
+        let long = vec![b'a'; 150];
+        let mut layer = LayerWriter::new(Vec::new(), 1);
+        layer.directory(&long).await.unwrap();
+        let (tar, _, _) = layer.finish().await.unwrap();
+        let entries = entries(&tar);
+        assert_eq!(entries[0].0, b"././@PaxHeader");
+        assert_eq!(entries[0].1, PAX_HEADER);
+        let mut expected = Vec::new();
+        pax_record(&mut expected, "path", &[&long[..], b"/"].concat());
+        assert_eq!(&expected[..4], b"161 ");
+        assert_eq!(expected.len(), 161);
+        assert_eq!(&tar[BLOCK_SIZE..BLOCK_SIZE + expected.len()], &expected[..]);
+        assert_eq!(entries[1].1, DIRECTORY);
+    }
+}
@@ -0,0 +1,272 @@
+use std::time::{Duration, SystemTime};
+
+use thiserror::Error;
+
+use crate::hash::{Algorithm, Hash};
+use crate::signature::{Signature, SignatureSet};
+use crate::store_path::{
+    ContentAddress, ContentAddressWithReferences, FileIngestionMethod, ParseContentAddressError,
+    StoreDir, StorePath, StorePathSet,
+};
+
+use super::ValidPathInfo;
+
+#[derive(Error, Debug, PartialEq, Clone)]
+pub enum BuildPathInfoError {
+    #[error("NAR hash of path '{0}' must use sha256 but uses {1}")]
+    NarHashAlgorithm(String, Algorithm),
+    #[error("NAR size of path '{0}' is missing")]
+    MissingNarSize(String),
+    #[error("deriver '{1}' of path '{0}' is not a derivation")]
+    DeriverNotDerivation(String, String),
+    #[error("invalid content address of path '{0}': {1}")]
+    ContentAddress(String, #[source] ParseContentAddressError),
+    #[error("path '{0}' does not match its content address")]
+    ContentAddressMismatch(String),
+}
+
+/// Builds a [`ValidPathInfo`] and checks that its fields fit together.
+///
+/// ```
+/// # use nixrs::hash::{digest, Algorithm};
+/// # use nixrs::path_info::ValidPathInfo;
+/// # use nixrs::store_path::{StoreDir, StorePath};
+/// let store_dir = StoreDir::default();
+/// let path = StorePath::new_from_base_name("7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-konsole-18.12.3").unwrap();
+/// let info = ValidPathInfo::builder(path, digest(Algorithm::SHA256, "nar"))
+///     .nar_size(3)
+///     .registration_time_secs(1_700_000_000)
+///     .build(&store_dir)
+///     .unwrap();
+/// assert_eq!(info.nar_size, 3);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ValidPathInfoBuilder {
+    path: StorePath,
+    nar_hash: Hash,
+    nar_size: Option<u64>,
+    deriver: Option<StorePath>,
+    references: StorePathSet,
+    sigs: SignatureSet,
+    registration_time: SystemTime,
+    ultimate: bool,
+    ca: Option<Result<ContentAddress, ParseContentAddressError>>,
+}
+
+impl ValidPathInfoBuilder {
+    pub fn new(path: StorePath, nar_hash: Hash) -> ValidPathInfoBuilder {
+        ValidPathInfoBuilder {
+            path,
+            nar_hash,
+            nar_size: None,
+            deriver: None,
+            references: StorePathSet::new(),
+            sigs: SignatureSet::new(),
+            registration_time: SystemTime::UNIX_EPOCH,
+            ultimate: false,
+            ca: None,
+        }
+    }
+
+    /// Size of the NAR in bytes.
+    pub fn nar_size(&mut self, nar_size: u64) -> &mut Self {
+        self.nar_size = Some(nar_size);
+        self
+    }
+
+    pub fn deriver(&mut self, deriver: StorePath) -> &mut Self {
+        self.deriver = Some(deriver);
+        self
+    }
+
+    /// Add references. They may come in any order and repeat.
+    pub fn references<I>(&mut self, references: I) -> &mut Self
+    where
+        I: IntoIterator<Item = StorePath>,
+    {
+        self.references.extend(references);
+        self
+    }
+
+    pub fn signature(&mut self, sig: Signature) -> &mut Self {
+        self.sigs.insert(sig);
+        self
+    }
+
+    pub fn registration_time(&mut self, time: SystemTime) -> &mut Self {
+        self.registration_time = time;
+        self
+    }
+
+    /// Registration time in seconds since the epoch, like the Nix database
+    /// and the daemon protocol use.
+    pub fn registration_time_secs(&mut self, secs: u64) -> &mut Self {
+        self.registration_time = SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        self
+    }
+
+    pub fn ultimate(&mut self, ultimate: bool) -> &mut Self {
+        self.ultimate = ultimate;
+        self
+    }
+
+    pub fn ca(&mut self, ca: ContentAddress) -> &mut Self {
+        self.ca = Some(Ok(ca));
+        self
+    }
+
+    /// Content address in its textual form, like `fixed:r:sha256:...`.
+    /// It is checked by [`build`](Self::build).
+    pub fn ca_str(&mut self, ca: &str) -> &mut Self {
+        self.ca = Some(ContentAddress::parse(ca));
+        self
+    }
+
+    pub fn build(&self, store_dir: &StoreDir) -> Result<ValidPathInfo, BuildPathInfoError> {
+        let print = || store_dir.print_path(&self.path);
+        if self.nar_hash.algorithm() != Algorithm::SHA256 {
+            return Err(BuildPathInfoError::NarHashAlgorithm(
+                print(),
+                self.nar_hash.algorithm(),
+            ));
+        }
+        let nar_size = self
+            .nar_size
+            .ok_or_else(|| BuildPathInfoError::MissingNarSize(print()))?;
+        if let Some(deriver) = self.deriver.as_ref() {
+            if !deriver.is_derivation() {
+                return Err(BuildPathInfoError::DeriverNotDerivation(
+                    print(),
+                    store_dir.print_path(deriver),
+                ));
+            }
+        }
+        let ca = match self.ca.clone() {
+            None => None,
+            Some(Ok(ca)) => Some(ca),
+            Some(Err(err)) => return Err(BuildPathInfoError::ContentAddress(print(), err)),
+        };
+        let info = ValidPathInfo {
+            path: self.path.clone(),
+            deriver: self.deriver.clone(),
+            nar_size,
+            nar_hash: self.nar_hash,
+            references: self.references.clone(),
+            sigs: self.sigs.clone(),
+            registration_time: self.registration_time,
+            ultimate: self.ultimate,
+            ca,
+        };
+        if let Some(ca) = info.ca.as_ref() {
+            if !matches_content_address(store_dir, &info, ca) {
+                return Err(BuildPathInfoError::ContentAddressMismatch(print()));
+            }
+        }
+        Ok(info)
+    }
+}
+
+fn matches_content_address(
+    store_dir: &StoreDir,
+    info: &ValidPathInfo,
+    ca: &ContentAddress,
+) -> bool {
+    use crate::store_path::ContentAddressMethod::*;
+    // Only text and recursive SHA-256 content addresses can have references
+    // and text ones can't refer to themselves.
+    let can_refer = match ca.method {
+        Text => !info.references.contains(&info.path),
+        Fixed(method) => {
+            method == FileIngestionMethod::Recursive && ca.hash.algorithm() == Algorithm::SHA256
+        }
+    };
+    if !can_refer && !info.references.is_empty() {
+        return false;
+    }
+    let ca_refs: ContentAddressWithReferences = match info.content_address_with_references() {
+        Some(ca_refs) => ca_refs,
+        None => return false,
+    };
+    match store_dir.make_fixed_output_path_from_ca(info.path.name.name(), &ca_refs) {
+        Ok(path) => path == info.path,
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use super::*;
+    use crate::hash::digest;
+    use crate::store_path::{FixedOutputInfo, StoreReferences};
+
+    fn nar_hash() -> Hash {
+        digest(Algorithm::SHA256, "nar")
+    }
+
+    #[test]
+    fn test_build_checks_nar_hash() {
+        let path = StorePath::test_from_seed("foo");
+        let res = ValidPathInfo::builder(path, digest(Algorithm::SHA1, "nar"))
+            .nar_size(3)
+            .build(&StoreDir::default());
+        assert_matches!(
+            res,
+            Err(BuildPathInfoError::NarHashAlgorithm(_, Algorithm::SHA1))
+        );
+    }
+
+    #[test]
+    fn test_build_requires_nar_size() {
+        let path = StorePath::test_from_seed("foo");
+        let res = ValidPathInfo::builder(path, nar_hash()).build(&StoreDir::default());
+        assert_matches!(res, Err(BuildPathInfoError::MissingNarSize(_)));
+    }
+
+    #[test]
+    fn test_build_checks_deriver() {
+        let path = StorePath::test_from_seed("foo");
+        let res = ValidPathInfo::builder(path, nar_hash())
+            .nar_size(3)
+            .deriver(StorePath::test_from_seed("bar"))
+            .build(&StoreDir::default());
+        assert_matches!(res, Err(BuildPathInfoError::DeriverNotDerivation(_, _)));
+    }
+
+    #[test]
+    fn test_build_content_address() {
+        let store_dir = StoreDir::default();
+        let ca = ContentAddress::fixed(FileIngestionMethod::Recursive, nar_hash());
+        let ca_refs = ContentAddressWithReferences::Fixed(FixedOutputInfo {
+            method: FileIngestionMethod::Recursive,
+            hash: nar_hash(),
+            references: StoreReferences {
+                others: StorePathSet::new(),
+                self_ref: false,
+            },
+        });
+        let path = store_dir
+            .make_fixed_output_path_from_ca("source", &ca_refs)
+            .unwrap();
+
+        let info = ValidPathInfo::builder(path.clone(), nar_hash())
+            .nar_size(3)
+            .ca_str(&ca.to_string())
+            .build(&store_dir)
+            .unwrap();
+        assert_eq!(info.ca, Some(ca));
+
+        let res = ValidPathInfo::builder(StorePath::test_from_seed("source"), nar_hash())
+            .nar_size(3)
+            .ca(ca)
+            .build(&store_dir);
+        assert_matches!(res, Err(BuildPathInfoError::ContentAddressMismatch(_)));
+
+        let res = ValidPathInfo::builder(path, nar_hash())
+            .nar_size(3)
+            .ca_str("fixed:nonsense")
+            .build(&store_dir);
+        assert_matches!(res, Err(BuildPathInfoError::ContentAddress(_, _)));
+    }
+}
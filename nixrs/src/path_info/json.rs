@@ -0,0 +1,103 @@
+use std::time::SystemTime;
+
+use serde::Serialize;
+
+use super::ValidPathInfo;
+use crate::store_path::StoreDir;
+
+/// A path info in the format of `nix path-info --json`.
+///
+/// The fields are in alphabetical order because that is the order Nix
+/// writes them in.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PathInfoJson {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ca: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    closure_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deriver: Option<String>,
+    nar_hash: String,
+    nar_size: u64,
+    path: String,
+    references: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    registration_time: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    signatures: Vec<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    ultimate: bool,
+}
+
+impl ValidPathInfo {
+    /// This info as `nix path-info --json` prints it, with `closureSize`
+    /// only when `closure_size` is given.
+    pub fn to_json(
+        &self,
+        store_dir: &StoreDir,
+        closure_size: Option<u64>,
+    ) -> serde_json::Result<serde_json::Value> {
+        let registration_time = self
+            .registration_time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let json = PathInfoJson {
+            ca: self.ca.as_ref().map(|ca| ca.to_string()),
+            closure_size,
+            deriver: self.deriver.as_ref().map(|p| store_dir.print_path(p)),
+            nar_hash: self.nar_hash.to_sri().to_string(),
+            nar_size: self.nar_size,
+            path: store_dir.print_path(&self.path),
+            references: self
+                .references
+                .iter()
+                .map(|p| store_dir.print_path(p))
+                .collect(),
+            registration_time: (registration_time != 0).then_some(registration_time),
+            signatures: self.sigs.iter().map(|s| s.to_string()).collect(),
+            ultimate: self.ultimate,
+        };
+        serde_json::to_value(json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::hash;
+    use crate::store_path::StorePath;
+
+    #[test]
+    fn test_to_json() {
+        let store_dir = StoreDir::default();
+        let path =
+            StorePath::new_from_base_name("7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-konsole-18.12.3")
+                .unwrap();
+        let reference =
+            StorePath::new_from_base_name("ldhh7c134ap5swsm86rqnc0i7cinqvrc-konsole-18.12.3")
+                .unwrap();
+        let mut info = ValidPathInfo::new(
+            path.clone(),
+            hash::digest(hash::Algorithm::SHA256, "nar contents"),
+        );
+        info.nar_size = 12;
+        info.references.insert(reference);
+        info.registration_time = SystemTime::UNIX_EPOCH + Duration::from_secs(1700000000);
+
+        let expected = json!({
+            "closureSize": 24,
+            "narHash": info.nar_hash.to_sri().to_string(),
+            "narSize": 12,
+            "path": "/nix/store/7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-konsole-18.12.3",
+            "references": ["/nix/store/ldhh7c134ap5swsm86rqnc0i7cinqvrc-konsole-18.12.3"],
+            "registrationTime": 1700000000,
+        });
+        assert_eq!(info.to_json(&store_dir, Some(24)).unwrap(), expected);
+    }
+}
@@ -1,7 +1,10 @@
+mod builder;
+mod json;
 mod nar_info;
 mod valid_path_info;
 
-pub use nar_info::{Compression, NarInfo, ParseNarInfoError};
+pub use builder::{BuildPathInfoError, ValidPathInfoBuilder};
+pub use nar_info::{Compression, NarInfo, ParseMode, ParseNarInfoError};
 pub use valid_path_info::{InvalidPathInfo, ValidPathInfo};
 
 #[cfg(any(test, feature = "test"))]
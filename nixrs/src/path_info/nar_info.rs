@@ -1,8 +1,10 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::convert::Infallible;
 use std::str::FromStr;
-use std::{collections::BTreeMap, fmt, num::ParseIntError, time::SystemTime};
+use std::{fmt, num::ParseIntError, time::SystemTime};
 
 use thiserror::Error;
+use tracing::warn;
 
 use super::ValidPathInfo;
 use crate::hash::{Hash, ParseHashError};
@@ -103,6 +105,27 @@ impl FromStr for Compression {
     }
 }
 
+/// How forgiving [`NarInfo::parse_with`] is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ParseMode {
+    /// Fail on lines that are not `key: value` pairs, on fields other than
+    /// `Sig` that appear more than once and on any field that doesn't parse.
+    #[default]
+    Strict,
+    /// Skip lines that are not `key: value` pairs and signatures, derivers,
+    /// content addresses and file hashes or sizes that don't parse, which
+    /// some binary caches in the wild have. The last of repeated fields
+    /// wins. Missing or broken store paths, NAR hashes and sizes and
+    /// references still fail.
+    Lenient,
+}
+
+impl ParseMode {
+    fn is_optional(key: &str) -> bool {
+        matches!(key, "Sig" | "Deriver" | "CA" | "FileHash" | "FileSize")
+    }
+}
+
 #[derive(Debug, Eq, PartialOrd, Ord, Clone)]
 pub struct NarInfo {
     pub path_info: ValidPathInfo,
@@ -189,7 +212,16 @@ impl NarInfo {
         }
     }
 
+    /// Parse a `.narinfo` file in [`ParseMode::Strict`].
     pub fn parse(store_dir: &StoreDir, s: &str) -> Result<NarInfo, ParseNarInfoError> {
+        NarInfo::parse_with(store_dir, s, ParseMode::Strict)
+    }
+
+    pub fn parse_with(
+        store_dir: &StoreDir,
+        s: &str,
+        mode: ParseMode,
+    ) -> Result<NarInfo, ParseNarInfoError> {
         let mut path = None;
         let mut url = String::new();
         let mut compression = Default::default();
@@ -202,11 +234,24 @@ impl NarInfo {
         let mut sigs = SignatureSet::new();
         let mut ca = None;
         let mut extra = BTreeMap::new();
+        let mut seen = BTreeSet::new();
 
         for line in s.split('\n') {
             let mut kv = line.splitn(2, ": ");
             let key = kv.next().unwrap();
-            if let Some(value) = kv.next() {
+            let value = match kv.next() {
+                Some(value) => value,
+                None if line.trim().is_empty() => continue,
+                None if mode == ParseMode::Lenient => {
+                    warn!("ignoring invalid line '{}' in narinfo", line);
+                    continue;
+                }
+                None => return Err(ParseNarInfoError::InvalidLine(line.into())),
+            };
+            if key != "Sig" && !seen.insert(key) && mode == ParseMode::Strict {
+                return Err(ParseNarInfoError::DuplicateField(key.into()));
+            }
+            let mut parse_field = || -> Result<(), ParseNarInfoError> {
                 match key {
                     "StorePath" => {
                         path = Some(store_dir.parse_path(value)?);
@@ -238,12 +283,17 @@ impl NarInfo {
                             ca = Some(ContentAddress::parse(value)?);
                         }
                     }
-                    e => {
-                        extra.insert(key.into(), e.into());
+                    _ => {
+                        extra.insert(key.into(), value.into());
                     }
                 }
-            } else if !line.trim().is_empty() {
-                return Err(ParseNarInfoError::InvalidLine(line.into()));
+                Ok(())
+            };
+            match parse_field() {
+                Err(err) if mode == ParseMode::Lenient && ParseMode::is_optional(key) => {
+                    warn!("ignoring invalid {} in narinfo: {}", key, err);
+                }
+                res => res?,
             }
         }
         if path.is_none() {
@@ -324,6 +374,12 @@ impl From<ValidPathInfo> for NarInfo {
     }
 }
 
+impl From<NarInfo> for ValidPathInfo {
+    fn from(nar_info: NarInfo) -> Self {
+        nar_info.path_info
+    }
+}
+
 impl StateParse<NarInfo> for StoreDir {
     type Err = ParseNarInfoError;
 
@@ -366,6 +422,8 @@ pub enum ParseNarInfoError {
     ),
     #[error("invalid line {0}")]
     InvalidLine(String),
+    #[error("duplicate field {0}")]
+    DuplicateField(String),
     #[error("missing StorePath")]
     MissingStorePath,
     #[error("missing URL")]
@@ -538,4 +596,55 @@ mod tests {
 
         assert_eq!(key.verify(fingerprint, &sig), true);
     }
+
+    const HELLO: &str = "StorePath: /nix/store/ycbqd7822qcnasaqy0mmiv2j9n9m62yl-hello-2.12.1
+URL: nar/0vpy0ghvb98n2s928ldw855rnk2qadi4pyqmy74fvwnl2x086kyc.nar.xz
+NarHash: sha256:1bnz0km10yckg8808px5ifdbd7hwkl8fhi2hbvzdlnf269xmb55a
+NarSize: 74704
+References: 
+";
+
+    #[test]
+    fn test_narinfo_parse_extra_fields() {
+        let store_dir = StoreDir::default();
+        let data = format!("{}System: x86_64-linux\n", HELLO);
+        let info = NarInfo::parse(&store_dir, &data).unwrap();
+        let mut extra = BTreeMap::new();
+        extra.insert("System".to_string(), "x86_64-linux".to_string());
+        assert_eq!(info.extra, extra);
+    }
+
+    #[test]
+    fn test_narinfo_parse_strict() {
+        let store_dir = StoreDir::default();
+        let data = format!("{}NarSize: 74704\n", HELLO);
+        assert!(matches!(
+            NarInfo::parse(&store_dir, &data),
+            Err(ParseNarInfoError::DuplicateField(field)) if field == "NarSize"
+        ));
+        let data = format!("{}Sig: garbage\n", HELLO);
+        assert!(matches!(
+            NarInfo::parse(&store_dir, &data),
+            Err(ParseNarInfoError::ParseSignatureError(_))
+        ));
+    }
+
+    #[test]
+    fn test_narinfo_parse_lenient() {
+        let store_dir = StoreDir::default();
+        let data = format!(
+            "{}Sig: garbage\nDeriver: not a path\nnot a field\nNarSize: 74704\n",
+            HELLO
+        );
+        let info = NarInfo::parse_with(&store_dir, &data, ParseMode::Lenient).unwrap();
+        assert_eq!(info.path_info.nar_size, 74_704);
+        assert_eq!(info.path_info.sigs, SignatureSet::new());
+        assert_eq!(info.path_info.deriver, None);
+
+        let data = HELLO.replace("NarSize: 74704", "NarSize: lots");
+        assert!(matches!(
+            NarInfo::parse_with(&store_dir, &data, ParseMode::Lenient),
+            Err(ParseNarInfoError::ParseIntError(_))
+        ));
+    }
 }
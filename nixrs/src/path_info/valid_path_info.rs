@@ -15,6 +15,8 @@ use crate::store_path::{
 };
 use crate::StringSet;
 
+use super::ValidPathInfoBuilder;
+
 #[derive(Debug, Eq, PartialOrd, Ord, Clone)]
 pub struct ValidPathInfo {
     pub path: StorePath,
@@ -94,6 +96,11 @@ impl ValidPathInfo {
         }
     }
 
+    /// Build a path info with checks, see [`ValidPathInfoBuilder`].
+    pub fn builder(path: StorePath, nar_hash: Hash) -> ValidPathInfoBuilder {
+        ValidPathInfoBuilder::new(path, nar_hash)
+    }
+
     pub fn fingerprint<'a>(
         &'a self,
         store: &'a StoreDir,
@@ -175,9 +182,7 @@ impl ValidPathInfo {
 
             let ca_s = source.read_string().await?;
             debug!(ca = ca_s, "CA is {}", ca_s);
-            if !ca_s.is_empty() {
-                ca = Some(ca_s.parse()?);
-            };
+            ca = ContentAddress::parse_opt(&ca_s)?;
         }
 
         Ok(ValidPathInfo {
@@ -216,11 +221,8 @@ impl ValidPathInfo {
             sink.write_bool(self.ultimate).await?;
             let sigs: StringSet = self.sigs.iter().map(|s| s.to_string()).collect();
             sink.write_string_coll(&sigs).await?;
-            if let Some(ca) = self.ca.as_ref() {
-                sink.write_string(ca.to_string()).await?;
-            } else {
-                sink.write_str("").await?;
-            }
+            sink.write_string(ContentAddress::print_opt(self.ca.as_ref()))
+                .await?;
         }
         Ok(())
     }
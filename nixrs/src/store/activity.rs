@@ -1,7 +1,9 @@
 use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 
 use derive_more::{LowerHex, UpperHex};
+use lazy_static::lazy_static;
 use tracing::event;
 use tracing::field::{Field, Visit};
 use tracing::span;
@@ -12,20 +14,147 @@ use tracing::{Event, Level, Span};
 use crate::num_enum::num_enum;
 
 use super::error::Verbosity;
+use super::Error;
 
 pub type ActivityId = u64;
 
+lazy_static! {
+    // Like Nix, start from the pid so that ids from different processes
+    // that end up in the same log don't collide.
+    static ref NEXT_ACTIVITY_ID: AtomicU64 = AtomicU64::new((std::process::id() as u64) << 32);
+}
+
+/// Allocate a new activity id that is unique within this process.
+pub fn next_activity_id() -> ActivityId {
+    NEXT_ACTIVITY_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A running activity. It is stopped when the last clone of its span is
+/// dropped.
 pub struct Activity {
+    pub id: ActivityId,
     pub span: Span,
 }
 
+impl Activity {
+    /// An activity that is never shown and drops its results, for
+    /// callers that need an [`Activity`] but have nowhere to report to.
+    pub fn disabled() -> Activity {
+        Activity {
+            id: 0,
+            span: Span::none(),
+        }
+    }
+
+    pub fn id(&self) -> ActivityId {
+        self.id
+    }
+
+    /// Report a result for this activity.
+    pub fn result(&self, result_type: ResultType, fields: Vec<LoggerField>) {
+        if self.span.is_none() {
+            return;
+        }
+        let result_type: u64 = result_type.into();
+        let span = &self.span;
+        expand_fields!( event, @ { target: RESULT_TARGET, parent: span, Level::ERROR, result_type }, fields)
+    }
+
+    /// Report a typed result for this activity.
+    pub fn report(&self, kind: ResultKind) {
+        self.result(kind.result_type(), kind.into_fields());
+    }
+
+    pub fn progress(&self, done: u64, expected: u64, running: u64, failed: u64) {
+        self.report(ResultKind::Progress {
+            done,
+            expected,
+            running,
+            failed,
+        });
+    }
+
+    pub fn set_expected(&self, activity_type: ActivityType, expected: u64) {
+        self.report(ResultKind::SetExpected {
+            activity_type,
+            expected,
+        });
+    }
+}
+
+/// Builds an [`Activity`] with fields only known at runtime.
+///
+/// ```ignore
+/// let act = ActivityBuilder::new(Verbosity::Info, ActivityType::CopyPath, "copying path")
+///     .field(path)
+///     .field(from)
+///     .field(to)
+///     .start();
+/// act.progress(done, total, 0, 0);
+/// ```
+///
+/// Without an explicit parent the activity is nested in the activity of
+/// the current span, if any.
+pub struct ActivityBuilder {
+    level: Verbosity,
+    activity_type: ActivityType,
+    text: String,
+    fields: Vec<LoggerField>,
+    parent: Option<(ActivityId, Span)>,
+}
+
+impl ActivityBuilder {
+    pub fn new(
+        level: Verbosity,
+        activity_type: ActivityType,
+        text: impl Into<String>,
+    ) -> ActivityBuilder {
+        ActivityBuilder {
+            level,
+            activity_type,
+            text: text.into(),
+            fields: Vec::new(),
+            parent: None,
+        }
+    }
+
+    pub fn field(mut self, field: impl Into<LoggerField>) -> Self {
+        self.fields.push(field.into());
+        self
+    }
+
+    pub fn parent(mut self, parent: &Activity) -> Self {
+        self.parent = Some((parent.id, parent.span.clone()));
+        self
+    }
+
+    pub fn start(self) -> Activity {
+        let id = next_activity_id();
+        let (parent, parent_span) = match self.parent.as_ref() {
+            Some((parent, span)) => (*parent, Some(span)),
+            None => (0, None),
+        };
+        let span = activity_span(
+            id,
+            self.level,
+            self.activity_type,
+            self.text,
+            self.fields,
+            parent,
+            parent_span,
+        );
+        Activity { id, span }
+    }
+}
+
 #[macro_export]
 macro_rules! activity {
     ($level:expr, $act_type:expr, $msg:expr, $($fields:tt)*) => {{
+        let act = $crate::store::activity::next_activity_id();
         let level : u64 = $level.into();
         let activity_type : u64 = $act_type.into();
-        let span = tracing::span!($level.to_tracing(), $crate::store::activity::ACTIVITY_NAME, level, activity_type, message=$msg, $($fields)*);
-        $crate::store::activity::Activity { span }
+        let span = tracing::span!($level.to_tracing(), $crate::store::activity::ACTIVITY_NAME, act, level, activity_type, message=$msg, $($fields)*);
+        $crate::store::activity::Activity { id: act, span }
     }}
 }
 
@@ -35,6 +164,24 @@ pub enum LoggerField {
     String(String),
 }
 
+impl From<u64> for LoggerField {
+    fn from(value: u64) -> Self {
+        LoggerField::Int(value)
+    }
+}
+
+impl From<String> for LoggerField {
+    fn from(value: String) -> Self {
+        LoggerField::String(value)
+    }
+}
+
+impl<'a> From<&'a str> for LoggerField {
+    fn from(value: &'a str) -> Self {
+        LoggerField::String(value.to_string())
+    }
+}
+
 impl LoggerField {
     fn as_value(&self) -> Box<dyn tracing::Value> {
         match self {
@@ -85,6 +232,9 @@ num_enum! {
         Progress = 105,
         SetExpected = 106,
         PostBuildLogLine = 107,
+        /// Not part of the Nix protocol. The daemon server sends these to
+        /// clients as log messages.
+        DeletedPath = 200,
     }
 }
 
@@ -233,6 +383,128 @@ impl ActivityResult {
             Err(())
         }
     }
+
+    /// Parse the fields of this result according to its type.
+    pub fn kind(&self) -> Result<ResultKind, Error> {
+        ResultKind::from_fields(self.result_type, &self.fields)
+    }
+}
+
+/// An activity result with its fields parsed according to its
+/// [`ResultType`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResultKind {
+    /// A file was hard linked while optimising the store, saving `bytes`
+    /// in `blocks` blocks.
+    FileLinked {
+        bytes: u64,
+        blocks: u64,
+    },
+    BuildLogLine(String),
+    UntrustedPath(String),
+    CorruptedPath(String),
+    SetPhase(String),
+    Progress {
+        done: u64,
+        expected: u64,
+        running: u64,
+        failed: u64,
+    },
+    /// The number of expected child activities of `activity_type`.
+    SetExpected {
+        activity_type: ActivityType,
+        expected: u64,
+    },
+    PostBuildLogLine(String),
+    /// The garbage collector deleted `path`, freeing `bytes`, which is 0
+    /// when the store does not know the size of single paths.
+    DeletedPath {
+        path: String,
+        bytes: u64,
+    },
+}
+
+impl ResultKind {
+    pub fn result_type(&self) -> ResultType {
+        match self {
+            ResultKind::FileLinked { .. } => ResultType::FileLinked,
+            ResultKind::BuildLogLine(_) => ResultType::BuildLogLine,
+            ResultKind::UntrustedPath(_) => ResultType::UntrustedPath,
+            ResultKind::CorruptedPath(_) => ResultType::CorruptedPath,
+            ResultKind::SetPhase(_) => ResultType::SetPhase,
+            ResultKind::Progress { .. } => ResultType::Progress,
+            ResultKind::SetExpected { .. } => ResultType::SetExpected,
+            ResultKind::PostBuildLogLine(_) => ResultType::PostBuildLogLine,
+            ResultKind::DeletedPath { .. } => ResultType::DeletedPath,
+        }
+    }
+
+    /// Parse the raw fields of a result of type `result_type`.
+    ///
+    /// Fields beyond the ones a result type is known to have are ignored so
+    /// that newer daemons can add more.
+    pub fn from_fields(
+        result_type: ResultType,
+        fields: &[LoggerField],
+    ) -> Result<ResultKind, Error> {
+        let invalid = || Error::InvalidResultFields(result_type);
+        let int = |idx: usize| match fields.get(idx) {
+            Some(LoggerField::Int(value)) => Ok(*value),
+            _ => Err(invalid()),
+        };
+        let string = |idx: usize| match fields.get(idx) {
+            Some(LoggerField::String(value)) => Ok(value.clone()),
+            _ => Err(invalid()),
+        };
+        Ok(match result_type {
+            ResultType::FileLinked => ResultKind::FileLinked {
+                bytes: int(0)?,
+                blocks: int(1)?,
+            },
+            ResultType::BuildLogLine => ResultKind::BuildLogLine(string(0)?),
+            ResultType::UntrustedPath => ResultKind::UntrustedPath(string(0)?),
+            ResultType::CorruptedPath => ResultKind::CorruptedPath(string(0)?),
+            ResultType::SetPhase => ResultKind::SetPhase(string(0)?),
+            ResultType::Progress => ResultKind::Progress {
+                done: int(0)?,
+                expected: int(1)?,
+                running: int(2)?,
+                failed: int(3)?,
+            },
+            ResultType::SetExpected => ResultKind::SetExpected {
+                activity_type: int(0)?.into(),
+                expected: int(1)?,
+            },
+            ResultType::PostBuildLogLine => ResultKind::PostBuildLogLine(string(0)?),
+            ResultType::DeletedPath => ResultKind::DeletedPath {
+                path: string(0)?,
+                bytes: int(1)?,
+            },
+            ResultType::Invalid(_) => return Err(invalid()),
+        })
+    }
+
+    pub fn into_fields(self) -> Vec<LoggerField> {
+        match self {
+            ResultKind::FileLinked { bytes, blocks } => vec![bytes.into(), blocks.into()],
+            ResultKind::BuildLogLine(line)
+            | ResultKind::UntrustedPath(line)
+            | ResultKind::CorruptedPath(line)
+            | ResultKind::SetPhase(line)
+            | ResultKind::PostBuildLogLine(line) => vec![line.into()],
+            ResultKind::Progress {
+                done,
+                expected,
+                running,
+                failed,
+            } => vec![done.into(), expected.into(), running.into(), failed.into()],
+            ResultKind::SetExpected {
+                activity_type,
+                expected,
+            } => vec![u64::from(activity_type).into(), expected.into()],
+            ResultKind::DeletedPath { path, bytes } => vec![path.into(), bytes.into()],
+        }
+    }
 }
 
 #[derive(Default)]
@@ -343,45 +615,15 @@ impl ActivityLogger {
         trace!(?activity, "Start activity");
 
         let mut map = self.map.lock().unwrap();
-        let level: u64 = lvl.into();
-        let activity_type: u64 = act_type.into();
-        let span = if let Some(parent_span) = map.get(&parent) {
-            match lvl.into() {
-                Level::ERROR => {
-                    remote!(parent: parent_span, Level::ERROR, act, level, activity_type, message, parent, fields)
-                }
-                Level::WARN => {
-                    remote!(parent: parent_span, Level::WARN, act, level, activity_type, message, parent, fields)
-                }
-                Level::INFO => {
-                    remote!(parent: parent_span, Level::INFO, act, level, activity_type, message, parent, fields)
-                }
-                Level::DEBUG => {
-                    remote!(parent: parent_span, Level::DEBUG, act, level, activity_type, message, parent, fields)
-                }
-                Level::TRACE => {
-                    remote!(parent: parent_span, Level::TRACE, act, level, activity_type, message, parent, fields)
-                }
-            }
-        } else {
-            match lvl.into() {
-                Level::ERROR => {
-                    remote!(Level::ERROR, act, level, activity_type, message, fields)
-                }
-                Level::WARN => {
-                    remote!(Level::WARN, act, level, activity_type, message, fields)
-                }
-                Level::INFO => {
-                    remote!(Level::INFO, act, level, activity_type, message, fields)
-                }
-                Level::DEBUG => {
-                    remote!(Level::DEBUG, act, level, activity_type, message, fields)
-                }
-                Level::TRACE => {
-                    remote!(Level::TRACE, act, level, activity_type, message, fields)
-                }
-            }
-        };
+        let span = activity_span(
+            act,
+            lvl,
+            act_type,
+            message,
+            fields,
+            parent,
+            map.get(&parent),
+        );
         map.insert(act, span);
     }
 
@@ -405,6 +647,56 @@ impl ActivityLogger {
     }
 }
 
+fn activity_span(
+    act: ActivityId,
+    lvl: Verbosity,
+    act_type: ActivityType,
+    message: String,
+    fields: Vec<LoggerField>,
+    parent: ActivityId,
+    parent_span: Option<&Span>,
+) -> Span {
+    let level: u64 = lvl.into();
+    let activity_type: u64 = act_type.into();
+    if let Some(parent_span) = parent_span {
+        match lvl.into() {
+            Level::ERROR => {
+                remote!(parent: parent_span, Level::ERROR, act, level, activity_type, message, parent, fields)
+            }
+            Level::WARN => {
+                remote!(parent: parent_span, Level::WARN, act, level, activity_type, message, parent, fields)
+            }
+            Level::INFO => {
+                remote!(parent: parent_span, Level::INFO, act, level, activity_type, message, parent, fields)
+            }
+            Level::DEBUG => {
+                remote!(parent: parent_span, Level::DEBUG, act, level, activity_type, message, parent, fields)
+            }
+            Level::TRACE => {
+                remote!(parent: parent_span, Level::TRACE, act, level, activity_type, message, parent, fields)
+            }
+        }
+    } else {
+        match lvl.into() {
+            Level::ERROR => {
+                remote!(Level::ERROR, act, level, activity_type, message, fields)
+            }
+            Level::WARN => {
+                remote!(Level::WARN, act, level, activity_type, message, fields)
+            }
+            Level::INFO => {
+                remote!(Level::INFO, act, level, activity_type, message, fields)
+            }
+            Level::DEBUG => {
+                remote!(Level::DEBUG, act, level, activity_type, message, fields)
+            }
+            Level::TRACE => {
+                remote!(Level::TRACE, act, level, activity_type, message, fields)
+            }
+        }
+    }
+}
+
 macro_rules! remote {
     (parent: $parent_span:expr, $lvl:expr, $act:ident, $level:ident, $activity_type:ident, $text:ident, $parent:ident, $fields:ident) => {
         expand_fields!( span, @ { target: ACTIVITY_TARGET, parent: $parent_span, $lvl, ACTIVITY_NAME, $act, $level, $activity_type, $text, $parent }, $fields)
@@ -629,3 +921,49 @@ macro_rules! expand_fields {
     };
 }
 pub(crate) use expand_fields;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_activity_id_is_unique() {
+        let first = next_activity_id();
+        let second = next_activity_id();
+        assert_ne!(first, second);
+        assert_eq!(first >> 32, second >> 32);
+    }
+
+    #[test]
+    fn test_builder_parent() {
+        let parent = ActivityBuilder::new(Verbosity::Info, ActivityType::Builds, "builds").start();
+        let child = ActivityBuilder::new(Verbosity::Info, ActivityType::Build, "build")
+            .field("/nix/store/x.drv")
+            .parent(&parent)
+            .start();
+        assert_ne!(parent.id(), child.id());
+    }
+
+    #[test]
+    fn test_result_kind_round_trip() {
+        let kind = ResultKind::Progress {
+            done: 1,
+            expected: 4,
+            running: 2,
+            failed: 0,
+        };
+        let fields = kind.clone().into_fields();
+        let parsed = ResultKind::from_fields(ResultType::Progress, &fields).unwrap();
+        assert_eq!(parsed, kind);
+    }
+
+    #[test]
+    fn test_result_kind_invalid_fields() {
+        let fields = vec![LoggerField::Int(1)];
+        let res = ResultKind::from_fields(ResultType::BuildLogLine, &fields);
+        assert!(matches!(
+            res,
+            Err(Error::InvalidResultFields(ResultType::BuildLogLine))
+        ));
+    }
+}
@@ -1,6 +1,9 @@
+use std::fmt;
+use std::sync::Arc;
+
 use async_trait::async_trait;
 use bytes::Bytes;
-use pretty_assertions::assert_eq;
+use pretty_assertions::Comparison;
 use proptest::test_runner::TestCaseError;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
@@ -8,9 +11,10 @@ use crate::path_info::ValidPathInfo;
 use crate::store::legacy_worker::LegacyStore;
 use crate::store::settings::BuildSettings;
 use crate::store::{BasicDerivation, BuildMode, BuildResult, CheckSignaturesFlag, Error, Store};
-use crate::store::{DerivedPath, RepairFlag, SubstituteFlag};
+use crate::store::{DerivedPath, ExperimentalFeatures, RepairFlag, SubstituteFlag};
 use crate::store_path::{StoreDir, StoreDirProvider, StorePath, StorePathSet};
 
+use super::daemon::golden::byte_difference;
 use super::daemon::{DaemonStore, QueryMissingResult, TrustedFlag};
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
@@ -57,6 +61,94 @@ pub enum Message {
         repair: RepairFlag,
         check_sigs: CheckSignaturesFlag,
     },
+    AddBuildLog {
+        drv_path: StorePath,
+        log: String,
+    },
+}
+
+impl Message {
+    /// Name of the store operation that sends the message.
+    pub fn name(&self) -> &'static str {
+        use Message::*;
+        match self {
+            AddTempRoot(_) => "add_temp_root",
+            QueryValidPaths { .. } => "query_valid_paths",
+            QueryPathInfo(_) => "query_path_info",
+            LegacyQueryValidPaths { .. } => "query_valid_paths_locked",
+            NarFromPath(_) => "nar_from_path",
+            ExportPaths(_) => "export_paths",
+            ImportPaths(_) => "import_paths",
+            BuildDerivation { .. } => "build_derivation",
+            BuildPaths { .. } => "build_paths",
+            AddToStore { .. } => "add_to_store",
+            QueryClosure { .. } => "query_closure",
+            QueryMissing(_) => "query_missing",
+            IsValidPath(_) => "is_valid_path",
+            AddMultipleToStore { .. } => "add_multiple_to_store",
+            AddBuildLog { .. } => "add_build_log",
+        }
+    }
+
+    /// Path info sent with the message.
+    pub fn path_info(&self) -> Option<&ValidPathInfo> {
+        match self {
+            Message::AddToStore { info, .. } => Some(info),
+            _ => None,
+        }
+    }
+
+    /// Raw bytes the store read while handling the message.
+    pub fn source(&self) -> Option<&Bytes> {
+        match self {
+            Message::ImportPaths(source)
+            | Message::AddToStore { source, .. }
+            | Message::AddMultipleToStore { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Custom check of the message an [`AssertStore`] gets, used instead of
+/// comparing it with the expected message.
+#[derive(Clone)]
+pub struct Matcher {
+    description: String,
+    check: Arc<dyn Fn(&Message) -> bool + Send + Sync>,
+}
+
+impl Matcher {
+    pub fn new<D, F>(description: D, check: F) -> Matcher
+    where
+        D: Into<String>,
+        F: Fn(&Message) -> bool + Send + Sync + 'static,
+    {
+        Matcher {
+            description: description.into(),
+            check: Arc::new(check),
+        }
+    }
+
+    /// Matches an `add_to_store` of any path info for `path`.
+    pub fn path_info_for(store_dir: &StoreDir, path: &StorePath) -> Matcher {
+        let description = format!("any path info for '{}'", store_dir.print_path(path));
+        let path = path.clone();
+        Matcher::new(description, move |msg| {
+            msg.path_info()
+                .map(|info| info.path == path)
+                .unwrap_or(false)
+        })
+    }
+
+    pub fn matches(&self, msg: &Message) -> bool {
+        (self.check)(msg)
+    }
+}
+
+impl fmt::Debug for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Matcher").field(&self.description).finish()
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
@@ -117,6 +209,7 @@ pub struct AssertStore {
     store_dir: StoreDir,
     expected: Message,
     actual: Option<Message>,
+    matcher: Option<Matcher>,
     response: Result<MessageResponse, Error>,
 }
 
@@ -139,6 +232,7 @@ impl AssertStore {
             expected,
             response,
             actual: None,
+            matcher: None,
         }
     }
     pub fn assert_query_path_info(
@@ -155,6 +249,7 @@ impl AssertStore {
             expected,
             response,
             actual: None,
+            matcher: None,
         }
     }
     pub fn assert_query_valid_paths_locked(
@@ -176,6 +271,7 @@ impl AssertStore {
             expected,
             response,
             actual: None,
+            matcher: None,
         }
     }
     pub fn assert_nar_from_path(
@@ -192,6 +288,7 @@ impl AssertStore {
             expected,
             response,
             actual: None,
+            matcher: None,
         }
     }
     pub fn assert_export_paths(
@@ -208,6 +305,7 @@ impl AssertStore {
             expected,
             response,
             actual: None,
+            matcher: None,
         }
     }
     pub fn assert_import_paths(
@@ -224,6 +322,7 @@ impl AssertStore {
             expected,
             response,
             actual: None,
+            matcher: None,
         }
     }
     pub fn assert_build_derivation(
@@ -248,6 +347,7 @@ impl AssertStore {
             expected,
             response,
             actual: None,
+            matcher: None,
         }
     }
     pub fn assert_build_paths(
@@ -270,6 +370,7 @@ impl AssertStore {
             expected,
             response,
             actual: None,
+            matcher: None,
         }
     }
     pub fn assert_add_to_store(
@@ -294,6 +395,7 @@ impl AssertStore {
             expected,
             response,
             actual: None,
+            matcher: None,
         }
     }
     pub fn assert_query_closure(
@@ -313,6 +415,7 @@ impl AssertStore {
             expected,
             response,
             actual: None,
+            matcher: None,
         }
     }
 
@@ -326,6 +429,7 @@ impl AssertStore {
             expected,
             response,
             actual: None,
+            matcher: None,
         }
     }
 
@@ -349,6 +453,7 @@ impl AssertStore {
             expected,
             response,
             actual: None,
+            matcher: None,
         }
     }
     pub fn assert_query_missing(
@@ -364,16 +469,108 @@ impl AssertStore {
             expected,
             response,
             actual: None,
+            matcher: None,
         }
     }
 
+    pub fn assert_add_build_log(
+        trusted_client: Option<TrustedFlag>,
+        drv_path: &StorePath,
+        log: &str,
+        response: Result<(), Error>,
+    ) -> AssertStore {
+        let store_dir = Default::default();
+        let expected = Message::AddBuildLog {
+            drv_path: drv_path.clone(),
+            log: log.into(),
+        };
+        let response = response.map(|e| e.into());
+        AssertStore {
+            trusted_client,
+            store_dir,
+            expected,
+            response,
+            actual: None,
+            matcher: None,
+        }
+    }
+
+    /// Check the message with `matcher` instead of comparing it with the
+    /// expected one. It must still be for the same operation.
+    pub fn matching(mut self, matcher: Matcher) -> Self {
+        self.matcher = Some(matcher);
+        self
+    }
+
+    fn record(&mut self, actual: Message) {
+        if let Some(previous) = self.actual.as_ref() {
+            panic!(
+                "{} called after {} but only one call was expected",
+                actual.name(),
+                previous.name()
+            );
+        }
+        self.actual = Some(actual);
+    }
+
+    /// Describe how the message the store got differs from what was
+    /// expected.
+    pub fn mismatch(&self) -> Option<String> {
+        let actual = match self.actual.as_ref() {
+            Some(actual) => actual,
+            None => {
+                return Some(format!(
+                    "expected {} but the store was never called",
+                    self.expected.name()
+                ))
+            }
+        };
+        if actual.name() != self.expected.name() {
+            return Some(format!(
+                "expected {} but got {}:\n{:#?}",
+                self.expected.name(),
+                actual.name(),
+                actual
+            ));
+        }
+        if let Some(matcher) = self.matcher.as_ref() {
+            if matcher.matches(actual) {
+                return None;
+            }
+            return Some(format!(
+                "{} does not match {}:\n{:#?}",
+                actual.name(),
+                matcher.description,
+                actual
+            ));
+        }
+        if actual == &self.expected {
+            return None;
+        }
+        let mut ret = format!(
+            "{} differs from what was expected:\n{}",
+            actual.name(),
+            Comparison::new(&self.expected, actual)
+        );
+        if let (Some(expected), Some(source)) = (self.expected.source(), actual.source()) {
+            if let Some(diff) = byte_difference(expected, source) {
+                ret.push_str(&format!("\nsource bytes differ {}", diff));
+            }
+        }
+        Some(ret)
+    }
+
     pub fn prop_assert_eq(self) -> Result<(), TestCaseError> {
-        ::proptest::prop_assert_eq!(self.expected, self.actual.unwrap());
-        Ok(())
+        match self.mismatch() {
+            Some(msg) => Err(TestCaseError::fail(msg)),
+            None => Ok(()),
+        }
     }
 
     pub fn assert_eq(self) {
-        ::pretty_assertions::assert_eq!(self.expected, self.actual.unwrap());
+        if let Some(msg) = self.mismatch() {
+            panic!("{}", msg);
+        }
     }
 }
 
@@ -394,8 +591,7 @@ impl Store for AssertStore {
             paths: paths.clone(),
             maybe_substitute,
         };
-        assert_eq!(None, self.actual.take(), "existing result");
-        self.actual = Some(actual);
+        self.record(actual);
         match take(&mut self.response)? {
             MessageResponse::StorePathSet(set) => Ok(set),
             e => panic!("Invalid response {:?} for query_valid_paths", e),
@@ -404,8 +600,7 @@ impl Store for AssertStore {
 
     async fn query_path_info(&mut self, path: &StorePath) -> Result<Option<ValidPathInfo>, Error> {
         let actual = Message::QueryPathInfo(path.clone());
-        assert_eq!(None, self.actual.take(), "existing result");
-        self.actual = Some(actual);
+        self.record(actual);
         match take(&mut self.response)? {
             MessageResponse::ValidPathInfo(res) => Ok(res),
             e => panic!("Invalid response {:?} for query_path_info", e),
@@ -418,8 +613,7 @@ impl Store for AssertStore {
         mut sink: W,
     ) -> Result<(), Error> {
         let actual = Message::NarFromPath(path.clone());
-        assert_eq!(None, self.actual.take(), "existing result");
-        self.actual = Some(actual);
+        self.record(actual);
         match take(&mut self.response)? {
             MessageResponse::Bytes(set) => {
                 sink.write_all(&set).await?;
@@ -445,8 +639,7 @@ impl Store for AssertStore {
             repair,
             check_sigs,
         };
-        assert_eq!(None, self.actual.take(), "existing result");
-        self.actual = Some(actual);
+        self.record(actual);
         match take(&mut self.response)? {
             MessageResponse::Empty => Ok(()),
             e => panic!("Invalid response {:?} for add_to_store", e),
@@ -465,8 +658,7 @@ impl Store for AssertStore {
             build_mode,
             settings,
         };
-        assert_eq!(None, self.actual.take(), "existing result");
-        self.actual = Some(actual);
+        self.record(actual);
         match take(&mut self.response)? {
             MessageResponse::BuildResult(res) => Ok(res),
             e => panic!("Invalid response {:?} for build_derivation", e),
@@ -482,8 +674,7 @@ impl Store for AssertStore {
             build_mode,
             settings: BuildSettings::default(),
         };
-        assert_eq!(None, self.actual.take(), "existing result");
-        self.actual = Some(actual);
+        self.record(actual);
         match take(&mut self.response)? {
             MessageResponse::Empty => Ok(()),
             e => panic!("Invalid response {:?} for build_paths", e),
@@ -504,8 +695,7 @@ impl LegacyStore for AssertStore {
             lock,
             maybe_substitute,
         };
-        assert_eq!(None, self.actual.take(), "existing result");
-        self.actual = Some(actual);
+        self.record(actual);
         match take(&mut self.response)? {
             MessageResponse::StorePathSet(set) => Ok(set),
             e => panic!("Invalid response {:?} for legacy_query_valid_paths", e),
@@ -517,8 +707,7 @@ impl LegacyStore for AssertStore {
         mut sink: W,
     ) -> Result<(), Error> {
         let actual = Message::ExportPaths(paths.clone());
-        assert_eq!(None, self.actual.take(), "existing result");
-        self.actual = Some(actual);
+        self.record(actual);
         match take(&mut self.response)? {
             MessageResponse::Bytes(set) => {
                 sink.write_all(&set).await?;
@@ -535,8 +724,7 @@ impl LegacyStore for AssertStore {
         let mut buf = Vec::new();
         source.read_to_end(&mut buf).await?;
         let actual = Message::ImportPaths(buf.into());
-        assert_eq!(None, self.actual.take(), "existing result");
-        self.actual = Some(actual);
+        self.record(actual);
         match take(&mut self.response)? {
             MessageResponse::Empty => Ok(()),
             e => panic!("Invalid response {:?} for import_paths", e),
@@ -551,8 +739,7 @@ impl LegacyStore for AssertStore {
             paths: paths.clone(),
             include_outputs,
         };
-        assert_eq!(None, self.actual.take(), "existing result");
-        self.actual = Some(actual);
+        self.record(actual);
         match take(&mut self.response)? {
             MessageResponse::StorePathSet(set) => Ok(set),
             e => panic!("Invalid response {:?} for query_closure", e),
@@ -566,14 +753,18 @@ impl DaemonStore for AssertStore {
         self.trusted_client
     }
 
+    /// Every call is passed on to be asserted, whatever features it needs.
+    fn experimental_features(&self) -> ExperimentalFeatures {
+        ExperimentalFeatures::all()
+    }
+
     async fn set_options(&mut self) -> Result<(), Error> {
         Ok(())
     }
 
     async fn is_valid_path(&mut self, path: &StorePath) -> Result<bool, Error> {
         let actual = Message::IsValidPath(path.clone());
-        assert_eq!(None, self.actual.take(), "existing result");
-        self.actual = Some(actual);
+        self.record(actual);
         match take(&mut self.response)? {
             MessageResponse::Bool(res) => Ok(res),
             e => panic!("Invalid response {:?} for is_valid_path", e),
@@ -593,8 +784,7 @@ impl DaemonStore for AssertStore {
             repair,
             check_sigs,
         };
-        assert_eq!(None, self.actual.take(), "existing result");
-        self.actual = Some(actual);
+        self.record(actual);
         match take(&mut self.response)? {
             MessageResponse::Empty => Ok(()),
             e => panic!("Invalid response {:?} for add_multiple_to_store", e),
@@ -606,11 +796,87 @@ impl DaemonStore for AssertStore {
         targets: &[DerivedPath],
     ) -> Result<QueryMissingResult, Error> {
         let actual = Message::QueryMissing(targets.into());
-        assert_eq!(None, self.actual.take(), "existing result");
-        self.actual = Some(actual);
+        self.record(actual);
         match take(&mut self.response)? {
             MessageResponse::QueryMissingResult(res) => Ok(res),
             e => panic!("Invalid response {:?} for query_missing", e),
         }
     }
+
+    async fn add_build_log(&mut self, drv_path: &StorePath, log: &str) -> Result<(), Error> {
+        let actual = Message::AddBuildLog {
+            drv_path: drv_path.clone(),
+            log: log.into(),
+        };
+        self.record(actual);
+        match take(&mut self.response)? {
+            MessageResponse::Empty => Ok(()),
+            e => panic!("Invalid response {:?} for add_build_log", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::hash::{digest, Algorithm};
+
+    fn info(path: &StorePath, nar_size: u64) -> ValidPathInfo {
+        ValidPathInfo::builder(path.clone(), digest(Algorithm::SHA256, "nar"))
+            .nar_size(nar_size)
+            .build(&StoreDir::default())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_matcher() {
+        let path = StorePath::test_from_seed("foo");
+        let mut store = AssertStore::assert_add_to_store(
+            None,
+            &info(&path, 3),
+            Bytes::from_static(b"nar"),
+            RepairFlag::NoRepair,
+            CheckSignaturesFlag::NoCheckSigs,
+            Ok(()),
+        )
+        .matching(Matcher::path_info_for(&StoreDir::default(), &path));
+        store
+            .add_to_store(
+                &info(&path, 7),
+                Cursor::new(b"another"),
+                RepairFlag::NoRepair,
+                CheckSignaturesFlag::NoCheckSigs,
+            )
+            .await
+            .unwrap();
+        assert_eq!(store.mismatch(), None);
+    }
+
+    #[tokio::test]
+    async fn test_mismatch() {
+        let path = StorePath::test_from_seed("foo");
+        let mut store = AssertStore::assert_add_to_store(
+            None,
+            &info(&path, 3),
+            Bytes::from_static(b"nar"),
+            RepairFlag::NoRepair,
+            CheckSignaturesFlag::NoCheckSigs,
+            Ok(()),
+        );
+        assert!(store.mismatch().unwrap().contains("never called"));
+        store
+            .add_to_store(
+                &info(&path, 3),
+                Cursor::new(b"nat"),
+                RepairFlag::NoRepair,
+                CheckSignaturesFlag::NoCheckSigs,
+            )
+            .await
+            .unwrap();
+        let msg = store.mismatch().unwrap();
+        assert!(msg.starts_with("add_to_store differs"), "{}", msg);
+        assert!(msg.contains("source bytes differ at offset 0x2"), "{}", msg);
+    }
 }
@@ -0,0 +1,65 @@
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::BinaryCache;
+use crate::store::blob_store::BlobStore;
+use crate::store::Error;
+use crate::store_path::{StoreDir, StoreDirProvider};
+
+/// A binary cache for `store_dir` kept in a [`BlobStore`].
+///
+/// ```
+/// # use nixrs::store::binary_cache::{BinaryStoreWrap, BlobBinaryCache};
+/// # use nixrs::store::blob_store::FileBlobStore;
+/// let blobs = FileBlobStore::new("/var/cache/nix");
+/// let store = BinaryStoreWrap::new(BlobBinaryCache::from_blob_store(blobs, Default::default()));
+/// ```
+#[derive(Clone, Debug)]
+pub struct BlobBinaryCache<S> {
+    store_dir: StoreDir,
+    blobs: S,
+}
+
+impl<S> BlobBinaryCache<S> {
+    pub fn from_blob_store(blobs: S, store_dir: StoreDir) -> BlobBinaryCache<S> {
+        BlobBinaryCache { store_dir, blobs }
+    }
+
+    pub fn blobs(&self) -> &S {
+        &self.blobs
+    }
+
+    pub fn into_inner(self) -> S {
+        self.blobs
+    }
+}
+
+impl<S> StoreDirProvider for BlobBinaryCache<S> {
+    fn store_dir(&self) -> StoreDir {
+        self.store_dir.clone()
+    }
+}
+
+#[async_trait]
+impl<S> BinaryCache for BlobBinaryCache<S>
+where
+    S: BlobStore + Send + Sync,
+{
+    async fn file_exists(&self, path: &str) -> Result<bool, Error> {
+        Ok(self.blobs.head(path).await?.is_some())
+    }
+
+    async fn upsert_file<R>(&self, path: &str, stream: R, mime_type: &str) -> Result<(), Error>
+    where
+        R: AsyncRead + Send + Unpin,
+    {
+        self.blobs.put(path, stream, mime_type).await
+    }
+
+    async fn get_file<W>(&self, path: &str, sink: W) -> Result<(), Error>
+    where
+        W: AsyncWrite + Send + Unpin,
+    {
+        self.blobs.get(path, sink).await
+    }
+}
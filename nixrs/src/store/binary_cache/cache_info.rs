@@ -0,0 +1,145 @@
+use std::fmt;
+use std::num::ParseIntError;
+
+use thiserror::Error;
+
+use crate::store_path::{ParseStorePathError, StoreDir};
+
+/// Name of the file in the root of a binary cache that describes it.
+pub const CACHE_INFO_FILE: &str = "nix-cache-info";
+pub const CACHE_INFO_MIME_TYPE: &str = "text/x-nix-cache-info";
+
+/// Priority Nix gives a binary cache whose `nix-cache-info` has none.
+pub const DEFAULT_PRIORITY: u64 = 50;
+
+/// Contents of the `nix-cache-info` file of a binary cache.
+///
+/// ```
+/// # use nixrs::store::binary_cache::CacheInfo;
+/// # use nixrs::store_path::StoreDir;
+/// let info = CacheInfo::parse("StoreDir: /nix/store\nWantMassQuery: 1\nPriority: 40\n").unwrap();
+/// assert_eq!(info.store_dir, StoreDir::default());
+/// assert!(info.want_mass_query);
+/// assert_eq!(info.priority, 40);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheInfo {
+    pub store_dir: StoreDir,
+    pub want_mass_query: bool,
+    pub priority: u64,
+}
+
+impl CacheInfo {
+    pub fn new(store_dir: StoreDir) -> CacheInfo {
+        CacheInfo {
+            store_dir,
+            want_mass_query: false,
+            priority: DEFAULT_PRIORITY,
+        }
+    }
+
+    /// Parse a `nix-cache-info` file. Like Nix, unknown fields are ignored.
+    pub fn parse(s: &str) -> Result<CacheInfo, ParseCacheInfoError> {
+        let mut store_dir = None;
+        let mut want_mass_query = false;
+        let mut priority = DEFAULT_PRIORITY;
+        for line in s.split('\n') {
+            let mut kv = line.splitn(2, ':');
+            let key = kv.next().unwrap().trim();
+            let value = match kv.next() {
+                Some(value) => value.trim(),
+                None if key.is_empty() => continue,
+                None => return Err(ParseCacheInfoError::InvalidLine(line.into())),
+            };
+            match key {
+                "StoreDir" => store_dir = Some(StoreDir::new(value)?),
+                "WantMassQuery" => {
+                    want_mass_query = match value {
+                        "1" => true,
+                        "0" => false,
+                        _ => return Err(ParseCacheInfoError::BadWantMassQuery(value.into())),
+                    }
+                }
+                "Priority" => priority = value.parse()?,
+                _ => {}
+            }
+        }
+        let store_dir = store_dir.ok_or(ParseCacheInfoError::MissingStoreDir)?;
+        Ok(CacheInfo {
+            store_dir,
+            want_mass_query,
+            priority,
+        })
+    }
+}
+
+impl fmt::Display for CacheInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "StoreDir: {}", self.store_dir)?;
+        writeln!(
+            f,
+            "WantMassQuery: {}",
+            if self.want_mass_query { 1 } else { 0 }
+        )?;
+        writeln!(f, "Priority: {}", self.priority)
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Clone)]
+pub enum ParseCacheInfoError {
+    #[error("invalid line {0}")]
+    InvalidLine(String),
+    #[error("missing StoreDir")]
+    MissingStoreDir,
+    #[error("error parsing StoreDir {0}")]
+    BadStoreDir(
+        #[from]
+        #[source]
+        ParseStorePathError,
+    ),
+    #[error("invalid WantMassQuery '{0}'")]
+    BadWantMassQuery(String),
+    #[error("error parsing Priority {0}")]
+    BadPriority(
+        #[from]
+        #[source]
+        ParseIntError,
+    ),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_info_round_trip() {
+        let mut info = CacheInfo::new(StoreDir::new("/gnu/store").unwrap());
+        info.want_mass_query = true;
+        info.priority = 40;
+        let s = info.to_string();
+        assert_eq!(s, "StoreDir: /gnu/store\nWantMassQuery: 1\nPriority: 40\n");
+        assert_eq!(CacheInfo::parse(&s).unwrap(), info);
+    }
+
+    #[test]
+    fn test_cache_info_defaults() {
+        let info = CacheInfo::parse("StoreDir: /nix/store\nSomethingNew: yes\n").unwrap();
+        assert_eq!(info, CacheInfo::new(StoreDir::default()));
+    }
+
+    #[test]
+    fn test_cache_info_errors() {
+        assert_eq!(
+            CacheInfo::parse("WantMassQuery: 1\n"),
+            Err(ParseCacheInfoError::MissingStoreDir)
+        );
+        assert_eq!(
+            CacheInfo::parse("StoreDir: /nix/store\nWantMassQuery: yes\n"),
+            Err(ParseCacheInfoError::BadWantMassQuery("yes".into()))
+        );
+        assert!(matches!(
+            CacheInfo::parse("StoreDir: /nix/store\nPriority: high\n"),
+            Err(ParseCacheInfoError::BadPriority(_))
+        ));
+    }
+}
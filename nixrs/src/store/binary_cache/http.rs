@@ -1,17 +1,12 @@
-use async_trait::async_trait;
-use reqwest::{header::CONTENT_TYPE, Client, IntoUrl, StatusCode, Url};
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use reqwest::IntoUrl;
 
-use super::BinaryCache;
+use super::BlobBinaryCache;
+use crate::store::blob_store::HttpBlobStore;
 use crate::store::Error;
-use crate::store_path::{StoreDir, StoreDirProvider};
+use crate::store_path::StoreDir;
 
-#[derive(Clone, Debug)]
-pub struct HttpBinaryCache {
-    store_dir: StoreDir,
-    client: Client,
-    base_url: Url,
-}
+/// Binary cache served over HTTP, like `https://cache.nixos.org`.
+pub type HttpBinaryCache = BlobBinaryCache<HttpBlobStore>;
 
 impl HttpBinaryCache {
     pub fn new<U: IntoUrl>(url: U) -> Result<HttpBinaryCache, Error> {
@@ -20,72 +15,10 @@ impl HttpBinaryCache {
     }
 
     pub fn with_store<U: IntoUrl>(url: U, store_dir: StoreDir) -> Result<HttpBinaryCache, Error> {
-        let client = reqwest::Client::builder().build()?;
-        let base_url = url.into_url()?;
-        Ok(HttpBinaryCache {
+        Ok(BlobBinaryCache::from_blob_store(
+            HttpBlobStore::new(url)?,
             store_dir,
-            client,
-            base_url,
-        })
-    }
-}
-
-impl StoreDirProvider for HttpBinaryCache {
-    fn store_dir(&self) -> StoreDir {
-        self.store_dir.clone()
-    }
-}
-
-#[async_trait]
-impl BinaryCache for HttpBinaryCache {
-    async fn file_exists(&self, path: &str) -> Result<bool, Error> {
-        let url = self.base_url.join(path)?;
-        let resp = self.client.head(url).send().await?;
-        let status = resp.status();
-        if status.is_success() {
-            Ok(true)
-        } else if status == StatusCode::NOT_FOUND || status == StatusCode::FORBIDDEN {
-            Ok(false)
-        } else {
-            Err(resp.error_for_status().unwrap_err().into())
-        }
-    }
-    async fn upsert_file<R>(&self, path: &str, mut stream: R, mime_type: &str) -> Result<(), Error>
-    where
-        R: AsyncRead + Send + Unpin,
-    {
-        let mut content = Vec::new();
-        stream.read_to_end(&mut content).await?;
-        let url = self.base_url.join(path)?;
-        let resp = self
-            .client
-            .put(url)
-            .body(content)
-            .header(CONTENT_TYPE, mime_type)
-            .send()
-            .await?;
-        if resp.status().is_success() {
-            Ok(())
-        } else {
-            Err(resp.error_for_status().unwrap_err().into())
-        }
-    }
-
-    /// Dump the contents of the specified file to a sink.
-    async fn get_file<W>(&self, path: &str, mut sink: W) -> Result<(), Error>
-    where
-        W: AsyncWrite + Send + Unpin,
-    {
-        let url = self.base_url.join(path)?;
-        let mut resp = self.client.get(url).send().await?;
-        if resp.status().is_success() {
-            while let Some(chunk) = resp.chunk().await? {
-                sink.write_all(&chunk).await?;
-            }
-            Ok(())
-        } else {
-            Err(resp.error_for_status().unwrap_err().into())
-        }
+        ))
     }
 }
 
@@ -1,8 +1,14 @@
+mod blob;
+mod cache_info;
 mod file;
 mod http;
 mod traits;
 mod wrap;
 
+pub use self::blob::BlobBinaryCache;
+pub use self::cache_info::{
+    CacheInfo, ParseCacheInfoError, CACHE_INFO_FILE, CACHE_INFO_MIME_TYPE, DEFAULT_PRIORITY,
+};
 pub use self::file::FileBinaryCache;
 pub use self::http::HttpBinaryCache;
 pub use self::traits::BinaryCache;
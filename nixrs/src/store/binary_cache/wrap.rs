@@ -7,19 +7,25 @@ use tokio::io::{AsyncRead, AsyncWrite};
 #[cfg(feature = "compress-tools")]
 use tokio::try_join;
 
-use crate::path_info::{Compression, NarInfo, ValidPathInfo};
-use crate::store::{CheckSignaturesFlag, Error, RepairFlag, Store};
+use crate::path_info::{Compression, NarInfo, ParseMode, ValidPathInfo};
+use crate::store::{CheckSignaturesFlag, Error, LogStore, RepairFlag, Store};
 use crate::store_path::{StoreDir, StoreDirProvider, StorePath};
 
-use super::BinaryCache;
+use super::{BinaryCache, CacheInfo, CACHE_INFO_FILE, CACHE_INFO_MIME_TYPE};
 
 fn nar_info_file_for(path: &StorePath) -> String {
     format!("{}.narinfo", path.hash)
 }
 
+fn log_file_for(drv_path: &StorePath) -> String {
+    format!("log/{}", drv_path)
+}
+
 #[derive(Clone)]
 pub struct BinaryStoreWrap<B> {
     cache: B,
+    parse_mode: ParseMode,
+    cache_info: Option<CacheInfo>,
 }
 
 impl<B> BinaryStoreWrap<B>
@@ -27,7 +33,67 @@ where
     B: BinaryCache + Send + Sync,
 {
     pub fn new(cache: B) -> Self {
-        Self { cache }
+        Self {
+            cache,
+            parse_mode: ParseMode::Strict,
+            cache_info: None,
+        }
+    }
+
+    /// Wrap a cache after checking its `nix-cache-info` file, if it has
+    /// one, is for the same store dir as the cache.
+    pub async fn open(cache: B) -> Result<Self, Error> {
+        let mut store = Self::new(cache);
+        store.cache_info = store.read_cache_info().await?;
+        Ok(store)
+    }
+
+    /// Like [`open`](Self::open) but writes a `nix-cache-info` file when
+    /// the cache doesn't have one.
+    pub async fn create(cache: B) -> Result<Self, Error> {
+        let mut store = Self::open(cache).await?;
+        if store.cache_info.is_none() {
+            let info = CacheInfo::new(store.store_dir());
+            store
+                .cache
+                .upsert_file_data(
+                    CACHE_INFO_FILE,
+                    info.to_string().as_bytes(),
+                    CACHE_INFO_MIME_TYPE,
+                )
+                .await?;
+            store.cache_info = Some(info);
+        }
+        Ok(store)
+    }
+
+    async fn read_cache_info(&self) -> Result<Option<CacheInfo>, Error> {
+        if !self.cache.file_exists(CACHE_INFO_FILE).await? {
+            return Ok(None);
+        }
+        let mut buf = Vec::new();
+        self.cache.get_file(CACHE_INFO_FILE, &mut buf).await?;
+        let info = CacheInfo::parse(&String::from_utf8_lossy(&buf))?;
+        let store_dir = self.store_dir();
+        if info.store_dir != store_dir {
+            return Err(Error::StoreDirMismatch(
+                info.store_dir.to_string(),
+                store_dir.to_string(),
+            ));
+        }
+        Ok(Some(info))
+    }
+
+    /// The `nix-cache-info` of the cache when it was opened with
+    /// [`open`](Self::open) or [`create`](Self::create).
+    pub fn cache_info(&self) -> Option<&CacheInfo> {
+        self.cache_info.as_ref()
+    }
+
+    /// How to parse the `.narinfo` files of the cache.
+    pub fn with_parse_mode(mut self, mode: ParseMode) -> Self {
+        self.parse_mode = mode;
+        self
     }
     pub async fn nar_info_for_path(&self, path: &StorePath) -> Result<Option<NarInfo>, Error> {
         let file = nar_info_file_for(path);
@@ -37,7 +103,8 @@ where
         let mut buf = Vec::new();
         self.cache.get_file(&file, &mut buf).await?;
         let s = String::from_utf8(buf).map_err(|_| Error::BadNarInfo)?;
-        let info = NarInfo::parse(&self.store_dir(), &s).map_err(|_| Error::BadNarInfo)?;
+        let info = NarInfo::parse_with(&self.store_dir(), &s, self.parse_mode)
+            .map_err(|_| Error::BadNarInfo)?;
         Ok(Some(info))
     }
 }
@@ -101,6 +168,33 @@ where
     }
 }
 
+/// Build logs are kept in `log/<drv base name>` like Nix does.
+#[async_trait]
+impl<B> LogStore for BinaryStoreWrap<B>
+where
+    B: BinaryCache + Send + Sync,
+{
+    async fn get_build_log(&mut self, drv_path: &StorePath) -> Result<Option<String>, Error> {
+        let file = log_file_for(drv_path);
+        if !self.cache.file_exists(&file).await? {
+            return Ok(None);
+        }
+        let mut buf = Vec::new();
+        self.cache.get_file(&file, &mut buf).await?;
+        Ok(Some(String::from_utf8_lossy(&buf).into_owned()))
+    }
+
+    async fn add_build_log(&mut self, drv_path: &StorePath, log: &str) -> Result<(), Error> {
+        self.cache
+            .upsert_file_data(
+                &log_file_for(drv_path),
+                log.as_bytes(),
+                "text/plain; charset=utf-8",
+            )
+            .await
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #[cfg(feature = "compress-tools")]
@@ -131,6 +225,62 @@ mod tests {
         assert_eq!(info.path, path);
     }
 
+    #[tokio::test]
+    async fn test_open() {
+        let store = BinaryStoreWrap::open(FileBinaryCache::new("test-data/binary-cache"))
+            .await
+            .unwrap();
+        let info = store.cache_info().unwrap();
+        assert_eq!(info.store_dir, StoreDir::default());
+        assert!(info.want_mass_query);
+        assert_eq!(info.priority, 40);
+
+        let store_dir = StoreDir::new("/gnu/store").unwrap();
+        let res = BinaryStoreWrap::open(FileBinaryCache::with_store(
+            "test-data/binary-cache",
+            store_dir,
+        ))
+        .await;
+        assert!(matches!(res, Err(Error::StoreDirMismatch(_, _))));
+    }
+
+    #[tokio::test]
+    async fn test_create() {
+        let dir = tempfile::tempdir().unwrap();
+        let store_dir = StoreDir::new("/gnu/store").unwrap();
+        let cache = FileBinaryCache::with_store(dir.path(), store_dir.clone());
+        let store = BinaryStoreWrap::open(cache.clone()).await.unwrap();
+        assert_eq!(None, store.cache_info());
+
+        let store = BinaryStoreWrap::create(cache.clone()).await.unwrap();
+        assert_eq!(Some(&CacheInfo::new(store_dir)), store.cache_info());
+        let store = BinaryStoreWrap::open(cache).await.unwrap();
+        assert!(store.cache_info().is_some());
+        assert!(matches!(
+            BinaryStoreWrap::open(FileBinaryCache::new(dir.path())).await,
+            Err(Error::StoreDirMismatch(_, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_build_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let drv_path =
+            StorePath::new_from_base_name("ycbqd7822qcnasaqy0mmiv2j9n9m62yl-hello-2.12.1.drv")
+                .unwrap();
+        let mut store = BinaryStoreWrap::new(FileBinaryCache::new(dir.path()));
+        assert_eq!(None, store.get_build_log(&drv_path).await.unwrap());
+        store.add_build_log(&drv_path, "building\n").await.unwrap();
+        assert!(dir
+            .path()
+            .join("log/ycbqd7822qcnasaqy0mmiv2j9n9m62yl-hello-2.12.1.drv")
+            .exists());
+        assert_eq!(
+            Some("building\n".to_string()),
+            store.get_build_log(&drv_path).await.unwrap()
+        );
+    }
+
     #[cfg(feature = "compress-tools")]
     #[tokio::test]
     async fn test_nar_from_path_gcc() {
@@ -0,0 +1,592 @@
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::debug;
+
+use super::{BlobInfo, BlobStore};
+use crate::hash::{digest, Algorithm, Context, Sri};
+use crate::store::Error;
+
+pub const CHUNK_MANIFEST_CONTENT_TYPE: &str = "application/x-nix-chunk-manifest+json";
+
+lazy_static! {
+    /// Random value for every byte, generated with splitmix64 so the same
+    /// data is always cut at the same places.
+    static ref GEAR: [u64; 256] = {
+        let mut table = [0u64; 256];
+        let mut state = 0x6e69_7872_735f_6364u64;
+        for entry in table.iter_mut() {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            *entry = z ^ (z >> 31);
+        }
+        table
+    };
+}
+
+/// Chunk sizes for [`Chunker`]. Changing them changes where data is cut,
+/// so chunks only dedup against chunks made with the same config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkerConfig {
+    pub min_size: usize,
+    pub avg_size: usize,
+    pub max_size: usize,
+}
+
+impl Default for ChunkerConfig {
+    fn default() -> Self {
+        ChunkerConfig {
+            min_size: 16 * 1024,
+            avg_size: 64 * 1024,
+            max_size: 256 * 1024,
+        }
+    }
+}
+
+impl ChunkerConfig {
+    /// Rejects sizes the chunker can't work with.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.min_size == 0
+            || self.min_size > self.avg_size
+            || self.avg_size > self.max_size
+            || !self.avg_size.is_power_of_two()
+        {
+            return Err(Error::Misc(format!(
+                "invalid chunk sizes {}/{}/{}: need 0 < min <= avg <= max and avg a power of two",
+                self.min_size, self.avg_size, self.max_size
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Content-defined chunking with FastCDC.
+///
+/// Boundaries are picked from a rolling hash of the last 64 bytes, so an
+/// insertion only changes the chunks around it and the rest of a NAR still
+/// dedups against earlier versions. Cuts follow the normalized chunking of
+/// FastCDC: harder to hit before the average size and easier after it.
+#[derive(Debug)]
+pub struct Chunker {
+    config: ChunkerConfig,
+    mask_small: u64,
+    mask_large: u64,
+    buf: BytesMut,
+}
+
+impl Chunker {
+    /// Fails when the config doesn't [validate](ChunkerConfig::validate).
+    pub fn new(config: ChunkerConfig) -> Result<Chunker, Error> {
+        config.validate()?;
+        let bits = config.avg_size.trailing_zeros().clamp(3, 61);
+        Ok(Chunker {
+            config,
+            mask_small: top_bits(bits + 2),
+            mask_large: top_bits(bits - 2),
+            buf: BytesMut::new(),
+        })
+    }
+
+    /// Adds `data` and returns the chunks that are complete. Where chunks
+    /// end doesn't depend on how the data is split between calls.
+    pub fn update(&mut self, data: &[u8]) -> Vec<Bytes> {
+        self.buf.extend_from_slice(data);
+        let mut chunks = Vec::new();
+        while self.buf.len() >= self.config.max_size {
+            let len = self.cut(&self.buf);
+            chunks.push(self.buf.split_to(len).freeze());
+        }
+        chunks
+    }
+
+    /// Returns the chunks of the data that is left.
+    pub fn finish(mut self) -> Vec<Bytes> {
+        let mut chunks = Vec::new();
+        while !self.buf.is_empty() {
+            let len = self.cut(&self.buf);
+            chunks.push(self.buf.split_to(len).freeze());
+        }
+        chunks
+    }
+
+    fn cut(&self, data: &[u8]) -> usize {
+        let ChunkerConfig {
+            min_size,
+            avg_size,
+            max_size,
+        } = self.config;
+        if data.len() <= min_size {
+            return data.len();
+        }
+        let end = data.len().min(max_size);
+        let normal = avg_size.min(end);
+        let mut hash = 0u64;
+        for (i, b) in data.iter().enumerate().take(normal).skip(min_size) {
+            hash = (hash << 1).wrapping_add(GEAR[*b as usize]);
+            if hash & self.mask_small == 0 {
+                return i + 1;
+            }
+        }
+        for (i, b) in data.iter().enumerate().take(end).skip(normal) {
+            hash = (hash << 1).wrapping_add(GEAR[*b as usize]);
+            if hash & self.mask_large == 0 {
+                return i + 1;
+            }
+        }
+        end
+    }
+}
+
+fn top_bits(n: u32) -> u64 {
+    !0u64 << (64 - n)
+}
+
+/// One chunk of a blob in a [`ChunkManifest`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkRef {
+    pub hash: Sri,
+    pub size: u64,
+}
+
+/// How to put a chunked blob back together.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkManifest {
+    /// SHA-256 of the whole blob.
+    pub hash: Sri,
+    pub size: u64,
+    pub content_type: String,
+    pub chunks: Vec<ChunkRef>,
+}
+
+/// A [`BlobStore`] that splits blobs into content-defined chunks and
+/// stores every distinct chunk once, like casync or attic.
+///
+/// Blobs with keys under one of the chunked prefixes (`nar/` by default)
+/// are stored in the inner store as a [`ChunkManifest`] under their key
+/// and their chunks under `chunks/<hash>`. Other blobs, like `.narinfo`
+/// files, are passed through. Reading a blob gives back exactly the bytes
+/// that were put, and every chunk is checked against its hash on the way.
+///
+/// Deleting a blob only deletes its manifest since chunks may be shared.
+///
+/// ```
+/// # use nixrs::store::blob_store::{BlobStore, ChunkedBlobStore, FileBlobStore};
+/// # #[tokio::main]
+/// # async fn main() {
+/// let dir = tempfile::tempdir().unwrap();
+/// let blobs = ChunkedBlobStore::new(FileBlobStore::new(dir.path()));
+/// blobs.put("nar/abc.nar", &b"some NAR"[..], "application/x-nix-nar").await.unwrap();
+/// let mut nar = Vec::new();
+/// blobs.get("nar/abc.nar", &mut nar).await.unwrap();
+/// assert_eq!(nar, b"some NAR");
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ChunkedBlobStore<B> {
+    inner: B,
+    config: ChunkerConfig,
+    prefixes: Vec<String>,
+    chunk_prefix: String,
+}
+
+impl<B> ChunkedBlobStore<B> {
+    pub fn new(inner: B) -> ChunkedBlobStore<B> {
+        ChunkedBlobStore {
+            inner,
+            config: ChunkerConfig::default(),
+            prefixes: vec!["nar/".into()],
+            chunk_prefix: "chunks/".into(),
+        }
+    }
+
+    pub fn with_config(inner: B, config: ChunkerConfig) -> Result<ChunkedBlobStore<B>, Error> {
+        config.validate()?;
+        Ok(ChunkedBlobStore {
+            config,
+            ..ChunkedBlobStore::new(inner)
+        })
+    }
+
+    /// Key prefixes of the blobs to chunk.
+    pub fn chunk_prefixes(mut self, prefixes: Vec<String>) -> Self {
+        self.prefixes = prefixes;
+        self
+    }
+
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    fn is_chunked(&self, key: &str) -> bool {
+        self.prefixes.iter().any(|prefix| key.starts_with(prefix))
+    }
+
+    fn chunk_key(&self, hash: &Sri) -> String {
+        format!("{}{}", self.chunk_prefix, hash.0.encode_base32())
+    }
+}
+
+impl<B: BlobStore + Send + Sync> ChunkedBlobStore<B> {
+    /// The manifest of a chunked blob, or `None` when there is no such
+    /// blob.
+    pub async fn manifest(&self, key: &str) -> Result<Option<ChunkManifest>, Error> {
+        if self.inner.head(key).await?.is_none() {
+            return Ok(None);
+        }
+        let mut json = Vec::new();
+        self.inner.get(key, &mut json).await?;
+        Ok(Some(serde_json::from_slice(&json)?))
+    }
+
+    /// Stores the chunks of `body` that aren't stored yet and returns the
+    /// manifest, without storing it.
+    pub async fn put_chunks<R>(
+        &self,
+        mut body: R,
+        content_type: &str,
+    ) -> Result<ChunkManifest, Error>
+    where
+        R: AsyncRead + Send + Unpin,
+    {
+        let mut chunker = Chunker::new(self.config)?;
+        let mut ctx = Context::new(Algorithm::SHA256);
+        let mut size = 0;
+        let mut chunks = Vec::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let read = body.read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+            ctx.update(&buf[..read]);
+            size += read as u64;
+            for chunk in chunker.update(&buf[..read]) {
+                chunks.push(self.put_chunk(chunk).await?);
+            }
+        }
+        for chunk in chunker.finish() {
+            chunks.push(self.put_chunk(chunk).await?);
+        }
+        Ok(ChunkManifest {
+            hash: Sri(ctx.finish()),
+            size,
+            content_type: content_type.into(),
+            chunks,
+        })
+    }
+
+    async fn put_chunk(&self, chunk: Bytes) -> Result<ChunkRef, Error> {
+        let hash = Sri(digest(Algorithm::SHA256, &chunk));
+        let key = self.chunk_key(&hash);
+        let size = chunk.len() as u64;
+        if self.is_stored(&key, size).await? {
+            debug!("chunk {} is already stored", key);
+        } else {
+            self.inner
+                .put(&key, &chunk[..], "application/octet-stream")
+                .await?;
+        }
+        Ok(ChunkRef { hash, size })
+    }
+
+    /// Whether the chunk at `key` is stored with the right size. A chunk
+    /// that was truncated is stored again instead of being shared by the
+    /// new blob.
+    ///
+    /// Only the size is checked since the key is the hash of the chunk and
+    /// fetching it would cost what sharing it saves. Other corruption is
+    /// found when the chunk is read.
+    async fn is_stored(&self, key: &str, size: u64) -> Result<bool, Error> {
+        match self.inner.head(key).await? {
+            None => Ok(false),
+            Some(BlobInfo {
+                size: Some(len), ..
+            }) if len != size => {
+                debug!("chunk {} has the wrong size, storing it again", key);
+                Ok(false)
+            }
+            Some(_) => Ok(true),
+        }
+    }
+
+    /// Writes the blob described by `manifest` to `sink`.
+    ///
+    /// A corrupted chunk is an error and is deleted, so the next blob that
+    /// has it stores it again.
+    pub async fn get_chunks<W>(&self, manifest: &ChunkManifest, mut sink: W) -> Result<(), Error>
+    where
+        W: AsyncWrite + Send + Unpin,
+    {
+        let mut chunk = Vec::new();
+        for entry in manifest.chunks.iter() {
+            chunk.clear();
+            let key = self.chunk_key(&entry.hash);
+            self.inner.get(&key, &mut chunk).await?;
+            let actual = digest(entry.hash.0.algorithm(), &chunk);
+            if actual != entry.hash.0 || chunk.len() as u64 != entry.size {
+                self.inner.delete(&key).await?;
+                return Err(Error::Misc(format!(
+                    "chunk '{}' is corrupted: expected {} bytes with hash {}, got {} bytes with hash {}",
+                    key,
+                    entry.size,
+                    entry.hash,
+                    chunk.len(),
+                    actual.to_sri()
+                )));
+            }
+            sink.write_all(&chunk).await?;
+        }
+        sink.flush().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<B: BlobStore + Send + Sync> BlobStore for ChunkedBlobStore<B> {
+    async fn head(&self, key: &str) -> Result<Option<BlobInfo>, Error> {
+        if !self.is_chunked(key) {
+            return self.inner.head(key).await;
+        }
+        Ok(self.manifest(key).await?.map(|manifest| BlobInfo {
+            size: Some(manifest.size),
+            content_type: Some(manifest.content_type),
+        }))
+    }
+
+    async fn get<W>(&self, key: &str, sink: W) -> Result<(), Error>
+    where
+        W: AsyncWrite + Send + Unpin,
+    {
+        if !self.is_chunked(key) {
+            return self.inner.get(key, sink).await;
+        }
+        let manifest = self
+            .manifest(key)
+            .await?
+            .ok_or_else(|| Error::Misc(format!("blob '{}' does not exist", key)))?;
+        self.get_chunks(&manifest, sink).await
+    }
+
+    async fn put<R>(&self, key: &str, body: R, content_type: &str) -> Result<(), Error>
+    where
+        R: AsyncRead + Send + Unpin,
+    {
+        if !self.is_chunked(key) {
+            return self.inner.put(key, body, content_type).await;
+        }
+        let manifest = self.put_chunks(body, content_type).await?;
+        debug!(
+            "stored '{}' as {} chunks of {} bytes",
+            key,
+            manifest.chunks.len(),
+            manifest.size
+        );
+        let json = serde_json::to_vec(&manifest)?;
+        self.inner
+            .put(key, &json[..], CHUNK_MANIFEST_CONTENT_TYPE)
+            .await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        self.inner.delete(key).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::blob_store::FileBlobStore;
+
+    fn small() -> ChunkerConfig {
+        ChunkerConfig {
+            min_size: 64,
+            avg_size: 256,
+            max_size: 1024,
+        }
+    }
+
+    fn data(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 33) as u8
+            })
+            .collect()
+    }
+
+    fn chunk_all(data: &[u8], step: usize) -> Vec<Bytes> {
+        let mut chunker = Chunker::new(small()).unwrap();
+        let mut chunks = Vec::new();
+        for part in data.chunks(step) {
+            chunks.extend(chunker.update(part));
+        }
+        chunks.extend(chunker.finish());
+        chunks
+    }
+
+    #[test]
+    fn test_chunk_boundaries() {
+        let data = data(20_000, 1);
+        let chunks = chunk_all(&data, 20_000);
+        assert_eq!(chunks.concat(), data);
+        assert!(chunks.len() > 10);
+        for chunk in &chunks[..chunks.len() - 1] {
+            assert!(chunk.len() >= 64 && chunk.len() <= 1024);
+        }
+        // Feeding the data in pieces doesn't move the cuts.
+        assert_eq!(chunk_all(&data, 7), chunks);
+        assert_eq!(chunk_all(&data, 1000), chunks);
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        for (min_size, avg_size, max_size) in
+            [(0, 0, 0), (0, 256, 1024), (64, 200, 1024), (64, 256, 128)]
+        {
+            let config = ChunkerConfig {
+                min_size,
+                avg_size,
+                max_size,
+            };
+            assert!(Chunker::new(config).is_err(), "{:?}", config);
+        }
+    }
+
+    #[test]
+    fn test_insertion_keeps_most_chunks() {
+        let original = data(50_000, 2);
+        let mut edited = original[..25_000].to_vec();
+        edited.extend_from_slice(b"inserted in the middle");
+        edited.extend_from_slice(&original[25_000..]);
+        let before = chunk_all(&original, 4096);
+        let after = chunk_all(&edited, 4096);
+        let shared = after.iter().filter(|c| before.contains(c)).count();
+        assert!(shared + 4 >= before.len(), "{} of {}", shared, before.len());
+    }
+
+    #[tokio::test]
+    async fn test_chunked_blob_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let blobs = ChunkedBlobStore::with_config(FileBlobStore::new(dir.path()), small()).unwrap();
+        let nar = data(30_000, 3);
+        blobs
+            .put("nar/a.nar", &nar[..], "application/x-nix-nar")
+            .await
+            .unwrap();
+        blobs
+            .put("nar/b.nar", &nar[..], "application/x-nix-nar")
+            .await
+            .unwrap();
+        blobs
+            .put("a.narinfo", &b"StorePath: a"[..], "text/x-nix-narinfo")
+            .await
+            .unwrap();
+
+        let info = blobs.head("nar/a.nar").await.unwrap().unwrap();
+        assert_eq!(info.size, Some(30_000));
+        let mut out = Vec::new();
+        blobs.get("nar/b.nar", &mut out).await.unwrap();
+        assert_eq!(out, nar);
+        let manifest = blobs.manifest("nar/a.nar").await.unwrap().unwrap();
+        assert_eq!(manifest.hash.0, digest(Algorithm::SHA256, &nar));
+        // Both NARs share every chunk.
+        let stored = std::fs::read_dir(dir.path().join("chunks"))
+            .unwrap()
+            .count();
+        assert_eq!(stored, manifest.chunks.len());
+        // Blobs outside the chunked prefixes are stored as they are.
+        assert_eq!(
+            std::fs::read(dir.path().join("a.narinfo")).unwrap(),
+            b"StorePath: a"
+        );
+
+        let first = blobs.chunk_key(&manifest.chunks[0].hash);
+        std::fs::write(dir.path().join(&first), b"garbage").unwrap();
+        let mut out = Vec::new();
+        assert!(blobs.get("nar/a.nar", &mut out).await.is_err());
+
+        // Putting the blob again replaces the corrupted chunk.
+        blobs
+            .put("nar/a.nar", &nar[..], "application/x-nix-nar")
+            .await
+            .unwrap();
+        let mut out = Vec::new();
+        blobs.get("nar/a.nar", &mut out).await.unwrap();
+        assert_eq!(out, nar);
+
+        // A corrupted chunk with the right size is only found on read,
+        // which removes it so that the next put replaces it.
+        let len = manifest.chunks[0].size as usize;
+        std::fs::write(dir.path().join(&first), vec![0; len]).unwrap();
+        let mut out = Vec::new();
+        assert!(blobs.get("nar/a.nar", &mut out).await.is_err());
+        assert!(!dir.path().join(&first).exists());
+        blobs
+            .put("nar/b.nar", &nar[..], "application/x-nix-nar")
+            .await
+            .unwrap();
+        let mut out = Vec::new();
+        blobs.get("nar/a.nar", &mut out).await.unwrap();
+        assert_eq!(out, nar);
+    }
+
+    /// Counts the blobs fetched from the inner store.
+    struct CountingGets {
+        inner: FileBlobStore,
+        gets: std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait]
+    impl BlobStore for CountingGets {
+        async fn head(&self, key: &str) -> Result<Option<BlobInfo>, Error> {
+            self.inner.head(key).await
+        }
+
+        async fn get<W>(&self, key: &str, sink: W) -> Result<(), Error>
+        where
+            W: AsyncWrite + Send + Unpin,
+        {
+            self.gets.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            self.inner.get(key, sink).await
+        }
+
+        async fn put<R>(&self, key: &str, body: R, content_type: &str) -> Result<(), Error>
+        where
+            R: AsyncRead + Send + Unpin,
+        {
+            self.inner.put(key, body, content_type).await
+        }
+
+        async fn delete(&self, key: &str) -> Result<(), Error> {
+            self.inner.delete(key).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_put_doesnt_fetch_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let inner = CountingGets {
+            inner: FileBlobStore::new(dir.path()),
+            gets: Default::default(),
+        };
+        let blobs = ChunkedBlobStore::with_config(inner, small()).unwrap();
+        let nar = data(30_000, 4);
+        for key in ["nar/a.nar", "nar/b.nar"] {
+            blobs
+                .put(key, &nar[..], "application/x-nix-nar")
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            blobs.inner().gets.load(std::sync::atomic::Ordering::SeqCst),
+            0
+        );
+    }
+}
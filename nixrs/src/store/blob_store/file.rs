@@ -0,0 +1,139 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::{BlobInfo, BlobStore};
+use crate::store::Error;
+
+/// Blobs kept as files in a directory, with keys as paths relative to it.
+#[derive(Clone, Debug)]
+pub struct FileBlobStore {
+    base_path: Arc<PathBuf>,
+}
+
+impl FileBlobStore {
+    pub fn new(base_path: impl AsRef<Path>) -> FileBlobStore {
+        FileBlobStore {
+            base_path: Arc::new(base_path.as_ref().to_owned()),
+        }
+    }
+
+    pub fn base_path(&self) -> &Path {
+        &self.base_path
+    }
+}
+
+#[async_trait]
+impl BlobStore for FileBlobStore {
+    async fn head(&self, key: &str) -> Result<Option<BlobInfo>, Error> {
+        match fs::metadata(self.base_path.join(key)).await {
+            Ok(metadata) if metadata.is_file() => Ok(Some(BlobInfo {
+                size: Some(metadata.len()),
+                content_type: None,
+            })),
+            Ok(_) => Ok(None),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn get<W>(&self, key: &str, mut sink: W) -> Result<(), Error>
+    where
+        W: AsyncWrite + Send + Unpin,
+    {
+        let mut f = fs::File::open(self.base_path.join(key)).await?;
+        tokio::io::copy(&mut f, &mut sink).await?;
+        Ok(())
+    }
+
+    async fn put<R>(&self, key: &str, mut body: R, _content_type: &str) -> Result<(), Error>
+    where
+        R: AsyncRead + Send + Unpin,
+    {
+        // The body goes to a temporary file next to the blob that is renamed
+        // over it when complete, so readers never see a partial blob and a
+        // failed put leaves any old blob in place.
+        let path = self.base_path.join(key);
+        let parent = path.parent().unwrap_or(&self.base_path);
+        fs::create_dir_all(parent).await?;
+        let (file, temp_path) = tempfile::Builder::new()
+            .prefix(".tmp")
+            .tempfile_in(parent)?
+            .into_parts();
+        let mut f = fs::File::from_std(file);
+        tokio::io::copy(&mut body, &mut f).await?;
+        f.sync_all().await?;
+        temp_path.persist(&path).map_err(|err| err.error)?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        match fs::remove_file(self.base_path.join(key)).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_put_get_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let blobs = FileBlobStore::new(dir.path());
+        assert_eq!(None, blobs.head("log/foo").await.unwrap());
+        assert_eq!(None, blobs.head("log").await.unwrap());
+
+        blobs
+            .put("log/foo", &b"hello"[..], "text/plain")
+            .await
+            .unwrap();
+        let info = blobs.head("log/foo").await.unwrap().unwrap();
+        assert_eq!(info.size, Some(5));
+        let mut buf = Vec::new();
+        blobs.get("log/foo", &mut buf).await.unwrap();
+        assert_eq!(buf, b"hello");
+
+        blobs.delete("log/foo").await.unwrap();
+        assert_eq!(None, blobs.head("log/foo").await.unwrap());
+        blobs.delete("log/foo").await.unwrap();
+    }
+
+    struct FailingReader;
+
+    impl AsyncRead for FailingReader {
+        fn poll_read(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            _buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, "failed")))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_put_keeps_blob() {
+        let dir = tempfile::tempdir().unwrap();
+        let blobs = FileBlobStore::new(dir.path());
+        blobs
+            .put("log/foo", &b"hello"[..], "text/plain")
+            .await
+            .unwrap();
+        blobs
+            .put("log/foo", FailingReader, "text/plain")
+            .await
+            .unwrap_err();
+
+        let mut buf = Vec::new();
+        blobs.get("log/foo", &mut buf).await.unwrap();
+        assert_eq!(buf, b"hello");
+        let entries = std::fs::read_dir(dir.path().join("log")).unwrap().count();
+        assert_eq!(entries, 1);
+    }
+}
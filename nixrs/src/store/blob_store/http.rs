@@ -0,0 +1,129 @@
+use async_trait::async_trait;
+use reqwest::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use reqwest::{Client, IntoUrl, Response, StatusCode, Url};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::{BlobInfo, BlobStore};
+use crate::store::Error;
+
+/// Blobs behind an HTTP server, with keys as URLs relative to a base URL.
+/// Writing needs a server that accepts `PUT` and `DELETE`.
+#[derive(Clone, Debug)]
+pub struct HttpBlobStore {
+    client: Client,
+    base_url: Url,
+}
+
+impl HttpBlobStore {
+    pub fn new<U: IntoUrl>(url: U) -> Result<HttpBlobStore, Error> {
+        let client = reqwest::Client::builder().build()?;
+        Self::with_client(client, url)
+    }
+
+    pub fn with_client<U: IntoUrl>(client: Client, url: U) -> Result<HttpBlobStore, Error> {
+        let base_url = url.into_url()?;
+        Ok(HttpBlobStore { client, base_url })
+    }
+
+    pub fn base_url(&self) -> &Url {
+        &self.base_url
+    }
+}
+
+pub(super) fn blob_info(resp: &Response) -> BlobInfo {
+    let header = |name| {
+        resp.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    BlobInfo {
+        size: header(CONTENT_LENGTH).and_then(|len| len.parse().ok()),
+        content_type: header(CONTENT_TYPE).map(String::from),
+    }
+}
+
+/// Error for a response that wasn't a success. Redirects that weren't
+/// followed and other statuses `error_for_status` accepts are reported as
+/// unexpected instead of panicking.
+pub(super) fn status_error(resp: Response) -> Error {
+    let status = resp.status();
+    match resp.error_for_status() {
+        Err(err) => err.into(),
+        Ok(resp) => Error::Misc(format!(
+            "HTTP error: unexpected status {} for '{}'",
+            status,
+            resp.url()
+        )),
+    }
+}
+
+/// Copy the body of a successful response to a sink.
+pub(super) async fn copy_body<W>(mut resp: Response, mut sink: W) -> Result<(), Error>
+where
+    W: AsyncWrite + Send + Unpin,
+{
+    if resp.status().is_success() {
+        while let Some(chunk) = resp.chunk().await? {
+            sink.write_all(&chunk).await?;
+        }
+        Ok(())
+    } else {
+        Err(status_error(resp))
+    }
+}
+
+#[async_trait]
+impl BlobStore for HttpBlobStore {
+    async fn head(&self, key: &str) -> Result<Option<BlobInfo>, Error> {
+        let url = self.base_url.join(key)?;
+        let resp = self.client.head(url).send().await?;
+        let status = resp.status();
+        if status.is_success() {
+            Ok(Some(blob_info(&resp)))
+        } else if status == StatusCode::NOT_FOUND || status == StatusCode::FORBIDDEN {
+            Ok(None)
+        } else {
+            Err(status_error(resp))
+        }
+    }
+
+    async fn get<W>(&self, key: &str, sink: W) -> Result<(), Error>
+    where
+        W: AsyncWrite + Send + Unpin,
+    {
+        let url = self.base_url.join(key)?;
+        let resp = self.client.get(url).send().await?;
+        copy_body(resp, sink).await
+    }
+
+    async fn put<R>(&self, key: &str, mut body: R, content_type: &str) -> Result<(), Error>
+    where
+        R: AsyncRead + Send + Unpin,
+    {
+        let mut content = Vec::new();
+        body.read_to_end(&mut content).await?;
+        let url = self.base_url.join(key)?;
+        let resp = self
+            .client
+            .put(url)
+            .body(content)
+            .header(CONTENT_TYPE, content_type)
+            .send()
+            .await?;
+        if resp.status().is_success() {
+            Ok(())
+        } else {
+            Err(status_error(resp))
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        let url = self.base_url.join(key)?;
+        let resp = self.client.delete(url).send().await?;
+        if resp.status().is_success() || resp.status() == StatusCode::NOT_FOUND {
+            Ok(())
+        } else {
+            Err(status_error(resp))
+        }
+    }
+}
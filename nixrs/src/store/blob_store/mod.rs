@@ -0,0 +1,18 @@
+//! Key-value storage of blobs that binary caches are kept in.
+//!
+//! [`BlobBinaryCache`](super::binary_cache::BlobBinaryCache) turns any
+//! [`BlobStore`] into a binary cache, so storage services other than the
+//! ones here only need to implement the four operations of the trait.
+mod chunked;
+mod file;
+mod http;
+mod s3;
+mod traits;
+
+pub use self::chunked::{
+    ChunkManifest, ChunkRef, ChunkedBlobStore, Chunker, ChunkerConfig, CHUNK_MANIFEST_CONTENT_TYPE,
+};
+pub use self::file::FileBlobStore;
+pub use self::http::HttpBlobStore;
+pub use self::s3::{S3BlobStore, S3Credentials};
+pub use self::traits::{BlobInfo, BlobStore};
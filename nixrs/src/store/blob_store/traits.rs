@@ -0,0 +1,33 @@
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::store::Error;
+
+/// What is known about a blob without fetching it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlobInfo {
+    pub size: Option<u64>,
+    pub content_type: Option<String>,
+}
+
+/// Storage of blobs by key. Keys are relative paths like
+/// `nar/<hash>.nar.xz` which backends should store as they are.
+#[async_trait]
+pub trait BlobStore {
+    /// Information about the blob, or `None` when there is no such blob.
+    async fn head(&self, key: &str) -> Result<Option<BlobInfo>, Error>;
+
+    /// Write the contents of the blob to a sink. It is an error when there
+    /// is no such blob.
+    async fn get<W>(&self, key: &str, sink: W) -> Result<(), Error>
+    where
+        W: AsyncWrite + Send + Unpin;
+
+    /// Create or replace a blob.
+    async fn put<R>(&self, key: &str, body: R, content_type: &str) -> Result<(), Error>
+    where
+        R: AsyncRead + Send + Unpin;
+
+    /// Delete a blob. Deleting a blob that doesn't exist is not an error.
+    async fn delete(&self, key: &str) -> Result<(), Error>;
+}
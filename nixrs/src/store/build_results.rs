@@ -0,0 +1,504 @@
+use tracing::instrument;
+
+use crate::store_path::{StoreDir, StorePath};
+
+use super::{
+    read_derivation, BuildMode, BuildResult, BuildStatus, DerivedPath, Error, OutputSpec,
+    SingleDerivedPath, Store,
+};
+
+/// Result of building one of the requested derived paths.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyedBuildResult {
+    pub path: DerivedPath,
+    pub result: BuildResult,
+    /// Worked out by [`build_paths_with_results_emulated`] instead of
+    /// reported by the store, so it has no built outputs or timings.
+    pub emulated: bool,
+}
+
+impl KeyedBuildResult {
+    pub fn new(path: DerivedPath, result: BuildResult) -> KeyedBuildResult {
+        KeyedBuildResult {
+            path,
+            result,
+            emulated: false,
+        }
+    }
+
+    pub fn new_emulated(path: DerivedPath, result: BuildResult) -> KeyedBuildResult {
+        KeyedBuildResult {
+            path,
+            result,
+            emulated: true,
+        }
+    }
+
+    /// Whether this is the result of building `output` of `drv_path`.
+    fn builds_output(&self, drv_path: &StorePath, output: &str) -> bool {
+        match &self.path {
+            DerivedPath::Built {
+                drv_path: SingleDerivedPath::Opaque(path),
+                outputs,
+            } if path == drv_path => match outputs {
+                OutputSpec::All => true,
+                OutputSpec::Names(names) => names.contains(output),
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Results of building several derived paths in the order they were
+/// requested.
+///
+/// ```
+/// # use nixrs::store::{BuildResult, BuildResults, BuildStatus, DerivedPath, KeyedBuildResult};
+/// # use nixrs::store_path::StoreDir;
+/// let store_dir = StoreDir::default();
+/// let drv = "/nix/store/7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-konsole-18.12.3.drv";
+/// let path = DerivedPath::parse(&store_dir, &format!("{}^out,dev", drv)).unwrap();
+/// let results: BuildResults = vec![KeyedBuildResult::new(
+///     path,
+///     BuildResult::new(BuildStatus::Built, String::new()),
+/// )]
+/// .into_iter()
+/// .collect();
+/// let drv_path = store_dir.parse_path(drv).unwrap();
+/// assert!(results.get_output(&drv_path, "dev").unwrap().success());
+/// assert!(results.get_output(&drv_path, "doc").is_none());
+/// assert!(results.check(&store_dir).is_ok());
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuildResults {
+    results: Vec<KeyedBuildResult>,
+}
+
+impl BuildResults {
+    pub fn new() -> BuildResults {
+        Default::default()
+    }
+
+    pub fn push(&mut self, result: KeyedBuildResult) {
+        self.results.push(result);
+    }
+
+    pub fn len(&self) -> usize {
+        self.results.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.results.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, KeyedBuildResult> {
+        self.results.iter()
+    }
+
+    /// Result for exactly the derived path that was requested.
+    pub fn get(&self, path: &DerivedPath) -> Option<&BuildResult> {
+        self.results
+            .iter()
+            .find(|keyed| &keyed.path == path)
+            .map(|keyed| &keyed.result)
+    }
+
+    /// Result of the request that built `output` of `drv_path`, either by
+    /// naming it or by asking for all outputs.
+    pub fn get_output(&self, drv_path: &StorePath, output: &str) -> Option<&BuildResult> {
+        self.results
+            .iter()
+            .find(|keyed| keyed.builds_output(drv_path, output))
+            .map(|keyed| &keyed.result)
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.results.iter().all(|keyed| keyed.result.success())
+    }
+
+    pub fn successes(&self) -> impl Iterator<Item = &KeyedBuildResult> {
+        self.results.iter().filter(|keyed| keyed.result.success())
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &KeyedBuildResult> {
+        self.results.iter().filter(|keyed| !keyed.result.success())
+    }
+
+    /// Split into the successful and the failed results.
+    pub fn partition(self) -> (BuildResults, BuildResults) {
+        let (ok, failed) = self
+            .results
+            .into_iter()
+            .partition(|keyed| keyed.result.success());
+        (
+            BuildResults { results: ok },
+            BuildResults { results: failed },
+        )
+    }
+
+    /// Describe the failed builds for showing to a user, or `None` when
+    /// everything was built.
+    ///
+    /// A single failure is reported with its error message. Several are
+    /// listed below a line naming all of them, like `nix build` does.
+    pub fn failure_summary(&self, store_dir: &StoreDir) -> Option<String> {
+        let failures: Vec<_> = self.failures().collect();
+        match failures.as_slice() {
+            [] => None,
+            [keyed] => Some(format!(
+                "build of '{}' failed: {}",
+                keyed.path.display(store_dir),
+                keyed.result
+            )),
+            _ => {
+                let mut ret = format!(
+                    "build of {} failed",
+                    quoted_paths(store_dir, failures.iter().copied())
+                );
+                for keyed in failures.iter() {
+                    ret.push_str(&format!(
+                        "\n  {}: {}",
+                        keyed.path.display(store_dir),
+                        keyed.result
+                    ));
+                }
+                Some(ret)
+            }
+        }
+    }
+
+    /// Fail with [`Error::BuildFailed`] unless every build succeeded.
+    pub fn check(&self, store_dir: &StoreDir) -> Result<(), Error> {
+        let failures: Vec<_> = self.failures().collect();
+        if failures.is_empty() {
+            return Ok(());
+        }
+        let paths: Vec<String> = failures
+            .iter()
+            .map(|keyed| keyed.path.display(store_dir).to_string())
+            .collect();
+        let msgs: Vec<String> = failures
+            .iter()
+            .map(|keyed| keyed.result.to_string())
+            .collect();
+        Err(Error::BuildFailed(paths.join("', '"), msgs.join("; ")))
+    }
+}
+
+/// Builds `paths` with [`Store::build_paths`] and works out a result for
+/// each of them from what is valid afterwards. This is what
+/// `BuildPathsWithResults` does for daemons older than protocol 1.34.
+///
+/// Opaque paths are reported as substituted and derivations as built, or
+/// as failed when a wanted output with a known path is still missing. Built
+/// outputs are keyed by derivation hashes only the daemon knows, so they
+/// are left empty. Like `build_paths`, any failed build fails the whole
+/// call.
+#[instrument(skip_all, fields(paths = paths.len(), ?build_mode))]
+pub async fn build_paths_with_results_emulated<S>(
+    store: &mut S,
+    paths: &[DerivedPath],
+    build_mode: BuildMode,
+) -> Result<BuildResults, Error>
+where
+    S: Store + Send,
+{
+    store.build_paths(paths, build_mode).await?;
+    let store_dir = store.store_dir();
+    let mut results = BuildResults::new();
+    for path in paths {
+        let result = match path {
+            DerivedPath::Opaque(_) => BuildResult::new(BuildStatus::Substituted, String::new()),
+            DerivedPath::Built {
+                drv_path: SingleDerivedPath::Opaque(drv_path),
+                outputs,
+            } => {
+                let drv = read_derivation(store, drv_path).await?;
+                let mut missing = Vec::new();
+                for (name, (_, out_path)) in drv.basic.outputs_and_opt_paths(&store_dir)? {
+                    if let OutputSpec::Names(names) = outputs {
+                        if !names.contains(&name) {
+                            continue;
+                        }
+                    }
+                    // Floating outputs can't be checked without the
+                    // realisations.
+                    if let Some(out_path) = out_path {
+                        if store.query_path_info(&out_path).await?.is_none() {
+                            missing.push(name);
+                        }
+                    }
+                }
+                if missing.is_empty() {
+                    BuildResult::new(BuildStatus::Built, String::new())
+                } else {
+                    BuildResult::new(
+                        BuildStatus::MiscFailure,
+                        format!(
+                            "outputs '{}' of '{}' are not valid after building",
+                            missing.join("', '"),
+                            store_dir.print_path(drv_path)
+                        ),
+                    )
+                }
+            }
+            // The derivation is itself built, so there is nothing to read
+            // the outputs from.
+            DerivedPath::Built { .. } => BuildResult::new(BuildStatus::Built, String::new()),
+        };
+        results.push(KeyedBuildResult::new_emulated(path.clone(), result));
+    }
+    Ok(results)
+}
+
+fn quoted_paths<'a, I>(store_dir: &StoreDir, results: I) -> String
+where
+    I: IntoIterator<Item = &'a KeyedBuildResult>,
+{
+    results
+        .into_iter()
+        .map(|keyed| format!("'{}'", keyed.path.display(store_dir)))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl FromIterator<KeyedBuildResult> for BuildResults {
+    fn from_iter<T: IntoIterator<Item = KeyedBuildResult>>(iter: T) -> Self {
+        BuildResults {
+            results: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl From<Vec<KeyedBuildResult>> for BuildResults {
+    fn from(results: Vec<KeyedBuildResult>) -> Self {
+        BuildResults { results }
+    }
+}
+
+impl IntoIterator for BuildResults {
+    type Item = KeyedBuildResult;
+    type IntoIter = std::vec::IntoIter<KeyedBuildResult>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.results.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a BuildResults {
+    type Item = &'a KeyedBuildResult;
+    type IntoIter = std::slice::Iter<'a, KeyedBuildResult>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.results.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt;
+    use std::sync::Arc;
+
+    use assert_matches::assert_matches;
+    use async_trait::async_trait;
+    use bytes::{Bytes, BytesMut};
+    use tokio::io::{AsyncRead, AsyncWrite};
+
+    use super::*;
+    use crate::archive::{NAREvent, NAR_VERSION_MAGIC_1};
+    use crate::hash;
+    use crate::path_info::ValidPathInfo;
+    use crate::store::{CheckSignaturesFlag, MemoryStore, RepairFlag};
+    use crate::store_path::StoreDirProvider;
+
+    fn results(store_dir: &StoreDir) -> BuildResults {
+        let built = |s: &str, status| {
+            let drv = store_dir.print_path(&StorePath::test_from_seed(s));
+            KeyedBuildResult::new(
+                DerivedPath::parse(store_dir, &format!("{}^*", drv)).unwrap(),
+                BuildResult::new(status, String::new()),
+            )
+        };
+        vec![
+            built("a.drv", BuildStatus::Built),
+            built("b.drv", BuildStatus::PermanentFailure),
+            KeyedBuildResult::new(
+                DerivedPath::Opaque(StorePath::test_from_seed("c")),
+                BuildResult::new(BuildStatus::Substituted, String::new()),
+            ),
+            built("d.drv", BuildStatus::TimedOut),
+        ]
+        .into()
+    }
+
+    #[test]
+    fn test_lookup() {
+        let store_dir = StoreDir::default();
+        let results = results(&store_dir);
+        let a = StorePath::test_from_seed("a.drv");
+        assert_eq!(
+            results.get_output(&a, "out").map(|r| r.status),
+            Some(BuildStatus::Built)
+        );
+        let c = DerivedPath::Opaque(StorePath::test_from_seed("c"));
+        assert_eq!(
+            results.get(&c).map(|r| r.status),
+            Some(BuildStatus::Substituted)
+        );
+        assert!(results
+            .get_output(&StorePath::test_from_seed("c"), "out")
+            .is_none());
+    }
+
+    #[test]
+    fn test_failures() {
+        let store_dir = StoreDir::default();
+        let results = results(&store_dir);
+        assert!(!results.is_success());
+        assert_eq!(results.successes().count(), 2);
+        let summary = results.failure_summary(&store_dir).unwrap();
+        assert_eq!(summary.lines().count(), 3);
+        assert!(summary.starts_with("build of '/nix/store/"));
+        assert_matches!(results.check(&store_dir), Err(Error::BuildFailed(_, _)));
+
+        let (ok, failed) = results.partition();
+        assert!(ok.is_success());
+        assert_eq!(ok.failure_summary(&store_dir), None);
+        assert!(ok.check(&store_dir).is_ok());
+        assert_eq!(failed.len(), 2);
+    }
+
+    /// Records what it is asked to build without building anything.
+    struct BuildStore {
+        inner: MemoryStore,
+        builds: Vec<DerivedPath>,
+    }
+
+    impl StoreDirProvider for BuildStore {
+        fn store_dir(&self) -> StoreDir {
+            self.inner.store_dir()
+        }
+    }
+
+    #[async_trait]
+    impl Store for BuildStore {
+        async fn query_path_info(
+            &mut self,
+            path: &StorePath,
+        ) -> Result<Option<ValidPathInfo>, Error> {
+            self.inner.query_path_info(path).await
+        }
+
+        async fn nar_from_path<W: AsyncWrite + fmt::Debug + Send + Unpin>(
+            &mut self,
+            path: &StorePath,
+            sink: W,
+        ) -> Result<(), Error> {
+            self.inner.nar_from_path(path, sink).await
+        }
+
+        async fn add_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+            &mut self,
+            info: &ValidPathInfo,
+            source: R,
+            repair: RepairFlag,
+            check_sigs: CheckSignaturesFlag,
+        ) -> Result<(), Error> {
+            self.inner
+                .add_to_store(info, source, repair, check_sigs)
+                .await
+        }
+
+        async fn build_paths(
+            &mut self,
+            drv_paths: &[DerivedPath],
+            _build_mode: BuildMode,
+        ) -> Result<(), Error> {
+            self.builds.extend_from_slice(drv_paths);
+            Ok(())
+        }
+    }
+
+    async fn add_file(store: &mut MemoryStore, path: &StorePath, contents: &str) {
+        let size = contents.len() as u64;
+        let mut nar = BytesMut::new();
+        NAREvent::Magic(Arc::new(NAR_VERSION_MAGIC_1.into())).encode_into(&mut nar);
+        NAREvent::RegularNode {
+            executable: false,
+            size,
+            offset: 0,
+        }
+        .encode_into(&mut nar);
+        NAREvent::Contents {
+            total: size,
+            index: 0,
+            buf: Bytes::copy_from_slice(contents.as_bytes()),
+        }
+        .encode_into(&mut nar);
+        let mut info =
+            ValidPathInfo::new(path.clone(), hash::digest(hash::Algorithm::SHA256, &nar));
+        info.nar_size = nar.len() as u64;
+        store
+            .add_to_store(
+                &info,
+                &nar[..],
+                RepairFlag::NoRepair,
+                CheckSignaturesFlag::NoCheckSigs,
+            )
+            .await
+            .unwrap();
+    }
+
+    async fn add_drv(store: &mut MemoryStore, name: &str, out: &StorePath) -> StorePath {
+        let store_dir = store.store_dir();
+        let drv_path = StorePath::test_from_seed(&format!("{}.drv", name));
+        let drv = format!(
+            "Derive([(\"out\",\"{}\",\"\",\"\")],[],[],\"x86_64-linux\",\"/bin/sh\",[],[(\"name\",\"{}\")])",
+            store_dir.print_path(out),
+            name
+        );
+        add_file(store, &drv_path, &drv).await;
+        drv_path
+    }
+
+    #[tokio::test]
+    async fn test_build_paths_with_results_emulated() {
+        let mut inner = MemoryStore::new();
+        let a = StorePath::test_from_seed("a");
+        let b = StorePath::test_from_seed("b");
+        let a_drv = add_drv(&mut inner, "a", &a).await;
+        let b_drv = add_drv(&mut inner, "b", &b).await;
+        add_file(&mut inner, &a, "a").await;
+        let built = |drv_path: &StorePath| DerivedPath::Built {
+            drv_path: SingleDerivedPath::Opaque(drv_path.clone()),
+            outputs: OutputSpec::All,
+        };
+        let paths = vec![
+            built(&a_drv),
+            built(&b_drv),
+            DerivedPath::Opaque(StorePath::test_from_seed("c")),
+        ];
+        let mut store = BuildStore {
+            inner,
+            builds: Vec::new(),
+        };
+
+        let results = build_paths_with_results_emulated(&mut store, &paths, BuildMode::Normal)
+            .await
+            .unwrap();
+        assert_eq!(store.builds, paths);
+        assert_eq!(results.len(), 3);
+        assert!(results.iter().all(|keyed| keyed.emulated));
+        assert_eq!(
+            results.get_output(&a_drv, "out").unwrap().status,
+            BuildStatus::Built
+        );
+        let b_result = results.get_output(&b_drv, "out").unwrap();
+        assert_eq!(b_result.status, BuildStatus::MiscFailure);
+        assert!(b_result.error_msg.contains("'out'"));
+        assert_eq!(
+            results.get(&paths[2]).unwrap().status,
+            BuildStatus::Substituted
+        );
+    }
+}
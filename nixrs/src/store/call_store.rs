@@ -0,0 +1,269 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::hash::{Algorithm, Hash};
+use crate::path_info::ValidPathInfo;
+use crate::store::activity::Activity;
+use crate::store::daemon::{
+    DaemonPath, DaemonStore, GCAction, GCOptions, GCResults, QueryMissingResult, TrustedFlag,
+};
+use crate::store::{
+    CheckSignaturesFlag, DerivedPath, DrvOutput, Error, Realisation, RepairFlag, Store,
+};
+use crate::store_path::{StoreDir, StoreDirProvider, StorePath, StorePathSet};
+
+/// The [`DaemonStore`] methods [`call_all`] calls, in the order it calls
+/// them.
+pub const DAEMON_STORE_METHODS: &[&str] = &[
+    "set_options",
+    "is_valid_path",
+    "add_multiple_to_store",
+    "query_missing",
+    "add_build_log",
+    "collect_garbage",
+    "collect_garbage_streaming",
+    "query_realisation",
+    "register_drv_output",
+    "add_indirect_root",
+    "find_roots",
+    "verify_store",
+    "optimise_store",
+    "repair_path",
+    "substitute_paths",
+];
+
+/// Records every [`DaemonStore`] method called on it and answers them all
+/// with an empty success, as if the store had no paths, so wrappers can be
+/// checked to pass every method on.
+#[derive(Debug, Default)]
+pub struct CallStore {
+    store_dir: StoreDir,
+    pub calls: Vec<&'static str>,
+}
+
+impl CallStore {
+    pub fn new(store_dir: StoreDir) -> CallStore {
+        CallStore {
+            store_dir,
+            calls: Vec::new(),
+        }
+    }
+
+    /// Whether every method in `methods` was called.
+    pub fn called_all(&self, methods: &[&str]) -> bool {
+        methods.iter().all(|method| self.calls.contains(method))
+    }
+}
+
+impl StoreDirProvider for CallStore {
+    fn store_dir(&self) -> StoreDir {
+        self.store_dir.clone()
+    }
+}
+
+#[async_trait]
+impl Store for CallStore {
+    async fn query_path_info(&mut self, _path: &StorePath) -> Result<Option<ValidPathInfo>, Error> {
+        self.calls.push("query_path_info");
+        Ok(None)
+    }
+
+    async fn nar_from_path<W: AsyncWrite + fmt::Debug + Send + Unpin>(
+        &mut self,
+        _path: &StorePath,
+        _sink: W,
+    ) -> Result<(), Error> {
+        self.calls.push("nar_from_path");
+        Ok(())
+    }
+
+    async fn add_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        _info: &ValidPathInfo,
+        _source: R,
+        _repair: RepairFlag,
+        _check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error> {
+        self.calls.push("add_to_store");
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl DaemonStore for CallStore {
+    fn is_trusted_client(&self) -> Option<TrustedFlag> {
+        Some(TrustedFlag::Trusted)
+    }
+
+    async fn set_options(&mut self) -> Result<(), Error> {
+        self.calls.push("set_options");
+        Ok(())
+    }
+
+    async fn is_valid_path(&mut self, _path: &StorePath) -> Result<bool, Error> {
+        self.calls.push("is_valid_path");
+        Ok(false)
+    }
+
+    async fn add_multiple_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        _source: R,
+        _repair: RepairFlag,
+        _check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error> {
+        self.calls.push("add_multiple_to_store");
+        Ok(())
+    }
+
+    async fn query_missing(
+        &mut self,
+        _targets: &[DerivedPath],
+    ) -> Result<QueryMissingResult, Error> {
+        self.calls.push("query_missing");
+        Ok(QueryMissingResult {
+            will_build: StorePathSet::new(),
+            will_substitute: StorePathSet::new(),
+            unknown: StorePathSet::new(),
+            download_size: 0,
+            nar_size: 0,
+        })
+    }
+
+    async fn add_build_log(&mut self, _drv_path: &StorePath, _log: &str) -> Result<(), Error> {
+        self.calls.push("add_build_log");
+        Ok(())
+    }
+
+    async fn collect_garbage(&mut self, _options: &GCOptions) -> Result<GCResults, Error> {
+        self.calls.push("collect_garbage");
+        Ok(GCResults::default())
+    }
+
+    async fn collect_garbage_streaming(
+        &mut self,
+        _options: &GCOptions,
+        _act: &Activity,
+    ) -> Result<GCResults, Error> {
+        self.calls.push("collect_garbage_streaming");
+        Ok(GCResults::default())
+    }
+
+    async fn query_realisation(&mut self, _id: &DrvOutput) -> Result<Option<Realisation>, Error> {
+        self.calls.push("query_realisation");
+        Ok(None)
+    }
+
+    async fn register_drv_output(&mut self, _realisation: &Realisation) -> Result<(), Error> {
+        self.calls.push("register_drv_output");
+        Ok(())
+    }
+
+    async fn add_indirect_root(&mut self, _path: &DaemonPath) -> Result<(), Error> {
+        self.calls.push("add_indirect_root");
+        Ok(())
+    }
+
+    async fn find_roots(&mut self) -> Result<BTreeMap<DaemonPath, StorePath>, Error> {
+        self.calls.push("find_roots");
+        Ok(BTreeMap::new())
+    }
+
+    async fn verify_store(
+        &mut self,
+        _check_contents: bool,
+        _repair: RepairFlag,
+    ) -> Result<bool, Error> {
+        self.calls.push("verify_store");
+        Ok(false)
+    }
+
+    async fn optimise_store(&mut self) -> Result<(), Error> {
+        self.calls.push("optimise_store");
+        Ok(())
+    }
+
+    async fn repair_path(&mut self, _path: &StorePath) -> Result<(), Error> {
+        self.calls.push("repair_path");
+        Ok(())
+    }
+
+    async fn substitute_paths(&mut self, _paths: &StorePathSet) -> Result<(), Error> {
+        self.calls.push("substitute_paths");
+        Ok(())
+    }
+}
+
+/// Call every method in [`DAEMON_STORE_METHODS`] on `store` and return
+/// their results by name. No paths are added, and repairs and garbage
+/// collection ask for the mutating variants.
+pub async fn call_all<S>(store: &mut S) -> Vec<(&'static str, Result<(), Error>)>
+where
+    S: DaemonStore + Send,
+{
+    let path = StorePath::test_from_seed("call");
+    let paths: StorePathSet = [path.clone()].into_iter().collect();
+    let gc = GCOptions {
+        action: GCAction::DeleteDead,
+        ..Default::default()
+    };
+    let id = DrvOutput {
+        drv_hash: Hash::test_from_seed(Algorithm::SHA256, "call"),
+        output_name: "out".into(),
+    };
+    let realisation = Realisation {
+        id: id.clone(),
+        out_path: path.clone(),
+        signatures: Default::default(),
+        dependent_realisations: Default::default(),
+    };
+    let root = DaemonPath::new(b"/nix/var/nix/gcroots/auto/call".to_vec()).unwrap();
+    let act = Activity::disabled();
+    let opaque = [DerivedPath::Opaque(path.clone())];
+    vec![
+        ("set_options", store.set_options().await),
+        ("is_valid_path", store.is_valid_path(&path).await.map(drop)),
+        (
+            "add_multiple_to_store",
+            store
+                .add_multiple_to_store(
+                    &0u64.to_le_bytes()[..],
+                    RepairFlag::NoRepair,
+                    CheckSignaturesFlag::NoCheckSigs,
+                )
+                .await,
+        ),
+        (
+            "query_missing",
+            store.query_missing(&opaque).await.map(drop),
+        ),
+        ("add_build_log", store.add_build_log(&path, "log").await),
+        (
+            "collect_garbage",
+            store.collect_garbage(&gc).await.map(drop),
+        ),
+        (
+            "collect_garbage_streaming",
+            store.collect_garbage_streaming(&gc, &act).await.map(drop),
+        ),
+        (
+            "query_realisation",
+            store.query_realisation(&id).await.map(drop),
+        ),
+        (
+            "register_drv_output",
+            store.register_drv_output(&realisation).await,
+        ),
+        ("add_indirect_root", store.add_indirect_root(&root).await),
+        ("find_roots", store.find_roots().await.map(drop)),
+        (
+            "verify_store",
+            store.verify_store(true, RepairFlag::Repair).await.map(drop),
+        ),
+        ("optimise_store", store.optimise_store().await),
+        ("repair_path", store.repair_path(&path).await),
+        ("substitute_paths", store.substitute_paths(&paths).await),
+    ]
+}
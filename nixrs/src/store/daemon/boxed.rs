@@ -0,0 +1,505 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::path_info::ValidPathInfo;
+use crate::store::activity::Activity;
+use crate::store::{
+    BasicDerivation, BuildMode, BuildResult, CheckSignaturesFlag, DerivedPath, DrvOutput, Error,
+    ExperimentalFeatures, Realisation, RepairFlag, Store, SubstituteFlag,
+};
+use crate::store_path::{StoreDir, StoreDirProvider, StorePath, StorePathSet};
+
+use super::{DaemonPath, DaemonStore, GCOptions, GCResults, QueryMissingResult, TrustedFlag};
+
+/// A boxed [`AsyncRead`] for passing sources to a [`DynDaemonStore`].
+pub struct DynReader<'a>(Pin<Box<dyn AsyncRead + Send + 'a>>);
+
+impl<'a> DynReader<'a> {
+    pub fn new<R: AsyncRead + Send + 'a>(reader: R) -> DynReader<'a> {
+        DynReader(Box::pin(reader))
+    }
+}
+
+impl<'a> AsyncRead for DynReader<'a> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.0.as_mut().poll_read(cx, buf)
+    }
+}
+
+impl<'a> fmt::Debug for DynReader<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DynReader").finish_non_exhaustive()
+    }
+}
+
+/// A boxed [`AsyncWrite`] for passing sinks to a [`DynDaemonStore`].
+pub struct DynWriter<'a>(Pin<Box<dyn AsyncWrite + Send + 'a>>);
+
+impl<'a> DynWriter<'a> {
+    pub fn new<W: AsyncWrite + Send + 'a>(writer: W) -> DynWriter<'a> {
+        DynWriter(Box::pin(writer))
+    }
+}
+
+impl<'a> AsyncWrite for DynWriter<'a> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.0.as_mut().poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.0.as_mut().poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.0.as_mut().poll_shutdown(cx)
+    }
+}
+
+impl<'a> fmt::Debug for DynWriter<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DynWriter").finish_non_exhaustive()
+    }
+}
+
+/// An object safe version of [`Store`] and [`DaemonStore`].
+///
+/// Every [`DaemonStore`] is a `DynDaemonStore`, so stores picked at runtime
+/// can be kept as `Box<dyn DynDaemonStore>`. Wrap that in a
+/// [`BoxedDaemonStore`] to hand it to code that wants a [`DaemonStore`].
+#[async_trait]
+pub trait DynDaemonStore: StoreDirProvider + Send {
+    fn is_trusted_client(&self) -> Option<TrustedFlag>;
+    fn experimental_features(&self) -> ExperimentalFeatures;
+    async fn query_valid_paths(
+        &mut self,
+        paths: &StorePathSet,
+        maybe_substitute: SubstituteFlag,
+    ) -> Result<StorePathSet, Error>;
+    async fn query_path_info(&mut self, path: &StorePath) -> Result<Option<ValidPathInfo>, Error>;
+    async fn nar_from_path<'a>(
+        &mut self,
+        path: &StorePath,
+        sink: DynWriter<'a>,
+    ) -> Result<(), Error>;
+    async fn add_to_store<'a>(
+        &mut self,
+        info: &ValidPathInfo,
+        source: DynReader<'a>,
+        repair: RepairFlag,
+        check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error>;
+    async fn build_derivation(
+        &mut self,
+        drv_path: &StorePath,
+        drv: &BasicDerivation,
+        build_mode: BuildMode,
+    ) -> Result<BuildResult, Error>;
+    async fn build_paths(
+        &mut self,
+        drv_paths: &[DerivedPath],
+        build_mode: BuildMode,
+    ) -> Result<(), Error>;
+    async fn set_options(&mut self) -> Result<(), Error>;
+    async fn is_valid_path(&mut self, path: &StorePath) -> Result<bool, Error>;
+    async fn add_multiple_to_store<'a>(
+        &mut self,
+        source: DynReader<'a>,
+        repair: RepairFlag,
+        check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error>;
+    async fn query_missing(&mut self, targets: &[DerivedPath])
+        -> Result<QueryMissingResult, Error>;
+    async fn add_build_log(&mut self, drv_path: &StorePath, log: &str) -> Result<(), Error>;
+    async fn repair_path(&mut self, path: &StorePath) -> Result<(), Error>;
+    async fn collect_garbage(&mut self, options: &GCOptions) -> Result<GCResults, Error>;
+    async fn query_realisation(&mut self, id: &DrvOutput) -> Result<Option<Realisation>, Error>;
+    async fn register_drv_output(&mut self, realisation: &Realisation) -> Result<(), Error>;
+    async fn add_indirect_root(&mut self, path: &DaemonPath) -> Result<(), Error>;
+    async fn find_roots(&mut self) -> Result<BTreeMap<DaemonPath, StorePath>, Error>;
+    async fn verify_store(
+        &mut self,
+        check_contents: bool,
+        repair: RepairFlag,
+    ) -> Result<bool, Error>;
+    async fn optimise_store(&mut self) -> Result<(), Error>;
+    async fn collect_garbage_streaming(
+        &mut self,
+        options: &GCOptions,
+        act: &Activity,
+    ) -> Result<GCResults, Error>;
+    async fn substitute_paths(&mut self, paths: &StorePathSet) -> Result<(), Error>;
+}
+
+#[async_trait]
+impl<S> DynDaemonStore for S
+where
+    S: DaemonStore + Send,
+{
+    fn is_trusted_client(&self) -> Option<TrustedFlag> {
+        DaemonStore::is_trusted_client(self)
+    }
+
+    fn experimental_features(&self) -> ExperimentalFeatures {
+        DaemonStore::experimental_features(self)
+    }
+
+    async fn query_valid_paths(
+        &mut self,
+        paths: &StorePathSet,
+        maybe_substitute: SubstituteFlag,
+    ) -> Result<StorePathSet, Error> {
+        Store::query_valid_paths(self, paths, maybe_substitute).await
+    }
+
+    async fn query_path_info(&mut self, path: &StorePath) -> Result<Option<ValidPathInfo>, Error> {
+        Store::query_path_info(self, path).await
+    }
+
+    async fn nar_from_path<'a>(
+        &mut self,
+        path: &StorePath,
+        sink: DynWriter<'a>,
+    ) -> Result<(), Error> {
+        Store::nar_from_path(self, path, sink).await
+    }
+
+    async fn add_to_store<'a>(
+        &mut self,
+        info: &ValidPathInfo,
+        source: DynReader<'a>,
+        repair: RepairFlag,
+        check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error> {
+        Store::add_to_store(self, info, source, repair, check_sigs).await
+    }
+
+    async fn build_derivation(
+        &mut self,
+        drv_path: &StorePath,
+        drv: &BasicDerivation,
+        build_mode: BuildMode,
+    ) -> Result<BuildResult, Error> {
+        Store::build_derivation(self, drv_path, drv, build_mode).await
+    }
+
+    async fn build_paths(
+        &mut self,
+        drv_paths: &[DerivedPath],
+        build_mode: BuildMode,
+    ) -> Result<(), Error> {
+        Store::build_paths(self, drv_paths, build_mode).await
+    }
+
+    async fn set_options(&mut self) -> Result<(), Error> {
+        DaemonStore::set_options(self).await
+    }
+
+    async fn is_valid_path(&mut self, path: &StorePath) -> Result<bool, Error> {
+        DaemonStore::is_valid_path(self, path).await
+    }
+
+    async fn add_multiple_to_store<'a>(
+        &mut self,
+        source: DynReader<'a>,
+        repair: RepairFlag,
+        check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error> {
+        DaemonStore::add_multiple_to_store(self, source, repair, check_sigs).await
+    }
+
+    async fn query_missing(
+        &mut self,
+        targets: &[DerivedPath],
+    ) -> Result<QueryMissingResult, Error> {
+        DaemonStore::query_missing(self, targets).await
+    }
+
+    async fn add_build_log(&mut self, drv_path: &StorePath, log: &str) -> Result<(), Error> {
+        DaemonStore::add_build_log(self, drv_path, log).await
+    }
+
+    async fn repair_path(&mut self, path: &StorePath) -> Result<(), Error> {
+        DaemonStore::repair_path(self, path).await
+    }
+
+    async fn collect_garbage(&mut self, options: &GCOptions) -> Result<GCResults, Error> {
+        DaemonStore::collect_garbage(self, options).await
+    }
+
+    async fn query_realisation(&mut self, id: &DrvOutput) -> Result<Option<Realisation>, Error> {
+        DaemonStore::query_realisation(self, id).await
+    }
+
+    async fn register_drv_output(&mut self, realisation: &Realisation) -> Result<(), Error> {
+        DaemonStore::register_drv_output(self, realisation).await
+    }
+
+    async fn add_indirect_root(&mut self, path: &DaemonPath) -> Result<(), Error> {
+        DaemonStore::add_indirect_root(self, path).await
+    }
+
+    async fn find_roots(&mut self) -> Result<BTreeMap<DaemonPath, StorePath>, Error> {
+        DaemonStore::find_roots(self).await
+    }
+
+    async fn verify_store(
+        &mut self,
+        check_contents: bool,
+        repair: RepairFlag,
+    ) -> Result<bool, Error> {
+        DaemonStore::verify_store(self, check_contents, repair).await
+    }
+
+    async fn optimise_store(&mut self) -> Result<(), Error> {
+        DaemonStore::optimise_store(self).await
+    }
+
+    async fn collect_garbage_streaming(
+        &mut self,
+        options: &GCOptions,
+        act: &Activity,
+    ) -> Result<GCResults, Error> {
+        DaemonStore::collect_garbage_streaming(self, options, act).await
+    }
+
+    async fn substitute_paths(&mut self, paths: &StorePathSet) -> Result<(), Error> {
+        DaemonStore::substitute_paths(self, paths).await
+    }
+}
+
+/// A `Box<dyn DynDaemonStore>` that is a [`DaemonStore`] again.
+///
+/// Any [`DaemonStore`] is also a [`DynDaemonStore`], so importing both
+/// traits makes method calls ambiguous. Only import `DynDaemonStore` where
+/// the trait objects are made.
+///
+/// ```
+/// use nixrs::store::daemon::{BoxedDaemonStore, DaemonWrapStore, DynDaemonStore};
+/// use nixrs::store::MemoryStore;
+///
+/// let stores: Vec<Box<dyn DynDaemonStore>> = vec![
+///     Box::new(MemoryStore::new()),
+///     Box::new(DaemonWrapStore::new(MemoryStore::new())),
+/// ];
+/// let picked = BoxedDaemonStore::new(stores.into_iter().next().unwrap());
+/// ```
+pub struct BoxedDaemonStore<'s>(Box<dyn DynDaemonStore + 's>);
+
+impl<'s> BoxedDaemonStore<'s> {
+    pub fn new(store: Box<dyn DynDaemonStore + 's>) -> BoxedDaemonStore<'s> {
+        BoxedDaemonStore(store)
+    }
+
+    pub fn into_inner(self) -> Box<dyn DynDaemonStore + 's> {
+        self.0
+    }
+}
+
+impl<'s> From<Box<dyn DynDaemonStore + 's>> for BoxedDaemonStore<'s> {
+    fn from(store: Box<dyn DynDaemonStore + 's>) -> Self {
+        BoxedDaemonStore(store)
+    }
+}
+
+impl<'s> fmt::Debug for BoxedDaemonStore<'s> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("BoxedDaemonStore")
+            .field(&self.0.store_dir())
+            .finish()
+    }
+}
+
+impl<'s> StoreDirProvider for BoxedDaemonStore<'s> {
+    fn store_dir(&self) -> StoreDir {
+        self.0.store_dir()
+    }
+}
+
+#[async_trait]
+impl<'s> Store for BoxedDaemonStore<'s> {
+    async fn query_valid_paths(
+        &mut self,
+        paths: &StorePathSet,
+        maybe_substitute: SubstituteFlag,
+    ) -> Result<StorePathSet, Error> {
+        DynDaemonStore::query_valid_paths(&mut *self.0, paths, maybe_substitute).await
+    }
+
+    async fn query_path_info(&mut self, path: &StorePath) -> Result<Option<ValidPathInfo>, Error> {
+        DynDaemonStore::query_path_info(&mut *self.0, path).await
+    }
+
+    async fn nar_from_path<W: AsyncWrite + fmt::Debug + Send + Unpin>(
+        &mut self,
+        path: &StorePath,
+        sink: W,
+    ) -> Result<(), Error> {
+        DynDaemonStore::nar_from_path(&mut *self.0, path, DynWriter::new(sink)).await
+    }
+
+    async fn add_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        info: &ValidPathInfo,
+        source: R,
+        repair: RepairFlag,
+        check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error> {
+        let source = DynReader::new(source);
+        DynDaemonStore::add_to_store(&mut *self.0, info, source, repair, check_sigs).await
+    }
+
+    async fn build_derivation(
+        &mut self,
+        drv_path: &StorePath,
+        drv: &BasicDerivation,
+        build_mode: BuildMode,
+    ) -> Result<BuildResult, Error> {
+        DynDaemonStore::build_derivation(&mut *self.0, drv_path, drv, build_mode).await
+    }
+
+    async fn build_paths(
+        &mut self,
+        drv_paths: &[DerivedPath],
+        build_mode: BuildMode,
+    ) -> Result<(), Error> {
+        DynDaemonStore::build_paths(&mut *self.0, drv_paths, build_mode).await
+    }
+}
+
+#[async_trait]
+impl<'s> DaemonStore for BoxedDaemonStore<'s> {
+    fn is_trusted_client(&self) -> Option<TrustedFlag> {
+        DynDaemonStore::is_trusted_client(&*self.0)
+    }
+
+    fn experimental_features(&self) -> ExperimentalFeatures {
+        DynDaemonStore::experimental_features(&*self.0)
+    }
+
+    async fn set_options(&mut self) -> Result<(), Error> {
+        DynDaemonStore::set_options(&mut *self.0).await
+    }
+
+    async fn is_valid_path(&mut self, path: &StorePath) -> Result<bool, Error> {
+        DynDaemonStore::is_valid_path(&mut *self.0, path).await
+    }
+
+    async fn add_multiple_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        source: R,
+        repair: RepairFlag,
+        check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error> {
+        let source = DynReader::new(source);
+        DynDaemonStore::add_multiple_to_store(&mut *self.0, source, repair, check_sigs).await
+    }
+
+    async fn query_missing(
+        &mut self,
+        targets: &[DerivedPath],
+    ) -> Result<QueryMissingResult, Error> {
+        DynDaemonStore::query_missing(&mut *self.0, targets).await
+    }
+
+    async fn add_build_log(&mut self, drv_path: &StorePath, log: &str) -> Result<(), Error> {
+        DynDaemonStore::add_build_log(&mut *self.0, drv_path, log).await
+    }
+
+    async fn repair_path(&mut self, path: &StorePath) -> Result<(), Error> {
+        DynDaemonStore::repair_path(&mut *self.0, path).await
+    }
+
+    async fn collect_garbage(&mut self, options: &GCOptions) -> Result<GCResults, Error> {
+        DynDaemonStore::collect_garbage(&mut *self.0, options).await
+    }
+
+    async fn query_realisation(&mut self, id: &DrvOutput) -> Result<Option<Realisation>, Error> {
+        DynDaemonStore::query_realisation(&mut *self.0, id).await
+    }
+
+    async fn register_drv_output(&mut self, realisation: &Realisation) -> Result<(), Error> {
+        DynDaemonStore::register_drv_output(&mut *self.0, realisation).await
+    }
+
+    async fn add_indirect_root(&mut self, path: &DaemonPath) -> Result<(), Error> {
+        DynDaemonStore::add_indirect_root(&mut *self.0, path).await
+    }
+
+    async fn find_roots(&mut self) -> Result<BTreeMap<DaemonPath, StorePath>, Error> {
+        DynDaemonStore::find_roots(&mut *self.0).await
+    }
+
+    async fn verify_store(
+        &mut self,
+        check_contents: bool,
+        repair: RepairFlag,
+    ) -> Result<bool, Error> {
+        DynDaemonStore::verify_store(&mut *self.0, check_contents, repair).await
+    }
+
+    async fn optimise_store(&mut self) -> Result<(), Error> {
+        DynDaemonStore::optimise_store(&mut *self.0).await
+    }
+
+    async fn collect_garbage_streaming(
+        &mut self,
+        options: &GCOptions,
+        act: &Activity,
+    ) -> Result<GCResults, Error> {
+        DynDaemonStore::collect_garbage_streaming(&mut *self.0, options, act).await
+    }
+
+    async fn substitute_paths(&mut self, paths: &StorePathSet) -> Result<(), Error> {
+        DynDaemonStore::substitute_paths(&mut *self.0, paths).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Not `super::*`: with `DynDaemonStore` in scope the method calls would
+    // be ambiguous.
+    use super::{BoxedDaemonStore, DaemonStore, TrustedFlag};
+    use crate::hash;
+    use crate::path_info::ValidPathInfo;
+    use crate::store::{CheckSignaturesFlag, MemoryStore, RepairFlag, Store};
+    use crate::store_path::StorePath;
+
+    #[tokio::test]
+    async fn test_boxed_round_trip() {
+        let mut store = BoxedDaemonStore::new(Box::new(MemoryStore::new()));
+        let path = StorePath::test_from_seed("foo");
+        let nar = b"nar".to_vec();
+        let mut info =
+            ValidPathInfo::new(path.clone(), hash::digest(hash::Algorithm::SHA256, &nar));
+        info.nar_size = nar.len() as u64;
+        store
+            .add_to_store(
+                &info,
+                &nar[..],
+                RepairFlag::NoRepair,
+                CheckSignaturesFlag::NoCheckSigs,
+            )
+            .await
+            .unwrap();
+
+        assert!(store.is_valid_path(&path).await.unwrap());
+        assert_eq!(store.is_trusted_client(), Some(TrustedFlag::Trusted));
+        let mut out = Vec::new();
+        store.nar_from_path(&path, &mut out).await.unwrap();
+        assert_eq!(out, nar);
+    }
+}
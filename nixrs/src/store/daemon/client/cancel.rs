@@ -0,0 +1,322 @@
+//! Cancelling operations of a [`DaemonStoreClient`].
+//!
+//! The daemon protocol has no message for aborting an operation. Like the
+//! Nix client does on `SIGINT`, we close the connection instead: the daemon
+//! notices the hang up, interrupts what it was doing for us and the
+//! connection is replaced with [`DaemonStoreClient::reconnect`].
+//!
+//! What the daemon has done by the time it notices depends on the
+//! operation:
+//!
+//! * Queries (`IsValidPath`, `QueryValidPaths`, `QueryPathInfo`,
+//!   `QueryMissing`) and `NarFromPath` don't change the store and are
+//!   always safe to abort.
+//! * `BuildPaths` and `BuildDerivation` interrupt the builds. Outputs that
+//!   were already registered stay valid and the rest are not registered.
+//! * `AddToStoreNar` and `AddMultipleToStore` get a truncated NAR and don't
+//!   register the path being added. Paths that were completely sent before
+//!   it may already be valid.
+//! * `CollectGarbage` stops, but paths it already deleted stay deleted.
+//! * `SetOptions` and `AddBuildLog` are small enough that they have
+//!   usually completed; there is no telling whether they took effect.
+use std::collections::BTreeMap;
+use std::fmt;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::sync::CancellationToken;
+
+use crate::path_info::ValidPathInfo;
+use crate::store::daemon::{
+    DaemonPath, DaemonStore, DaemonStoreClient, GCOptions, GCResults, QueryMissingResult,
+    TrustedFlag,
+};
+use crate::store::{
+    BasicDerivation, BuildMode, BuildResult, CheckSignaturesFlag, DerivedPath, DrvOutput, Error,
+    ExperimentalFeatures, Realisation, RepairFlag, Store, SubstituteFlag,
+};
+use crate::store_path::{StoreDir, StoreDirProvider, StorePath, StorePathSet};
+
+macro_rules! cancellable {
+    ($self:ident, $op:expr, $call:expr) => {{
+        let res = tokio::select! {
+            res = $call => Some(res),
+            _ = $self.token.cancelled() => None,
+        };
+        match res {
+            Some(res) => res,
+            None => {
+                $self.client.abort().await;
+                Err(Error::Cancelled($op.into()))
+            }
+        }
+    }};
+}
+
+/// A [`DaemonStoreClient`] whose operations fail with [`Error::Cancelled`]
+/// as soon as the [`CancellationToken`] is cancelled.
+///
+/// Cancelling an operation in flight closes the connection to the daemon
+/// with [`DaemonStoreClient::abort`]. See the [module docs](self) for what
+/// that leaves behind for each operation.
+#[derive(Debug)]
+pub struct CancellableClient<'a, R, W> {
+    client: &'a mut DaemonStoreClient<R, W>,
+    token: CancellationToken,
+}
+
+impl<'a, R, W> CancellableClient<'a, R, W> {
+    pub fn new(
+        client: &'a mut DaemonStoreClient<R, W>,
+        token: CancellationToken,
+    ) -> CancellableClient<'a, R, W> {
+        CancellableClient { client, token }
+    }
+
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+}
+
+impl<R, W> StoreDirProvider for CancellableClient<'_, R, W> {
+    fn store_dir(&self) -> StoreDir {
+        self.client.store_dir()
+    }
+}
+
+#[async_trait]
+impl<'a, R, W> Store for CancellableClient<'a, R, W>
+where
+    R: AsyncRead + fmt::Debug + Unpin + Send + 'static,
+    W: AsyncWrite + fmt::Debug + Unpin + Send + 'static,
+{
+    async fn query_valid_paths(
+        &mut self,
+        paths: &StorePathSet,
+        maybe_substitute: SubstituteFlag,
+    ) -> Result<StorePathSet, Error> {
+        cancellable!(
+            self,
+            "querying valid paths",
+            self.client.query_valid_paths(paths, maybe_substitute)
+        )
+    }
+
+    async fn query_path_info(&mut self, path: &StorePath) -> Result<Option<ValidPathInfo>, Error> {
+        cancellable!(
+            self,
+            "querying path info",
+            self.client.query_path_info(path)
+        )
+    }
+
+    async fn nar_from_path<SW: AsyncWrite + fmt::Debug + Send + Unpin>(
+        &mut self,
+        path: &StorePath,
+        sink: SW,
+    ) -> Result<(), Error> {
+        cancellable!(self, "fetching NAR", self.client.nar_from_path(path, sink))
+    }
+
+    async fn add_to_store<SR: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        info: &ValidPathInfo,
+        source: SR,
+        repair: RepairFlag,
+        check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error> {
+        cancellable!(
+            self,
+            "adding path",
+            self.client.add_to_store(info, source, repair, check_sigs)
+        )
+    }
+
+    async fn build_derivation(
+        &mut self,
+        drv_path: &StorePath,
+        drv: &BasicDerivation,
+        build_mode: BuildMode,
+    ) -> Result<BuildResult, Error> {
+        cancellable!(
+            self,
+            "building derivation",
+            self.client.build_derivation(drv_path, drv, build_mode)
+        )
+    }
+
+    async fn build_paths(
+        &mut self,
+        drv_paths: &[DerivedPath],
+        build_mode: BuildMode,
+    ) -> Result<(), Error> {
+        cancellable!(
+            self,
+            "building paths",
+            self.client.build_paths(drv_paths, build_mode)
+        )
+    }
+}
+
+#[async_trait]
+impl<'a, R, W> DaemonStore for CancellableClient<'a, R, W>
+where
+    R: AsyncRead + fmt::Debug + Unpin + Send + 'static,
+    W: AsyncWrite + fmt::Debug + Unpin + Send + 'static,
+{
+    fn is_trusted_client(&self) -> Option<TrustedFlag> {
+        self.client.is_trusted_client()
+    }
+
+    fn experimental_features(&self) -> ExperimentalFeatures {
+        self.client.experimental_features()
+    }
+
+    async fn set_options(&mut self) -> Result<(), Error> {
+        cancellable!(self, "setting options", self.client.set_options())
+    }
+
+    async fn is_valid_path(&mut self, path: &StorePath) -> Result<bool, Error> {
+        cancellable!(self, "querying valid path", self.client.is_valid_path(path))
+    }
+
+    async fn add_multiple_to_store<SR: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        source: SR,
+        repair: RepairFlag,
+        check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error> {
+        cancellable!(
+            self,
+            "adding paths",
+            self.client
+                .add_multiple_to_store(source, repair, check_sigs)
+        )
+    }
+
+    async fn query_missing(
+        &mut self,
+        targets: &[DerivedPath],
+    ) -> Result<QueryMissingResult, Error> {
+        cancellable!(
+            self,
+            "querying missing paths",
+            self.client.query_missing(targets)
+        )
+    }
+
+    async fn add_build_log(&mut self, drv_path: &StorePath, log: &str) -> Result<(), Error> {
+        cancellable!(
+            self,
+            "adding build log",
+            self.client.add_build_log(drv_path, log)
+        )
+    }
+
+    async fn collect_garbage(&mut self, options: &GCOptions) -> Result<GCResults, Error> {
+        cancellable!(
+            self,
+            "collecting garbage",
+            self.client.collect_garbage(options)
+        )
+    }
+
+    async fn query_realisation(&mut self, id: &DrvOutput) -> Result<Option<Realisation>, Error> {
+        cancellable!(
+            self,
+            "querying realisation",
+            self.client.query_realisation(id)
+        )
+    }
+
+    async fn register_drv_output(&mut self, realisation: &Realisation) -> Result<(), Error> {
+        cancellable!(
+            self,
+            "registering derivation output",
+            self.client.register_drv_output(realisation)
+        )
+    }
+
+    async fn add_indirect_root(&mut self, path: &DaemonPath) -> Result<(), Error> {
+        cancellable!(
+            self,
+            "adding indirect root",
+            self.client.add_indirect_root(path)
+        )
+    }
+
+    async fn find_roots(&mut self) -> Result<BTreeMap<DaemonPath, StorePath>, Error> {
+        cancellable!(self, "finding roots", self.client.find_roots())
+    }
+
+    async fn verify_store(
+        &mut self,
+        check_contents: bool,
+        repair: RepairFlag,
+    ) -> Result<bool, Error> {
+        cancellable!(
+            self,
+            "verifying store",
+            self.client.verify_store(check_contents, repair)
+        )
+    }
+
+    async fn optimise_store(&mut self) -> Result<(), Error> {
+        cancellable!(self, "optimising store", self.client.optimise_store())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use assert_matches::assert_matches;
+    use tokio::io::{AsyncWriteExt, DuplexStream, ReadHalf};
+
+    use super::*;
+    use crate::store::daemon::transcripts::server_hello;
+    use crate::store::daemon::STDERR_LAST;
+
+    /// A daemon that completes the handshake and `SetOptions` and then
+    /// never answers again.
+    async fn silent_daemon() -> (ReadHalf<DuplexStream>, DuplexStream) {
+        let (client, mut server) = tokio::io::duplex(64_000);
+        let mut reply = server_hello(35, b"nix.rs 1.2.3", 1);
+        reply.extend_from_slice(&STDERR_LAST.to_le_bytes());
+        server.write_all(&reply).await.unwrap();
+        let (read, _write) = tokio::io::split(client);
+        (read, server)
+    }
+
+    #[tokio::test]
+    async fn test_cancel_and_reconnect() {
+        let (read, _server) = silent_daemon().await;
+        let mut client = DaemonStoreClient::new(
+            StoreDir::default(),
+            "localhost".into(),
+            read,
+            tokio::io::sink(),
+        );
+        client.init_connection().await.unwrap();
+
+        let token = CancellationToken::new();
+        let cancel = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            cancel.cancel();
+        });
+        let path = StorePath::test_from_seed("foo");
+        let res = client.cancellable(token).query_path_info(&path).await;
+        assert_matches!(res, Err(Error::Cancelled(_)));
+        assert!(client.is_cancelled());
+        assert_matches!(
+            client.query_path_info(&path).await,
+            Err(Error::ConnectionCancelled)
+        );
+
+        let (read, _server) = silent_daemon().await;
+        client.reconnect(read, tokio::io::sink()).await.unwrap();
+        assert!(!client.is_cancelled());
+        assert_eq!(client.daemon_version().await.unwrap(), 1 << 8 | 35);
+    }
+}
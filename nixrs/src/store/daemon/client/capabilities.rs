@@ -0,0 +1,410 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::store::daemon::{get_protocol_major, get_protocol_minor, PROTOCOL_VERSION};
+use crate::store::Error;
+
+/// Inclusive range of protocol versions.
+///
+/// ```
+/// # use nixrs::store::daemon::ProtocolRange;
+/// let ours = ProtocolRange::new(1 << 8 | 10, 1 << 8 | 35);
+/// let theirs = ProtocolRange::new(1 << 8 | 21, 1 << 8 | 37);
+/// let common = ours.intersect(&theirs).unwrap();
+/// assert!(common.contains(1 << 8 | 30));
+/// assert_eq!(common.iter_minors().count(), 15);
+/// assert_eq!(common.to_string(), "1.21-1.35");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ProtocolRange {
+    min: u64,
+    max: u64,
+}
+
+impl ProtocolRange {
+    pub const fn new(min: u64, max: u64) -> ProtocolRange {
+        ProtocolRange { min, max }
+    }
+
+    /// Every version from `min` up to the one this crate implements.
+    pub const fn from_version(min: u64) -> ProtocolRange {
+        ProtocolRange::new(min, PROTOCOL_VERSION)
+    }
+
+    pub fn min(&self) -> u64 {
+        self.min
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min > self.max
+    }
+
+    pub fn contains(&self, version: u64) -> bool {
+        self.min <= version && version <= self.max
+    }
+
+    /// Versions in both ranges or `None` when they don't overlap.
+    pub fn intersect(&self, other: &ProtocolRange) -> Option<ProtocolRange> {
+        let range = ProtocolRange::new(self.min.max(other.min), self.max.min(other.max));
+        if range.is_empty() {
+            None
+        } else {
+            Some(range)
+        }
+    }
+
+    /// The minor versions in the range. Only minor versions of the major
+    /// version of `min` are returned.
+    pub fn iter_minors(&self) -> impl Iterator<Item = u64> {
+        let min = get_protocol_minor!(self.min);
+        let max = if get_protocol_major!(self.max) > get_protocol_major!(self.min) {
+            0xff
+        } else {
+            get_protocol_minor!(self.max)
+        };
+        let (min, max) = if self.is_empty() { (1, 0) } else { (min, max) };
+        min..=max
+    }
+
+    /// The full versions in the range, under the major version of `min`.
+    pub fn iter_versions(&self) -> impl Iterator<Item = u64> {
+        let major = self.min & 0xff00;
+        self.iter_minors().map(move |minor| major | minor)
+    }
+}
+
+impl fmt::Display for ProtocolRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{}-{}.{}",
+            get_protocol_major!(self.min),
+            get_protocol_minor!(self.min),
+            get_protocol_major!(self.max),
+            get_protocol_minor!(self.max)
+        )
+    }
+}
+
+/// Parts of the daemon protocol that were added after 1.10, the oldest
+/// version the client talks to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ProtocolFeature {
+    /// `QueryValidPaths` and setting overrides in `SetOptions`.
+    QueryValidPaths,
+    /// The build mode argument of `BuildPaths`.
+    BuildMode,
+    /// The validity flag in front of `QueryPathInfo` responses.
+    QueryPathInfoValidity,
+    /// `AddToStoreNar` instead of `ImportPaths`.
+    AddToStoreNar,
+    QueryMissing,
+    /// Streaming the NAR of `AddToStoreNar` through `STDERR_READ`.
+    AddToStoreNarStderrRead,
+    /// Streaming the NAR of `AddToStoreNar` as framed data.
+    AddToStoreNarFramed,
+    /// Substituting paths while handling `QueryValidPaths`.
+    SubstituteOnQuery,
+    /// Built outputs in `BuildDerivation` results.
+    BuiltOutputs,
+    /// Build times and determinism in `BuildDerivation` results.
+    BuildTimes,
+    /// Sending derived paths instead of store paths with outputs.
+    DerivedPaths,
+    AddMultipleToStore,
+    AddBuildLog,
+    /// The Nix version of the daemon in the handshake.
+    DaemonNixVersion,
+    /// `BuildPathsWithResults`.
+    BuildPathsWithResults,
+    /// `QueryRealisation` and `RegisterDrvOutput`.
+    Realisations,
+    /// Realisations with their signatures and dependencies instead of
+    /// just the output path.
+    FullRealisations,
+    /// Whether the client is trusted in the handshake.
+    TrustedFlag,
+    /// CPU times of the builder in build results.
+    CpuTimes,
+}
+
+impl ProtocolFeature {
+    /// The minor protocol version the feature was added in.
+    pub fn min_minor(&self) -> u64 {
+        use ProtocolFeature::*;
+        match self {
+            QueryValidPaths => 12,
+            BuildMode => 15,
+            QueryPathInfoValidity => 17,
+            AddToStoreNar => 18,
+            QueryMissing => 19,
+            AddToStoreNarStderrRead => 21,
+            AddToStoreNarFramed => 23,
+            SubstituteOnQuery => 27,
+            Realisations => 27,
+            BuiltOutputs => 28,
+            BuildTimes => 29,
+            DerivedPaths => 30,
+            FullRealisations => 31,
+            AddMultipleToStore => 32,
+            AddBuildLog => 32,
+            DaemonNixVersion => 33,
+            BuildPathsWithResults => 34,
+            TrustedFlag => 35,
+            CpuTimes => 37,
+        }
+    }
+
+    /// The protocol versions that support the feature.
+    pub fn range(&self) -> ProtocolRange {
+        ProtocolRange::from_version(1 << 8 | self.min_minor())
+    }
+
+    /// Name used to refer to the feature in configuration.
+    pub fn name(&self) -> &'static str {
+        use ProtocolFeature::*;
+        match self {
+            QueryValidPaths => "query-valid-paths",
+            BuildMode => "build-mode",
+            QueryPathInfoValidity => "query-path-info-validity",
+            AddToStoreNar => "add-to-store-nar",
+            QueryMissing => "query-missing",
+            AddToStoreNarStderrRead => "add-to-store-nar-stderr-read",
+            AddToStoreNarFramed => "framed-add-to-store",
+            SubstituteOnQuery => "substitute-on-query",
+            BuiltOutputs => "built-outputs",
+            BuildTimes => "build-times",
+            DerivedPaths => "derived-paths",
+            Realisations => "realisations",
+            FullRealisations => "full-realisations",
+            AddMultipleToStore => "add-multiple-to-store",
+            AddBuildLog => "add-build-log",
+            DaemonNixVersion => "daemon-nix-version",
+            BuildPathsWithResults => "build-paths-with-results",
+            TrustedFlag => "trusted-flag",
+            CpuTimes => "cpu-times",
+        }
+    }
+
+    pub fn all() -> &'static [ProtocolFeature] {
+        use ProtocolFeature::*;
+        &[
+            QueryValidPaths,
+            BuildMode,
+            QueryPathInfoValidity,
+            AddToStoreNar,
+            QueryMissing,
+            AddToStoreNarStderrRead,
+            AddToStoreNarFramed,
+            SubstituteOnQuery,
+            BuiltOutputs,
+            BuildTimes,
+            DerivedPaths,
+            Realisations,
+            FullRealisations,
+            AddMultipleToStore,
+            AddBuildLog,
+            DaemonNixVersion,
+            BuildPathsWithResults,
+            TrustedFlag,
+            CpuTimes,
+        ]
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq, Clone)]
+#[error("unknown protocol feature '{0}'")]
+pub struct ParseProtocolFeatureError(String);
+
+impl FromStr for ProtocolFeature {
+    type Err = ParseProtocolFeatureError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ProtocolFeature::all()
+            .iter()
+            .find(|feature| feature.name() == s)
+            .copied()
+            .ok_or_else(|| ParseProtocolFeatureError(s.into()))
+    }
+}
+
+impl fmt::Display for ProtocolFeature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ProtocolFeature::*;
+        let name = match self {
+            QueryValidPaths => "querying valid paths",
+            BuildMode => "build modes",
+            QueryPathInfoValidity => "path info validity",
+            AddToStoreNar => "adding NARs",
+            QueryMissing => "querying missing paths",
+            AddToStoreNarStderrRead => "streaming NARs",
+            AddToStoreNarFramed => "framed NARs",
+            SubstituteOnQuery => "substituting while querying valid paths",
+            BuiltOutputs => "built outputs",
+            BuildTimes => "build times",
+            DerivedPaths => "derived paths",
+            Realisations => "realisations",
+            FullRealisations => "realisations with signatures",
+            AddMultipleToStore => "adding multiple paths",
+            AddBuildLog => "adding build logs",
+            DaemonNixVersion => "daemon version",
+            BuildPathsWithResults => "build results per path",
+            TrustedFlag => "trust status",
+            CpuTimes => "build CPU times",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Which protocol versions each [`ProtocolFeature`] is used with.
+///
+/// By default a feature is used with every version since it was added.
+/// Features can be restricted further, for example to work around a daemon
+/// that is known to get one wrong.
+///
+/// ```
+/// # use nixrs::store::daemon::{FeatureGate, ProtocolFeature, ProtocolRange};
+/// let mut gate = FeatureGate::new();
+/// assert!(gate.supports(ProtocolFeature::BuiltOutputs, 1 << 8 | 28));
+/// gate.disable(ProtocolFeature::BuiltOutputs);
+/// assert!(!gate.supports(ProtocolFeature::BuiltOutputs, 1 << 8 | 28));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FeatureGate {
+    restricted: BTreeMap<ProtocolFeature, Option<ProtocolRange>>,
+}
+
+impl FeatureGate {
+    pub fn new() -> FeatureGate {
+        Default::default()
+    }
+
+    /// Only use `feature` with versions in both `range` and
+    /// [`ProtocolFeature::range`].
+    pub fn restrict(&mut self, feature: ProtocolFeature, range: ProtocolRange) -> &mut Self {
+        self.restricted
+            .insert(feature, feature.range().intersect(&range));
+        self
+    }
+
+    /// Never use `feature`.
+    pub fn disable(&mut self, feature: ProtocolFeature) -> &mut Self {
+        self.restricted.insert(feature, None);
+        self
+    }
+
+    pub fn range(&self, feature: ProtocolFeature) -> Option<ProtocolRange> {
+        match self.restricted.get(&feature) {
+            Some(range) => *range,
+            None => Some(feature.range()),
+        }
+    }
+
+    pub fn supports(&self, feature: ProtocolFeature, version: u64) -> bool {
+        self.range(feature)
+            .map(|range| range.contains(version))
+            .unwrap_or(false)
+    }
+}
+
+/// What the negotiated protocol version supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DaemonCapabilities {
+    version: u64,
+}
+
+impl DaemonCapabilities {
+    pub fn new(version: u64) -> DaemonCapabilities {
+        DaemonCapabilities { version }
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn supports(&self, feature: ProtocolFeature) -> bool {
+        get_protocol_minor!(self.version) >= feature.min_minor()
+    }
+
+    /// Fail with [`Error::UnsupportedFeature`] unless `feature` is
+    /// supported.
+    pub fn require(&self, feature: ProtocolFeature) -> Result<(), Error> {
+        if self.supports(feature) {
+            Ok(())
+        } else {
+            Err(Error::UnsupportedFeature(
+                feature,
+                get_protocol_major!(self.version),
+                get_protocol_minor!(self.version),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use super::*;
+
+    #[test]
+    fn test_require() {
+        let caps = DaemonCapabilities::new(1 << 8 | 26);
+        assert!(caps.supports(ProtocolFeature::QueryMissing));
+        assert!(caps.require(ProtocolFeature::QueryMissing).is_ok());
+        assert_matches!(
+            caps.require(ProtocolFeature::SubstituteOnQuery),
+            Err(Error::UnsupportedFeature(
+                ProtocolFeature::SubstituteOnQuery,
+                1,
+                26
+            ))
+        );
+    }
+
+    #[test]
+    fn test_protocol_range() {
+        let range = ProtocolRange::new(1 << 8 | 21, 1 << 8 | 25);
+        assert!(range.contains(1 << 8 | 21));
+        assert!(range.contains(1 << 8 | 25));
+        assert!(!range.contains(1 << 8 | 26));
+        assert_eq!(
+            range.iter_minors().collect::<Vec<_>>(),
+            [21, 22, 23, 24, 25]
+        );
+        assert_eq!(range.iter_versions().next(), Some(1 << 8 | 21));
+        let other = ProtocolRange::new(1 << 8 | 26, 1 << 8 | 30);
+        assert_eq!(range.intersect(&other), None);
+        assert_eq!(ProtocolRange::new(2, 1).iter_minors().count(), 0);
+    }
+
+    #[test]
+    fn test_feature_gate() {
+        let mut gate = FeatureGate::new();
+        let feature: ProtocolFeature = "framed-add-to-store".parse().unwrap();
+        assert_eq!(feature, ProtocolFeature::AddToStoreNarFramed);
+        assert!(!gate.supports(feature, 1 << 8 | 22));
+        assert!(gate.supports(feature, 1 << 8 | 23));
+        gate.restrict(feature, ProtocolRange::new(1 << 8 | 10, 1 << 8 | 30));
+        assert!(gate.supports(feature, 1 << 8 | 30));
+        assert!(!gate.supports(feature, 1 << 8 | 31));
+        assert!(!gate.supports(feature, 1 << 8 | 22));
+        assert_eq!(
+            "nonsense".parse::<ProtocolFeature>(),
+            Err(ParseProtocolFeatureError("nonsense".into()))
+        );
+        for feature in ProtocolFeature::all() {
+            assert_eq!(
+                feature.name().parse::<ProtocolFeature>().as_ref(),
+                Ok(feature)
+            );
+        }
+    }
+}
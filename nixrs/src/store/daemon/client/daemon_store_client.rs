@@ -1,29 +1,41 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
 use std::fmt;
+use std::time::Instant;
 
 use async_trait::async_trait;
 use futures::TryFutureExt;
 use tokio::io::{copy, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tracing::{debug, instrument};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, field, instrument, Span};
 
+use super::cancel::CancellableClient;
+use super::capabilities::{DaemonCapabilities, ProtocolFeature};
+use super::handshake::HandshakeInfo;
+use super::nar_download::NarDownload;
 use super::process_stderr::ProcessStderr;
 use crate::archive::copy_nar;
 use crate::io::FramedSink;
-use crate::io::{AsyncSink, AsyncSource};
+use crate::io::{
+    zstd_switch, AsyncSink, AsyncSource, ZstdSwitch, ZstdSwitchReader, ZstdSwitchWriter,
+};
 use crate::path_info::ValidPathInfo;
 use crate::store::activity::ActivityLogger;
 use crate::store::daemon::{
-    get_protocol_major, get_protocol_minor, DaemonStore, QueryMissingResult, TrustedFlag,
-    WorkerProtoOp, PROTOCOL_VERSION, WORKER_MAGIC_1, WORKER_MAGIC_2,
+    get_protocol_major, get_protocol_minor, read_opt_micros, DaemonPath, DaemonStore, GCOptions,
+    GCResults, QueryMissingResult, TrustLevel, TrustedFlag, WorkerProtoOp, CENSORED_ROOT,
+    COMPRESSION_MARKER, PROTOCOL_VERSION, WORKER_MAGIC_1, WORKER_MAGIC_2,
 };
 use crate::store::error::Verbosity;
 use crate::store::misc::add_multiple_to_store_old;
 use crate::store::settings::get_settings;
 use crate::store::{
-    BasicDerivation, BuildMode, BuildResult, BuildStatus, CheckSignaturesFlag, DerivedPath, Error,
-    RepairFlag, SPWOParseResult, Store, SubstituteFlag, EXPORT_MAGIC,
+    build_paths_with_results_emulated, BasicDerivation, BuildMode, BuildResult, BuildResults,
+    BuildStatus, CheckSignaturesFlag, DerivedPath, DrvOutput, Error, ExperimentalFeatures,
+    KeyedBuildResult, Realisation, RepairFlag, SPWOParseResult, Store, SubstituteFlag,
+    EXPORT_MAGIC,
 };
-use crate::store_path::{StoreDir, StoreDirProvider, StorePath, StorePathSet};
+use crate::store_path::{ContentAddress, StoreDir, StoreDirProvider, StorePath, StorePathSet};
+use crate::StringSet;
 
 macro_rules! with_framed_sink {
     ($store:expr, |$sink:ident| $handle:block) => {
@@ -58,35 +70,249 @@ macro_rules! with_framed_sink {
     };
 }
 
+/// Record the negotiated protocol version on the span of the current
+/// operation.
+///
+/// Every operation span carries `op` with the name of the worker op,
+/// `protocol` and, with the `remote-activity-ids` feature,
+/// `remote_activity` with the id of the top-level activity the daemon
+/// started for it.
+fn record_protocol(daemon_version: u64) {
+    let major = get_protocol_major!(daemon_version);
+    let minor = get_protocol_minor!(daemon_version);
+    Span::current().record("protocol", format!("{}.{}", major, minor).as_str());
+    debug!(
+        daemon_version,
+        daemon.major = major,
+        daemon.minor = minor,
+        "Daemon version {}.{}",
+        major,
+        minor
+    );
+}
+
+/// Answers the daemon sent us that only change when they are deleted, like
+/// infos of valid paths and realisations. Once full the oldest entry makes
+/// room.
+#[derive(Debug)]
+struct DaemonCache<K, V> {
+    capacity: usize,
+    entries: BTreeMap<K, V>,
+    order: VecDeque<K>,
+}
+
+impl<K: Ord + Clone, V> DaemonCache<K, V> {
+    fn new(capacity: usize) -> DaemonCache<K, V> {
+        DaemonCache {
+            capacity,
+            entries: BTreeMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key)
+    }
+
+    fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.insert(key.clone(), value).is_none() {
+            self.order.push_back(key);
+            if self.order.len() > self.capacity {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.entries.remove(&oldest);
+                }
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &K) {
+        if self.entries.remove(key).is_some() {
+            self.order.retain(|k| k != key);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
+
+/// Configures how a [`DaemonStoreClient`] connects to a daemon.
+///
+/// The defaults match a regular Nix client. The protocol range and the
+/// obsolete handshake fields are only meant to be changed when testing
+/// against older daemons.
+#[derive(Debug, Clone)]
+pub struct DaemonStoreBuilder {
+    store_dir: StoreDir,
+    host: String,
+    min_version: u64,
+    max_version: u64,
+    obsolete_fields: bool,
+    path_info_cache_size: usize,
+    realisation_cache_size: usize,
+    compression: bool,
+}
+
+impl Default for DaemonStoreBuilder {
+    fn default() -> Self {
+        DaemonStoreBuilder {
+            store_dir: StoreDir::default(),
+            host: "localhost".into(),
+            min_version: 1 << 8 | 10,
+            max_version: PROTOCOL_VERSION,
+            obsolete_fields: true,
+            path_info_cache_size: 0,
+            realisation_cache_size: 1024,
+            compression: false,
+        }
+    }
+}
+
+impl DaemonStoreBuilder {
+    pub fn new() -> DaemonStoreBuilder {
+        Default::default()
+    }
+
+    pub fn host<H: Into<String>>(&mut self, host: H) -> &mut Self {
+        self.host = host.into();
+        self
+    }
+
+    pub fn store_dir(&mut self, store_dir: StoreDir) -> &mut Self {
+        self.store_dir = store_dir;
+        self
+    }
+
+    /// Refuse daemons older than `version`. Versions older than 1.10 are
+    /// never supported.
+    pub fn min_version(&mut self, version: u64) -> &mut Self {
+        self.min_version = version;
+        self
+    }
+
+    /// The protocol version announced to the daemon. Versions newer than
+    /// the one this client implements are capped to it.
+    pub fn max_version(&mut self, version: u64) -> &mut Self {
+        self.max_version = version.min(PROTOCOL_VERSION);
+        self
+    }
+
+    /// Whether to send the obsolete CPU affinity and reserve space fields
+    /// in the handshake. Real daemons expect them so this should only be
+    /// turned off in tests.
+    pub fn obsolete_fields(&mut self, send: bool) -> &mut Self {
+        self.obsolete_fields = send;
+        self
+    }
+
+    /// Keep the infos of up to `size` valid paths so asking for them again
+    /// doesn't go to the daemon. Off by default.
+    pub fn path_info_cache_size(&mut self, size: usize) -> &mut Self {
+        self.path_info_cache_size = size;
+        self
+    }
+
+    /// Keep up to `size` realisations the daemon returned so asking for
+    /// them again doesn't go to the daemon. Defaults to 1024.
+    pub fn realisation_cache_size(&mut self, size: usize) -> &mut Self {
+        self.realisation_cache_size = size;
+        self
+    }
+
+    /// Ask nixrs daemons that offer it with
+    /// [`DaemonServerBuilder::compression`](crate::store::daemon::DaemonServerBuilder::compression)
+    /// to compress the connection with zstd after the handshake. Other
+    /// daemons are talked to with the plain protocol. Off by default.
+    pub fn compression(&mut self, enable: bool) -> &mut Self {
+        self.compression = enable;
+        self
+    }
+
+    pub fn build<R, W>(&self, reader: R, writer: W) -> DaemonStoreClient<R, W>
+    where
+        R: AsyncRead + fmt::Debug + Unpin + Send + 'static,
+        W: AsyncWrite + fmt::Debug + Unpin + Send + 'static,
+    {
+        let (source, sink, switch) = zstd_switch(reader, writer);
+        DaemonStoreClient {
+            host: self.host.clone(),
+            store_dir: self.store_dir.clone(),
+            source,
+            sink,
+            compression: self.compression,
+            switch,
+            min_version: self.min_version,
+            max_version: self.max_version,
+            obsolete_fields: self.obsolete_fields,
+            daemon_version: None,
+            daemon_nix_version: None,
+            remote_trusts_us: None,
+            handshake_info: None,
+            realisations: DaemonCache::new(self.realisation_cache_size),
+            path_infos: DaemonCache::new(self.path_info_cache_size),
+            cancelled: false,
+            logger: ActivityLogger::new(),
+        }
+    }
+
+    pub async fn connect<R, W>(
+        &self,
+        reader: R,
+        writer: W,
+    ) -> Result<DaemonStoreClient<R, W>, Error>
+    where
+        R: AsyncRead + fmt::Debug + Unpin + Send + 'static,
+        W: AsyncWrite + fmt::Debug + Unpin + Send + 'static,
+    {
+        let mut store = self.build(reader, writer);
+        store.init_connection().await?;
+        Ok(store)
+    }
+}
+
 #[derive(Debug)]
 pub struct DaemonStoreClient<R, W> {
     host: String,
     store_dir: StoreDir,
-    source: R,
-    sink: W,
+    source: ZstdSwitchReader<R>,
+    sink: ZstdSwitchWriter<W>,
+    compression: bool,
+    switch: ZstdSwitch,
+    min_version: u64,
+    max_version: u64,
+    obsolete_fields: bool,
     daemon_version: Option<u64>,
     daemon_nix_version: Option<String>,
     remote_trusts_us: Option<TrustedFlag>,
+    handshake_info: Option<HandshakeInfo>,
+    /// Realisations the daemon returned. They never change once
+    /// registered, apart from gaining signatures.
+    realisations: DaemonCache<DrvOutput, Realisation>,
+    path_infos: DaemonCache<StorePath, ValidPathInfo>,
+    cancelled: bool,
     logger: ActivityLogger,
 }
 
+impl DaemonStoreClient<(), ()> {
+    pub fn builder() -> DaemonStoreBuilder {
+        DaemonStoreBuilder::new()
+    }
+}
+
 impl<R, W> DaemonStoreClient<R, W>
 where
     R: AsyncRead + fmt::Debug + Unpin + Send + 'static,
     W: AsyncWrite + fmt::Debug + Unpin + Send + 'static,
 {
     pub fn new(store_dir: StoreDir, host: String, reader: R, writer: W) -> Self {
-        let sink = writer;
-        Self {
-            store_dir,
-            source: reader,
-            sink,
-            daemon_version: None,
-            daemon_nix_version: None,
-            remote_trusts_us: None,
-            host,
-            logger: ActivityLogger::new(),
-        }
+        DaemonStoreBuilder::new()
+            .store_dir(store_dir)
+            .host(host)
+            .build(reader, writer)
     }
 
     #[instrument(skip(store_dir, reader, writer))]
@@ -100,12 +326,47 @@ where
         store.init_connection().await?;
         Ok(store)
     }
+    /// The protocol version negotiated with the daemon, which is the older
+    /// of the daemon's version and the one we announced.
     pub async fn daemon_version(&mut self) -> Result<u64, Error> {
+        if self.cancelled {
+            return Err(Error::ConnectionCancelled);
+        }
         if self.daemon_version.is_none() {
             self.init_connection().await?;
         }
         Ok(*self.daemon_version.as_ref().unwrap())
     }
+
+    /// The protocol features supported by the negotiated version.
+    pub async fn capabilities(&mut self) -> Result<DaemonCapabilities, Error> {
+        Ok(DaemonCapabilities::new(self.daemon_version().await?))
+    }
+
+    /// The Nix version reported by the daemon. Only available after
+    /// connecting to daemons speaking protocol 1.33 or newer.
+    pub fn daemon_nix_version(&self) -> Option<&str> {
+        self.daemon_nix_version.as_deref()
+    }
+
+    /// Whether the daemon trusts us. Only available after connecting to
+    /// daemons speaking protocol 1.35 or newer.
+    pub fn remote_trusts_us(&self) -> Option<TrustedFlag> {
+        self.remote_trusts_us
+    }
+
+    /// Everything the daemon told us in the handshake. Only available once
+    /// connected.
+    pub fn handshake_info(&self) -> Option<&HandshakeInfo> {
+        self.handshake_info.as_ref()
+    }
+
+    /// Whether the connection is compressed with zstd. See
+    /// [`DaemonStoreBuilder::compression`].
+    pub fn is_compressed(&self) -> bool {
+        self.switch.is_enabled()
+    }
+
     pub async fn init_connection(&mut self) -> Result<(), Error> {
         if self.daemon_version.is_some() {
             return Ok(());
@@ -120,8 +381,9 @@ where
         Ok(())
     }
 
-    #[instrument(skip(self))]
+    #[instrument(skip(self), fields(protocol = field::Empty, daemon.nix_version = field::Empty, trusted = field::Empty))]
     async fn handshake(&mut self) -> Result<(), Error> {
+        let start = Instant::now();
         // Send the magic greeting, check for the reply.
         self.sink.write_u64_le(WORKER_MAGIC_1).await?;
         self.sink.flush().await?;
@@ -131,44 +393,85 @@ where
             return Err(Error::DaemonProtocolMismatch);
         }
 
-        let daemon_version = self.source.read_u64_le().await?;
+        let remote_version = self.source.read_u64_le().await?;
+        let daemon_version = remote_version.min(self.max_version);
         self.daemon_version = Some(daemon_version);
-        if get_protocol_major!(daemon_version) != get_protocol_major!(PROTOCOL_VERSION) {
+        if get_protocol_major!(remote_version) != get_protocol_major!(PROTOCOL_VERSION) {
             return Err(Error::UnsupportedDaemonProtocol);
         }
-        if get_protocol_minor!(daemon_version) < 10 {
+        if get_protocol_minor!(remote_version) < 10 || remote_version < self.min_version {
             return Err(Error::DaemonVersionTooOld);
         }
-        self.sink.write_u64_le(PROTOCOL_VERSION).await?;
+        self.sink.write_u64_le(self.max_version).await?;
 
-        if get_protocol_minor!(daemon_version) >= 14 {
+        if get_protocol_minor!(daemon_version) >= 14 && self.obsolete_fields {
             // Obsolete CPU affinity.
             self.sink.write_u64_le(0).await?;
         }
 
-        if get_protocol_minor!(daemon_version) >= 11 {
+        if get_protocol_minor!(daemon_version) >= 11 && self.obsolete_fields {
             // obsolete reserveSpace
             self.sink.write_bool(false).await?;
         }
 
-        if get_protocol_minor!(daemon_version) >= 33 {
+        let caps = DaemonCapabilities::new(daemon_version);
+        if caps.supports(ProtocolFeature::DaemonNixVersion) {
             self.sink.flush().await?;
             let daemon_nix_version = self.source.read_string().await?;
             self.daemon_nix_version = Some(daemon_nix_version);
         }
 
-        if get_protocol_minor!(daemon_version) >= 35 {
+        if caps.supports(ProtocolFeature::TrustedFlag) {
             let temp = self.source.read_u64_le().await?;
-            self.remote_trusts_us = match temp {
-                0 => None,
-                1 => Some(TrustedFlag::Trusted),
-                2 => Some(TrustedFlag::NotTrusted),
-                _ => return Err(Error::InvalidTrustedStatus),
-            };
+            let level = TrustLevel::try_from(temp).map_err(|_| Error::InvalidTrustedStatus)?;
+            self.remote_trusts_us = level.into();
         }
 
         self.process_stderr().await?;
 
+        let info = HandshakeInfo {
+            daemon_version: remote_version,
+            protocol_version: daemon_version,
+            nix_version: self.daemon_nix_version.clone(),
+            trusted: self.remote_trusts_us,
+            duration: start.elapsed(),
+        };
+        let span = Span::current();
+        span.record("protocol", info.protocol().as_str());
+        if let Some(version) = info.nix_version.as_ref() {
+            span.record("daemon.nix_version", version.as_str());
+        }
+        if let Some(trusted) = info.trusted {
+            span.record("trusted", trusted == TrustedFlag::Trusted);
+        }
+        debug!("{} on {} in {:?}", info, self.host, info.duration);
+        self.handshake_info = Some(info);
+
+        let offered = self
+            .daemon_nix_version
+            .as_deref()
+            .map(|version| version.starts_with("nix.rs") && version.ends_with(COMPRESSION_MARKER))
+            .unwrap_or(false);
+        if self.compression && offered {
+            self.negotiate_compression().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Ask the daemon to compress the rest of the connection. Both ends
+    /// switch once the reply is read.
+    async fn negotiate_compression(&mut self) -> Result<(), Error> {
+        self.sink
+            .write_enum(WorkerProtoOp::NixrsCompression)
+            .await?;
+        self.sink.write_str("zstd").await?;
+        self.process_stderr().await?;
+        let accepted = self.source.read_bool().await?;
+        if accepted {
+            debug!("compressing connection to {} with zstd", self.host);
+            self.switch.enable();
+        }
         Ok(())
     }
 
@@ -177,6 +480,71 @@ where
         Ok(())
     }
 
+    /// Start sending the NAR of `path` and hand the connection over to the
+    /// returned [`NarDownload`].
+    ///
+    /// Unlike [`nar_from_path`](Store::nar_from_path) this doesn't keep the
+    /// client borrowed until the whole NAR was written to a sink, so a copy
+    /// can go on with queries on other connections while the NAR drains.
+    /// The connection comes back from [`NarDownload::finish`].
+    #[instrument(skip_all, fields(op = "NarFromPath", %path, protocol = field::Empty, remote_activity = field::Empty))]
+    pub async fn download_nar(mut self, path: &StorePath) -> Result<NarDownload<R, W>, Error> {
+        let daemon_version = self.daemon_version().await?;
+        record_protocol(daemon_version);
+        let store_dir = self.store_dir.clone();
+        self.sink.write_enum(WorkerProtoOp::NarFromPath).await?;
+        self.sink.write_printed(&store_dir, path).await?;
+        self.process_stderr().await?;
+        let (reader, writer) = tokio::io::duplex(65_000);
+        let transfer = async move {
+            let res = copy_nar(&mut self.source, writer)
+                .await
+                .map_err(Error::from);
+            (self, res)
+        };
+        Ok(NarDownload::new(reader, Box::pin(transfer)))
+    }
+
+    /// Whether an operation was cancelled and the connection must be
+    /// replaced with [`reconnect`](Self::reconnect) before the next one.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled
+    }
+
+    /// Give up on the operation in flight by closing our end of the
+    /// connection. The daemon protocol has no way to abort an operation so
+    /// this is what makes the daemon stop working on it.
+    ///
+    /// Every operation fails with [`Error::ConnectionCancelled`] until
+    /// [`reconnect`](Self::reconnect) is called.
+    pub async fn abort(&mut self) {
+        self.cancelled = true;
+        if let Err(err) = self.sink.shutdown().await {
+            debug!("Error closing cancelled connection: {}", err);
+        }
+    }
+
+    /// Replace the connection with a new one to the same daemon and do the
+    /// handshake again, for instance after [`abort`](Self::abort).
+    pub async fn reconnect(&mut self, reader: R, writer: W) -> Result<(), Error> {
+        let (source, sink, switch) = zstd_switch(reader, writer);
+        self.source = source;
+        self.sink = sink;
+        self.switch = switch;
+        self.daemon_version = None;
+        self.daemon_nix_version = None;
+        self.remote_trusts_us = None;
+        self.handshake_info = None;
+        self.cancelled = false;
+        self.init_connection().await
+    }
+
+    /// Run the following operations on this client until `token` is
+    /// cancelled. See [`CancellableClient`] for what cancellation does.
+    pub fn cancellable(&mut self, token: CancellationToken) -> CancellableClient<'_, R, W> {
+        CancellableClient::new(self, token)
+    }
+
     async fn process_stderr(&mut self) -> Result<(), Error> {
         self.sink.flush().await?;
         ProcessStderr::new(
@@ -203,10 +571,71 @@ where
         .await
     }
 
+    /// Build `drv_paths` and get a result for each of them.
+    ///
+    /// Daemons older than protocol 1.34 can't report results, so there the
+    /// paths are built with `BuildPaths` and the results are worked out
+    /// with [`build_paths_with_results_emulated`], which marks them as
+    /// emulated.
+    #[instrument(skip_all, fields(op = "BuildPathsWithResults", drv_paths = drv_paths.len(), ?build_mode, protocol = field::Empty, remote_activity = field::Empty))]
+    pub async fn build_paths_with_results(
+        &mut self,
+        drv_paths: &[DerivedPath],
+        build_mode: BuildMode,
+    ) -> Result<BuildResults, Error> {
+        let store_dir = self.store_dir.clone();
+        let daemon_version = self.daemon_version().await?;
+        record_protocol(daemon_version);
+        if !DaemonCapabilities::new(daemon_version).supports(ProtocolFeature::BuildPathsWithResults)
+        {
+            return build_paths_with_results_emulated(self, drv_paths, build_mode).await;
+        }
+        self.sink
+            .write_enum(WorkerProtoOp::BuildPathsWithResults)
+            .await?;
+        self.write_derived_paths(drv_paths).await?;
+        self.sink.write_enum(build_mode).await?;
+        self.process_stderr().await?;
+        let count = self.source.read_usize().await?;
+        let mut results = BuildResults::new();
+        for _ in 0..count {
+            let path: DerivedPath = self.source.read_parsed(&store_dir).await?;
+            let result = self.read_build_result(daemon_version).await?;
+            results.push(KeyedBuildResult::new(path, result));
+        }
+        Ok(results)
+    }
+
+    async fn read_build_result(&mut self, daemon_version: u64) -> Result<BuildResult, Error> {
+        let status: BuildStatus = self.source.read_enum().await?;
+        let error_msg = self.source.read_string().await?;
+        let mut status = BuildResult::new(status, error_msg);
+        let caps = DaemonCapabilities::new(daemon_version);
+        if caps.supports(ProtocolFeature::BuildTimes) {
+            status.times_built = self.source.read_u64_le().await?;
+            status.is_non_deterministic = self.source.read_bool().await?;
+            status.start_time = self.source.read_time().await?;
+            status.stop_time = self.source.read_time().await?;
+        }
+        if caps.supports(ProtocolFeature::CpuTimes) {
+            status.cpu_user = read_opt_micros(&mut self.source).await?;
+            status.cpu_system = read_opt_micros(&mut self.source).await?;
+        }
+        if caps.supports(ProtocolFeature::BuiltOutputs) {
+            let count = self.source.read_usize().await?;
+            for _i in 0..count {
+                let id = self.source.read_string().await?.parse()?;
+                let realisation = self.source.read_string().await?.parse()?;
+                status.built_outputs.insert(id, realisation);
+            }
+        }
+        Ok(status)
+    }
+
     async fn write_derived_paths(&mut self, reqs: &[DerivedPath]) -> Result<(), Error> {
         let store_dir = self.store_dir();
         let daemon_version = self.daemon_version.unwrap();
-        if get_protocol_minor!(daemon_version) >= 30 {
+        if DaemonCapabilities::new(daemon_version).supports(ProtocolFeature::DerivedPaths) {
             self.sink.write_printed_coll(&store_dir, reqs).await?;
         } else {
             self.sink.write_usize(reqs.len()).await?;
@@ -244,9 +673,16 @@ where
         self.remote_trusts_us
     }
 
-    #[instrument(skip(self))]
+    /// The protocol doesn't tell which features the daemon has turned on,
+    /// so every feature is assumed to be and the daemon checks for itself.
+    fn experimental_features(&self) -> ExperimentalFeatures {
+        ExperimentalFeatures::all()
+    }
+
+    #[instrument(skip_all, fields(op = "SetOptions", protocol = field::Empty, remote_activity = field::Empty))]
     async fn set_options(&mut self) -> Result<(), Error> {
         let daemon_version = self.daemon_version().await?;
+        record_protocol(daemon_version);
 
         let (
             keep_failed,
@@ -290,7 +726,7 @@ where
         self.sink.write_u64_le(build_cores).await?;
         self.sink.write_bool(use_substitutes).await?;
 
-        if get_protocol_minor!(daemon_version) >= 12 {
+        if DaemonCapabilities::new(daemon_version).supports(ProtocolFeature::QueryValidPaths) {
             let mut overrides = BTreeMap::new();
             get_settings(|settings| {
                 settings.get_all(&mut overrides);
@@ -302,11 +738,18 @@ where
             overrides.remove("max-silent-time");
             overrides.remove("cores"); // build_cores
             overrides.remove("substitute"); // use_substitutes
-                                            /*
-                                            overrides.erase(loggerSettings.showTrace.name);
-                                            overrides.erase(experimentalFeatureSettings.experimentalFeatures.name);
-                                            overrides.erase(settings.pluginFiles.name);
-                                             */
+            if overrides
+                .get("sandbox-paths")
+                .is_some_and(|paths| paths.is_empty())
+            {
+                // Don't clear the sandbox paths the daemon has configured.
+                overrides.remove("sandbox-paths");
+            }
+            /*
+            overrides.erase(loggerSettings.showTrace.name);
+            overrides.erase(experimentalFeatureSettings.experimentalFeatures.name);
+            overrides.erase(settings.pluginFiles.name);
+             */
             self.sink.write_usize(overrides.len()).await?;
             for (k, v) in overrides.iter() {
                 self.sink.write_str(k).await?;
@@ -317,17 +760,21 @@ where
         Ok(())
     }
 
-    #[instrument(skip_all, fields(%path))]
+    #[instrument(skip_all, fields(op = "IsValidPath", %path, protocol = field::Empty, remote_activity = field::Empty))]
     async fn is_valid_path(&mut self, path: &StorePath) -> Result<bool, Error> {
+        if self.path_infos.get(path).is_some() {
+            return Ok(true);
+        }
         let store_dir = self.store_dir.clone();
-        self.init_connection().await?;
+        let daemon_version = self.daemon_version().await?;
+        record_protocol(daemon_version);
         self.sink.write_enum(WorkerProtoOp::IsValidPath).await?;
         self.sink.write_printed(&store_dir, path).await?;
         self.process_stderr().await?;
         Ok(self.source.read_bool().await?)
     }
 
-    #[instrument(skip(self, source))]
+    #[instrument(skip_all, fields(op = "AddMultipleToStore", ?repair, ?check_sigs, protocol = field::Empty, remote_activity = field::Empty))]
     async fn add_multiple_to_store<SR: AsyncRead + fmt::Debug + Send + Unpin>(
         &mut self,
         mut source: SR,
@@ -335,15 +782,8 @@ where
         check_sigs: CheckSignaturesFlag,
     ) -> Result<(), Error> {
         let daemon_version = self.daemon_version().await?;
-        debug!(
-            daemon_version,
-            daemon.major = get_protocol_major!(daemon_version),
-            daemon.minor = get_protocol_minor!(daemon_version),
-            "Daemon version {}.{}",
-            get_protocol_major!(daemon_version),
-            get_protocol_minor!(daemon_version)
-        );
-        if get_protocol_minor!(daemon_version) >= 32 {
+        record_protocol(daemon_version);
+        if DaemonCapabilities::new(daemon_version).supports(ProtocolFeature::AddMultipleToStore) {
             self.sink
                 .write_enum(WorkerProtoOp::AddMultipleToStore)
                 .await?;
@@ -358,13 +798,174 @@ where
         }
     }
 
-    #[instrument(skip_all)]
+    #[instrument(skip_all, fields(op = "AddBuildLog", %drv_path, protocol = field::Empty, remote_activity = field::Empty))]
+    async fn add_build_log(&mut self, drv_path: &StorePath, log: &str) -> Result<(), Error> {
+        let store_dir = self.store_dir.clone();
+        let daemon_version = self.daemon_version().await?;
+        record_protocol(daemon_version);
+        DaemonCapabilities::new(daemon_version).require(ProtocolFeature::AddBuildLog)?;
+        self.sink.write_enum(WorkerProtoOp::AddBuildLog).await?;
+        self.sink.write_printed(&store_dir, drv_path).await?;
+        with_framed_sink!(self, |sink| {
+            sink.write_all(log.as_bytes()).map_err(Error::from)
+        });
+        self.source.read_u64_le().await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(op = "CollectGarbage", action = ?options.action, protocol = field::Empty, remote_activity = field::Empty))]
+    async fn collect_garbage(&mut self, options: &GCOptions) -> Result<GCResults, Error> {
+        let store_dir = self.store_dir.clone();
+        let daemon_version = self.daemon_version().await?;
+        record_protocol(daemon_version);
+        self.sink.write_enum(WorkerProtoOp::CollectGarbage).await?;
+        self.sink.write_u64_le(options.action.into()).await?;
+        self.sink
+            .write_printed_coll(&store_dir, &options.paths_to_delete)
+            .await?;
+        self.sink.write_bool(options.ignore_liveness).await?;
+        self.sink.write_u64_le(options.max_freed).await?;
+        // Removed options.
+        self.sink.write_u64_le(0).await?;
+        self.sink.write_u64_le(0).await?;
+        self.sink.write_u64_le(0).await?;
+        self.process_stderr().await?;
+        let paths = self.source.read_string_coll().await?;
+        let bytes_freed = self.source.read_u64_le().await?;
+        self.source.read_u64_le().await?; // obsolete
+        self.path_infos.clear();
+        Ok(GCResults { paths, bytes_freed })
+    }
+
+    #[instrument(skip_all, fields(op = "QueryRealisation", %id, protocol = field::Empty, remote_activity = field::Empty))]
+    async fn query_realisation(&mut self, id: &DrvOutput) -> Result<Option<Realisation>, Error> {
+        if let Some(realisation) = self.realisations.get(id) {
+            return Ok(Some(realisation.clone()));
+        }
+        let store_dir = self.store_dir.clone();
+        let daemon_version = self.daemon_version().await?;
+        record_protocol(daemon_version);
+        let caps = DaemonCapabilities::new(daemon_version);
+        caps.require(ProtocolFeature::Realisations)?;
+        self.sink
+            .write_enum(WorkerProtoOp::QueryRealisation)
+            .await?;
+        self.sink.write_string(id.to_string()).await?;
+        self.process_stderr().await?;
+        let realisation = if caps.supports(ProtocolFeature::FullRealisations) {
+            let realisations: StringSet = self.source.read_string_coll().await?;
+            match realisations.into_iter().next() {
+                Some(json) => Some(json.parse::<Realisation>()?),
+                None => None,
+            }
+        } else {
+            let paths: StorePathSet = self.source.read_parsed_coll(&store_dir).await?;
+            paths.into_iter().next().map(|out_path| Realisation {
+                id: id.clone(),
+                out_path,
+                signatures: StringSet::new(),
+                dependent_realisations: BTreeMap::new(),
+            })
+        };
+        if let Some(realisation) = realisation.as_ref() {
+            self.realisations.insert(id.clone(), realisation.clone());
+        }
+        Ok(realisation)
+    }
+
+    #[instrument(skip_all, fields(op = "RegisterDrvOutput", id = %realisation.id, protocol = field::Empty, remote_activity = field::Empty))]
+    async fn register_drv_output(&mut self, realisation: &Realisation) -> Result<(), Error> {
+        let store_dir = self.store_dir.clone();
+        let daemon_version = self.daemon_version().await?;
+        record_protocol(daemon_version);
+        let caps = DaemonCapabilities::new(daemon_version);
+        caps.require(ProtocolFeature::Realisations)?;
+        self.sink
+            .write_enum(WorkerProtoOp::RegisterDrvOutput)
+            .await?;
+        if caps.supports(ProtocolFeature::FullRealisations) {
+            self.sink
+                .write_string(realisation.to_json_string()?)
+                .await?;
+        } else {
+            self.sink.write_string(realisation.id.to_string()).await?;
+            self.sink
+                .write_printed(&store_dir, &realisation.out_path)
+                .await?;
+        }
+        self.process_stderr().await?;
+        // The daemon merges what we sent with what it had, so only its
+        // answer to the next query is worth keeping.
+        self.realisations.remove(&realisation.id);
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(op = "AddIndirectRoot", %path, protocol = field::Empty, remote_activity = field::Empty))]
+    async fn add_indirect_root(&mut self, path: &DaemonPath) -> Result<(), Error> {
+        let daemon_version = self.daemon_version().await?;
+        record_protocol(daemon_version);
+        self.sink.write_enum(WorkerProtoOp::AddIndirectRoot).await?;
+        AsyncSink::write_buf(&mut self.sink, path.as_bytes()).await?;
+        self.process_stderr().await?;
+        self.source.read_u64_le().await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(op = "FindRoots", protocol = field::Empty, remote_activity = field::Empty))]
+    async fn find_roots(&mut self) -> Result<BTreeMap<DaemonPath, StorePath>, Error> {
+        let store_dir = self.store_dir.clone();
+        let daemon_version = self.daemon_version().await?;
+        record_protocol(daemon_version);
+        self.sink.write_enum(WorkerProtoOp::FindRoots).await?;
+        self.process_stderr().await?;
+        let count = self.source.read_usize().await?;
+        let mut roots = BTreeMap::new();
+        for _ in 0..count {
+            let link = self.source.read_bytes().await?;
+            let target = self.source.read_parsed(&store_dir).await?;
+            // Untrusted clients only get to see that there is a root.
+            if link.as_ref() == CENSORED_ROOT.as_bytes() {
+                debug!("Skipping censored root for {}", target);
+                continue;
+            }
+            roots.insert(DaemonPath::new(link.as_ref())?, target);
+        }
+        Ok(roots)
+    }
+
+    #[instrument(skip_all, fields(op = "VerifyStore", check_contents = check_contents, ?repair, protocol = field::Empty, remote_activity = field::Empty))]
+    async fn verify_store(
+        &mut self,
+        check_contents: bool,
+        repair: RepairFlag,
+    ) -> Result<bool, Error> {
+        let daemon_version = self.daemon_version().await?;
+        record_protocol(daemon_version);
+        self.sink.write_enum(WorkerProtoOp::VerifyStore).await?;
+        self.sink.write_bool(check_contents).await?;
+        self.sink.write_flag(repair).await?;
+        self.process_stderr().await?;
+        Ok(self.source.read_bool().await?)
+    }
+
+    #[instrument(skip_all, fields(op = "OptimiseStore", protocol = field::Empty, remote_activity = field::Empty))]
+    async fn optimise_store(&mut self) -> Result<(), Error> {
+        let daemon_version = self.daemon_version().await?;
+        record_protocol(daemon_version);
+        self.sink.write_enum(WorkerProtoOp::OptimiseStore).await?;
+        self.process_stderr().await?;
+        self.source.read_u64_le().await?;
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(op = "QueryMissing", targets = targets.len(), protocol = field::Empty, remote_activity = field::Empty))]
     async fn query_missing(
         &mut self,
         targets: &[DerivedPath],
     ) -> Result<QueryMissingResult, Error> {
         let daemon_version = self.daemon_version().await?;
-        if get_protocol_minor!(daemon_version) < 19 {
+        record_protocol(daemon_version);
+        if !DaemonCapabilities::new(daemon_version).supports(ProtocolFeature::QueryMissing) {
             // TODO: Implement fallback
             return Err(Error::DaemonVersionTooOld);
         }
@@ -393,22 +994,20 @@ where
     R: AsyncRead + fmt::Debug + Unpin + Send + 'static,
     W: AsyncWrite + fmt::Debug + Unpin + Send + 'static,
 {
-    #[instrument(skip_all)]
+    #[instrument(skip_all, fields(op = "QueryValidPaths", paths = paths.len(), protocol = field::Empty, remote_activity = field::Empty))]
     async fn query_valid_paths(
         &mut self,
         paths: &StorePathSet,
-        _maybe_substitute: SubstituteFlag,
+        maybe_substitute: SubstituteFlag,
     ) -> Result<StorePathSet, Error> {
         let daemon_version = self.daemon_version().await?;
-        debug!(
-            daemon_version,
-            daemon.major = get_protocol_major!(daemon_version),
-            daemon.minor = get_protocol_minor!(daemon_version),
-            "Daemon version {}.{}",
-            get_protocol_major!(daemon_version),
-            get_protocol_minor!(daemon_version)
-        );
-        if get_protocol_minor!(daemon_version) < 12 {
+        record_protocol(daemon_version);
+        let caps = DaemonCapabilities::new(daemon_version);
+        // Older daemons would silently not substitute so fail instead.
+        if maybe_substitute == SubstituteFlag::Substitute {
+            caps.require(ProtocolFeature::SubstituteOnQuery)?;
+        }
+        if !caps.supports(ProtocolFeature::QueryValidPaths) {
             let mut res = StorePathSet::new();
             for i in paths.iter() {
                 if self.is_valid_path(i).await? {
@@ -420,9 +1019,8 @@ where
             let store_dir = self.store_dir.clone();
             self.sink.write_enum(WorkerProtoOp::QueryValidPaths).await?;
             self.sink.write_printed_coll(&store_dir, paths).await?;
-            if get_protocol_minor!(daemon_version) >= 27 {
-                // conn->to << (settings.buildersUseSubstitutes ? 1 : 0);
-                self.sink.write_bool(false).await?;
+            if caps.supports(ProtocolFeature::SubstituteOnQuery) {
+                self.sink.write_flag(maybe_substitute).await?;
             }
             self.process_stderr().await?;
             let res = self.source.read_parsed_coll(&store_dir).await?;
@@ -430,18 +1028,14 @@ where
         }
     }
 
-    #[instrument(skip_all, fields(%path))]
+    #[instrument(skip_all, fields(op = "QueryPathInfo", %path, protocol = field::Empty, remote_activity = field::Empty))]
     async fn query_path_info(&mut self, path: &StorePath) -> Result<Option<ValidPathInfo>, Error> {
+        if let Some(info) = self.path_infos.get(path) {
+            return Ok(Some(info.clone()));
+        }
         let store_dir = self.store_dir.clone();
         let daemon_version = self.daemon_version().await?;
-        debug!(
-            daemon_version,
-            daemon.major = get_protocol_major!(daemon_version),
-            daemon.minor = get_protocol_minor!(daemon_version),
-            "Daemon version {}.{}",
-            get_protocol_major!(daemon_version),
-            get_protocol_minor!(daemon_version)
-        );
+        record_protocol(daemon_version);
         self.sink.write_enum(WorkerProtoOp::QueryPathInfo).await?;
         self.sink.write_printed(&store_dir, path).await?;
         if let Err(err) = self.process_stderr().await {
@@ -453,7 +1047,8 @@ where
             }
         }
 
-        if get_protocol_minor!(daemon_version) >= 17 {
+        if DaemonCapabilities::new(daemon_version).supports(ProtocolFeature::QueryPathInfoValidity)
+        {
             let valid = self.source.read_bool().await?;
             if !valid {
                 return Ok(None);
@@ -467,23 +1062,17 @@ where
             path.clone(),
         )
         .await?;
+        self.path_infos.insert(info.path.clone(), info.clone());
         Ok(Some(info))
     }
 
-    #[instrument(skip_all, fields(%path))]
+    #[instrument(skip_all, fields(op = "NarFromPath", %path, protocol = field::Empty, remote_activity = field::Empty))]
     async fn nar_from_path<SW>(&mut self, path: &StorePath, writer: SW) -> Result<(), Error>
     where
         SW: AsyncWrite + fmt::Debug + Send + Unpin,
     {
         let daemon_version = self.daemon_version().await?;
-        debug!(
-            daemon_version,
-            daemon.major = get_protocol_major!(daemon_version),
-            daemon.minor = get_protocol_minor!(daemon_version),
-            "Daemon version {}.{}",
-            get_protocol_major!(daemon_version),
-            get_protocol_minor!(daemon_version)
-        );
+        record_protocol(daemon_version);
         debug!("Sending NAR for path {}", path);
         let store_dir = self.store_dir.clone();
         self.sink.write_enum(WorkerProtoOp::NarFromPath).await?;
@@ -495,7 +1084,7 @@ where
         Ok(())
     }
 
-    #[instrument(skip_all)]
+    #[instrument(skip_all, fields(op = "AddToStoreNar", path = %info.path, nar_size = info.nar_size, protocol = field::Empty, remote_activity = field::Empty))]
     async fn add_to_store<SR: AsyncRead + fmt::Debug + Send + Unpin>(
         &mut self,
         info: &ValidPathInfo,
@@ -510,15 +1099,9 @@ where
             self.host
         );
         let daemon_version = self.daemon_version().await?;
-        debug!(
-            daemon_version,
-            daemon.major = get_protocol_major!(daemon_version),
-            daemon.minor = get_protocol_minor!(daemon_version),
-            "Daemon version {}.{}",
-            get_protocol_major!(daemon_version),
-            get_protocol_minor!(daemon_version)
-        );
-        if get_protocol_minor!(daemon_version) < 18 {
+        record_protocol(daemon_version);
+        let caps = DaemonCapabilities::new(daemon_version);
+        if !caps.supports(ProtocolFeature::AddToStoreNar) {
             self.sink.write_enum(WorkerProtoOp::ImportPaths).await?;
 
             let (source2, mut sink) = tokio::io::duplex(65_000);
@@ -561,17 +1144,15 @@ where
             self.sink.write_bool(info.ultimate).await?;
             let sigs: Vec<String> = info.sigs.iter().map(ToString::to_string).collect();
             self.sink.write_string_coll(&sigs).await?;
-            if let Some(ca) = info.ca.as_ref() {
-                self.sink.write_str(&ca.to_string()).await?;
-            } else {
-                self.sink.write_str("").await?;
-            }
+            self.sink
+                .write_string(ContentAddress::print_opt(info.ca.as_ref()))
+                .await?;
             self.sink.write_flag(repair).await?;
             self.sink.write_flag(!check_sigs).await?;
 
-            if get_protocol_minor!(daemon_version) >= 23 {
+            if caps.supports(ProtocolFeature::AddToStoreNarFramed) {
                 with_framed_sink!(self, |sink| { copy_nar(source, sink).map_err(Error::from) });
-            } else if get_protocol_minor!(daemon_version) >= 21 {
+            } else if caps.supports(ProtocolFeature::AddToStoreNarStderrRead) {
                 self.process_stderr_source(source).await?;
             } else {
                 copy_nar(source, &mut self.sink).await?;
@@ -581,7 +1162,7 @@ where
         Ok(())
     }
 
-    #[instrument(skip_all, fields(%drv_path, build_mode))]
+    #[instrument(skip_all, fields(op = "BuildDerivation", %drv_path, build_mode, protocol = field::Empty, remote_activity = field::Empty))]
     async fn build_derivation(
         &mut self,
         drv_path: &StorePath,
@@ -591,40 +1172,16 @@ where
         debug!("Build derivation {} with path {}", drv.name, drv_path);
         let store_dir = self.store_dir.clone();
         let daemon_version = self.daemon_version().await?;
-        debug!(
-            daemon_version,
-            daemon.major = get_protocol_major!(daemon_version),
-            daemon.minor = get_protocol_minor!(daemon_version),
-            "Daemon version {}.{}",
-            get_protocol_major!(daemon_version),
-            get_protocol_minor!(daemon_version)
-        );
+        record_protocol(daemon_version);
         self.sink.write_enum(WorkerProtoOp::BuildDerivation).await?;
         self.sink.write_printed(&store_dir, drv_path).await?;
         drv.write_drv(&mut self.sink, &store_dir).await?;
         self.sink.write_enum(build_mode).await?;
         self.process_stderr().await?;
-        let status: BuildStatus = self.source.read_enum().await?;
-        let error_msg = self.source.read_string().await?;
-        let mut status = BuildResult::new(status, error_msg);
-        if get_protocol_minor!(daemon_version) >= 29 {
-            status.times_built = self.source.read_u64_le().await?;
-            status.is_non_deterministic = self.source.read_bool().await?;
-            status.start_time = self.source.read_time().await?;
-            status.stop_time = self.source.read_time().await?;
-        }
-        if get_protocol_minor!(daemon_version) >= 28 {
-            let count = self.source.read_usize().await?;
-            for _i in 0..count {
-                let id = self.source.read_string().await?.parse()?;
-                let realisation = self.source.read_string().await?.parse()?;
-                status.built_outputs.insert(id, realisation);
-            }
-        }
-        Ok(status)
+        self.read_build_result(daemon_version).await
     }
 
-    #[instrument(skip(self, drv_paths))]
+    #[instrument(skip_all, fields(op = "BuildPaths", drv_paths = drv_paths.len(), ?build_mode, protocol = field::Empty, remote_activity = field::Empty))]
     async fn build_paths(
         &mut self,
         drv_paths: &[DerivedPath],
@@ -633,18 +1190,11 @@ where
         debug!("Build paths {:?}", drv_paths);
         // copyDrvsFromEvalStore(drvPaths, evalStore);
         let daemon_version = self.daemon_version().await?;
-        debug!(
-            daemon_version,
-            daemon.major = get_protocol_major!(daemon_version),
-            daemon.minor = get_protocol_minor!(daemon_version),
-            "Daemon version {}.{}",
-            get_protocol_major!(daemon_version),
-            get_protocol_minor!(daemon_version)
-        );
+        record_protocol(daemon_version);
         self.sink.write_enum(WorkerProtoOp::BuildPaths).await?;
         assert!(get_protocol_minor!(daemon_version) >= 13);
         self.write_derived_paths(drv_paths).await?;
-        if get_protocol_minor!(daemon_version) >= 15 {
+        if DaemonCapabilities::new(daemon_version).supports(ProtocolFeature::BuildMode) {
             self.sink.write_enum(build_mode).await?;
         } else {
             // Old daemons did not take a 'buildMode' parameter, so we
@@ -682,6 +1232,7 @@ mod tests {
     use crate::pretty_prop_assert_eq;
     use crate::signature::SignatureSet;
     use crate::store::assert_store::AssertStore;
+    use crate::store::daemon::transcripts::{client_hello, protocol_matrix, server_hello};
     use crate::store::settings::BuildSettings;
     use crate::store::DerivationOutput;
     use crate::store::DrvOutput;
@@ -820,6 +1371,8 @@ mod tests {
             built_outputs,
             start_time: SystemTime::UNIX_EPOCH,
             stop_time: SystemTime::UNIX_EPOCH,
+            cpu_user: None,
+            cpu_system: None,
         };
         store_cmd!(
             TrustedFlag::Trusted,
@@ -836,6 +1389,23 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_add_build_log() {
+        let drv_path =
+            StorePath::new_from_base_name("00000000000000000000000000000000-0.drv").unwrap();
+        store_cmd!(
+            TrustedFlag::Trusted,
+            assert_add_build_log(
+                Some(TrustedFlag::Trusted),
+                &drv_path,
+                "building '0'\n",
+                Ok(())
+            ),
+            add_build_log(&drv_path, "building '0'\n"),
+            ()
+        );
+    }
+
     macro_rules! prop_store_cmd {
         (
             $trusted:expr,
@@ -981,4 +1551,71 @@ mod tests {
             );
         }
     }
+
+    protocol_matrix! {
+        async fn test_handshake_transcripts(version: u64) {
+            let minor = version & 0xff;
+            let daemon = server_hello(minor, b"2.18.1", 1);
+            let mut client = DaemonStoreClient::new(
+                StoreDir::default(),
+                "localhost".into(),
+                Cursor::new(daemon),
+                Cursor::new(Vec::new()),
+            );
+            client.handshake().await.unwrap();
+
+            let mut expected = client_hello(minor);
+            // The client always announces its own version.
+            expected[8..16].copy_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+            assert_eq!(client.sink.get_ref().get_ref(), &expected);
+            assert_eq!(client.daemon_version, Some(PROTOCOL_VERSION.min(version)));
+            let (nix_version, trust) = match minor {
+                0..=32 => (None, None),
+                33 | 34 => (Some("2.18.1"), None),
+                _ => (Some("2.18.1"), Some(TrustedFlag::Trusted)),
+            };
+            assert_eq!(client.daemon_nix_version(), nix_version);
+            assert_eq!(client.remote_trusts_us(), trust);
+            let info = client.handshake_info().unwrap();
+            assert_eq!(info.protocol_version, PROTOCOL_VERSION.min(version));
+            assert_eq!(info.nix_version.as_deref(), nix_version);
+            assert_eq!(info.trusted, trust);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handshake_max_version() {
+        // A 1.35 daemon talking to a client that announces 1.30 uses the
+        // 1.30 handshake.
+        let mut daemon = server_hello(30, b"", 0);
+        daemon[8..16].copy_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+        let mut client = DaemonStoreClient::builder()
+            .max_version(1 << 8 | 30)
+            .build(Cursor::new(daemon), Cursor::new(Vec::new()));
+        client.handshake().await.unwrap();
+        assert_eq!(client.sink.get_ref().get_ref(), &client_hello(30));
+        assert_eq!(client.daemon_version, Some(1 << 8 | 30));
+        assert_eq!(client.daemon_nix_version(), None);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_without_obsolete_fields() {
+        let mut client = DaemonStoreClient::builder().obsolete_fields(false).build(
+            Cursor::new(server_hello(35, b"2.18.1", 2)),
+            Cursor::new(Vec::new()),
+        );
+        client.handshake().await.unwrap();
+        assert_eq!(client.sink.get_ref().get_ref(), &client_hello(35)[..16]);
+        assert_eq!(client.remote_trusts_us(), Some(TrustedFlag::NotTrusted));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_min_version() {
+        let mut client = DaemonStoreClient::builder().min_version(1 << 8 | 30).build(
+            Cursor::new(server_hello(21, b"", 0)),
+            Cursor::new(Vec::new()),
+        );
+        let res = client.handshake().await;
+        assert!(matches!(res, Err(Error::DaemonVersionTooOld)), "{:?}", res);
+    }
 }
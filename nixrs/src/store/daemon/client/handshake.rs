@@ -0,0 +1,64 @@
+use std::fmt;
+use std::time::Duration;
+
+use super::capabilities::DaemonCapabilities;
+use crate::store::daemon::{get_protocol_major, get_protocol_minor, TrustedFlag};
+
+/// What the daemon told us while connecting.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use nixrs::store::daemon::{HandshakeInfo, TrustedFlag};
+/// let info = HandshakeInfo {
+///     daemon_version: 1 << 8 | 37,
+///     protocol_version: 1 << 8 | 35,
+///     nix_version: Some("2.24.9".into()),
+///     trusted: Some(TrustedFlag::Trusted),
+///     duration: Duration::from_millis(3),
+/// };
+/// assert_eq!(info.to_string(), "connected to nix 2.24.9 (protocol 1.35, trusted)");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandshakeInfo {
+    /// Protocol version the daemon announced.
+    pub daemon_version: u64,
+    /// Protocol version used on the connection, the older of ours and the
+    /// daemon's.
+    pub protocol_version: u64,
+    /// Nix version of the daemon. Only sent by protocol 1.33 and newer.
+    pub nix_version: Option<String>,
+    /// Whether the daemon trusts us. Only sent by protocol 1.35 and newer.
+    pub trusted: Option<TrustedFlag>,
+    /// How long the handshake took.
+    pub duration: Duration,
+}
+
+impl HandshakeInfo {
+    pub fn capabilities(&self) -> DaemonCapabilities {
+        DaemonCapabilities::new(self.protocol_version)
+    }
+
+    /// The negotiated protocol version as `major.minor`.
+    pub fn protocol(&self) -> String {
+        format!(
+            "{}.{}",
+            get_protocol_major!(self.protocol_version),
+            get_protocol_minor!(self.protocol_version)
+        )
+    }
+}
+
+impl fmt::Display for HandshakeInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.nix_version.as_ref() {
+            Some(version) => write!(f, "connected to nix {}", version)?,
+            None => write!(f, "connected to unknown nix version")?,
+        }
+        write!(f, " (protocol {}", self.protocol())?;
+        match self.trusted {
+            Some(TrustedFlag::Trusted) => write!(f, ", trusted)"),
+            Some(TrustedFlag::NotTrusted) => write!(f, ", not trusted)"),
+            None => write!(f, ")"),
+        }
+    }
+}
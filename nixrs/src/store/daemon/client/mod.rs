@@ -1,4 +1,14 @@
+mod cancel;
+mod capabilities;
 mod daemon_store_client;
+mod handshake;
+mod nar_download;
 mod process_stderr;
 
-pub use daemon_store_client::DaemonStoreClient;
+pub use cancel::CancellableClient;
+pub use capabilities::{
+    DaemonCapabilities, FeatureGate, ParseProtocolFeatureError, ProtocolFeature, ProtocolRange,
+};
+pub use daemon_store_client::{DaemonStoreBuilder, DaemonStoreClient};
+pub use handshake::HandshakeInfo;
+pub use nar_download::NarDownload;
@@ -0,0 +1,140 @@
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use tokio::io::{AsyncRead, DuplexStream, ReadBuf};
+
+use super::DaemonStoreClient;
+use crate::store::Error;
+
+type Transfer<R, W> = BoxFuture<'static, (DaemonStoreClient<R, W>, Result<(), Error>)>;
+
+/// A NAR being read from a daemon connection that was handed over by
+/// [`DaemonStoreClient::download_nar`].
+///
+/// Reading gives the NAR and nothing else. The connection is given back by
+/// [`finish`](NarDownload::finish), which skips whatever was not read yet.
+pub struct NarDownload<R, W> {
+    reader: DuplexStream,
+    transfer: Option<Transfer<R, W>>,
+    done: Option<(DaemonStoreClient<R, W>, Result<(), Error>)>,
+}
+
+impl<R, W> NarDownload<R, W> {
+    pub(super) fn new(reader: DuplexStream, transfer: Transfer<R, W>) -> NarDownload<R, W> {
+        NarDownload {
+            reader,
+            transfer: Some(transfer),
+            done: None,
+        }
+    }
+
+    fn poll_transfer(&mut self, cx: &mut Context<'_>) {
+        if let Some(transfer) = self.transfer.as_mut() {
+            if let Poll::Ready(done) = transfer.poll_unpin(cx) {
+                self.done = Some(done);
+                self.transfer = None;
+            }
+        }
+    }
+
+    /// Skip the rest of the NAR and give the connection back, ready for the
+    /// next operation.
+    pub async fn finish(mut self) -> Result<DaemonStoreClient<R, W>, Error>
+    where
+        R: Unpin,
+        W: Unpin,
+    {
+        tokio::io::copy(&mut self, &mut tokio::io::sink()).await?;
+        let (client, res) = self.done.take().expect("NAR transfer is done");
+        res.map(|_| client)
+    }
+}
+
+impl<R: Unpin, W: Unpin> AsyncRead for NarDownload<R, W> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.poll_transfer(cx);
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.reader).poll_read(cx, buf))?;
+        if buf.filled().len() == filled {
+            // The transfer drops its end of the pipe when it is done, so
+            // this is the end of the NAR or the transfer failed.
+            if let Some((_, Err(err))) = this.done.as_ref() {
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, err.to_string())));
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R, W> fmt::Debug for NarDownload<R, W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NarDownload")
+            .field("done", &self.transfer.is_none())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Bytes, BytesMut};
+    use futures::future::try_join;
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::archive::test_data::dir_example;
+    use crate::store::assert_store::AssertStore;
+    use crate::store::daemon::TrustedFlag;
+    use crate::store_path::{StoreDir, StorePath};
+
+    fn example_nar() -> Bytes {
+        let mut buf = BytesMut::new();
+        for event in dir_example() {
+            event.encode_into(&mut buf);
+        }
+        buf.freeze()
+    }
+
+    #[tokio::test]
+    async fn test_download_nar() {
+        let path = StorePath::test_from_seed("foo");
+        let contents = example_nar();
+        let (client, server) = tokio::io::duplex(1_000_000);
+        let (read, write) = tokio::io::split(client);
+        let client = DaemonStoreClient::new(StoreDir::default(), "localhost".into(), read, write);
+
+        let mut store = AssertStore::assert_nar_from_path(
+            Some(TrustedFlag::Trusted),
+            &path,
+            Ok(contents.clone()),
+        );
+        let (read, write) = tokio::io::split(server);
+        let server = Box::pin(crate::store::daemon::run_server(
+            read,
+            write,
+            &mut store,
+            TrustedFlag::Trusted,
+        ));
+        let cmd = async {
+            let mut client = client;
+            client.init_connection().await?;
+            let mut download = client.download_nar(&path).await?;
+            let mut buf = Vec::new();
+            download.read_to_end(&mut buf).await?;
+            let mut client = download.finish().await?;
+            client.close().await?;
+            Ok(buf) as Result<Vec<u8>, Error>
+        };
+        let (buf, _) = try_join(cmd, server).await.unwrap();
+        store.assert_eq();
+        assert_eq!(buf, contents);
+    }
+}
@@ -95,11 +95,13 @@ impl<R, W, SR, SW> ProcessStderr<R, W, SR, SW> {
                     debug!("Got STDERR_READ");
                     if let Some(source) = self.source.as_mut() {
                         let mut to = self.to.as_mut().unwrap();
-                        let len = source.read_usize().await?;
+                        let len = self.from.read_usize().await?;
                         if buf.capacity() < len {
                             buf.reserve(len);
                         }
-                        source.read_buf(&mut buf).await?;
+                        // Sending more than asked for would be read as the
+                        // next message.
+                        (&mut *source).take(len as u64).read_buf(&mut buf).await?;
                         AsyncSink::write_buf(&mut to, &buf).await?;
                         buf.clear();
                         to.flush().await?;
@@ -145,6 +147,10 @@ impl<R, W, SR, SW> ProcessStderr<R, W, SR, SW> {
                     let s = self.from.read_string().await?;
                     let fields = read_fields(&mut self.from).await?;
                     let parent: ActivityId = self.from.read_u64_le().await?;
+                    #[cfg(feature = "remote-activity-ids")]
+                    if parent == 0 {
+                        tracing::Span::current().record("remote_activity", act);
+                    }
                     self.logger
                         .start_activity(act, lvl, act_type, s, fields, parent);
                 }
@@ -0,0 +1,193 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq, Clone)]
+pub enum DaemonPathError {
+    #[error("path is empty")]
+    Empty,
+    #[error("path '{0}' is not absolute")]
+    NotAbsolute(String),
+    #[error("path '{0}' contains a NUL byte")]
+    ContainsNul(String),
+    #[error("path '{0}' is not valid UTF-8")]
+    NotUtf8(String),
+}
+
+/// A path outside the store that is sent over the daemon protocol, like
+/// the link of a GC root.
+///
+/// The protocol sends paths as plain bytes. A `DaemonPath` is always
+/// absolute and never contains NUL bytes so it can be handed to the file
+/// system, but it doesn't have to be UTF-8.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
+pub struct DaemonPath(Vec<u8>);
+
+impl DaemonPath {
+    pub fn new<B: Into<Vec<u8>>>(bytes: B) -> Result<DaemonPath, DaemonPathError> {
+        let bytes = bytes.into();
+        if bytes.is_empty() {
+            return Err(DaemonPathError::Empty);
+        }
+        if bytes.contains(&0) {
+            return Err(DaemonPathError::ContainsNul(lossy(&bytes)));
+        }
+        if bytes[0] != b'/' {
+            return Err(DaemonPathError::NotAbsolute(lossy(&bytes)));
+        }
+        Ok(DaemonPath(bytes))
+    }
+
+    /// Takes `path` exactly as the OS represents it.
+    ///
+    /// On platforms where paths aren't bytes, `path` has to be UTF-8.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<DaemonPath, DaemonPathError> {
+        let path = path.as_ref();
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            DaemonPath::new(path.as_os_str().as_bytes())
+        }
+        #[cfg(not(unix))]
+        {
+            match path.to_str() {
+                Some(s) => DaemonPath::new(s),
+                None => Err(DaemonPathError::NotUtf8(path.to_string_lossy().into())),
+            }
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn to_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.0).ok()
+    }
+
+    /// Converts to a [`PathBuf`] naming exactly this path.
+    ///
+    /// Fails with [`DaemonPathError::NotUtf8`] on platforms where paths
+    /// aren't bytes and this path isn't UTF-8.
+    pub fn to_path_buf(&self) -> Result<PathBuf, DaemonPathError> {
+        #[cfg(unix)]
+        {
+            use std::ffi::OsStr;
+            use std::os::unix::ffi::OsStrExt;
+            Ok(PathBuf::from(OsStr::from_bytes(&self.0)))
+        }
+        #[cfg(not(unix))]
+        {
+            match self.to_str() {
+                Some(s) => Ok(PathBuf::from(s)),
+                None => Err(DaemonPathError::NotUtf8(lossy(&self.0))),
+            }
+        }
+    }
+
+    /// Converts to a [`PathBuf`], replacing invalid UTF-8 on platforms
+    /// where paths aren't bytes. The result is only fit for showing to
+    /// users there since it may name a different file.
+    pub fn to_path_buf_lossy(&self) -> PathBuf {
+        self.to_path_buf()
+            .unwrap_or_else(|_| PathBuf::from(lossy(&self.0)))
+    }
+}
+
+fn lossy(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+impl fmt::Display for DaemonPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.0))
+    }
+}
+
+impl fmt::Debug for DaemonPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("DaemonPath")
+            .field(&String::from_utf8_lossy(&self.0))
+            .finish()
+    }
+}
+
+impl FromStr for DaemonPath {
+    type Err = DaemonPathError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        DaemonPath::new(s)
+    }
+}
+
+impl<'a> TryFrom<&'a Path> for DaemonPath {
+    type Error = DaemonPathError;
+
+    fn try_from(value: &'a Path) -> Result<Self, Self::Error> {
+        DaemonPath::from_path(value)
+    }
+}
+
+impl TryFrom<PathBuf> for DaemonPath {
+    type Error = DaemonPathError;
+
+    fn try_from(value: PathBuf) -> Result<Self, Self::Error> {
+        DaemonPath::from_path(value)
+    }
+}
+
+impl AsRef<[u8]> for DaemonPath {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validation() {
+        assert_eq!(DaemonPath::new(""), Err(DaemonPathError::Empty));
+        assert_eq!(
+            DaemonPath::new("result"),
+            Err(DaemonPathError::NotAbsolute("result".into()))
+        );
+        assert_eq!(
+            DaemonPath::new("/tmp/a\0b"),
+            Err(DaemonPathError::ContainsNul("/tmp/a\0b".into()))
+        );
+        let path: DaemonPath = "/home/user/result".parse().unwrap();
+        assert_eq!(path.to_string(), "/home/user/result");
+        assert_eq!(path.to_str(), Some("/home/user/result"));
+    }
+
+    #[test]
+    fn test_path_buf_round_trip() {
+        let path = DaemonPath::from_path("/nix/var/nix/gcroots/auto/abc").unwrap();
+        assert_eq!(
+            path.to_path_buf().unwrap(),
+            PathBuf::from("/nix/var/nix/gcroots/auto/abc")
+        );
+        assert_eq!(path.to_path_buf_lossy(), path.to_path_buf().unwrap());
+        assert_eq!(
+            DaemonPath::try_from(PathBuf::from("relative")),
+            Err(DaemonPathError::NotAbsolute("relative".into()))
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_is_exact() {
+        use std::os::unix::ffi::OsStrExt;
+        let path = DaemonPath::new(&b"/tmp/\xff\xfe"[..]).unwrap();
+        assert_eq!(path.to_str(), None);
+        assert_eq!(path.to_string(), "/tmp/\u{fffd}\u{fffd}");
+        let buf = path.to_path_buf().unwrap();
+        assert_eq!(buf.as_os_str().as_bytes(), b"/tmp/\xff\xfe");
+        assert_eq!(DaemonPath::from_path(buf).unwrap(), path);
+    }
+}
@@ -0,0 +1,885 @@
+//! Decoding captured daemon protocol traffic for debugging.
+//!
+//! [`dissect`] takes everything the client sent and everything the daemon
+//! sent on one connection and lists the handshake, each operation with its
+//! arguments, the log messages the daemon sent while working on it and the
+//! reply together with their offsets in the stream they were read from.
+//!
+//! The two streams are decoded separately so they don't need to be
+//! interleaved correctly. Decoding stops at the first thing it doesn't
+//! understand, like an operation it doesn't know the arguments of, since
+//! there is no telling where the next message starts after that.
+//!
+//! Captures can also be kept in a single file of records made of a
+//! direction byte (`>` for client to daemon and `<` for daemon to client),
+//! a 32-bit little endian length and that many bytes, which
+//! [`split_capture`] turns into the two streams.
+use std::fmt;
+
+use super::{
+    get_protocol_minor, WorkerProtoOp, STDERR_ERROR, STDERR_LAST, STDERR_NEXT, STDERR_READ,
+    STDERR_RESULT, STDERR_START_ACTIVITY, STDERR_STOP_ACTIVITY, STDERR_WRITE, WORKER_MAGIC_1,
+    WORKER_MAGIC_2,
+};
+
+/// Longest string that is shown in full.
+const MAX_SHOWN: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ClientToDaemon,
+    DaemonToClient,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::ClientToDaemon => write!(f, "C>D"),
+            Direction::DaemonToClient => write!(f, "C<D"),
+        }
+    }
+}
+
+/// One decoded message or value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DissectLine {
+    pub direction: Direction,
+    /// Offset of the start of the value in the stream of its direction.
+    pub offset: usize,
+    /// How deep the value is nested in lists and replies.
+    pub depth: usize,
+    pub text: String,
+}
+
+/// The decoded contents of a connection.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Dissection {
+    /// The protocol version both ends agreed on in the handshake.
+    pub version: Option<u64>,
+    pub lines: Vec<DissectLine>,
+    /// Why decoding stopped before the end of the streams.
+    pub error: Option<String>,
+}
+
+impl fmt::Display for Dissection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in self.lines.iter() {
+            writeln!(
+                f,
+                "{:08x} {} {:indent$}{}",
+                line.offset,
+                line.direction,
+                "",
+                line.text,
+                indent = line.depth * 2
+            )?;
+        }
+        if let Some(err) = self.error.as_ref() {
+            writeln!(f, "error: {}", err)?;
+        }
+        Ok(())
+    }
+}
+
+/// Split a capture file into what the client sent and what the daemon
+/// sent. See the [module docs](self) for the format.
+pub fn split_capture(mut capture: &[u8]) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let mut client = Vec::new();
+    let mut daemon = Vec::new();
+    while !capture.is_empty() {
+        if capture.len() < 5 {
+            return Err("truncated capture record header".into());
+        }
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&capture[1..5]);
+        let len = u32::from_le_bytes(len) as usize;
+        let data = capture
+            .get(5..5 + len)
+            .ok_or_else(|| "truncated capture record".to_string())?;
+        match capture[0] {
+            b'>' => client.extend_from_slice(data),
+            b'<' => daemon.extend_from_slice(data),
+            d => return Err(format!("invalid capture direction {:#04x}", d)),
+        }
+        capture = &capture[5 + len..];
+    }
+    Ok((client, daemon))
+}
+
+/// Decode a connection from the bytes sent by the client and the bytes
+/// sent by the daemon.
+///
+/// ```
+/// # use nixrs::store::daemon::dissect::dissect;
+/// let client: Vec<u8> = [0x6e697863u64, 1 << 8 | 35, 0, 0]
+///     .iter()
+///     .flat_map(|w| w.to_le_bytes())
+///     .collect();
+/// let dissection = dissect(&client, &[]);
+/// assert_eq!(dissection.lines[0].text, "client magic");
+/// ```
+pub fn dissect(client: &[u8], daemon: &[u8]) -> Dissection {
+    let mut dissector = Dissector {
+        client: Decoder::new(client),
+        daemon: Decoder::new(daemon),
+        minor: 0,
+        depth: 0,
+        dissection: Dissection::default(),
+    };
+    if let Err(err) = dissector.run() {
+        dissector.dissection.error = Some(err);
+    }
+    dissector.dissection
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(data: &'a [u8]) -> Decoder<'a> {
+        Decoder { data, pos: 0 }
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.data.len() - self.pos < len {
+            return Err(format!(
+                "needed {} bytes at offset {:#x} but only {} are left",
+                len,
+                self.pos,
+                self.data.len() - self.pos
+            ));
+        }
+        let ret = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(ret)
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn len(&mut self) -> Result<usize, String> {
+        let offset = self.pos;
+        let len = self.u64()?;
+        if len > (self.data.len() - self.pos) as u64 {
+            return Err(format!(
+                "length {} at offset {:#x} is too long",
+                len, offset
+            ));
+        }
+        Ok(len as usize)
+    }
+
+    fn string(&mut self) -> Result<&'a [u8], String> {
+        let len = self.len()?;
+        let ret = self.take(len)?;
+        let padding = (8 - len % 8) % 8;
+        self.take(padding)?;
+        Ok(ret)
+    }
+}
+
+fn show_string(s: &[u8]) -> String {
+    match std::str::from_utf8(s) {
+        Ok(s) if !s.chars().any(|c| c.is_control() && c != '\n' && c != '\t') => {
+            if s.len() > MAX_SHOWN {
+                let mut end = MAX_SHOWN;
+                while !s.is_char_boundary(end) {
+                    end -= 1;
+                }
+                format!("{:?}... ({} bytes)", &s[..end], s.len())
+            } else {
+                format!("{:?}", s)
+            }
+        }
+        _ => {
+            let shown = s.len().min(32);
+            format!("<{} bytes> {}", s.len(), hex::encode(&s[..shown]))
+        }
+    }
+}
+
+/// How to decode a value.
+enum Field {
+    U64(&'static str),
+    Bool(&'static str),
+    Str(&'static str),
+    /// A count followed by that many elements made of the fields.
+    List(&'static str, Vec<Field>),
+    /// A flag followed by the fields when it is set.
+    Optional(&'static str, Vec<Field>),
+    /// Data sent in frames with a length prefix and ended by an empty one.
+    Framed(&'static str),
+    Nar(&'static str),
+    /// Typed fields of activities and results.
+    LoggerFields(&'static str),
+}
+
+fn strings(name: &'static str) -> Field {
+    Field::List(name, vec![Field::Str("")])
+}
+
+fn string_pairs(name: &'static str) -> Field {
+    Field::List(name, vec![Field::Str("name"), Field::Str("value")])
+}
+
+fn path_info(minor: u64) -> Vec<Field> {
+    let mut ret = vec![
+        Field::Str("deriver"),
+        Field::Str("nar hash"),
+        strings("references"),
+        Field::U64("registration time"),
+        Field::U64("nar size"),
+    ];
+    if minor >= 16 {
+        ret.push(Field::Bool("ultimate"));
+        ret.push(strings("signatures"));
+        ret.push(Field::Str("content address"));
+    }
+    ret
+}
+
+fn build_result(minor: u64) -> Vec<Field> {
+    let mut ret = vec![Field::U64("status"), Field::Str("error message")];
+    if minor >= 29 {
+        ret.push(Field::U64("times built"));
+        ret.push(Field::Bool("non deterministic"));
+        ret.push(Field::U64("start time"));
+        ret.push(Field::U64("stop time"));
+    }
+    if minor >= 37 {
+        ret.push(Field::Optional(
+            "cpu user",
+            vec![Field::U64("microseconds")],
+        ));
+        ret.push(Field::Optional(
+            "cpu system",
+            vec![Field::U64("microseconds")],
+        ));
+    }
+    if minor >= 28 {
+        ret.push(string_pairs("built outputs"));
+    }
+    ret
+}
+
+/// Arguments the client sends with `op`, or `None` when they are not known.
+fn request_fields(op: WorkerProtoOp, minor: u64) -> Option<Vec<Field>> {
+    use WorkerProtoOp::*;
+    let ret = match op {
+        IsValidPath
+        | QueryPathHash
+        | QueryReferences
+        | QueryReferrers
+        | QueryDeriver
+        | QueryPathInfo
+        | NarFromPath
+        | QueryValidDerivers
+        | QueryDerivationOutputs
+        | QueryDerivationOutputNames
+        | QueryDerivationOutputMap
+        | EnsurePath
+        | AddTempRoot
+        | AddIndirectRoot
+        | QuerySubstitutablePathInfo => vec![Field::Str("path")],
+        QueryPathFromHashPart => vec![Field::Str("hash part")],
+        QueryValidPaths => {
+            let mut ret = vec![strings("paths")];
+            if minor >= 27 {
+                ret.push(Field::Bool("substitute"));
+            }
+            ret
+        }
+        HasSubstitutes | QuerySubstitutablePaths => vec![strings("paths")],
+        QueryAllValidPaths | SyncWithGC | FindRoots | OptimiseStore => vec![],
+        AddToStore if minor >= 25 => vec![
+            Field::Str("name"),
+            Field::Str("content address method"),
+            strings("references"),
+            Field::Bool("repair"),
+            Field::Framed("dump"),
+        ],
+        AddToStore => vec![
+            Field::Str("base name"),
+            Field::Bool("fixed"),
+            Field::U64("recursive"),
+            Field::Str("hash algorithm"),
+            Field::Nar("dump"),
+        ],
+        AddTextToStore => vec![
+            Field::Str("suffix"),
+            Field::Str("text"),
+            strings("references"),
+        ],
+        AddToStoreNar if !(21..23).contains(&minor) => {
+            let mut ret = vec![Field::Str("path")];
+            ret.extend(path_info(16));
+            ret.push(Field::Bool("repair"));
+            ret.push(Field::Bool("dont check sigs"));
+            if minor >= 23 {
+                ret.push(Field::Framed("nar"));
+            } else {
+                ret.push(Field::Nar("nar"));
+            }
+            ret
+        }
+        AddMultipleToStore => vec![
+            Field::Bool("repair"),
+            Field::Bool("dont check sigs"),
+            Field::Framed("paths"),
+        ],
+        BuildPaths | BuildPathsWithResults => {
+            let mut ret = vec![strings("derived paths")];
+            if minor >= 15 {
+                ret.push(Field::U64("build mode"));
+            }
+            ret
+        }
+        BuildDerivation => vec![
+            Field::Str("derivation path"),
+            Field::List(
+                "outputs",
+                vec![
+                    Field::Str("name"),
+                    Field::Str("path"),
+                    Field::Str("hash algorithm"),
+                    Field::Str("hash"),
+                ],
+            ),
+            strings("input sources"),
+            Field::Str("platform"),
+            Field::Str("builder"),
+            strings("args"),
+            string_pairs("env"),
+            Field::U64("build mode"),
+        ],
+        CollectGarbage => vec![
+            Field::U64("action"),
+            strings("paths to delete"),
+            Field::Bool("ignore liveness"),
+            Field::U64("max freed"),
+            Field::U64("obsolete"),
+            Field::U64("obsolete"),
+            Field::U64("obsolete"),
+        ],
+        SetOptions => {
+            let mut ret = vec![
+                Field::Bool("keep failed"),
+                Field::Bool("keep going"),
+                Field::Bool("try fallback"),
+                Field::U64("verbosity"),
+                Field::U64("max build jobs"),
+                Field::U64("max silent time"),
+                Field::U64("obsolete use build hook"),
+                Field::U64("build verbosity"),
+                Field::U64("obsolete log type"),
+                Field::U64("obsolete print build trace"),
+                Field::U64("build cores"),
+                Field::Bool("use substitutes"),
+            ];
+            if minor >= 12 {
+                ret.push(string_pairs("overrides"));
+            }
+            ret
+        }
+        QueryMissing => vec![strings("targets")],
+        VerifyStore => vec![Field::Bool("check contents"), Field::Bool("repair")],
+        AddSignatures => vec![Field::Str("path"), strings("signatures")],
+        AddBuildLog => vec![Field::Str("derivation path"), Field::Framed("log")],
+        RegisterDrvOutput if minor >= 31 => vec![Field::Str("realisation")],
+        RegisterDrvOutput => vec![Field::Str("output id"), Field::Str("output path")],
+        QueryRealisation => vec![Field::Str("output id")],
+        NixrsCompression => vec![Field::Str("algorithm")],
+        _ => return None,
+    };
+    Some(ret)
+}
+
+/// What the daemon replies to `op` after `STDERR_LAST`, or `None` when it
+/// is not known.
+fn reply_fields(op: WorkerProtoOp, minor: u64) -> Option<Vec<Field>> {
+    use WorkerProtoOp::*;
+    let ret = match op {
+        IsValidPath | HasSubstitutes | VerifyStore => vec![Field::Bool("result")],
+        QueryPathHash | QueryDeriver | QueryPathFromHashPart | AddTextToStore => {
+            vec![Field::Str("result")]
+        }
+        AddToStore if minor >= 25 => {
+            let mut ret = vec![Field::Str("path")];
+            ret.extend(path_info(minor));
+            ret
+        }
+        AddToStore => vec![Field::Str("path")],
+        QueryValidPaths
+        | QuerySubstitutablePaths
+        | QueryReferences
+        | QueryReferrers
+        | QueryValidDerivers
+        | QueryDerivationOutputs
+        | QueryDerivationOutputNames
+        | QueryAllValidPaths => vec![strings("paths")],
+        QueryDerivationOutputMap => vec![string_pairs("outputs")],
+        QueryPathInfo if minor >= 17 => vec![Field::Optional("valid", path_info(minor))],
+        QueryPathInfo => path_info(minor),
+        QuerySubstitutablePathInfo => vec![Field::Optional(
+            "valid",
+            vec![
+                Field::Str("deriver"),
+                strings("references"),
+                Field::U64("download size"),
+                Field::U64("nar size"),
+            ],
+        )],
+        NarFromPath => vec![Field::Nar("nar")],
+        AddToStoreNar | AddMultipleToStore | SetOptions | RegisterDrvOutput => vec![],
+        BuildPaths | EnsurePath | AddTempRoot | AddIndirectRoot | SyncWithGC | OptimiseStore
+        | AddSignatures | AddBuildLog => vec![Field::U64("result")],
+        BuildPathsWithResults => {
+            let mut result = vec![Field::Str("derived path")];
+            result.extend(build_result(minor));
+            vec![Field::List("results", result)]
+        }
+        BuildDerivation => build_result(minor),
+        FindRoots => vec![string_pairs("roots")],
+        CollectGarbage => vec![
+            strings("paths"),
+            Field::U64("bytes freed"),
+            Field::U64("obsolete"),
+        ],
+        QueryMissing => vec![
+            strings("will build"),
+            strings("will substitute"),
+            strings("unknown"),
+            Field::U64("download size"),
+            Field::U64("nar size"),
+        ],
+        QueryRealisation => vec![strings("realisations")],
+        NixrsCompression => vec![Field::Bool("accepted")],
+        _ => return None,
+    };
+    Some(ret)
+}
+
+struct Dissector<'a> {
+    client: Decoder<'a>,
+    daemon: Decoder<'a>,
+    minor: u64,
+    depth: usize,
+    dissection: Dissection,
+}
+
+impl<'a> Dissector<'a> {
+    fn decoder(&mut self, direction: Direction) -> &mut Decoder<'a> {
+        match direction {
+            Direction::ClientToDaemon => &mut self.client,
+            Direction::DaemonToClient => &mut self.daemon,
+        }
+    }
+
+    fn line(&mut self, direction: Direction, offset: usize, text: String) {
+        self.dissection.lines.push(DissectLine {
+            direction,
+            offset,
+            depth: self.depth,
+            text,
+        });
+    }
+
+    fn expect_magic(&mut self, direction: Direction, magic: u64, name: &str) -> Result<(), String> {
+        let offset = self.decoder(direction).pos;
+        let value = self.decoder(direction).u64()?;
+        if value != magic {
+            return Err(format!("expected {} but got {:#x}", name, value));
+        }
+        self.line(direction, offset, name.into());
+        Ok(())
+    }
+
+    fn u64(&mut self, direction: Direction, name: &str) -> Result<u64, String> {
+        let offset = self.decoder(direction).pos;
+        let value = self.decoder(direction).u64()?;
+        self.line(direction, offset, format!("{}: {}", name, value));
+        Ok(value)
+    }
+
+    fn string(&mut self, direction: Direction, name: &str) -> Result<(), String> {
+        let offset = self.decoder(direction).pos;
+        let value = self.decoder(direction).string()?;
+        self.line(
+            direction,
+            offset,
+            format!("{}: {}", name, show_string(value)),
+        );
+        Ok(())
+    }
+
+    fn run(&mut self) -> Result<(), String> {
+        use Direction::*;
+        if self.client.at_end() && self.daemon.at_end() {
+            return Ok(());
+        }
+        self.expect_magic(ClientToDaemon, WORKER_MAGIC_1, "client magic")?;
+        let client_version = self.u64(ClientToDaemon, "client version")?;
+        let mut version = client_version;
+        if !self.daemon.at_end() {
+            self.expect_magic(DaemonToClient, WORKER_MAGIC_2, "daemon magic")?;
+            let daemon_version = self.u64(DaemonToClient, "daemon version")?;
+            version = version.min(daemon_version);
+        }
+        self.minor = get_protocol_minor!(version);
+        self.dissection.version = Some(version);
+        if self.minor >= 14 && !self.client.at_end() {
+            let offset = self.client.pos;
+            if self.client.u64()? != 0 {
+                let cpu = self.client.u64()?;
+                self.line(ClientToDaemon, offset, format!("cpu affinity: {}", cpu));
+            } else {
+                self.line(ClientToDaemon, offset, "cpu affinity: none".into());
+            }
+        }
+        if self.minor >= 11 && !self.client.at_end() {
+            self.u64(ClientToDaemon, "reserve space")?;
+        }
+        if !self.daemon.at_end() {
+            if self.minor >= 33 {
+                self.string(DaemonToClient, "daemon nix version")?;
+            }
+            if self.minor >= 35 {
+                self.u64(DaemonToClient, "trust level")?;
+            }
+            self.stderr()?;
+        }
+
+        while !self.client.at_end() {
+            let offset = self.client.pos;
+            let op = WorkerProtoOp::from(self.client.u64()?);
+            self.line(ClientToDaemon, offset, format!("op {:?}", op));
+            let fields = request_fields(op, self.minor)
+                .ok_or_else(|| format!("arguments of {:?} are not known", op))?;
+            self.depth += 1;
+            self.fields(ClientToDaemon, &fields)?;
+            self.depth -= 1;
+
+            if self.daemon.at_end() {
+                break;
+            }
+            if self.stderr()? {
+                let fields = reply_fields(op, self.minor)
+                    .ok_or_else(|| format!("reply to {:?} is not known", op))?;
+                self.depth += 1;
+                self.fields(DaemonToClient, &fields)?;
+                self.depth -= 1;
+            }
+        }
+        if !self.daemon.at_end() {
+            let offset = self.daemon.pos;
+            let left = self.daemon.data.len() - offset;
+            self.line(DaemonToClient, offset, format!("{} trailing bytes", left));
+        }
+        Ok(())
+    }
+
+    /// Decode log messages up to `STDERR_LAST`, which is followed by a
+    /// reply, or an error, which is not.
+    fn stderr(&mut self) -> Result<bool, String> {
+        use Direction::DaemonToClient as D;
+        loop {
+            let offset = self.daemon.pos;
+            let msg = self.daemon.u64()?;
+            let (text, fields) = match msg {
+                STDERR_LAST => {
+                    self.line(D, offset, "STDERR_LAST".into());
+                    return Ok(true);
+                }
+                STDERR_NEXT => {
+                    let s = self.daemon.string()?;
+                    (format!("STDERR_NEXT {}", show_string(s)), vec![])
+                }
+                STDERR_READ => {
+                    let len = self.daemon.u64()?;
+                    (format!("STDERR_READ {} bytes", len), vec![])
+                }
+                STDERR_WRITE => {
+                    let s = self.daemon.string()?;
+                    (format!("STDERR_WRITE {}", show_string(s)), vec![])
+                }
+                STDERR_ERROR => {
+                    self.line(D, offset, "STDERR_ERROR".into());
+                    self.depth += 1;
+                    let res = self.error();
+                    self.depth -= 1;
+                    return res.map(|_| false);
+                }
+                STDERR_START_ACTIVITY => (
+                    "STDERR_START_ACTIVITY".to_string(),
+                    vec![
+                        Field::U64("id"),
+                        Field::U64("level"),
+                        Field::U64("type"),
+                        Field::Str("text"),
+                        Field::LoggerFields("fields"),
+                        Field::U64("parent"),
+                    ],
+                ),
+                STDERR_STOP_ACTIVITY => {
+                    let id = self.daemon.u64()?;
+                    (format!("STDERR_STOP_ACTIVITY {}", id), vec![])
+                }
+                STDERR_RESULT => (
+                    "STDERR_RESULT".to_string(),
+                    vec![
+                        Field::U64("id"),
+                        Field::U64("type"),
+                        Field::LoggerFields("fields"),
+                    ],
+                ),
+                msg => {
+                    return Err(format!(
+                        "unknown log message {:#x} at offset {:#x}",
+                        msg, offset
+                    ))
+                }
+            };
+            self.line(D, offset, text);
+            self.depth += 1;
+            self.fields(D, &fields)?;
+            self.depth -= 1;
+        }
+    }
+
+    fn error(&mut self) -> Result<(), String> {
+        use Direction::DaemonToClient as D;
+        if self.minor >= 26 {
+            self.string(D, "type")?;
+            self.u64(D, "level")?;
+            self.string(D, "name")?;
+            self.string(D, "message")?;
+            self.u64(D, "have position")?;
+            let traces = self.u64(D, "traces")?;
+            for _ in 0..traces {
+                self.u64(D, "have position")?;
+                self.string(D, "trace")?;
+            }
+        } else {
+            self.string(D, "message")?;
+            self.u64(D, "status")?;
+        }
+        Ok(())
+    }
+
+    fn fields(&mut self, direction: Direction, fields: &[Field]) -> Result<(), String> {
+        for field in fields {
+            self.field(direction, field)?;
+        }
+        Ok(())
+    }
+
+    fn field(&mut self, direction: Direction, field: &Field) -> Result<(), String> {
+        let label = |name: &str, value: String| {
+            if name.is_empty() {
+                value
+            } else {
+                format!("{}: {}", name, value)
+            }
+        };
+        let offset = self.decoder(direction).pos;
+        match field {
+            Field::U64(name) => {
+                let value = self.decoder(direction).u64()?;
+                self.line(direction, offset, label(name, value.to_string()));
+            }
+            Field::Bool(name) => {
+                let value = match self.decoder(direction).u64()? {
+                    0 => "false".to_string(),
+                    1 => "true".to_string(),
+                    v => format!("invalid bool {}", v),
+                };
+                self.line(direction, offset, label(name, value));
+            }
+            Field::Str(name) => {
+                let value = self.decoder(direction).string()?;
+                self.line(direction, offset, label(name, show_string(value)));
+            }
+            Field::List(name, element) => {
+                let len = self.decoder(direction).len()?;
+                self.line(direction, offset, label(name, format!("{} items", len)));
+                self.depth += 1;
+                for _ in 0..len {
+                    self.fields(direction, element)?;
+                }
+                self.depth -= 1;
+            }
+            Field::Optional(name, fields) => {
+                let flag = self.decoder(direction).u64()?;
+                self.line(direction, offset, label(name, (flag != 0).to_string()));
+                if flag != 0 {
+                    self.depth += 1;
+                    self.fields(direction, fields)?;
+                    self.depth -= 1;
+                }
+            }
+            Field::Framed(name) => {
+                let decoder = self.decoder(direction);
+                let mut frames = 0;
+                let mut size = 0;
+                loop {
+                    let len = decoder.len()?;
+                    if len == 0 {
+                        break;
+                    }
+                    decoder.take(len)?;
+                    frames += 1;
+                    size += len;
+                }
+                let value = format!("{} bytes in {} frames", size, frames);
+                self.line(direction, offset, label(name, value));
+            }
+            Field::Nar(name) => {
+                let decoder = self.decoder(direction);
+                let mut depth = 0;
+                loop {
+                    match decoder.string()? {
+                        // These are followed by arbitrary strings that
+                        // must not be taken for parentheses.
+                        b"contents" | b"target" | b"name" => {
+                            decoder.string()?;
+                        }
+                        b"(" => depth += 1,
+                        b")" => {
+                            depth -= 1;
+                            if depth == 0 {
+                                break;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                let size = self.decoder(direction).pos - offset;
+                self.line(
+                    direction,
+                    offset,
+                    label(name, format!("NAR of {} bytes", size)),
+                );
+            }
+            Field::LoggerFields(name) => {
+                let len = self.decoder(direction).len()?;
+                self.line(direction, offset, label(name, format!("{} fields", len)));
+                self.depth += 1;
+                for _ in 0..len {
+                    let offset = self.decoder(direction).pos;
+                    match self.decoder(direction).u64()? {
+                        0 => {
+                            let value = self.decoder(direction).u64()?;
+                            self.line(direction, offset, value.to_string());
+                        }
+                        1 => {
+                            let value = self.decoder(direction).string()?;
+                            self.line(direction, offset, show_string(value));
+                        }
+                        t => return Err(format!("unknown logger field type {}", t)),
+                    }
+                }
+                self.depth -= 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::daemon::transcripts::{client_hello, server_hello};
+
+    fn word(buf: &mut Vec<u8>, value: u64) {
+        buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn string(buf: &mut Vec<u8>, value: &[u8]) {
+        word(buf, value.len() as u64);
+        buf.extend_from_slice(value);
+        buf.resize(buf.len() + (8 - value.len() % 8) % 8, 0);
+    }
+
+    fn texts(dissection: &Dissection) -> Vec<(Direction, usize, &str)> {
+        dissection
+            .lines
+            .iter()
+            .map(|line| (line.direction, line.depth, line.text.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn test_dissect_query_path_info() {
+        use Direction::*;
+        let path = b"/nix/store/7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-konsole-18.12.3";
+        let mut client = client_hello(35);
+        word(&mut client, 26);
+        string(&mut client, path);
+
+        let mut daemon = server_hello(35, b"nix.rs 1.2.3", 1);
+        word(&mut daemon, STDERR_NEXT);
+        string(&mut daemon, b"querying");
+        word(&mut daemon, STDERR_LAST);
+        word(&mut daemon, 0);
+
+        let dissection = dissect(&client, &daemon);
+        assert_eq!(dissection.error, None);
+        assert_eq!(dissection.version, Some(1 << 8 | 35));
+        let path_line = format!("path: {:?}", std::str::from_utf8(path).unwrap());
+        assert_eq!(
+            texts(&dissection),
+            vec![
+                (ClientToDaemon, 0, "client magic"),
+                (ClientToDaemon, 0, "client version: 291"),
+                (DaemonToClient, 0, "daemon magic"),
+                (DaemonToClient, 0, "daemon version: 291"),
+                (ClientToDaemon, 0, "cpu affinity: none"),
+                (ClientToDaemon, 0, "reserve space: 0"),
+                (DaemonToClient, 0, "daemon nix version: \"nix.rs 1.2.3\""),
+                (DaemonToClient, 0, "trust level: 1"),
+                (DaemonToClient, 0, "STDERR_LAST"),
+                (ClientToDaemon, 0, "op QueryPathInfo"),
+                (ClientToDaemon, 1, path_line.as_str()),
+                (DaemonToClient, 0, "STDERR_NEXT \"querying\""),
+                (DaemonToClient, 0, "STDERR_LAST"),
+                (DaemonToClient, 1, "valid: false"),
+            ]
+        );
+        assert_eq!(dissection.lines[9].offset, 32);
+    }
+
+    #[test]
+    fn test_dissect_unknown_op() {
+        let mut client = client_hello(35);
+        word(&mut client, 99);
+        let dissection = dissect(&client, &server_hello(35, b"nix.rs 1.2.3", 1));
+        assert_eq!(
+            dissection.error.as_deref(),
+            Some("arguments of Unknown(99) are not known")
+        );
+    }
+
+    #[test]
+    fn test_split_capture() {
+        let mut capture = Vec::new();
+        for (direction, data) in [(b'>', &b"ab"[..]), (b'<', &b"c"[..]), (b'>', &b"d"[..])] {
+            capture.push(direction);
+            capture.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            capture.extend_from_slice(data);
+        }
+        assert_eq!(
+            split_capture(&capture).unwrap(),
+            (b"abd".to_vec(), b"c".to_vec())
+        );
+        assert!(split_capture(&capture[..capture.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,302 @@
+//! Golden files for the daemon protocol wire format.
+//!
+//! [`golden_values`] serializes a few representative values of each type the
+//! daemon protocol sends at every protocol version we support. The files
+//! are named like the characterization test data of C++ Nix
+//! (`src/libstore-tests/data/worker-protocol`) so the fixtures produced
+//! there can be dropped into a directory and checked with
+//! [`assert_fixture`]:
+//!
+//! ```ignore
+//! # use nixrs::store::daemon::golden::{assert_fixture, golden_values};
+//! # use nixrs::store_path::StoreDir;
+//! # #[tokio::main]
+//! # async fn main() {
+//! for golden in golden_values(&StoreDir::default()).await.unwrap() {
+//!     assert_fixture("tests/data/worker-protocol", &golden);
+//! }
+//! # }
+//! ```
+//!
+//! Like in Nix, setting `_NIX_TEST_ACCEPT=1` makes [`assert_fixture`] write
+//! the value to the fixture instead of comparing against it.
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::hash::Hash;
+use crate::io::AsyncSink;
+use crate::path_info::ValidPathInfo;
+use crate::store::{DerivedPath, Error, Verbosity};
+use crate::store_path::{StoreDir, StorePath};
+
+use super::ProtocolRange;
+
+/// Oldest protocol minor version golden files are made for.
+pub const MIN_GOLDEN_MINOR: u64 = 21;
+
+const ACCEPT_VAR: &str = "_NIX_TEST_ACCEPT";
+
+/// How many bytes around the first difference are shown.
+const CONTEXT: usize = 16;
+
+/// Serialized value and the name of the file it is kept in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Golden {
+    pub name: String,
+    pub bytes: Vec<u8>,
+}
+
+impl Golden {
+    pub fn new<N: Into<String>>(name: N, bytes: Vec<u8>) -> Golden {
+        Golden {
+            name: name.into(),
+            bytes,
+        }
+    }
+
+    /// Name for a type whose encoding depends on the protocol version.
+    fn versioned(kind: &str, minor: u64, bytes: Vec<u8>) -> Golden {
+        Golden::new(format!("{}-1.{}", kind, minor), bytes)
+    }
+
+    pub fn file_name(&self) -> String {
+        format!("{}.bin", self.name)
+    }
+}
+
+fn path(name: &str) -> StorePath {
+    StorePath::new_from_base_name(&format!("g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-{}", name)).unwrap()
+}
+
+fn path_infos(store_dir: &StoreDir) -> Vec<ValidPathInfo> {
+    let nar_hash =
+        Hash::parse_any_prefixed("sha256-FePFYIlMuycIXPZbWi7LGEiMmZSX9FMbaQenWBzm1Sc=").unwrap();
+    let simple = ValidPathInfo::builder(path("foo"), nar_hash)
+        .nar_size(34878)
+        .registration_time_secs(23423)
+        .build(store_dir)
+        .unwrap();
+    let full = ValidPathInfo::builder(path("bar"), nar_hash)
+        .nar_size(34878)
+        .registration_time_secs(23423)
+        .deriver(path("bar.drv"))
+        .references([path("foo"), path("bar")])
+        .ultimate(true)
+        .build(store_dir)
+        .unwrap();
+    vec![simple, full]
+}
+
+fn derived_paths(store_dir: &StoreDir) -> Vec<DerivedPath> {
+    let opaque = store_dir.print_path(&path("foo"));
+    let drv = store_dir.print_path(&path("bar.drv"));
+    [opaque, format!("{}^*", drv), format!("{}^bar,foo", drv)]
+        .iter()
+        .map(|s| DerivedPath::parse(store_dir, s).unwrap())
+        .collect()
+}
+
+fn errors() -> Vec<Error> {
+    vec![
+        Error::Custom(1, "no such path".into()),
+        Error::ErrorInfo {
+            level: Verbosity::Error,
+            msg: "builder failed".into(),
+            traces: vec!["while building".into(), "while evaluating".into()],
+        },
+    ]
+}
+
+/// Serializes the representative values of every wire type.
+///
+/// Types whose encoding depends on the protocol version get one golden file
+/// per minor version from [`MIN_GOLDEN_MINOR`] up to the one we speak,
+/// leaving out versions that can't send them at all.
+pub async fn golden_values(store_dir: &StoreDir) -> Result<Vec<Golden>, Error> {
+    let mut ret = Vec::new();
+
+    let mut bytes = Vec::new();
+    for s in [
+        "",
+        "hi",
+        "white rabbit",
+        "大白兔",
+        "oh no \0\0\0 what was that!",
+    ] {
+        bytes.write_str(s).await?;
+    }
+    ret.push(Golden::new("string", bytes));
+
+    let mut bytes = Vec::new();
+    for p in [path("foo"), path("foo-bar")] {
+        bytes.write_printed(store_dir, &p).await?;
+    }
+    ret.push(Golden::new("store-path", bytes));
+
+    let infos = path_infos(store_dir);
+    let derived = derived_paths(store_dir);
+    let errors = errors();
+    for version in ProtocolRange::from_version(1 << 8 | MIN_GOLDEN_MINOR).iter_versions() {
+        let minor = super::get_protocol_minor!(version);
+
+        let mut bytes = Vec::new();
+        for info in infos.iter() {
+            info.write(&mut bytes, store_dir, version, false).await?;
+        }
+        ret.push(Golden::versioned("valid-path-info", minor, bytes));
+
+        // Older versions send derived paths as store paths with outputs.
+        if minor >= 30 {
+            let mut bytes = Vec::new();
+            for p in derived.iter() {
+                bytes.write_printed(store_dir, p).await?;
+            }
+            ret.push(Golden::versioned("derived-path", minor, bytes));
+        }
+
+        // Older versions send errors as a plain string and exit status.
+        if minor >= 26 {
+            let mut bytes = Vec::new();
+            for err in errors.iter() {
+                err.write(&mut bytes).await?;
+            }
+            ret.push(Golden::versioned("error", minor, bytes));
+        }
+    }
+    Ok(ret)
+}
+
+/// Writes every golden value to its file in `dir`.
+pub fn write_fixtures<P: AsRef<Path>>(dir: P, goldens: &[Golden]) -> io::Result<()> {
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir)?;
+    for golden in goldens {
+        fs::write(dir.join(golden.file_name()), &golden.bytes)?;
+    }
+    Ok(())
+}
+
+struct HexWindow<'a>(&'a [u8], usize);
+
+impl<'a> fmt::Display for HexWindow<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let start = self.1.saturating_sub(CONTEXT);
+        let end = (self.1 + CONTEXT).min(self.0.len());
+        if start < end {
+            write!(f, "{:#x}: {}", start, hex::encode(&self.0[start..end]))
+        } else {
+            write!(f, "<end of data>")
+        }
+    }
+}
+
+/// Compares a golden value against its fixture in `dir`.
+///
+/// Panics when the fixture is missing or differs, showing the first offset
+/// where they differ. With `_NIX_TEST_ACCEPT=1` in the environment the
+/// fixture is overwritten instead.
+pub fn assert_fixture<P: AsRef<Path>>(dir: P, golden: &Golden) {
+    let file = dir.as_ref().join(golden.file_name());
+    if std::env::var(ACCEPT_VAR).map(|v| v == "1").unwrap_or(false) {
+        if let Some(parent) = file.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        fs::write(&file, &golden.bytes)
+            .unwrap_or_else(|err| panic!("writing {}: {}", file.display(), err));
+        return;
+    }
+    let expected = fs::read(&file).unwrap_or_else(|err| {
+        panic!(
+            "reading {}: {} (set {}=1 to create it)",
+            file.display(),
+            err,
+            ACCEPT_VAR
+        )
+    });
+    if let Some(diff) = byte_difference(&expected, &golden.bytes) {
+        panic!("{} differs from {} {}", golden.name, file.display(), diff);
+    }
+}
+
+/// Describe where two byte strings first differ with some of the bytes
+/// around it in hex, or `None` when they are equal.
+pub fn byte_difference(expected: &[u8], actual: &[u8]) -> Option<String> {
+    if expected == actual {
+        return None;
+    }
+    let offset = expected
+        .iter()
+        .zip(actual.iter())
+        .position(|(a, b)| a != b)
+        .unwrap_or_else(|| expected.len().min(actual.len()));
+    Some(format!(
+        "at offset {:#x} ({} bytes expected, {} actual)\nexpected {}\nactual   {}",
+        offset,
+        expected.len(),
+        actual.len(),
+        HexWindow(expected, offset),
+        HexWindow(actual, offset),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::daemon::PROTOCOL_VERSION;
+
+    /// `simple` path info from the characterization tests of Nix.
+    const SIMPLE_PATH_INFO: &str = concat!(
+        "0000000000000000",
+        "4000000000000000",
+        "3135653363353630383934636262323730383563663635623561326563623138",
+        "3438386339393934393766343533316236393037613735383163653664353237",
+        "0000000000000000",
+        "7f5b000000000000",
+        "3e88000000000000",
+        "0000000000000000",
+        "0000000000000000",
+        "0000000000000000",
+    );
+
+    #[tokio::test]
+    async fn test_valid_path_info_fixture() {
+        let store_dir = StoreDir::default();
+        let dir = tempfile::tempdir().unwrap();
+        let info = &path_infos(&store_dir)[0];
+        let mut bytes = Vec::new();
+        info.write(&mut bytes, &store_dir, PROTOCOL_VERSION, false)
+            .await
+            .unwrap();
+        let golden = Golden::versioned("valid-path-info", 16, bytes);
+        std::fs::write(
+            dir.path().join("valid-path-info-1.16.bin"),
+            hex::decode(SIMPLE_PATH_INFO).unwrap(),
+        )
+        .unwrap();
+        assert_fixture(dir.path(), &golden);
+    }
+
+    #[tokio::test]
+    async fn test_golden_round_trip() {
+        let goldens = golden_values(&StoreDir::default()).await.unwrap();
+        assert!(goldens.iter().any(|g| g.name == "valid-path-info-1.21"));
+        assert!(!goldens.iter().any(|g| g.name == "derived-path-1.29"));
+        assert!(goldens.iter().any(|g| g.name == "error-1.26"));
+        let dir = tempfile::tempdir().unwrap();
+        write_fixtures(dir.path(), &goldens).unwrap();
+        for golden in goldens.iter() {
+            assert_fixture(dir.path(), golden);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "differs from")]
+    fn test_fixture_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let golden = Golden::new("string", vec![0; 8]);
+        write_fixtures(dir.path(), &[Golden::new("string", vec![1; 8])]).unwrap();
+        assert_fixture(dir.path(), &golden);
+    }
+}
@@ -1,17 +1,43 @@
 use std::fmt;
+use std::time::Duration;
 
 use derive_more::{LowerHex, UpperHex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+use crate::store::Error;
 use crate::{flag_enum::flag_enum, num_enum::num_enum};
 
+mod boxed;
 mod client;
+mod daemon_path;
+pub mod dissect;
+#[cfg(any(test, feature = "test"))]
+pub mod golden;
+mod pool;
 mod server;
 mod traits;
+#[cfg(test)]
+mod transcripts;
+mod uri;
 mod wrap;
 
-pub use client::DaemonStoreClient;
-pub use server::{run_server, run_server_raw};
-pub use traits::{DaemonStore, QueryMissingResult};
+pub use boxed::{BoxedDaemonStore, DynDaemonStore, DynReader, DynWriter};
+pub use client::{
+    CancellableClient, DaemonCapabilities, DaemonStoreBuilder, DaemonStoreClient, FeatureGate,
+    HandshakeInfo, NarDownload, ParseProtocolFeatureError, ProtocolFeature, ProtocolRange,
+};
+pub use daemon_path::{DaemonPath, DaemonPathError};
+pub use pool::{query_valid_paths_chunked, DaemonPool, PooledConnection, DEFAULT_QUERY_CHUNK_SIZE};
+pub use server::{
+    is_audited, run_server, run_server_raw, AuditLog, AuditOutcome, AuditRecord, AuditSink,
+    Builder as DaemonServerBuilder, ConnectionInfo, OpContext,
+};
+pub use traits::{DaemonStore, GCOptions, GCResults, QueryMissingResult};
+pub use uri::{
+    DaemonConnection, DaemonConnectionClient, StoreLocation, StoreParams, StoreUri,
+    DEFAULT_DAEMON_SOCKET,
+};
+pub use wrap::DaemonWrapStore;
 
 macro_rules! get_protocol_major {
     ($x:expr) => {
@@ -98,6 +124,15 @@ const WORKER_MAGIC_2: u64 = 0x6478696f;
 // Nix 2.18.1
 const PROTOCOL_VERSION: u64 = 1 << 8 | 35;
 
+/// Appended to the Nix version of nixrs daemons that can compress the
+/// connection with zstd once the client asks with
+/// [`WorkerProtoOp::NixrsCompression`]. Real Nix never sends it, so clients
+/// only ask nixrs daemons that have it turned on.
+pub const COMPRESSION_MARKER: &str = "+zstd";
+
+/// Link sent by `FindRoots` in place of roots hidden from untrusted clients.
+const CENSORED_ROOT: &str = "{censored}";
+
 const STDERR_NEXT: u64 = 0x6f6c6d67;
 const STDERR_READ: u64 = 0x64617461; // data needed from source
 const STDERR_WRITE: u64 = 0x64617416; // data for sink
@@ -154,6 +189,9 @@ num_enum! {
         AddMultipleToStore = 44,
         AddBuildLog = 45,
         BuildPathsWithResults = 46,
+        /// Not a Nix op. Only sent to nixrs daemons that announce
+        /// [`COMPRESSION_MARKER`] in their version.
+        NixrsCompression = 0x6e72_0001,
     }
 }
 
@@ -205,6 +243,7 @@ impl fmt::Display for WorkerProtoOp {
             AddMultipleToStore => write!(f, "add multiple to store"),
             AddBuildLog => write!(f, "add build log"),
             BuildPathsWithResults => write!(f, "build paths with results"),
+            NixrsCompression => write!(f, "nixrs compression"),
         }
     }
 }
@@ -216,3 +255,73 @@ flag_enum! {
         Trusted = true
     }
 }
+
+num_enum! {
+    #[enum_as(u64)]
+    /// Whether the daemon trusts the client, as sent in the handshake.
+    #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+    pub enum TrustLevel {
+        Unknown = 0,
+        Trusted = 1,
+        NotTrusted = 2,
+    }
+}
+
+impl From<Option<TrustedFlag>> for TrustLevel {
+    fn from(value: Option<TrustedFlag>) -> TrustLevel {
+        match value {
+            None => TrustLevel::Unknown,
+            Some(TrustedFlag::Trusted) => TrustLevel::Trusted,
+            Some(TrustedFlag::NotTrusted) => TrustLevel::NotTrusted,
+        }
+    }
+}
+
+impl From<TrustLevel> for Option<TrustedFlag> {
+    fn from(value: TrustLevel) -> Option<TrustedFlag> {
+        match value {
+            TrustLevel::Unknown => None,
+            TrustLevel::Trusted => Some(TrustedFlag::Trusted),
+            TrustLevel::NotTrusted => Some(TrustedFlag::NotTrusted),
+        }
+    }
+}
+
+num_enum! {
+    #[enum_as(u64)]
+    /// What `CollectGarbage` is asked to do.
+    #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+    pub enum GCAction {
+        ReturnLive = 0,
+        ReturnDead = 1,
+        DeleteDead = 2,
+        DeleteSpecific = 3,
+    }
+}
+
+/// Write an optional duration like the CPU times in build results: a tag
+/// saying whether there is a value followed by it in microseconds.
+pub(crate) async fn write_opt_micros<W>(sink: &mut W, value: Option<Duration>) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    match value {
+        Some(value) => {
+            sink.write_u64_le(1).await?;
+            sink.write_u64_le(value.as_micros() as u64).await?;
+        }
+        None => sink.write_u64_le(0).await?,
+    }
+    Ok(())
+}
+
+pub(crate) async fn read_opt_micros<R>(source: &mut R) -> Result<Option<Duration>, Error>
+where
+    R: AsyncRead + Unpin,
+{
+    match source.read_u64_le().await? {
+        0 => Ok(None),
+        1 => Ok(Some(Duration::from_micros(source.read_u64_le().await?))),
+        tag => Err(Error::InvalidOptionalTag(tag)),
+    }
+}
@@ -0,0 +1,245 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+use futures::{StreamExt, TryStreamExt};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tracing::debug;
+
+use crate::store::{Error, Store, SubstituteFlag};
+use crate::store_path::{StoreDir, StorePath, StorePathSet};
+
+use super::uri::{DaemonConnection, DaemonConnectionClient, StoreUri};
+
+/// Connections to one daemon store, at most `max-connections` at a time.
+///
+/// Connections are opened when needed and kept open for the next caller
+/// once the [`PooledConnection`] is dropped.
+#[derive(Debug)]
+pub struct DaemonPool {
+    uri: StoreUri,
+    store_dir: StoreDir,
+    idle: Mutex<Vec<DaemonConnection>>,
+    permits: Semaphore,
+}
+
+impl DaemonPool {
+    pub fn new(uri: StoreUri, store_dir: StoreDir) -> DaemonPool {
+        let permits = Semaphore::new(uri.params.max_connections);
+        DaemonPool {
+            uri,
+            store_dir,
+            idle: Mutex::new(Vec::new()),
+            permits,
+        }
+    }
+
+    pub fn uri(&self) -> &StoreUri {
+        &self.uri
+    }
+
+    /// Waits until fewer than `max-connections` connections are in use and
+    /// returns an idle one, or opens a new one.
+    pub async fn get(&self) -> Result<PooledConnection<'_>, Error> {
+        let permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("pool semaphore is never closed");
+        let idle = self.idle.lock().unwrap().pop();
+        let conn = match idle {
+            Some(conn) => conn,
+            None => self.uri.connect(self.store_dir.clone()).await?,
+        };
+        Ok(PooledConnection {
+            pool: self,
+            conn: Some(conn),
+            _permit: permit,
+        })
+    }
+
+    /// Closes all idle connections.
+    pub async fn close(&self) -> Result<(), Error> {
+        let idle = std::mem::take(&mut *self.idle.lock().unwrap());
+        for conn in idle {
+            conn.close().await?;
+        }
+        Ok(())
+    }
+}
+
+/// How many paths [`query_valid_paths_chunked`] sends in one request.
+/// The daemon reads a request whole before answering it, so very large
+/// sets are better split.
+pub const DEFAULT_QUERY_CHUNK_SIZE: usize = 10_000;
+
+/// Checks which of `paths` are valid in chunks of at most `chunk_size`
+/// paths, running as many chunks at once as the pool has connections.
+pub async fn query_valid_paths_chunked(
+    pool: &DaemonPool,
+    paths: &StorePathSet,
+    chunk_size: usize,
+    maybe_substitute: SubstituteFlag,
+) -> Result<StorePathSet, Error> {
+    let chunk_size = chunk_size.max(1);
+    let parallelism = pool.uri.params.max_connections.max(1);
+    let paths: Vec<&StorePath> = paths.iter().collect();
+    debug!("querying {} paths in chunks of {}", paths.len(), chunk_size);
+    futures::stream::iter(paths.chunks(chunk_size))
+        .map(|chunk| async move {
+            let chunk: StorePathSet = chunk.iter().map(|path| (*path).clone()).collect();
+            let mut conn = pool.get().await?;
+            match conn.query_valid_paths(&chunk, maybe_substitute).await {
+                Ok(valid) => Ok(valid),
+                Err(err) => {
+                    conn.discard();
+                    Err(err)
+                }
+            }
+        })
+        .buffer_unordered(parallelism)
+        .try_fold(StorePathSet::new(), |mut ret, valid| async move {
+            ret.extend(valid);
+            Ok(ret)
+        })
+        .await
+}
+
+/// A connection borrowed from a [`DaemonPool`]. It goes back to the pool
+/// when dropped.
+#[derive(Debug)]
+pub struct PooledConnection<'a> {
+    pool: &'a DaemonPool,
+    conn: Option<DaemonConnection>,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl<'a> PooledConnection<'a> {
+    /// Drops the connection instead of returning it to the pool. Call this
+    /// when an operation failed in a way that may have left the connection
+    /// out of sync with the daemon.
+    pub fn discard(mut self) {
+        self.conn.take();
+    }
+}
+
+impl<'a> Deref for PooledConnection<'a> {
+    type Target = DaemonConnectionClient;
+
+    fn deref(&self) -> &Self::Target {
+        &self.conn.as_ref().unwrap().client
+    }
+}
+
+impl<'a> DerefMut for PooledConnection<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.conn.as_mut().unwrap().client
+    }
+}
+
+impl<'a> Drop for PooledConnection<'a> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.idle.lock().unwrap().push(conn);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::net::UnixListener;
+
+    use super::*;
+    use crate::archive::test_data;
+    use crate::hash;
+    use crate::path_info::ValidPathInfo;
+    use crate::store::daemon::{run_server, DaemonStore, TrustedFlag};
+    use crate::store::{CheckSignaturesFlag, MemoryStore, RepairFlag, Store};
+    use crate::store_path::StorePath;
+
+    async fn serve(listener: UnixListener, store: MemoryStore) {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let store = store.clone();
+            tokio::spawn(async move {
+                let (reader, writer) = stream.into_split();
+                let _ = run_server(reader, writer, store, TrustedFlag::Trusted).await;
+            });
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_connections() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("socket");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let server = tokio::spawn(serve(listener, MemoryStore::new()));
+
+        let uri: StoreUri = format!("unix://{}?max-connections=2", socket.display())
+            .parse()
+            .unwrap();
+        let pool = DaemonPool::new(uri, StoreDir::default());
+        let mut first = pool.get().await.unwrap();
+        let second = pool.get().await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(50), pool.get())
+            .await
+            .is_err());
+
+        let path = StorePath::test_from_seed("missing");
+        assert!(!first.is_valid_path(&path).await.unwrap());
+        assert!(first.query_path_info(&path).await.unwrap().is_none());
+        drop(first);
+        second.discard();
+        // The first connection is reused and the discarded one reopened.
+        let mut again = pool.get().await.unwrap();
+        let _other = pool.get().await.unwrap();
+        assert!(!again.is_valid_path(&path).await.unwrap());
+        drop(again);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_query_valid_paths_chunked() {
+        let mut nar = bytes::BytesMut::new();
+        for event in test_data::text_file() {
+            event.encode_into(&mut nar);
+        }
+        let mut store = MemoryStore::new();
+        let mut paths = StorePathSet::new();
+        let mut valid = StorePathSet::new();
+        for i in 0..25 {
+            let path = StorePath::test_from_seed(&format!("path{}", i));
+            if i % 3 == 0 {
+                let mut info =
+                    ValidPathInfo::new(path.clone(), hash::digest(hash::Algorithm::SHA256, &nar));
+                info.nar_size = nar.len() as u64;
+                store
+                    .add_to_store(
+                        &info,
+                        &nar[..],
+                        RepairFlag::NoRepair,
+                        CheckSignaturesFlag::NoCheckSigs,
+                    )
+                    .await
+                    .unwrap();
+                valid.insert(path.clone());
+            }
+            paths.insert(path);
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("socket");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let server = tokio::spawn(serve(listener, store));
+        let uri: StoreUri = format!("unix://{}?max-connections=3", socket.display())
+            .parse()
+            .unwrap();
+        let pool = DaemonPool::new(uri, StoreDir::default());
+        let res = query_valid_paths_chunked(&pool, &paths, 4, SubstituteFlag::NoSubstitute)
+            .await
+            .unwrap();
+        assert_eq!(res, valid);
+        server.abort();
+    }
+}
@@ -0,0 +1,221 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+use tracing::error;
+
+use super::{ConnectionInfo, OpContext};
+use crate::store::daemon::{get_protocol_major, get_protocol_minor, WorkerProtoOp};
+use crate::store::Error;
+
+/// Whether `op` changes the store, and so is sent to the [`AuditSink`].
+pub fn is_audited(op: WorkerProtoOp) -> bool {
+    use WorkerProtoOp::*;
+    matches!(
+        op,
+        AddToStore
+            | AddTextToStore
+            | AddToStoreNar
+            | AddMultipleToStore
+            | ImportPaths
+            | BuildPaths
+            | BuildPathsWithResults
+            | BuildDerivation
+            | AddTempRoot
+            | AddIndirectRoot
+            | CollectGarbage
+            | OptimiseStore
+            | VerifyStore
+            | AddSignatures
+            | RegisterDrvOutput
+            | AddBuildLog
+    )
+}
+
+/// How an audited operation ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditOutcome {
+    Success,
+    Failure(String),
+}
+
+/// One operation that changed, or tried to change, the store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    /// When the operation finished.
+    pub time: SystemTime,
+    pub connection: Arc<ConnectionInfo>,
+    pub trusted: bool,
+    pub op: WorkerProtoOp,
+    /// The paths the operation added, built, deleted or otherwise touched,
+    /// as far as the server can tell. Paths in the stream of
+    /// `AddMultipleToStore` are not listed.
+    pub paths: Vec<String>,
+    pub outcome: AuditOutcome,
+}
+
+impl AuditRecord {
+    pub(crate) fn new(
+        context: &OpContext,
+        op: WorkerProtoOp,
+        paths: Vec<String>,
+        res: &Result<(), Error>,
+    ) -> AuditRecord {
+        AuditRecord {
+            time: SystemTime::now(),
+            connection: context.connection.clone(),
+            trusted: context.trusted.into(),
+            op,
+            paths,
+            outcome: match res {
+                Ok(()) => AuditOutcome::Success,
+                Err(err) => AuditOutcome::Failure(err.to_string()),
+            },
+        }
+    }
+
+    /// The record as a single line of JSON.
+    pub fn to_json_string(&self) -> String {
+        let conn = &self.connection;
+        let time = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_millis() as u64)
+            .unwrap_or(0);
+        let version = conn.client_version.map(|version| {
+            format!(
+                "{}.{}",
+                get_protocol_major!(version),
+                get_protocol_minor!(version)
+            )
+        });
+        let (outcome, error) = match &self.outcome {
+            AuditOutcome::Success => ("success", None),
+            AuditOutcome::Failure(msg) => ("failure", Some(msg)),
+        };
+        json!({
+            "time_ms": time,
+            "uid": conn.peer_uid,
+            "gid": conn.peer_gid,
+            "pid": conn.peer_pid,
+            "remote_addr": conn.remote_addr,
+            "protocol": version,
+            "trusted": self.trusted,
+            "op": self.op.to_string(),
+            "paths": self.paths,
+            "outcome": outcome,
+            "error": error,
+        })
+        .to_string()
+    }
+}
+
+/// Where the daemon server sends an [`AuditRecord`] for every operation
+/// that changes the store.
+///
+/// Records are sent as each operation finishes, from the task serving the
+/// connection, so sinks should be quick about it. Any
+/// `Fn(&AuditRecord)` closure is a sink.
+pub trait AuditSink: Send + Sync {
+    fn record(&self, record: &AuditRecord);
+}
+
+impl<F> AuditSink for F
+where
+    F: Fn(&AuditRecord) + Send + Sync,
+{
+    fn record(&self, record: &AuditRecord) {
+        self(record)
+    }
+}
+
+/// An [`AuditSink`] that appends each record to a file as a line of JSON.
+#[derive(Debug)]
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    /// Append to the file at `path`, creating it if needed.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<AuditLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl AuditSink for AuditLog {
+    fn record(&self, record: &AuditRecord) {
+        let mut line = record.to_json_string();
+        line.push('\n');
+        let mut file = self.file.lock().unwrap();
+        if let Err(err) = file.write_all(line.as_bytes()) {
+            error!("Could not write audit record: {}", err);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Audit(pub(crate) Arc<dyn AuditSink>);
+
+impl fmt::Debug for Audit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Audit")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::daemon::TrustedFlag;
+
+    #[test]
+    fn test_audit_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let log = AuditLog::open(&path).unwrap();
+        let mut info = ConnectionInfo::new();
+        info.peer_uid = Some(1000);
+        info.client_version = Some(0x125);
+        let context = OpContext::new(Arc::new(info), TrustedFlag::NotTrusted);
+        let paths = vec!["/nix/store/ldzm8n5lx3ijd6rjvmz6kr9rvfrb0x7a-hello".to_string()];
+        log.record(&AuditRecord::new(
+            &context,
+            WorkerProtoOp::CollectGarbage,
+            paths,
+            &Err(Error::IgnoreLivenessNotAllowed),
+        ));
+        log.record(&AuditRecord::new(
+            &context,
+            WorkerProtoOp::OptimiseStore,
+            Vec::new(),
+            &Ok(()),
+        ));
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["uid"], 1000);
+        assert_eq!(lines[0]["protocol"], "1.37");
+        assert_eq!(lines[0]["trusted"], false);
+        assert_eq!(lines[0]["outcome"], "failure");
+        assert_eq!(
+            lines[0]["error"],
+            Error::IgnoreLivenessNotAllowed.to_string()
+        );
+        assert_eq!(
+            lines[0]["paths"][0],
+            "/nix/store/ldzm8n5lx3ijd6rjvmz6kr9rvfrb0x7a-hello"
+        );
+        assert_eq!(lines[1]["outcome"], "success");
+        assert!(lines[1]["error"].is_null());
+    }
+}
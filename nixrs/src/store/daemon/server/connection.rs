@@ -0,0 +1,239 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use pin_project_lite::pin_project;
+
+use crate::store::daemon::{get_protocol_major, get_protocol_minor, TrustedFlag, WorkerProtoOp};
+
+/// Who is on the other end of a daemon connection.
+///
+/// Every field the server knows is put on the span of each operation and
+/// on the errors it logs, so that the logs of many clients can be told
+/// apart. The wrapped store can look at it with
+/// [`ConnectionInfo::current`], or at all of the [`OpContext`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub peer_uid: Option<u32>,
+    pub peer_gid: Option<u32>,
+    pub peer_pid: Option<i32>,
+    /// Where the connection came from, e.g. a host name or mux channel.
+    pub remote_addr: Option<String>,
+    /// The protocol version agreed on with the client. Set by the server
+    /// once the greeting has been exchanged.
+    pub client_version: Option<u64>,
+}
+
+impl ConnectionInfo {
+    pub fn new() -> ConnectionInfo {
+        Default::default()
+    }
+
+    /// Info for a connection to a unix socket, with the credentials of the
+    /// process that made it.
+    #[cfg(unix)]
+    pub fn from_unix_stream(stream: &tokio::net::UnixStream) -> ConnectionInfo {
+        let mut info = ConnectionInfo::new();
+        if let Ok(cred) = stream.peer_cred() {
+            info.peer_uid = Some(cred.uid());
+            info.peer_gid = Some(cred.gid());
+            info.peer_pid = cred.pid();
+        }
+        if let Ok(addr) = stream.peer_addr() {
+            if let Some(path) = addr.as_pathname() {
+                info.remote_addr = Some(path.display().to_string());
+            }
+        }
+        info
+    }
+
+    pub fn remote_addr(mut self, addr: impl Into<String>) -> ConnectionInfo {
+        self.remote_addr = Some(addr.into());
+        self
+    }
+
+    /// The connection the server is performing an operation for, when
+    /// called from a store it is serving.
+    pub fn current() -> Option<Arc<ConnectionInfo>> {
+        CURRENT_CONTEXT.with(|current| {
+            current
+                .borrow()
+                .as_ref()
+                .map(|context| context.connection.clone())
+        })
+    }
+}
+
+impl fmt::Display for ConnectionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut sep = "";
+        if let Some(uid) = self.peer_uid {
+            write!(f, "uid {}", uid)?;
+            sep = " ";
+        }
+        if let Some(pid) = self.peer_pid {
+            write!(f, "{}pid {}", sep, pid)?;
+            sep = " ";
+        }
+        if let Some(addr) = &self.remote_addr {
+            write!(f, "{}from {}", sep, addr)?;
+            sep = " ";
+        }
+        if let Some(version) = self.client_version {
+            write!(
+                f,
+                "{}protocol {}.{}",
+                sep,
+                get_protocol_major!(version),
+                get_protocol_minor!(version)
+            )?;
+            sep = " ";
+        }
+        if sep.is_empty() {
+            f.write_str("unknown client")?;
+        }
+        Ok(())
+    }
+}
+
+/// What the daemon server knows about the operation a store is called
+/// for.
+///
+/// The server makes it available to the store it serves while each
+/// operation is performed, so that stores can audit or decide on calls
+/// without any change to the store traits. Stores that are called any other
+/// way see the [`anonymous`](OpContext::anonymous) context.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpContext {
+    pub connection: Arc<ConnectionInfo>,
+    /// Whether the server trusts the client.
+    pub trusted: TrustedFlag,
+    /// The operation being performed, when there is one.
+    pub op: Option<WorkerProtoOp>,
+    /// Settings the client sent with its last `SetOptions`, by their name
+    /// in `nix.conf`, including the ones the server doesn't know.
+    pub client_options: Arc<BTreeMap<String, String>>,
+}
+
+impl OpContext {
+    /// The context of a store that is used directly instead of through a
+    /// daemon connection: an unknown client. Stores that decide on calls by
+    /// their context can't tell who it is, so it isn't trusted.
+    pub fn anonymous() -> OpContext {
+        OpContext {
+            connection: Arc::new(ConnectionInfo::new()),
+            trusted: TrustedFlag::NotTrusted,
+            op: None,
+            client_options: Default::default(),
+        }
+    }
+
+    pub(crate) fn new(connection: Arc<ConnectionInfo>, trusted: TrustedFlag) -> OpContext {
+        OpContext {
+            connection,
+            trusted,
+            op: None,
+            client_options: Default::default(),
+        }
+    }
+
+    /// The context of the operation the server is performing, or the
+    /// anonymous one when not called from a daemon server.
+    pub fn current() -> OpContext {
+        CURRENT_CONTEXT
+            .with(|current| current.borrow().clone())
+            .unwrap_or_else(OpContext::anonymous)
+    }
+
+    /// The protocol version agreed on with the client.
+    pub fn client_version(&self) -> Option<u64> {
+        self.connection.client_version
+    }
+
+    pub fn client_option(&self, name: &str) -> Option<&str> {
+        self.client_options.get(name).map(|value| value.as_str())
+    }
+}
+
+impl Default for OpContext {
+    fn default() -> Self {
+        OpContext::anonymous()
+    }
+}
+
+thread_local! {
+    static CURRENT_CONTEXT: RefCell<Option<OpContext>> = RefCell::new(None);
+}
+
+struct ResetContext(Option<OpContext>);
+
+impl Drop for ResetContext {
+    fn drop(&mut self) {
+        let prev = self.0.take();
+        let _ = CURRENT_CONTEXT.try_with(|current| *current.borrow_mut() = prev);
+    }
+}
+
+pin_project! {
+    #[derive(Debug)]
+    #[must_use = "futures do nothing unless you `.await` or poll them"]
+    pub(crate) struct WithContextFuture<F> {
+        context: OpContext,
+        #[pin]
+        inner: F,
+    }
+}
+
+impl<F: Future> Future for WithContextFuture<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let _reset = ResetContext(
+            CURRENT_CONTEXT.with(|current| current.replace(Some(this.context.clone()))),
+        );
+        this.inner.poll(cx)
+    }
+}
+
+/// Make `context` the [`OpContext::current`] context while polling `fut`.
+pub(crate) fn with_context<F: Future>(context: OpContext, fut: F) -> WithContextFuture<F> {
+    WithContextFuture {
+        context,
+        inner: fut,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_current_context() {
+        let mut info = ConnectionInfo::new().remote_addr("mux channel 3");
+        info.peer_uid = Some(1000);
+        info.client_version = Some(0x123);
+        assert_eq!(
+            info.to_string(),
+            "uid 1000 from mux channel 3 protocol 1.35"
+        );
+        assert_eq!(ConnectionInfo::new().to_string(), "unknown client");
+
+        let info = Arc::new(info);
+        let mut context = OpContext::new(info.clone(), TrustedFlag::NotTrusted);
+        context.op = Some(WorkerProtoOp::IsValidPath);
+        let seen = with_context(context.clone(), async {
+            tokio::task::yield_now().await;
+            (ConnectionInfo::current(), OpContext::current())
+        })
+        .await;
+        assert_eq!(seen, (Some(info), context));
+        assert_eq!(ConnectionInfo::current(), None);
+        assert_eq!(OpContext::current(), OpContext::anonymous());
+        assert_eq!(OpContext::current().trusted, TrustedFlag::NotTrusted);
+    }
+}
@@ -7,37 +7,62 @@ use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::task::Poll;
 
-use bytes::{Buf, Bytes, BytesMut};
+use bytes::{Bytes, BytesMut};
+use futures::stream::{FuturesUnordered, StreamExt};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
 use tokio::sync::{mpsc, oneshot};
 use tracing::field::Visit;
 use tracing::span;
-use tracing::{debug, error, instrument, trace, Event, Subscriber};
+use tracing::{debug, error, instrument, trace, warn, Event, Subscriber};
 use tracing_futures::WithSubscriber;
 use tracing_subscriber::prelude::*;
-use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::registry::{LookupSpan, SpanRef};
 use tracing_subscriber::Layer;
 use tracing_subscriber::{layer, registry};
 
 use super::{
-    get_protocol_major, get_protocol_minor, DaemonStore, TrustedFlag, WorkerProtoOp,
+    get_protocol_major, get_protocol_minor, write_opt_micros, DaemonPath, DaemonStore, GCAction,
+    GCOptions, TrustLevel, TrustedFlag, WorkerProtoOp, CENSORED_ROOT, COMPRESSION_MARKER,
     PROTOCOL_VERSION, STDERR_ERROR, STDERR_LAST, STDERR_NEXT, STDERR_READ, STDERR_RESULT,
     STDERR_START_ACTIVITY, STDERR_STOP_ACTIVITY, WORKER_MAGIC_1, WORKER_MAGIC_2,
 };
+use crate::archive::copy_nar;
 use crate::hash;
-use crate::io::{AsyncSink, AsyncSource, FramedSource, TakenStream, Taker};
+use crate::io::{
+    zstd_switch, AsyncSink, AsyncSource, FramedSource, LimitedReader, Mux, Poison, ReadBytes,
+    ReadLimits, TakenStream, Taker, WithReadLimits,
+};
 use crate::path_info::ValidPathInfo;
-use crate::signature::{ParseSignatureError, SignatureSet};
-use crate::store::activity::{ActivityResult, LoggerField, LoggerFieldType, StartActivity};
+use crate::signature::{ParseSignatureError, PublicKey, SignatureSet};
+use crate::store::activity::{
+    ActivityBuilder, ActivityId, ActivityResult, ActivityType, LoggerField, LoggerFieldType,
+    ResultKind, ResultType, StartActivity,
+};
 use crate::store::error::Verbosity;
 use crate::store::settings::{get_mut_settings, BuildSettings, WithSettings};
 use crate::store::{
-    BasicDerivation, BuildMode, CheckSignaturesFlag, DerivedPath, DrvOutputs, Error,
-    StorePathWithOutputs, SubstituteFlag,
+    add_ca_to_store, check_realisation_dependencies, BasicDerivation, BuildMode,
+    CheckSignaturesFlag, DerivedPath, DrvOutput, DrvOutputs, Error, ExperimentalFeature,
+    ExperimentalFeatures, Realisation, RepairFlag, StorePathWithOutputs, SubstituteFlag,
 };
-use crate::store_path::{StoreDir, StorePath};
+use crate::store_path::{ContentAddress, FileIngestionMethod, StoreDir, StorePath, StorePathSet};
 use crate::tracing::ParentLayer;
-
+use crate::StringSet;
+
+mod audit;
+mod connection;
+mod verify;
+
+use audit::Audit;
+pub use audit::{is_audited, AuditLog, AuditOutcome, AuditRecord, AuditSink};
+use connection::with_context;
+pub use connection::{ConnectionInfo, OpContext};
+use verify::NarVerifier;
+
+/// The verbosity the client asked for with `SetOptions`.
+///
+/// Log lines above it are not sent to the client. The same level is put in
+/// `BuildSettings::verbosity` so the wrapped store can see it too.
 #[derive(Debug, Clone)]
 struct ActiveVerbosity(Arc<AtomicU64>);
 
@@ -99,6 +124,11 @@ impl Drop for OpCounter {
     }
 }
 
+/// Write `cmd` to the client the way Nix's `TunnelLogger` does.
+///
+/// Clients older than 1.20 don't know about activities, so starting one is
+/// sent as a plain log line if it is within the client's verbosity and
+/// stopping one and results are dropped.
 async fn send_command<W>(
     level: ActiveVerbosity,
     client_version: u64,
@@ -110,13 +140,11 @@ where
 {
     match cmd {
         TunnelCommand::LogNext(msg) => {
-            eprintln!("Log next: {}", msg);
             debug!("Log next: {}", msg);
             writer.write_u64_le(STDERR_NEXT).await?;
             writer.write_string(format!("{}\n", msg)).await?;
         }
         TunnelCommand::StartActivity(id, activity) => {
-            eprintln!("start activity {}", id);
             debug!(id, "start activity {} {:?}", id, activity);
             if get_protocol_minor!(client_version) < 20 {
                 if !activity.text.is_empty() && level.get() >= activity.level {
@@ -148,7 +176,6 @@ where
             writer.write_u64_le(activity.parent).await?;
         }
         TunnelCommand::StopActivity(id) => {
-            eprintln!("stop activity {}", id);
             debug!(id, "stop activity {}", id);
             if get_protocol_minor!(client_version) < 20 {
                 return Ok(());
@@ -156,8 +183,20 @@ where
             writer.write_u64_le(STDERR_STOP_ACTIVITY).await?;
             writer.write_u64_le(id).await?;
         }
+        TunnelCommand::Result(result) if result.result_type == ResultType::DeletedPath => {
+            // Clients don't know this result type so tell them like Nix
+            // does when it deletes a path.
+            debug!("result {}, {:?}", result.act, result);
+            if level.get() >= Verbosity::Info {
+                if let Ok(ResultKind::DeletedPath { path, .. }) = result.kind() {
+                    writer.write_u64_le(STDERR_NEXT).await?;
+                    writer
+                        .write_string(format!("deleting '{}'\n", path))
+                        .await?;
+                }
+            }
+        }
         TunnelCommand::Result(result) => {
-            eprintln!("result {}, {:?}", result.act, result);
             debug!("result {}, {:?}", result.act, result);
             if get_protocol_minor!(client_version) < 20 {
                 return Ok(());
@@ -179,12 +218,6 @@ where
                 }
             }
         }
-        TunnelCommand::Read(len) => {
-            eprintln!("read {}", len);
-            debug!(len, "read {}", len);
-            writer.write_u64_le(STDERR_READ).await?;
-            writer.write_usize(len).await?;
-        }
         _ => unreachable!(),
     }
     Ok(())
@@ -204,7 +237,6 @@ async fn process_tunnel<S>(
     while let Some(cmd) = receiver.recv().await {
         match cmd {
             TunnelCommand::StartWork => {
-                eprintln!("Start work");
                 debug!("Start work");
                 let mut s = taker.take();
                 if let Err(err) = s.write_all(&buf).await {
@@ -216,7 +248,6 @@ async fn process_tunnel<S>(
                 writer = Some(s);
             }
             TunnelCommand::StopWork(err, reply) => {
-                eprintln!("Stop work");
                 debug!("Stop work");
                 let mut stream = writer.take().unwrap_or_else(|| taker.take());
                 let res = async {
@@ -235,6 +266,24 @@ async fn process_tunnel<S>(
                 drop(stream);
                 let _ = reply.send(());
             }
+            TunnelCommand::Read(len, reply) => {
+                let res = match writer.as_mut() {
+                    Some(s) => {
+                        debug!(len, "read {}", len);
+                        async {
+                            s.write_u64_le(STDERR_READ).await?;
+                            s.write_usize(len).await?;
+                            s.flush().await
+                        }
+                        .await
+                    }
+                    None => Err(io::Error::new(
+                        io::ErrorKind::NotConnected,
+                        "data was requested from the client outside of work",
+                    )),
+                };
+                let _ = reply.send(res);
+            }
             _ if writer.is_some() => {
                 let mut s = writer.as_mut().unwrap();
                 if let Err(err) = send_command(level.clone(), client_version, &mut s, cmd).await {
@@ -268,9 +317,18 @@ enum TunnelCommand {
     StartActivity(u64, StartActivity),
     StopActivity(u64),
     Result(ActivityResult),
-    Read(usize),
+    /// Asks the client for at most this many bytes with `STDERR_READ`,
+    /// replying once the request has been sent.
+    Read(usize, oneshot::Sender<io::Result<()>>),
 }
 
+/// How many commands can wait for the tunnel to write them.
+///
+/// Log lines and activities are dropped when it is full, while
+/// [`TunnelSource`] waits for room. A store that floods the log while
+/// reading a NAR is slowed down rather than have its data requests lost.
+const TUNNEL_QUEUE_LEN: usize = 1000;
+
 fn format_event(a_level: ActiveVerbosity, event: &Event<'_>) -> Option<TunnelCommand> {
     let mut fmt = EventFormat::default();
     event.record(&mut fmt);
@@ -333,12 +391,16 @@ impl<'r, R> fmt::Debug for TunnelSourceOp<'r, R> {
     }
 }
 
+/// Reads data from the client by asking for it with `STDERR_READ`, one
+/// request at a time.
 #[derive(Debug)]
 pub struct TunnelSource<'r, R> {
     state: TunnelSourceOp<'r, R>,
     buffer: BytesMut,
+    capacity: usize,
     cut_off: usize,
     sender: mpsc::Sender<TunnelCommand>,
+    broken: bool,
 }
 
 impl<'r, R> TunnelSource<'r, R> {
@@ -347,11 +409,23 @@ impl<'r, R> TunnelSource<'r, R> {
         sender: mpsc::Sender<TunnelCommand>,
         capacity: usize,
     ) -> TunnelSource<'r, R> {
+        let capacity = capacity.max(1);
         TunnelSource {
             state: TunnelSourceOp::Empty(reader),
             buffer: BytesMut::with_capacity(capacity),
+            capacity,
             cut_off: capacity / 4,
             sender,
+            broken: false,
+        }
+    }
+
+    /// Turns an I/O error the reading side ran into because of this
+    /// source into [`Error::ClientSourceBroken`].
+    fn typed_error(&self, err: Error) -> Error {
+        match err {
+            Error::IOError { source } if self.broken => Error::ClientSourceBroken(source),
+            err => err,
         }
     }
 }
@@ -372,7 +446,7 @@ where
                         buf.put_slice(&avail);
                         self.state = TunnelSourceOp::Empty(reader);
                     } else {
-                        let n = avail.split_off(buf.remaining());
+                        let n = avail.split_to(buf.remaining());
                         buf.put_slice(&n);
                         self.state = TunnelSourceOp::Available(reader, avail);
                     }
@@ -384,6 +458,7 @@ where
                         return Poll::Pending;
                     }
                     Poll::Ready(Err(err)) => {
+                        self.broken = true;
                         return Poll::Ready(Err(err));
                     }
                     Poll::Ready(Ok((avail, reader))) => {
@@ -391,23 +466,35 @@ where
                     }
                 },
                 TunnelSourceOp::Empty(reader) => {
-                    let cut_off = self.cut_off;
-                    if self.buffer.remaining() < cut_off {
-                        self.buffer.reserve(cut_off);
+                    let capacity = self.capacity;
+                    if self.buffer.capacity() < self.cut_off {
+                        self.buffer.reserve(capacity);
                     }
+                    let len = capacity.min(self.buffer.capacity());
                     let buffer = self.buffer.split_off(0);
                     let sender = self.sender.clone();
                     let fut = async move {
-                        if sender
-                            .send(TunnelCommand::Read(buffer.remaining()))
-                            .await
-                            .is_err()
-                        {
-                            return Err(io::ErrorKind::BrokenPipe.into());
+                        let (reply, sent) = oneshot::channel();
+                        if sender.send(TunnelCommand::Read(len, reply)).await.is_err() {
+                            return Err(io::Error::new(
+                                io::ErrorKind::BrokenPipe,
+                                "the tunnel to the client is closed",
+                            ));
                         }
-                        let bytes = reader.read_bytes_buf(buffer).await?;
+                        match sent.await {
+                            Ok(res) => res?,
+                            Err(_) => {
+                                return Err(io::Error::new(
+                                    io::ErrorKind::BrokenPipe,
+                                    "the tunnel to the client is closed",
+                                ))
+                            }
+                        }
+                        let bytes = ReadBytes::with_limit(&mut *reader, len, buffer).await?;
                         if bytes.is_empty() {
-                            return Err(io::ErrorKind::Unsupported.into());
+                            // Like Nix, an empty reply means the client has
+                            // nothing more to send.
+                            return Err(io::ErrorKind::UnexpectedEof.into());
                         }
                         Ok((bytes, reader))
                     };
@@ -419,6 +506,19 @@ where
     }
 }
 
+/// The activity id sent to the client, stored in the span extensions.
+struct WireActivityId(ActivityId);
+
+fn wire_activity_id<S>(span: &SpanRef<'_, S>) -> ActivityId
+where
+    for<'lookup> S: Subscriber + LookupSpan<'lookup>,
+{
+    span.extensions()
+        .get::<WireActivityId>()
+        .map(|id| id.0)
+        .unwrap_or_else(|| span.id().into_u64())
+}
+
 struct TunnelLayer {
     level: ActiveVerbosity,
     sender: mpsc::Sender<TunnelCommand>,
@@ -429,7 +529,7 @@ impl TunnelLayer {
     where
         S: AsyncWrite + Send + Unpin + 'static,
     {
-        let (sender, receiver) = mpsc::channel(1000);
+        let (sender, receiver) = mpsc::channel(TUNNEL_QUEUE_LEN);
         let sender2 = sender.clone();
         let level = ActiveVerbosity::default();
         let level2 = level.clone();
@@ -456,12 +556,22 @@ where
     for<'lookup> S: Subscriber + LookupSpan<'lookup>,
 {
     fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: layer::Context<'_, S>) {
-        if let Some(meta) = ctx.metadata(id) {
-            if meta.name() == crate::store::activity::ACTIVITY_NAME {
-                if let Ok(activity) = attrs.try_into() {
+        if let Some(span) = ctx.span(id) {
+            if span.name() == crate::store::activity::ACTIVITY_NAME {
+                if let Ok(mut activity) = StartActivity::try_from(attrs) {
+                    // Nest in the closest enclosing activity so that the
+                    // client sees the same tree as we do.
+                    let parent = span
+                        .scope()
+                        .skip(1)
+                        .find(|p| p.name() == crate::store::activity::ACTIVITY_NAME);
+                    if let Some(parent) = parent {
+                        activity.parent = wire_activity_id(&parent);
+                    }
+                    span.extensions_mut().insert(WireActivityId(activity.act));
                     if let Err(err) = self
                         .sender
-                        .try_send(TunnelCommand::StartActivity(id.into_u64(), activity))
+                        .try_send(TunnelCommand::StartActivity(activity.act, activity))
                     {
                         eprintln!("Activity start was dropped {err}")
                     }
@@ -493,7 +603,8 @@ where
                 return;
             }
             let parent = activity.unwrap();
-            if let Ok(result) = ActivityResult::from_event(event, parent.id()) {
+            if let Ok(mut result) = ActivityResult::from_event(event, parent.id()) {
+                result.act = wire_activity_id(&parent);
                 if let Err(err) = self.sender.try_send(TunnelCommand::Result(result)) {
                     eprintln!("Activity result was dropped {err}")
                 }
@@ -508,12 +619,10 @@ where
     }
 
     fn on_close(&self, id: span::Id, ctx: layer::Context<'_, S>) {
-        if let Some(meta) = ctx.metadata(&id) {
-            if meta.name() == crate::store::activity::ACTIVITY_NAME {
-                if let Err(err) = self
-                    .sender
-                    .try_send(TunnelCommand::StopActivity(id.into_u64()))
-                {
+        if let Some(span) = ctx.span(&id) {
+            if span.name() == crate::store::activity::ACTIVITY_NAME {
+                let act = wire_activity_id(&span);
+                if let Err(err) = self.sender.try_send(TunnelCommand::StopActivity(act)) {
                     eprintln!("Activity stop was dropped {err}")
                 }
             }
@@ -566,7 +675,7 @@ impl TunnelController {
         error!("stop_work_err {}", ex);
         let (s, r) = oneshot::channel();
         let mut buf = Cursor::new(Vec::new());
-        if get_protocol_major!(self.client_version) >= 26 {
+        if get_protocol_minor!(self.client_version) >= 26 {
             ex.write(&mut buf).await.unwrap();
         } else {
             buf.write_string(ex.to_string()).await.unwrap();
@@ -609,7 +718,203 @@ impl TunnelController {
     }
 }
 
-#[instrument(skip(source, out, store))]
+/// Options for serving daemon connections.
+#[derive(Debug, Clone)]
+pub struct Builder {
+    trusted: TrustedFlag,
+    verify_nar: bool,
+    read_limits: ReadLimits,
+    compression: bool,
+    trusted_keys: Vec<PublicKey>,
+    audit: Option<Audit>,
+    settings: Option<BuildSettings>,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Builder {
+            trusted: TrustedFlag::NotTrusted,
+            verify_nar: false,
+            read_limits: ReadLimits::default(),
+            compression: false,
+            trusted_keys: Vec::new(),
+            audit: None,
+            settings: None,
+        }
+    }
+}
+
+impl Builder {
+    pub fn new() -> Builder {
+        Default::default()
+    }
+
+    pub fn trusted(&mut self, trusted: TrustedFlag) -> &mut Self {
+        self.trusted = trusted;
+        self
+    }
+
+    /// Hash the NAR of `AddToStoreNar` while it is streamed to the store
+    /// and fail when it doesn't match the NAR hash and size sent by the
+    /// client. Without this the store is trusted to check them.
+    pub fn verify_nar(&mut self, verify: bool) -> &mut Self {
+        self.verify_nar = verify;
+        self
+    }
+
+    /// Bound what each connection can make the server allocate. Without
+    /// this a client can send a length prefix for a huge string or
+    /// collection.
+    pub fn read_limits(&mut self, limits: ReadLimits) -> &mut Self {
+        self.read_limits = limits;
+        self
+    }
+
+    /// Offer to compress connections with zstd. Only nixrs clients that
+    /// turned on [`DaemonStoreBuilder::compression`](crate::store::daemon::DaemonStoreBuilder::compression)
+    /// take it up, so Nix clients keep talking the plain protocol.
+    pub fn compression(&mut self, enable: bool) -> &mut Self {
+        self.compression = enable;
+        self
+    }
+
+    /// Keys that realisations registered by untrusted clients have to be
+    /// signed by. Without any, untrusted clients can't register
+    /// realisations at all.
+    pub fn trusted_keys(&mut self, keys: Vec<PublicKey>) -> &mut Self {
+        self.trusted_keys = keys;
+        self
+    }
+
+    /// Send a record of every operation that changes the store, and of
+    /// who asked for it, to `sink`.
+    pub fn audit<A: AuditSink + 'static>(&mut self, sink: A) -> &mut Self {
+        self.audit = Some(Audit(Arc::new(sink)));
+        self
+    }
+
+    /// Settings every connection starts from before the client overrides
+    /// them with `SetOptions`. Without this connections start from the
+    /// default settings of the task that serves them.
+    pub fn settings(&mut self, settings: BuildSettings) -> &mut Self {
+        self.settings = Some(settings);
+        self
+    }
+
+    pub async fn serve<S, R, W>(&self, source: R, out: W, store: S) -> Result<(), Error>
+    where
+        S: DaemonStore + fmt::Debug + Send,
+        R: AsyncRead + fmt::Debug + Send + Unpin + 'static,
+        W: AsyncWrite + fmt::Debug + Send + Unpin + 'static,
+    {
+        self.serve_with_info(ConnectionInfo::new(), source, out, store)
+            .await
+    }
+
+    /// Like [`serve`](Builder::serve) for a client described by `info`.
+    #[instrument(skip(self, source, out, store), fields(connection = %info))]
+    pub async fn serve_with_info<S, R, W>(
+        &self,
+        info: ConnectionInfo,
+        source: R,
+        out: W,
+        store: S,
+    ) -> Result<(), Error>
+    where
+        S: DaemonStore + fmt::Debug + Send,
+        R: AsyncRead + fmt::Debug + Send + Unpin + 'static,
+        W: AsyncWrite + fmt::Debug + Send + Unpin + 'static,
+    {
+        let settings = self.settings.clone().unwrap_or_default();
+        let fut =
+            serve_connection(self, info, source, out, store).with_read_limits(self.read_limits);
+        fut.with_settings(settings).await
+    }
+
+    pub async fn serve_raw<S, R, W>(&self, source: R, out: W, store: S) -> Result<(), Error>
+    where
+        S: DaemonStore + fmt::Debug + Send,
+        R: AsyncRead + fmt::Debug + Send + Unpin + 'static,
+        W: AsyncWrite + fmt::Debug + Send + Unpin + 'static,
+    {
+        let settings = self.settings.clone().unwrap_or_default();
+        serve_connection(self, ConnectionInfo::new(), source, out, store)
+            .with_read_limits(self.read_limits)
+            .with_settings(settings)
+            .await
+    }
+
+    /// Serve every channel the other end opens on `mux` as a daemon
+    /// connection of its own, with a store from `new_store`, until the
+    /// multiplexed connection is closed. A failing channel doesn't stop the
+    /// others.
+    pub async fn serve_mux<S, F>(&self, mut mux: Mux, mut new_store: F) -> Result<(), Error>
+    where
+        S: DaemonStore + fmt::Debug + Send,
+        F: FnMut() -> S,
+    {
+        let mut conns = FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                channel = mux.accept() => match channel {
+                    Some(channel) => {
+                        debug!(channel = channel.id(), "Serving mux channel");
+                        let info = ConnectionInfo::new()
+                            .remote_addr(format!("mux channel {}", channel.id()));
+                        let (source, out) = tokio::io::split(channel);
+                        conns.push(self.serve_with_info(info, source, out, new_store()));
+                    }
+                    None => break,
+                },
+                Some(res) = conns.next(), if !conns.is_empty() => {
+                    if let Err(err) = res {
+                        error!("Error serving mux channel: {}", err);
+                    }
+                }
+            }
+        }
+        while let Some(res) = conns.next().await {
+            if let Err(err) = res {
+                error!("Error serving mux channel: {}", err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Serve every connection made to `listener`, with a store from
+    /// `new_store`, until accepting a connection fails. A failing
+    /// connection doesn't stop the others. Drop the future to stop
+    /// serving.
+    #[cfg(unix)]
+    pub async fn serve_unix<S, F>(
+        &self,
+        listener: tokio::net::UnixListener,
+        mut new_store: F,
+    ) -> Result<(), Error>
+    where
+        S: DaemonStore + fmt::Debug + Send,
+        F: FnMut() -> S,
+    {
+        let mut conns = FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                conn = listener.accept() => {
+                    let (stream, _) = conn?;
+                    let info = ConnectionInfo::from_unix_stream(&stream);
+                    debug!(connection = %info, "Serving unix socket connection");
+                    let (source, out) = stream.into_split();
+                    conns.push(self.serve_with_info(info, source, out, new_store()));
+                }
+                Some(res) = conns.next(), if !conns.is_empty() => {
+                    if let Err(err) = res {
+                        error!("Error serving unix socket connection: {}", err);
+                    }
+                }
+            }
+        }
+    }
+}
+
 pub async fn run_server<S, R, W>(
     source: R,
     out: W,
@@ -622,15 +927,16 @@ where
     R: AsyncRead + fmt::Debug + Send + Unpin + 'static,
     W: AsyncWrite + fmt::Debug + Send + Unpin + 'static,
 {
-    let settings = BuildSettings::default();
-    let fut = run_server_raw(source, out, store, trusted);
-    fut.with_settings(settings).await
+    Builder::new()
+        .trusted(trusted)
+        .serve(source, out, store)
+        .await
 }
 
 pub async fn run_server_raw<S, R, W>(
-    mut source: R,
-    mut out: W,
-    mut store: S,
+    source: R,
+    out: W,
+    store: S,
     trusted: TrustedFlag,
     //recursive: RecursiveFlag,
 ) -> Result<(), Error>
@@ -639,6 +945,26 @@ where
     R: AsyncRead + fmt::Debug + Send + Unpin + 'static,
     W: AsyncWrite + fmt::Debug + Send + Unpin + 'static,
 {
+    Builder::new()
+        .trusted(trusted)
+        .serve_raw(source, out, store)
+        .await
+}
+
+async fn serve_connection<S, R, W>(
+    options: &Builder,
+    mut info: ConnectionInfo,
+    source: R,
+    out: W,
+    mut store: S,
+) -> Result<(), Error>
+where
+    S: DaemonStore + fmt::Debug + Send,
+    R: AsyncRead + fmt::Debug + Send + Unpin + 'static,
+    W: AsyncWrite + fmt::Debug + Send + Unpin + 'static,
+{
+    let trusted = options.trusted;
+    let (mut source, mut out, compression) = zstd_switch(source, out);
     // Exchange the greeting.
     let magic = source.read_u64_le().await?;
     if magic != WORKER_MAGIC_1 {
@@ -651,8 +977,16 @@ where
     if client_version < 0x10a {
         return Err(Error::DaemonClientVersionTooOld);
     }
+    info.client_version = Some(client_version);
+    // Everything after the handshake is in the older of the two versions,
+    // which is the one a newer client talks to us in.
+    let client_version = client_version.min(PROTOCOL_VERSION);
+    let info = Arc::new(info);
+    let mut context = OpContext::new(info.clone(), trusted);
+    let connection_context = context.clone();
     let mut to = TakenStream::new(out);
     let op_count = OpCounter::new();
+    let poison = Poison::default();
     let (tunnel_layer, mut tunnel_logger) = TunnelLayer::new(to.taker(), client_version);
     /*
     auto tunnelLogger = new TunnelLogger(to, clientVersion);
@@ -672,7 +1006,12 @@ where
             source.read_u64_le().await?;
         }
         if get_protocol_minor!(client_version) >= 33 {
-            to.write_str("nix.rs 1.2.3").await?;
+            if options.compression {
+                to.write_str(&format!("nix.rs 1.2.3{}", COMPRESSION_MARKER))
+                    .await?;
+            } else {
+                to.write_str("nix.rs 1.2.3").await?;
+            }
         }
         if get_protocol_minor!(client_version) >= 35 {
             // We and the underlying store both need to trust the client for
@@ -682,11 +1021,7 @@ where
             } else {
                 Some(TrustedFlag::NotTrusted)
             };
-            match temp {
-                None => to.write_u64_le(0).await?,
-                Some(TrustedFlag::Trusted) => to.write_u64_le(1).await?,
-                Some(TrustedFlag::NotTrusted) => to.write_u64_le(2).await?,
-            }
+            to.write_u64_le(TrustLevel::from(temp).into()).await?;
         }
 
         /* Send startup error messages to the client. */
@@ -698,17 +1033,44 @@ where
 
             while let Ok(op) = source.read_enum::<WorkerProtoOp>().await {
                 op_count.report_op(op);
+                if op == WorkerProtoOp::NixrsCompression {
+                    let algorithm = source.read_string().await?;
+                    let accepted = options.compression && algorithm == "zstd";
+                    debug!(accepted, "client asked for {} compression", algorithm);
+                    tunnel_logger.start_work().await;
+                    tunnel_logger.stop_work().await;
+                    to.write_bool(accepted).await?;
+                    to.flush().await?;
+                    if accepted {
+                        compression.enable();
+                    }
+                    continue;
+                }
                 debug!("performing daemon worker op: {}", op);
-                let fut = perform_op(
-                    &mut tunnel_logger,
-                    &mut store,
-                    trusted,
-                    client_version,
-                    &mut source,
-                    &mut to,
-                    op,
+                context.op = Some(op);
+                let mut audit_paths = Vec::new();
+                let fut = with_context(
+                    context.clone(),
+                    perform_op(
+                        &mut tunnel_logger,
+                        &mut store,
+                        options,
+                        &mut context,
+                        client_version,
+                        &mut source,
+                        &mut to,
+                        &poison,
+                        &mut audit_paths,
+                        op,
+                    ),
                 );
-                if let Err(err) = fut.await {
+                let res = fut.await;
+                if let Some(audit) = options.audit.as_ref().filter(|_| is_audited(op)) {
+                    audit
+                        .0
+                        .record(&AuditRecord::new(&context, op, audit_paths, &res));
+                }
+                if let Err(err) = res {
                     /*
                         If we're not in a state where we can send replies, then
                         something went wrong processing the input of the
@@ -717,7 +1079,7 @@ where
                         happens, just send the error message and exit.
                     */
                     let error_allowed = tunnel_logger.can_send_stderr;
-                    error!("Command error {} {:?}", error_allowed, err);
+                    error!(connection = %info, "Command error {} {:?}", error_allowed, err);
                     tunnel_logger.stop_work_err(&err).await;
                     if !error_allowed {
                         return Err(err);
@@ -726,6 +1088,15 @@ where
                 debug!("Completed op {}", op);
 
                 to.flush().await?;
+                if poison.is_poisoned() {
+                    // What is left of the framed data would be read as the
+                    // next op.
+                    error!(
+                        connection = %info,
+                        "Closing connection after {} left framed data unread", op
+                    );
+                    return Ok(());
+                }
 
                 assert!(!tunnel_logger.can_send_stderr);
             }
@@ -739,7 +1110,9 @@ where
         Ok(())
     };
     let sub = registry().with(tunnel_layer).with(ParentLayer::new());
-    fut.with_subscriber(sub).await
+    with_context(connection_context, fut)
+        .with_subscriber(sub)
+        .await
 }
 
 async fn read_derived_paths<R>(
@@ -755,7 +1128,9 @@ where
         Ok(ret)
     } else {
         let len = source.read_usize().await?;
-        let mut ret = Vec::with_capacity(len);
+        let limits = ReadLimits::current();
+        limits.check_collection_len(len)?;
+        let mut ret = Vec::with_capacity(limits.initial_capacity(len));
         for _ in 0..len {
             let paths: Vec<StorePathWithOutputs> = source.read_parsed_coll(&store_dir).await?;
             for path in paths {
@@ -766,14 +1141,74 @@ where
     }
 }
 
-#[instrument(skip(logger, store, from, to), fields(client.major=get_protocol_major!(client_version), client.minor=get_protocol_minor!(client_version)))]
+/// Info for `path` for the obsolete ops that are answered from it, which
+/// fail like `queryPathInfo` in Nix when the path is not valid.
+async fn query_existing_path_info<S>(
+    store: &mut S,
+    store_dir: &StoreDir,
+    path: &StorePath,
+) -> Result<ValidPathInfo, Error>
+where
+    S: DaemonStore + fmt::Debug + Send,
+{
+    match store.query_path_info(path).await? {
+        Some(info) => Ok(info),
+        None => Err(Error::InvalidPath(store_dir.print_path(path))),
+    }
+}
+
+/// Add a NAR sent by the client, optionally checking it with
+/// [`NarVerifier`] on the way to the store.
+///
+/// When verification fails the mismatch is returned instead of whatever
+/// error the store made of the failed read.
+async fn add_nar_to_store<S, R>(
+    store: &mut S,
+    store_dir: &StoreDir,
+    info: &ValidPathInfo,
+    source: R,
+    verify: bool,
+    repair: RepairFlag,
+    check_sigs: CheckSignaturesFlag,
+) -> Result<(), Error>
+where
+    S: DaemonStore + fmt::Debug + Send,
+    R: AsyncRead + fmt::Debug + Send + Unpin,
+{
+    if !verify {
+        return store.add_to_store(info, source, repair, check_sigs).await;
+    }
+    let mut source = NarVerifier::new(source, store_dir, info);
+    match store
+        .add_to_store(info, &mut source, repair, check_sigs)
+        .await
+    {
+        Ok(()) => source.finish().await,
+        Err(err) => Err(source.take_mismatch().unwrap_or(err)),
+    }
+}
+
+#[instrument(
+    skip(logger, store, context, from, to, audit_paths),
+    fields(
+        client.major = get_protocol_major!(client_version),
+        client.minor = get_protocol_minor!(client_version),
+        peer.uid = context.connection.peer_uid,
+        peer.pid = context.connection.peer_pid,
+        remote.addr = context.connection.remote_addr.as_deref(),
+    )
+)]
+#[allow(clippy::too_many_arguments)]
 async fn perform_op<S, R, W>(
     logger: &mut TunnelController,
     store: &mut S,
-    trusted: TrustedFlag,
+    options: &Builder,
+    context: &mut OpContext,
     client_version: u64,
     mut from: &mut R,
     mut to: W,
+    poison: &Poison,
+    audit_paths: &mut Vec<String>,
     op: WorkerProtoOp,
 ) -> Result<(), Error>
 where
@@ -782,6 +1217,7 @@ where
     W: AsyncWrite + fmt::Debug + Send + Unpin,
 {
     debug!(?op, "Perform op {}", op);
+    let trusted = options.trusted;
     let store_dir = store.store_dir();
     use WorkerProtoOp::*;
     match op {
@@ -808,13 +1244,75 @@ where
         }
         // HasSubstitutes => {} // TODO
         // QuerySubstitutablePaths => {} // TODO
-        // QueryPathHash => {} // TODO
-        // QueryReferences | QueryReferrers | QueryValidDerivers | QueryDerivationOutputs => {} // TODO
+        QueryPathHash => {
+            let path = from.read_parsed(&store_dir).await?;
+            logger.start_work().await;
+            let info = query_existing_path_info(store, &store_dir, &path).await?;
+            logger.stop_work().await;
+            to.write_str(&info.nar_hash.encode_base16()).await?;
+        }
+        QueryReferences => {
+            let path = from.read_parsed(&store_dir).await?;
+            logger.start_work().await;
+            let info = query_existing_path_info(store, &store_dir, &path).await?;
+            logger.stop_work().await;
+            to.write_printed_coll(&store_dir, &info.references).await?;
+        }
+        // QueryReferrers | QueryValidDerivers | QueryDerivationOutputs => {} // TODO
         // QueryDerivationOutputNames => {} // TODO
         // QueryDerivationOutputMap => {} // TODO
-        // QueryDeriver => {} // TODO
+        QueryDeriver => {
+            let path = from.read_parsed(&store_dir).await?;
+            logger.start_work().await;
+            let info = query_existing_path_info(store, &store_dir, &path).await?;
+            logger.stop_work().await;
+            let deriver = info
+                .deriver
+                .map(|deriver| store_dir.print_path(&deriver))
+                .unwrap_or_default();
+            to.write_str(&deriver).await?;
+        }
         // QueryPathFromHashPart => {} // TODO
-        // AddToStore => {} // TODO
+        AddToStore if get_protocol_minor!(client_version) < 25 => {
+            let base_name = from.read_string().await?;
+            let fixed = from.read_bool().await?; // obsolete
+            let recursive = from.read_u64_le().await?;
+            let hash_algo = from.read_string().await?;
+            let mut method = match recursive {
+                0 => FileIngestionMethod::Flat,
+                1 => FileIngestionMethod::Recursive,
+                _ => return Err(Error::UnsupportedFileIngestionMethod(recursive)),
+            };
+            // Compatibility hack.
+            let hash_algo = if fixed {
+                hash_algo
+                    .parse()
+                    .map_err(|err| Error::BadHash(hash::ParseHashError::Algorithm(err)))?
+            } else {
+                method = FileIngestionMethod::Recursive;
+                hash::Algorithm::SHA256
+            };
+
+            logger.start_work().await;
+            // The old protocol always sends a NAR. Parsing it finds where it
+            // ends. It is held in memory, so it is bounded like any other
+            // buffer read from the connection.
+            let limit = ReadLimits::current().max_string_len as u64;
+            let mut nar = Vec::new();
+            copy_nar(LimitedReader::new(&mut from, limit), &mut nar).await?;
+            let path = add_ca_to_store(
+                store,
+                &base_name,
+                method,
+                hash_algo,
+                nar.into(),
+                RepairFlag::NoRepair,
+            )
+            .await?;
+            audit_paths.push(store_dir.print_path(&path));
+            logger.stop_work().await;
+            to.write_printed(&store_dir, &path).await?;
+        }
         AddMultipleToStore => {
             trace!("Add multiple");
             let repair = from.read_flag().await?;
@@ -829,12 +1327,12 @@ where
             logger.start_work().await;
             {
                 trace!("Framed source");
-                let mut source = FramedSource::new(&mut from);
+                let mut source = FramedSource::with_poison(&mut from, poison);
                 let res = store
                     .add_multiple_to_store(&mut source, repair, check_sigs)
                     .await;
                 debug!("Done with add multiple");
-                source.drain().await?;
+                source.finish().await?;
                 debug!("Drained frame source {:?}", res);
                 res?
             }
@@ -865,6 +1363,7 @@ where
                     return Err(Error::RepairNotAllowed);
                 }
             }
+            audit_paths.extend(drv_paths.iter().map(|path| path.print(&store_dir)));
             logger.start_work().await;
             store.build_paths(&drv_paths, build_mode).await?;
             logger.stop_work().await;
@@ -876,6 +1375,7 @@ where
             let drv =
                 BasicDerivation::read_drv(&mut from, &store_dir, drv_path.name_from_drv()).await?;
             let build_mode = from.read_enum().await?;
+            audit_paths.push(store_dir.print_path(&drv_path));
             logger.start_work().await;
 
             let drv_type = drv.drv_type()?;
@@ -915,6 +1415,9 @@ where
             if !(drv_type.is_ca() || trusted.into()) {
                 return Err(Error::MissingPrivilegesToBuild);
             }
+            store
+                .experimental_features()
+                .require_all(&ExperimentalFeatures::required_by(&drv))?;
 
             /* Make sure that the non-input-addressed derivations that got this far
             are in fact content-addressed if we don't trust them. */
@@ -947,6 +1450,10 @@ where
                 to.write_time(res.start_time).await?;
                 to.write_time(res.stop_time).await?;
             }
+            if get_protocol_minor!(client_version) >= 37 {
+                write_opt_micros(&mut to, res.cpu_user).await?;
+                write_opt_micros(&mut to, res.cpu_system).await?;
+            }
             if get_protocol_minor!(client_version) >= 28 {
                 let mut built_outputs = DrvOutputs::new();
                 for (_, realisation) in res.built_outputs {
@@ -962,11 +1469,79 @@ where
 
         // EnsurePath => {} // TODO
         // AddTempRoot => {} // TODO
-        // AddIndirectRoot => {} // TODO
+        AddIndirectRoot => {
+            // Checked before it gets near the file system.
+            let path = DaemonPath::new(from.read_bytes().await?.as_ref())?;
+            audit_paths.push(path.to_string());
+            logger.start_work().await;
+            store.add_indirect_root(&path).await?;
+            logger.stop_work().await;
+            to.write_u64_le(1).await?;
+        }
         // Obsolete.
         // SyncWithGC  => {} // TODO
-        // FindRoots => {} // TODO
-        // CollectGarbage => {} // TODO
+        FindRoots => {
+            logger.start_work().await;
+            let roots = store.find_roots().await?;
+            logger.stop_work().await;
+            to.write_usize(roots.len()).await?;
+            for (link, target) in roots {
+                if trusted.into() {
+                    AsyncSink::write_buf(&mut to, link.as_bytes()).await?;
+                } else {
+                    to.write_str(CENSORED_ROOT).await?;
+                }
+                to.write_printed(&store_dir, &target).await?;
+            }
+        }
+        CollectGarbage => {
+            let action = from.read_u64_le().await?;
+            let action = GCAction::try_from(action).map_err(|_| Error::InvalidGCAction(action))?;
+            let paths_to_delete: StorePathSet = from.read_parsed_coll(&store_dir).await?;
+            let ignore_liveness = from.read_bool().await?;
+            let max_freed = from.read_u64_le().await?;
+            // Obsolete fields.
+            from.read_u64_le().await?;
+            from.read_u64_le().await?;
+            from.read_u64_le().await?;
+            let options = GCOptions {
+                action,
+                paths_to_delete,
+                ignore_liveness,
+                max_freed,
+            };
+
+            audit_paths.extend(
+                options
+                    .paths_to_delete
+                    .iter()
+                    .map(|path| store_dir.print_path(path)),
+            );
+            logger.start_work().await;
+            if options.ignore_liveness {
+                return Err(Error::IgnoreLivenessNotAllowed);
+            }
+            let act = ActivityBuilder::new(
+                Verbosity::Talkative,
+                ActivityType::Unknown,
+                "collecting garbage",
+            )
+            .start();
+            let results = store.collect_garbage_streaming(&options, &act).await?;
+            drop(act);
+            if matches!(
+                options.action,
+                GCAction::DeleteDead | GCAction::DeleteSpecific
+            ) {
+                // What was actually deleted.
+                audit_paths.clear();
+                audit_paths.extend(results.paths.iter().cloned());
+            }
+            logger.stop_work().await;
+            to.write_string_coll(&results.paths).await?;
+            to.write_u64_le(results.bytes_freed).await?;
+            to.write_u64_le(0).await?; // obsolete
+        }
         SetOptions => {
             let keep_failed = from.read_bool().await?;
             let keep_going = from.read_bool().await?;
@@ -985,12 +1560,35 @@ where
             let mut unknown = BTreeMap::new();
             if get_protocol_minor!(client_version) >= 12 {
                 let len = from.read_usize().await?;
+                ReadLimits::current().check_collection_len(len)?;
                 for _i in 0..len {
                     let name = from.read_string().await?;
                     let value = from.read_string().await?;
                     unknown.insert(name, value);
                 }
             }
+            if (!trusted).into() {
+                // These would give untrusted clients host paths in their
+                // builds.
+                for name in ["sandbox-paths", "build-sandbox-paths", "build-chroot-dirs"] {
+                    if unknown.remove(name).is_some() {
+                        warn!("ignoring setting '{}' from untrusted client", name);
+                    }
+                }
+            }
+            let mut client_options = BTreeMap::new();
+            client_options.insert("keep-failed".into(), keep_failed.to_string());
+            client_options.insert("keep-going".into(), keep_going.to_string());
+            client_options.insert("fallback".into(), try_fallback.to_string());
+            client_options.insert("max-jobs".into(), max_build_jobs.to_string());
+            client_options.insert(
+                "max-silent-time".into(),
+                max_silent_time.as_secs().to_string(),
+            );
+            client_options.insert("cores".into(), build_cores.to_string());
+            client_options.insert("substitute".into(), use_substitutes.to_string());
+            client_options.extend(unknown.clone());
+            context.client_options = Arc::new(client_options);
 
             logger.start_work().await;
             // if !recursive {
@@ -1034,8 +1632,23 @@ where
                 to.write_u64_le(0).await?
             }
         }
-        // OptimiseStore => {} // TODO
-        // VerifyStore => {} // TODO
+        OptimiseStore => {
+            logger.start_work().await;
+            store.optimise_store().await?;
+            logger.stop_work().await;
+            to.write_u64_le(1).await?;
+        }
+        VerifyStore => {
+            let check_contents = from.read_bool().await?;
+            let repair: RepairFlag = from.read_flag().await?;
+            logger.start_work().await;
+            if repair == RepairFlag::Repair && (!trusted).into() {
+                return Err(Error::MissingPrivilegesToRepair);
+            }
+            let errors = store.verify_store(check_contents, repair).await?;
+            logger.stop_work().await;
+            to.write_bool(errors).await?;
+        }
         // AddSignatures => {} // TODO
         NarFromPath => {
             let path = from.read_parsed(&store_dir).await?;
@@ -1045,6 +1658,7 @@ where
         }
         AddToStoreNar => {
             let path = from.read_parsed(&store_dir).await?;
+            audit_paths.push(store_dir.print_path(&path));
             let deriver = from.read_string().await?;
             let deriver = if !deriver.is_empty() {
                 Some(store_dir.parse_path(&deriver)?)
@@ -1063,11 +1677,7 @@ where
                 .map(|s| s.parse())
                 .collect::<Result<SignatureSet, ParseSignatureError>>()?;
             let ca_s = from.read_string().await?;
-            let ca = if !ca_s.is_empty() {
-                Some(ca_s.parse()?)
-            } else {
-                None
-            };
+            let ca = ContentAddress::parse_opt(&ca_s)?;
             let repair = from.read_flag().await?;
             let mut dont_check_sigs = from.read_bool().await?;
             if (!trusted).into() && dont_check_sigs {
@@ -1093,24 +1703,41 @@ where
                 CheckSignaturesFlag::CheckSigs
             };
 
+            let verify = options.verify_nar;
             if get_protocol_minor!(client_version) >= 23 {
                 logger.start_work().await;
                 {
-                    let mut source = FramedSource::new(&mut from);
-                    let res = store
-                        .add_to_store(&info, &mut source, repair, check_sigs)
-                        .await;
-                    source.drain().await?;
+                    let mut source = FramedSource::with_poison(&mut from, poison);
+                    let res = add_nar_to_store(
+                        store,
+                        &store_dir,
+                        &info,
+                        &mut source,
+                        verify,
+                        repair,
+                        check_sigs,
+                    )
+                    .await;
+                    source.finish().await?;
                     res?
                 }
                 logger.stop_work().await;
             } else if get_protocol_minor!(client_version) >= 21 {
-                let mut source = TunnelSource::with_capacity(&mut from, logger.sender(), 65_000);
+                let capacity = options.read_limits.max_buffer_capacity;
+                let mut source = TunnelSource::with_capacity(&mut from, logger.sender(), capacity);
                 logger.start_work().await;
                 // FIXME: race if addToStore doesn't read source?
-                store
-                    .add_to_store(&info, &mut source, repair, check_sigs)
-                    .await?;
+                add_nar_to_store(
+                    store,
+                    &store_dir,
+                    &info,
+                    &mut source,
+                    verify,
+                    repair,
+                    check_sigs,
+                )
+                .await
+                .map_err(|err| source.typed_error(err))?;
                 logger.stop_work().await;
             } else {
                 /*
@@ -1122,9 +1749,16 @@ where
                 let mut source = tokio::io::AsyncReadExt::take(&mut from, info.nar_size);
                 logger.start_work().await;
                 // FIXME: race if addToStore doesn't read source?
-                store
-                    .add_to_store(&info, &mut source, repair, check_sigs)
-                    .await?;
+                add_nar_to_store(
+                    store,
+                    &store_dir,
+                    &info,
+                    &mut source,
+                    verify,
+                    repair,
+                    check_sigs,
+                )
+                .await?;
                 logger.stop_work().await;
             }
         }
@@ -1141,9 +1775,69 @@ where
             to.write_u64_le(result.download_size).await?;
             to.write_u64_le(result.nar_size).await?;
         }
-        // RegisterDrvOutput => {} // TODO
-        // QueryRealisation => {} // TODO
-        // AddBuildLog => {} // TODO
+        RegisterDrvOutput => {
+            let realisation: Realisation = if get_protocol_minor!(client_version) < 31 {
+                let id: DrvOutput = from.read_string().await?.parse()?;
+                let out_path = from.read_parsed(&store_dir).await?;
+                Realisation {
+                    id,
+                    out_path,
+                    signatures: StringSet::new(),
+                    dependent_realisations: BTreeMap::new(),
+                }
+            } else {
+                from.read_string().await?.parse()?
+            };
+            audit_paths.push(store_dir.print_path(&realisation.out_path));
+            logger.start_work().await;
+            store
+                .experimental_features()
+                .require(ExperimentalFeature::CaDerivations)?;
+            // Otherwise a client could claim any output path for an output.
+            if (!trusted).into() {
+                realisation.verify_signatures(&options.trusted_keys)?;
+            }
+            check_realisation_dependencies(store, &realisation).await?;
+            store.register_drv_output(&realisation).await?;
+            logger.stop_work().await;
+        }
+        QueryRealisation => {
+            let id: DrvOutput = from.read_string().await?.parse()?;
+            logger.start_work().await;
+            store
+                .experimental_features()
+                .require(ExperimentalFeature::CaDerivations)?;
+            let realisation = store.query_realisation(&id).await?;
+            logger.stop_work().await;
+            // Older clients only get the output path.
+            if get_protocol_minor!(client_version) < 31 {
+                let paths: StorePathSet = realisation.map(|r| r.out_path).into_iter().collect();
+                to.write_printed_coll(&store_dir, &paths).await?;
+            } else {
+                let mut realisations = StringSet::new();
+                if let Some(realisation) = realisation {
+                    realisations.insert(realisation.to_json_string()?);
+                }
+                to.write_string_coll(&realisations).await?;
+            }
+        }
+        AddBuildLog => {
+            let path = from.read_parsed(&store_dir).await?;
+            audit_paths.push(store_dir.print_path(&path));
+            logger.start_work().await;
+            if (!trusted).into() {
+                return Err(Error::MissingPrivilegesToAddLogs);
+            }
+            let mut log = Vec::new();
+            {
+                let mut source = FramedSource::with_poison(&mut from, poison);
+                source.read_to_end(&mut log).await?;
+            }
+            let log = String::from_utf8_lossy(&log);
+            store.add_build_log(&path, &log).await?;
+            logger.stop_work().await;
+            to.write_u64_le(1).await?;
+        }
         QueryFailedPaths | ClearFailedPaths => return Err(Error::RemovedOperation(op)),
         _ => {
             // throw Error("invalid operation %1%", op);
@@ -1152,3 +1846,607 @@ where
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::activity::ActivityType;
+
+    fn activity(level: Verbosity) -> StartActivity {
+        StartActivity {
+            act: 1,
+            level,
+            activity_type: ActivityType::Build,
+            text: "building foo".into(),
+            fields: Vec::new(),
+            parent: 0,
+        }
+    }
+
+    async fn sent(level: Verbosity, client_version: u64, cmd: TunnelCommand) -> Vec<u8> {
+        let active = ActiveVerbosity::default();
+        active.set(level);
+        let mut buf = Vec::new();
+        send_command(active, client_version, &mut buf, cmd)
+            .await
+            .unwrap();
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_old_client_gets_activity_as_log_line() {
+        let cmd = TunnelCommand::StartActivity(1, activity(Verbosity::Info));
+        let mut expected = Vec::new();
+        expected.write_u64_le(STDERR_NEXT).await.unwrap();
+        expected.write_str("building foo...\n").await.unwrap();
+        assert_eq!(sent(Verbosity::Info, 1 << 8 | 19, cmd).await, expected);
+    }
+
+    #[tokio::test]
+    async fn test_old_client_activity_below_verbosity() {
+        let cmd = TunnelCommand::StartActivity(1, activity(Verbosity::Debug));
+        assert!(sent(Verbosity::Info, 1 << 8 | 19, cmd).await.is_empty());
+        let cmd = TunnelCommand::StopActivity(1);
+        assert!(sent(Verbosity::Vomit, 1 << 8 | 19, cmd).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_deleted_path_sent_as_log_line() {
+        let kind = ResultKind::DeletedPath {
+            path: "/nix/store/7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-konsole-18.12.3".into(),
+            bytes: 12,
+        };
+        let cmd = || {
+            TunnelCommand::Result(ActivityResult {
+                act: 1,
+                result_type: kind.result_type(),
+                fields: kind.clone().into_fields(),
+            })
+        };
+        let mut expected = Vec::new();
+        expected.write_u64_le(STDERR_NEXT).await.unwrap();
+        expected
+            .write_str("deleting '/nix/store/7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-konsole-18.12.3'\n")
+            .await
+            .unwrap();
+        assert_eq!(sent(Verbosity::Info, 1 << 8 | 35, cmd()).await, expected);
+        assert_eq!(sent(Verbosity::Info, 1 << 8 | 19, cmd()).await, expected);
+        assert!(sent(Verbosity::Warn, 1 << 8 | 35, cmd()).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_new_client_gets_all_activities() {
+        let cmd = TunnelCommand::StartActivity(1, activity(Verbosity::Debug));
+        let buf = sent(Verbosity::Error, 1 << 8 | 20, cmd).await;
+        assert_eq!(&buf[..8], &STDERR_START_ACTIVITY.to_le_bytes());
+    }
+
+    /// Announces `version` as the client's protocol version, the second
+    /// word a client writes, in place of the one it wrote.
+    #[derive(Debug)]
+    struct AnnounceVersion<W> {
+        inner: W,
+        version: [u8; 8],
+        written: usize,
+    }
+
+    impl<W: AsyncWrite + Unpin> AsyncWrite for AnnounceVersion<W> {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let mut patched = buf.to_vec();
+            for (i, b) in patched.iter_mut().enumerate() {
+                if let Some(offset) = (self.written + i).checked_sub(8) {
+                    if offset < 8 {
+                        *b = self.version[offset];
+                    }
+                }
+            }
+            let res = Pin::new(&mut self.inner).poll_write(cx, &patched);
+            if let Poll::Ready(Ok(n)) = res {
+                self.written += n;
+            }
+            res
+        }
+
+        fn poll_flush(
+            mut self: Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_build_result_for_newer_client() {
+        use std::collections::BTreeSet;
+        use std::path::PathBuf;
+        use std::time::{Duration, SystemTime};
+
+        use crate::store::assert_store::AssertStore;
+        use crate::store::daemon::DaemonStoreClient;
+        use crate::store::{
+            BuildResult, BuildStatus, DerivationOutput, DrvOutput, Realisation, Store,
+        };
+
+        let drv_path = StorePath::test_from_seed("foo.drv");
+        let mut outputs = BTreeMap::new();
+        outputs.insert(
+            "out".to_string(),
+            DerivationOutput::InputAddressed(StorePath::test_from_seed("foo")),
+        );
+        let drv = BasicDerivation {
+            outputs,
+            input_srcs: BTreeSet::new(),
+            platform: "x86_64-linux".into(),
+            builder: PathBuf::from("/bin/sh"),
+            arguments: Vec::new(),
+            env: Vec::new(),
+            name: "foo".into(),
+        };
+        let id = DrvOutput {
+            drv_hash: hash::Hash::test_from_seed(hash::Algorithm::SHA256, "foo"),
+            output_name: "out".into(),
+        };
+        let mut result = BuildResult::new(BuildStatus::Built, String::new());
+        result.times_built = 1;
+        result.start_time = SystemTime::UNIX_EPOCH + Duration::from_secs(10);
+        result.stop_time = SystemTime::UNIX_EPOCH + Duration::from_secs(20);
+        result.cpu_user = Some(Duration::from_millis(2100));
+        result.cpu_system = Some(Duration::from_millis(400));
+        result.built_outputs.insert(
+            id.clone(),
+            Realisation {
+                id,
+                out_path: StorePath::test_from_seed("foo"),
+                signatures: BTreeSet::new(),
+                dependent_realisations: BTreeMap::new(),
+            },
+        );
+        let store = AssertStore::assert_build_derivation(
+            Some(TrustedFlag::Trusted),
+            &drv_path,
+            &drv,
+            BuildMode::Normal,
+            &BuildSettings::default(),
+            Ok(result.clone()),
+        );
+
+        // A client on 1.37 talks 1.35 with us, so it gets no CPU times and
+        // the rest of the result must still line up.
+        let (client, server) = tokio::io::duplex(64_000);
+        let (read, write) = tokio::io::split(server);
+        let server = tokio::spawn(run_server(read, write, store, TrustedFlag::Trusted));
+        let (read, write) = tokio::io::split(client);
+        let write = AnnounceVersion {
+            inner: write,
+            version: (1u64 << 8 | 37).to_le_bytes(),
+            written: 0,
+        };
+        let mut client =
+            DaemonStoreClient::connect(StoreDir::default(), "test".into(), read, write)
+                .await
+                .unwrap();
+        let actual = client
+            .build_derivation(&drv_path, &drv, BuildMode::Normal)
+            .await
+            .unwrap();
+        client.close().await.unwrap();
+        server.await.unwrap().unwrap();
+
+        result.cpu_user = None;
+        result.cpu_system = None;
+        assert_eq!(actual, result);
+    }
+
+    #[tokio::test]
+    async fn test_settings() {
+        use crate::store::assert_store::AssertStore;
+        use crate::store::daemon::DaemonStoreClient;
+        use crate::store::settings::WithSettings;
+        use crate::store::{DerivedPath, Store};
+
+        let drv_paths = [DerivedPath::Opaque(StorePath::test_from_seed("foo"))];
+        let mut server_settings = BuildSettings::default();
+        server_settings.sandbox_paths = vec!["/bin".into()];
+        let mut client_settings = BuildSettings::default();
+        client_settings.sandbox_paths = vec!["/etc".into()];
+
+        // Only trusted clients can change the sandbox paths.
+        for (trusted, sandbox_path) in [
+            (TrustedFlag::NotTrusted, "/bin"),
+            (TrustedFlag::Trusted, "/etc"),
+        ] {
+            let mut expected = BuildSettings::default();
+            expected.sandbox_paths = vec![sandbox_path.into()];
+            let store = AssertStore::assert_build_paths(
+                Some(trusted),
+                &drv_paths,
+                BuildMode::Normal,
+                &expected,
+                Ok(()),
+            );
+            let (client, server) = tokio::io::duplex(64_000);
+            let (read, write) = tokio::io::split(server);
+            let settings = server_settings.clone();
+            let server = tokio::spawn(async move {
+                Builder::new()
+                    .trusted(trusted)
+                    .settings(settings)
+                    .serve(read, write, store)
+                    .await
+            });
+            let (read, write) = tokio::io::split(client);
+            async {
+                let mut client =
+                    DaemonStoreClient::connect(StoreDir::default(), "test".into(), read, write)
+                        .await
+                        .unwrap();
+                client
+                    .build_paths(&drv_paths, BuildMode::Normal)
+                    .await
+                    .unwrap();
+                client.close().await.unwrap();
+            }
+            .with_settings(client_settings.clone())
+            .await;
+            server.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serve_mux_channels() {
+        use crate::io::MuxSide;
+        use crate::store::daemon::DaemonStoreClient;
+        use crate::store::MemoryStore;
+
+        let (client, server) = tokio::io::duplex(64_000);
+        let (read, write) = tokio::io::split(server);
+        let (server_mux, server_driver) = Mux::new(read, write, MuxSide::Server);
+        let (read, write) = tokio::io::split(client);
+        let (mut client_mux, client_driver) = Mux::new(read, write, MuxSide::Client);
+        tokio::spawn(client_driver);
+        let server = tokio::spawn(async move {
+            let builder = Builder::new();
+            let serve = builder.serve_mux(server_mux, MemoryStore::new);
+            tokio::try_join!(serve, async { server_driver.await.map_err(Error::from) })
+        });
+
+        let path = StorePath::new_from_base_name("00000000000000000000000000000000-test").unwrap();
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let (read, write) = tokio::io::split(client_mux.open());
+            let client =
+                DaemonStoreClient::connect(StoreDir::default(), "mux".into(), read, write).await;
+            clients.push(client.unwrap());
+        }
+        for client in clients.iter_mut() {
+            assert!(!client.is_valid_path(&path).await.unwrap());
+            client.close().await.unwrap();
+        }
+        drop(clients);
+        drop(client_mux);
+        server.await.unwrap().unwrap();
+    }
+
+    async fn started_tunnel() -> (
+        mpsc::Sender<TunnelCommand>,
+        TakenStream<tokio::io::DuplexStream>,
+        tokio::io::DuplexStream,
+    ) {
+        let (server, client) = tokio::io::duplex(1024);
+        let to = TakenStream::new(server);
+        let (sender, receiver) = mpsc::channel(TUNNEL_QUEUE_LEN);
+        tokio::spawn(process_tunnel(
+            ActiveVerbosity::default(),
+            PROTOCOL_VERSION,
+            to.taker(),
+            receiver,
+        ));
+        sender.send(TunnelCommand::StartWork).await.unwrap();
+        (sender, to, client)
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_source_slow_store_spamming_logs() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        let (sender, to, mut client_in) = started_tunnel().await;
+        let (mut client_out, mut from) = tokio::io::duplex(1024);
+        let expected = data.clone();
+        let client = tokio::spawn(async move {
+            let mut logs = 0;
+            let mut offset = 0;
+            while let Ok(msg) = client_in.read_u64_le().await {
+                match msg {
+                    STDERR_NEXT => {
+                        client_in.read_string().await.unwrap();
+                        logs += 1;
+                    }
+                    STDERR_READ => {
+                        let len = client_in.read_usize().await.unwrap();
+                        assert!(len > 0 && len <= 100, "asked for {} bytes", len);
+                        let end = (offset + len).min(expected.len());
+                        AsyncSink::write_buf(&mut client_out, &expected[offset..end])
+                            .await
+                            .unwrap();
+                        client_out.flush().await.unwrap();
+                        offset = end;
+                    }
+                    msg => panic!("unexpected message {:x}", msg),
+                }
+            }
+            logs
+        });
+        let spam = sender.clone();
+        let spammer = tokio::spawn(async move {
+            for i in 0..5000 {
+                let _ = spam.try_send(TunnelCommand::LogNext(format!("line {}", i)));
+                tokio::task::yield_now().await;
+            }
+        });
+
+        let mut source = TunnelSource::with_capacity(&mut from, sender.clone(), 100);
+        let mut read = Vec::new();
+        let mut buf = [0u8; 7];
+        while read.len() < data.len() {
+            let n = source.read(&mut buf).await.unwrap();
+            assert!(n > 0);
+            read.extend_from_slice(&buf[..n]);
+            if read.len() % 50 == 0 {
+                tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+            }
+        }
+        assert_eq!(read, data);
+
+        drop(source);
+        spammer.await.unwrap();
+        drop(sender);
+        drop(to);
+        assert!(client.await.unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_source_broken() {
+        let (sender, receiver) = mpsc::channel(1);
+        drop(receiver);
+        let mut from: &[u8] = &[];
+        let mut source = TunnelSource::with_capacity(&mut from, sender, 100);
+        let err = source.read(&mut [0u8; 10]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert!(matches!(
+            source.typed_error(err.into()),
+            Error::ClientSourceBroken(_)
+        ));
+
+        // The client hanging up while asked for data.
+        let (sender, _to, _client) = started_tunnel().await;
+        let mut from: &[u8] = &[];
+        let mut source = TunnelSource::with_capacity(&mut from, sender, 100);
+        let err = source.read(&mut [0u8; 10]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(matches!(
+            source.typed_error(err.into()),
+            Error::ClientSourceBroken(_)
+        ));
+        // Errors of the store itself are left alone.
+        assert!(matches!(
+            source.typed_error(Error::Misc("bad NAR".into())),
+            Error::Misc(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        use crate::store::daemon::DaemonStoreBuilder;
+        use crate::store::MemoryStore;
+
+        let path = StorePath::new_from_base_name("00000000000000000000000000000000-test").unwrap();
+        for (server_side, client_side) in [(true, true), (true, false), (false, true)] {
+            let (client, server) = tokio::io::duplex(64_000);
+            let (read, write) = tokio::io::split(server);
+            let server = tokio::spawn(async move {
+                Builder::new()
+                    .compression(server_side)
+                    .serve(read, write, MemoryStore::new())
+                    .await
+            });
+            let (read, write) = tokio::io::split(client);
+            let mut client = DaemonStoreBuilder::new()
+                .compression(client_side)
+                .connect(read, write)
+                .await
+                .unwrap();
+            assert_eq!(client.is_compressed(), server_side && client_side);
+            assert!(!client.is_valid_path(&path).await.unwrap());
+            client.close().await.unwrap();
+            drop(client);
+            server.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_missing_experimental_feature() {
+        use crate::store::daemon::DaemonStoreClient;
+        use crate::store::{FailStore, MemoryStore};
+
+        let id: DrvOutput =
+            "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad!out"
+                .parse()
+                .unwrap();
+        let (client, server) = tokio::io::duplex(64_000);
+        let (read, write) = tokio::io::split(server);
+        let server =
+            tokio::spawn(
+                async move { Builder::new().serve(read, write, MemoryStore::new()).await },
+            );
+        let (read, write) = tokio::io::split(client);
+        let mut client =
+            DaemonStoreClient::connect(StoreDir::default(), "test".into(), read, write)
+                .await
+                .unwrap();
+        assert_eq!(client.query_realisation(&id).await.unwrap(), None);
+        client.close().await.unwrap();
+        drop(client);
+        server.await.unwrap().unwrap();
+
+        let (client, server) = tokio::io::duplex(64_000);
+        let (read, write) = tokio::io::split(server);
+        let server =
+            tokio::spawn(async move { Builder::new().serve(read, write, FailStore).await });
+        let (read, write) = tokio::io::split(client);
+        let mut client =
+            DaemonStoreClient::connect(StoreDir::default(), "test".into(), read, write)
+                .await
+                .unwrap();
+        let err = client.query_realisation(&id).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            Error::MissingExperimentalFeature(ExperimentalFeature::CaDerivations).to_string()
+        );
+        client.close().await.unwrap();
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_untrusted_realisations() {
+        use ring::rand::SystemRandom;
+
+        use crate::signature::SecretKey;
+        use crate::store::daemon::DaemonStoreClient;
+        use crate::store::{CheckSignaturesFlag, MemoryStore, Store};
+
+        let rng = SystemRandom::new();
+        let key = SecretKey::generate("cache.example.org-1".into(), &rng).unwrap();
+        let mut store = MemoryStore::new();
+        let nar = b"output";
+        let mut info = ValidPathInfo::new(
+            StorePath::test_from_seed("output"),
+            hash::digest(hash::Algorithm::SHA256, nar),
+        );
+        info.nar_size = nar.len() as u64;
+        store
+            .add_to_store(
+                &info,
+                &nar[..],
+                RepairFlag::NoRepair,
+                CheckSignaturesFlag::NoCheckSigs,
+            )
+            .await
+            .unwrap();
+        let mut realisation = Realisation {
+            id: "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad!out"
+                .parse()
+                .unwrap(),
+            out_path: info.path.clone(),
+            signatures: StringSet::new(),
+            dependent_realisations: BTreeMap::new(),
+        };
+
+        let (client, server) = tokio::io::duplex(64_000);
+        let (read, write) = tokio::io::split(server);
+        let public = key.to_public_key();
+        let server = tokio::spawn(async move {
+            Builder::new()
+                .trusted_keys(vec![public])
+                .serve(read, write, store)
+                .await
+        });
+        let (read, write) = tokio::io::split(client);
+        let mut client =
+            DaemonStoreClient::connect(StoreDir::default(), "test".into(), read, write)
+                .await
+                .unwrap();
+        let err = client.register_drv_output(&realisation).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            Error::UntrustedRealisation(realisation.id.to_string()).to_string()
+        );
+        assert_eq!(
+            client.query_realisation(&realisation.id).await.unwrap(),
+            None
+        );
+
+        realisation.sign(&key).unwrap();
+        client.register_drv_output(&realisation).await.unwrap();
+        assert_eq!(
+            client.query_realisation(&realisation.id).await.unwrap(),
+            Some(realisation)
+        );
+        client.close().await.unwrap();
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_audit() {
+        use std::sync::Mutex;
+
+        use crate::store::daemon::DaemonStoreClient;
+        use crate::store::{CheckSignaturesFlag, MemoryStore, Store};
+
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let sink = records.clone();
+        let (client, server) = tokio::io::duplex(64_000);
+        let (read, write) = tokio::io::split(server);
+        let server = tokio::spawn(async move {
+            Builder::new()
+                .audit(move |record: &AuditRecord| sink.lock().unwrap().push(record.clone()))
+                .serve(read, write, MemoryStore::new())
+                .await
+        });
+        let (read, write) = tokio::io::split(client);
+        let mut client =
+            DaemonStoreClient::connect(StoreDir::default(), "test".into(), read, write)
+                .await
+                .unwrap();
+        let mut nar = bytes::BytesMut::new();
+        for event in crate::archive::test_data::text_file() {
+            event.encode_into(&mut nar);
+        }
+        let mut info = ValidPathInfo::new(
+            StorePath::test_from_seed("added"),
+            hash::digest(hash::Algorithm::SHA256, &nar),
+        );
+        info.nar_size = nar.len() as u64;
+        client
+            .add_to_store(
+                &info,
+                &nar[..],
+                RepairFlag::NoRepair,
+                CheckSignaturesFlag::NoCheckSigs,
+            )
+            .await
+            .unwrap();
+        assert!(client.is_valid_path(&info.path).await.unwrap());
+        client.add_build_log(&info.path, "log").await.unwrap_err();
+        client.close().await.unwrap();
+        drop(client);
+        server.await.unwrap().unwrap();
+
+        let records = records.lock().unwrap();
+        let ops: Vec<WorkerProtoOp> = records.iter().map(|record| record.op).collect();
+        assert_eq!(
+            ops,
+            vec![WorkerProtoOp::AddToStoreNar, WorkerProtoOp::AddBuildLog]
+        );
+        let path = StoreDir::default().print_path(&info.path);
+        assert_eq!(records[0].paths, vec![path.clone()]);
+        assert_eq!(records[0].outcome, AuditOutcome::Success);
+        assert!(!records[0].trusted);
+        assert_eq!(records[1].paths, vec![path]);
+        assert_eq!(
+            records[1].outcome,
+            AuditOutcome::Failure(Error::MissingPrivilegesToAddLogs.to_string())
+        );
+    }
+}
@@ -0,0 +1,175 @@
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use pin_project_lite::pin_project;
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::hash;
+use crate::path_info::ValidPathInfo;
+use crate::store::Error;
+use crate::store_path::StoreDir;
+
+pin_project! {
+    /// Hashes a NAR while it is being read and checks it against the NAR
+    /// hash and size of its path info.
+    ///
+    /// A mismatch is reported as an I/O error when the end of the NAR is
+    /// read, so a store that reads the whole NAR before adding it never sees
+    /// the end of a bad NAR. [`finish`](NarVerifier::finish) turns that into
+    /// the precise error.
+    pub(super) struct NarVerifier<R> {
+        #[pin]
+        inner: R,
+        path: String,
+        expected_hash: hash::Hash,
+        expected_size: u64,
+        size: u64,
+        ctx: Option<hash::Context>,
+        mismatch: Option<Error>,
+    }
+}
+
+impl<R> NarVerifier<R> {
+    pub(super) fn new(inner: R, store_dir: &StoreDir, info: &ValidPathInfo) -> NarVerifier<R> {
+        NarVerifier {
+            inner,
+            path: store_dir.print_path(&info.path),
+            expected_hash: info.nar_hash,
+            expected_size: info.nar_size,
+            size: 0,
+            ctx: Some(hash::Context::new(info.nar_hash.algorithm())),
+            mismatch: None,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> NarVerifier<R> {
+    /// Read whatever the store left unread and report a mismatch.
+    pub(super) async fn finish(mut self) -> Result<(), Error> {
+        let res = if self.ctx.is_some() {
+            tokio::io::copy(&mut self, &mut tokio::io::sink()).await
+        } else {
+            Ok(0)
+        };
+        match self.mismatch {
+            Some(err) => Err(err),
+            None => res.map(|_| ()).map_err(Error::from),
+        }
+    }
+
+    /// The mismatch found so far, if any.
+    pub(super) fn take_mismatch(&mut self) -> Option<Error> {
+        self.mismatch.take()
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for NarVerifier<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        if buf.remaining() == 0 {
+            return this.inner.poll_read(cx, buf);
+        }
+        let before = buf.filled().len();
+        ready!(this.inner.poll_read(cx, buf))?;
+        let read = &buf.filled()[before..];
+        if let Some(ctx) = this.ctx.as_mut() {
+            if !read.is_empty() {
+                ctx.update(read);
+                *this.size += read.len() as u64;
+                return Poll::Ready(Ok(()));
+            }
+            let nar_hash = this.ctx.take().unwrap().finish();
+            if nar_hash != *this.expected_hash {
+                *this.mismatch = Some(Error::NarHashMismatch(
+                    this.path.clone(),
+                    this.expected_hash.to_sri().to_string(),
+                    nar_hash.to_sri().to_string(),
+                ));
+            } else if *this.expected_size != 0 && *this.expected_size != *this.size {
+                *this.mismatch = Some(Error::NarSizeMismatch(
+                    this.path.clone(),
+                    *this.expected_size,
+                    *this.size,
+                ));
+            }
+        }
+        if let Some(err) = this.mismatch.as_ref() {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                err.to_string(),
+            )));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R> fmt::Debug for NarVerifier<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NarVerifier")
+            .field("path", &self.path)
+            .field("expected_hash", &self.expected_hash)
+            .field("expected_size", &self.expected_size)
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::store_path::StorePath;
+
+    fn info(nar: &[u8], nar_size: u64) -> ValidPathInfo {
+        let path =
+            StorePath::new_from_base_name("7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-konsole-18.12.3")
+                .unwrap();
+        let mut info = ValidPathInfo::new(path, hash::digest(hash::Algorithm::SHA256, nar));
+        info.nar_size = nar_size;
+        info
+    }
+
+    #[tokio::test]
+    async fn test_verify_ok() {
+        let store_dir = StoreDir::default();
+        let info = info(b"nar contents", 12);
+        let mut verifier = NarVerifier::new(&b"nar contents"[..], &store_dir, &info);
+        let mut buf = Vec::new();
+        verifier.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"nar contents");
+        verifier.finish().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_hash_mismatch() {
+        let store_dir = StoreDir::default();
+        let info = info(b"nar contents", 12);
+        let mut verifier = NarVerifier::new(&b"bad contents"[..], &store_dir, &info);
+        let mut buf = Vec::new();
+        let err = verifier.read_to_end(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_matches!(
+            verifier.take_mismatch(),
+            Some(Error::NarHashMismatch(_, _, _))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_size_mismatch_unread() {
+        let store_dir = StoreDir::default();
+        let info = info(b"nar contents", 20);
+        let verifier = NarVerifier::new(&b"nar contents"[..], &store_dir, &info);
+        assert_matches!(
+            verifier.finish().await,
+            Err(Error::NarSizeMismatch(_, 20, 12))
+        );
+    }
+}
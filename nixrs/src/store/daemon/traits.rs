@@ -1,13 +1,19 @@
+use std::collections::BTreeMap;
 use std::fmt;
 
 use async_trait::async_trait;
 use tokio::io::AsyncRead;
 use tracing::warn;
 
-use crate::store::{BuildMode, CheckSignaturesFlag, DerivedPath, Error, RepairFlag, Store};
+use crate::store::activity::{Activity, ResultKind};
+use crate::store::{
+    BuildMode, CheckSignaturesFlag, DerivedPath, DrvOutput, Error, ExperimentalFeatures,
+    OutputSpec, Realisation, RepairFlag, SingleDerivedPath, Store,
+};
 use crate::store_path::{StorePath, StorePathSet};
+use crate::StringSet;
 
-use super::TrustedFlag;
+use super::{DaemonPath, GCAction, TrustedFlag};
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
 pub struct QueryMissingResult {
@@ -18,9 +24,45 @@ pub struct QueryMissingResult {
     pub nar_size: u64,
 }
 
+/// What `CollectGarbage` should do.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct GCOptions {
+    pub action: GCAction,
+    /// The paths to delete with [`GCAction::DeleteSpecific`].
+    pub paths_to_delete: StorePathSet,
+    /// Delete paths even when they are reachable from a root.
+    pub ignore_liveness: bool,
+    /// Stop after freeing this many bytes.
+    pub max_freed: u64,
+}
+
+impl Default for GCOptions {
+    fn default() -> Self {
+        GCOptions {
+            action: GCAction::DeleteDead,
+            paths_to_delete: StorePathSet::new(),
+            ignore_liveness: false,
+            max_freed: u64::MAX,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct GCResults {
+    /// The live or dead paths for the `Return` actions and the deleted
+    /// paths for the `Delete` actions.
+    pub paths: StringSet,
+    pub bytes_freed: u64,
+}
+
 #[async_trait]
 pub trait DaemonStore: Store {
     fn is_trusted_client(&self) -> Option<TrustedFlag>;
+    /// The experimental features turned on for the store. The daemon server
+    /// refuses operations that need one that isn't.
+    fn experimental_features(&self) -> ExperimentalFeatures {
+        ExperimentalFeatures::new()
+    }
     async fn set_options(&mut self) -> Result<(), Error>;
     async fn is_valid_path(&mut self, path: &StorePath) -> Result<bool, Error>;
 
@@ -36,6 +78,101 @@ pub trait DaemonStore: Store {
     /// will be substituted.
     async fn query_missing(&mut self, targets: &[DerivedPath])
         -> Result<QueryMissingResult, Error>;
+    /// Store the build log of `drv_path` sent with `AddBuildLog`.
+    async fn add_build_log(&mut self, _drv_path: &StorePath, _log: &str) -> Result<(), Error> {
+        Err(Error::UnsupportedOperation("add_build_log".into()))
+    }
+    async fn collect_garbage(&mut self, _options: &GCOptions) -> Result<GCResults, Error> {
+        Err(Error::UnsupportedOperation("collect_garbage".into()))
+    }
+    /// Where the output `id` of a content-addressed derivation ended up, if
+    /// the store knows.
+    async fn query_realisation(&mut self, _id: &DrvOutput) -> Result<Option<Realisation>, Error> {
+        Err(Error::UnsupportedOperation("query_realisation".into()))
+    }
+    /// Record where an output of a content-addressed derivation ended up.
+    async fn register_drv_output(&mut self, _realisation: &Realisation) -> Result<(), Error> {
+        Err(Error::UnsupportedOperation("register_drv_output".into()))
+    }
+    /// Make the store path that the symlink at `path` points to a GC root
+    /// for as long as the symlink exists.
+    async fn add_indirect_root(&mut self, _path: &DaemonPath) -> Result<(), Error> {
+        Err(Error::UnsupportedOperation("add_indirect_root".into()))
+    }
+    /// All GC roots of the store, keyed by the link that makes them a root.
+    ///
+    /// Links the store doesn't want to show are left out.
+    async fn find_roots(&mut self) -> Result<BTreeMap<DaemonPath, StorePath>, Error> {
+        Err(Error::UnsupportedOperation("find_roots".into()))
+    }
+    /// Check that all valid paths are consistent and, with
+    /// `check_contents`, that their contents still match their NAR hash.
+    ///
+    /// Returns whether any problems were found. See
+    /// [`Verifier`](crate::store::Verifier) for checking stores that can
+    /// list their paths.
+    async fn verify_store(
+        &mut self,
+        _check_contents: bool,
+        _repair: RepairFlag,
+    ) -> Result<bool, Error> {
+        Err(Error::UnsupportedOperation("verify_store".into()))
+    }
+    /// Deduplicate identical files in the store by hard linking them.
+    async fn optimise_store(&mut self) -> Result<(), Error> {
+        Err(Error::UnsupportedOperation("optimise_store".into()))
+    }
+    /// Like [`collect_garbage`](DaemonStore::collect_garbage) but reports
+    /// every deleted path to `act` as a [`ResultKind::DeletedPath`].
+    ///
+    /// Stores that delete paths one at a time should report each path as
+    /// soon as it is gone. The default reports them all once
+    /// `collect_garbage` returns.
+    async fn collect_garbage_streaming(
+        &mut self,
+        options: &GCOptions,
+        act: &Activity,
+    ) -> Result<GCResults, Error> {
+        let results = self.collect_garbage(options).await?;
+        if options.action == GCAction::DeleteDead || options.action == GCAction::DeleteSpecific {
+            for path in results.paths.iter() {
+                act.report(ResultKind::DeletedPath {
+                    path: path.clone(),
+                    bytes: 0,
+                });
+            }
+        }
+        Ok(results)
+    }
+    /// Repair a corrupted or missing `path`.
+    ///
+    /// The path is substituted again with [`BuildMode::Repair`] and when
+    /// that fails its deriver, if still valid, is rebuilt in repair mode.
+    async fn repair_path(&mut self, path: &StorePath) -> Result<(), Error> {
+        let info = self
+            .query_path_info(path)
+            .await?
+            .ok_or_else(|| Error::InvalidPath(self.store_dir().print_path(path)))?;
+        let opaque = [DerivedPath::Opaque(path.clone())];
+        match self.build_paths(&opaque, BuildMode::Repair).await {
+            Ok(()) => Ok(()),
+            Err(err) => match info.deriver {
+                Some(deriver) if self.is_valid_path(&deriver).await? => {
+                    warn!(
+                        "could not substitute '{}', rebuilding it: {}",
+                        self.store_dir().print_path(path),
+                        err
+                    );
+                    let built = [DerivedPath::Built {
+                        drv_path: SingleDerivedPath::Opaque(deriver),
+                        outputs: OutputSpec::All,
+                    }];
+                    self.build_paths(&built, BuildMode::Repair).await
+                }
+                _ => Err(err),
+            },
+        }
+    }
     async fn substitute_paths(&mut self, paths: &StorePathSet) -> Result<(), Error> {
         let mut paths2 = Vec::new();
         for path in paths {
@@ -67,6 +204,10 @@ macro_rules! deref_daemon_store {
             (**self).is_trusted_client()
         }
 
+        fn experimental_features(&self) -> ExperimentalFeatures {
+            (**self).experimental_features()
+        }
+
         #[must_use]
         #[allow(clippy::type_complexity, clippy::type_repetition_in_bounds)]
         fn set_options<'life0, 'async_trait>(
@@ -146,6 +287,206 @@ macro_rules! deref_daemon_store {
         {
             (**self).query_missing(targets)
         }
+
+        #[must_use]
+        #[allow(clippy::type_complexity, clippy::type_repetition_in_bounds)]
+        fn add_build_log<'life0, 'life1, 'life2, 'async_trait>(
+            &'life0 mut self,
+            drv_path: &'life1 StorePath,
+            log: &'life2 str,
+        ) -> ::core::pin::Pin<
+            Box<
+                dyn ::core::future::Future<Output = Result<(), Error>>
+                    + ::core::marker::Send
+                    + 'async_trait,
+            >,
+        >
+        where
+            'life0: 'async_trait,
+            'life1: 'async_trait,
+            'life2: 'async_trait,
+            Self: 'async_trait,
+        {
+            (**self).add_build_log(drv_path, log)
+        }
+
+        #[must_use]
+        #[allow(clippy::type_complexity, clippy::type_repetition_in_bounds)]
+        fn repair_path<'life0, 'life1, 'async_trait>(
+            &'life0 mut self,
+            path: &'life1 StorePath,
+        ) -> ::core::pin::Pin<
+            Box<
+                dyn ::core::future::Future<Output = Result<(), Error>>
+                    + ::core::marker::Send
+                    + 'async_trait,
+            >,
+        >
+        where
+            'life0: 'async_trait,
+            'life1: 'async_trait,
+            Self: 'async_trait,
+        {
+            (**self).repair_path(path)
+        }
+
+        #[must_use]
+        #[allow(clippy::type_complexity, clippy::type_repetition_in_bounds)]
+        fn collect_garbage<'life0, 'life1, 'async_trait>(
+            &'life0 mut self,
+            options: &'life1 GCOptions,
+        ) -> ::core::pin::Pin<
+            Box<
+                dyn ::core::future::Future<Output = Result<GCResults, Error>>
+                    + ::core::marker::Send
+                    + 'async_trait,
+            >,
+        >
+        where
+            'life0: 'async_trait,
+            'life1: 'async_trait,
+            Self: 'async_trait,
+        {
+            (**self).collect_garbage(options)
+        }
+
+        #[must_use]
+        #[allow(clippy::type_complexity, clippy::type_repetition_in_bounds)]
+        fn query_realisation<'life0, 'life1, 'async_trait>(
+            &'life0 mut self,
+            id: &'life1 DrvOutput,
+        ) -> ::core::pin::Pin<
+            Box<
+                dyn ::core::future::Future<Output = Result<Option<Realisation>, Error>>
+                    + ::core::marker::Send
+                    + 'async_trait,
+            >,
+        >
+        where
+            'life0: 'async_trait,
+            'life1: 'async_trait,
+            Self: 'async_trait,
+        {
+            (**self).query_realisation(id)
+        }
+
+        #[must_use]
+        #[allow(clippy::type_complexity, clippy::type_repetition_in_bounds)]
+        fn register_drv_output<'life0, 'life1, 'async_trait>(
+            &'life0 mut self,
+            realisation: &'life1 Realisation,
+        ) -> ::core::pin::Pin<
+            Box<
+                dyn ::core::future::Future<Output = Result<(), Error>>
+                    + ::core::marker::Send
+                    + 'async_trait,
+            >,
+        >
+        where
+            'life0: 'async_trait,
+            'life1: 'async_trait,
+            Self: 'async_trait,
+        {
+            (**self).register_drv_output(realisation)
+        }
+
+        #[must_use]
+        #[allow(clippy::type_complexity, clippy::type_repetition_in_bounds)]
+        fn add_indirect_root<'life0, 'life1, 'async_trait>(
+            &'life0 mut self,
+            path: &'life1 DaemonPath,
+        ) -> ::core::pin::Pin<
+            Box<
+                dyn ::core::future::Future<Output = Result<(), Error>>
+                    + ::core::marker::Send
+                    + 'async_trait,
+            >,
+        >
+        where
+            'life0: 'async_trait,
+            'life1: 'async_trait,
+            Self: 'async_trait,
+        {
+            (**self).add_indirect_root(path)
+        }
+
+        #[must_use]
+        #[allow(clippy::type_complexity, clippy::type_repetition_in_bounds)]
+        fn find_roots<'life0, 'async_trait>(
+            &'life0 mut self,
+        ) -> ::core::pin::Pin<
+            Box<
+                dyn ::core::future::Future<Output = Result<BTreeMap<DaemonPath, StorePath>, Error>>
+                    + ::core::marker::Send
+                    + 'async_trait,
+            >,
+        >
+        where
+            'life0: 'async_trait,
+            Self: 'async_trait,
+        {
+            (**self).find_roots()
+        }
+
+        #[must_use]
+        #[allow(clippy::type_complexity, clippy::type_repetition_in_bounds)]
+        fn verify_store<'life0, 'async_trait>(
+            &'life0 mut self,
+            check_contents: bool,
+            repair: RepairFlag,
+        ) -> ::core::pin::Pin<
+            Box<
+                dyn ::core::future::Future<Output = Result<bool, Error>>
+                    + ::core::marker::Send
+                    + 'async_trait,
+            >,
+        >
+        where
+            'life0: 'async_trait,
+            Self: 'async_trait,
+        {
+            (**self).verify_store(check_contents, repair)
+        }
+
+        #[must_use]
+        #[allow(clippy::type_complexity, clippy::type_repetition_in_bounds)]
+        fn optimise_store<'life0, 'async_trait>(
+            &'life0 mut self,
+        ) -> ::core::pin::Pin<
+            Box<
+                dyn ::core::future::Future<Output = Result<(), Error>>
+                    + ::core::marker::Send
+                    + 'async_trait,
+            >,
+        >
+        where
+            'life0: 'async_trait,
+            Self: 'async_trait,
+        {
+            (**self).optimise_store()
+        }
+
+        #[must_use]
+        #[allow(clippy::type_complexity, clippy::type_repetition_in_bounds)]
+        fn collect_garbage_streaming<'life0, 'life1, 'life2, 'async_trait>(
+            &'life0 mut self,
+            options: &'life1 GCOptions,
+            act: &'life2 Activity,
+        ) -> ::core::pin::Pin<
+            Box<
+                dyn ::core::future::Future<Output = Result<GCResults, Error>>
+                    + ::core::marker::Send
+                    + 'async_trait,
+            >,
+        >
+        where
+            'life0: 'async_trait,
+            'life1: 'async_trait,
+            'life2: 'async_trait,
+            Self: 'async_trait,
+        {
+            (**self).collect_garbage_streaming(options, act)
+        }
     };
 }
 
@@ -156,3 +497,159 @@ impl<T: ?Sized + DaemonStore + Unpin + Send> DaemonStore for Box<T> {
 impl<T: ?Sized + DaemonStore + Unpin + Send> DaemonStore for &mut T {
     deref_daemon_store!();
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWrite;
+
+    use super::*;
+    use crate::hash;
+    use crate::path_info::ValidPathInfo;
+    use crate::store::MemoryStore;
+    use crate::store_path::{StoreDir, StoreDirProvider};
+
+    /// Records the builds it is asked for and fails the ones of opaque
+    /// paths, as if no substituter had the path.
+    struct RepairStore {
+        inner: MemoryStore,
+        builds: Vec<(Vec<DerivedPath>, BuildMode)>,
+    }
+
+    impl StoreDirProvider for RepairStore {
+        fn store_dir(&self) -> StoreDir {
+            self.inner.store_dir()
+        }
+    }
+
+    #[async_trait]
+    impl Store for RepairStore {
+        async fn query_path_info(
+            &mut self,
+            path: &StorePath,
+        ) -> Result<Option<ValidPathInfo>, Error> {
+            self.inner.query_path_info(path).await
+        }
+
+        async fn nar_from_path<W: AsyncWrite + fmt::Debug + Send + Unpin>(
+            &mut self,
+            path: &StorePath,
+            sink: W,
+        ) -> Result<(), Error> {
+            self.inner.nar_from_path(path, sink).await
+        }
+
+        async fn add_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+            &mut self,
+            info: &ValidPathInfo,
+            source: R,
+            repair: RepairFlag,
+            check_sigs: CheckSignaturesFlag,
+        ) -> Result<(), Error> {
+            self.inner
+                .add_to_store(info, source, repair, check_sigs)
+                .await
+        }
+
+        async fn build_paths(
+            &mut self,
+            drv_paths: &[DerivedPath],
+            build_mode: BuildMode,
+        ) -> Result<(), Error> {
+            self.builds.push((drv_paths.to_vec(), build_mode));
+            match drv_paths {
+                [DerivedPath::Opaque(_)] => Err(Error::UnsupportedOperation("substitute".into())),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl DaemonStore for RepairStore {
+        fn is_trusted_client(&self) -> Option<TrustedFlag> {
+            Some(TrustedFlag::Trusted)
+        }
+
+        async fn set_options(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn is_valid_path(&mut self, path: &StorePath) -> Result<bool, Error> {
+            self.inner.is_valid_path(path).await
+        }
+
+        async fn add_multiple_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+            &mut self,
+            source: R,
+            repair: RepairFlag,
+            check_sigs: CheckSignaturesFlag,
+        ) -> Result<(), Error> {
+            self.inner
+                .add_multiple_to_store(source, repair, check_sigs)
+                .await
+        }
+
+        async fn query_missing(
+            &mut self,
+            targets: &[DerivedPath],
+        ) -> Result<QueryMissingResult, Error> {
+            self.inner.query_missing(targets).await
+        }
+    }
+
+    async fn add(store: &mut MemoryStore, name: &str, deriver: Option<&StorePath>) -> StorePath {
+        let nar = name.as_bytes();
+        let mut info = ValidPathInfo::new(
+            StorePath::test_from_seed(name),
+            hash::digest(hash::Algorithm::SHA256, nar),
+        );
+        info.nar_size = nar.len() as u64;
+        info.deriver = deriver.cloned();
+        store
+            .add_to_store(
+                &info,
+                nar,
+                RepairFlag::NoRepair,
+                CheckSignaturesFlag::NoCheckSigs,
+            )
+            .await
+            .unwrap();
+        info.path
+    }
+
+    #[tokio::test]
+    async fn test_repair_path_rebuilds_deriver() {
+        let mut inner = MemoryStore::new();
+        let drv = add(&mut inner, "foo.drv", None).await;
+        let path = add(&mut inner, "foo", Some(&drv)).await;
+        let mut store = RepairStore {
+            inner,
+            builds: Vec::new(),
+        };
+        store.repair_path(&path).await.unwrap();
+        assert_eq!(
+            store.builds,
+            vec![
+                (vec![DerivedPath::Opaque(path)], BuildMode::Repair),
+                (
+                    vec![DerivedPath::Built {
+                        drv_path: SingleDerivedPath::Opaque(drv),
+                        outputs: OutputSpec::All,
+                    }],
+                    BuildMode::Repair
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_repair_path_without_deriver() {
+        let mut inner = MemoryStore::new();
+        let path = add(&mut inner, "foo", None).await;
+        let mut store = RepairStore {
+            inner,
+            builds: Vec::new(),
+        };
+        assert!(store.repair_path(&path).await.is_err());
+        assert_eq!(store.builds.len(), 1);
+    }
+}
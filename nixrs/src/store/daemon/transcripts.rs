@@ -0,0 +1,312 @@
+//! Golden handshake transcripts for every protocol minor we talk to.
+//!
+//! The bytes here are written out from the protocol description in Nix's
+//! `daemon.cc` and `remote-store.cc` and not from our own encoders, so that
+//! a change in the order or width of a handshake field fails these tests
+//! instead of only showing up against a real daemon.
+
+use std::io::Cursor;
+
+use pretty_assertions::assert_eq;
+use tokio::io::AsyncReadExt;
+
+use super::{
+    run_server, TrustedFlag, PROTOCOL_VERSION, STDERR_LAST, WORKER_MAGIC_1, WORKER_MAGIC_2,
+};
+use crate::store::assert_store::AssertStore;
+use crate::store::SubstituteFlag;
+use crate::store_path::StorePathSet;
+
+/// Instantiate an async test body once for every protocol version from 1.21
+/// to 1.37, so that a failure shows which version broke.
+///
+/// ```ignore
+/// protocol_matrix! {
+///     async fn test_something(version: u64) skip(21, 22) {
+///         ...
+///     }
+/// }
+/// ```
+///
+/// generates a module `test_something` with tests `protocol_1_21` through
+/// `protocol_1_37`. Versions listed in the optional `skip` clause return
+/// without running the body.
+macro_rules! protocol_matrix {
+    (async fn $name:ident($version:ident: u64) $(skip($($skip:literal),*))? $body:block) => {
+        mod $name {
+            #[allow(unused_imports)]
+            use super::*;
+            // The glob import above would make this ambiguous with the
+            // prelude macro.
+            #[allow(unused_imports)]
+            use pretty_assertions::assert_eq;
+
+            async fn run($version: u64) {
+                let skipped: &[u64] = &[$($($skip),*)?];
+                if skipped.contains(&($version & 0xff)) {
+                    return;
+                }
+                $body
+            }
+
+            #[tokio::test]
+            async fn protocol_1_21() {
+                run(1 << 8 | 21).await
+            }
+            #[tokio::test]
+            async fn protocol_1_22() {
+                run(1 << 8 | 22).await
+            }
+            #[tokio::test]
+            async fn protocol_1_23() {
+                run(1 << 8 | 23).await
+            }
+            #[tokio::test]
+            async fn protocol_1_24() {
+                run(1 << 8 | 24).await
+            }
+            #[tokio::test]
+            async fn protocol_1_25() {
+                run(1 << 8 | 25).await
+            }
+            #[tokio::test]
+            async fn protocol_1_26() {
+                run(1 << 8 | 26).await
+            }
+            #[tokio::test]
+            async fn protocol_1_27() {
+                run(1 << 8 | 27).await
+            }
+            #[tokio::test]
+            async fn protocol_1_28() {
+                run(1 << 8 | 28).await
+            }
+            #[tokio::test]
+            async fn protocol_1_29() {
+                run(1 << 8 | 29).await
+            }
+            #[tokio::test]
+            async fn protocol_1_30() {
+                run(1 << 8 | 30).await
+            }
+            #[tokio::test]
+            async fn protocol_1_31() {
+                run(1 << 8 | 31).await
+            }
+            #[tokio::test]
+            async fn protocol_1_32() {
+                run(1 << 8 | 32).await
+            }
+            #[tokio::test]
+            async fn protocol_1_33() {
+                run(1 << 8 | 33).await
+            }
+            #[tokio::test]
+            async fn protocol_1_34() {
+                run(1 << 8 | 34).await
+            }
+            #[tokio::test]
+            async fn protocol_1_35() {
+                run(1 << 8 | 35).await
+            }
+            #[tokio::test]
+            async fn protocol_1_36() {
+                run(1 << 8 | 36).await
+            }
+            #[tokio::test]
+            async fn protocol_1_37() {
+                run(1 << 8 | 37).await
+            }
+        }
+    };
+}
+pub(crate) use protocol_matrix;
+
+/// The nix version the server reports from protocol 1.33 on.
+const SERVER_NIX_VERSION: &[u8] = b"nix.rs 1.2.3";
+
+fn word(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn string(buf: &mut Vec<u8>, value: &[u8]) {
+    word(buf, value.len() as u64);
+    buf.extend_from_slice(value);
+    let padding = (8 - value.len() % 8) % 8;
+    buf.resize(buf.len() + padding, 0);
+}
+
+/// What a client speaking protocol `1.minor` sends during the handshake.
+pub(crate) fn client_hello(minor: u64) -> Vec<u8> {
+    let mut buf = Vec::new();
+    word(&mut buf, WORKER_MAGIC_1);
+    word(&mut buf, 1 << 8 | minor);
+    if minor >= 14 {
+        // No CPU affinity.
+        word(&mut buf, 0);
+    }
+    if minor >= 11 {
+        // reserveSpace = false
+        word(&mut buf, 0);
+    }
+    buf
+}
+
+/// What a daemon speaking protocol `1.minor` sends during the handshake,
+/// ending with the `STDERR_LAST` of the startup messages.
+pub(crate) fn server_hello(minor: u64, nix_version: &[u8], trust: u64) -> Vec<u8> {
+    let mut buf = Vec::new();
+    word(&mut buf, WORKER_MAGIC_2);
+    word(&mut buf, 1 << 8 | minor);
+    if minor >= 33 {
+        string(&mut buf, nix_version);
+    }
+    if minor >= 35 {
+        word(&mut buf, trust);
+    }
+    word(&mut buf, STDERR_LAST);
+    buf
+}
+
+#[test]
+fn test_transcript_encoding() {
+    // Spot check the helpers against fully spelled out transcripts.
+    assert_eq!(
+        client_hello(35),
+        hex::decode(concat!(
+            "6378696e00000000",
+            "2301000000000000",
+            "0000000000000000",
+            "0000000000000000",
+        ))
+        .unwrap()
+    );
+    assert_eq!(
+        server_hello(35, b"nix.rs 1.2.3", 1),
+        hex::decode(concat!(
+            "6f69786400000000",
+            "2301000000000000",
+            "0c00000000000000",
+            "6e69782e727320312e322e3300000000",
+            "0100000000000000",
+            "73746c6100000000",
+        ))
+        .unwrap()
+    );
+    assert_eq!(
+        server_hello(21, b"ignored", 1),
+        hex::decode(concat!(
+            "6f69786400000000",
+            "1501000000000000",
+            "73746c6100000000",
+        ))
+        .unwrap()
+    );
+}
+
+protocol_matrix! {
+    async fn test_server_handshake_transcripts(version: u64) {
+        let minor = version & 0xff;
+        let store = AssertStore::assert_query_valid_paths(
+            Some(TrustedFlag::Trusted),
+            &StorePathSet::new(),
+            SubstituteFlag::NoSubstitute,
+            Ok(StorePathSet::new()),
+        );
+        let (out, mut daemon) = tokio::io::duplex(64 * 1024);
+        run_server(
+            Cursor::new(client_hello(minor)),
+            out,
+            store,
+            TrustedFlag::Trusted,
+        )
+        .await
+        .unwrap();
+
+        let mut actual = Vec::new();
+        daemon.read_to_end(&mut actual).await.unwrap();
+        let mut expected = server_hello(minor, SERVER_NIX_VERSION, 1);
+        // The server always announces its own version.
+        expected[8..16].copy_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+        assert_eq!(actual, expected);
+    }
+}
+
+#[tokio::test]
+async fn test_server_handshake_untrusted() {
+    let store = AssertStore::assert_query_valid_paths(
+        Some(TrustedFlag::Trusted),
+        &StorePathSet::new(),
+        SubstituteFlag::NoSubstitute,
+        Ok(StorePathSet::new()),
+    );
+    let (out, mut daemon) = tokio::io::duplex(64 * 1024);
+    run_server(
+        Cursor::new(client_hello(35)),
+        out,
+        store,
+        TrustedFlag::NotTrusted,
+    )
+    .await
+    .unwrap();
+
+    let mut actual = Vec::new();
+    daemon.read_to_end(&mut actual).await.unwrap();
+    assert_eq!(actual, server_hello(35, SERVER_NIX_VERSION, 2));
+}
+
+#[tokio::test]
+async fn test_server_obsolete_path_info_ops() {
+    use crate::hash;
+    use crate::path_info::ValidPathInfo;
+    use crate::store_path::{StoreDir, StorePath};
+
+    let store_dir = StoreDir::default();
+    let path =
+        StorePath::new_from_base_name("7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-konsole-18.12.3").unwrap();
+    let deriver =
+        StorePath::new_from_base_name("ldhh7c134ap5swsm86rqnc0i7cinqvrc-konsole-18.12.3.drv")
+            .unwrap();
+    let mut info = ValidPathInfo::new(path.clone(), hash::digest(hash::Algorithm::SHA256, "nar"));
+    info.deriver = Some(deriver.clone());
+    let path_s = store_dir.print_path(&path);
+
+    let ops: [(u64, Vec<u8>); 3] = [
+        // QueryPathHash
+        (4, {
+            let mut buf = Vec::new();
+            string(&mut buf, info.nar_hash.encode_base16().as_bytes());
+            buf
+        }),
+        // QueryReferences
+        (5, {
+            let mut buf = Vec::new();
+            word(&mut buf, 0);
+            buf
+        }),
+        // QueryDeriver
+        (18, {
+            let mut buf = Vec::new();
+            string(&mut buf, store_dir.print_path(&deriver).as_bytes());
+            buf
+        }),
+    ];
+    for (op, reply) in ops {
+        let store = AssertStore::assert_query_path_info(None, &path, Ok(Some(info.clone())));
+        let mut request = client_hello(15);
+        word(&mut request, op);
+        string(&mut request, path_s.as_bytes());
+        let (out, mut daemon) = tokio::io::duplex(64 * 1024);
+        run_server(Cursor::new(request), out, store, TrustedFlag::Trusted)
+            .await
+            .unwrap();
+
+        let mut actual = Vec::new();
+        daemon.read_to_end(&mut actual).await.unwrap();
+        let mut expected = server_hello(15, SERVER_NIX_VERSION, 1);
+        expected[8..16].copy_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+        word(&mut expected, STDERR_LAST);
+        expected.extend_from_slice(&reply);
+        assert_eq!(actual, expected);
+    }
+}
@@ -0,0 +1,407 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::str::FromStr;
+
+use tokio::process::{Child, Command};
+use tracing::warn;
+use url::Url;
+
+use crate::store::Error;
+use crate::store_path::StoreDir;
+
+use super::{DaemonStoreClient, DynReader, DynWriter};
+
+/// Socket of the local daemon unless `NIX_DAEMON_SOCKET_PATH` says otherwise.
+pub const DEFAULT_DAEMON_SOCKET: &str = "/nix/var/nix/daemon-socket/socket";
+
+/// Where a daemon store lives.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreLocation {
+    /// A daemon listening on a Unix socket, `daemon` or `unix://<path>`.
+    Unix(PathBuf),
+    /// `nix-daemon --stdio` on another machine, `ssh-ng://[user@]host[:port]`.
+    SshNg {
+        host: String,
+        user: Option<String>,
+        port: Option<u16>,
+    },
+}
+
+/// The parameters of a store URI that the daemon stores understand.
+///
+/// They are named like the settings of the C++ stores, so the same URIs
+/// work for both. Parameters this doesn't know about are kept in
+/// [`other`](StoreParams::other).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoreParams {
+    /// `remote-program`: what is run on the remote to talk to it.
+    pub remote_program: String,
+    /// `remote-store`: the store the remote program should use.
+    pub remote_store: Option<String>,
+    /// `compress`: let ssh compress the connection.
+    pub compress: bool,
+    /// `ssh-key`: identity file for ssh.
+    pub ssh_key: Option<PathBuf>,
+    /// `max-connections`: connections a pool keeps open at once.
+    pub max_connections: usize,
+    /// `path-info-cache-size`: path infos each connection remembers.
+    pub path_info_cache_size: usize,
+    pub other: BTreeMap<String, String>,
+}
+
+impl Default for StoreParams {
+    fn default() -> Self {
+        StoreParams {
+            remote_program: "nix-daemon".into(),
+            remote_store: None,
+            compress: false,
+            ssh_key: None,
+            max_connections: 1,
+            path_info_cache_size: 65536,
+            other: BTreeMap::new(),
+        }
+    }
+}
+
+impl StoreParams {
+    fn set(&mut self, uri: &str, key: &str, value: &str) -> Result<(), Error> {
+        let bad = |reason: String| Error::BadStoreUri(uri.into(), reason);
+        let number = |value: &str| {
+            value
+                .parse::<usize>()
+                .map_err(|_| bad(format!("'{}' should be a number, not '{}'", key, value)))
+        };
+        match key {
+            "remote-program" => self.remote_program = value.into(),
+            "remote-store" => self.remote_store = Some(value.into()),
+            "compress" => {
+                self.compress = match value {
+                    "true" | "1" => true,
+                    "false" | "0" => false,
+                    _ => {
+                        return Err(bad(format!(
+                            "'compress' should be a boolean, not '{}'",
+                            value
+                        )))
+                    }
+                }
+            }
+            "ssh-key" => self.ssh_key = Some(value.into()),
+            "max-connections" => {
+                self.max_connections = number(value)?;
+                if self.max_connections == 0 {
+                    return Err(bad("'max-connections' must be at least 1".into()));
+                }
+            }
+            "path-info-cache-size" => self.path_info_cache_size = number(value)?,
+            _ => {
+                warn!("unknown store setting '{}' in '{}'", key, uri);
+                self.other.insert(key.into(), value.into());
+            }
+        }
+        Ok(())
+    }
+
+    fn write_query(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let defaults = StoreParams::default();
+        let mut query = url::form_urlencoded::Serializer::new(String::new());
+        if self.remote_program != defaults.remote_program {
+            query.append_pair("remote-program", &self.remote_program);
+        }
+        if let Some(store) = self.remote_store.as_ref() {
+            query.append_pair("remote-store", store);
+        }
+        if self.compress {
+            query.append_pair("compress", "true");
+        }
+        if let Some(key) = self.ssh_key.as_ref() {
+            query.append_pair("ssh-key", &key.to_string_lossy());
+        }
+        if self.max_connections != defaults.max_connections {
+            query.append_pair("max-connections", &self.max_connections.to_string());
+        }
+        if self.path_info_cache_size != defaults.path_info_cache_size {
+            query.append_pair(
+                "path-info-cache-size",
+                &self.path_info_cache_size.to_string(),
+            );
+        }
+        for (key, value) in self.other.iter() {
+            query.append_pair(key, value);
+        }
+        let query = query.finish();
+        if !query.is_empty() {
+            write!(f, "?{}", query)?;
+        }
+        Ok(())
+    }
+}
+
+/// A parsed `daemon`, `unix://` or `ssh-ng://` store URI.
+///
+/// ```
+/// # use nixrs::store::daemon::{StoreLocation, StoreUri};
+/// let uri: StoreUri = "ssh-ng://builder?compress=true&max-connections=4"
+///     .parse()
+///     .unwrap();
+/// assert!(matches!(uri.location, StoreLocation::SshNg { .. }));
+/// assert!(uri.params.compress);
+/// assert_eq!(uri.params.max_connections, 4);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoreUri {
+    pub location: StoreLocation,
+    pub params: StoreParams,
+}
+
+impl StoreUri {
+    /// The `ssh` command that starts the remote program.
+    ///
+    /// All options go before a `--` that ends them, so a host or user
+    /// starting with `-` can't be taken for an option.
+    pub fn ssh_command(&self) -> Option<Command> {
+        let (host, user, port) = match &self.location {
+            StoreLocation::SshNg { host, user, port } => (host, user, port),
+            StoreLocation::Unix(_) => return None,
+        };
+        let mut cmd = Command::new("ssh");
+        cmd.args(["-x", "-a"]);
+        if let Some(port) = port {
+            cmd.arg("-p").arg(port.to_string());
+        }
+        if let Some(key) = self.params.ssh_key.as_ref() {
+            cmd.arg("-i").arg(key);
+        }
+        if self.params.compress {
+            cmd.arg("-C");
+        }
+        cmd.arg("--");
+        match user {
+            Some(user) => cmd.arg(format!("{}@{}", user, host)),
+            None => cmd.arg(host),
+        };
+        cmd.arg(&self.params.remote_program).arg("--stdio");
+        if let Some(store) = self.params.remote_store.as_ref() {
+            cmd.arg("--store").arg(store);
+        }
+        Some(cmd)
+    }
+
+    /// Opens a connection to the daemon and does the handshake.
+    pub async fn connect(&self, store_dir: StoreDir) -> Result<DaemonConnection, Error> {
+        let mut builder = DaemonStoreClient::builder();
+        builder
+            .store_dir(store_dir)
+            .host(self.to_string())
+            .path_info_cache_size(self.params.path_info_cache_size);
+        let (reader, writer, child) = match &self.location {
+            #[cfg(unix)]
+            StoreLocation::Unix(path) => {
+                let (reader, writer) = tokio::net::UnixStream::connect(path).await?.into_split();
+                (DynReader::new(reader), DynWriter::new(writer), None)
+            }
+            #[cfg(not(unix))]
+            StoreLocation::Unix(_) => {
+                return Err(Error::UnsupportedOperation("unix sockets".into()));
+            }
+            StoreLocation::SshNg { .. } => {
+                let mut cmd = self.ssh_command().unwrap();
+                let mut child = cmd
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::inherit())
+                    .spawn()?;
+                let reader = child.stdout.take().unwrap();
+                let writer = child.stdin.take().unwrap();
+                (DynReader::new(reader), DynWriter::new(writer), Some(child))
+            }
+        };
+        let client = builder.connect(reader, writer).await?;
+        Ok(DaemonConnection { client, child })
+    }
+}
+
+impl FromStr for StoreUri {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (base, query) = s.split_once('?').unwrap_or((s, ""));
+        let location = if base == "daemon" || base == "unix://" {
+            let socket = std::env::var_os("NIX_DAEMON_SOCKET_PATH")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| DEFAULT_DAEMON_SOCKET.into());
+            StoreLocation::Unix(socket)
+        } else if let Some(path) = base.strip_prefix("unix://") {
+            StoreLocation::Unix(path.into())
+        } else if base.starts_with("ssh-ng://") {
+            let url = Url::parse(base)?;
+            let host = match url.host_str() {
+                Some(host) if !host.is_empty() => host.to_string(),
+                _ => return Err(Error::BadStoreUri(s.into(), "no host".into())),
+            };
+            let user = Some(url.username())
+                .filter(|user| !user.is_empty())
+                .map(ToString::to_string);
+            StoreLocation::SshNg {
+                host,
+                user,
+                port: url.port(),
+            }
+        } else {
+            return Err(Error::BadStoreUri(
+                s.into(),
+                "only 'daemon', 'unix://' and 'ssh-ng://' are supported".into(),
+            ));
+        };
+        let mut params = StoreParams::default();
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            params.set(s, &key, &value)?;
+        }
+        Ok(StoreUri { location, params })
+    }
+}
+
+impl fmt::Display for StoreUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.location {
+            StoreLocation::Unix(path) if path.as_os_str() == DEFAULT_DAEMON_SOCKET => {
+                write!(f, "daemon")?
+            }
+            StoreLocation::Unix(path) => write!(f, "unix://{}", path.display())?,
+            StoreLocation::SshNg { host, user, port } => {
+                write!(f, "ssh-ng://")?;
+                if let Some(user) = user {
+                    write!(f, "{}@", user)?;
+                }
+                write!(f, "{}", host)?;
+                if let Some(port) = port {
+                    write!(f, ":{}", port)?;
+                }
+            }
+        }
+        self.params.write_query(f)
+    }
+}
+
+pub type DaemonConnectionClient = DaemonStoreClient<DynReader<'static>, DynWriter<'static>>;
+
+/// A connection opened by [`StoreUri::connect`].
+#[derive(Debug)]
+pub struct DaemonConnection {
+    pub client: DaemonConnectionClient,
+    child: Option<Child>,
+}
+
+impl DaemonConnection {
+    /// Closes the connection and waits for `ssh` to exit.
+    pub async fn close(mut self) -> Result<(), Error> {
+        self.client.close().await?;
+        drop(self.client);
+        if let Some(mut child) = self.child.take() {
+            child.wait().await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ssh_ng() {
+        let uri: StoreUri = "ssh-ng://nix@builder:2222?remote-program=/run/bin/nix-daemon&compress=true&ssh-key=/etc/key&path-info-cache-size=10"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            uri.location,
+            StoreLocation::SshNg {
+                host: "builder".into(),
+                user: Some("nix".into()),
+                port: Some(2222),
+            }
+        );
+        assert_eq!(uri.params.remote_program, "/run/bin/nix-daemon");
+        assert!(uri.params.compress);
+        assert_eq!(uri.params.path_info_cache_size, 10);
+        assert_eq!(uri.params.max_connections, 1);
+        assert_eq!(uri.to_string().parse::<StoreUri>().unwrap(), uri);
+
+        let cmd = uri.ssh_command().unwrap();
+        let args: Vec<_> = cmd
+            .as_std()
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(
+            args,
+            [
+                "-x",
+                "-a",
+                "-p",
+                "2222",
+                "-i",
+                "/etc/key",
+                "-C",
+                "--",
+                "nix@builder",
+                "/run/bin/nix-daemon",
+                "--stdio"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ssh_command_host_is_not_an_option() {
+        let uri = StoreUri {
+            location: StoreLocation::SshNg {
+                host: "-oProxyCommand=touch pwned".into(),
+                user: None,
+                port: None,
+            },
+            params: StoreParams::default(),
+        };
+        let cmd = uri.ssh_command().unwrap();
+        let args: Vec<_> = cmd
+            .as_std()
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        let end = args.iter().position(|arg| arg == "--").unwrap();
+        assert!(args[end + 1].starts_with("-o"));
+        assert!(!args[..end].iter().any(|arg| arg.starts_with("-o")));
+    }
+
+    #[test]
+    fn test_parse_unix() {
+        let uri: StoreUri = "unix:///tmp/socket?max-connections=3&foo=bar"
+            .parse()
+            .unwrap();
+        assert_eq!(uri.location, StoreLocation::Unix("/tmp/socket".into()));
+        assert_eq!(uri.params.max_connections, 3);
+        assert_eq!(uri.params.other["foo"], "bar");
+        assert!(uri.ssh_command().is_none());
+        assert_eq!(
+            uri.to_string(),
+            "unix:///tmp/socket?max-connections=3&foo=bar"
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(matches!(
+            "https://cache.nixos.org".parse::<StoreUri>(),
+            Err(Error::BadStoreUri(..))
+        ));
+        assert!(matches!(
+            "ssh-ng://host?max-connections=0".parse::<StoreUri>(),
+            Err(Error::BadStoreUri(..))
+        ));
+        assert!(matches!(
+            "ssh-ng://host?compress=maybe".parse::<StoreUri>(),
+            Err(Error::BadStoreUri(..))
+        ));
+    }
+}
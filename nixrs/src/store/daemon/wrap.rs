@@ -1 +1,329 @@
+use std::collections::BTreeMap;
+use std::fmt;
 
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::path_info::ValidPathInfo;
+use crate::store::activity::Activity;
+use crate::store::legacy_worker::LegacyStore;
+use crate::store::misc::add_multiple_to_store_old;
+use crate::store::{
+    query_missing_slow, BasicDerivation, BuildMode, BuildResult, CheckSignaturesFlag, DerivedPath,
+    DrvOutput, Error, ExperimentalFeatures, Realisation, RepairFlag, Store, SubstituteFlag,
+};
+use crate::store_path::{StoreDir, StoreDirProvider, StorePath, StorePathSet};
+
+use super::{DaemonPath, DaemonStore, GCOptions, GCResults, QueryMissingResult, TrustedFlag};
+
+/// Serves any [`Store`] as a [`DaemonStore`], e.g. a
+/// [`LegacyStoreClient`](crate::store::legacy_worker::LegacyStoreClient) so
+/// that daemon clients can use a store only reachable with the legacy serve
+/// protocol.
+///
+/// The daemon only operations are answered from the [`Store`] operations
+/// and paths are repaired by building them in repair mode. The ones a
+/// [`Store`] can't answer, like collecting garbage or querying
+/// realisations, fail with [`Error::UnsupportedOperation`]. Nothing is ever
+/// substituted. [`LegacyStore`] operations are passed on when
+/// the wrapped store has them, so the same wrapped store can be served with
+/// both protocols.
+#[derive(Clone, Debug)]
+pub struct DaemonWrapStore<S> {
+    store: S,
+    trusted_client: Option<TrustedFlag>,
+    experimental_features: ExperimentalFeatures,
+}
+
+impl<S> DaemonWrapStore<S> {
+    pub fn new(store: S) -> DaemonWrapStore<S> {
+        DaemonWrapStore {
+            store,
+            trusted_client: None,
+            experimental_features: ExperimentalFeatures::new(),
+        }
+    }
+
+    /// What to report to clients as their trust status. Defaults to
+    /// unknown.
+    pub fn with_trusted_client(store: S, trusted: TrustedFlag) -> DaemonWrapStore<S> {
+        DaemonWrapStore {
+            store,
+            trusted_client: Some(trusted),
+            experimental_features: ExperimentalFeatures::new(),
+        }
+    }
+
+    /// The experimental features to report for the wrapped store, which
+    /// has no way to say. Defaults to none.
+    pub fn with_experimental_features(mut self, features: ExperimentalFeatures) -> Self {
+        self.experimental_features = features;
+        self
+    }
+
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+}
+
+impl<S: StoreDirProvider> StoreDirProvider for DaemonWrapStore<S> {
+    fn store_dir(&self) -> StoreDir {
+        self.store.store_dir()
+    }
+}
+
+#[async_trait]
+impl<S: Store + Send> Store for DaemonWrapStore<S> {
+    async fn query_valid_paths(
+        &mut self,
+        paths: &StorePathSet,
+        maybe_substitute: SubstituteFlag,
+    ) -> Result<StorePathSet, Error> {
+        self.store.query_valid_paths(paths, maybe_substitute).await
+    }
+
+    async fn query_path_info(&mut self, path: &StorePath) -> Result<Option<ValidPathInfo>, Error> {
+        self.store.query_path_info(path).await
+    }
+
+    async fn nar_from_path<W: AsyncWrite + fmt::Debug + Send + Unpin>(
+        &mut self,
+        path: &StorePath,
+        sink: W,
+    ) -> Result<(), Error> {
+        self.store.nar_from_path(path, sink).await
+    }
+
+    async fn add_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        info: &ValidPathInfo,
+        source: R,
+        repair: RepairFlag,
+        check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error> {
+        self.store
+            .add_to_store(info, source, repair, check_sigs)
+            .await
+    }
+
+    async fn build_derivation(
+        &mut self,
+        drv_path: &StorePath,
+        drv: &BasicDerivation,
+        build_mode: BuildMode,
+    ) -> Result<BuildResult, Error> {
+        self.store.build_derivation(drv_path, drv, build_mode).await
+    }
+
+    async fn build_paths(
+        &mut self,
+        drv_paths: &[DerivedPath],
+        build_mode: BuildMode,
+    ) -> Result<(), Error> {
+        self.store.build_paths(drv_paths, build_mode).await
+    }
+}
+
+#[async_trait]
+impl<S> DaemonStore for DaemonWrapStore<S>
+where
+    S: Store + fmt::Debug + Send + Unpin,
+{
+    fn is_trusted_client(&self) -> Option<TrustedFlag> {
+        self.trusted_client
+    }
+
+    fn experimental_features(&self) -> ExperimentalFeatures {
+        self.experimental_features.clone()
+    }
+
+    async fn set_options(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn is_valid_path(&mut self, path: &StorePath) -> Result<bool, Error> {
+        let paths: StorePathSet = [path.clone()].into_iter().collect();
+        let valid = self
+            .store
+            .query_valid_paths(&paths, SubstituteFlag::NoSubstitute)
+            .await?;
+        Ok(valid.contains(path))
+    }
+
+    async fn add_multiple_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        source: R,
+        repair: RepairFlag,
+        check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error> {
+        add_multiple_to_store_old(&mut self.store, source, repair, check_sigs).await
+    }
+
+    /// Worked out with [`query_missing_slow`] from the derivations in the
+    /// wrapped store.
+    async fn query_missing(
+        &mut self,
+        targets: &[DerivedPath],
+    ) -> Result<QueryMissingResult, Error> {
+        query_missing_slow::<_, S>(&mut self.store, &mut [], targets).await
+    }
+
+    async fn add_build_log(&mut self, _drv_path: &StorePath, _log: &str) -> Result<(), Error> {
+        Err(unsupported("add_build_log"))
+    }
+
+    async fn collect_garbage(&mut self, _options: &GCOptions) -> Result<GCResults, Error> {
+        Err(unsupported("collect_garbage"))
+    }
+
+    async fn collect_garbage_streaming(
+        &mut self,
+        _options: &GCOptions,
+        _act: &Activity,
+    ) -> Result<GCResults, Error> {
+        Err(unsupported("collect_garbage"))
+    }
+
+    async fn query_realisation(&mut self, _id: &DrvOutput) -> Result<Option<Realisation>, Error> {
+        Err(unsupported("query_realisation"))
+    }
+
+    async fn register_drv_output(&mut self, _realisation: &Realisation) -> Result<(), Error> {
+        Err(unsupported("register_drv_output"))
+    }
+
+    async fn add_indirect_root(&mut self, _path: &DaemonPath) -> Result<(), Error> {
+        Err(unsupported("add_indirect_root"))
+    }
+
+    async fn find_roots(&mut self) -> Result<BTreeMap<DaemonPath, StorePath>, Error> {
+        Err(unsupported("find_roots"))
+    }
+
+    async fn verify_store(
+        &mut self,
+        _check_contents: bool,
+        _repair: RepairFlag,
+    ) -> Result<bool, Error> {
+        Err(unsupported("verify_store"))
+    }
+
+    async fn optimise_store(&mut self) -> Result<(), Error> {
+        Err(unsupported("optimise_store"))
+    }
+
+    /// There is nothing to substitute from.
+    async fn substitute_paths(&mut self, _paths: &StorePathSet) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+fn unsupported(op: &str) -> Error {
+    Error::UnsupportedOperation(format!("{op} on a wrapped store"))
+}
+
+#[async_trait]
+impl<S> LegacyStore for DaemonWrapStore<S>
+where
+    S: LegacyStore + fmt::Debug + Send,
+{
+    async fn query_valid_paths_locked(
+        &mut self,
+        paths: &StorePathSet,
+        lock: bool,
+        maybe_substitute: SubstituteFlag,
+    ) -> Result<StorePathSet, Error> {
+        self.store
+            .query_valid_paths_locked(paths, lock, maybe_substitute)
+            .await
+    }
+
+    async fn export_paths<SW: AsyncWrite + fmt::Debug + Send + Unpin>(
+        &mut self,
+        paths: &StorePathSet,
+        sink: SW,
+    ) -> Result<(), Error> {
+        self.store.export_paths(paths, sink).await
+    }
+
+    async fn import_paths<SR: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        source: SR,
+    ) -> Result<(), Error> {
+        self.store.import_paths(source).await
+    }
+
+    async fn query_closure(
+        &mut self,
+        paths: &StorePathSet,
+        include_outputs: bool,
+    ) -> Result<StorePathSet, Error> {
+        self.store.query_closure(paths, include_outputs).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use super::*;
+    use crate::hash;
+    use crate::store::call_store::call_all;
+    use crate::store::legacy_worker::LegacyWrapStore;
+    use crate::store::MemoryStore;
+
+    #[tokio::test]
+    async fn test_daemon_ops_from_store() {
+        let mut store = DaemonWrapStore::new(LegacyWrapStore::new(MemoryStore::new()));
+        let valid = StorePath::new_from_base_name("7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-a").unwrap();
+        let missing = StorePath::new_from_base_name("ivz5kvk528akza21x33r8jn2wl8bpsw3-b").unwrap();
+        let nar = b"nar".to_vec();
+        let mut info =
+            ValidPathInfo::new(valid.clone(), hash::digest(hash::Algorithm::SHA256, &nar));
+        info.nar_size = nar.len() as u64;
+        store
+            .add_to_store(
+                &info,
+                &nar[..],
+                RepairFlag::NoRepair,
+                CheckSignaturesFlag::NoCheckSigs,
+            )
+            .await
+            .unwrap();
+
+        assert!(store.is_valid_path(&valid).await.unwrap());
+        assert!(!store.is_valid_path(&missing).await.unwrap());
+        let res = store
+            .query_missing(&[
+                DerivedPath::Opaque(valid.clone()),
+                DerivedPath::Opaque(missing.clone()),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(res.unknown, [missing].into_iter().collect());
+        assert!(res.will_build.is_empty());
+        assert_eq!(
+            store
+                .query_closure(&[valid.clone()].into_iter().collect(), false)
+                .await
+                .unwrap(),
+            [valid].into_iter().collect()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_daemon_ops() {
+        let mut store = DaemonWrapStore::new(MemoryStore::new());
+        for (method, res) in call_all(&mut store).await {
+            match method {
+                "set_options"
+                | "is_valid_path"
+                | "add_multiple_to_store"
+                | "query_missing"
+                | "substitute_paths" => assert!(res.is_ok(), "{method}: {res:?}"),
+                "repair_path" => assert_matches!(res, Err(Error::InvalidPath(_))),
+                _ => assert_matches!(res, Err(Error::UnsupportedOperation(_)), "{method}"),
+            }
+        }
+    }
+}
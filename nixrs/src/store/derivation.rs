@@ -12,6 +12,7 @@ use crate::store_path::{
     ContentAddress, ContentAddressMethod, ContentAddressWithReferences, StorePathSet,
 };
 use crate::store_path::{ParseStorePathError, ReadStorePathError, StoreDir, StorePath};
+use crate::StringSet;
 
 flag_enum! {
     #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
@@ -37,6 +38,10 @@ pub enum ParseDerivationError {
         #[source]
         hash::ParseHashError,
     ),
+    #[error("expected string '{0}' in derivation")]
+    Expected(String),
+    #[error("derivation is not valid UTF-8")]
+    InvalidUtf8,
 }
 
 impl From<hash::UnknownAlgorithm> for ParseDerivationError {
@@ -572,6 +577,189 @@ impl BasicDerivation {
     }
 }
 
+/// A derivation as stored in a `.drv` file in the store.
+///
+/// This is a [`BasicDerivation`] together with the derivations whose outputs
+/// are used as inputs.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
+pub struct Derivation {
+    pub input_drvs: BTreeMap<StorePath, StringSet>,
+    pub basic: BasicDerivation,
+}
+
+impl Derivation {
+    /// Parse the ATerm representation of a derivation.
+    ///
+    /// `name` is the name of the derivation without the `.drv` extension.
+    pub fn parse(
+        store_dir: &StoreDir,
+        s: &str,
+        name: &str,
+    ) -> Result<Derivation, ParseDerivationError> {
+        let mut parser = DrvParser {
+            input: s.as_bytes(),
+            pos: 0,
+        };
+        parser.parse_derivation(store_dir, name)
+    }
+
+    /// The paths of all the derivations this derivation has as input.
+    pub fn input_drv_paths(&self) -> impl Iterator<Item = &StorePath> {
+        self.input_drvs.keys()
+    }
+}
+
+struct DrvParser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl DrvParser<'_> {
+    fn expect(&mut self, s: &str) -> Result<(), ParseDerivationError> {
+        if self.input[self.pos..].starts_with(s.as_bytes()) {
+            self.pos += s.len();
+            Ok(())
+        } else {
+            Err(ParseDerivationError::Expected(s.into()))
+        }
+    }
+
+    fn end_of_list(&mut self) -> bool {
+        match self.input.get(self.pos) {
+            Some(b',') => {
+                self.pos += 1;
+                false
+            }
+            Some(b']') => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, ParseDerivationError> {
+        self.expect("\"")?;
+        let mut res = Vec::new();
+        loop {
+            let c = *self
+                .input
+                .get(self.pos)
+                .ok_or_else(|| ParseDerivationError::Expected("\"".into()))?;
+            self.pos += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let c = *self
+                        .input
+                        .get(self.pos)
+                        .ok_or_else(|| ParseDerivationError::Expected("\"".into()))?;
+                    self.pos += 1;
+                    match c {
+                        b'n' => res.push(b'\n'),
+                        b'r' => res.push(b'\r'),
+                        b't' => res.push(b'\t'),
+                        c => res.push(c),
+                    }
+                }
+                c => res.push(c),
+            }
+        }
+        String::from_utf8(res).map_err(|_| ParseDerivationError::InvalidUtf8)
+    }
+
+    fn parse_path(&mut self) -> Result<String, ParseDerivationError> {
+        let s = self.parse_string()?;
+        validate_path(&s)?;
+        Ok(s)
+    }
+
+    fn parse_strings(&mut self, are_paths: bool) -> Result<Vec<String>, ParseDerivationError> {
+        let mut res = Vec::new();
+        while !self.end_of_list() {
+            if are_paths {
+                res.push(self.parse_path()?);
+            } else {
+                res.push(self.parse_string()?);
+            }
+        }
+        Ok(res)
+    }
+
+    fn parse_derivation(
+        &mut self,
+        store_dir: &StoreDir,
+        name: &str,
+    ) -> Result<Derivation, ParseDerivationError> {
+        self.expect("Derive([")?;
+
+        let mut outputs = DerivationOutputs::new();
+        while !self.end_of_list() {
+            self.expect("(")?;
+            let id = self.parse_string()?;
+            self.expect(",")?;
+            let path_s = self.parse_string()?;
+            self.expect(",")?;
+            let hash_algo = self.parse_string()?;
+            self.expect(",")?;
+            let hash = self.parse_string()?;
+            self.expect(")")?;
+            let output = DerivationOutput::parse_output(store_dir, path_s, hash_algo, hash)?;
+            outputs.insert(id, output);
+        }
+
+        self.expect(",[")?;
+        let mut input_drvs = BTreeMap::new();
+        while !self.end_of_list() {
+            self.expect("(")?;
+            let drv_path = store_dir.parse_path(&self.parse_path()?)?;
+            self.expect(",[")?;
+            let output_names = self.parse_strings(false)?.into_iter().collect();
+            self.expect(")")?;
+            input_drvs.insert(drv_path, output_names);
+        }
+
+        self.expect(",[")?;
+        let mut input_srcs = StorePathSet::new();
+        for path_s in self.parse_strings(true)? {
+            input_srcs.insert(store_dir.parse_path(&path_s)?);
+        }
+
+        self.expect(",")?;
+        let platform = self.parse_string()?;
+        self.expect(",")?;
+        let builder = PathBuf::from(self.parse_string()?);
+
+        self.expect(",[")?;
+        let arguments = self.parse_strings(false)?;
+
+        self.expect(",[")?;
+        let mut env = Vec::new();
+        while !self.end_of_list() {
+            self.expect("(")?;
+            let name = self.parse_string()?;
+            self.expect(",")?;
+            let value = self.parse_string()?;
+            self.expect(")")?;
+            env.push((name, value));
+        }
+        self.expect(")")?;
+
+        Ok(Derivation {
+            input_drvs,
+            basic: BasicDerivation {
+                outputs,
+                input_srcs,
+                platform,
+                builder,
+                arguments,
+                env,
+                name: name.to_owned(),
+            },
+        })
+    }
+}
+
 #[cfg(any(test, feature = "test"))]
 pub mod proptest {
     use super::*;
@@ -873,4 +1061,60 @@ mod tests {
             None
         );
     }
+
+    #[test]
+    fn test_derivation_parse() {
+        let store_dir = StoreDir::new("/nix/store").unwrap();
+        let s = concat!(
+            "Derive([(\"out\",\"/nix/store/7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-konsole-18.12.3\",\"\",\"\")],",
+            "[(\"/nix/store/ivz5kvk528akza21x33r8jn2wl8bpsw3-bash-5.2.drv\",[\"out\",\"dev\"])],",
+            "[\"/nix/store/ivz5kvk528akza21x33r8jn2wl8bpsw3-builder.sh\"],",
+            "\"x86_64-linux\",\"/bin/sh\",[\"-e\",\"echo \\\"hi\\\"\\n\"],",
+            "[(\"name\",\"konsole-18.12.3\"),(\"out\",\"/nix/store/7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-konsole-18.12.3\")])"
+        );
+        let drv = Derivation::parse(&store_dir, s, "konsole-18.12.3").unwrap();
+        let out = store_dir
+            .parse_path("/nix/store/7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-konsole-18.12.3")
+            .unwrap();
+        let bash = store_dir
+            .parse_path("/nix/store/ivz5kvk528akza21x33r8jn2wl8bpsw3-bash-5.2.drv")
+            .unwrap();
+        let src = store_dir
+            .parse_path("/nix/store/ivz5kvk528akza21x33r8jn2wl8bpsw3-builder.sh")
+            .unwrap();
+        let mut outputs = DerivationOutputs::new();
+        outputs.insert("out".into(), DerivationOutput::InputAddressed(out));
+        let mut input_drvs = BTreeMap::new();
+        input_drvs.insert(bash, crate::string_set!["out", "dev"]);
+        let mut input_srcs = StorePathSet::new();
+        input_srcs.insert(src);
+        assert_eq!(
+            drv,
+            Derivation {
+                input_drvs,
+                basic: BasicDerivation {
+                    outputs,
+                    input_srcs,
+                    platform: "x86_64-linux".into(),
+                    builder: "/bin/sh".into(),
+                    arguments: vec!["-e".into(), "echo \"hi\"\n".into()],
+                    env: vec![
+                        ("name".into(), "konsole-18.12.3".into()),
+                        (
+                            "out".into(),
+                            "/nix/store/7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-konsole-18.12.3".into()
+                        ),
+                    ],
+                    name: "konsole-18.12.3".into(),
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn test_derivation_parse_bad() {
+        let store_dir = StoreDir::new("/nix/store").unwrap();
+        let p = Derivation::parse(&store_dir, "Derive([(\"out\"", "bad");
+        assert_eq!(p, Err(ParseDerivationError::Expected(",".into())));
+    }
 }
@@ -0,0 +1,306 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+use futures::StreamExt;
+use tokio::pin;
+use tracing::{instrument, trace};
+
+use super::{Derivation, Error, ParseDerivationError, ReadDerivationError, Store};
+use crate::archive::{parse_nar, NAREvent};
+use crate::store_path::{StorePath, StorePathSet};
+
+/// Read and parse a single `.drv` file from a store.
+///
+/// The derivation is fetched with [`Store::nar_from_path`] and must be a NAR
+/// containing a single regular file.
+pub async fn read_derivation<S>(store: &mut S, drv_path: &StorePath) -> Result<Derivation, Error>
+where
+    S: Store + Send,
+{
+    if !drv_path.is_derivation() {
+        return Err(Error::Misc(format!("'{}' is not a derivation", drv_path)));
+    }
+    let mut nar = Vec::new();
+    store.nar_from_path(drv_path, &mut nar).await?;
+
+    let events = parse_nar(&nar[..]);
+    pin!(events);
+    let mut contents = Vec::new();
+    while let Some(event) = events.next().await {
+        match event? {
+            NAREvent::Magic(_) | NAREvent::RegularNode { .. } => {}
+            NAREvent::Contents { buf, .. } => contents.extend_from_slice(&buf),
+            _ => {
+                return Err(Error::Misc(format!(
+                    "derivation '{}' is not a regular file",
+                    drv_path
+                )))
+            }
+        }
+    }
+    let s = String::from_utf8(contents)
+        .map_err(|_| ReadDerivationError::BadDerivation(ParseDerivationError::InvalidUtf8))?;
+    let store_dir = store.store_dir();
+    Derivation::parse(&store_dir, &s, drv_path.name_from_drv())
+        .map_err(|err| Error::BadDerivation(err.into()))
+}
+
+/// The closure of a derivation over its input derivations.
+#[derive(Debug, Clone)]
+pub struct DerivationGraph {
+    root: StorePath,
+    derivations: BTreeMap<StorePath, Arc<Derivation>>,
+}
+
+impl DerivationGraph {
+    /// The derivation the graph was loaded for.
+    pub fn root(&self) -> &StorePath {
+        &self.root
+    }
+
+    pub fn get(&self, drv_path: &StorePath) -> Option<&Derivation> {
+        self.derivations.get(drv_path).map(|drv| drv.as_ref())
+    }
+
+    pub fn contains(&self, drv_path: &StorePath) -> bool {
+        self.derivations.contains_key(drv_path)
+    }
+
+    pub fn len(&self) -> usize {
+        self.derivations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.derivations.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&StorePath, &Derivation)> {
+        self.derivations
+            .iter()
+            .map(|(path, drv)| (path, drv.as_ref()))
+    }
+
+    /// All the paths of the derivations in the graph.
+    pub fn drv_paths(&self) -> StorePathSet {
+        self.derivations.keys().cloned().collect()
+    }
+
+    /// The derivations in the graph ordered so that every derivation comes
+    /// after all of its input derivations.
+    pub fn topo_sorted(&self) -> Vec<&StorePath> {
+        let mut sorted = Vec::with_capacity(self.derivations.len());
+        let mut visited = BTreeSet::new();
+        let mut stack = vec![(&self.root, false)];
+        while let Some((path, expanded)) = stack.pop() {
+            if expanded {
+                sorted.push(path);
+                continue;
+            }
+            if !visited.insert(path) {
+                continue;
+            }
+            stack.push((path, true));
+            if let Some(drv) = self.derivations.get(path) {
+                for input in drv.input_drv_paths() {
+                    if !visited.contains(input) {
+                        stack.push((input, false));
+                    }
+                }
+            }
+        }
+        sorted
+    }
+}
+
+/// Loads derivation closures from a store, keeping every parsed derivation
+/// in memory so that shared inputs are only read once.
+pub struct DerivationLoader<S> {
+    store: S,
+    cache: BTreeMap<StorePath, Arc<Derivation>>,
+}
+
+impl<S> DerivationLoader<S>
+where
+    S: Store + Send,
+{
+    pub fn new(store: S) -> DerivationLoader<S> {
+        DerivationLoader {
+            store,
+            cache: BTreeMap::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+
+    /// Read a single derivation, using the cache when possible.
+    pub async fn load(&mut self, drv_path: &StorePath) -> Result<Arc<Derivation>, Error> {
+        if let Some(drv) = self.cache.get(drv_path) {
+            return Ok(drv.clone());
+        }
+        trace!("reading derivation {}", drv_path);
+        let drv = Arc::new(read_derivation(&mut self.store, drv_path).await?);
+        self.cache.insert(drv_path.clone(), drv.clone());
+        Ok(drv)
+    }
+
+    /// Load the derivation and all the derivations it transitively depends on.
+    #[instrument(skip_all, fields(%drv_path))]
+    pub async fn load_closure(&mut self, drv_path: &StorePath) -> Result<DerivationGraph, Error> {
+        let mut derivations = BTreeMap::new();
+        let mut pending = vec![drv_path.clone()];
+        while let Some(path) = pending.pop() {
+            if derivations.contains_key(&path) {
+                continue;
+            }
+            let drv = self.load(&path).await?;
+            for input in drv.input_drv_paths() {
+                if !derivations.contains_key(input) {
+                    pending.push(input.clone());
+                }
+            }
+            derivations.insert(path, drv);
+        }
+        Ok(DerivationGraph {
+            root: drv_path.clone(),
+            derivations,
+        })
+    }
+}
+
+/// Load the closure of `drv_path` over its input derivations from `store`.
+pub async fn load_drv_closure<S>(store: S, drv_path: &StorePath) -> Result<DerivationGraph, Error>
+where
+    S: Store + Send,
+{
+    DerivationLoader::new(store).load_closure(drv_path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bytes::{Bytes, BytesMut};
+    use pretty_assertions::assert_eq;
+    use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+    use super::*;
+    use crate::archive::NAR_VERSION_MAGIC_1;
+    use crate::path_info::ValidPathInfo;
+    use crate::store::{CheckSignaturesFlag, RepairFlag};
+    use crate::store_path::{StoreDir, StoreDirProvider};
+
+    struct DrvStore {
+        drvs: BTreeMap<StorePath, String>,
+        reads: usize,
+    }
+
+    impl StoreDirProvider for DrvStore {
+        fn store_dir(&self) -> StoreDir {
+            StoreDir::default()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Store for DrvStore {
+        async fn query_path_info(
+            &mut self,
+            _path: &StorePath,
+        ) -> Result<Option<ValidPathInfo>, Error> {
+            Err(Error::UnsupportedOperation("query_path_info".into()))
+        }
+
+        async fn nar_from_path<W: AsyncWrite + std::fmt::Debug + Send + Unpin>(
+            &mut self,
+            path: &StorePath,
+            mut sink: W,
+        ) -> Result<(), Error> {
+            let drv = self
+                .drvs
+                .get(path)
+                .ok_or_else(|| Error::InvalidPath(self.store_dir().print_path(path)))?;
+            self.reads += 1;
+            let size = drv.len() as u64;
+            let mut buf = BytesMut::new();
+            NAREvent::Magic(Arc::new(NAR_VERSION_MAGIC_1.into())).encode_into(&mut buf);
+            NAREvent::RegularNode {
+                executable: false,
+                size,
+                offset: 0,
+            }
+            .encode_into(&mut buf);
+            NAREvent::Contents {
+                total: size,
+                index: 0,
+                buf: Bytes::from(drv.clone()),
+            }
+            .encode_into(&mut buf);
+            sink.write_all(&buf).await?;
+            Ok(())
+        }
+
+        async fn add_to_store<R: AsyncRead + std::fmt::Debug + Send + Unpin>(
+            &mut self,
+            _info: &ValidPathInfo,
+            _source: R,
+            _repair: RepairFlag,
+            _check_sigs: CheckSignaturesFlag,
+        ) -> Result<(), Error> {
+            Err(Error::UnsupportedOperation("add_to_store".into()))
+        }
+    }
+
+    fn drv(name: &str, inputs: &[&str]) -> String {
+        let inputs = inputs
+            .iter()
+            .map(|i| format!("(\"{}\",[\"out\"])", i))
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            "Derive([(\"out\",\"\",\"\",\"\")],[{}],[],\"x86_64-linux\",\"/bin/sh\",[],[(\"name\",\"{}\")])",
+            inputs, name
+        )
+    }
+
+    #[tokio::test]
+    async fn test_load_drv_closure() {
+        let store_dir = StoreDir::default();
+        let a_s = "/nix/store/7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-a.drv";
+        let b_s = "/nix/store/ivz5kvk528akza21x33r8jn2wl8bpsw3-b.drv";
+        let c_s = "/nix/store/00000000000000000000000000000000-c.drv";
+        let a = store_dir.parse_path(a_s).unwrap();
+        let b = store_dir.parse_path(b_s).unwrap();
+        let c = store_dir.parse_path(c_s).unwrap();
+        let mut drvs = BTreeMap::new();
+        drvs.insert(a.clone(), drv("a", &[b_s, c_s]));
+        drvs.insert(b.clone(), drv("b", &[c_s]));
+        drvs.insert(c.clone(), drv("c", &[]));
+        let store = DrvStore { drvs, reads: 0 };
+
+        let mut loader = DerivationLoader::new(store);
+        let graph = loader.load_closure(&a).await.unwrap();
+        assert_eq!(graph.root(), &a);
+        assert_eq!(graph.len(), 3);
+        assert_eq!(graph.get(&b).unwrap().basic.name, "b");
+        assert_eq!(graph.topo_sorted(), vec![&c, &b, &a]);
+
+        let graph = loader.load_closure(&b).await.unwrap();
+        assert_eq!(graph.len(), 2);
+        assert_eq!(loader.into_inner().reads, 3);
+    }
+
+    #[tokio::test]
+    async fn test_load_drv_closure_missing() {
+        let store_dir = StoreDir::default();
+        let a_s = "/nix/store/7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-a.drv";
+        let b_s = "/nix/store/ivz5kvk528akza21x33r8jn2wl8bpsw3-b.drv";
+        let a = store_dir.parse_path(a_s).unwrap();
+        let mut drvs = BTreeMap::new();
+        drvs.insert(a.clone(), drv("a", &[b_s]));
+        let store = DrvStore { drvs, reads: 0 };
+
+        let res = load_drv_closure(store, &a).await;
+        assert!(matches!(res, Err(Error::InvalidPath(_))), "{:?}", res);
+    }
+}
@@ -3,7 +3,7 @@ use std::fmt;
 use thiserror::Error;
 
 use crate::io::{StateParse, StatePrint};
-use crate::store_path::{ParseStorePathError, StoreDir, StorePath};
+use crate::store_path::{is_name, ParseStorePathError, StoreDir, StorePath};
 
 use super::{OutputSpec, ParseOutputSpecError};
 
@@ -16,14 +16,27 @@ pub enum SingleDerivedPath {
     },
 }
 
+/// Split `s` at the last output separator. Both the legacy `!` and the
+/// newer `^` are accepted since neither can be part of a store path.
+fn split_outputs(s: &str) -> Option<(&str, &str)> {
+    s.rfind(['!', '^']).map(|pos| (&s[..pos], &s[(pos + 1)..]))
+}
+
 impl SingleDerivedPath {
+    /// Parse a derived path in either `drv^out` or legacy `drv!out` form.
     pub fn parse(store_dir: &StoreDir, s: &str) -> Result<Self, ParseDerivedPathError> {
-        if let Some(pos) = s.rfind('!') {
-            let drv_path = SingleDerivedPath::parse(store_dir, &s[..pos])?;
-            let output = s[(pos + 1)..].to_string();
+        if let Some((drv_path, output)) = split_outputs(s) {
+            let drv_path = SingleDerivedPath::parse(store_dir, drv_path)?;
+            if !is_name(output) {
+                return Err(ParseDerivedPathError::BadOutputSelector(
+                    s.to_string(),
+                    output.to_string(),
+                    ParseOutputSpecError::BadOutputName(output.to_string()),
+                ));
+            }
             Ok(SingleDerivedPath::Built {
                 drv_path: Box::new(drv_path),
-                output,
+                output: output.to_string(),
             })
         } else {
             let path = store_dir.parse_path(s)?;
@@ -66,13 +79,12 @@ impl<'a> fmt::Display for SingleDerivedPathDisplay<'a> {
                 write!(f, "{}", self.store_dir.print_path(drv_path))
             }
             SingleDerivedPath::Built { drv_path, output } => {
-                write!(
-                    f,
-                    "{}{}{}",
-                    drv_path.legacy_display(self.store_dir),
-                    self.seperator,
-                    output
-                )
+                let drv_path = SingleDerivedPathDisplay {
+                    store_dir: self.store_dir,
+                    seperator: self.seperator,
+                    path: drv_path,
+                };
+                write!(f, "{}{}{}", drv_path, self.seperator, output)
             }
         }
     }
@@ -123,6 +135,8 @@ pub enum ParseDerivedPathError {
         #[from]
         ParseOutputSpecError,
     ),
+    #[error("invalid output selector '{1}' in derived path '{0}': {2}")]
+    BadOutputSelector(String, String, #[source] ParseOutputSpecError),
 }
 
 #[derive(Error, Debug)]
@@ -170,11 +184,23 @@ impl DerivedPath {
         }
     }
 
+    /// Print in the newer `drv^out1,out2` form. Use [`print`](Self::print)
+    /// for the legacy form that is sent over the wire.
+    pub fn display<'a>(&'a self, store_dir: &'a StoreDir) -> impl fmt::Display + 'a {
+        DerivedPathDisplay {
+            store_dir,
+            path: self,
+        }
+    }
+
+    /// Parse a derived path with outputs selected in either `drv^out1,out2`,
+    /// `drv^*` or legacy `drv!out1,out2` form.
     pub fn parse(store_dir: &StoreDir, s: &str) -> Result<Self, ParseDerivedPathError> {
-        if let Some(pos) = s.rfind('!') {
-            let drv_path = SingleDerivedPath::parse(store_dir, &s[..pos])?;
-            let o = &s[(pos + 1)..];
-            let outputs = o.parse()?;
+        if let Some((drv_path, outputs)) = split_outputs(s) {
+            let drv_path = SingleDerivedPath::parse(store_dir, drv_path)?;
+            let outputs = outputs.parse().map_err(|err| {
+                ParseDerivedPathError::BadOutputSelector(s.to_string(), outputs.to_string(), err)
+            })?;
             Ok(DerivedPath::Built { drv_path, outputs })
         } else {
             let path = store_dir.parse_path(s)?;
@@ -183,6 +209,22 @@ impl DerivedPath {
     }
 }
 
+struct DerivedPathDisplay<'a> {
+    store_dir: &'a StoreDir,
+    path: &'a DerivedPath,
+}
+
+impl<'a> fmt::Display for DerivedPathDisplay<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.path {
+            DerivedPath::Opaque(path) => write!(f, "{}", self.store_dir.display_path(path)),
+            DerivedPath::Built { drv_path, outputs } => {
+                write!(f, "{}^{}", drv_path.display(self.store_dir), outputs)
+            }
+        }
+    }
+}
+
 impl StateParse<DerivedPath> for StoreDir {
     type Err = ReadDerivedPathError;
 
@@ -415,7 +457,71 @@ mod tests {
         assert_eq!(path, path2);
     }
 
+    #[test]
+    fn test_derived_path_parse_new_style() {
+        let store_dir = StoreDir::new("/nix/store").unwrap();
+        let drv_path = store_dir
+            .parse_path("/nix/store/7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-konsole-18.12.3.drv")
+            .unwrap();
+        let p = DerivedPath::parse(
+            &store_dir,
+            "/nix/store/7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-konsole-18.12.3.drv^out^dev,out",
+        )
+        .unwrap();
+        assert_eq!(
+            p,
+            DerivedPath::Built {
+                drv_path: SingleDerivedPath::Built {
+                    drv_path: Box::new(SingleDerivedPath::Opaque(drv_path)),
+                    output: "out".into(),
+                },
+                outputs: string_set!["out", "dev"].try_into().unwrap()
+            }
+        );
+        assert_eq!(
+            p.display(&store_dir).to_string(),
+            "/nix/store/7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-konsole-18.12.3.drv^out^dev,out"
+        );
+    }
+
+    #[test]
+    fn test_derived_path_parse_bad_selector() {
+        let store_dir = StoreDir::new("/nix/store").unwrap();
+        let err = DerivedPath::parse(
+            &store_dir,
+            "/nix/store/7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-konsole-18.12.3.drv^out,b@d",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            ParseDerivedPathError::BadOutputSelector(
+                "/nix/store/7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-konsole-18.12.3.drv^out,b@d".into(),
+                "out,b@d".into(),
+                ParseOutputSpecError::BadOutputName("b@d".into()),
+            )
+        );
+        let err = DerivedPath::parse(
+            &store_dir,
+            "/nix/store/7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-konsole-18.12.3.drv^^out",
+        )
+        .unwrap_err();
+        assert!(
+            matches!(err, ParseDerivedPathError::BadOutputSelector(_, ref o, _) if o.is_empty())
+        );
+    }
+
     proptest! {
+        #[test]
+        fn proptest_derived_path_display_parsing(
+            drv_path in any::<DerivedPath>(),
+        )
+        {
+            let store_dir = StoreDir::default();
+            let s = drv_path.display(&store_dir).to_string();
+            let drv_path2 = DerivedPath::parse(&store_dir, &s).unwrap();
+            assert_eq!(drv_path, drv_path2);
+        }
+
         #[test]
         fn proptest_derived_path_print_parsing(
             drv_path in any::<DerivedPath>(),
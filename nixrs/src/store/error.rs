@@ -4,8 +4,11 @@ use std::io;
 use thiserror::Error;
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 
-use super::daemon::WorkerProtoOp;
+use super::activity::ResultType;
+use super::binary_cache::ParseCacheInfoError;
+use super::daemon::{DaemonPathError, ProtocolFeature, WorkerProtoOp};
 use super::derived_path::ReadDerivedPathError;
+use super::experimental::ExperimentalFeature;
 use super::legacy_worker::ServeCommand;
 use super::settings::ParseSettingError;
 use super::{
@@ -74,6 +77,8 @@ impl From<Verbosity> for tracing::Level {
 pub enum Error {
     #[error("Store path set forms a cycle")]
     CycleDetected,
+    #[error("cycle detected in the references of {}", .0.join(" -> "))]
+    ReferenceCycle(Vec<String>),
     #[error("wanted to fetch '{0}' but the legacy ssh protocol doesn't support merely substituting drv files via the build paths command. It would build them instead. Try using ssh-ng://")]
     WantedFetchInLegacy(String),
     #[error("{0}")]
@@ -108,12 +113,32 @@ pub enum Error {
     ),
     #[error("path '{0}' is not a valid store path")]
     InvalidPath(String),
+    #[error("{0}")]
+    BadDaemonPath(
+        #[from]
+        #[source]
+        DaemonPathError,
+    ),
+    #[error("no realisation for derivation output '{0}'")]
+    MissingRealisation(String),
+    #[error("cannot register realisation '{0}' because it lacks a signature by a trusted key")]
+    UntrustedRealisation(String),
+    #[error("realisation '{0}' depends on '{1}' with an output path other than the store has")]
+    InconsistentRealisation(String, String),
     #[error("path '{}' is not a store path", .0.display())]
     BadStorePath(std::path::PathBuf),
     #[error("path '{}' is not in the Nix store", .0.display())]
     NotInStore(std::path::PathBuf),
     #[error(".narinfo file is corrupt")]
     BadNarInfo,
+    #[error("nix-cache-info file is corrupt: {0}")]
+    BadCacheInfo(
+        #[from]
+        #[source]
+        ParseCacheInfoError,
+    ),
+    #[error("binary cache is for Nix stores with prefix '{0}', not '{1}'")]
+    StoreDirMismatch(String, String),
     #[error("invalid base32 string")]
     BadBase32(
         #[from]
@@ -158,6 +183,8 @@ pub enum Error {
         #[source]
         url::ParseError,
     ),
+    #[error("invalid store URI '{0}': {1}")]
+    BadStoreUri(String, String),
     #[error("HTTP error: {0}")]
     ReqwestError(
         #[from]
@@ -193,10 +220,14 @@ pub enum Error {
     UnsupportedDaemonProtocol,
     #[error("the Nix daemon version is too old")]
     DaemonVersionTooOld,
+    #[error("{0} is not supported by daemon protocol {1}.{2}")]
+    UnsupportedFeature(ProtocolFeature, u64, u64),
     #[error("the Nix client version is too old")]
     DaemonClientVersionTooOld,
     #[error("Invalid trusted status from remote")]
     InvalidTrustedStatus,
+    #[error("invalid optional tag {0} from remote")]
+    InvalidOptionalTag(u64),
     #[error("no sink")]
     NoSink,
     #[error("no source")]
@@ -213,6 +244,8 @@ pub enum Error {
     },
     #[error("got unsupported field type {0:x} from Nix daemon")]
     UnsupportedFieldType(u64),
+    #[error("invalid fields for activity result {0:?}")]
+    InvalidResultFields(ResultType),
     #[error("trying to request '{0}', but daemon protocol {1}.{2} is too old (< 1.29) to request a derivation file")]
     ProtocolTooOld(String, u64, u64),
     #[error("wanted to build a derivation that is itself a build product, but the legacy 'ssh://' protocol doesn't support that. Try using 'ssh-ng://'")]
@@ -225,8 +258,40 @@ pub enum Error {
     RemovedOperation(WorkerProtoOp),
     #[error("repairing is not allowed because you are not in 'trusted-users'")]
     RepairNotAllowed,
+    #[error("{0} is not allowed for this client")]
+    NotAllowed(String),
+    #[error("cannot {0} in a read-only store")]
+    ReadOnlyStore(String),
+    #[error("{0} timed out after {1:?}")]
+    Timeout(String, std::time::Duration),
+    #[error("the store connection is unusable after an operation timed out")]
+    StorePoisoned,
+    #[error("{0} was cancelled")]
+    Cancelled(String),
+    #[error("the daemon connection was closed after an operation was cancelled")]
+    ConnectionCancelled,
+    #[error("experimental Nix feature '{0}' is disabled; add '--extra-experimental-features {0}' to enable it")]
+    MissingExperimentalFeature(ExperimentalFeature),
+    #[error("the client stopped sending the data it was asked for: {0}")]
+    ClientSourceBroken(#[source] std::io::Error),
     #[error("you are not privileged to build input-addressed derivations")]
     MissingPrivilegesToBuild,
+    #[error("you are not privileged to add logs")]
+    MissingPrivilegesToAddLogs,
+    #[error("you are not privileged to repair paths")]
+    MissingPrivilegesToRepair,
+    #[error("you are not allowed to ignore liveness")]
+    IgnoreLivenessNotAllowed,
+    #[error("invalid garbage collector action {0}")]
+    InvalidGCAction(u64),
+    #[error("cannot delete path '{0}' since it is still alive")]
+    PathStillAlive(String),
+    #[error(
+        "unsupported FileIngestionMethod with value of {0}; you may need to upgrade nix-daemon"
+    )]
+    UnsupportedFileIngestionMethod(u64),
+    #[error("regular file expected")]
+    RegularFileExpected,
     #[error("{0}")]
     DerivationOutputs(
         #[from]
@@ -271,6 +336,20 @@ pub enum Error {
         #[source]
         ParseContentAddressError,
     ),
+    #[error("no remote builder can build '{0}' for system '{1}'")]
+    NoMatchingBuilder(String, String),
+    #[error("build of '{0}' failed: {1}")]
+    BuildFailed(String, String),
+    #[error("hash mismatch importing path '{0}';\n  specified: {1}\n  got:       {2}")]
+    NarHashMismatch(String, String, String),
+    #[error("size mismatch importing path '{0}';\n  specified: {1}\n  got:       {2}")]
+    NarSizeMismatch(String, u64, u64),
+    #[error("hash mismatch in file downloaded from '{0}':\n  specified: {1}\n  got:       {2}")]
+    FetchHashMismatch(String, String, String),
+    #[error("path '{0}' does not match its content address")]
+    ContentAddressMismatch(String),
+    #[error("cannot add path '{0}' because it references path '{1}' which is not valid")]
+    MissingReference(String, String),
     #[error("{1}")]
     Custom(u64, String),
 }
@@ -0,0 +1,251 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use super::{BasicDerivation, DerivationOutput, Error};
+
+/// Parts of Nix that have to be turned on with `experimental-features`
+/// before they can be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ExperimentalFeature {
+    /// Derivations with floating content-addressed outputs, and the
+    /// realisations that record where their outputs ended up.
+    CaDerivations,
+    /// Builders that use the Nix store through a daemon socket in the
+    /// sandbox.
+    RecursiveNix,
+    /// `mounted-ssh-ng://` stores, which read paths from a mounted copy of
+    /// the remote store.
+    MountedSshStore,
+}
+
+impl ExperimentalFeature {
+    /// Name of the feature in `experimental-features`.
+    pub fn name(&self) -> &'static str {
+        use ExperimentalFeature::*;
+        match self {
+            CaDerivations => "ca-derivations",
+            RecursiveNix => "recursive-nix",
+            MountedSshStore => "mounted-ssh-store",
+        }
+    }
+
+    pub fn all() -> &'static [ExperimentalFeature] {
+        use ExperimentalFeature::*;
+        &[CaDerivations, RecursiveNix, MountedSshStore]
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq, Clone)]
+#[error("unknown experimental feature '{0}'")]
+pub struct ParseExperimentalFeatureError(String);
+
+impl FromStr for ExperimentalFeature {
+    type Err = ParseExperimentalFeatureError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ExperimentalFeature::all()
+            .iter()
+            .find(|feature| feature.name() == s)
+            .copied()
+            .ok_or_else(|| ParseExperimentalFeatureError(s.into()))
+    }
+}
+
+impl fmt::Display for ExperimentalFeature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The experimental features a store has turned on.
+///
+/// Parsed from and printed as the space separated list used by the
+/// `experimental-features` setting. Features this crate doesn't know are
+/// left out when parsing, like Nix only warns about them.
+///
+/// ```
+/// # use nixrs::store::{ExperimentalFeature, ExperimentalFeatures};
+/// let features: ExperimentalFeatures = "nix-command ca-derivations".parse().unwrap();
+/// assert!(features.is_enabled(ExperimentalFeature::CaDerivations));
+/// assert!(features.require(ExperimentalFeature::RecursiveNix).is_err());
+/// assert_eq!(features.to_string(), "ca-derivations");
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExperimentalFeatures {
+    enabled: BTreeSet<ExperimentalFeature>,
+}
+
+impl ExperimentalFeatures {
+    /// No experimental features, which is what Nix starts out with.
+    pub fn new() -> ExperimentalFeatures {
+        Default::default()
+    }
+
+    /// Every feature this crate knows about.
+    pub fn all() -> ExperimentalFeatures {
+        ExperimentalFeature::all().iter().copied().collect()
+    }
+
+    pub fn enable(&mut self, feature: ExperimentalFeature) -> &mut Self {
+        self.enabled.insert(feature);
+        self
+    }
+
+    pub fn disable(&mut self, feature: ExperimentalFeature) -> &mut Self {
+        self.enabled.remove(&feature);
+        self
+    }
+
+    pub fn is_enabled(&self, feature: ExperimentalFeature) -> bool {
+        self.enabled.contains(&feature)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = ExperimentalFeature> + '_ {
+        self.enabled.iter().copied()
+    }
+
+    /// Features enabled in both `self` and `other`, e.g. what a store made
+    /// of two others supports.
+    pub fn intersect(&self, other: &ExperimentalFeatures) -> ExperimentalFeatures {
+        self.enabled.intersection(&other.enabled).copied().collect()
+    }
+
+    /// Fail with [`Error::MissingExperimentalFeature`] unless `feature` is
+    /// enabled.
+    pub fn require(&self, feature: ExperimentalFeature) -> Result<(), Error> {
+        if self.is_enabled(feature) {
+            Ok(())
+        } else {
+            Err(Error::MissingExperimentalFeature(feature))
+        }
+    }
+
+    /// Fail for the first of `required` that isn't enabled.
+    pub fn require_all(&self, required: &ExperimentalFeatures) -> Result<(), Error> {
+        required
+            .iter()
+            .try_for_each(|feature| self.require(feature))
+    }
+
+    /// The features needed to build `drv`: `ca-derivations` for floating
+    /// content-addressed outputs and `recursive-nix` when it is one of the
+    /// `requiredSystemFeatures`.
+    pub fn required_by(drv: &BasicDerivation) -> ExperimentalFeatures {
+        let mut required = ExperimentalFeatures::new();
+        if drv
+            .outputs
+            .values()
+            .any(|output| matches!(output, DerivationOutput::CAFloating { .. }))
+        {
+            required.enable(ExperimentalFeature::CaDerivations);
+        }
+        let recursive = drv
+            .env
+            .iter()
+            .find(|(name, _)| name == "requiredSystemFeatures")
+            .map(|(_, value)| value.split_whitespace().any(|f| f == "recursive-nix"))
+            .unwrap_or(false);
+        if recursive {
+            required.enable(ExperimentalFeature::RecursiveNix);
+        }
+        required
+    }
+}
+
+impl FromIterator<ExperimentalFeature> for ExperimentalFeatures {
+    fn from_iter<T: IntoIterator<Item = ExperimentalFeature>>(iter: T) -> Self {
+        ExperimentalFeatures {
+            enabled: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl FromStr for ExperimentalFeatures {
+    type Err = ParseExperimentalFeatureError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(s.split_whitespace()
+            .filter_map(|name| name.parse().ok())
+            .collect())
+    }
+}
+
+impl fmt::Display for ExperimentalFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, feature) in self.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{}", feature)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use super::*;
+    use crate::hash;
+    use crate::store_path::{ContentAddressMethod, FileIngestionMethod};
+
+    #[test]
+    fn test_parse_features() {
+        for feature in ExperimentalFeature::all() {
+            assert_eq!(
+                feature.name().parse::<ExperimentalFeature>().as_ref(),
+                Ok(feature)
+            );
+        }
+        assert_eq!(
+            "flakes".parse::<ExperimentalFeature>(),
+            Err(ParseExperimentalFeatureError("flakes".into()))
+        );
+        let features: ExperimentalFeatures =
+            "recursive-nix  flakes\tca-derivations".parse().unwrap();
+        assert_eq!(features.to_string(), "ca-derivations recursive-nix");
+        assert_eq!(
+            features.intersect(&"recursive-nix".parse().unwrap()),
+            [ExperimentalFeature::RecursiveNix].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn test_required_by() {
+        let mut drv = BasicDerivation {
+            outputs: Default::default(),
+            input_srcs: Default::default(),
+            platform: "x86_64-linux".into(),
+            builder: "/bin/sh".into(),
+            arguments: Vec::new(),
+            env: vec![("requiredSystemFeatures".into(), "kvm recursive-nix".into())],
+            name: "hello".into(),
+        };
+        drv.outputs.insert(
+            "out".into(),
+            DerivationOutput::CAFloating {
+                method: ContentAddressMethod::Fixed(FileIngestionMethod::Recursive),
+                hash_type: hash::Algorithm::SHA256,
+            },
+        );
+        let required = ExperimentalFeatures::required_by(&drv);
+        assert_eq!(required, ExperimentalFeatures::all().intersect(&required));
+        assert!(required.is_enabled(ExperimentalFeature::CaDerivations));
+        assert!(required.is_enabled(ExperimentalFeature::RecursiveNix));
+
+        let mut enabled = ExperimentalFeatures::new();
+        enabled.enable(ExperimentalFeature::CaDerivations);
+        assert_matches!(
+            enabled.require_all(&required),
+            Err(Error::MissingExperimentalFeature(
+                ExperimentalFeature::RecursiveNix
+            ))
+        );
+        enabled.enable(ExperimentalFeature::RecursiveNix);
+        assert!(enabled.require_all(&required).is_ok());
+    }
+}
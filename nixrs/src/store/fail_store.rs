@@ -9,7 +9,7 @@ use crate::{
 use super::{
     daemon::{DaemonStore, QueryMissingResult, TrustedFlag},
     legacy_worker::LegacyStore,
-    CheckSignaturesFlag, DerivedPath, Error, RepairFlag, Store, SubstituteFlag,
+    CheckSignaturesFlag, DerivedPath, Error, LogStore, RepairFlag, Store, SubstituteFlag,
 };
 
 #[derive(Debug)]
@@ -46,6 +46,17 @@ impl Store for FailStore {
     }
 }
 
+#[async_trait]
+impl LogStore for FailStore {
+    async fn get_build_log(&mut self, _drv_path: &StorePath) -> Result<Option<String>, Error> {
+        Err(Error::UnsupportedOperation("get_build_log".into()))
+    }
+
+    async fn add_build_log(&mut self, _drv_path: &StorePath, _log: &str) -> Result<(), Error> {
+        Err(Error::UnsupportedOperation("add_build_log".into()))
+    }
+}
+
 #[async_trait]
 impl LegacyStore for FailStore {
     async fn query_valid_paths_locked(
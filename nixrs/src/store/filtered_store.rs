@@ -0,0 +1,670 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::path_info::ValidPathInfo;
+use crate::signature::PublicKey;
+use crate::store::activity::Activity;
+use crate::store::daemon::{
+    DaemonPath, DaemonStore, GCOptions, GCResults, QueryMissingResult, TrustedFlag,
+};
+use crate::store::legacy_worker::LegacyStore;
+use crate::store::memory_store::base_drv_path;
+use crate::store::{
+    compute_fs_closure_slow, BasicDerivation, BuildMode, BuildResult, CheckSignaturesFlag,
+    DerivedPath, DrvOutput, Error, ExperimentalFeatures, Realisation, RepairFlag, Store,
+    SubstituteFlag,
+};
+use crate::store_path::{StoreDir, StoreDirProvider, StorePath, StorePathSet};
+
+type NameFn = dyn Fn(&str) -> bool + Send + Sync;
+type InfoFn = dyn Fn(&StoreDir, &ValidPathInfo) -> bool + Send + Sync;
+
+#[derive(Clone)]
+enum Rule {
+    Name(Arc<NameFn>),
+    Info(Arc<InfoFn>),
+    Paths(Arc<StorePathSet>),
+}
+
+impl Rule {
+    fn matches(
+        &self,
+        store_dir: &StoreDir,
+        path: &StorePath,
+        info: Option<&ValidPathInfo>,
+    ) -> bool {
+        match self {
+            Rule::Info(f) => info.map(|info| f(store_dir, info)).unwrap_or(false),
+            _ => self.matches_path(path).unwrap_or(false),
+        }
+    }
+
+    /// Whether the rule matches `path`, when that can be told without its
+    /// info.
+    fn matches_path(&self, path: &StorePath) -> Option<bool> {
+        match self {
+            Rule::Name(f) => Some(f(path.name.name())),
+            Rule::Info(_) => None,
+            Rule::Paths(paths) => Some(paths.contains(path)),
+        }
+    }
+}
+
+/// Which paths a [`FilteredStore`] shows.
+///
+/// A path is shown when it matches any of the allow rules, or there are
+/// none, and it matches none of the deny rules. Rules on names and on sets
+/// of paths are checked without asking the store; rules on path infos
+/// query the path first.
+///
+/// ```
+/// # use nixrs::store::StorePathFilter;
+/// let filter = StorePathFilter::new()
+///     .deny_name(|name| name.ends_with("-src") || name.ends_with(".tar.gz"));
+/// ```
+#[derive(Clone, Default)]
+pub struct StorePathFilter {
+    allow: Vec<Rule>,
+    deny: Vec<Rule>,
+}
+
+impl StorePathFilter {
+    pub fn new() -> StorePathFilter {
+        Default::default()
+    }
+
+    pub fn allow_name<F>(mut self, f: F) -> StorePathFilter
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.allow.push(Rule::Name(Arc::new(f)));
+        self
+    }
+
+    pub fn deny_name<F>(mut self, f: F) -> StorePathFilter
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.deny.push(Rule::Name(Arc::new(f)));
+        self
+    }
+
+    pub fn allow_info<F>(mut self, f: F) -> StorePathFilter
+    where
+        F: Fn(&StoreDir, &ValidPathInfo) -> bool + Send + Sync + 'static,
+    {
+        self.allow.push(Rule::Info(Arc::new(f)));
+        self
+    }
+
+    pub fn deny_info<F>(mut self, f: F) -> StorePathFilter
+    where
+        F: Fn(&StoreDir, &ValidPathInfo) -> bool + Send + Sync + 'static,
+    {
+        self.deny.push(Rule::Info(Arc::new(f)));
+        self
+    }
+
+    /// Allow only `paths`.
+    pub fn allow_paths(mut self, paths: StorePathSet) -> StorePathFilter {
+        self.allow.push(Rule::Paths(Arc::new(paths)));
+        self
+    }
+
+    pub fn deny_paths(mut self, paths: StorePathSet) -> StorePathFilter {
+        self.deny.push(Rule::Paths(Arc::new(paths)));
+        self
+    }
+
+    /// Allow the closure of `roots` in `store`, as it is now.
+    pub async fn allow_closure<S>(
+        self,
+        store: &mut S,
+        roots: &StorePathSet,
+    ) -> Result<StorePathFilter, Error>
+    where
+        S: Store + Send,
+    {
+        let closure = compute_fs_closure_slow(store, roots, false).await?;
+        Ok(self.allow_paths(closure))
+    }
+
+    /// Deny the closure of `roots` in `store`, as it is now.
+    pub async fn deny_closure<S>(
+        self,
+        store: &mut S,
+        roots: &StorePathSet,
+    ) -> Result<StorePathFilter, Error>
+    where
+        S: Store + Send,
+    {
+        let closure = compute_fs_closure_slow(store, roots, false).await?;
+        Ok(self.deny_paths(closure))
+    }
+
+    /// Allow paths with a valid signature by one of `keys`.
+    pub fn allow_signed_by(self, keys: Vec<PublicKey>) -> StorePathFilter {
+        self.allow_info(move |store_dir, info| {
+            let fingerprint = match info.fingerprint(store_dir) {
+                Ok(fingerprint) => fingerprint.to_string(),
+                Err(_) => return false,
+            };
+            info.sigs.iter().any(|sig| {
+                keys.iter()
+                    .any(|key| key.name() == sig.name() && key.verify(&fingerprint, sig))
+            })
+        })
+    }
+
+    fn needs_info(&self) -> bool {
+        self.allow
+            .iter()
+            .chain(self.deny.iter())
+            .any(|rule| matches!(rule, Rule::Info(_)))
+    }
+
+    pub fn is_allowed(
+        &self,
+        store_dir: &StoreDir,
+        path: &StorePath,
+        info: Option<&ValidPathInfo>,
+    ) -> bool {
+        let matches = |rule: &Rule| rule.matches(store_dir, path, info);
+        (self.allow.is_empty() || self.allow.iter().any(matches)) && !self.deny.iter().any(matches)
+    }
+
+    /// Whether `path`, which isn't valid yet, would be shown. Rules on
+    /// path infos can't be checked and are taken to allow it.
+    pub fn is_missing_allowed(&self, path: &StorePath) -> bool {
+        (self.allow.is_empty()
+            || self
+                .allow
+                .iter()
+                .any(|rule| rule.matches_path(path).unwrap_or(true)))
+            && !self
+                .deny
+                .iter()
+                .any(|rule| rule.matches_path(path).unwrap_or(false))
+    }
+}
+
+impl fmt::Debug for StorePathFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StorePathFilter")
+            .field("allow", &self.allow.len())
+            .field("deny", &self.deny.len())
+            .finish()
+    }
+}
+
+/// Wraps a store and hides the paths its [`StorePathFilter`] doesn't allow.
+///
+/// Hidden paths look invalid: queries leave them out and fetching their
+/// NARs fails with [`Error::InvalidPath`]. Operations that change the store
+/// are passed on, so wrap this in a [`PolicyStore`](super::PolicyStore)
+/// to serve a read-only view of a store.
+#[derive(Debug)]
+pub struct FilteredStore<S> {
+    store: S,
+    filter: StorePathFilter,
+}
+
+impl<S> FilteredStore<S> {
+    pub fn new(store: S, filter: StorePathFilter) -> FilteredStore<S> {
+        FilteredStore { store, filter }
+    }
+
+    pub fn filter(&self) -> &StorePathFilter {
+        &self.filter
+    }
+
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+}
+
+impl<S> FilteredStore<S>
+where
+    S: Store + Send,
+{
+    async fn is_allowed(&mut self, path: &StorePath) -> Result<bool, Error> {
+        let store_dir = self.store.store_dir();
+        if self.filter.needs_info() {
+            let info = self.store.query_path_info(path).await?;
+            Ok(self.filter.is_allowed(&store_dir, path, info.as_ref()))
+        } else {
+            Ok(self.filter.is_allowed(&store_dir, path, None))
+        }
+    }
+
+    async fn retain_allowed(&mut self, paths: StorePathSet) -> Result<StorePathSet, Error> {
+        let mut ret = StorePathSet::new();
+        for path in paths {
+            if self.is_allowed(&path).await? {
+                ret.insert(path);
+            }
+        }
+        Ok(ret)
+    }
+
+    async fn check_allowed(&mut self, path: &StorePath) -> Result<(), Error> {
+        if self.is_allowed(path).await? {
+            Ok(())
+        } else {
+            Err(Error::InvalidPath(self.store.store_dir().print_path(path)))
+        }
+    }
+}
+
+impl<S: StoreDirProvider> StoreDirProvider for FilteredStore<S> {
+    fn store_dir(&self) -> StoreDir {
+        self.store.store_dir()
+    }
+}
+
+#[async_trait]
+impl<S> Store for FilteredStore<S>
+where
+    S: Store + Send,
+{
+    async fn query_valid_paths(
+        &mut self,
+        paths: &StorePathSet,
+        maybe_substitute: SubstituteFlag,
+    ) -> Result<StorePathSet, Error> {
+        let valid = self
+            .store
+            .query_valid_paths(paths, maybe_substitute)
+            .await?;
+        self.retain_allowed(valid).await
+    }
+
+    async fn query_path_info(&mut self, path: &StorePath) -> Result<Option<ValidPathInfo>, Error> {
+        let info = self.store.query_path_info(path).await?;
+        if self
+            .filter
+            .is_allowed(&self.store.store_dir(), path, info.as_ref())
+        {
+            Ok(info)
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn nar_from_path<W: AsyncWrite + fmt::Debug + Send + Unpin>(
+        &mut self,
+        path: &StorePath,
+        sink: W,
+    ) -> Result<(), Error> {
+        self.check_allowed(path).await?;
+        self.store.nar_from_path(path, sink).await
+    }
+
+    async fn add_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        info: &ValidPathInfo,
+        source: R,
+        repair: RepairFlag,
+        check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error> {
+        self.store
+            .add_to_store(info, source, repair, check_sigs)
+            .await
+    }
+
+    async fn build_derivation(
+        &mut self,
+        drv_path: &StorePath,
+        drv: &BasicDerivation,
+        build_mode: BuildMode,
+    ) -> Result<BuildResult, Error> {
+        self.store.build_derivation(drv_path, drv, build_mode).await
+    }
+
+    async fn build_paths(
+        &mut self,
+        drv_paths: &[DerivedPath],
+        build_mode: BuildMode,
+    ) -> Result<(), Error> {
+        self.store.build_paths(drv_paths, build_mode).await
+    }
+}
+
+#[async_trait]
+impl<S> DaemonStore for FilteredStore<S>
+where
+    S: DaemonStore + Send,
+{
+    fn is_trusted_client(&self) -> Option<TrustedFlag> {
+        self.store.is_trusted_client()
+    }
+
+    fn experimental_features(&self) -> ExperimentalFeatures {
+        self.store.experimental_features()
+    }
+
+    async fn set_options(&mut self) -> Result<(), Error> {
+        self.store.set_options().await
+    }
+
+    async fn is_valid_path(&mut self, path: &StorePath) -> Result<bool, Error> {
+        Ok(self.store.is_valid_path(path).await? && self.is_allowed(path).await?)
+    }
+
+    async fn add_multiple_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        source: R,
+        repair: RepairFlag,
+        check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error> {
+        self.store
+            .add_multiple_to_store(source, repair, check_sigs)
+            .await
+    }
+
+    /// Only the targets that are shown are passed on, the others are
+    /// unknown, and so are the hidden paths it would build or substitute.
+    /// The sizes are the ones of the wrapped store.
+    async fn query_missing(
+        &mut self,
+        targets: &[DerivedPath],
+    ) -> Result<QueryMissingResult, Error> {
+        let mut shown = Vec::new();
+        let mut unknown = StorePathSet::new();
+        for target in targets {
+            let path = match target {
+                DerivedPath::Opaque(path) => path,
+                DerivedPath::Built { drv_path, .. } => base_drv_path(drv_path),
+            };
+            let allowed = if self.store.is_valid_path(path).await? {
+                self.is_allowed(path).await?
+            } else {
+                self.filter.is_missing_allowed(path)
+            };
+            if allowed {
+                shown.push(target.clone());
+            } else {
+                unknown.insert(path.clone());
+            }
+        }
+        let mut res = self.store.query_missing(&shown).await?;
+        let filter = &self.filter;
+        for paths in [&mut res.will_build, &mut res.will_substitute] {
+            let (kept, hidden): (StorePathSet, StorePathSet) = std::mem::take(paths)
+                .into_iter()
+                .partition(|path| filter.is_missing_allowed(path));
+            *paths = kept;
+            unknown.extend(hidden);
+        }
+        res.unknown.extend(unknown);
+        Ok(res)
+    }
+
+    async fn add_build_log(&mut self, drv_path: &StorePath, log: &str) -> Result<(), Error> {
+        self.store.add_build_log(drv_path, log).await
+    }
+
+    async fn collect_garbage(&mut self, options: &GCOptions) -> Result<GCResults, Error> {
+        self.store.collect_garbage(options).await
+    }
+
+    async fn collect_garbage_streaming(
+        &mut self,
+        options: &GCOptions,
+        act: &Activity,
+    ) -> Result<GCResults, Error> {
+        self.store.collect_garbage_streaming(options, act).await
+    }
+
+    /// Realisations of hidden paths are left out.
+    async fn query_realisation(&mut self, id: &DrvOutput) -> Result<Option<Realisation>, Error> {
+        match self.store.query_realisation(id).await? {
+            Some(realisation) if self.is_allowed(&realisation.out_path).await? => {
+                Ok(Some(realisation))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn register_drv_output(&mut self, realisation: &Realisation) -> Result<(), Error> {
+        self.store.register_drv_output(realisation).await
+    }
+
+    async fn add_indirect_root(&mut self, path: &DaemonPath) -> Result<(), Error> {
+        self.store.add_indirect_root(path).await
+    }
+
+    /// Roots of hidden paths are left out.
+    async fn find_roots(&mut self) -> Result<BTreeMap<DaemonPath, StorePath>, Error> {
+        let mut ret = BTreeMap::new();
+        for (link, path) in self.store.find_roots().await? {
+            if self.is_allowed(&path).await? {
+                ret.insert(link, path);
+            }
+        }
+        Ok(ret)
+    }
+
+    async fn verify_store(
+        &mut self,
+        check_contents: bool,
+        repair: RepairFlag,
+    ) -> Result<bool, Error> {
+        self.store.verify_store(check_contents, repair).await
+    }
+
+    async fn optimise_store(&mut self) -> Result<(), Error> {
+        self.store.optimise_store().await
+    }
+
+    async fn repair_path(&mut self, path: &StorePath) -> Result<(), Error> {
+        self.check_allowed(path).await?;
+        self.store.repair_path(path).await
+    }
+
+    async fn substitute_paths(&mut self, paths: &StorePathSet) -> Result<(), Error> {
+        self.store.substitute_paths(paths).await
+    }
+}
+
+#[async_trait]
+impl<S> LegacyStore for FilteredStore<S>
+where
+    S: LegacyStore + Send,
+{
+    async fn query_valid_paths_locked(
+        &mut self,
+        paths: &StorePathSet,
+        lock: bool,
+        maybe_substitute: SubstituteFlag,
+    ) -> Result<StorePathSet, Error> {
+        let valid = self
+            .store
+            .query_valid_paths_locked(paths, lock, maybe_substitute)
+            .await?;
+        self.retain_allowed(valid).await
+    }
+
+    async fn export_paths<W: AsyncWrite + fmt::Debug + Send + Unpin>(
+        &mut self,
+        paths: &StorePathSet,
+        sink: W,
+    ) -> Result<(), Error> {
+        for path in paths {
+            self.check_allowed(path).await?;
+        }
+        self.store.export_paths(paths, sink).await
+    }
+
+    async fn import_paths<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        source: R,
+    ) -> Result<(), Error> {
+        self.store.import_paths(source).await
+    }
+
+    async fn query_closure(
+        &mut self,
+        paths: &StorePathSet,
+        include_outputs: bool,
+    ) -> Result<StorePathSet, Error> {
+        let closure = self.store.query_closure(paths, include_outputs).await?;
+        self.retain_allowed(closure).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+    use ring::rand::SystemRandom;
+
+    use super::*;
+    use crate::hash;
+    use crate::signature::SecretKey;
+    use crate::store::call_store::{call_all, CallStore, DAEMON_STORE_METHODS};
+    use crate::store::MemoryStore;
+
+    async fn add(store: &mut MemoryStore, name: &str, key: Option<&SecretKey>) -> StorePath {
+        add_with_references(store, name, key, &[]).await
+    }
+
+    async fn add_with_references(
+        store: &mut MemoryStore,
+        name: &str,
+        key: Option<&SecretKey>,
+        references: &[&StorePath],
+    ) -> StorePath {
+        let nar = name.as_bytes();
+        let mut info = ValidPathInfo::new(
+            StorePath::test_from_seed(name),
+            hash::digest(hash::Algorithm::SHA256, nar),
+        );
+        info.nar_size = nar.len() as u64;
+        info.references = references.iter().map(|path| (*path).clone()).collect();
+        if let Some(key) = key {
+            let fingerprint = info.fingerprint(&store.store_dir()).unwrap().to_string();
+            info.sigs.insert(key.sign(fingerprint));
+        }
+        store
+            .add_to_store(
+                &info,
+                nar,
+                RepairFlag::NoRepair,
+                CheckSignaturesFlag::NoCheckSigs,
+            )
+            .await
+            .unwrap();
+        info.path
+    }
+
+    #[tokio::test]
+    async fn test_deny_name() {
+        let mut inner = MemoryStore::new();
+        let hello = add(&mut inner, "hello", None).await;
+        let src = add(&mut inner, "hello-src", None).await;
+        let filter = StorePathFilter::new().deny_name(|name| name.ends_with("-src"));
+        let mut store = FilteredStore::new(inner, filter);
+
+        let paths: StorePathSet = [hello.clone(), src.clone()].into_iter().collect();
+        let valid = store
+            .query_valid_paths(&paths, SubstituteFlag::NoSubstitute)
+            .await
+            .unwrap();
+        assert_eq!(valid, [hello.clone()].into_iter().collect());
+        assert!(store.query_path_info(&hello).await.unwrap().is_some());
+        assert_eq!(None, store.query_path_info(&src).await.unwrap());
+        assert!(!store.is_valid_path(&src).await.unwrap());
+
+        let mut buf = Vec::new();
+        store.nar_from_path(&hello, &mut buf).await.unwrap();
+        assert_eq!(buf, b"hello");
+        let res = store.nar_from_path(&src, &mut buf).await;
+        assert_matches!(res, Err(Error::InvalidPath(_)));
+    }
+
+    #[tokio::test]
+    async fn test_allow_signed_by() {
+        let rng = SystemRandom::new();
+        let key = SecretKey::generate("cache.example.org-1".into(), &rng).unwrap();
+        let other = SecretKey::generate("cache.example.org-2".into(), &rng).unwrap();
+        let mut inner = MemoryStore::new();
+        let signed = add(&mut inner, "signed", Some(&key)).await;
+        let unsigned = add(&mut inner, "unsigned", None).await;
+        let foreign = add(&mut inner, "foreign", Some(&other)).await;
+        let filter = StorePathFilter::new().allow_signed_by(vec![key.to_public_key()]);
+        let mut store = FilteredStore::new(inner, filter);
+
+        let paths: StorePathSet = [signed.clone(), unsigned, foreign].into_iter().collect();
+        let valid = store
+            .query_valid_paths(&paths, SubstituteFlag::NoSubstitute)
+            .await
+            .unwrap();
+        assert_eq!(valid, [signed].into_iter().collect());
+    }
+
+    #[tokio::test]
+    async fn test_allow_closure() {
+        let mut inner = MemoryStore::new();
+        let lib = add(&mut inner, "lib", None).await;
+        let bin = add_with_references(&mut inner, "bin", None, &[&lib]).await;
+        let other = add(&mut inner, "other", None).await;
+        let roots: StorePathSet = [bin.clone()].into_iter().collect();
+        let filter = StorePathFilter::new()
+            .allow_closure(&mut inner, &roots)
+            .await
+            .unwrap();
+        let mut store = FilteredStore::new(inner, filter);
+
+        let paths: StorePathSet = [bin.clone(), lib.clone(), other].into_iter().collect();
+        let valid = store
+            .query_valid_paths(&paths, SubstituteFlag::NoSubstitute)
+            .await
+            .unwrap();
+        assert_eq!(valid, [bin, lib].into_iter().collect());
+    }
+
+    #[tokio::test]
+    async fn test_query_missing_hides_targets() {
+        let mut inner = MemoryStore::new();
+        let hello = add(&mut inner, "hello", None).await;
+        let src = add(&mut inner, "hello-src", None).await;
+        let filter = StorePathFilter::new().deny_name(|name| name.ends_with("-src"));
+        let mut store = FilteredStore::new(inner, filter);
+
+        let targets = vec![
+            DerivedPath::Opaque(hello.clone()),
+            DerivedPath::Opaque(src.clone()),
+        ];
+        let res = store.query_missing(&targets).await.unwrap();
+        assert_eq!(res.unknown, [src].into_iter().collect());
+        assert!(res.will_build.is_empty());
+        assert!(res.will_substitute.is_empty());
+    }
+
+    #[test]
+    fn test_is_missing_allowed() {
+        let filter = StorePathFilter::new()
+            .allow_signed_by(Vec::new())
+            .deny_name(|name| name.ends_with("-src"));
+        assert!(filter.is_missing_allowed(&StorePath::test_from_seed("hello")));
+        assert!(!filter.is_missing_allowed(&StorePath::test_from_seed("hello-src")));
+    }
+
+    #[tokio::test]
+    async fn test_forwards_daemon_store() {
+        let mut store = FilteredStore::new(CallStore::default(), StorePathFilter::new());
+        for (method, res) in call_all(&mut store).await {
+            assert!(res.is_ok(), "{method}: {res:?}");
+        }
+        assert!(store.into_inner().called_all(DAEMON_STORE_METHODS));
+
+        let filter = StorePathFilter::new().deny_name(|name| name == "call");
+        let mut store = FilteredStore::new(CallStore::default(), filter);
+        let res = store.repair_path(&StorePath::test_from_seed("call")).await;
+        assert_matches!(res, Err(Error::InvalidPath(_)));
+    }
+}
@@ -32,7 +32,9 @@ use crate::store::{
     BasicDerivation, BuildMode, BuildResult, BuildStatus, CheckSignaturesFlag, DerivedPath, Error,
     RepairFlag, SPWOParseResult, Store, SubstituteFlag, EXPORT_MAGIC,
 };
-use crate::store_path::{ParseStorePathError, StoreDir, StoreDirProvider, StorePath, StorePathSet};
+use crate::store_path::{
+    ContentAddress, ParseStorePathError, StoreDir, StoreDirProvider, StorePath, StorePathSet,
+};
 
 #[derive(Debug, Clone)]
 struct DispatchInner {
@@ -514,11 +516,7 @@ where
         }
         let nar_hash: Hash = s.parse()?;
         let ca_s = self.source.read_string().await?;
-        let ca = if !ca_s.is_empty() {
-            Some(ca_s.parse()?)
-        } else {
-            None
-        };
+        let ca = ContentAddress::parse_opt(&ca_s)?;
         let sigs: Vec<String> = self.source.read_string_coll().await?;
         let sigs = sigs
             .iter()
@@ -595,11 +593,9 @@ where
             self.sink.write_bool(info.ultimate).await?;
             let sigs: Vec<String> = info.sigs.iter().map(ToString::to_string).collect();
             self.sink.write_string_coll(&sigs).await?;
-            if let Some(ca) = info.ca.as_ref() {
-                self.sink.write_str(&ca.to_string()).await?;
-            } else {
-                self.sink.write_str("").await?;
-            }
+            self.sink
+                .write_string(ContentAddress::print_opt(info.ca.as_ref()))
+                .await?;
 
             // TODO: Handle exceptions
             //try {
@@ -22,7 +22,7 @@ use crate::store::{
     BasicDerivation, CheckSignaturesFlag, DerivedPath, Error, RepairFlag, StorePathWithOutputs,
     SubstituteFlag,
 };
-use crate::store_path::{StorePath, StorePathSet};
+use crate::store_path::{ContentAddress, StorePath, StorePathSet};
 
 async fn read_build_settings<R>(source: &mut R, client_version: u64) -> Result<(), Error>
 where
@@ -158,12 +158,8 @@ where
                                 let s = info.nar_hash.to_base32().to_string();
                                 out.write_str(&s).await?;
 
-                                if let Some(ca) = info.ca.as_ref() {
-                                    let ca = ca.to_string();
-                                    out.write_str(&ca).await?;
-                                } else {
-                                    out.write_str("").await?;
-                                }
+                                out.write_string(ContentAddress::print_opt(info.ca.as_ref()))
+                                    .await?;
                                 let sigs: Vec<String> =
                                     info.sigs.iter().map(ToString::to_string).collect();
                                 out.write_string_coll(&sigs).await?;
@@ -277,11 +273,7 @@ where
                     .map(|s| s.parse())
                     .collect::<Result<SignatureSet, ParseSignatureError>>()?;
                 let ca_s = source.read_string().await?;
-                let ca = if !ca_s.is_empty() {
-                    Some(ca_s.parse()?)
-                } else {
-                    None
-                };
+                let ca = ContentAddress::parse_opt(&ca_s)?;
 
                 if nar_size == 0 {
                     return Err(Error::Misc(
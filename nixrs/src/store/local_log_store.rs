@@ -0,0 +1,231 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+
+use async_compression::tokio::bufread::{BzDecoder, ZstdDecoder};
+use async_compression::tokio::write::{BzEncoder, ZstdEncoder};
+use async_trait::async_trait;
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+use crate::store::{Error, LogStore};
+use crate::store_path::{StoreDir, StoreDirProvider, StorePath};
+
+pub const DEFAULT_LOG_DIR: &str = "/nix/var/log/nix";
+
+/// How new build logs are compressed. Logs are read back in any of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogCompression {
+    None,
+    /// What Nix itself writes when `compress-build-log` is on.
+    #[default]
+    Bzip2,
+    Zstd,
+}
+
+impl LogCompression {
+    fn extension(&self) -> &'static str {
+        match self {
+            LogCompression::None => "",
+            LogCompression::Bzip2 => ".bz2",
+            LogCompression::Zstd => ".zst",
+        }
+    }
+}
+
+pub type LogReader = Pin<Box<dyn AsyncRead + Send>>;
+
+/// Build logs kept on disk the way Nix keeps them:
+/// `<log dir>/drvs/<first 2 chars>/<rest of drv base name>[.bz2]`.
+///
+/// Logs written by Nix before it split them over subdirectories, directly
+/// in `drvs/`, are found too.
+#[derive(Debug, Clone)]
+pub struct LocalLogStore {
+    store_dir: StoreDir,
+    log_dir: PathBuf,
+    compression: LogCompression,
+}
+
+impl LocalLogStore {
+    pub fn new(store_dir: StoreDir, log_dir: impl Into<PathBuf>) -> LocalLogStore {
+        LocalLogStore {
+            store_dir,
+            log_dir: log_dir.into(),
+            compression: LogCompression::default(),
+        }
+    }
+
+    pub fn compression(mut self, compression: LogCompression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn log_dir(&self) -> &Path {
+        &self.log_dir
+    }
+
+    /// Where the log of `drv_path` is written, without the compression
+    /// extension.
+    pub fn log_path(&self, drv_path: &StorePath) -> PathBuf {
+        let base_name = drv_path.to_string();
+        let (dir, file) = base_name.split_at(2);
+        self.log_dir.join("drvs").join(dir).join(file)
+    }
+
+    fn candidates(&self, drv_path: &StorePath) -> Vec<(PathBuf, LogCompression)> {
+        let nested = self.log_path(drv_path);
+        let flat = self.log_dir.join("drvs").join(drv_path.to_string());
+        let mut ret = Vec::new();
+        for base in [nested, flat] {
+            for compression in [
+                LogCompression::Bzip2,
+                LogCompression::Zstd,
+                LogCompression::None,
+            ] {
+                let mut path = base.clone().into_os_string();
+                path.push(compression.extension());
+                ret.push((path.into(), compression));
+            }
+        }
+        ret
+    }
+
+    /// Opens the log of `drv_path` for reading, decompressing it on the
+    /// fly, or returns `None` when there is no log for it.
+    pub async fn open_build_log(&self, drv_path: &StorePath) -> Result<Option<LogReader>, Error> {
+        for (path, compression) in self.candidates(drv_path) {
+            let file = match fs::File::open(&path).await {
+                Ok(file) => BufReader::new(file),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            };
+            let reader: LogReader = match compression {
+                LogCompression::None => Box::pin(file),
+                LogCompression::Bzip2 => Box::pin(BzDecoder::new(file)),
+                LogCompression::Zstd => Box::pin(ZstdDecoder::new(file)),
+            };
+            return Ok(Some(reader));
+        }
+        Ok(None)
+    }
+
+    /// Writes the log of `drv_path` from `log`, replacing any log there
+    /// was. The log only shows up once it has been written completely.
+    pub async fn write_build_log<R>(&self, drv_path: &StorePath, mut log: R) -> Result<(), Error>
+    where
+        R: AsyncRead + Send + Unpin,
+    {
+        let mut path = self.log_path(drv_path).into_os_string();
+        path.push(self.compression.extension());
+        let path = PathBuf::from(path);
+        let dir = path.parent().unwrap();
+        fs::create_dir_all(dir).await?;
+        let tmp = dir.join(format!(".tmp-{}-{}", std::process::id(), drv_path));
+        let file = fs::File::create(&tmp).await?;
+        let res = match self.compression {
+            LogCompression::None => copy_log(&mut log, file).await,
+            LogCompression::Bzip2 => copy_log(&mut log, BzEncoder::new(file)).await,
+            LogCompression::Zstd => copy_log(&mut log, ZstdEncoder::new(file)).await,
+        };
+        if let Err(err) = res {
+            let _ = fs::remove_file(&tmp).await;
+            return Err(err.into());
+        }
+        fs::rename(&tmp, &path).await?;
+        for (other, _) in self.candidates(drv_path) {
+            if other != path {
+                match fs::remove_file(&other).await {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn copy_log<R, W>(log: &mut R, mut sink: W) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    tokio::io::copy(log, &mut sink).await?;
+    sink.shutdown().await
+}
+
+impl StoreDirProvider for LocalLogStore {
+    fn store_dir(&self) -> StoreDir {
+        self.store_dir.clone()
+    }
+}
+
+#[async_trait]
+impl LogStore for LocalLogStore {
+    async fn get_build_log(&mut self, drv_path: &StorePath) -> Result<Option<String>, Error> {
+        match self.open_build_log(drv_path).await? {
+            Some(mut reader) => {
+                let mut buf = Vec::new();
+                reader.read_to_end(&mut buf).await?;
+                Ok(Some(String::from_utf8_lossy(&buf).into_owned()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn add_build_log(&mut self, drv_path: &StorePath, log: &str) -> Result<(), Error> {
+        self.write_build_log(drv_path, log.as_bytes()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_build_log_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let drv_path = StorePath::test_from_seed("hello.drv");
+        for compression in [
+            LogCompression::None,
+            LogCompression::Bzip2,
+            LogCompression::Zstd,
+        ] {
+            let mut logs =
+                LocalLogStore::new(StoreDir::default(), dir.path()).compression(compression);
+            assert_eq!(None, logs.get_build_log(&drv_path).await.unwrap());
+            logs.add_build_log(&drv_path, "building '/nix/store/x.drv'\n")
+                .await
+                .unwrap();
+            assert_eq!(
+                Some("building '/nix/store/x.drv'\n".to_string()),
+                logs.get_build_log(&drv_path).await.unwrap()
+            );
+
+            let base_name = drv_path.to_string();
+            let mut expected = dir.path().join("drvs").join(&base_name[..2]);
+            expected.push(format!("{}{}", &base_name[2..], compression.extension()));
+            assert!(expected.exists());
+            let entries = std::fs::read_dir(expected.parent().unwrap()).unwrap();
+            assert_eq!(1, entries.count());
+            std::fs::remove_file(expected).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flat_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let drv_path = StorePath::test_from_seed("hello.drv");
+        std::fs::create_dir_all(dir.path().join("drvs")).unwrap();
+        std::fs::write(
+            dir.path().join("drvs").join(drv_path.to_string()),
+            "old log",
+        )
+        .unwrap();
+        let mut logs = LocalLogStore::new(StoreDir::default(), dir.path());
+        assert_eq!(
+            Some("old log".to_string()),
+            logs.get_build_log(&drv_path).await.unwrap()
+        );
+    }
+}
@@ -0,0 +1,36 @@
+use async_trait::async_trait;
+
+use crate::store::Error;
+use crate::store_path::{StoreDirProvider, StorePath};
+
+/// Stores that keep the build logs of derivations, which is what `nix log`
+/// reads.
+#[async_trait]
+pub trait LogStore: StoreDirProvider {
+    /// The build log of `drv_path`, or `None` when the store has no log for
+    /// it.
+    async fn get_build_log(&mut self, drv_path: &StorePath) -> Result<Option<String>, Error>;
+    async fn add_build_log(&mut self, drv_path: &StorePath, log: &str) -> Result<(), Error>;
+}
+
+#[async_trait]
+impl<T: ?Sized + LogStore + Send> LogStore for Box<T> {
+    async fn get_build_log(&mut self, drv_path: &StorePath) -> Result<Option<String>, Error> {
+        (**self).get_build_log(drv_path).await
+    }
+
+    async fn add_build_log(&mut self, drv_path: &StorePath, log: &str) -> Result<(), Error> {
+        (**self).add_build_log(drv_path, log).await
+    }
+}
+
+#[async_trait]
+impl<T: ?Sized + LogStore + Send> LogStore for &mut T {
+    async fn get_build_log(&mut self, drv_path: &StorePath) -> Result<Option<String>, Error> {
+        (**self).get_build_log(drv_path).await
+    }
+
+    async fn add_build_log(&mut self, drv_path: &StorePath, log: &str) -> Result<(), Error> {
+        (**self).add_build_log(drv_path, log).await
+    }
+}
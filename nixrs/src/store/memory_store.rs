@@ -0,0 +1,681 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::error;
+
+use crate::hash::Context;
+use crate::path_info::ValidPathInfo;
+use crate::store::activity::{Activity, ActivityBuilder, ActivityType, ResultKind};
+use crate::store::daemon::{
+    DaemonStore, GCAction, GCOptions, GCResults, QueryMissingResult, TrustedFlag,
+};
+use crate::store::misc::{add_multiple_to_store_old, topo_sort_paths};
+use crate::store::query_missing::query_missing_slow;
+use crate::store::{
+    CheckSignaturesFlag, DerivedPath, DrvOutput, Error, ExperimentalFeature, ExperimentalFeatures,
+    LogStore, Realisation, RepairFlag, SingleDerivedPath, Store, SubstituteFlag, Verbosity,
+    Verifier,
+};
+use crate::store_path::{StoreDir, StoreDirProvider, StorePath, StorePathSet};
+
+/// A store that keeps path infos and NARs in memory.
+///
+/// Adding a path checks its NAR hash and size and requires all its
+/// references to be valid, just like a real store, but signatures are not
+/// checked and nothing can be built. Build logs are kept for any path and
+/// realisations for any output that is valid.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    store_dir: StoreDir,
+    paths: BTreeMap<StorePath, (ValidPathInfo, Bytes)>,
+    logs: BTreeMap<StorePath, String>,
+    realisations: BTreeMap<DrvOutput, Realisation>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        Default::default()
+    }
+
+    pub fn with_store_dir(store_dir: StoreDir) -> MemoryStore {
+        MemoryStore {
+            store_dir,
+            paths: BTreeMap::new(),
+            logs: BTreeMap::new(),
+            realisations: BTreeMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    /// All valid paths in the store.
+    pub fn paths(&self) -> StorePathSet {
+        self.paths.keys().cloned().collect()
+    }
+
+    /// The paths in `paths` together with everything they reference,
+    /// ignoring paths that are not in the store.
+    pub fn closure(&self, paths: &StorePathSet) -> StorePathSet {
+        let mut res = StorePathSet::new();
+        let mut pending: Vec<&StorePath> = paths.iter().collect();
+        while let Some(path) = pending.pop() {
+            if let Some((info, _)) = self.paths.get(path) {
+                if res.insert(path.clone()) {
+                    pending.extend(info.references.iter());
+                }
+            }
+        }
+        res
+    }
+
+    /// Delete every path that is not in the closure of `roots` and return
+    /// the deleted paths.
+    pub fn collect_garbage(&mut self, roots: &StorePathSet) -> StorePathSet {
+        let live = self.closure(roots);
+        let dead: StorePathSet = self
+            .paths
+            .keys()
+            .filter(|path| !live.contains(*path))
+            .cloned()
+            .collect();
+        for path in dead.iter() {
+            self.paths.remove(path);
+        }
+        dead
+    }
+}
+
+pub(crate) fn base_drv_path(mut drv_path: &SingleDerivedPath) -> &StorePath {
+    loop {
+        match drv_path {
+            SingleDerivedPath::Opaque(path) => return path,
+            SingleDerivedPath::Built {
+                drv_path: inner, ..
+            } => drv_path = inner,
+        }
+    }
+}
+
+impl StoreDirProvider for MemoryStore {
+    fn store_dir(&self) -> StoreDir {
+        self.store_dir.clone()
+    }
+}
+
+#[async_trait]
+impl LogStore for MemoryStore {
+    async fn get_build_log(&mut self, drv_path: &StorePath) -> Result<Option<String>, Error> {
+        Ok(self.logs.get(drv_path).cloned())
+    }
+
+    async fn add_build_log(&mut self, drv_path: &StorePath, log: &str) -> Result<(), Error> {
+        self.logs.insert(drv_path.clone(), log.into());
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    async fn query_valid_paths(
+        &mut self,
+        paths: &StorePathSet,
+        _maybe_substitute: SubstituteFlag,
+    ) -> Result<StorePathSet, Error> {
+        Ok(paths
+            .iter()
+            .filter(|path| self.paths.contains_key(*path))
+            .cloned()
+            .collect())
+    }
+
+    async fn query_path_info(&mut self, path: &StorePath) -> Result<Option<ValidPathInfo>, Error> {
+        Ok(self.paths.get(path).map(|(info, _)| info.clone()))
+    }
+
+    async fn nar_from_path<W: AsyncWrite + fmt::Debug + Send + Unpin>(
+        &mut self,
+        path: &StorePath,
+        mut sink: W,
+    ) -> Result<(), Error> {
+        let (_, nar) = self
+            .paths
+            .get(path)
+            .ok_or_else(|| Error::InvalidPath(self.store_dir.print_path(path)))?;
+        sink.write_all(nar).await?;
+        sink.flush().await?;
+        Ok(())
+    }
+
+    async fn add_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        info: &ValidPathInfo,
+        mut source: R,
+        repair: RepairFlag,
+        _check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error> {
+        // The NAR has to be read even when the path is already valid so
+        // that the rest of the stream stays in sync.
+        let mut nar = Vec::new();
+        source.read_to_end(&mut nar).await?;
+        if repair == RepairFlag::NoRepair && self.paths.contains_key(&info.path) {
+            return Ok(());
+        }
+
+        let path_s = self.store_dir.print_path(&info.path);
+        let mut ctx = Context::new(info.nar_hash.algorithm());
+        ctx.update(&nar);
+        let nar_hash = ctx.finish();
+        if nar_hash != info.nar_hash {
+            return Err(Error::NarHashMismatch(
+                path_s,
+                info.nar_hash.to_sri().to_string(),
+                nar_hash.to_sri().to_string(),
+            ));
+        }
+        let mut info = info.clone();
+        let nar_size = nar.len() as u64;
+        if info.nar_size == 0 {
+            info.nar_size = nar_size;
+        } else if info.nar_size != nar_size {
+            return Err(Error::NarSizeMismatch(path_s, info.nar_size, nar_size));
+        }
+        let missing = info
+            .references
+            .iter()
+            .find(|reference| **reference != info.path && !self.paths.contains_key(*reference));
+        if let Some(missing) = missing {
+            return Err(Error::MissingReference(
+                path_s,
+                self.store_dir.print_path(missing),
+            ));
+        }
+
+        self.paths.insert(info.path.clone(), (info, nar.into()));
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl DaemonStore for MemoryStore {
+    fn is_trusted_client(&self) -> Option<TrustedFlag> {
+        Some(TrustedFlag::Trusted)
+    }
+
+    fn experimental_features(&self) -> ExperimentalFeatures {
+        [ExperimentalFeature::CaDerivations].into_iter().collect()
+    }
+
+    async fn set_options(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn is_valid_path(&mut self, path: &StorePath) -> Result<bool, Error> {
+        Ok(self.paths.contains_key(path))
+    }
+
+    async fn add_multiple_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        source: R,
+        repair: RepairFlag,
+        check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error> {
+        add_multiple_to_store_old(self, source, repair, check_sigs).await
+    }
+
+    async fn add_build_log(&mut self, drv_path: &StorePath, log: &str) -> Result<(), Error> {
+        LogStore::add_build_log(self, drv_path, log).await
+    }
+
+    async fn query_realisation(&mut self, id: &DrvOutput) -> Result<Option<Realisation>, Error> {
+        Ok(self.realisations.get(id).cloned())
+    }
+
+    async fn register_drv_output(&mut self, realisation: &Realisation) -> Result<(), Error> {
+        if !self.paths.contains_key(&realisation.out_path) {
+            return Err(Error::InvalidPath(
+                self.store_dir.print_path(&realisation.out_path),
+            ));
+        }
+        self.realisations
+            .insert(realisation.id.clone(), realisation.clone());
+        Ok(())
+    }
+
+    /// Nothing can be repaired since there is nowhere to get paths from.
+    async fn verify_store(
+        &mut self,
+        check_contents: bool,
+        _repair: RepairFlag,
+    ) -> Result<bool, Error> {
+        let act = ActivityBuilder::new(
+            Verbosity::Info,
+            ActivityType::VerifyPaths,
+            "checking path contents",
+        )
+        .start();
+        let findings = Verifier::new(self.paths())
+            .check_contents(check_contents)
+            .collect(self, &act)
+            .await?;
+        for finding in findings.iter() {
+            error!("{}", finding.describe(&self.store_dir));
+        }
+        Ok(!findings.is_empty())
+    }
+
+    async fn collect_garbage(&mut self, options: &GCOptions) -> Result<GCResults, Error> {
+        let act = Activity::disabled();
+        self.collect_garbage_streaming(options, &act).await
+    }
+
+    /// There are no GC roots so every path is dead unless it is referenced
+    /// by a path that is kept. The size of a path is the size of its NAR.
+    ///
+    /// Referrers are deleted before the paths they reference, so stopping
+    /// at `max_freed` never leaves a path referring to a deleted one.
+    async fn collect_garbage_streaming(
+        &mut self,
+        options: &GCOptions,
+        act: &Activity,
+    ) -> Result<GCResults, Error> {
+        let mut results = GCResults::default();
+        let dead = match options.action {
+            GCAction::ReturnLive => return Ok(results),
+            GCAction::ReturnDead => {
+                results.paths = self
+                    .paths
+                    .keys()
+                    .map(|path| self.store_dir.print_path(path))
+                    .collect();
+                return Ok(results);
+            }
+            GCAction::DeleteDead => self.paths(),
+            GCAction::DeleteSpecific => {
+                let dead = &options.paths_to_delete;
+                if !options.ignore_liveness {
+                    for (path, (info, _)) in self.paths.iter() {
+                        if dead.contains(path) {
+                            continue;
+                        }
+                        if let Some(alive) = info.references.iter().find(|r| dead.contains(*r)) {
+                            return Err(Error::PathStillAlive(self.store_dir.print_path(alive)));
+                        }
+                    }
+                }
+                dead.clone()
+            }
+        };
+        let dead = topo_sort_paths(self, &dead).await?;
+        for path in dead.iter().rev() {
+            if results.bytes_freed >= options.max_freed {
+                break;
+            }
+            if let Some((_, nar)) = self.paths.remove(path) {
+                let path = self.store_dir.print_path(path);
+                let bytes = nar.len() as u64;
+                act.report(ResultKind::DeletedPath {
+                    path: path.clone(),
+                    bytes,
+                });
+                results.bytes_freed += bytes;
+                results.paths.insert(path);
+            }
+        }
+        Ok(results)
+    }
+
+    /// Nothing is ever substituted, so missing outputs of valid
+    /// derivations are to be built and other missing paths are unknown.
+    async fn query_missing(
+        &mut self,
+        targets: &[DerivedPath],
+    ) -> Result<QueryMissingResult, Error> {
+        query_missing_slow::<_, MemoryStore>(self, &mut [], targets).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+    use bytes::BytesMut;
+
+    use super::*;
+    use crate::archive::{test_data, NAREvent};
+    use crate::store::{
+        check_realisation_dependencies, copy_paths, copy_paths_remapped, copy_realisations,
+        VerifyFinding,
+    };
+    use crate::store_path::StoreDirRemap;
+
+    fn text_file_nar() -> Bytes {
+        let mut buf = BytesMut::new();
+        for event in test_data::text_file() {
+            event.encode_into(&mut buf);
+        }
+        buf.freeze()
+    }
+
+    fn test_info(seed: &str, nar: &[u8], references: &[&StorePath]) -> ValidPathInfo {
+        let mut ctx = Context::new(crate::hash::Algorithm::SHA256);
+        ctx.update(nar);
+        let mut info = ValidPathInfo::new(StorePath::test_from_seed(seed), ctx.finish());
+        info.references = references.iter().map(|path| (*path).clone()).collect();
+        info
+    }
+
+    async fn add(store: &mut MemoryStore, info: &ValidPathInfo, nar: &[u8]) -> Result<(), Error> {
+        store
+            .add_to_store(
+                info,
+                nar,
+                RepairFlag::NoRepair,
+                CheckSignaturesFlag::NoCheckSigs,
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_add_and_read_back() {
+        let nar = text_file_nar();
+        let info = test_info("a", &nar, &[]);
+        let mut store = MemoryStore::new();
+        add(&mut store, &info, &nar).await.unwrap();
+
+        let stored = store.query_path_info(&info.path).await.unwrap().unwrap();
+        assert_eq!(stored.nar_size, nar.len() as u64);
+        let mut out = Vec::new();
+        store.nar_from_path(&info.path, &mut out).await.unwrap();
+        assert_eq!(out, nar);
+    }
+
+    #[tokio::test]
+    async fn test_add_checks_hash_and_references() {
+        let nar = text_file_nar();
+        let mut store = MemoryStore::new();
+
+        let info = test_info("bad", b"other", &[]);
+        let res = add(&mut store, &info, &nar).await;
+        assert_matches!(res, Err(Error::NarHashMismatch(_, _, _)));
+
+        let missing = StorePath::test_from_seed("missing");
+        let info = test_info("b", &nar, &[&missing]);
+        let res = add(&mut store, &info, &nar).await;
+        assert_matches!(res, Err(Error::MissingReference(_, _)));
+        assert!(store.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_copy_and_collect_garbage() {
+        let nar = text_file_nar();
+        let dep = test_info("dep", &nar, &[]);
+        let top = test_info("top", &nar, &[&dep.path]);
+        let other = test_info("other", &nar, &[]);
+        let mut src = MemoryStore::new();
+        add(&mut src, &dep, &nar).await.unwrap();
+        add(&mut src, &top, &nar).await.unwrap();
+        add(&mut src, &other, &nar).await.unwrap();
+
+        let mut dst = MemoryStore::new();
+        let paths = src.closure(&[top.path.clone()].into_iter().collect());
+        copy_paths(&mut src, &mut dst, &paths).await.unwrap();
+        assert_eq!(dst.paths(), paths);
+
+        let dead = src.collect_garbage(&[top.path.clone()].into_iter().collect());
+        assert_eq!(dead, [other.path].into_iter().collect());
+        assert_eq!(src.paths(), paths);
+    }
+
+    #[tokio::test]
+    async fn test_collect_garbage_specific() {
+        let nar = text_file_nar();
+        let dep = test_info("dep", &nar, &[]);
+        let top = test_info("top", &nar, &[&dep.path]);
+        let mut store = MemoryStore::new();
+        add(&mut store, &dep, &nar).await.unwrap();
+        add(&mut store, &top, &nar).await.unwrap();
+
+        let mut options = GCOptions {
+            action: GCAction::DeleteSpecific,
+            paths_to_delete: [dep.path.clone()].into_iter().collect(),
+            ..Default::default()
+        };
+        let res = DaemonStore::collect_garbage(&mut store, &options).await;
+        assert_matches!(res, Err(Error::PathStillAlive(_)));
+
+        options.paths_to_delete.insert(top.path.clone());
+        let res = DaemonStore::collect_garbage(&mut store, &options)
+            .await
+            .unwrap();
+        assert_eq!(res.paths.len(), 2);
+        assert_eq!(res.bytes_freed, 2 * nar.len() as u64);
+        assert!(store.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_collect_garbage_max_freed() {
+        let nar = text_file_nar();
+        let dep = test_info("dep", &nar, &[]);
+        let mid = test_info("mid", &nar, &[&dep.path]);
+        let top = test_info("top", &nar, &[&mid.path, &dep.path]);
+        for max_freed in [1, nar.len() as u64 + 1] {
+            let mut store = MemoryStore::new();
+            add(&mut store, &dep, &nar).await.unwrap();
+            add(&mut store, &mid, &nar).await.unwrap();
+            add(&mut store, &top, &nar).await.unwrap();
+            let options = GCOptions {
+                max_freed,
+                ..Default::default()
+            };
+            DaemonStore::collect_garbage(&mut store, &options)
+                .await
+                .unwrap();
+            assert!(!store.paths().contains(&top.path));
+            for (_, (info, _)) in store.paths.iter() {
+                for reference in info.references.iter() {
+                    assert!(store.paths.contains_key(reference));
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_copy_remapped() {
+        let nar = text_file_nar();
+        let dep = test_info("dep", &nar, &[]);
+        let dep_s = format!("/nix/store/{}", dep.path);
+        let mut top_nar = Vec::new();
+        for event in test_data::text_file() {
+            let event = match event {
+                NAREvent::RegularNode {
+                    executable, offset, ..
+                } => NAREvent::RegularNode {
+                    executable,
+                    size: dep_s.len() as u64,
+                    offset,
+                },
+                NAREvent::Contents { .. } => NAREvent::Contents {
+                    total: dep_s.len() as u64,
+                    index: 0,
+                    buf: Bytes::from(dep_s.clone()),
+                },
+                event => event,
+            };
+            let mut buf = BytesMut::new();
+            event.encode_into(&mut buf);
+            top_nar.extend_from_slice(&buf);
+        }
+        let top = test_info("top", &top_nar, &[&dep.path]);
+        let mut src = MemoryStore::new();
+        add(&mut src, &dep, &nar).await.unwrap();
+        add(&mut src, &top, &top_nar).await.unwrap();
+
+        let to = StoreDir::new("/gnu/store").unwrap();
+        let mut remap = StoreDirRemap::new(src.store_dir(), to.clone()).unwrap();
+        let new_dep = StorePath::test_from_seed("new-dep");
+        let new_dep =
+            StorePath::new_from_base_name(&format!("{}-{}", new_dep.hash, dep.path.name)).unwrap();
+        remap.insert(dep.path.clone(), new_dep.clone()).unwrap();
+
+        let mut dst = MemoryStore::with_store_dir(to);
+        copy_paths_remapped(
+            &mut src,
+            &mut dst,
+            &mut remap,
+            &[top.path.clone()].into_iter().collect(),
+            RepairFlag::NoRepair,
+        )
+        .await
+        .unwrap();
+        let mut out = Vec::new();
+        dst.nar_from_path(&top.path, &mut out).await.unwrap();
+        let expected = format!("/gnu/store/{}", new_dep);
+        assert!(out
+            .windows(expected.len())
+            .any(|window| window == expected.as_bytes()));
+        assert!(dst.paths().contains(&new_dep));
+    }
+
+    fn test_realisation(seed: &str, out_path: &StorePath, deps: &[&Realisation]) -> Realisation {
+        let mut ctx = Context::new(crate::hash::Algorithm::SHA256);
+        ctx.update(seed.as_bytes());
+        Realisation {
+            id: DrvOutput {
+                drv_hash: ctx.finish(),
+                output_name: "out".into(),
+            },
+            out_path: out_path.clone(),
+            signatures: Default::default(),
+            dependent_realisations: deps
+                .iter()
+                .map(|dep| (dep.id.clone(), dep.out_path.clone()))
+                .collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_copy_realisations() {
+        let nar = text_file_nar();
+        let dep = test_info("dep", &nar, &[]);
+        let top = test_info("top", &nar, &[&dep.path]);
+        let mut src = MemoryStore::new();
+        add(&mut src, &dep, &nar).await.unwrap();
+        add(&mut src, &top, &nar).await.unwrap();
+        let dep_realisation = test_realisation("dep.drv", &dep.path, &[]);
+        let top_realisation = test_realisation("top.drv", &top.path, &[&dep_realisation]);
+        src.register_drv_output(&dep_realisation).await.unwrap();
+        src.register_drv_output(&top_realisation).await.unwrap();
+
+        let mut dst = MemoryStore::new();
+        let ids = [top_realisation.id.clone()].into_iter().collect();
+        let mut verified = Vec::new();
+        copy_realisations(
+            &mut src,
+            &mut dst,
+            &ids,
+            RepairFlag::NoRepair,
+            CheckSignaturesFlag::CheckSigs,
+            |realisation| {
+                verified.push(realisation.id.clone());
+                Ok(())
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(verified.len(), 2);
+        assert!(dst.paths().contains(&dep.path));
+        assert_eq!(
+            dst.query_realisation(&dep_realisation.id).await.unwrap(),
+            Some(dep_realisation)
+        );
+        assert_eq!(
+            dst.query_realisation(&top_realisation.id).await.unwrap(),
+            Some(top_realisation.clone())
+        );
+
+        let unknown = test_realisation("unknown.drv", &top.path, &[]);
+        let res = copy_realisations(
+            &mut src,
+            &mut dst,
+            &[unknown.id].into_iter().collect(),
+            RepairFlag::NoRepair,
+            CheckSignaturesFlag::NoCheckSigs,
+            |_| Ok(()),
+        )
+        .await;
+        assert_matches!(res, Err(Error::MissingRealisation(_)));
+    }
+
+    #[tokio::test]
+    async fn test_check_realisation_dependencies() {
+        let nar = text_file_nar();
+        let dep = test_info("dep", &nar, &[]);
+        let other = test_info("other", &nar, &[]);
+        let mut store = MemoryStore::new();
+        add(&mut store, &dep, &nar).await.unwrap();
+        let dep_realisation = test_realisation("dep.drv", &dep.path, &[]);
+        let top = test_realisation("top.drv", &dep.path, &[&dep_realisation]);
+        assert_matches!(
+            check_realisation_dependencies(&mut store, &top).await,
+            Err(Error::MissingRealisation(_))
+        );
+
+        store.register_drv_output(&dep_realisation).await.unwrap();
+        check_realisation_dependencies(&mut store, &top)
+            .await
+            .unwrap();
+        let forged = test_realisation("dep.drv", &other.path, &[]);
+        let top = test_realisation("top.drv", &dep.path, &[&forged]);
+        assert_matches!(
+            check_realisation_dependencies(&mut store, &top).await,
+            Err(Error::InconsistentRealisation(_, _))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verify_store() {
+        let nar = text_file_nar();
+        let dep = test_info("dep", &nar, &[]);
+        let top = test_info("top", &nar, &[&dep.path]);
+        let mut store = MemoryStore::new();
+        add(&mut store, &dep, &nar).await.unwrap();
+        add(&mut store, &top, &nar).await.unwrap();
+        assert!(!store
+            .verify_store(true, RepairFlag::NoRepair)
+            .await
+            .unwrap());
+
+        store.paths.remove(&dep.path);
+        store.paths.get_mut(&top.path).unwrap().1 = Bytes::from_static(b"garbage");
+        let act = Activity::disabled();
+        let findings = Verifier::new(store.paths())
+            .check_contents(true)
+            .present([dep.path.clone(), top.path.clone()].into_iter().collect())
+            .collect(&mut store, &act)
+            .await
+            .unwrap();
+        assert_eq!(findings.len(), 3);
+        assert_eq!(findings[0], VerifyFinding::Unreferenced(dep.path.clone()));
+        assert_eq!(
+            findings[1],
+            VerifyFinding::MissingReference {
+                path: top.path.clone(),
+                reference: dep.path.clone(),
+            }
+        );
+        assert_matches!(&findings[2], VerifyFinding::HashMismatch { path, .. } if *path == top.path);
+        assert!(findings[2].is_corrupted());
+        assert!(store
+            .verify_store(false, RepairFlag::NoRepair)
+            .await
+            .unwrap());
+    }
+}
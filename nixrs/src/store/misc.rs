@@ -1,13 +1,81 @@
 use std::collections::{btree_map::Entry, BTreeMap};
 use std::fmt;
+use std::io::{self, Cursor};
 
+use async_trait::async_trait;
+use bytes::Bytes;
 use tokio::io::{AsyncRead, AsyncReadExt};
 use tracing::{instrument, trace};
 
 use super::{CheckSignaturesFlag, Error, RepairFlag, Store};
+use crate::archive::{parse_nar_visit, NarVisitor};
 use crate::compute_closure;
+use crate::hash;
 use crate::path_info::ValidPathInfo;
-use crate::store_path::{StorePath, StorePathSet};
+use crate::store_path::{
+    ContentAddress, ContentAddressWithReferences, FileIngestionMethod, StoreDir, StorePath,
+    StorePathSet, StorePathSetExt,
+};
+
+/// The info of `paths` in the format of `nix path-info --json`.
+///
+/// Paths that aren't valid are listed with `"valid": false`. With
+/// `closure_size` each info also has the total NAR size of its closure.
+pub async fn query_path_infos_json<S>(
+    store: &mut S,
+    paths: &StorePathSet,
+    closure_size: bool,
+) -> Result<serde_json::Value, Error>
+where
+    S: Store + ?Sized,
+{
+    let store_dir = store.store_dir();
+    let mut infos: BTreeMap<StorePath, ValidPathInfo> = BTreeMap::new();
+    let mut ret = Vec::with_capacity(paths.len());
+    for path in paths {
+        let info = match store.query_path_info(path).await? {
+            Some(info) => info,
+            None => {
+                ret.push(serde_json::json!({
+                    "path": store_dir.print_path(path),
+                    "valid": false,
+                }));
+                continue;
+            }
+        };
+        let size = if closure_size {
+            let mut size = 0;
+            let mut pending = vec![info.clone()];
+            let mut seen = StorePathSet::new();
+            seen.insert(info.path.clone());
+            while let Some(info) = pending.pop() {
+                size += info.nar_size;
+                for reference in info.references.iter() {
+                    if !seen.insert(reference.clone()) {
+                        continue;
+                    }
+                    let ref_info = match infos.entry(reference.clone()) {
+                        Entry::Occupied(e) => e.get().clone(),
+                        Entry::Vacant(e) => {
+                            let ref_info =
+                                store.query_path_info(reference).await?.ok_or_else(|| {
+                                    Error::InvalidPath(store_dir.print_path(reference))
+                                })?;
+                            e.insert(ref_info).clone()
+                        }
+                    };
+                    pending.push(ref_info);
+                }
+            }
+            Some(size)
+        } else {
+            None
+        };
+        ret.push(info.to_json(&store_dir, size)?);
+        infos.insert(info.path.clone(), info);
+    }
+    Ok(serde_json::Value::Array(ret))
+}
 
 pub async fn compute_fs_closure<S>(
     store: S,
@@ -227,6 +295,98 @@ pub async fn topo_sort_paths_slow<S: Store>(
     }
 }
 
+/// The references of every valid path in `store_paths` to the other paths
+/// in it. Paths without info are left out.
+async fn reference_graph<S: Store>(
+    store: &mut S,
+    store_paths: &StorePathSet,
+) -> Result<BTreeMap<StorePath, StorePathSet>, Error> {
+    let mut graph = BTreeMap::new();
+    for store_path in store_paths.iter() {
+        if let Some(info) = store.query_path_info(store_path).await? {
+            let edges: StorePathSet = info
+                .references
+                .intersection(store_paths)
+                .filter(|path| *path != store_path)
+                .cloned()
+                .collect();
+            graph.insert(store_path.clone(), edges);
+        }
+    }
+    Ok(graph)
+}
+
+/// Finds a cycle in what is left of a graph when no path is free of
+/// references any more, so that following any reference never ends.
+fn reference_cycle(store_dir: &StoreDir, refs: &BTreeMap<StorePath, StorePathSet>) -> Error {
+    let mut walk = Vec::new();
+    let mut seen = BTreeMap::new();
+    let mut current = refs.keys().next().expect("cycle in empty graph");
+    while !seen.contains_key(current) {
+        seen.insert(current, walk.len());
+        walk.push(current);
+        current = refs[current]
+            .iter()
+            .find(|next| refs.contains_key(*next))
+            .expect("path in cycle without references");
+    }
+    let mut cycle: Vec<String> = walk[seen[current]..]
+        .iter()
+        .map(|path| store_dir.print_path(path))
+        .collect();
+    cycle.push(store_dir.print_path(current));
+    Error::ReferenceCycle(cycle)
+}
+
+/// Sort `store_paths` so that every path comes after the paths it
+/// references, like [`topo_sort_paths_slow`], but when the references form
+/// a cycle the error names the paths in it.
+///
+/// Paths that aren't valid in `store` are left out.
+pub async fn topo_sort_paths<S: Store>(
+    store: &mut S,
+    store_paths: &StorePathSet,
+) -> Result<Vec<StorePath>, Error> {
+    Ok(topo_sort_path_levels(store, store_paths)
+        .await?
+        .into_iter()
+        .flatten()
+        .collect())
+}
+
+/// Group `store_paths` by how deep they are in the reference graph. The
+/// first level has the paths that reference none of the others and every
+/// later level only references paths in the levels before it, so all the
+/// paths of a level can be copied at the same time.
+///
+/// Paths that aren't valid in `store` are left out. A cycle in the
+/// references is an [`Error::ReferenceCycle`] naming the paths in it.
+pub async fn topo_sort_path_levels<S: Store>(
+    store: &mut S,
+    store_paths: &StorePathSet,
+) -> Result<Vec<StorePathSet>, Error> {
+    let mut refs = reference_graph(store, store_paths).await?;
+    let mut levels = Vec::new();
+    while !refs.is_empty() {
+        let level: StorePathSet = refs
+            .iter()
+            .filter(|(_, edges)| edges.is_empty())
+            .map(|(path, _)| path.clone())
+            .collect();
+        if level.is_empty() {
+            return Err(reference_cycle(&store.store_dir(), &refs));
+        }
+        for path in level.iter() {
+            refs.remove(path);
+        }
+        for edges in refs.values_mut() {
+            edges.retain_missing(&level);
+        }
+        levels.push(level);
+    }
+    Ok(levels)
+}
+
 #[instrument(skip_all)]
 pub async fn add_multiple_to_store_old<S, R>(
     mut store: S,
@@ -252,6 +412,105 @@ where
     Ok(())
 }
 
+/// Hashes the contents of a NAR that must hold a single regular file.
+struct FlatHasher {
+    ctx: hash::Context,
+    regular: bool,
+    other: bool,
+}
+
+impl FlatHasher {
+    fn not_regular(&mut self) -> io::Result<()> {
+        self.other = true;
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "expected a single regular file",
+        ))
+    }
+}
+
+#[async_trait]
+impl NarVisitor for FlatHasher {
+    async fn regular(&mut self, _executable: bool, _size: u64) -> io::Result<()> {
+        if self.regular {
+            return self.not_regular();
+        }
+        self.regular = true;
+        Ok(())
+    }
+
+    async fn contents(&mut self, buf: Bytes) -> io::Result<()> {
+        self.ctx.update(&buf);
+        Ok(())
+    }
+
+    async fn symlink(&mut self, _target: Bytes) -> io::Result<()> {
+        self.not_regular()
+    }
+
+    async fn directory(&mut self) -> io::Result<()> {
+        self.not_regular()
+    }
+}
+
+/// Add `nar` as a content addressed path without references named `name`.
+///
+/// With [`FileIngestionMethod::Flat`] the NAR must hold a single regular
+/// file and the path is addressed by the hash of its contents, otherwise by
+/// the hash of the NAR. Paths that are already valid are not added again
+/// unless they are repaired.
+#[instrument(skip(store, nar))]
+pub async fn add_ca_to_store<S>(
+    store: &mut S,
+    name: &str,
+    method: FileIngestionMethod,
+    hash_algo: hash::Algorithm,
+    nar: Bytes,
+    repair: RepairFlag,
+) -> Result<StorePath, Error>
+where
+    S: Store + ?Sized,
+{
+    let hash = match method {
+        FileIngestionMethod::Recursive => hash::digest(hash_algo, &nar),
+        FileIngestionMethod::Flat => {
+            let mut hasher = FlatHasher {
+                ctx: hash::Context::new(hash_algo),
+                regular: false,
+                other: false,
+            };
+            let res = parse_nar_visit(&nar[..], &mut hasher).await;
+            if hasher.other {
+                return Err(Error::RegularFileExpected);
+            }
+            res?;
+            if !hasher.regular {
+                return Err(Error::RegularFileExpected);
+            }
+            hasher.ctx.finish()
+        }
+    };
+    let ca = ContentAddress::fixed(method, hash);
+    let store_dir = store.store_dir();
+    let path = store_dir
+        .make_fixed_output_path_from_ca(name, &ContentAddressWithReferences::without_refs(ca))?;
+    if repair == RepairFlag::NoRepair && store.query_path_info(&path).await?.is_some() {
+        return Ok(path);
+    }
+    let mut info = ValidPathInfo::new(path.clone(), hash::digest(hash::Algorithm::SHA256, &nar));
+    info.nar_size = nar.len() as u64;
+    info.ca = Some(ca);
+    store
+        .add_to_store(
+            &info,
+            Cursor::new(nar),
+            repair,
+            CheckSignaturesFlag::NoCheckSigs,
+        )
+        .await?;
+    Ok(path)
+}
+
 #[cfg(test)]
 mod tests {
     use std::{collections::BTreeMap, time::SystemTime};
@@ -496,6 +755,59 @@ mod tests {
         assert_matches!(actual, Error::CycleDetected);
     }
 
+    #[tokio::test]
+    async fn test_topo_sort_cycle_members() {
+        let a = store_path!(b"a");
+        let b = store_path!(b"b");
+        let c = store_path!(b"c");
+        let f = store_path!(b"f");
+        let references = graph! {
+            a => [b, c],
+            b => [c],
+            c => [a],  // Loops back to A through B
+            f => [],
+        };
+        let mut store = QueryStore { references };
+        let actual = topo_sort_paths(&mut store, &set_clone! {a, b, c, f})
+            .await
+            .unwrap_err();
+        let store_dir = StoreDir::default();
+        let cycle = vec![
+            store_dir.print_path(&a),
+            store_dir.print_path(&b),
+            store_dir.print_path(&c),
+            store_dir.print_path(&a),
+        ];
+        assert_matches!(actual, Error::ReferenceCycle(paths) if paths == cycle);
+    }
+
+    #[tokio::test]
+    async fn test_topo_sort_path_levels() {
+        let a = store_path!(b"a");
+        let b = store_path!(b"b");
+        let c = store_path!(b"c");
+        let d = store_path!(b"d");
+        let f = store_path!(b"f");
+        let g = store_path!(b"g");
+        let references = graph! {
+            a => [b, c, g],
+            b => [f],
+            c => [f],
+            d => [], // Not in the set
+            f => [],
+            g => [g] // Self reference
+        };
+        let mut store = QueryStore { references };
+        let paths = set_clone! {a, b, c, f, g};
+        let actual = topo_sort_path_levels(&mut store, &paths).await.unwrap();
+        assert_eq!(
+            actual,
+            vec![set_clone! {f, g}, set_clone! {b, c}, set_clone! {a}]
+        );
+        let sorted = topo_sort_paths(&mut store, &paths).await.unwrap();
+        assert_eq!(sorted, vec![f, g, b, c, a]);
+    }
+
     #[tokio::test]
     async fn test_topo_sort() {
         let a = store_path!(b"a");
@@ -656,4 +968,80 @@ mod tests {
             .unwrap();
         assert_eq!(actual, expected);
     }
+
+    fn encode(events: Vec<crate::archive::NAREvent>) -> Bytes {
+        let mut buf = bytes::BytesMut::new();
+        for event in events {
+            buf.reserve(event.encoded_size());
+            event.encode_into(&mut buf);
+        }
+        buf.freeze()
+    }
+
+    #[tokio::test]
+    async fn test_query_path_infos_json() {
+        let a = store_path!(b"a");
+        let b = store_path!(b"b");
+        let c = store_path!(b"c");
+        let references = graph! {
+            a => [a, b],
+            b => [],
+        };
+        let mut store = QueryStore { references };
+        let json = query_path_infos_json(&mut store, &set_clone![a, c], true)
+            .await
+            .unwrap();
+        let json = json.as_array().unwrap();
+        assert_eq!(json.len(), 2);
+        assert_eq!(json[0]["path"], store.store_dir().print_path(&a));
+        assert_eq!(json[0]["closureSize"], 0);
+        assert_eq!(json[0]["references"].as_array().unwrap().len(), 2);
+        assert_eq!(json[1]["path"], store.store_dir().print_path(&c));
+        assert_eq!(json[1]["valid"], false);
+    }
+
+    #[tokio::test]
+    async fn test_add_ca_to_store_flat() {
+        use crate::archive::test_data;
+        use crate::store::MemoryStore;
+
+        let mut store = MemoryStore::new();
+        let nar = encode(test_data::text_file());
+        let path = add_ca_to_store(
+            &mut store,
+            "hello.txt",
+            FileIngestionMethod::Flat,
+            hash::Algorithm::SHA256,
+            nar.clone(),
+            RepairFlag::NoRepair,
+        )
+        .await
+        .unwrap();
+        let ca = ContentAddress::fixed(
+            FileIngestionMethod::Flat,
+            hash::digest(hash::Algorithm::SHA256, "Hello world!"),
+        );
+        let expected = store
+            .store_dir()
+            .make_fixed_output_path_from_ca(
+                "hello.txt",
+                &ContentAddressWithReferences::without_refs(ca),
+            )
+            .unwrap();
+        assert_eq!(path, expected);
+        let info = store.query_path_info(&path).await.unwrap().unwrap();
+        assert_eq!(info.ca, Some(ca));
+        assert_eq!(info.nar_size, nar.len() as u64);
+
+        let res = add_ca_to_store(
+            &mut store,
+            "dir",
+            FileIngestionMethod::Flat,
+            hash::Algorithm::SHA256,
+            encode(test_data::dir_example()),
+            RepairFlag::NoRepair,
+        )
+        .await;
+        assert_matches!(res, Err(Error::RegularFileExpected));
+    }
 }
@@ -1,41 +1,91 @@
-mod error;
+pub(crate) mod error;
 pub(crate) mod extra;
 
 pub(crate) mod activity;
 #[cfg(any(feature = "test", test))]
 pub mod assert_store;
 pub mod binary_cache;
+pub mod blob_store;
+mod build_results;
 mod cached_store;
+#[cfg(test)]
+pub(crate) mod call_store;
 pub mod daemon;
 mod derivation;
+mod derivation_graph;
 mod derived_path;
+mod experimental;
 mod fail_store;
+mod filtered_store;
 pub mod legacy_worker;
+mod local_log_store;
+mod log_store;
+mod memory_store;
 mod misc;
 mod mutex_store;
+#[cfg(unix)]
+mod optimise;
 mod output_spec;
 mod path_with_outputs;
+mod policy_store;
+mod progress;
+mod query_missing;
+mod read_only_store;
 mod realisation;
+mod register;
+mod restricted_store;
+mod retry_store;
 pub mod settings;
 mod store_api;
+mod timeout_store;
+mod union_store;
+mod verify;
 
+pub use activity::{
+    Activity, ActivityBuilder, ActivityId, ActivityResult, ActivityType, LoggerField, ResultKind,
+    ResultType, StartActivity,
+};
+pub use build_results::{build_paths_with_results_emulated, BuildResults, KeyedBuildResult};
 pub use cached_store::CachedStore;
 pub use mutex_store::MutexStore;
+#[cfg(unix)]
+pub use optimise::{OptimiseStats, Optimiser, DEFAULT_MAX_LINKS};
+pub use policy_store::{PolicyStore, StorePolicy};
+pub use progress::{ActivityInfo, ActivityStats, ProgressTracker};
+pub use query_missing::{query_missing_slow, SubstitutablePathInfo, Substituter};
+pub use read_only_store::ReadOnlyStore;
+pub use retry_store::{is_transient_error, RetryPolicy, RetryStore};
+pub use timeout_store::{StoreTimeouts, TimeoutStore};
 
 pub use derivation::{
-    BasicDerivation, DerivationOutput, DerivationOutputsError, DerivationType, ParseDerivationError,
+    BasicDerivation, Derivation, DerivationOutput, DerivationOutputsError, DerivationType,
+    ParseDerivationError,
 };
 pub use derivation::{ReadDerivationError, RepairFlag, WriteDerivationError};
+pub use derivation_graph::{load_drv_closure, read_derivation, DerivationGraph, DerivationLoader};
 pub use derived_path::{DerivedPath, SingleDerivedPath};
-pub use error::Error;
+pub use error::{Error, Verbosity};
+pub use experimental::{ExperimentalFeature, ExperimentalFeatures, ParseExperimentalFeatureError};
 pub use fail_store::FailStore;
+pub use filtered_store::{FilteredStore, StorePathFilter};
+pub use local_log_store::{LocalLogStore, LogCompression, LogReader, DEFAULT_LOG_DIR};
+pub use log_store::LogStore;
+pub use memory_store::MemoryStore;
 pub use misc::{
-    add_multiple_to_store_old, compute_fs_closure, compute_fs_closure_slow, topo_sort_paths_slow,
+    add_ca_to_store, add_multiple_to_store_old, compute_fs_closure, compute_fs_closure_slow,
+    query_path_infos_json, topo_sort_path_levels, topo_sort_paths, topo_sort_paths_slow,
 };
 pub use output_spec::{OutputSpec, ParseOutputSpecError};
 pub use path_with_outputs::{SPWOParseResult, StorePathWithOutputs};
 pub use realisation::{DrvOutput, DrvOutputs, ParseDrvOutputError, Realisation};
-pub use store_api::{copy_paths, copy_paths_full, copy_store_path};
+pub use register::register_valid_path;
+pub use restricted_store::{RestrictedPaths, RestrictedStore};
+pub use store_api::{
+    check_realisation_dependencies, copy_paths, copy_paths_full, copy_paths_remapped,
+    copy_realisations, copy_store_path,
+};
 pub use store_api::{
     BuildMode, BuildResult, BuildStatus, CheckSignaturesFlag, Store, SubstituteFlag, EXPORT_MAGIC,
 };
+pub use union_store::{UnionLayer, UnionStore};
+pub use verify::{Verifier, VerifyFinding};
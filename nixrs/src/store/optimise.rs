@@ -0,0 +1,335 @@
+use std::collections::HashSet;
+use std::io;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use futures::StreamExt;
+use tokio::fs;
+use tokio::pin;
+use tracing::{debug, warn};
+
+use crate::archive::dump;
+use crate::hash::{Algorithm, Context, Hash};
+
+use super::activity::{Activity, ResultKind};
+use super::Error;
+
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Most links to one file in `.links`, below what common file systems
+/// allow so there is some room left for other tools.
+pub const DEFAULT_MAX_LINKS: u64 = 60000;
+
+/// What an [`Optimiser`] saved, or would save in a dry run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OptimiseStats {
+    pub files_linked: u64,
+    pub bytes_freed: u64,
+    /// 512 byte blocks freed, like `st_blocks`.
+    pub blocks_freed: u64,
+}
+
+/// Deduplicates identical files in a store directory on this machine by
+/// hard linking them, like `nix-store --optimise`.
+///
+/// Every regular file is hard linked into `.links` in the store directory
+/// under the hash of its NAR serialisation. A file whose contents are
+/// already there is replaced by a hard link to that copy. Every linked
+/// file is reported to the activity as a [`ResultKind::FileLinked`].
+#[derive(Debug, Clone)]
+pub struct Optimiser {
+    store_dir: PathBuf,
+    links_dir: PathBuf,
+    dry_run: bool,
+    max_links: u64,
+}
+
+impl Optimiser {
+    pub fn new<P: Into<PathBuf>>(store_dir: P) -> Optimiser {
+        let store_dir = store_dir.into();
+        let links_dir = store_dir.join(".links");
+        Optimiser {
+            store_dir,
+            links_dir,
+            dry_run: false,
+            max_links: DEFAULT_MAX_LINKS,
+        }
+    }
+
+    /// Only count what would be saved without changing anything.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Files in `.links` with this many links are not linked to again.
+    pub fn max_links(mut self, max_links: u64) -> Self {
+        self.max_links = max_links;
+        self
+    }
+
+    /// Optimises every path in the store directory.
+    pub async fn optimise_store(&self, act: &Activity) -> Result<OptimiseStats, Error> {
+        let mut paths = Vec::new();
+        let mut entries = fs::read_dir(&self.store_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if !entry.file_name().to_string_lossy().starts_with('.') {
+                paths.push(entry.path());
+            }
+        }
+        paths.sort();
+        self.optimise_paths(&paths, act).await
+    }
+
+    /// Optimises the given paths in the store directory.
+    pub async fn optimise_paths(
+        &self,
+        paths: &[PathBuf],
+        act: &Activity,
+    ) -> Result<OptimiseStats, Error> {
+        if !self.dry_run {
+            fs::create_dir_all(&self.links_dir).await?;
+        }
+        let mut state = State {
+            stats: OptimiseStats::default(),
+            inodes: self.linked_inodes().await?,
+            planned: HashSet::new(),
+        };
+        let expected = paths.len() as u64;
+        act.progress(0, expected, 0, 0);
+        for (done, path) in paths.iter().enumerate() {
+            self.optimise_tree(&mut state, path, act).await?;
+            act.progress(done as u64 + 1, expected, 0, 0);
+        }
+        Ok(state.stats)
+    }
+
+    /// Inodes of the files already in `.links`.
+    async fn linked_inodes(&self) -> Result<HashSet<u64>, Error> {
+        let mut inodes = HashSet::new();
+        let mut entries = match fs::read_dir(&self.links_dir).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(inodes),
+            Err(err) => return Err(err.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            inodes.insert(entry.metadata().await?.ino());
+        }
+        Ok(inodes)
+    }
+
+    async fn optimise_tree(
+        &self,
+        state: &mut State,
+        path: &Path,
+        act: &Activity,
+    ) -> Result<(), Error> {
+        let mut todo = vec![path.to_owned()];
+        while let Some(path) = todo.pop() {
+            let metadata = fs::symlink_metadata(&path).await?;
+            if metadata.is_dir() {
+                let mut entries = fs::read_dir(&path).await?;
+                while let Some(entry) = entries.next_entry().await? {
+                    todo.push(entry.path());
+                }
+            } else if metadata.is_file() {
+                self.optimise_file(state, &path, &metadata, act).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn optimise_file(
+        &self,
+        state: &mut State,
+        path: &Path,
+        metadata: &std::fs::Metadata,
+        act: &Activity,
+    ) -> Result<(), Error> {
+        // Files in the store are read-only. One that isn't may be in use
+        // by something that writes to the store behind our back.
+        if metadata.permissions().mode() & 0o200 != 0 {
+            warn!("skipping suspicious writable file '{}'", path.display());
+            return Ok(());
+        }
+        if metadata.nlink() > 1 && state.inodes.contains(&metadata.ino()) {
+            debug!("'{}' is already linked", path.display());
+            return Ok(());
+        }
+
+        let hash = nar_hash(path).await?;
+        let link = self.links_dir.join(hash.encode_base32());
+        match fs::symlink_metadata(&link).await {
+            Ok(link_metadata) if link_metadata.ino() == metadata.ino() => {
+                debug!(
+                    "'{}' is already linked to '{}'",
+                    path.display(),
+                    link.display()
+                );
+                return Ok(());
+            }
+            Ok(link_metadata) if link_metadata.nlink() >= self.max_links => {
+                debug!(
+                    "'{}' has too many links, not linking '{}'",
+                    link.display(),
+                    path.display()
+                );
+                return Ok(());
+            }
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                if !self.dry_run {
+                    // First time we see these contents.
+                    fs::hard_link(path, &link).await?;
+                    state.inodes.insert(metadata.ino());
+                    return Ok(());
+                }
+                // Nothing is linked in a dry run so remember what would be.
+                if state.planned.insert(hash) {
+                    return Ok(());
+                }
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        if !self.dry_run {
+            self.replace_with_link(path, &link).await?;
+        }
+        state.stats.files_linked += 1;
+        // Other links still keep the old contents alive.
+        let (bytes, blocks) = if metadata.nlink() == 1 {
+            (metadata.len(), metadata.blocks())
+        } else {
+            (0, 0)
+        };
+        state.stats.bytes_freed += bytes;
+        state.stats.blocks_freed += blocks;
+        act.report(ResultKind::FileLinked { bytes, blocks });
+        Ok(())
+    }
+
+    /// Atomically replaces `path` with a hard link to `link`.
+    async fn replace_with_link(&self, path: &Path, link: &Path) -> Result<(), Error> {
+        debug!("linking '{}' to '{}'", path.display(), link.display());
+        let tmp = self.store_dir.join(format!(
+            ".tmp-link-{}-{}",
+            std::process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::hard_link(link, &tmp).await?;
+
+        // Store directories are read-only so the parent has to be made
+        // writable for the rename.
+        let parent = path.parent().unwrap_or(&self.store_dir);
+        let parent_perms = fs::metadata(parent).await?.permissions();
+        let toggle = parent != self.store_dir && parent_perms.mode() & 0o200 == 0;
+        if toggle {
+            let mut writable = parent_perms.clone();
+            writable.set_mode(parent_perms.mode() | 0o200);
+            fs::set_permissions(parent, writable).await?;
+        }
+        let res = fs::rename(&tmp, path).await;
+        if toggle {
+            fs::set_permissions(parent, parent_perms).await?;
+        }
+        if let Err(err) = res {
+            let _ = fs::remove_file(&tmp).await;
+            return Err(err.into());
+        }
+        Ok(())
+    }
+}
+
+struct State {
+    stats: OptimiseStats,
+    inodes: HashSet<u64>,
+    planned: HashSet<Hash>,
+}
+
+async fn nar_hash(path: &Path) -> Result<Hash, Error> {
+    let events = dump(path);
+    pin!(events);
+    let mut ctx = Context::new(Algorithm::SHA256);
+    let mut buf = bytes::BytesMut::new();
+    while let Some(event) = events.next().await {
+        buf.clear();
+        event?.encode_into(&mut buf);
+        ctx.update(&buf);
+    }
+    Ok(ctx.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_only(path: &Path) {
+        let mut perms = std::fs::metadata(path).unwrap().permissions();
+        perms.set_mode(0o444);
+        std::fs::set_permissions(path, perms).unwrap();
+    }
+
+    fn setup() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["a", "b"] {
+            let out = dir.path().join(name);
+            std::fs::create_dir(&out).unwrap();
+            std::fs::write(out.join("same"), b"Hello world!\n").unwrap();
+            std::fs::write(out.join("own"), name).unwrap();
+            read_only(&out.join("same"));
+            read_only(&out.join("own"));
+        }
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_optimise_store() {
+        let dir = setup();
+        let act = Activity::disabled();
+        let optimiser = Optimiser::new(dir.path());
+
+        let stats = optimiser
+            .clone()
+            .dry_run(true)
+            .optimise_store(&act)
+            .await
+            .unwrap();
+        assert_eq!(stats.files_linked, 1);
+        assert_eq!(stats.bytes_freed, 13);
+        assert!(!dir.path().join(".links").exists());
+
+        let stats = optimiser.optimise_store(&act).await.unwrap();
+        assert_eq!(stats.files_linked, 1);
+        assert_eq!(stats.bytes_freed, 13);
+        let a = std::fs::metadata(dir.path().join("a/same")).unwrap();
+        let b = std::fs::metadata(dir.path().join("b/same")).unwrap();
+        assert_eq!(a.ino(), b.ino());
+        assert_eq!(a.nlink(), 3);
+        assert_eq!(
+            std::fs::read(dir.path().join("b/same")).unwrap(),
+            b"Hello world!\n"
+        );
+        assert_eq!(
+            std::fs::read_dir(dir.path().join(".links"))
+                .unwrap()
+                .count(),
+            3
+        );
+
+        let stats = optimiser.optimise_store(&act).await.unwrap();
+        assert_eq!(stats, OptimiseStats::default());
+    }
+
+    #[tokio::test]
+    async fn test_max_links() {
+        let dir = setup();
+        let act = Activity::disabled();
+        let stats = Optimiser::new(dir.path())
+            .max_links(1)
+            .optimise_store(&act)
+            .await
+            .unwrap();
+        assert_eq!(stats.files_linked, 0);
+    }
+}
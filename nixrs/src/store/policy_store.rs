@@ -0,0 +1,458 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::path_info::ValidPathInfo;
+use crate::store::activity::Activity;
+use crate::store::daemon::{
+    DaemonPath, DaemonStore, GCOptions, GCResults, QueryMissingResult, TrustedFlag,
+};
+use crate::store::legacy_worker::LegacyStore;
+use crate::store::{
+    BasicDerivation, BuildMode, BuildResult, CheckSignaturesFlag, DerivedPath, DrvOutput, Error,
+    ExperimentalFeatures, LogStore, Realisation, RepairFlag, Store, SubstituteFlag,
+};
+use crate::store_path::{StoreDir, StoreDirProvider, StorePath, StorePathSet};
+
+/// The operations a [`PolicyStore`] lets through. Querying paths and
+/// reading their contents is always allowed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StorePolicy {
+    /// Adding and importing paths.
+    pub add: bool,
+    /// Building derivations and paths.
+    pub build: bool,
+    /// Substituting paths, both explicitly and while querying them.
+    pub substitute: bool,
+    /// Repairing paths while adding them.
+    pub repair: bool,
+    /// Collecting garbage, adding GC roots and optimising the store.
+    pub gc: bool,
+}
+
+impl StorePolicy {
+    pub fn allow_all() -> StorePolicy {
+        StorePolicy {
+            add: true,
+            build: true,
+            substitute: true,
+            repair: true,
+            gc: true,
+        }
+    }
+
+    /// Only allow querying paths and fetching their NARs, which is all that
+    /// is needed to use the store as a substituter.
+    pub fn read_only() -> StorePolicy {
+        StorePolicy {
+            add: false,
+            build: false,
+            substitute: false,
+            repair: false,
+            gc: false,
+        }
+    }
+
+    fn check(&self, allowed: bool, op: &str) -> Result<(), Error> {
+        if allowed {
+            Ok(())
+        } else {
+            Err(Error::NotAllowed(op.into()))
+        }
+    }
+
+    fn check_repair(&self, repair: RepairFlag) -> Result<(), Error> {
+        if repair == RepairFlag::Repair && !self.repair {
+            Err(Error::RepairNotAllowed)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn substitute(&self, maybe_substitute: SubstituteFlag) -> SubstituteFlag {
+        if self.substitute {
+            maybe_substitute
+        } else {
+            SubstituteFlag::NoSubstitute
+        }
+    }
+}
+
+impl Default for StorePolicy {
+    fn default() -> Self {
+        Self::allow_all()
+    }
+}
+
+/// Wraps a store and rejects the operations its [`StorePolicy`] doesn't
+/// allow with [`Error::NotAllowed`].
+///
+/// Queries that ask for substitution are downgraded to plain queries
+/// instead of failing when substitution is not allowed.
+#[derive(Debug)]
+pub struct PolicyStore<S> {
+    store: S,
+    policy: StorePolicy,
+}
+
+impl<S> PolicyStore<S> {
+    pub fn new(store: S, policy: StorePolicy) -> PolicyStore<S> {
+        PolicyStore { store, policy }
+    }
+
+    pub fn policy(&self) -> StorePolicy {
+        self.policy
+    }
+
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+}
+
+impl<S: StoreDirProvider> StoreDirProvider for PolicyStore<S> {
+    fn store_dir(&self) -> StoreDir {
+        self.store.store_dir()
+    }
+}
+
+#[async_trait]
+impl<S> Store for PolicyStore<S>
+where
+    S: Store + Send,
+{
+    async fn query_valid_paths(
+        &mut self,
+        paths: &StorePathSet,
+        maybe_substitute: SubstituteFlag,
+    ) -> Result<StorePathSet, Error> {
+        let maybe_substitute = self.policy.substitute(maybe_substitute);
+        self.store.query_valid_paths(paths, maybe_substitute).await
+    }
+
+    async fn query_path_info(&mut self, path: &StorePath) -> Result<Option<ValidPathInfo>, Error> {
+        self.store.query_path_info(path).await
+    }
+
+    async fn nar_from_path<W: AsyncWrite + fmt::Debug + Send + Unpin>(
+        &mut self,
+        path: &StorePath,
+        sink: W,
+    ) -> Result<(), Error> {
+        self.store.nar_from_path(path, sink).await
+    }
+
+    async fn add_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        info: &ValidPathInfo,
+        source: R,
+        repair: RepairFlag,
+        check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error> {
+        self.policy.check(self.policy.add, "adding paths")?;
+        self.policy.check_repair(repair)?;
+        self.store
+            .add_to_store(info, source, repair, check_sigs)
+            .await
+    }
+
+    async fn build_derivation(
+        &mut self,
+        drv_path: &StorePath,
+        drv: &BasicDerivation,
+        build_mode: BuildMode,
+    ) -> Result<BuildResult, Error> {
+        self.policy
+            .check(self.policy.build, "building derivations")?;
+        self.store.build_derivation(drv_path, drv, build_mode).await
+    }
+
+    async fn build_paths(
+        &mut self,
+        drv_paths: &[DerivedPath],
+        build_mode: BuildMode,
+    ) -> Result<(), Error> {
+        self.policy.check(self.policy.build, "building paths")?;
+        self.store.build_paths(drv_paths, build_mode).await
+    }
+}
+
+#[async_trait]
+impl<S> DaemonStore for PolicyStore<S>
+where
+    S: DaemonStore + Send,
+{
+    fn is_trusted_client(&self) -> Option<TrustedFlag> {
+        self.store.is_trusted_client()
+    }
+
+    fn experimental_features(&self) -> ExperimentalFeatures {
+        self.store.experimental_features()
+    }
+
+    async fn set_options(&mut self) -> Result<(), Error> {
+        self.store.set_options().await
+    }
+
+    async fn is_valid_path(&mut self, path: &StorePath) -> Result<bool, Error> {
+        self.store.is_valid_path(path).await
+    }
+
+    async fn add_multiple_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        source: R,
+        repair: RepairFlag,
+        check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error> {
+        self.policy.check(self.policy.add, "adding paths")?;
+        self.policy.check_repair(repair)?;
+        self.store
+            .add_multiple_to_store(source, repair, check_sigs)
+            .await
+    }
+
+    async fn query_missing(
+        &mut self,
+        targets: &[DerivedPath],
+    ) -> Result<QueryMissingResult, Error> {
+        self.store.query_missing(targets).await
+    }
+
+    async fn add_build_log(&mut self, drv_path: &StorePath, log: &str) -> Result<(), Error> {
+        self.policy.check(self.policy.add, "adding build logs")?;
+        self.store.add_build_log(drv_path, log).await
+    }
+
+    async fn collect_garbage(&mut self, options: &GCOptions) -> Result<GCResults, Error> {
+        self.policy.check(self.policy.gc, "collecting garbage")?;
+        self.store.collect_garbage(options).await
+    }
+
+    async fn collect_garbage_streaming(
+        &mut self,
+        options: &GCOptions,
+        act: &Activity,
+    ) -> Result<GCResults, Error> {
+        self.policy.check(self.policy.gc, "collecting garbage")?;
+        self.store.collect_garbage_streaming(options, act).await
+    }
+
+    async fn query_realisation(&mut self, id: &DrvOutput) -> Result<Option<Realisation>, Error> {
+        self.store.query_realisation(id).await
+    }
+
+    async fn register_drv_output(&mut self, realisation: &Realisation) -> Result<(), Error> {
+        self.policy
+            .check(self.policy.add, "registering derivation outputs")?;
+        self.store.register_drv_output(realisation).await
+    }
+
+    async fn add_indirect_root(&mut self, path: &DaemonPath) -> Result<(), Error> {
+        self.policy.check(self.policy.gc, "adding roots")?;
+        self.store.add_indirect_root(path).await
+    }
+
+    async fn find_roots(&mut self) -> Result<BTreeMap<DaemonPath, StorePath>, Error> {
+        self.store.find_roots().await
+    }
+
+    async fn verify_store(
+        &mut self,
+        check_contents: bool,
+        repair: RepairFlag,
+    ) -> Result<bool, Error> {
+        self.policy.check_repair(repair)?;
+        self.store.verify_store(check_contents, repair).await
+    }
+
+    async fn optimise_store(&mut self) -> Result<(), Error> {
+        self.policy.check(self.policy.gc, "optimising the store")?;
+        self.store.optimise_store().await
+    }
+
+    async fn repair_path(&mut self, path: &StorePath) -> Result<(), Error> {
+        self.policy.check_repair(RepairFlag::Repair)?;
+        self.store.repair_path(path).await
+    }
+
+    async fn substitute_paths(&mut self, paths: &StorePathSet) -> Result<(), Error> {
+        self.policy
+            .check(self.policy.substitute, "substituting paths")?;
+        self.store.substitute_paths(paths).await
+    }
+}
+
+#[async_trait]
+impl<S> LegacyStore for PolicyStore<S>
+where
+    S: LegacyStore + Send,
+{
+    async fn query_valid_paths_locked(
+        &mut self,
+        paths: &StorePathSet,
+        lock: bool,
+        maybe_substitute: SubstituteFlag,
+    ) -> Result<StorePathSet, Error> {
+        let maybe_substitute = self.policy.substitute(maybe_substitute);
+        self.store
+            .query_valid_paths_locked(paths, lock, maybe_substitute)
+            .await
+    }
+
+    async fn export_paths<W: AsyncWrite + fmt::Debug + Send + Unpin>(
+        &mut self,
+        paths: &StorePathSet,
+        sink: W,
+    ) -> Result<(), Error> {
+        self.store.export_paths(paths, sink).await
+    }
+
+    async fn import_paths<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        source: R,
+    ) -> Result<(), Error> {
+        self.policy.check(self.policy.add, "importing paths")?;
+        self.store.import_paths(source).await
+    }
+
+    async fn query_closure(
+        &mut self,
+        paths: &StorePathSet,
+        include_outputs: bool,
+    ) -> Result<StorePathSet, Error> {
+        self.store.query_closure(paths, include_outputs).await
+    }
+}
+
+#[async_trait]
+impl<S> LogStore for PolicyStore<S>
+where
+    S: LogStore + Send,
+{
+    async fn get_build_log(&mut self, drv_path: &StorePath) -> Result<Option<String>, Error> {
+        self.store.get_build_log(drv_path).await
+    }
+
+    async fn add_build_log(&mut self, drv_path: &StorePath, log: &str) -> Result<(), Error> {
+        self.policy.check(self.policy.add, "adding build logs")?;
+        self.store.add_build_log(drv_path, log).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use super::*;
+    use crate::store::assert_store::AssertStore;
+    use crate::store::call_store::{call_all, CallStore, DAEMON_STORE_METHODS};
+    use crate::store::settings::BuildSettings;
+
+    #[tokio::test]
+    async fn test_read_only_rejects_build() {
+        let store = AssertStore::assert_build_paths(
+            None,
+            &[],
+            BuildMode::Normal,
+            &BuildSettings::default(),
+            Ok(()),
+        );
+        let mut store = PolicyStore::new(store, StorePolicy::read_only());
+        let res = store.build_paths(&[], BuildMode::Normal).await;
+        assert_matches!(res, Err(Error::NotAllowed(_)));
+    }
+
+    #[tokio::test]
+    async fn test_read_only_downgrades_substitution() {
+        let paths = StorePathSet::new();
+        let mut store = PolicyStore::new(
+            AssertStore::assert_query_valid_paths(
+                None,
+                &paths,
+                SubstituteFlag::NoSubstitute,
+                Ok(StorePathSet::new()),
+            ),
+            StorePolicy::read_only(),
+        );
+        let res = store
+            .query_valid_paths(&paths, SubstituteFlag::Substitute)
+            .await
+            .unwrap();
+        assert!(res.is_empty());
+        store.into_inner().assert_eq();
+    }
+
+    #[tokio::test]
+    async fn test_allow_all_forwards_daemon_store() {
+        let mut store = PolicyStore::new(CallStore::default(), StorePolicy::allow_all());
+        for (method, res) in call_all(&mut store).await {
+            assert!(res.is_ok(), "{method}: {res:?}");
+        }
+        assert!(store.into_inner().called_all(DAEMON_STORE_METHODS));
+    }
+
+    #[tokio::test]
+    async fn test_read_only_rejects_daemon_store_mutations() {
+        let mut store = PolicyStore::new(CallStore::default(), StorePolicy::read_only());
+        for (method, res) in call_all(&mut store).await {
+            match method {
+                "add_multiple_to_store"
+                | "add_build_log"
+                | "register_drv_output"
+                | "collect_garbage"
+                | "collect_garbage_streaming"
+                | "add_indirect_root"
+                | "optimise_store"
+                | "substitute_paths" => assert_matches!(res, Err(Error::NotAllowed(_))),
+                "verify_store" | "repair_path" => {
+                    assert_matches!(res, Err(Error::RepairNotAllowed))
+                }
+                _ => assert!(res.is_ok(), "{method}: {res:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gc() {
+        let options = GCOptions::default();
+        let act = Activity::disabled();
+        let root = DaemonPath::new(b"/nix/var/nix/gcroots/auto/root".to_vec()).unwrap();
+        let policy = StorePolicy {
+            gc: false,
+            ..StorePolicy::allow_all()
+        };
+        let mut store = PolicyStore::new(CallStore::default(), policy);
+        let res = store.collect_garbage(&options).await;
+        assert_matches!(res, Err(Error::NotAllowed(_)));
+        let res = store.collect_garbage_streaming(&options, &act).await;
+        assert_matches!(res, Err(Error::NotAllowed(_)));
+        let res = store.add_indirect_root(&root).await;
+        assert_matches!(res, Err(Error::NotAllowed(_)));
+        let res = store.optimise_store().await;
+        assert_matches!(res, Err(Error::NotAllowed(_)));
+        assert!(store.into_inner().calls.is_empty());
+
+        let policy = StorePolicy {
+            gc: true,
+            ..StorePolicy::read_only()
+        };
+        let mut store = PolicyStore::new(CallStore::default(), policy);
+        store.collect_garbage(&options).await.unwrap();
+        store
+            .collect_garbage_streaming(&options, &act)
+            .await
+            .unwrap();
+        store.add_indirect_root(&root).await.unwrap();
+        store.optimise_store().await.unwrap();
+        assert_eq!(
+            store.into_inner().calls,
+            [
+                "collect_garbage",
+                "collect_garbage_streaming",
+                "add_indirect_root",
+                "optimise_store"
+            ]
+        );
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::BTreeMap;
+
+use super::activity::{ActivityResult, StartActivity};
+use super::{ActivityId, ActivityType, Error, LoggerField, ResultKind};
+
+/// Progress of a set of activities.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ActivityStats {
+    pub done: u64,
+    pub expected: u64,
+    pub running: u64,
+    pub failed: u64,
+}
+
+impl ActivityStats {
+    fn add(&mut self, other: &ActivityStats) {
+        self.done += other.done;
+        self.expected += other.expected;
+        self.running += other.running;
+        self.failed += other.failed;
+    }
+}
+
+/// State of a running activity.
+#[derive(Debug, Clone)]
+pub struct ActivityInfo {
+    pub activity_type: ActivityType,
+    pub text: String,
+    pub fields: Vec<LoggerField>,
+    pub parent: ActivityId,
+    /// The phase last set with [`ResultKind::SetPhase`].
+    pub phase: Option<String>,
+    /// The last build log line of the activity.
+    pub last_line: Option<String>,
+    pub progress: ActivityStats,
+    expected_by_type: BTreeMap<ActivityType, u64>,
+}
+
+#[derive(Debug, Default)]
+struct ActivitiesByType {
+    running: Vec<ActivityId>,
+    done: u64,
+    failed: u64,
+    expected: u64,
+}
+
+/// Aggregates activity messages into progress per activity type.
+///
+/// This follows the bookkeeping of the progress bar in Nix: progress of
+/// stopped activities is folded into the totals of their type, and the
+/// expected count of a type is the largest of what its running activities
+/// report and what their parents announced with
+/// [`ResultKind::SetExpected`].
+#[derive(Debug, Default)]
+pub struct ProgressTracker {
+    activities: BTreeMap<ActivityId, ActivityInfo>,
+    by_type: BTreeMap<ActivityType, ActivitiesByType>,
+    pub files_linked: u64,
+    pub bytes_linked: u64,
+    pub untrusted_paths: u64,
+    pub corrupted_paths: u64,
+}
+
+impl ProgressTracker {
+    pub fn new() -> ProgressTracker {
+        Default::default()
+    }
+
+    pub fn start_activity(&mut self, activity: &StartActivity) {
+        self.by_type
+            .entry(activity.activity_type)
+            .or_default()
+            .running
+            .push(activity.act);
+        self.activities.insert(
+            activity.act,
+            ActivityInfo {
+                activity_type: activity.activity_type,
+                text: activity.text.clone(),
+                fields: activity.fields.clone(),
+                parent: activity.parent,
+                phase: None,
+                last_line: None,
+                progress: ActivityStats::default(),
+                expected_by_type: BTreeMap::new(),
+            },
+        );
+    }
+
+    pub fn stop_activity(&mut self, act: ActivityId) {
+        if let Some(info) = self.activities.remove(&act) {
+            let by_type = self.by_type.entry(info.activity_type).or_default();
+            by_type.done += info.progress.done;
+            by_type.failed += info.progress.failed;
+            by_type.running.retain(|running| *running != act);
+            for (activity_type, expected) in info.expected_by_type {
+                let by_type = self.by_type.entry(activity_type).or_default();
+                by_type.expected = by_type.expected.saturating_sub(expected);
+            }
+        }
+    }
+
+    /// Apply a result. Results for unknown activities are ignored but
+    /// results with invalid fields are reported as errors.
+    pub fn result(&mut self, result: &ActivityResult) -> Result<(), Error> {
+        let kind = result.kind()?;
+        match kind {
+            ResultKind::FileLinked { bytes, .. } => {
+                self.files_linked += 1;
+                self.bytes_linked += bytes;
+            }
+            ResultKind::UntrustedPath(_) => self.untrusted_paths += 1,
+            ResultKind::CorruptedPath(_) => self.corrupted_paths += 1,
+            _ => {}
+        }
+        let info = match self.activities.get_mut(&result.act) {
+            Some(info) => info,
+            None => return Ok(()),
+        };
+        match kind {
+            ResultKind::BuildLogLine(line) | ResultKind::PostBuildLogLine(line) => {
+                info.last_line = Some(line);
+            }
+            ResultKind::SetPhase(phase) => info.phase = Some(phase),
+            ResultKind::Progress {
+                done,
+                expected,
+                running,
+                failed,
+            } => {
+                info.progress = ActivityStats {
+                    done,
+                    expected,
+                    running,
+                    failed,
+                };
+            }
+            ResultKind::SetExpected {
+                activity_type,
+                expected,
+            } => {
+                let previous = info
+                    .expected_by_type
+                    .insert(activity_type, expected)
+                    .unwrap_or(0);
+                let by_type = self.by_type.entry(activity_type).or_default();
+                by_type.expected = by_type.expected.saturating_sub(previous) + expected;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub fn activity(&self, act: ActivityId) -> Option<&ActivityInfo> {
+        self.activities.get(&act)
+    }
+
+    /// Running activities of `activity_type` in the order they were started.
+    pub fn running(&self, activity_type: ActivityType) -> impl Iterator<Item = &ActivityInfo> {
+        self.by_type
+            .get(&activity_type)
+            .into_iter()
+            .flat_map(|by_type| by_type.running.iter())
+            .filter_map(|act| self.activities.get(act))
+    }
+
+    /// Total progress of all activities of `activity_type`, both running
+    /// and stopped.
+    pub fn stats(&self, activity_type: ActivityType) -> ActivityStats {
+        let by_type = match self.by_type.get(&activity_type) {
+            Some(by_type) => by_type,
+            None => return ActivityStats::default(),
+        };
+        let mut stats = ActivityStats {
+            done: by_type.done,
+            expected: by_type.done,
+            running: 0,
+            failed: by_type.failed,
+        };
+        for info in self.running(activity_type) {
+            stats.add(&info.progress);
+        }
+        stats.expected = stats.expected.max(by_type.expected);
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{ResultType, Verbosity};
+
+    fn start(tracker: &mut ProgressTracker, act: ActivityId, activity_type: ActivityType) {
+        tracker.start_activity(&StartActivity {
+            act,
+            level: Verbosity::Info,
+            activity_type,
+            text: String::new(),
+            fields: Vec::new(),
+            parent: 0,
+        });
+    }
+
+    fn result(tracker: &mut ProgressTracker, act: ActivityId, kind: ResultKind) {
+        let result = ActivityResult {
+            act,
+            result_type: kind.result_type(),
+            fields: kind.into_fields(),
+        };
+        tracker.result(&result).unwrap();
+    }
+
+    #[test]
+    fn test_expected_from_parent() {
+        let mut tracker = ProgressTracker::new();
+        start(&mut tracker, 1, ActivityType::Builds);
+        result(
+            &mut tracker,
+            1,
+            ResultKind::SetExpected {
+                activity_type: ActivityType::Build,
+                expected: 3,
+            },
+        );
+        start(&mut tracker, 2, ActivityType::Build);
+        result(&mut tracker, 2, ResultKind::SetPhase("buildPhase".into()));
+        assert_eq!(
+            tracker.running(ActivityType::Build).next().unwrap().phase,
+            Some("buildPhase".into())
+        );
+        assert_eq!(tracker.stats(ActivityType::Build).expected, 3);
+
+        tracker.stop_activity(1);
+        assert_eq!(tracker.stats(ActivityType::Build).expected, 0);
+    }
+
+    #[test]
+    fn test_done_survives_stop() {
+        let mut tracker = ProgressTracker::new();
+        start(&mut tracker, 1, ActivityType::CopyPath);
+        result(
+            &mut tracker,
+            1,
+            ResultKind::Progress {
+                done: 10,
+                expected: 10,
+                running: 0,
+                failed: 0,
+            },
+        );
+        tracker.stop_activity(1);
+        let stats = tracker.stats(ActivityType::CopyPath);
+        assert_eq!(stats.done, 10);
+        assert_eq!(stats.expected, 10);
+        assert!(tracker.activity(1).is_none());
+
+        let res = tracker.result(&ActivityResult {
+            act: 1,
+            result_type: ResultType::Progress,
+            fields: Vec::new(),
+        });
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::BTreeSet;
+
+use async_trait::async_trait;
+use tracing::{instrument, trace};
+
+use super::daemon::QueryMissingResult;
+use super::memory_store::base_drv_path;
+use super::{read_derivation, DerivedPath, Error, OutputSpec, SingleDerivedPath, Store};
+use crate::store_path::{StorePath, StorePathSet};
+
+/// What a substituter knows about a path it can provide.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubstitutablePathInfo {
+    pub deriver: Option<StorePath>,
+    pub references: StorePathSet,
+    /// Size of the compressed download, or the NAR size when unknown.
+    pub download_size: u64,
+    pub nar_size: u64,
+}
+
+/// Where [`query_missing_slow`] looks for paths that can be substituted.
+#[async_trait]
+pub trait Substituter {
+    async fn query_substitutable_path_info(
+        &mut self,
+        path: &StorePath,
+    ) -> Result<Option<SubstitutablePathInfo>, Error>;
+}
+
+/// Any store can be substituted from. Stores don't know how big a
+/// compressed download would be, so the NAR size is used instead.
+#[async_trait]
+impl<S> Substituter for S
+where
+    S: Store + Send,
+{
+    async fn query_substitutable_path_info(
+        &mut self,
+        path: &StorePath,
+    ) -> Result<Option<SubstitutablePathInfo>, Error> {
+        Ok(self
+            .query_path_info(path)
+            .await?
+            .map(|info| SubstitutablePathInfo {
+                deriver: info.deriver,
+                references: info.references,
+                download_size: info.nar_size,
+                nar_size: info.nar_size,
+            }))
+    }
+}
+
+async fn query_substitute<U>(
+    substituters: &mut [U],
+    path: &StorePath,
+) -> Result<Option<SubstitutablePathInfo>, Error>
+where
+    U: Substituter + Send,
+{
+    for sub in substituters.iter_mut() {
+        if let Some(info) = sub.query_substitutable_path_info(path).await? {
+            return Ok(Some(info));
+        }
+    }
+    Ok(None)
+}
+
+/// Works out what building `targets` in `store` would take, the way Nix
+/// answers `QueryMissing`, for stores that can't answer it themselves.
+///
+/// Invalid paths are substituted from the first of `substituters` that has
+/// them, along with their missing references. Derivations are read from
+/// `store`, and when not all wanted outputs are valid or substitutable the
+/// derivation is built and its inputs are looked at in turn. Paths that
+/// are neither valid nor substitutable, including derivations, end up in
+/// `unknown`. Pass no substituters when substitution is disabled.
+///
+/// Outputs of [`SingleDerivedPath::Built`] derivations can't be known
+/// without building, so only the derivation they come from is looked at.
+#[instrument(skip_all, fields(targets = targets.len()))]
+pub async fn query_missing_slow<S, U>(
+    store: &mut S,
+    substituters: &mut [U],
+    targets: &[DerivedPath],
+) -> Result<QueryMissingResult, Error>
+where
+    S: Store + Send,
+    U: Substituter + Send,
+{
+    let store_dir = store.store_dir();
+    let mut res = QueryMissingResult {
+        will_build: StorePathSet::new(),
+        will_substitute: StorePathSet::new(),
+        unknown: StorePathSet::new(),
+        download_size: 0,
+        nar_size: 0,
+    };
+    let mut done = BTreeSet::new();
+    let mut pending: Vec<DerivedPath> = targets.iter().rev().cloned().collect();
+    while let Some(target) = pending.pop() {
+        if !done.insert(target.clone()) {
+            continue;
+        }
+        match target {
+            DerivedPath::Opaque(path) => {
+                if store.query_path_info(&path).await?.is_some() {
+                    continue;
+                }
+                match query_substitute(substituters, &path).await? {
+                    Some(info) => {
+                        trace!("{} will be substituted", path);
+                        res.download_size += info.download_size;
+                        res.nar_size += info.nar_size;
+                        for reference in info.references {
+                            if reference != path {
+                                pending.push(DerivedPath::Opaque(reference));
+                            }
+                        }
+                        res.will_substitute.insert(path);
+                    }
+                    None => {
+                        res.unknown.insert(path);
+                    }
+                }
+            }
+            DerivedPath::Built { drv_path, outputs } => {
+                let drv_path = base_drv_path(&drv_path).clone();
+                if store.query_path_info(&drv_path).await?.is_none() {
+                    res.unknown.insert(drv_path);
+                    continue;
+                }
+                let drv = read_derivation(store, &drv_path).await?;
+                let mut unknown_outputs = false;
+                let mut invalid = Vec::new();
+                for (name, (_, path)) in drv.basic.outputs_and_opt_paths(&store_dir)? {
+                    if let OutputSpec::Names(names) = &outputs {
+                        if !names.contains(&name) {
+                            continue;
+                        }
+                    }
+                    match path {
+                        Some(path) => {
+                            if store.query_path_info(&path).await?.is_none() {
+                                invalid.push(path);
+                            }
+                        }
+                        None => unknown_outputs = true,
+                    }
+                }
+                if invalid.is_empty() && !unknown_outputs {
+                    continue;
+                }
+
+                if !unknown_outputs && !substituters.is_empty() {
+                    let mut infos = Vec::with_capacity(invalid.len());
+                    for path in invalid.iter() {
+                        match query_substitute(substituters, path).await? {
+                            Some(info) => infos.push(info),
+                            None => break,
+                        }
+                    }
+                    if infos.len() == invalid.len() {
+                        for (path, info) in invalid.into_iter().zip(infos) {
+                            if done.insert(DerivedPath::Opaque(path.clone())) {
+                                res.download_size += info.download_size;
+                                res.nar_size += info.nar_size;
+                                for reference in info.references {
+                                    if reference != path {
+                                        pending.push(DerivedPath::Opaque(reference));
+                                    }
+                                }
+                                res.will_substitute.insert(path);
+                            }
+                        }
+                        continue;
+                    }
+                }
+
+                trace!("{} will be built", drv_path);
+                for (input, names) in drv.input_drvs.iter() {
+                    let outputs = match OutputSpec::try_from(names.clone()) {
+                        Ok(outputs) => outputs,
+                        Err(_) => continue,
+                    };
+                    pending.push(DerivedPath::Built {
+                        drv_path: SingleDerivedPath::Opaque(input.clone()),
+                        outputs,
+                    });
+                }
+                for src in drv.basic.input_srcs.iter() {
+                    pending.push(DerivedPath::Opaque(src.clone()));
+                }
+                res.will_build.insert(drv_path);
+            }
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bytes::{Bytes, BytesMut};
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::archive::{NAREvent, NAR_VERSION_MAGIC_1};
+    use crate::hash;
+    use crate::path_info::ValidPathInfo;
+    use crate::store::{CheckSignaturesFlag, MemoryStore, RepairFlag};
+    use crate::store_path::{StoreDir, StoreDirProvider};
+
+    async fn add_file(store: &mut MemoryStore, path: &StorePath, contents: &str) -> u64 {
+        let size = contents.len() as u64;
+        let mut nar = BytesMut::new();
+        NAREvent::Magic(Arc::new(NAR_VERSION_MAGIC_1.into())).encode_into(&mut nar);
+        NAREvent::RegularNode {
+            executable: false,
+            size,
+            offset: 0,
+        }
+        .encode_into(&mut nar);
+        NAREvent::Contents {
+            total: size,
+            index: 0,
+            buf: Bytes::copy_from_slice(contents.as_bytes()),
+        }
+        .encode_into(&mut nar);
+        let mut info =
+            ValidPathInfo::new(path.clone(), hash::digest(hash::Algorithm::SHA256, &nar));
+        info.nar_size = nar.len() as u64;
+        store
+            .add_to_store(
+                &info,
+                &nar[..],
+                RepairFlag::NoRepair,
+                CheckSignaturesFlag::NoCheckSigs,
+            )
+            .await
+            .unwrap();
+        info.nar_size
+    }
+
+    fn drv(store_dir: &StoreDir, name: &str, out: &StorePath, inputs: &[&StorePath]) -> String {
+        let inputs = inputs
+            .iter()
+            .map(|i| format!("(\"{}\",[\"out\"])", store_dir.print_path(i)))
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            "Derive([(\"out\",\"{}\",\"\",\"\")],[{}],[],\"x86_64-linux\",\"/bin/sh\",[],[(\"name\",\"{}\")])",
+            store_dir.print_path(out),
+            inputs,
+            name
+        )
+    }
+
+    fn built(drv_path: &StorePath) -> DerivedPath {
+        DerivedPath::Built {
+            drv_path: SingleDerivedPath::Opaque(drv_path.clone()),
+            outputs: OutputSpec::All,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_missing_slow() {
+        let mut store = MemoryStore::new();
+        let store_dir = store.store_dir();
+        let a_drv = StorePath::test_from_seed("a.drv");
+        let b_drv = StorePath::test_from_seed("b.drv");
+        let c_drv = StorePath::test_from_seed("c.drv");
+        let a = StorePath::test_from_seed("a");
+        let b = StorePath::test_from_seed("b");
+        let c = StorePath::test_from_seed("c");
+        let missing = StorePath::test_from_seed("missing");
+        add_file(
+            &mut store,
+            &a_drv,
+            &drv(&store_dir, "a", &a, &[&b_drv, &c_drv]),
+        )
+        .await;
+        add_file(&mut store, &b_drv, &drv(&store_dir, "b", &b, &[])).await;
+        add_file(&mut store, &c_drv, &drv(&store_dir, "c", &c, &[])).await;
+        add_file(&mut store, &c, "c").await;
+
+        let mut cache = MemoryStore::new();
+        let b_size = add_file(&mut cache, &b, "b").await;
+
+        let res = query_missing_slow(
+            &mut store,
+            &mut [cache],
+            &[built(&a_drv), DerivedPath::Opaque(missing.clone())],
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.will_build, [a_drv.clone()].into_iter().collect());
+        assert_eq!(res.will_substitute, [b.clone()].into_iter().collect());
+        assert_eq!(res.unknown, [missing].into_iter().collect());
+        assert_eq!(res.download_size, b_size);
+        assert_eq!(res.nar_size, b_size);
+
+        let res = query_missing_slow::<_, MemoryStore>(&mut store, &mut [], &[built(&a_drv)])
+            .await
+            .unwrap();
+        assert_eq!(res.will_build, [a_drv, b_drv].into_iter().collect());
+        assert!(res.will_substitute.is_empty());
+        assert!(res.unknown.is_empty());
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::path_info::ValidPathInfo;
+use crate::store::activity::Activity;
+use crate::store::daemon::{
+    DaemonPath, DaemonStore, GCAction, GCOptions, GCResults, QueryMissingResult, TrustedFlag,
+};
+use crate::store::legacy_worker::LegacyStore;
+use crate::store::{
+    BasicDerivation, BuildMode, BuildResult, CheckSignaturesFlag, DerivedPath, DrvOutput, Error,
+    ExperimentalFeatures, Realisation, RepairFlag, Store, SubstituteFlag,
+};
+use crate::store_path::{StoreDir, StoreDirProvider, StorePath, StorePathSet};
+
+/// Serves queries and NARs from a store but refuses to change it.
+///
+/// Every mutating operation fails with [`Error::ReadOnlyStore`] whatever
+/// the wrapped store supports, and queries never substitute. Use this in
+/// front of stores that serve a cache and must stay immutable.
+#[derive(Debug)]
+pub struct ReadOnlyStore<S> {
+    store: S,
+}
+
+impl<S> ReadOnlyStore<S> {
+    pub fn new(store: S) -> ReadOnlyStore<S> {
+        ReadOnlyStore { store }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+}
+
+impl<S: StoreDirProvider> StoreDirProvider for ReadOnlyStore<S> {
+    fn store_dir(&self) -> StoreDir {
+        self.store.store_dir()
+    }
+}
+
+#[async_trait]
+impl<S> Store for ReadOnlyStore<S>
+where
+    S: Store + Send,
+{
+    async fn query_valid_paths(
+        &mut self,
+        paths: &StorePathSet,
+        _maybe_substitute: SubstituteFlag,
+    ) -> Result<StorePathSet, Error> {
+        self.store
+            .query_valid_paths(paths, SubstituteFlag::NoSubstitute)
+            .await
+    }
+
+    async fn query_path_info(&mut self, path: &StorePath) -> Result<Option<ValidPathInfo>, Error> {
+        self.store.query_path_info(path).await
+    }
+
+    async fn nar_from_path<W: AsyncWrite + fmt::Debug + Send + Unpin>(
+        &mut self,
+        path: &StorePath,
+        sink: W,
+    ) -> Result<(), Error> {
+        self.store.nar_from_path(path, sink).await
+    }
+
+    async fn add_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        _info: &ValidPathInfo,
+        _source: R,
+        _repair: RepairFlag,
+        _check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error> {
+        Err(Error::ReadOnlyStore("add paths".into()))
+    }
+
+    async fn build_derivation(
+        &mut self,
+        _drv_path: &StorePath,
+        _drv: &BasicDerivation,
+        _build_mode: BuildMode,
+    ) -> Result<BuildResult, Error> {
+        Err(Error::ReadOnlyStore("build derivations".into()))
+    }
+
+    async fn build_paths(
+        &mut self,
+        _drv_paths: &[DerivedPath],
+        _build_mode: BuildMode,
+    ) -> Result<(), Error> {
+        Err(Error::ReadOnlyStore("build paths".into()))
+    }
+}
+
+#[async_trait]
+impl<S> DaemonStore for ReadOnlyStore<S>
+where
+    S: DaemonStore + Send,
+{
+    fn is_trusted_client(&self) -> Option<TrustedFlag> {
+        self.store.is_trusted_client()
+    }
+
+    fn experimental_features(&self) -> ExperimentalFeatures {
+        self.store.experimental_features()
+    }
+
+    async fn set_options(&mut self) -> Result<(), Error> {
+        self.store.set_options().await
+    }
+
+    async fn is_valid_path(&mut self, path: &StorePath) -> Result<bool, Error> {
+        self.store.is_valid_path(path).await
+    }
+
+    async fn add_multiple_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        _source: R,
+        _repair: RepairFlag,
+        _check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error> {
+        Err(Error::ReadOnlyStore("add paths".into()))
+    }
+
+    async fn query_missing(
+        &mut self,
+        targets: &[DerivedPath],
+    ) -> Result<QueryMissingResult, Error> {
+        self.store.query_missing(targets).await
+    }
+
+    async fn add_build_log(&mut self, _drv_path: &StorePath, _log: &str) -> Result<(), Error> {
+        Err(Error::ReadOnlyStore("add build logs".into()))
+    }
+
+    /// Only the `Return` actions are passed on.
+    async fn collect_garbage(&mut self, options: &GCOptions) -> Result<GCResults, Error> {
+        check_gc_action(options)?;
+        self.store.collect_garbage(options).await
+    }
+
+    async fn collect_garbage_streaming(
+        &mut self,
+        options: &GCOptions,
+        act: &Activity,
+    ) -> Result<GCResults, Error> {
+        check_gc_action(options)?;
+        self.store.collect_garbage_streaming(options, act).await
+    }
+
+    async fn query_realisation(&mut self, id: &DrvOutput) -> Result<Option<Realisation>, Error> {
+        self.store.query_realisation(id).await
+    }
+
+    async fn register_drv_output(&mut self, _realisation: &Realisation) -> Result<(), Error> {
+        Err(Error::ReadOnlyStore("register derivation outputs".into()))
+    }
+
+    async fn add_indirect_root(&mut self, _path: &DaemonPath) -> Result<(), Error> {
+        Err(Error::ReadOnlyStore("add roots".into()))
+    }
+
+    async fn find_roots(&mut self) -> Result<BTreeMap<DaemonPath, StorePath>, Error> {
+        self.store.find_roots().await
+    }
+
+    async fn verify_store(
+        &mut self,
+        check_contents: bool,
+        repair: RepairFlag,
+    ) -> Result<bool, Error> {
+        if repair == RepairFlag::Repair {
+            return Err(Error::ReadOnlyStore("repair paths".into()));
+        }
+        self.store.verify_store(check_contents, repair).await
+    }
+
+    async fn optimise_store(&mut self) -> Result<(), Error> {
+        Err(Error::ReadOnlyStore("optimise the store".into()))
+    }
+
+    async fn repair_path(&mut self, _path: &StorePath) -> Result<(), Error> {
+        Err(Error::ReadOnlyStore("repair paths".into()))
+    }
+
+    async fn substitute_paths(&mut self, _paths: &StorePathSet) -> Result<(), Error> {
+        Err(Error::ReadOnlyStore("substitute paths".into()))
+    }
+}
+
+fn check_gc_action(options: &GCOptions) -> Result<(), Error> {
+    match options.action {
+        GCAction::ReturnLive | GCAction::ReturnDead => Ok(()),
+        GCAction::DeleteDead | GCAction::DeleteSpecific => {
+            Err(Error::ReadOnlyStore("delete paths".into()))
+        }
+    }
+}
+
+#[async_trait]
+impl<S> LegacyStore for ReadOnlyStore<S>
+where
+    S: LegacyStore + Send,
+{
+    async fn query_valid_paths_locked(
+        &mut self,
+        paths: &StorePathSet,
+        lock: bool,
+        _maybe_substitute: SubstituteFlag,
+    ) -> Result<StorePathSet, Error> {
+        self.store
+            .query_valid_paths_locked(paths, lock, SubstituteFlag::NoSubstitute)
+            .await
+    }
+
+    async fn export_paths<W: AsyncWrite + fmt::Debug + Send + Unpin>(
+        &mut self,
+        paths: &StorePathSet,
+        sink: W,
+    ) -> Result<(), Error> {
+        self.store.export_paths(paths, sink).await
+    }
+
+    async fn import_paths<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        _source: R,
+    ) -> Result<(), Error> {
+        Err(Error::ReadOnlyStore("import paths".into()))
+    }
+
+    async fn query_closure(
+        &mut self,
+        paths: &StorePathSet,
+        include_outputs: bool,
+    ) -> Result<StorePathSet, Error> {
+        self.store.query_closure(paths, include_outputs).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::store::assert_store::AssertStore;
+    use crate::store::call_store::{call_all, CallStore};
+    use crate::store::FailStore;
+
+    #[tokio::test]
+    async fn test_rejects_mutations() {
+        let mut store = ReadOnlyStore::new(FailStore);
+        let res = store.import_paths(&b""[..]).await;
+        assert_matches!(res, Err(Error::ReadOnlyStore(_)));
+        let res = store.build_paths(&[], BuildMode::Normal).await;
+        assert_matches!(res, Err(Error::ReadOnlyStore(_)));
+    }
+
+    #[tokio::test]
+    async fn test_forwards_nar_from_path() {
+        let path = StorePath::test_from_seed("nar");
+        let mut store = ReadOnlyStore::new(AssertStore::assert_nar_from_path(
+            None,
+            &path,
+            Ok(Bytes::from_static(b"nar")),
+        ));
+        let mut out = Vec::new();
+        store.nar_from_path(&path, &mut out).await.unwrap();
+        assert_eq!(out, b"nar");
+        store.into_inner().assert_eq();
+    }
+
+    #[tokio::test]
+    async fn test_daemon_store_mutations() {
+        let mut store = ReadOnlyStore::new(CallStore::default());
+        for (method, res) in call_all(&mut store).await {
+            match method {
+                "set_options" | "is_valid_path" | "query_missing" | "query_realisation"
+                | "find_roots" => assert!(res.is_ok(), "{method}: {res:?}"),
+                _ => assert_matches!(res, Err(Error::ReadOnlyStore(_)), "{method}"),
+            }
+        }
+        let calls = store.into_inner().calls;
+        assert_eq!(
+            calls,
+            [
+                "set_options",
+                "is_valid_path",
+                "query_missing",
+                "query_realisation",
+                "find_roots"
+            ]
+        );
+
+        let mut store = ReadOnlyStore::new(CallStore::default());
+        let options = GCOptions {
+            action: GCAction::ReturnDead,
+            ..Default::default()
+        };
+        store.collect_garbage(&options).await.unwrap();
+        store
+            .verify_store(true, RepairFlag::NoRepair)
+            .await
+            .unwrap();
+        assert_eq!(
+            store.into_inner().calls,
+            ["collect_garbage", "verify_store"]
+        );
+    }
+}
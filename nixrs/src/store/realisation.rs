@@ -7,6 +7,8 @@ use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 use crate::hash;
+use crate::signature::{PublicKey, SecretKey, Signature};
+use crate::store::Error;
 use crate::store_path::StorePath;
 use crate::StringSet;
 
@@ -98,6 +100,50 @@ impl Realisation {
         let value = self.to_json()?;
         serde_json::to_string(&value)
     }
+
+    /// What the signatures of the realisation sign: its JSON without the
+    /// signatures, with the keys sorted like Nix does.
+    pub fn fingerprint(&self) -> serde_json::Result<String> {
+        let mut value = self.to_json()?;
+        if let Some(object) = value.as_object_mut() {
+            object.remove("signatures");
+        }
+        serde_json::to_string(&value)
+    }
+
+    pub fn sign(&mut self, key: &SecretKey) -> serde_json::Result<()> {
+        let signature = key.sign(self.fingerprint()?);
+        self.signatures.insert(signature.to_string());
+        Ok(())
+    }
+
+    /// The number of signatures that are valid signatures by one of `keys`.
+    /// Signatures that can't be parsed don't count.
+    pub fn check_signatures(&self, keys: &[PublicKey]) -> usize {
+        let fingerprint = match self.fingerprint() {
+            Ok(fingerprint) => fingerprint,
+            Err(_) => return 0,
+        };
+        self.signatures
+            .iter()
+            .filter_map(|sig| sig.parse::<Signature>().ok())
+            .filter(|sig| {
+                keys.iter()
+                    .any(|key| key.name() == sig.name() && key.verify(&fingerprint, sig))
+            })
+            .count()
+    }
+
+    /// Fail with [`Error::UntrustedRealisation`] unless the realisation is
+    /// signed by one of `keys`. Fits the `verify` argument of
+    /// [`copy_realisations`](crate::store::copy_realisations).
+    pub fn verify_signatures(&self, keys: &[PublicKey]) -> Result<(), Error> {
+        if self.check_signatures(keys) > 0 {
+            Ok(())
+        } else {
+            Err(Error::UntrustedRealisation(self.id.to_string()))
+        }
+    }
 }
 
 impl FromStr for Realisation {
@@ -179,6 +225,7 @@ pub mod proptest {
 #[cfg(test)]
 mod tests {
     use crate::string_set;
+    use ring::rand::SystemRandom;
 
     use super::*;
     use pretty_assertions::assert_eq;
@@ -293,5 +340,42 @@ mod tests {
         let r = s.parse::<Realisation>().unwrap();
         assert_eq!(r, r2);
         assert_eq!(s, r2.to_json_string().unwrap());
+        assert_eq!(
+            r2.fingerprint().unwrap(),
+            s.replace(",\"signatures\":[\"hello\",\"test1234\"]", "")
+        );
+    }
+
+    #[test]
+    fn test_realisation_signatures() {
+        let rng = SystemRandom::new();
+        let key = SecretKey::generate("cache.example.org-1".into(), &rng).unwrap();
+        let other = SecretKey::generate("cache.example.org-2".into(), &rng).unwrap();
+        let mut r = Realisation {
+            id: "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad!out"
+                .parse()
+                .unwrap(),
+            out_path: StorePath::new_from_base_name(
+                "7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-konsole-18.12.3",
+            )
+            .unwrap(),
+            signatures: string_set!["garbage"],
+            dependent_realisations: BTreeMap::new(),
+        };
+        let keys = [key.to_public_key()];
+        assert_eq!(r.check_signatures(&keys), 0);
+        assert!(matches!(
+            r.verify_signatures(&keys),
+            Err(Error::UntrustedRealisation(_))
+        ));
+        r.sign(&other).unwrap();
+        assert_eq!(r.check_signatures(&keys), 0);
+        r.sign(&key).unwrap();
+        assert_eq!(r.check_signatures(&keys), 1);
+        assert!(r.verify_signatures(&keys).is_ok());
+
+        r.out_path =
+            StorePath::new_from_base_name("7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-forged").unwrap();
+        assert_eq!(r.check_signatures(&keys), 0);
     }
 }
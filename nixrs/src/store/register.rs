@@ -0,0 +1,187 @@
+use std::io;
+
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tracing::{debug, instrument};
+
+use super::{CheckSignaturesFlag, Error, RepairFlag, Store, SubstituteFlag};
+use crate::hash::{HashSink, ParallelHashSink, PARALLEL_HASH_THRESHOLD};
+use crate::io::TeeReader;
+use crate::path_info::ValidPathInfo;
+use crate::signature::SecretKey;
+use crate::store_path::StorePathSet;
+
+/// Copy `nar` to `file` while it is written to `sink` too.
+async fn stage<R, W>(nar: R, file: &mut File, sink: W) -> io::Result<W>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut tee = TeeReader::new(nar, sink);
+    tokio::io::copy(&mut tee, file).await?;
+    file.flush().await?;
+    Ok(tee.into_inner().1)
+}
+
+/// Register a path in `store` for a trusted writer.
+///
+/// This performs the whole sequence needed to turn a NAR into a valid store
+/// path:
+///
+/// 1. For content-addressed paths the store path is checked against the
+///    content address.
+/// 2. Every reference, apart from a self reference, must already be valid
+///    in `store`.
+/// 3. The NAR is copied to an anonymous temporary file, and hashed on the
+///    way, before anything is written to the store.
+/// 4. The NAR hash and size are checked against `info`. A `nar_size` of `0`
+///    is taken as unknown and filled in.
+/// 5. When `secret_key` is given the path info is signed with it.
+/// 6. The path is added to `store` from the temporary file without checking
+///    signatures.
+///
+/// Returns the path info as it was registered.
+#[instrument(skip_all, fields(path = %info.path))]
+pub async fn register_valid_path<S, R>(
+    store: &mut S,
+    mut info: ValidPathInfo,
+    nar: R,
+    repair: RepairFlag,
+    secret_key: Option<&SecretKey>,
+) -> Result<ValidPathInfo, Error>
+where
+    S: Store + Send,
+    R: AsyncRead + Unpin,
+{
+    let store_dir = store.store_dir();
+    let path_s = store_dir.print_path(&info.path);
+
+    if let Some(ca) = info.content_address_with_references() {
+        let expected = store_dir.make_fixed_output_path_from_ca(info.path.name.name(), &ca)?;
+        if expected != info.path {
+            return Err(Error::ContentAddressMismatch(path_s));
+        }
+    }
+
+    let mut references: StorePathSet = info.references.clone();
+    references.remove(&info.path);
+    if !references.is_empty() {
+        let valid = store
+            .query_valid_paths(&references, SubstituteFlag::NoSubstitute)
+            .await?;
+        if let Some(missing) = references.difference(&valid).next() {
+            return Err(Error::MissingReference(
+                path_s,
+                store_dir.print_path(missing),
+            ));
+        }
+    }
+
+    let mut staged = File::from_std(tempfile::tempfile()?);
+    let algorithm = info.nar_hash.algorithm();
+    let (nar_size, nar_hash) = if info.nar_size > PARALLEL_HASH_THRESHOLD {
+        let sink = stage(nar, &mut staged, ParallelHashSink::new(algorithm)).await?;
+        sink.finish().await?
+    } else {
+        stage(nar, &mut staged, HashSink::new(algorithm))
+            .await?
+            .finish()
+    };
+    if nar_hash != info.nar_hash {
+        return Err(Error::NarHashMismatch(
+            path_s,
+            info.nar_hash.to_sri().to_string(),
+            nar_hash.to_sri().to_string(),
+        ));
+    }
+    if info.nar_size == 0 {
+        info.nar_size = nar_size;
+    } else if info.nar_size != nar_size {
+        return Err(Error::NarSizeMismatch(path_s, info.nar_size, nar_size));
+    }
+
+    if let Some(key) = secret_key {
+        let fingerprint = info
+            .fingerprint(&store_dir)
+            .map_err(|err| Error::Misc(err.to_string()))?
+            .to_string();
+        info.sigs.insert(key.sign(fingerprint));
+    }
+
+    debug!("registering {} ({} bytes)", path_s, nar_size);
+    staged.rewind().await?;
+    store
+        .add_to_store(
+            &info,
+            BufReader::new(staged),
+            repair,
+            CheckSignaturesFlag::NoCheckSigs,
+        )
+        .await?;
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+    use bytes::{Bytes, BytesMut};
+
+    use super::*;
+    use crate::archive::test_data;
+    use crate::hash::Context;
+    use crate::store::assert_store::AssertStore;
+    use crate::store_path::StorePath;
+
+    fn text_file_nar() -> Bytes {
+        let mut buf = BytesMut::new();
+        for event in test_data::text_file() {
+            event.encode_into(&mut buf);
+        }
+        buf.freeze()
+    }
+
+    fn test_info(nar: &[u8]) -> ValidPathInfo {
+        let path = StorePath::test_from_seed("test");
+        let mut ctx = Context::new(crate::hash::Algorithm::SHA256);
+        ctx.update(nar);
+        ValidPathInfo::new(path, ctx.finish())
+    }
+
+    #[tokio::test]
+    async fn test_register_valid_path() {
+        let nar = text_file_nar();
+        let info = test_info(&nar);
+        let mut expected = info.clone();
+        expected.nar_size = nar.len() as u64;
+        let mut store = AssertStore::assert_add_to_store(
+            None,
+            &expected,
+            nar.clone(),
+            RepairFlag::NoRepair,
+            CheckSignaturesFlag::NoCheckSigs,
+            Ok(()),
+        );
+        let res = register_valid_path(&mut store, info, &nar[..], RepairFlag::NoRepair, None)
+            .await
+            .unwrap();
+        assert_eq!(res, expected);
+        store.assert_eq();
+    }
+
+    #[tokio::test]
+    async fn test_register_valid_path_bad_size() {
+        let nar = text_file_nar();
+        let mut info = test_info(&nar);
+        info.nar_size = 1;
+        let mut store = AssertStore::assert_add_to_store(
+            None,
+            &info,
+            Bytes::new(),
+            RepairFlag::NoRepair,
+            CheckSignaturesFlag::NoCheckSigs,
+            Ok(()),
+        );
+        let res = register_valid_path(&mut store, info, &nar[..], RepairFlag::NoRepair, None).await;
+        assert_matches!(res, Err(Error::NarSizeMismatch(_, 1, _)));
+    }
+}
@@ -0,0 +1,341 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::path_info::ValidPathInfo;
+use crate::store::daemon::{DaemonStore, QueryMissingResult, TrustedFlag};
+use crate::store::memory_store::base_drv_path;
+use crate::store::misc::add_multiple_to_store_old;
+use crate::store::{
+    compute_fs_closure_slow, read_derivation, BasicDerivation, BuildMode, BuildResult,
+    CheckSignaturesFlag, DerivedPath, Error, ExperimentalFeatures, OutputSpec, RepairFlag, Store,
+    SubstituteFlag,
+};
+use crate::store_path::{StoreDir, StoreDirProvider, StorePath, StorePathSet};
+
+#[derive(Debug, Default)]
+struct Paths {
+    inputs: StorePathSet,
+    added: StorePathSet,
+}
+
+/// The paths a [`RestrictedStore`] shows: the input closure it was made
+/// with and whatever has been added or built through it since.
+///
+/// Clones share the same paths, so a build can hand a clone to every
+/// connection of the builder and look at what was added afterwards.
+#[derive(Debug, Clone, Default)]
+pub struct RestrictedPaths(Arc<Mutex<Paths>>);
+
+impl RestrictedPaths {
+    pub fn new(inputs: StorePathSet) -> RestrictedPaths {
+        RestrictedPaths(Arc::new(Mutex::new(Paths {
+            inputs,
+            added: StorePathSet::new(),
+        })))
+    }
+
+    pub fn is_allowed(&self, path: &StorePath) -> bool {
+        let paths = self.0.lock().unwrap();
+        paths.inputs.contains(path) || paths.added.contains(path)
+    }
+
+    /// Make `path` visible from now on.
+    pub fn allow(&self, path: StorePath) {
+        let mut paths = self.0.lock().unwrap();
+        if !paths.inputs.contains(&path) {
+            paths.added.insert(path);
+        }
+    }
+
+    /// Paths added or built since the store was made, which the outputs of
+    /// the build may refer to.
+    pub fn added(&self) -> StorePathSet {
+        self.0.lock().unwrap().added.clone()
+    }
+}
+
+/// The store a builder sees when it uses Nix itself, as with the
+/// `recursive-nix` experimental feature.
+///
+/// Only paths in [`RestrictedPaths`] are valid. Paths can be added and
+/// derivations that are visible built, which makes the results visible
+/// too. Everything that would let a builder look at or change the rest of
+/// the store, like garbage collection or build logs, is not supported, and
+/// the client is never trusted.
+#[derive(Debug)]
+pub struct RestrictedStore<S> {
+    store: S,
+    paths: RestrictedPaths,
+}
+
+impl<S> RestrictedStore<S> {
+    pub fn new(store: S, paths: RestrictedPaths) -> RestrictedStore<S> {
+        RestrictedStore { store, paths }
+    }
+
+    pub fn paths(&self) -> &RestrictedPaths {
+        &self.paths
+    }
+
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+}
+
+impl<S> RestrictedStore<S>
+where
+    S: Store + Send,
+{
+    fn check_allowed(&self, path: &StorePath) -> Result<(), Error> {
+        if self.paths.is_allowed(path) {
+            Ok(())
+        } else {
+            Err(Error::InvalidPath(self.store.store_dir().print_path(path)))
+        }
+    }
+
+    /// Make the outputs of `drv_path` that were asked for visible along
+    /// with their closure.
+    async fn allow_outputs(
+        &mut self,
+        drv_path: &StorePath,
+        outputs: &OutputSpec,
+    ) -> Result<(), Error> {
+        let store_dir = self.store.store_dir();
+        let drv = read_derivation(&mut self.store, drv_path).await?;
+        let mut built = StorePathSet::new();
+        for (name, (_, path)) in drv.basic.outputs_and_opt_paths(&store_dir)? {
+            if let OutputSpec::Names(names) = outputs {
+                if !names.contains(&name) {
+                    continue;
+                }
+            }
+            if let Some(path) = path {
+                built.insert(path);
+            }
+        }
+        let valid = self
+            .store
+            .query_valid_paths(&built, SubstituteFlag::NoSubstitute)
+            .await?;
+        for path in compute_fs_closure_slow(&mut self.store, &valid, false).await? {
+            self.paths.allow(path);
+        }
+        Ok(())
+    }
+}
+
+impl<S: StoreDirProvider> StoreDirProvider for RestrictedStore<S> {
+    fn store_dir(&self) -> StoreDir {
+        self.store.store_dir()
+    }
+}
+
+#[async_trait]
+impl<S> Store for RestrictedStore<S>
+where
+    S: Store + Send,
+{
+    async fn query_valid_paths(
+        &mut self,
+        paths: &StorePathSet,
+        maybe_substitute: SubstituteFlag,
+    ) -> Result<StorePathSet, Error> {
+        let allowed: StorePathSet = paths
+            .iter()
+            .filter(|path| self.paths.is_allowed(path))
+            .cloned()
+            .collect();
+        self.store
+            .query_valid_paths(&allowed, maybe_substitute)
+            .await
+    }
+
+    async fn query_path_info(&mut self, path: &StorePath) -> Result<Option<ValidPathInfo>, Error> {
+        if self.paths.is_allowed(path) {
+            self.store.query_path_info(path).await
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn nar_from_path<W: AsyncWrite + fmt::Debug + Send + Unpin>(
+        &mut self,
+        path: &StorePath,
+        sink: W,
+    ) -> Result<(), Error> {
+        self.check_allowed(path)?;
+        self.store.nar_from_path(path, sink).await
+    }
+
+    async fn add_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        info: &ValidPathInfo,
+        source: R,
+        repair: RepairFlag,
+        check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error> {
+        if repair == RepairFlag::Repair {
+            return Err(Error::RepairNotAllowed);
+        }
+        self.store
+            .add_to_store(info, source, repair, check_sigs)
+            .await?;
+        self.paths.allow(info.path.clone());
+        Ok(())
+    }
+
+    async fn build_derivation(
+        &mut self,
+        _drv_path: &StorePath,
+        _drv: &BasicDerivation,
+        _build_mode: BuildMode,
+    ) -> Result<BuildResult, Error> {
+        Err(Error::UnsupportedOperation("build_derivation".into()))
+    }
+
+    async fn build_paths(
+        &mut self,
+        drv_paths: &[DerivedPath],
+        build_mode: BuildMode,
+    ) -> Result<(), Error> {
+        if build_mode != BuildMode::Normal {
+            return Err(Error::RepairingOrCheckingNotSupported);
+        }
+        for path in drv_paths {
+            match path {
+                DerivedPath::Opaque(path) => self.check_allowed(path)?,
+                DerivedPath::Built { drv_path, .. } => {
+                    self.check_allowed(base_drv_path(drv_path))?
+                }
+            }
+        }
+        self.store.build_paths(drv_paths, build_mode).await?;
+        for path in drv_paths {
+            if let DerivedPath::Built { drv_path, outputs } = path {
+                self.allow_outputs(base_drv_path(drv_path), outputs).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<S> DaemonStore for RestrictedStore<S>
+where
+    S: DaemonStore + Unpin + Send,
+{
+    fn is_trusted_client(&self) -> Option<TrustedFlag> {
+        Some(TrustedFlag::NotTrusted)
+    }
+
+    fn experimental_features(&self) -> ExperimentalFeatures {
+        self.store.experimental_features()
+    }
+
+    async fn set_options(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn is_valid_path(&mut self, path: &StorePath) -> Result<bool, Error> {
+        Ok(self.paths.is_allowed(path) && self.store.is_valid_path(path).await?)
+    }
+
+    async fn add_multiple_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        source: R,
+        repair: RepairFlag,
+        check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error> {
+        add_multiple_to_store_old(self, source, repair, check_sigs).await
+    }
+
+    /// Only the targets that are visible are passed on, the others are
+    /// unknown.
+    async fn query_missing(
+        &mut self,
+        targets: &[DerivedPath],
+    ) -> Result<QueryMissingResult, Error> {
+        let mut allowed = Vec::new();
+        let mut unknown = StorePathSet::new();
+        for target in targets {
+            let path = match target {
+                DerivedPath::Opaque(path) => path,
+                DerivedPath::Built { drv_path, .. } => base_drv_path(drv_path),
+            };
+            if self.paths.is_allowed(path) {
+                allowed.push(target.clone());
+            } else {
+                unknown.insert(path.clone());
+            }
+        }
+        let mut res = self.store.query_missing(&allowed).await?;
+        res.unknown.extend(unknown);
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use super::*;
+    use crate::hash;
+    use crate::store::MemoryStore;
+
+    async fn add<S: Store + Send>(store: &mut S, name: &str) -> Result<StorePath, Error> {
+        let nar = name.as_bytes();
+        let mut info = ValidPathInfo::new(
+            StorePath::test_from_seed(name),
+            hash::digest(hash::Algorithm::SHA256, nar),
+        );
+        info.nar_size = nar.len() as u64;
+        store
+            .add_to_store(
+                &info,
+                nar,
+                RepairFlag::NoRepair,
+                CheckSignaturesFlag::NoCheckSigs,
+            )
+            .await?;
+        Ok(info.path)
+    }
+
+    #[tokio::test]
+    async fn test_restricted_store() {
+        let mut inner = MemoryStore::new();
+        let input = add(&mut inner, "input").await.unwrap();
+        let other = add(&mut inner, "other").await.unwrap();
+        let paths = RestrictedPaths::new([input.clone()].into_iter().collect());
+        let mut store = RestrictedStore::new(inner, paths.clone());
+
+        assert!(store.is_valid_path(&input).await.unwrap());
+        assert!(!store.is_valid_path(&other).await.unwrap());
+        assert_eq!(None, store.query_path_info(&other).await.unwrap());
+        let all: StorePathSet = [input.clone(), other.clone()].into_iter().collect();
+        let valid = store
+            .query_valid_paths(&all, SubstituteFlag::NoSubstitute)
+            .await
+            .unwrap();
+        assert_eq!(valid, [input.clone()].into_iter().collect());
+        let mut buf = Vec::new();
+        assert_matches!(
+            store.nar_from_path(&other, &mut buf).await,
+            Err(Error::InvalidPath(_))
+        );
+        assert_matches!(
+            store
+                .build_paths(&[DerivedPath::Opaque(other.clone())], BuildMode::Normal)
+                .await,
+            Err(Error::InvalidPath(_))
+        );
+
+        let added = add(&mut store, "added").await.unwrap();
+        assert!(store.is_valid_path(&added).await.unwrap());
+        assert_eq!(paths.added(), [added].into_iter().collect());
+        assert!(store.into_inner().is_valid_path(&other).await.unwrap());
+    }
+}
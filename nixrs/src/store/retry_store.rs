@@ -0,0 +1,676 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::warn;
+
+use crate::path_info::ValidPathInfo;
+use crate::store::activity::Activity;
+use crate::store::daemon::{
+    DaemonPath, DaemonStore, GCOptions, GCResults, QueryMissingResult, TrustedFlag,
+};
+use crate::store::legacy_worker::LegacyStore;
+use crate::store::{
+    BasicDerivation, BuildMode, BuildResult, CheckSignaturesFlag, DerivedPath, DrvOutput, Error,
+    ExperimentalFeatures, Realisation, RepairFlag, Store, SubstituteFlag,
+};
+use crate::store_path::{StoreDir, StoreDirProvider, StorePath, StorePathSet};
+
+/// Whether `err` looks like a hiccup of the network or the remote store
+/// that could go away when the operation is tried again.
+pub fn is_transient_error(err: &Error) -> bool {
+    match err {
+        Error::IOError { source } => matches!(
+            source.kind(),
+            io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::Interrupted
+                | io::ErrorKind::UnexpectedEof
+        ),
+        Error::ReqwestError(err) => {
+            err.is_timeout()
+                || err.is_connect()
+                || err.status().map(|s| s.is_server_error()).unwrap_or(false)
+        }
+        Error::Timeout(_, _) => true,
+        _ => false,
+    }
+}
+
+/// Whether `err` leaves a connection in an unknown state, so the operation
+/// can only be tried again on a new connection.
+fn needs_reconnect(err: &Error) -> bool {
+    matches!(err, Error::Timeout(_, _) | Error::StorePoisoned)
+}
+
+/// When and how often a [`RetryStore`] tries an operation again.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// How many times an operation is tried in total, including the first
+    /// attempt.
+    pub max_attempts: u32,
+    /// How long to wait before the first retry. The wait doubles with each
+    /// following retry.
+    pub initial_backoff: Duration,
+    /// Longest wait between two attempts.
+    pub max_backoff: Duration,
+    /// Which errors are worth retrying.
+    pub retryable: fn(&Error) -> bool,
+}
+
+impl RetryPolicy {
+    /// Only try operations once.
+    pub fn never() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// How long to wait after `attempt` failed.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+
+    fn should_retry(&self, attempt: u32, err: &Error) -> bool {
+        attempt < self.max_attempts && (self.retryable)(err)
+    }
+
+    /// Timeouts and poisoned stores are only tried again when there is a
+    /// new connection to try them on.
+    fn should_retry_on(&self, reconnects: bool, attempt: u32, err: &Error) -> bool {
+        if reconnects {
+            self.should_retry(attempt, err)
+                || (attempt < self.max_attempts && matches!(err, Error::StorePoisoned))
+        } else {
+            !needs_reconnect(err) && self.should_retry(attempt, err)
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(10),
+            retryable: is_transient_error,
+        }
+    }
+}
+
+async fn wait_for_retry(policy: &RetryPolicy, op: &str, attempt: u32, err: Error) {
+    let backoff = policy.backoff(attempt);
+    warn!(
+        "{} failed (attempt {}/{}), retrying in {:?}: {}",
+        op, attempt, policy.max_attempts, backoff, err
+    );
+    tokio::time::sleep(backoff).await;
+}
+
+macro_rules! retry {
+    ($self:ident, $op:expr, $call:expr) => {{
+        let mut attempt = 1;
+        loop {
+            match $call.await {
+                Err(err) if $self.should_retry(attempt, &err) => {
+                    attempt = $self.retry_after($op, attempt, err).await?;
+                }
+                res => break res,
+            }
+        }
+    }};
+}
+
+/// Counts what is written to a sink so that a NAR is only fetched again
+/// when none of it reached the sink.
+#[derive(Debug)]
+struct CountingWriter<W> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CountingWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.written += n as u64;
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+type Reconnect<S> = Box<dyn FnMut() -> BoxFuture<'static, Result<S, Error>> + Send>;
+
+/// Wraps a store and tries idempotent operations again, with exponential
+/// backoff, when they fail with an error its [`RetryPolicy`] deems
+/// transient.
+///
+/// Only queries and [`nar_from_path`](Store::nar_from_path) are retried.
+/// A NAR is not fetched again once part of it was written to the sink.
+/// Adding paths, building and collecting garbage are tried once.
+///
+/// By itself it tries operations again on the same store, which suits
+/// stores where every request stands on its own, like HTTP binary caches.
+/// Stores over a connection, like the daemon client, need a
+/// [reconnect hook](RetryStore::with_reconnect) to replace the connection
+/// before every retry. Without one, operations that timed out
+/// ([`Error::Timeout`]) or found the store poisoned
+/// ([`Error::StorePoisoned`]) are not tried again since the connection
+/// can't be used anymore.
+pub struct RetryStore<S> {
+    store: S,
+    policy: RetryPolicy,
+    reconnect: Option<Reconnect<S>>,
+}
+
+impl<S> RetryStore<S> {
+    pub fn new(store: S, policy: RetryPolicy) -> RetryStore<S> {
+        RetryStore {
+            store,
+            policy,
+            reconnect: None,
+        }
+    }
+
+    /// Replace the wrapped store with a new one from `reconnect` before
+    /// trying an operation again. Failing to reconnect counts as a failed
+    /// attempt.
+    pub fn with_reconnect<F, Fut>(mut self, mut reconnect: F) -> Self
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<S, Error>> + Send + 'static,
+    {
+        self.reconnect = Some(Box::new(move || reconnect().boxed()));
+        self
+    }
+
+    pub fn policy(&self) -> RetryPolicy {
+        self.policy
+    }
+
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+
+    fn should_retry(&self, attempt: u32, err: &Error) -> bool {
+        self.policy
+            .should_retry_on(self.reconnect.is_some(), attempt, err)
+    }
+
+    /// Wait before trying `op` again after `attempt` failed with `err`,
+    /// and reconnect when there is a hook for it. Returns the number of
+    /// the next attempt.
+    async fn retry_after(&mut self, op: &str, mut attempt: u32, err: Error) -> Result<u32, Error> {
+        wait_for_retry(&self.policy, op, attempt, err).await;
+        attempt += 1;
+        if let Some(reconnect) = self.reconnect.as_mut() {
+            loop {
+                match reconnect().await {
+                    Ok(store) => {
+                        self.store = store;
+                        break;
+                    }
+                    Err(err) if self.policy.should_retry(attempt, &err) => {
+                        wait_for_retry(&self.policy, "reconnecting", attempt, err).await;
+                        attempt += 1;
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        Ok(attempt)
+    }
+}
+
+impl<S: fmt::Debug> fmt::Debug for RetryStore<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryStore")
+            .field("store", &self.store)
+            .field("policy", &self.policy)
+            .field("reconnects", &self.reconnect.is_some())
+            .finish()
+    }
+}
+
+impl<S: StoreDirProvider> StoreDirProvider for RetryStore<S> {
+    fn store_dir(&self) -> StoreDir {
+        self.store.store_dir()
+    }
+}
+
+#[async_trait]
+impl<S> Store for RetryStore<S>
+where
+    S: Store + Send,
+{
+    async fn query_valid_paths(
+        &mut self,
+        paths: &StorePathSet,
+        maybe_substitute: SubstituteFlag,
+    ) -> Result<StorePathSet, Error> {
+        retry!(
+            self,
+            "querying valid paths",
+            self.store.query_valid_paths(paths, maybe_substitute)
+        )
+    }
+
+    async fn query_path_info(&mut self, path: &StorePath) -> Result<Option<ValidPathInfo>, Error> {
+        retry!(self, "querying path info", self.store.query_path_info(path))
+    }
+
+    async fn nar_from_path<W: AsyncWrite + fmt::Debug + Send + Unpin>(
+        &mut self,
+        path: &StorePath,
+        mut sink: W,
+    ) -> Result<(), Error> {
+        let mut attempt = 1;
+        loop {
+            let mut counter = CountingWriter {
+                inner: &mut sink,
+                written: 0,
+            };
+            match self.store.nar_from_path(path, &mut counter).await {
+                Err(err) if counter.written == 0 && self.should_retry(attempt, &err) => {
+                    attempt = self.retry_after("fetching NAR", attempt, err).await?;
+                }
+                res => break res,
+            }
+        }
+    }
+
+    async fn add_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        info: &ValidPathInfo,
+        source: R,
+        repair: RepairFlag,
+        check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error> {
+        self.store
+            .add_to_store(info, source, repair, check_sigs)
+            .await
+    }
+
+    async fn build_derivation(
+        &mut self,
+        drv_path: &StorePath,
+        drv: &BasicDerivation,
+        build_mode: BuildMode,
+    ) -> Result<BuildResult, Error> {
+        self.store.build_derivation(drv_path, drv, build_mode).await
+    }
+
+    async fn build_paths(
+        &mut self,
+        drv_paths: &[DerivedPath],
+        build_mode: BuildMode,
+    ) -> Result<(), Error> {
+        self.store.build_paths(drv_paths, build_mode).await
+    }
+}
+
+#[async_trait]
+impl<S> DaemonStore for RetryStore<S>
+where
+    S: DaemonStore + Send,
+{
+    fn is_trusted_client(&self) -> Option<TrustedFlag> {
+        self.store.is_trusted_client()
+    }
+
+    fn experimental_features(&self) -> ExperimentalFeatures {
+        self.store.experimental_features()
+    }
+
+    async fn set_options(&mut self) -> Result<(), Error> {
+        retry!(self, "setting options", self.store.set_options())
+    }
+
+    async fn is_valid_path(&mut self, path: &StorePath) -> Result<bool, Error> {
+        retry!(self, "querying valid path", self.store.is_valid_path(path))
+    }
+
+    async fn add_multiple_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        source: R,
+        repair: RepairFlag,
+        check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error> {
+        self.store
+            .add_multiple_to_store(source, repair, check_sigs)
+            .await
+    }
+
+    async fn query_missing(
+        &mut self,
+        targets: &[DerivedPath],
+    ) -> Result<QueryMissingResult, Error> {
+        retry!(
+            self,
+            "querying missing paths",
+            self.store.query_missing(targets)
+        )
+    }
+
+    async fn add_build_log(&mut self, drv_path: &StorePath, log: &str) -> Result<(), Error> {
+        self.store.add_build_log(drv_path, log).await
+    }
+
+    async fn repair_path(&mut self, path: &StorePath) -> Result<(), Error> {
+        self.store.repair_path(path).await
+    }
+
+    async fn collect_garbage(&mut self, options: &GCOptions) -> Result<GCResults, Error> {
+        self.store.collect_garbage(options).await
+    }
+
+    async fn query_realisation(&mut self, id: &DrvOutput) -> Result<Option<Realisation>, Error> {
+        retry!(
+            self,
+            "querying realisation",
+            self.store.query_realisation(id)
+        )
+    }
+
+    async fn register_drv_output(&mut self, realisation: &Realisation) -> Result<(), Error> {
+        self.store.register_drv_output(realisation).await
+    }
+
+    async fn add_indirect_root(&mut self, path: &DaemonPath) -> Result<(), Error> {
+        retry!(
+            self,
+            "adding indirect root",
+            self.store.add_indirect_root(path)
+        )
+    }
+
+    async fn find_roots(&mut self) -> Result<BTreeMap<DaemonPath, StorePath>, Error> {
+        retry!(self, "finding roots", self.store.find_roots())
+    }
+
+    async fn verify_store(
+        &mut self,
+        check_contents: bool,
+        repair: RepairFlag,
+    ) -> Result<bool, Error> {
+        self.store.verify_store(check_contents, repair).await
+    }
+
+    async fn optimise_store(&mut self) -> Result<(), Error> {
+        retry!(self, "optimising store", self.store.optimise_store())
+    }
+
+    async fn collect_garbage_streaming(
+        &mut self,
+        options: &GCOptions,
+        act: &Activity,
+    ) -> Result<GCResults, Error> {
+        self.store.collect_garbage_streaming(options, act).await
+    }
+
+    async fn substitute_paths(&mut self, paths: &StorePathSet) -> Result<(), Error> {
+        self.store.substitute_paths(paths).await
+    }
+}
+
+#[async_trait]
+impl<S> LegacyStore for RetryStore<S>
+where
+    S: LegacyStore + Send,
+{
+    async fn query_valid_paths_locked(
+        &mut self,
+        paths: &StorePathSet,
+        lock: bool,
+        maybe_substitute: SubstituteFlag,
+    ) -> Result<StorePathSet, Error> {
+        retry!(
+            self,
+            "querying valid paths",
+            self.store
+                .query_valid_paths_locked(paths, lock, maybe_substitute)
+        )
+    }
+
+    async fn export_paths<W: AsyncWrite + fmt::Debug + Send + Unpin>(
+        &mut self,
+        paths: &StorePathSet,
+        sink: W,
+    ) -> Result<(), Error> {
+        self.store.export_paths(paths, sink).await
+    }
+
+    async fn import_paths<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        source: R,
+    ) -> Result<(), Error> {
+        self.store.import_paths(source).await
+    }
+
+    async fn query_closure(
+        &mut self,
+        paths: &StorePathSet,
+        include_outputs: bool,
+    ) -> Result<StorePathSet, Error> {
+        retry!(
+            self,
+            "querying closure",
+            self.store.query_closure(paths, include_outputs)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    use assert_matches::assert_matches;
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    /// A store that fails the first `failures` operations with `kind`, or
+    /// with a timeout when `kind` is `None`.
+    struct FlakyStore {
+        failures: u32,
+        kind: Option<io::ErrorKind>,
+        calls: u32,
+    }
+
+    impl FlakyStore {
+        fn new(failures: u32, kind: io::ErrorKind) -> FlakyStore {
+            FlakyStore {
+                failures,
+                kind: Some(kind),
+                calls: 0,
+            }
+        }
+
+        fn timing_out(failures: u32) -> FlakyStore {
+            FlakyStore {
+                failures,
+                kind: None,
+                calls: 0,
+            }
+        }
+
+        fn check(&mut self) -> Result<(), Error> {
+            self.calls += 1;
+            if self.calls > self.failures {
+                Ok(())
+            } else if let Some(kind) = self.kind {
+                Err(io::Error::from(kind).into())
+            } else {
+                Err(Error::Timeout("querying".into(), Duration::ZERO))
+            }
+        }
+    }
+
+    impl StoreDirProvider for FlakyStore {
+        fn store_dir(&self) -> StoreDir {
+            StoreDir::default()
+        }
+    }
+
+    #[async_trait]
+    impl Store for FlakyStore {
+        async fn query_path_info(
+            &mut self,
+            _path: &StorePath,
+        ) -> Result<Option<ValidPathInfo>, Error> {
+            self.check()?;
+            Ok(None)
+        }
+
+        async fn nar_from_path<W: AsyncWrite + fmt::Debug + Send + Unpin>(
+            &mut self,
+            _path: &StorePath,
+            mut sink: W,
+        ) -> Result<(), Error> {
+            sink.write_all(b"nix-archive-1").await?;
+            self.check()
+        }
+
+        async fn add_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+            &mut self,
+            _info: &ValidPathInfo,
+            _source: R,
+            _repair: RepairFlag,
+            _check_sigs: CheckSignaturesFlag,
+        ) -> Result<(), Error> {
+            self.check()
+        }
+    }
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::ZERO,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(400));
+        assert_eq!(policy.backoff(4), Duration::from_millis(500));
+        assert_eq!(policy.backoff(100), Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn test_retries_transient_errors() {
+        let mut store = RetryStore::new(
+            FlakyStore::new(2, io::ErrorKind::ConnectionReset),
+            policy(3),
+        );
+        let path = StorePath::test_from_seed("foo");
+        assert_matches!(store.query_path_info(&path).await, Ok(None));
+        assert_eq!(store.into_inner().calls, 3);
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_max_attempts() {
+        let mut store = RetryStore::new(
+            FlakyStore::new(5, io::ErrorKind::ConnectionReset),
+            policy(3),
+        );
+        let path = StorePath::test_from_seed("foo");
+        assert_matches!(
+            store.query_path_info(&path).await,
+            Err(Error::IOError { .. })
+        );
+        assert_eq!(store.into_inner().calls, 3);
+    }
+
+    #[tokio::test]
+    async fn test_permanent_errors_not_retried() {
+        let mut store = RetryStore::new(
+            FlakyStore::new(1, io::ErrorKind::PermissionDenied),
+            policy(3),
+        );
+        let path = StorePath::test_from_seed("foo");
+        assert_matches!(
+            store.query_path_info(&path).await,
+            Err(Error::IOError { .. })
+        );
+        assert_eq!(store.into_inner().calls, 1);
+    }
+
+    #[tokio::test]
+    async fn test_partial_nar_not_retried() {
+        let mut store = RetryStore::new(
+            FlakyStore::new(1, io::ErrorKind::ConnectionReset),
+            policy(3),
+        );
+        let path = StorePath::test_from_seed("foo");
+        let mut sink = Vec::new();
+        assert_matches!(
+            store.nar_from_path(&path, &mut sink).await,
+            Err(Error::IOError { .. })
+        );
+        assert_eq!(sink, b"nix-archive-1");
+        assert_eq!(store.into_inner().calls, 1);
+    }
+
+    #[tokio::test]
+    async fn test_timeout_not_retried_without_reconnect() {
+        let mut store = RetryStore::new(FlakyStore::timing_out(1), policy(3));
+        let path = StorePath::test_from_seed("foo");
+        assert_matches!(
+            store.query_path_info(&path).await,
+            Err(Error::Timeout(_, _))
+        );
+        assert_eq!(store.into_inner().calls, 1);
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_before_retry() {
+        let reconnects = Arc::new(AtomicU32::new(0));
+        let counter = reconnects.clone();
+        let mut store =
+            RetryStore::new(FlakyStore::timing_out(1), policy(3)).with_reconnect(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+                async { Ok(FlakyStore::new(0, io::ErrorKind::ConnectionReset)) }
+            });
+        let path = StorePath::test_from_seed("foo");
+        assert_matches!(store.query_path_info(&path).await, Ok(None));
+        assert_eq!(reconnects.load(Ordering::SeqCst), 1);
+        // The operation was tried again on the new store.
+        assert_eq!(store.into_inner().calls, 1);
+    }
+}
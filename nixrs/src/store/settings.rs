@@ -1,7 +1,10 @@
 use std::cell::RefCell;
 use std::collections::BTreeMap;
+use std::env;
+use std::fs;
 use std::future::Future;
 use std::num::ParseIntError;
+use std::path::{Path, PathBuf};
 use std::str::ParseBoolError;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
@@ -25,8 +28,17 @@ pub enum ParseSettingError {
         #[from]
         ParseIntError,
     ),
+    #[error("illegal configuration line '{0}'")]
+    IllegalLine(String),
+    #[error("could not read configuration file '{0}': {1}")]
+    ReadFile(String, String),
+    #[error("setting '{0}' is not a list and can't be extended with 'extra-{0}'")]
+    NotAList(String),
 }
 
+/// Settings that take a list of values, which `extra-` lines add to.
+const LIST_SETTINGS: &[&str] = &["sandbox-paths"];
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct BuildSettings {
     /// Whether to keep temporary directories of failed builds.
@@ -111,6 +123,11 @@ pub struct BuildSettings {
     /// line.
     pub run_diff_hook: bool,
 
+    /// Host paths that are available at the same location in the build
+    /// sandbox. They are added to those from
+    /// [`LocalBuilder::sandbox_paths`](crate::build::LocalBuilder::sandbox_paths).
+    pub sandbox_paths: Vec<String>,
+
     /// Unknown settings
     pub unknown: BTreeMap<String, String>,
 }
@@ -131,6 +148,7 @@ impl BuildSettings {
             build_timeout: Duration::from_secs(0),
             max_log_size: 0,
             run_diff_hook: false,
+            sandbox_paths: Vec::new(),
             unknown: BTreeMap::new(),
         }
     }
@@ -142,6 +160,11 @@ impl BuildSettings {
                 "keep-going" => self.keep_going = v.parse()?,
                 "fallback" => self.try_fallback = v.parse()?,
                 "build-fallback" => self.try_fallback = v.parse()?,
+                "max-jobs" | "build-max-jobs" if v == "auto" => {
+                    self.max_build_jobs = std::thread::available_parallelism()
+                        .map(|n| n.get() as u64)
+                        .unwrap_or(1)
+                }
                 "max-jobs" => self.max_build_jobs = v.parse()?,
                 "build-max-jobs" => self.max_build_jobs = v.parse()?,
                 "cores" => self.build_cores = v.parse()?,
@@ -169,6 +192,9 @@ impl BuildSettings {
                 "max-build-log-size" => self.max_log_size = v.parse()?,
                 "build-max-log-size" => self.max_log_size = v.parse()?,
                 "run-diff-hook" => self.run_diff_hook = v.parse()?,
+                "sandbox-paths" | "build-sandbox-paths" | "build-chroot-dirs" => {
+                    self.sandbox_paths = v.split_whitespace().map(String::from).collect()
+                }
                 _ => {
                     self.unknown.insert(k, v);
                 }
@@ -177,6 +203,101 @@ impl BuildSettings {
         Ok(())
     }
 
+    /// The settings in effect for a new process: the defaults, overridden
+    /// by `$NIX_CONF_DIR/nix.conf` and then by `NIX_CONFIG`.
+    ///
+    /// Clients of the daemon override these again with `SetOptions`.
+    pub fn load() -> Result<BuildSettings, ParseSettingError> {
+        let mut settings = BuildSettings::const_default();
+        let conf_dir = env::var_os("NIX_CONF_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("/etc/nix"));
+        let conf_file = conf_dir.join("nix.conf");
+        if conf_file.exists() {
+            settings.apply_conf_file(&conf_file)?;
+        }
+        settings.apply_env()?;
+        Ok(settings)
+    }
+
+    /// Apply the settings in `NIX_CONFIG`, which uses the format of
+    /// `nix.conf`.
+    pub fn apply_env(&mut self) -> Result<(), ParseSettingError> {
+        if let Ok(config) = env::var("NIX_CONFIG") {
+            self.apply_conf(&config, None)?;
+        }
+        Ok(())
+    }
+
+    pub fn apply_conf_file(&mut self, path: &Path) -> Result<(), ParseSettingError> {
+        let contents = fs::read_to_string(path).map_err(|err| {
+            ParseSettingError::ReadFile(path.display().to_string(), err.to_string())
+        })?;
+        self.apply_conf(&contents, path.parent())
+    }
+
+    /// Apply settings in the format of `nix.conf`.
+    ///
+    /// Relative paths in `include` and `!include` lines are resolved against
+    /// `dir`. Missing files are an error for `include` and skipped for
+    /// `!include`.
+    pub fn apply_conf(
+        &mut self,
+        contents: &str,
+        dir: Option<&Path>,
+    ) -> Result<(), ParseSettingError> {
+        let mut map = BTreeMap::new();
+        for line in contents.lines() {
+            let line = match line.find('#') {
+                Some(pos) => &line[..pos],
+                None => line,
+            };
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if tokens.is_empty() {
+                continue;
+            }
+            if tokens[0] == "include" || tokens[0] == "!include" {
+                if tokens.len() != 2 {
+                    return Err(ParseSettingError::IllegalLine(line.trim().into()));
+                }
+                let path = match dir {
+                    Some(dir) => dir.join(tokens[1]),
+                    None => PathBuf::from(tokens[1]),
+                };
+                self.set(std::mem::take(&mut map))?;
+                if tokens[0] == "include" || path.exists() {
+                    self.apply_conf_file(&path)?;
+                }
+                continue;
+            }
+            if tokens.len() < 2 || tokens[1] != "=" {
+                return Err(ParseSettingError::IllegalLine(line.trim().into()));
+            }
+            let value = tokens[2..].join(" ");
+            if let Some(name) = tokens[0].strip_prefix("extra-") {
+                let mut current = BTreeMap::new();
+                self.get_all(&mut current);
+                if current.contains_key(name)
+                    && !self.unknown.contains_key(name)
+                    && !LIST_SETTINGS.contains(&name)
+                {
+                    return Err(ParseSettingError::NotAList(name.into()));
+                }
+                let prev = map
+                    .remove(name)
+                    .or_else(|| current.remove(name))
+                    .filter(|prev| !prev.is_empty());
+                match prev {
+                    Some(prev) => map.insert(name.into(), format!("{} {}", prev, value)),
+                    None => map.insert(name.into(), value),
+                };
+            } else {
+                map.insert(tokens[0].into(), value);
+            }
+        }
+        self.set(map)
+    }
+
     pub fn get_all(&self, map: &mut BTreeMap<String, String>) {
         map.insert("keep-failed".into(), self.keep_failed.to_string());
         map.insert("keep-going".into(), self.keep_going.to_string());
@@ -192,6 +313,7 @@ impl BuildSettings {
         map.insert("timeout".into(), self.build_timeout.as_secs().to_string());
         map.insert("max-build-log-size".into(), self.max_log_size.to_string());
         map.insert("run-diff-hook".into(), self.run_diff_hook.to_string());
+        map.insert("sandbox-paths".into(), self.sandbox_paths.join(" "));
         map.extend(self.unknown.iter().map(|(k, v)| (k.clone(), v.clone())));
     }
 }
@@ -377,6 +499,76 @@ impl<T: Sized> WithSettings for T {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_conf() {
+        let mut settings = BuildSettings::const_default();
+        settings
+            .apply_conf(
+                "# comment\nkeep-failed = true\nmax-silent-time = 30 # seconds\n\nsubstituters = https://cache.nixos.org\nextra-substituters = https://example.org\n",
+                None,
+            )
+            .unwrap();
+        assert!(settings.keep_failed);
+        assert_eq!(settings.max_silent_time, Duration::from_secs(30));
+        assert_eq!(
+            settings.unknown.get("substituters").unwrap(),
+            "https://cache.nixos.org https://example.org"
+        );
+    }
+
+    #[test]
+    fn test_apply_conf_extra_list() {
+        let mut settings = BuildSettings::const_default();
+        settings.sandbox_paths = vec!["/bin".into()];
+        settings
+            .apply_conf("extra-sandbox-paths = /usr /lib\n", None)
+            .unwrap();
+        settings
+            .apply_conf("extra-sandbox-paths = /etc\n", None)
+            .unwrap();
+        assert_eq!(settings.sandbox_paths, vec!["/bin", "/usr", "/lib", "/etc"]);
+
+        settings
+            .apply_conf("sandbox-paths = /nix\nextra-sandbox-paths = /tmp\n", None)
+            .unwrap();
+        assert_eq!(settings.sandbox_paths, vec!["/nix", "/tmp"]);
+
+        let res = settings.apply_conf("extra-keep-failed = true", None);
+        assert!(matches!(res, Err(ParseSettingError::NotAList(name)) if name == "keep-failed"));
+    }
+
+    #[test]
+    fn test_apply_conf_illegal_line() {
+        let mut settings = BuildSettings::const_default();
+        let res = settings.apply_conf("keep-failed true", None);
+        assert!(matches!(res, Err(ParseSettingError::IllegalLine(_))));
+    }
+
+    #[test]
+    fn test_apply_conf_include() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("extra.conf"), "keep-going = true\n").unwrap();
+        fs::write(
+            dir.path().join("nix.conf"),
+            "include extra.conf\n!include missing.conf\ncores = 4\n",
+        )
+        .unwrap();
+        let mut settings = BuildSettings::const_default();
+        settings
+            .apply_conf_file(&dir.path().join("nix.conf"))
+            .unwrap();
+        assert!(settings.keep_going);
+        assert_eq!(settings.build_cores, 4);
+
+        let res = settings.apply_conf("include missing.conf", Some(dir.path()));
+        assert!(matches!(res, Err(ParseSettingError::ReadFile(_, _))));
+    }
+}
+
 #[cfg(any(test, feature = "test"))]
 pub mod proptest {
     use super::*;
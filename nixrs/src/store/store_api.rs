@@ -1,18 +1,24 @@
+use std::collections::BTreeSet;
 use std::fmt;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 use async_trait::async_trait;
 use futures::future::try_join;
 use tokio::io::AsyncRead;
 use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
 use tracing::debug;
 
-use super::topo_sort_paths_slow;
-use super::{BasicDerivation, DerivedPath, DrvOutputs, Error, RepairFlag};
+use super::daemon::DaemonStore;
+use super::{compute_fs_closure_slow, topo_sort_paths_slow};
+use super::{BasicDerivation, DerivedPath, DrvOutput, DrvOutputs, Error, Realisation, RepairFlag};
 use crate::flag_enum::flag_enum;
+use crate::hash::{Algorithm, Context, ParallelHashSink, PARALLEL_HASH_THRESHOLD};
 use crate::num_enum::num_enum;
 use crate::path_info::ValidPathInfo;
-use crate::store_path::{StoreDirProvider, StorePath, StorePathSet};
+use crate::store_path::{
+    StoreDirProvider, StoreDirRemap, StorePath, StorePathSet, StorePathSetExt,
+};
 
 /* Magic header of exportPath() output (obsolete). */
 pub const EXPORT_MAGIC: u64 = 0x4558494e;
@@ -92,6 +98,11 @@ pub struct BuildResult {
     pub start_time: SystemTime,
     /// The stop time of the build (or one of the rounds, if it was repeated).
     pub stop_time: SystemTime,
+
+    /// User CPU time the build took, if known.
+    pub cpu_user: Option<Duration>,
+    /// System CPU time the build took, if known.
+    pub cpu_system: Option<Duration>,
 }
 
 impl BuildResult {
@@ -104,11 +115,54 @@ impl BuildResult {
             built_outputs: DrvOutputs::new(),
             start_time: SystemTime::UNIX_EPOCH,
             stop_time: SystemTime::UNIX_EPOCH,
+            cpu_user: None,
+            cpu_system: None,
         }
     }
     pub fn success(&self) -> bool {
         self.status.success()
     }
+
+    /// Wall clock time the build took, if it was timed.
+    pub fn duration(&self) -> Option<Duration> {
+        if self.start_time == SystemTime::UNIX_EPOCH {
+            return None;
+        }
+        self.stop_time.duration_since(self.start_time).ok()
+    }
+
+    /// User and system CPU time together, if either is known.
+    pub fn cpu_time(&self) -> Option<Duration> {
+        match (self.cpu_user, self.cpu_system) {
+            (None, None) => None,
+            (user, system) => Some(user.unwrap_or_default() + system.unwrap_or_default()),
+        }
+    }
+}
+
+/// Reports the status with the error message and how long the build took,
+/// like `Built in 3.20s (2.10s user, 0.40s system)`.
+impl fmt::Display for BuildResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.status)?;
+        if !self.error_msg.is_empty() {
+            write!(f, ": {}", self.error_msg)?;
+        }
+        if let Some(duration) = self.duration() {
+            write!(f, " in {:.2}s", duration.as_secs_f64())?;
+        }
+        let mut cpu = Vec::new();
+        if let Some(user) = self.cpu_user {
+            cpu.push(format!("{:.2}s user", user.as_secs_f64()));
+        }
+        if let Some(system) = self.cpu_system {
+            cpu.push(format!("{:.2}s system", system.as_secs_f64()));
+        }
+        if !cpu.is_empty() {
+            write!(f, " ({})", cpu.join(", "))?;
+        }
+        Ok(())
+    }
 }
 
 pub async fn copy_paths<S, D>(
@@ -145,7 +199,8 @@ where
 {
     let valid = dst_store.query_valid_paths(store_paths, substitute).await?;
 
-    let missing: StorePathSet = store_paths.difference(&valid).cloned().collect();
+    let mut missing = StorePathSet::new();
+    store_paths.difference_into(&valid, &mut missing);
 
     let sorted = topo_sort_paths_slow(src_store, &missing).await?;
     for store_path in sorted {
@@ -156,6 +211,60 @@ where
     Ok(())
 }
 
+/// Copy `store_paths` and their references to a store with a different
+/// store dir, rewriting references in the NARs to the new store dir.
+///
+/// The NARs are held in memory while they are rewritten. Signatures don't
+/// survive the rewrite so the paths are added without checking them.
+pub async fn copy_paths_remapped<S, D>(
+    src_store: &mut S,
+    dst_store: &mut D,
+    remap: &mut StoreDirRemap,
+    store_paths: &StorePathSet,
+    repair: RepairFlag,
+) -> Result<(), Error>
+where
+    S: Store,
+    D: Store + Send,
+{
+    let closure = compute_fs_closure_slow(src_store, store_paths, false).await?;
+    let sorted = topo_sort_paths_slow(src_store, &closure).await?;
+    for store_path in sorted {
+        debug!("Copying path {} to {}", store_path, remap.to_dir());
+        let info = src_store
+            .query_path_info(&store_path)
+            .await?
+            .ok_or(Error::InvalidPath(store_path.to_string()))?;
+        let mut new_info = remap.remap_info(&info)?;
+
+        let mut nar = Vec::new();
+        src_store.nar_from_path(&store_path, &mut nar).await?;
+        let mut references = info.references.clone();
+        references.insert(info.path.clone());
+        remap.rewrites(&references).apply(&mut nar);
+
+        new_info.nar_hash = if nar.len() as u64 > PARALLEL_HASH_THRESHOLD {
+            let mut sink = ParallelHashSink::new(Algorithm::SHA256);
+            sink.write_all(&nar).await?;
+            sink.finish().await?.1
+        } else {
+            let mut ctx = Context::new(Algorithm::SHA256);
+            ctx.update(&nar);
+            ctx.finish()
+        };
+        new_info.nar_size = nar.len() as u64;
+        dst_store
+            .add_to_store(
+                &new_info,
+                &nar[..],
+                repair,
+                CheckSignaturesFlag::NoCheckSigs,
+            )
+            .await?;
+    }
+    Ok(())
+}
+
 pub async fn copy_store_path<S, D>(
     src_store: &mut S,
     dst_store: &mut D,
@@ -465,10 +574,147 @@ pub mod proptest {
         ) -> BuildResult
         {
             let stop_time = start_time + Duration::from_secs(duration_secs);
+            // CPU times are only sent by protocol versions newer than ours.
             BuildResult {
                 status, error_msg, times_built, is_non_deterministic,
                 built_outputs, start_time, stop_time,
+                cpu_user: None, cpu_system: None,
+            }
+        }
+    }
+}
+
+/// Check that every realisation `realisation` depends on is known to `store`
+/// with the same output path, so that registering it can't make the
+/// store's realisations disagree with each other.
+///
+/// Realisations are only registered after the ones they depend on, so a
+/// store that checks this for every realisation has checked their whole
+/// closure.
+pub async fn check_realisation_dependencies<S>(
+    store: &mut S,
+    realisation: &Realisation,
+) -> Result<(), Error>
+where
+    S: DaemonStore + Send,
+{
+    for (dep, out_path) in realisation.dependent_realisations.iter() {
+        match store.query_realisation(dep).await? {
+            Some(known) if known.out_path == *out_path => {}
+            Some(_) => {
+                return Err(Error::InconsistentRealisation(
+                    realisation.id.to_string(),
+                    dep.to_string(),
+                ))
             }
+            None => return Err(Error::MissingRealisation(dep.to_string())),
+        }
+    }
+    Ok(())
+}
+
+/// Copy the realisations `ids` of content-addressed derivation outputs to
+/// `dst_store` together with the realisations they depend on and the
+/// closure of their output paths.
+///
+/// When `check_sigs` is [`CheckSignaturesFlag::CheckSigs`] every realisation
+/// is passed to `verify` before anything is copied, so the caller decides
+/// which signatures it trusts, e.g. with
+/// [`Realisation::verify_signatures`]. Realisations the destination already
+/// knows are left alone.
+pub async fn copy_realisations<S, D, V>(
+    src_store: &mut S,
+    dst_store: &mut D,
+    ids: &BTreeSet<DrvOutput>,
+    repair: RepairFlag,
+    check_sigs: CheckSignaturesFlag,
+    mut verify: V,
+) -> Result<(), Error>
+where
+    S: DaemonStore + Send,
+    D: DaemonStore + Send,
+    V: FnMut(&Realisation) -> Result<(), Error>,
+{
+    let mut realisations = DrvOutputs::new();
+    let mut todo: Vec<DrvOutput> = ids.iter().cloned().collect();
+    while let Some(id) = todo.pop() {
+        if realisations.contains_key(&id) {
+            continue;
         }
+        let realisation = src_store
+            .query_realisation(&id)
+            .await?
+            .ok_or_else(|| Error::MissingRealisation(id.to_string()))?;
+        todo.extend(realisation.dependent_realisations.keys().cloned());
+        realisations.insert(id, realisation);
+    }
+    if check_sigs == CheckSignaturesFlag::CheckSigs {
+        for realisation in realisations.values() {
+            verify(realisation)?;
+        }
+    }
+
+    let out_paths: StorePathSet = realisations
+        .values()
+        .map(|realisation| realisation.out_path.clone())
+        .collect();
+    let closure = compute_fs_closure_slow(src_store, &out_paths, false).await?;
+    copy_paths_full(
+        src_store,
+        dst_store,
+        &closure,
+        repair,
+        check_sigs,
+        SubstituteFlag::NoSubstitute,
+    )
+    .await?;
+
+    // The destination checks dependencies when registering, so they go first.
+    let mut registered = BTreeSet::new();
+    while registered.len() < realisations.len() {
+        let before = registered.len();
+        for (id, realisation) in realisations.iter() {
+            if registered.contains(id)
+                || !realisation
+                    .dependent_realisations
+                    .keys()
+                    .all(|dep| registered.contains(dep))
+            {
+                continue;
+            }
+            debug!("Copying realisation {} to destination", id);
+            if dst_store.query_realisation(id).await?.is_none() {
+                dst_store.register_drv_output(realisation).await?;
+            }
+            registered.insert(id.clone());
+        }
+        if registered.len() == before {
+            return Err(Error::CycleDetected);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_result_display() {
+        let mut result = BuildResult::new(BuildStatus::Built, String::new());
+        assert_eq!(result.to_string(), "Built");
+        assert_eq!(result.cpu_time(), None);
+        result.start_time = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        result.stop_time = result.start_time + Duration::from_millis(3200);
+        result.cpu_user = Some(Duration::from_millis(2100));
+        result.cpu_system = Some(Duration::from_millis(400));
+        assert_eq!(
+            result.to_string(),
+            "Built in 3.20s (2.10s user, 0.40s system)"
+        );
+        assert_eq!(result.cpu_time(), Some(Duration::from_millis(2500)));
+
+        let result = BuildResult::new(BuildStatus::PermanentFailure, "builder failed".into());
+        assert_eq!(result.to_string(), "PermanentFailure: builder failed");
     }
 }
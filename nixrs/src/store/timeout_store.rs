@@ -0,0 +1,530 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::path_info::ValidPathInfo;
+use crate::store::activity::Activity;
+use crate::store::daemon::{
+    DaemonPath, DaemonStore, GCOptions, GCResults, QueryMissingResult, TrustedFlag,
+};
+use crate::store::legacy_worker::LegacyStore;
+use crate::store::{
+    BasicDerivation, BuildMode, BuildResult, CheckSignaturesFlag, DerivedPath, DrvOutput, Error,
+    ExperimentalFeatures, Realisation, RepairFlag, Store, SubstituteFlag,
+};
+use crate::store_path::{StoreDir, StoreDirProvider, StorePath, StorePathSet};
+
+/// How long a [`TimeoutStore`] waits for each kind of operation. `None`
+/// waits forever.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StoreTimeouts {
+    /// Querying paths, path infos and missing paths.
+    pub query: Option<Duration>,
+    /// Sending and receiving NARs, build logs and substitutes.
+    pub transfer: Option<Duration>,
+    /// Building, repairing and collecting garbage.
+    pub build: Option<Duration>,
+}
+
+impl StoreTimeouts {
+    /// Don't time out at all.
+    pub fn none() -> StoreTimeouts {
+        Default::default()
+    }
+
+    /// Use `timeout` for every kind of operation.
+    pub fn all(timeout: Duration) -> StoreTimeouts {
+        StoreTimeouts {
+            query: Some(timeout),
+            transfer: Some(timeout),
+            build: Some(timeout),
+        }
+    }
+}
+
+async fn timed<T, F>(
+    poisoned: &mut bool,
+    op: &str,
+    timeout: Option<Duration>,
+    fut: F,
+) -> Result<T, Error>
+where
+    F: Future<Output = Result<T, Error>>,
+{
+    if *poisoned {
+        return Err(Error::StorePoisoned);
+    }
+    match timeout {
+        None => fut.await,
+        Some(timeout) => match tokio::time::timeout(timeout, fut).await {
+            Ok(res) => res,
+            Err(_) => {
+                *poisoned = true;
+                Err(Error::Timeout(op.into(), timeout))
+            }
+        },
+    }
+}
+
+/// Wraps a store and fails operations that take longer than its
+/// [`StoreTimeouts`] with [`Error::Timeout`].
+///
+/// An operation that timed out is dropped halfway, which leaves a
+/// connection to a remote store in an unknown state. After the first
+/// timeout every operation fails with [`Error::StorePoisoned`] and the
+/// wrapped store should be thrown away with
+/// [`into_inner`](TimeoutStore::into_inner) and reconnected.
+#[derive(Debug)]
+pub struct TimeoutStore<S> {
+    store: S,
+    timeouts: StoreTimeouts,
+    poisoned: bool,
+}
+
+impl<S> TimeoutStore<S> {
+    pub fn new(store: S, timeouts: StoreTimeouts) -> TimeoutStore<S> {
+        TimeoutStore {
+            store,
+            timeouts,
+            poisoned: false,
+        }
+    }
+
+    pub fn timeouts(&self) -> StoreTimeouts {
+        self.timeouts
+    }
+
+    /// Whether an operation timed out and the wrapped store can't be used
+    /// anymore.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+}
+
+impl<S: StoreDirProvider> StoreDirProvider for TimeoutStore<S> {
+    fn store_dir(&self) -> StoreDir {
+        self.store.store_dir()
+    }
+}
+
+#[async_trait]
+impl<S> Store for TimeoutStore<S>
+where
+    S: Store + Send,
+{
+    async fn query_valid_paths(
+        &mut self,
+        paths: &StorePathSet,
+        maybe_substitute: SubstituteFlag,
+    ) -> Result<StorePathSet, Error> {
+        timed(
+            &mut self.poisoned,
+            "querying valid paths",
+            self.timeouts.query,
+            self.store.query_valid_paths(paths, maybe_substitute),
+        )
+        .await
+    }
+
+    async fn query_path_info(&mut self, path: &StorePath) -> Result<Option<ValidPathInfo>, Error> {
+        timed(
+            &mut self.poisoned,
+            "querying path info",
+            self.timeouts.query,
+            self.store.query_path_info(path),
+        )
+        .await
+    }
+
+    async fn nar_from_path<W: AsyncWrite + fmt::Debug + Send + Unpin>(
+        &mut self,
+        path: &StorePath,
+        sink: W,
+    ) -> Result<(), Error> {
+        timed(
+            &mut self.poisoned,
+            "fetching NAR",
+            self.timeouts.transfer,
+            self.store.nar_from_path(path, sink),
+        )
+        .await
+    }
+
+    async fn add_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        info: &ValidPathInfo,
+        source: R,
+        repair: RepairFlag,
+        check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error> {
+        timed(
+            &mut self.poisoned,
+            "adding path",
+            self.timeouts.transfer,
+            self.store.add_to_store(info, source, repair, check_sigs),
+        )
+        .await
+    }
+
+    async fn build_derivation(
+        &mut self,
+        drv_path: &StorePath,
+        drv: &BasicDerivation,
+        build_mode: BuildMode,
+    ) -> Result<BuildResult, Error> {
+        timed(
+            &mut self.poisoned,
+            "building derivation",
+            self.timeouts.build,
+            self.store.build_derivation(drv_path, drv, build_mode),
+        )
+        .await
+    }
+
+    async fn build_paths(
+        &mut self,
+        drv_paths: &[DerivedPath],
+        build_mode: BuildMode,
+    ) -> Result<(), Error> {
+        timed(
+            &mut self.poisoned,
+            "building paths",
+            self.timeouts.build,
+            self.store.build_paths(drv_paths, build_mode),
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl<S> DaemonStore for TimeoutStore<S>
+where
+    S: DaemonStore + Send,
+{
+    fn is_trusted_client(&self) -> Option<TrustedFlag> {
+        self.store.is_trusted_client()
+    }
+
+    fn experimental_features(&self) -> ExperimentalFeatures {
+        self.store.experimental_features()
+    }
+
+    async fn set_options(&mut self) -> Result<(), Error> {
+        timed(
+            &mut self.poisoned,
+            "setting options",
+            self.timeouts.query,
+            self.store.set_options(),
+        )
+        .await
+    }
+
+    async fn is_valid_path(&mut self, path: &StorePath) -> Result<bool, Error> {
+        timed(
+            &mut self.poisoned,
+            "querying valid path",
+            self.timeouts.query,
+            self.store.is_valid_path(path),
+        )
+        .await
+    }
+
+    async fn add_multiple_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        source: R,
+        repair: RepairFlag,
+        check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error> {
+        timed(
+            &mut self.poisoned,
+            "adding paths",
+            self.timeouts.transfer,
+            self.store.add_multiple_to_store(source, repair, check_sigs),
+        )
+        .await
+    }
+
+    async fn query_missing(
+        &mut self,
+        targets: &[DerivedPath],
+    ) -> Result<QueryMissingResult, Error> {
+        timed(
+            &mut self.poisoned,
+            "querying missing paths",
+            self.timeouts.query,
+            self.store.query_missing(targets),
+        )
+        .await
+    }
+
+    async fn add_build_log(&mut self, drv_path: &StorePath, log: &str) -> Result<(), Error> {
+        timed(
+            &mut self.poisoned,
+            "adding build log",
+            self.timeouts.transfer,
+            self.store.add_build_log(drv_path, log),
+        )
+        .await
+    }
+
+    async fn repair_path(&mut self, path: &StorePath) -> Result<(), Error> {
+        timed(
+            &mut self.poisoned,
+            "repairing path",
+            self.timeouts.build,
+            self.store.repair_path(path),
+        )
+        .await
+    }
+
+    async fn collect_garbage(&mut self, options: &GCOptions) -> Result<GCResults, Error> {
+        timed(
+            &mut self.poisoned,
+            "collecting garbage",
+            self.timeouts.build,
+            self.store.collect_garbage(options),
+        )
+        .await
+    }
+
+    async fn query_realisation(&mut self, id: &DrvOutput) -> Result<Option<Realisation>, Error> {
+        timed(
+            &mut self.poisoned,
+            "querying realisation",
+            self.timeouts.query,
+            self.store.query_realisation(id),
+        )
+        .await
+    }
+
+    async fn register_drv_output(&mut self, realisation: &Realisation) -> Result<(), Error> {
+        timed(
+            &mut self.poisoned,
+            "registering derivation output",
+            self.timeouts.query,
+            self.store.register_drv_output(realisation),
+        )
+        .await
+    }
+
+    async fn add_indirect_root(&mut self, path: &DaemonPath) -> Result<(), Error> {
+        timed(
+            &mut self.poisoned,
+            "adding indirect root",
+            self.timeouts.query,
+            self.store.add_indirect_root(path),
+        )
+        .await
+    }
+
+    async fn find_roots(&mut self) -> Result<BTreeMap<DaemonPath, StorePath>, Error> {
+        timed(
+            &mut self.poisoned,
+            "finding roots",
+            self.timeouts.query,
+            self.store.find_roots(),
+        )
+        .await
+    }
+
+    async fn verify_store(
+        &mut self,
+        check_contents: bool,
+        repair: RepairFlag,
+    ) -> Result<bool, Error> {
+        timed(
+            &mut self.poisoned,
+            "verifying store",
+            self.timeouts.build,
+            self.store.verify_store(check_contents, repair),
+        )
+        .await
+    }
+
+    async fn optimise_store(&mut self) -> Result<(), Error> {
+        timed(
+            &mut self.poisoned,
+            "optimising store",
+            self.timeouts.build,
+            self.store.optimise_store(),
+        )
+        .await
+    }
+
+    async fn collect_garbage_streaming(
+        &mut self,
+        options: &GCOptions,
+        act: &Activity,
+    ) -> Result<GCResults, Error> {
+        timed(
+            &mut self.poisoned,
+            "collecting garbage",
+            self.timeouts.build,
+            self.store.collect_garbage_streaming(options, act),
+        )
+        .await
+    }
+
+    async fn substitute_paths(&mut self, paths: &StorePathSet) -> Result<(), Error> {
+        timed(
+            &mut self.poisoned,
+            "substituting paths",
+            self.timeouts.transfer,
+            self.store.substitute_paths(paths),
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl<S> LegacyStore for TimeoutStore<S>
+where
+    S: LegacyStore + Send,
+{
+    async fn query_valid_paths_locked(
+        &mut self,
+        paths: &StorePathSet,
+        lock: bool,
+        maybe_substitute: SubstituteFlag,
+    ) -> Result<StorePathSet, Error> {
+        timed(
+            &mut self.poisoned,
+            "querying valid paths",
+            self.timeouts.query,
+            self.store
+                .query_valid_paths_locked(paths, lock, maybe_substitute),
+        )
+        .await
+    }
+
+    async fn export_paths<W: AsyncWrite + fmt::Debug + Send + Unpin>(
+        &mut self,
+        paths: &StorePathSet,
+        sink: W,
+    ) -> Result<(), Error> {
+        timed(
+            &mut self.poisoned,
+            "exporting paths",
+            self.timeouts.transfer,
+            self.store.export_paths(paths, sink),
+        )
+        .await
+    }
+
+    async fn import_paths<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        source: R,
+    ) -> Result<(), Error> {
+        timed(
+            &mut self.poisoned,
+            "importing paths",
+            self.timeouts.transfer,
+            self.store.import_paths(source),
+        )
+        .await
+    }
+
+    async fn query_closure(
+        &mut self,
+        paths: &StorePathSet,
+        include_outputs: bool,
+    ) -> Result<StorePathSet, Error> {
+        timed(
+            &mut self.poisoned,
+            "querying closure",
+            self.timeouts.query,
+            self.store.query_closure(paths, include_outputs),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use super::*;
+    use crate::store::assert_store::AssertStore;
+
+    /// A store whose queries never return, like a hung daemon.
+    struct HangingStore;
+
+    impl StoreDirProvider for HangingStore {
+        fn store_dir(&self) -> StoreDir {
+            StoreDir::default()
+        }
+    }
+
+    #[async_trait]
+    impl Store for HangingStore {
+        async fn query_path_info(
+            &mut self,
+            _path: &StorePath,
+        ) -> Result<Option<ValidPathInfo>, Error> {
+            futures::future::pending().await
+        }
+
+        async fn nar_from_path<W: AsyncWrite + fmt::Debug + Send + Unpin>(
+            &mut self,
+            _path: &StorePath,
+            _sink: W,
+        ) -> Result<(), Error> {
+            futures::future::pending().await
+        }
+
+        async fn add_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+            &mut self,
+            _info: &ValidPathInfo,
+            _source: R,
+            _repair: RepairFlag,
+            _check_sigs: CheckSignaturesFlag,
+        ) -> Result<(), Error> {
+            futures::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_timeout_poisons_store() {
+        let timeouts = StoreTimeouts {
+            query: Some(Duration::from_millis(10)),
+            ..StoreTimeouts::none()
+        };
+        let mut store = TimeoutStore::new(HangingStore, timeouts);
+        let path = StorePath::test_from_seed("foo");
+        let res = store.query_path_info(&path).await;
+        assert_matches!(res, Err(Error::Timeout(_, _)));
+        assert!(store.is_poisoned());
+
+        let res = store.query_path_info(&path).await;
+        assert_matches!(res, Err(Error::StorePoisoned));
+    }
+
+    #[tokio::test]
+    async fn test_fast_operations_pass() {
+        let paths = StorePathSet::new();
+        let mut store = TimeoutStore::new(
+            AssertStore::assert_query_valid_paths(
+                None,
+                &paths,
+                SubstituteFlag::NoSubstitute,
+                Ok(StorePathSet::new()),
+            ),
+            StoreTimeouts::all(Duration::from_secs(10)),
+        );
+        let res = store
+            .query_valid_paths(&paths, SubstituteFlag::NoSubstitute)
+            .await
+            .unwrap();
+        assert!(res.is_empty());
+        assert!(!store.is_poisoned());
+        store.into_inner().assert_eq();
+    }
+}
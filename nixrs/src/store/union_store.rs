@@ -0,0 +1,387 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::path_info::ValidPathInfo;
+use crate::store::activity::Activity;
+use crate::store::daemon::{
+    DaemonPath, DaemonStore, GCOptions, GCResults, QueryMissingResult, TrustedFlag,
+};
+use crate::store::{
+    BasicDerivation, BuildMode, BuildResult, CheckSignaturesFlag, DerivedPath, DrvOutput, Error,
+    ExperimentalFeatures, Realisation, RepairFlag, Store, SubstituteFlag,
+};
+use crate::store_path::{StoreDir, StoreDirProvider, StorePath, StorePathSet, StorePathSetExt};
+
+/// One of the two layers of a [`UnionStore`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnionLayer {
+    First,
+    Second,
+}
+
+/// Combined view of two stores with the same store dir.
+///
+/// Paths are looked up in `first` and then in `second`, so `first` shadows
+/// `second` for paths they both have. Adding paths and building goes to the
+/// layer chosen as writable. This is the same arrangement as Nix's
+/// `local-overlay` store and lets a local store and an upstream cache be
+/// served as one.
+#[derive(Debug)]
+pub struct UnionStore<A, B> {
+    first: A,
+    second: B,
+    writable: UnionLayer,
+}
+
+impl<A, B> UnionStore<A, B> {
+    pub fn new(first: A, second: B, writable: UnionLayer) -> UnionStore<A, B> {
+        UnionStore {
+            first,
+            second,
+            writable,
+        }
+    }
+
+    pub fn writable(&self) -> UnionLayer {
+        self.writable
+    }
+
+    pub fn into_inner(self) -> (A, B) {
+        (self.first, self.second)
+    }
+}
+
+impl<A: StoreDirProvider, B> StoreDirProvider for UnionStore<A, B> {
+    fn store_dir(&self) -> StoreDir {
+        self.first.store_dir()
+    }
+}
+
+#[async_trait]
+impl<A, B> Store for UnionStore<A, B>
+where
+    A: Store + Send,
+    B: Store + Send,
+{
+    async fn query_valid_paths(
+        &mut self,
+        paths: &StorePathSet,
+        maybe_substitute: SubstituteFlag,
+    ) -> Result<StorePathSet, Error> {
+        let mut valid = self
+            .first
+            .query_valid_paths(paths, maybe_substitute)
+            .await?;
+        let mut rest = StorePathSet::new();
+        paths.difference_into(&valid, &mut rest);
+        if !rest.is_empty() {
+            valid.extend(
+                self.second
+                    .query_valid_paths(&rest, maybe_substitute)
+                    .await?,
+            );
+        }
+        Ok(valid)
+    }
+
+    async fn query_path_info(&mut self, path: &StorePath) -> Result<Option<ValidPathInfo>, Error> {
+        match self.first.query_path_info(path).await? {
+            Some(info) => Ok(Some(info)),
+            None => self.second.query_path_info(path).await,
+        }
+    }
+
+    async fn nar_from_path<W: AsyncWrite + fmt::Debug + Send + Unpin>(
+        &mut self,
+        path: &StorePath,
+        sink: W,
+    ) -> Result<(), Error> {
+        if self.first.query_path_info(path).await?.is_some() {
+            self.first.nar_from_path(path, sink).await
+        } else {
+            self.second.nar_from_path(path, sink).await
+        }
+    }
+
+    async fn add_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        info: &ValidPathInfo,
+        source: R,
+        repair: RepairFlag,
+        check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error> {
+        match self.writable {
+            UnionLayer::First => {
+                self.first
+                    .add_to_store(info, source, repair, check_sigs)
+                    .await
+            }
+            UnionLayer::Second => {
+                self.second
+                    .add_to_store(info, source, repair, check_sigs)
+                    .await
+            }
+        }
+    }
+
+    async fn build_derivation(
+        &mut self,
+        drv_path: &StorePath,
+        drv: &BasicDerivation,
+        build_mode: BuildMode,
+    ) -> Result<BuildResult, Error> {
+        match self.writable {
+            UnionLayer::First => self.first.build_derivation(drv_path, drv, build_mode).await,
+            UnionLayer::Second => {
+                self.second
+                    .build_derivation(drv_path, drv, build_mode)
+                    .await
+            }
+        }
+    }
+
+    async fn build_paths(
+        &mut self,
+        drv_paths: &[DerivedPath],
+        build_mode: BuildMode,
+    ) -> Result<(), Error> {
+        match self.writable {
+            UnionLayer::First => self.first.build_paths(drv_paths, build_mode).await,
+            UnionLayer::Second => self.second.build_paths(drv_paths, build_mode).await,
+        }
+    }
+}
+
+#[async_trait]
+impl<A, B> DaemonStore for UnionStore<A, B>
+where
+    A: DaemonStore + Send,
+    B: DaemonStore + Send,
+{
+    fn is_trusted_client(&self) -> Option<TrustedFlag> {
+        match self.writable {
+            UnionLayer::First => self.first.is_trusted_client(),
+            UnionLayer::Second => self.second.is_trusted_client(),
+        }
+    }
+
+    /// Only the features both layers have turned on.
+    fn experimental_features(&self) -> ExperimentalFeatures {
+        self.first
+            .experimental_features()
+            .intersect(&self.second.experimental_features())
+    }
+
+    async fn set_options(&mut self) -> Result<(), Error> {
+        self.first.set_options().await?;
+        self.second.set_options().await
+    }
+
+    async fn is_valid_path(&mut self, path: &StorePath) -> Result<bool, Error> {
+        Ok(self.first.is_valid_path(path).await? || self.second.is_valid_path(path).await?)
+    }
+
+    async fn add_multiple_to_store<R: AsyncRead + fmt::Debug + Send + Unpin>(
+        &mut self,
+        source: R,
+        repair: RepairFlag,
+        check_sigs: CheckSignaturesFlag,
+    ) -> Result<(), Error> {
+        match self.writable {
+            UnionLayer::First => {
+                self.first
+                    .add_multiple_to_store(source, repair, check_sigs)
+                    .await
+            }
+            UnionLayer::Second => {
+                self.second
+                    .add_multiple_to_store(source, repair, check_sigs)
+                    .await
+            }
+        }
+    }
+
+    async fn query_missing(
+        &mut self,
+        targets: &[DerivedPath],
+    ) -> Result<QueryMissingResult, Error> {
+        match self.writable {
+            UnionLayer::First => self.first.query_missing(targets).await,
+            UnionLayer::Second => self.second.query_missing(targets).await,
+        }
+    }
+
+    async fn add_build_log(&mut self, drv_path: &StorePath, log: &str) -> Result<(), Error> {
+        match self.writable {
+            UnionLayer::First => self.first.add_build_log(drv_path, log).await,
+            UnionLayer::Second => self.second.add_build_log(drv_path, log).await,
+        }
+    }
+
+    /// Only the writable layer is collected.
+    async fn collect_garbage(&mut self, options: &GCOptions) -> Result<GCResults, Error> {
+        match self.writable {
+            UnionLayer::First => self.first.collect_garbage(options).await,
+            UnionLayer::Second => self.second.collect_garbage(options).await,
+        }
+    }
+
+    async fn collect_garbage_streaming(
+        &mut self,
+        options: &GCOptions,
+        act: &Activity,
+    ) -> Result<GCResults, Error> {
+        match self.writable {
+            UnionLayer::First => self.first.collect_garbage_streaming(options, act).await,
+            UnionLayer::Second => self.second.collect_garbage_streaming(options, act).await,
+        }
+    }
+
+    async fn query_realisation(&mut self, id: &DrvOutput) -> Result<Option<Realisation>, Error> {
+        match self.first.query_realisation(id).await? {
+            Some(realisation) => Ok(Some(realisation)),
+            None => self.second.query_realisation(id).await,
+        }
+    }
+
+    async fn register_drv_output(&mut self, realisation: &Realisation) -> Result<(), Error> {
+        match self.writable {
+            UnionLayer::First => self.first.register_drv_output(realisation).await,
+            UnionLayer::Second => self.second.register_drv_output(realisation).await,
+        }
+    }
+
+    async fn add_indirect_root(&mut self, path: &DaemonPath) -> Result<(), Error> {
+        match self.writable {
+            UnionLayer::First => self.first.add_indirect_root(path).await,
+            UnionLayer::Second => self.second.add_indirect_root(path).await,
+        }
+    }
+
+    /// The roots of both layers, with `first` shadowing `second` for links
+    /// they both have.
+    async fn find_roots(&mut self) -> Result<BTreeMap<DaemonPath, StorePath>, Error> {
+        let mut roots = self.second.find_roots().await?;
+        roots.extend(self.first.find_roots().await?);
+        Ok(roots)
+    }
+
+    async fn verify_store(
+        &mut self,
+        check_contents: bool,
+        repair: RepairFlag,
+    ) -> Result<bool, Error> {
+        match self.writable {
+            UnionLayer::First => self.first.verify_store(check_contents, repair).await,
+            UnionLayer::Second => self.second.verify_store(check_contents, repair).await,
+        }
+    }
+
+    async fn optimise_store(&mut self) -> Result<(), Error> {
+        match self.writable {
+            UnionLayer::First => self.first.optimise_store().await,
+            UnionLayer::Second => self.second.optimise_store().await,
+        }
+    }
+
+    async fn repair_path(&mut self, path: &StorePath) -> Result<(), Error> {
+        match self.writable {
+            UnionLayer::First => self.first.repair_path(path).await,
+            UnionLayer::Second => self.second.repair_path(path).await,
+        }
+    }
+
+    async fn substitute_paths(&mut self, paths: &StorePathSet) -> Result<(), Error> {
+        match self.writable {
+            UnionLayer::First => self.first.substitute_paths(paths).await,
+            UnionLayer::Second => self.second.substitute_paths(paths).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::{Algorithm, Hash};
+    use crate::store::assert_store::AssertStore;
+    use crate::store::call_store::{call_all, CallStore, DAEMON_STORE_METHODS};
+    use crate::store::{ExperimentalFeature, MemoryStore};
+
+    #[tokio::test]
+    async fn test_query_path_info_falls_back() {
+        let path = StorePath::test_from_seed("lower");
+        let info = ValidPathInfo::new(path.clone(), Hash::test_from_seed(Algorithm::SHA256, "nar"));
+        let first = AssertStore::assert_query_path_info(None, &path, Ok(None));
+        let second = AssertStore::assert_query_path_info(None, &path, Ok(Some(info.clone())));
+        let mut store = UnionStore::new(first, second, UnionLayer::First);
+        let res = store.query_path_info(&path).await.unwrap();
+        assert_eq!(res, Some(info));
+        let (first, second) = store.into_inner();
+        first.assert_eq();
+        second.assert_eq();
+    }
+
+    #[tokio::test]
+    async fn test_query_valid_paths_merges_layers() {
+        let upper = StorePath::test_from_seed("upper");
+        let lower = StorePath::test_from_seed("lower");
+        let paths: StorePathSet = [upper.clone(), lower.clone()].into_iter().collect();
+        let first = AssertStore::assert_query_valid_paths(
+            None,
+            &paths,
+            SubstituteFlag::NoSubstitute,
+            Ok([upper].into_iter().collect()),
+        );
+        let second = AssertStore::assert_query_valid_paths(
+            None,
+            &[lower].into_iter().collect(),
+            SubstituteFlag::NoSubstitute,
+            Ok(Default::default()),
+        );
+        let mut store = UnionStore::new(first, second, UnionLayer::First);
+        let res = store
+            .query_valid_paths(&paths, SubstituteFlag::NoSubstitute)
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 1);
+        let (first, second) = store.into_inner();
+        first.assert_eq();
+        second.assert_eq();
+    }
+
+    #[tokio::test]
+    async fn test_forwards_daemon_store() {
+        let mut store = UnionStore::new(
+            CallStore::default(),
+            CallStore::default(),
+            UnionLayer::Second,
+        );
+        for (method, res) in call_all(&mut store).await {
+            assert!(res.is_ok(), "{method}: {res:?}");
+        }
+        let (first, second) = store.into_inner();
+        assert!(second.called_all(DAEMON_STORE_METHODS));
+        assert_eq!(
+            first.calls,
+            [
+                "set_options",
+                "is_valid_path",
+                "query_realisation",
+                "find_roots"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_experimental_features_intersect() {
+        let store = UnionStore::new(MemoryStore::new(), MemoryStore::new(), UnionLayer::First);
+        assert!(store
+            .experimental_features()
+            .is_enabled(ExperimentalFeature::CaDerivations));
+        let store = UnionStore::new(MemoryStore::new(), CallStore::default(), UnionLayer::First);
+        assert_eq!(store.experimental_features(), ExperimentalFeatures::new());
+    }
+}
@@ -0,0 +1,248 @@
+use async_stream::try_stream;
+use futures::{Stream, TryStreamExt};
+
+use crate::hash::{Hash, HashSink};
+use crate::store_path::{StoreDir, StorePath, StorePathSet};
+
+use super::activity::{Activity, ResultKind};
+use super::{Error, Store};
+
+/// Problem with a single path found by a [`Verifier`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyFinding {
+    /// The path should be valid but the store has no path info for it.
+    Missing(StorePath),
+    /// The path is valid but its NAR could not be read.
+    Unreadable { path: StorePath, reason: String },
+    HashMismatch {
+        path: StorePath,
+        expected: Hash,
+        actual: Hash,
+    },
+    SizeMismatch {
+        path: StorePath,
+        expected: u64,
+        actual: u64,
+    },
+    /// The path info of `path` lists `reference` but that is not valid.
+    MissingReference {
+        path: StorePath,
+        reference: StorePath,
+    },
+    /// The path has data in the store but was never registered as valid.
+    Unreferenced(StorePath),
+}
+
+impl VerifyFinding {
+    pub fn path(&self) -> &StorePath {
+        match self {
+            VerifyFinding::Missing(path) | VerifyFinding::Unreferenced(path) => path,
+            VerifyFinding::Unreadable { path, .. }
+            | VerifyFinding::HashMismatch { path, .. }
+            | VerifyFinding::SizeMismatch { path, .. }
+            | VerifyFinding::MissingReference { path, .. } => path,
+        }
+    }
+
+    /// Whether the contents of the path are damaged and it needs repairing,
+    /// as opposed to only its metadata being inconsistent.
+    pub fn is_corrupted(&self) -> bool {
+        matches!(
+            self,
+            VerifyFinding::Unreadable { .. }
+                | VerifyFinding::HashMismatch { .. }
+                | VerifyFinding::SizeMismatch { .. }
+        )
+    }
+
+    pub fn describe(&self, store_dir: &StoreDir) -> String {
+        let path = store_dir.print_path(self.path());
+        match self {
+            VerifyFinding::Missing(_) => format!("path '{}' is not valid", path),
+            VerifyFinding::Unreadable { reason, .. } => {
+                format!("cannot read path '{}': {}", path, reason)
+            }
+            VerifyFinding::HashMismatch {
+                expected, actual, ..
+            } => format!(
+                "path '{}' was modified! expected hash '{}', got '{}'",
+                path,
+                expected.to_sri(),
+                actual.to_sri()
+            ),
+            VerifyFinding::SizeMismatch {
+                expected, actual, ..
+            } => format!(
+                "path '{}' should be {} bytes but its NAR is {} bytes",
+                path, expected, actual
+            ),
+            VerifyFinding::MissingReference { reference, .. } => format!(
+                "path '{}' references missing path '{}'",
+                path,
+                store_dir.print_path(reference)
+            ),
+            VerifyFinding::Unreferenced(_) => {
+                format!("path '{}' is in the store but not valid", path)
+            }
+        }
+    }
+}
+
+/// Checks that a set of valid paths is consistent, like
+/// `nix-store --verify`.
+///
+/// Every path must have a path info and all its references must be valid.
+/// With [`check_contents`](Verifier::check_contents) the NAR of every path
+/// is also read and hashed. Problems are reported as [`VerifyFinding`]s
+/// while the paths are checked.
+///
+/// ```
+/// # use futures::TryStreamExt;
+/// # use nixrs::store::{Activity, MemoryStore, Verifier};
+/// # #[tokio::main]
+/// # async fn main() {
+/// let mut store = MemoryStore::new();
+/// let act = Activity::disabled();
+/// let findings: Vec<_> = Verifier::new(store.paths())
+///     .check_contents(true)
+///     .run(&mut store, &act)
+///     .try_collect()
+///     .await
+///     .unwrap();
+/// assert!(findings.is_empty());
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Verifier {
+    paths: StorePathSet,
+    present: StorePathSet,
+    check_contents: bool,
+}
+
+impl Verifier {
+    pub fn new(paths: StorePathSet) -> Verifier {
+        Verifier {
+            paths,
+            ..Default::default()
+        }
+    }
+
+    pub fn check_contents(mut self, check_contents: bool) -> Self {
+        self.check_contents = check_contents;
+        self
+    }
+
+    /// Paths there is data for in the backing storage, like the entries of
+    /// the store directory. Those that are not valid are reported as
+    /// [`VerifyFinding::Unreferenced`].
+    pub fn present(mut self, present: StorePathSet) -> Self {
+        self.present = present;
+        self
+    }
+
+    /// Checks the paths one at a time.
+    ///
+    /// Progress is reported to `act` and paths with damaged contents also
+    /// as [`ResultKind::CorruptedPath`]. The stream only fails when talking
+    /// to the store fails.
+    pub fn run<'a, S>(
+        self,
+        store: &'a mut S,
+        act: &'a Activity,
+    ) -> impl Stream<Item = Result<VerifyFinding, Error>> + 'a
+    where
+        S: Store + Send,
+    {
+        try_stream! {
+            let expected = self.paths.len() as u64;
+            let mut done = 0;
+            let mut failed = 0;
+            act.progress(done, expected, 0, failed);
+            for path in self.present.difference(&self.paths) {
+                yield VerifyFinding::Unreferenced(path.clone());
+            }
+            let mut valid = self.paths.clone();
+            for path in self.paths.iter() {
+                let mut found = Vec::new();
+                match store.query_path_info(path).await? {
+                    None => found.push(VerifyFinding::Missing(path.clone())),
+                    Some(info) => {
+                        for reference in info.references.iter() {
+                            if valid.contains(reference) {
+                                continue;
+                            }
+                            if store.query_path_info(reference).await?.is_some() {
+                                valid.insert(reference.clone());
+                            } else {
+                                found.push(VerifyFinding::MissingReference {
+                                    path: path.clone(),
+                                    reference: reference.clone(),
+                                });
+                            }
+                        }
+                        if self.check_contents {
+                            found.extend(check_nar(store, path, &info.nar_hash, info.nar_size).await);
+                        }
+                    }
+                }
+                done += 1;
+                if !found.is_empty() {
+                    failed += 1;
+                }
+                for finding in found {
+                    if finding.is_corrupted() {
+                        act.report(ResultKind::CorruptedPath(store.store_dir().print_path(path)));
+                    }
+                    yield finding;
+                }
+                act.progress(done, expected, 0, failed);
+            }
+        }
+    }
+
+    /// Like [`run`](Verifier::run) but waits for all the findings.
+    pub async fn collect<S>(
+        self,
+        store: &mut S,
+        act: &Activity,
+    ) -> Result<Vec<VerifyFinding>, Error>
+    where
+        S: Store + Send,
+    {
+        self.run(store, act).try_collect().await
+    }
+}
+
+async fn check_nar<S>(
+    store: &mut S,
+    path: &StorePath,
+    nar_hash: &Hash,
+    nar_size: u64,
+) -> Option<VerifyFinding>
+where
+    S: Store + Send,
+{
+    let mut sink = HashSink::new(nar_hash.algorithm());
+    if let Err(err) = store.nar_from_path(path, &mut sink).await {
+        return Some(VerifyFinding::Unreadable {
+            path: path.clone(),
+            reason: err.to_string(),
+        });
+    }
+    let (size, actual) = sink.finish();
+    if actual != *nar_hash {
+        Some(VerifyFinding::HashMismatch {
+            path: path.clone(),
+            expected: *nar_hash,
+            actual,
+        })
+    } else if nar_size != 0 && size != nar_size {
+        Some(VerifyFinding::SizeMismatch {
+            path: path.clone(),
+            expected: nar_size,
+            actual: size,
+        })
+    } else {
+        None
+    }
+}
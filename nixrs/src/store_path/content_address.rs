@@ -155,6 +155,22 @@ impl ContentAddress {
             Err(ParseContentAddressError::InvalidForm(s.to_string()))
         }
     }
+
+    /// Parse the `ca` field of a path info where an empty string means that
+    /// the path is not content addressed.
+    pub fn parse_opt(s: &str) -> Result<Option<ContentAddress>, ParseContentAddressError> {
+        if s.is_empty() {
+            Ok(None)
+        } else {
+            Self::parse(s).map(Some)
+        }
+    }
+
+    /// Print an optional content address the way it is stored in the `ca`
+    /// field of a path info.
+    pub fn print_opt(ca: Option<&ContentAddress>) -> String {
+        ca.map(|ca| ca.to_string()).unwrap_or_default()
+    }
 }
 
 impl fmt::Display for ContentAddress {
@@ -309,6 +325,22 @@ impl ContentAddressWithReferences {
             Fixed(foi) => ContentAddressMethod::Fixed(foi.method),
         }
     }
+
+    pub fn hash(&self) -> Hash {
+        use ContentAddressWithReferences::*;
+        match self {
+            Text(ti) => ti.hash,
+            Fixed(foi) => foi.hash,
+        }
+    }
+
+    /// The content address without the references.
+    pub fn content_address(&self) -> ContentAddress {
+        ContentAddress {
+            method: self.method(),
+            hash: self.hash(),
+        }
+    }
 }
 
 #[cfg(any(test, feature = "test"))]
@@ -347,9 +379,12 @@ pub mod proptest {
         type Strategy = BoxedStrategy<ContentAddress>;
 
         fn arbitrary_with(_args: Self::Parameters) -> Self::Strategy {
-            (any::<ContentAddressMethod>(), any::<hash::Hash>())
-                .prop_map(|(method, hash)| ContentAddress { method, hash })
-                .boxed()
+            prop_oneof![
+                any_with::<hash::Hash>(Algorithm::SHA256).prop_map(ContentAddress::text),
+                (any::<FileIngestionMethod>(), any::<hash::Hash>())
+                    .prop_map(|(method, hash)| ContentAddress::fixed(method, hash))
+            ]
+            .boxed()
         }
     }
 
@@ -369,6 +404,7 @@ pub mod proptest {
 mod tests {
     use super::*;
     use crate::hash;
+    use ::proptest::prelude::*;
     use pretty_assertions::assert_eq;
 
     #[test]
@@ -479,6 +515,17 @@ mod tests {
         assert_eq!(content_address, v.parse().unwrap());
     }
 
+    #[cfg(feature = "blake3")]
+    #[test]
+    fn test_fixed_content_address_blake3() {
+        let hash = hash::digest(Algorithm::BLAKE3, "abc");
+        let content_address = ContentAddress::fixed(FileIngestionMethod::Recursive, hash);
+
+        let v = "fixed:r:blake3:11cxppanr71mzl1xnyax8rccaj5milx2fx9vnvzk6la672nb6dv4";
+        assert_eq!(content_address.to_string(), v);
+        assert_eq!(content_address, v.parse().unwrap());
+    }
+
     #[test]
     fn test_content_address_error() {
         assert_eq!(
@@ -518,4 +565,52 @@ mod tests {
             "text:sha1:kpcd173cq987hw957sx6m0868wv3x6d9".parse::<ContentAddress>()
         );
     }
+
+    #[test]
+    fn test_content_address_opt() {
+        assert_eq!(Ok(None), ContentAddress::parse_opt(""));
+        assert_eq!("", ContentAddress::print_opt(None));
+
+        let v = "fixed:r:sha256:1b8m03r63zqhnjf7l5wnldhh7c134ap5vpj0850ymkq1iyzicy5s";
+        let ca = ContentAddress::parse_opt(v).unwrap();
+        assert_eq!(
+            Some(ContentAddress::fixed(
+                FileIngestionMethod::Recursive,
+                hash::digest(Algorithm::SHA256, "abc")
+            )),
+            ca
+        );
+        assert_eq!(v, ContentAddress::print_opt(ca.as_ref()));
+    }
+
+    #[test]
+    fn test_content_address_wrong_base16_length() {
+        assert_eq!(
+            Err(ParseContentAddressError::InvalidHash(
+                ParseHashError::WrongHashLength(
+                    Algorithm::SHA1,
+                    "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".into()
+                )
+            )),
+            "fixed:sha1:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+                .parse::<ContentAddress>()
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn proptest_content_address_round_trip(ca in any::<ContentAddress>()) {
+            let s = ca.to_string();
+            prop_assert_eq!(Ok(ca), s.parse::<ContentAddress>());
+            prop_assert_eq!(Ok(Some(ca)), ContentAddress::parse_opt(&s));
+            prop_assert_eq!(s, ContentAddress::print_opt(Some(&ca)));
+        }
+
+        #[test]
+        fn proptest_content_address_without_refs(ca in any::<ContentAddress>()) {
+            let cawr = ContentAddressWithReferences::without_refs(ca);
+            prop_assert_eq!(ca.method, cawr.method());
+            prop_assert_eq!(ca, cawr.content_address());
+        }
+    }
 }
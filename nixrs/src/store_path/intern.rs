@@ -0,0 +1,51 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use lazy_static::lazy_static;
+
+/// Sets smaller than this are never purged.
+const MIN_PURGE: usize = 1024;
+
+#[derive(Debug, Default)]
+struct Names {
+    names: HashSet<Arc<str>>,
+    purge_at: usize,
+}
+
+lazy_static! {
+    static ref NAMES: Mutex<Names> = Mutex::new(Names::default());
+}
+
+/// The copy of `name` every store path with that name shares, made the
+/// first time it is seen.
+///
+/// Names no path holds on to any more are forgotten once the set has
+/// doubled since the last time that was done, so the interner only keeps
+/// what is in use, give or take.
+pub(crate) fn intern(name: &str) -> Arc<str> {
+    let mut names = NAMES.lock().unwrap();
+    if let Some(name) = names.names.get(name) {
+        return name.clone();
+    }
+    if names.names.len() >= names.purge_at {
+        names.names.retain(|name| Arc::strong_count(name) > 1);
+        names.purge_at = (names.names.len() * 2).max(MIN_PURGE);
+    }
+    let name: Arc<str> = name.into();
+    names.names.insert(name.clone());
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store_path::{StorePath, StorePathName};
+
+    #[test]
+    fn test_shared_names() {
+        let a = StorePathName::new("interned-1.0").unwrap();
+        let b = StorePath::test_from_seed("interned-1.0");
+        assert!(Arc::ptr_eq(&a.0, &b.name.0));
+        assert!(Arc::ptr_eq(&intern("interned-1.0"), &a.0));
+    }
+}
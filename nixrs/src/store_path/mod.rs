@@ -1,5 +1,8 @@
 mod content_address;
+#[cfg(feature = "intern-store-paths")]
+mod intern;
 mod path;
+mod remap;
 mod store_dir;
 
 pub use content_address::{
@@ -7,9 +10,10 @@ pub use content_address::{
     FixedOutputInfo, ParseContentAddressError, StoreReferences, TextInfo,
 };
 pub use path::{
-    is_name, ParseStorePathError, ReadStorePathError, StorePath, StorePathHash, StorePathName,
-    StorePathSet, StorePathSetExt, STORE_PATH_HASH_BYTES, STORE_PATH_HASH_CHARS,
+    compress_hash, is_name, ParseStorePathError, ReadStorePathError, StorePath, StorePathHash,
+    StorePathName, StorePathSet, StorePathSetExt, STORE_PATH_HASH_BYTES, STORE_PATH_HASH_CHARS,
 };
+pub use remap::{RemapStoreDirError, StoreDirRemap};
 pub use store_dir::{StoreDir, StoreDirProvider};
 
 #[cfg(any(test, feature = "test"))]
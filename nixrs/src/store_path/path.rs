@@ -3,6 +3,7 @@ use std::convert::TryFrom;
 use std::fmt;
 use std::ops::Deref;
 use std::path::Path;
+use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
@@ -11,23 +12,52 @@ use super::StoreDir;
 use crate::path::clean_path;
 use crate::{base32, hash};
 
+fn is_name_char(i: usize, c: char) -> bool {
+    c.is_ascii_alphanumeric()
+        || c == '+'
+        || c == '-'
+        || c == '_'
+        || c == '?'
+        || c == '='
+        || (i > 0 && c == '.')
+}
+
+/// The byte offset and value of the first character in `s` that is not
+/// allowed in a store path name.
+fn find_bad_char(s: &str) -> Option<(usize, char)> {
+    s.char_indices().find(|(i, c)| !is_name_char(*i, *c))
+}
+
+/// Fold `hash` into `N` bytes by XOR-ing every byte into position
+/// `i % N`, the way Nix shortens the SHA-256 of a store path fingerprint to
+/// the 160 bits of a store path hash.
+pub fn compress_hash<const N: usize>(hash: &[u8]) -> [u8; N] {
+    let mut bytes = [0u8; N];
+    for (i, item) in hash.iter().enumerate() {
+        bytes[i % N] ^= item;
+    }
+    bytes
+}
+
 pub fn is_name(s: &str) -> bool {
-    !s.is_empty()
-        && s.char_indices().all(|(i, c)| {
-            c.is_ascii_alphanumeric()
-                || c == '+'
-                || c == '-'
-                || c == '_'
-                || c == '?'
-                || c == '='
-                || (i > 0 && c == '.')
-        })
+    !s.is_empty() && find_bad_char(s).is_none()
 }
 
 pub type StorePathSet = BTreeSet<StorePath>;
 
 pub trait StorePathSetExt {
     fn join(&self) -> String;
+
+    /// Add the paths in `self` that are not in `other` to `out`.
+    ///
+    /// When `out` is empty it is built in one go from the sorted difference
+    /// instead of inserting path by path, and a set kept around between
+    /// calls saves allocating a new one each time.
+    fn difference_into(&self, other: &StorePathSet, out: &mut StorePathSet);
+
+    /// Remove every path that is in `present`, leaving the ones missing from
+    /// it, without cloning any of them.
+    fn retain_missing(&mut self, present: &StorePathSet);
 }
 
 impl StorePathSetExt for StorePathSet {
@@ -41,6 +71,26 @@ impl StorePathSetExt for StorePathSet {
         }
         ret
     }
+
+    fn difference_into(&self, other: &StorePathSet, out: &mut StorePathSet) {
+        let missing = self.difference(other).cloned();
+        if out.is_empty() {
+            *out = missing.collect();
+        } else {
+            out.extend(missing);
+        }
+    }
+
+    fn retain_missing(&mut self, present: &StorePathSet) {
+        // Look up the paths of whichever set is smaller in the other.
+        if present.len() < self.len() {
+            for path in present {
+                self.remove(path);
+            }
+        } else {
+            self.retain(|path| !present.contains(path));
+        }
+    }
 }
 
 #[macro_export]
@@ -67,8 +117,8 @@ pub enum ParseStorePathError {
     StorePathNameEmpty,
     #[error("store path name is longer than 211 characters")]
     StorePathNameTooLong,
-    #[error("store path name '{0}' contains forbidden character")]
-    BadStorePathName(String),
+    #[error("store path name '{0}' contains forbidden character '{2}' at offset {1}")]
+    BadStorePathName(String, usize, char),
 }
 
 #[derive(Error, Debug)]
@@ -127,6 +177,12 @@ impl StorePath {
         })
     }
 
+    /// The store path for a fingerprint made with
+    /// [`StoreDir::fingerprint`].
+    pub fn from_fingerprint(fingerprint: &str, name: &str) -> Result<Self, ParseStorePathError> {
+        Self::from_hash(&hash::digest(hash::Algorithm::SHA256, fingerprint), name)
+    }
+
     pub fn new_from_base_name(base_name: &str) -> Result<Self, ParseStorePathError> {
         if base_name.len() < STORE_PATH_HASH_CHARS + 1
             || base_name.as_bytes()[STORE_PATH_HASH_CHARS] != b'-'
@@ -190,12 +246,7 @@ impl StorePathHash {
     }
 
     pub fn new_from_hash(hash: &hash::Hash) -> Self {
-        let mut bytes = [0u8; STORE_PATH_HASH_BYTES];
-        for (i, item) in hash.as_ref().iter().enumerate() {
-            let idx = i % STORE_PATH_HASH_BYTES;
-            bytes[idx] ^= item;
-        }
-        StorePathHash(bytes)
+        StorePathHash(compress_hash(hash.as_ref()))
     }
 
     pub fn hash(&self) -> &[u8; STORE_PATH_HASH_BYTES] {
@@ -251,8 +302,14 @@ impl From<StorePathHash> for [u8; STORE_PATH_HASH_BYTES] {
     }
 }
 
+/// The name part of a store path, after the hash.
+///
+/// Clones share the same string. With the `intern-store-paths` feature
+/// every name with the same contents shares one string as well, which
+/// saves a lot of memory for large closures where the same names come up
+/// over and over.
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
-pub struct StorePathName(String);
+pub struct StorePathName(pub(super) Arc<str>);
 
 impl StorePathName {
     pub fn new(s: &str) -> Result<Self, ParseStorePathError> {
@@ -264,11 +321,27 @@ impl StorePathName {
             return Err(ParseStorePathError::StorePathNameTooLong);
         }
 
-        if !is_name(s) {
-            return Err(ParseStorePathError::BadStorePathName(s.to_string()));
+        if let Some((offset, c)) = find_bad_char(s) {
+            return Err(ParseStorePathError::BadStorePathName(
+                s.to_string(),
+                offset,
+                c,
+            ));
         }
 
-        Ok(Self(s.to_string()))
+        Ok(Self(new_name(s)))
+    }
+
+    /// Turn an arbitrary string into a valid store path name by replacing
+    /// every forbidden character with `_` and truncating it to 211
+    /// characters. Only fails when `s` is empty.
+    pub fn sanitize(s: &str) -> Result<Self, ParseStorePathError> {
+        let mut name: String = s
+            .char_indices()
+            .map(|(i, c)| if is_name_char(i, c) { c } else { '_' })
+            .collect();
+        name.truncate(211);
+        Self::new(&name)
     }
 
     pub fn name(&self) -> &str {
@@ -276,6 +349,16 @@ impl StorePathName {
     }
 }
 
+#[cfg(feature = "intern-store-paths")]
+fn new_name(s: &str) -> Arc<str> {
+    super::intern::intern(s)
+}
+
+#[cfg(not(feature = "intern-store-paths"))]
+fn new_name(s: &str) -> Arc<str> {
+    s.into()
+}
+
 impl fmt::Display for StorePathName {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_str(&self.0)
@@ -296,6 +379,19 @@ impl Deref for StorePathName {
     }
 }
 
+#[cfg(any(test, feature = "test"))]
+impl StorePath {
+    /// A store path that is derived from `seed` alone, so that tests get the
+    /// same path on every run.
+    ///
+    /// The seed is also used as the name of the path and so must be a valid
+    /// store path name.
+    pub fn test_from_seed(seed: &str) -> StorePath {
+        let hash = hash::Hash::test_from_seed(hash::Algorithm::SHA256, seed);
+        StorePath::from_hash(&hash, seed).expect("test seed is not a valid store path name")
+    }
+}
+
 #[cfg(any(test, feature = "test"))]
 pub mod proptest {
     use super::*;
@@ -374,11 +470,45 @@ mod tests {
     use assert_matches::assert_matches;
     use pretty_assertions::assert_eq;
 
+    #[test]
+    fn test_set_difference() {
+        let paths: Vec<StorePath> = ["a", "b", "c", "d"]
+            .iter()
+            .map(|seed| StorePath::test_from_seed(seed))
+            .collect();
+        let all: StorePathSet = paths.iter().cloned().collect();
+        let some: StorePathSet = [paths[1].clone(), paths[3].clone()].into_iter().collect();
+        let expected: StorePathSet = [paths[0].clone(), paths[2].clone()].into_iter().collect();
+
+        let mut out = StorePathSet::new();
+        all.difference_into(&some, &mut out);
+        assert_eq!(out, expected);
+        let mut out: StorePathSet = [paths[3].clone()].into_iter().collect();
+        all.difference_into(&some, &mut out);
+        assert_eq!(out.len(), 3);
+
+        let mut missing = all.clone();
+        missing.retain_missing(&some);
+        assert_eq!(missing, expected);
+        let mut missing = some.clone();
+        missing.retain_missing(&all);
+        assert!(missing.is_empty());
+    }
+
+    #[test]
+    fn test_from_seed() {
+        let p = StorePath::test_from_seed("foo");
+        assert_eq!(p, StorePath::test_from_seed("foo"));
+        assert_ne!(p.hash, StorePath::test_from_seed("bar").hash);
+        assert_eq!(p.name.name(), "foo");
+        assert_eq!(StorePath::new_from_base_name(&p.to_string()).unwrap(), p);
+    }
+
     #[test]
     fn test_parse() {
         let s = "7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-konsole-18.12.3";
         let p = StorePath::new_from_base_name(&s).unwrap();
-        assert_eq!(p.name.0, "konsole-18.12.3");
+        assert_eq!(&*p.name.0, "konsole-18.12.3");
         assert_eq!(p.name.name(), "konsole-18.12.3");
         assert_eq!(p.name.as_ref(), "konsole-18.12.3");
         assert_eq!(&*p.name, "konsole-18.12.3");
@@ -403,7 +533,7 @@ mod tests {
     fn test_parse2() {
         let s = "7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-konsole-18.12.3.drv".to_owned();
         let p = StorePath::try_from(s).unwrap();
-        assert_eq!(p.name.0, "konsole-18.12.3.drv");
+        assert_eq!(&*p.name.0, "konsole-18.12.3.drv");
         assert_eq!(
             format!("{}", p),
             "7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-konsole-18.12.3.drv"
@@ -485,13 +615,42 @@ mod tests {
         let s = "7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-foo bar";
         assert_matches!(
             StorePath::new_from_base_name(&s),
-            Err(ParseStorePathError::BadStorePathName(_))
+            Err(ParseStorePathError::BadStorePathName(_, 3, ' '))
         );
 
         let s = "7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-kónsole";
         assert_matches!(
             StorePath::new_from_base_name(&s),
-            Err(ParseStorePathError::BadStorePathName(_))
+            Err(ParseStorePathError::BadStorePathName(_, 1, 'ó'))
+        );
+
+        let s = "7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-.hidden";
+        assert_eq!(
+            StorePath::new_from_base_name(&s).unwrap_err().to_string(),
+            "store path name '.hidden' contains forbidden character '.' at offset 0"
+        );
+    }
+
+    #[test]
+    fn test_sanitize_name() {
+        assert_eq!(
+            StorePathName::sanitize("foo bar.tar.gz").unwrap().name(),
+            "foo_bar.tar.gz"
+        );
+        assert_eq!(
+            StorePathName::sanitize(".kónsole").unwrap().name(),
+            "_k_nsole"
+        );
+        assert_eq!(
+            StorePathName::sanitize(&"x".repeat(300))
+                .unwrap()
+                .name()
+                .len(),
+            211
+        );
+        assert_matches!(
+            StorePathName::sanitize(""),
+            Err(ParseStorePathError::StorePathNameEmpty)
         );
     }
 
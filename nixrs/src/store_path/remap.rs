@@ -0,0 +1,158 @@
+use std::collections::BTreeMap;
+
+use thiserror::Error;
+
+use super::{ParseStorePathError, StoreDir, StorePath, StorePathSet};
+use crate::archive::Rewrites;
+use crate::path_info::ValidPathInfo;
+
+#[derive(Error, Debug, PartialEq, Eq, Clone)]
+pub enum RemapStoreDirError {
+    #[error("cannot remap store dir '{0}' to '{1}' because they differ in length")]
+    LengthMismatch(String, String),
+    #[error("cannot remap '{0}' to '{1}' because their names differ")]
+    NameMismatch(String, String),
+}
+
+/// Translates store paths from one store dir to another.
+///
+/// The hash of a store path depends on the store dir so most paths get a
+/// new hash in the other store dir. Paths that aren't mapped explicitly
+/// with [`insert`](StoreDirRemap::insert) keep their hash, which is only
+/// right for paths that are never looked up by hash, e.g. when making a
+/// relocatable copy of a closure.
+///
+/// References in NAR contents are rewritten in place with [`Rewrites`], so
+/// both store dirs must have the same length.
+#[derive(Debug, Clone)]
+pub struct StoreDirRemap {
+    from: StoreDir,
+    to: StoreDir,
+    paths: BTreeMap<StorePath, StorePath>,
+}
+
+impl StoreDirRemap {
+    pub fn new(from: StoreDir, to: StoreDir) -> Result<StoreDirRemap, RemapStoreDirError> {
+        if from.to_str().len() != to.to_str().len() {
+            return Err(RemapStoreDirError::LengthMismatch(
+                from.to_string(),
+                to.to_string(),
+            ));
+        }
+        Ok(StoreDirRemap {
+            from,
+            to,
+            paths: BTreeMap::new(),
+        })
+    }
+
+    pub fn from_dir(&self) -> &StoreDir {
+        &self.from
+    }
+
+    pub fn to_dir(&self) -> &StoreDir {
+        &self.to
+    }
+
+    /// Map `from` in the source store dir to `to` in the destination store
+    /// dir.
+    pub fn insert(&mut self, from: StorePath, to: StorePath) -> Result<(), RemapStoreDirError> {
+        if from.name != to.name {
+            return Err(RemapStoreDirError::NameMismatch(
+                self.from.print_path(&from),
+                self.to.print_path(&to),
+            ));
+        }
+        self.paths.insert(from, to);
+        Ok(())
+    }
+
+    pub fn map_path(&self, path: &StorePath) -> StorePath {
+        self.paths.get(path).unwrap_or(path).clone()
+    }
+
+    /// The rewrites that turn references to `paths` in the source store
+    /// dir into references in the destination store dir.
+    pub fn rewrites(&self, paths: &StorePathSet) -> Rewrites {
+        paths
+            .iter()
+            .map(|path| {
+                let from = format!("{}/{}", self.from, path.hash);
+                let to = format!("{}/{}", self.to, self.map_path(path).hash);
+                (from, to)
+            })
+            .filter(|(from, to)| from != to)
+            .collect()
+    }
+
+    /// Translate `info` to the destination store dir.
+    ///
+    /// Content addressed paths without references get a new path computed
+    /// in the destination store dir, which is remembered for later paths
+    /// referring to them. Other content addresses are dropped because
+    /// rewriting references changes the contents. Signatures are dropped as
+    /// well.
+    ///
+    /// The NAR hash is left untouched and has to be updated by the caller
+    /// after the NAR has been rewritten with [`rewrites`](StoreDirRemap::rewrites).
+    pub fn remap_info(
+        &mut self,
+        info: &ValidPathInfo,
+    ) -> Result<ValidPathInfo, ParseStorePathError> {
+        let mut ret = info.clone();
+        if info.ca.is_some() && info.references.is_empty() {
+            let path = self.to.make_fixed_output_path_from_ca(
+                info.path.name.name(),
+                &info.content_address_with_references().unwrap(),
+            )?;
+            self.paths.insert(info.path.clone(), path);
+        } else {
+            ret.ca = None;
+        }
+        ret.path = self.map_path(&info.path);
+        ret.references = info
+            .references
+            .iter()
+            .map(|path| self.map_path(path))
+            .collect();
+        ret.deriver = info.deriver.as_ref().map(|path| self.map_path(path));
+        ret.sigs.clear();
+        ret.ultimate = false;
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_length_mismatch() {
+        let res = StoreDirRemap::new(
+            StoreDir::new("/nix/store").unwrap(),
+            StoreDir::new("/home/user/.local/nix/store").unwrap(),
+        );
+        assert!(matches!(res, Err(RemapStoreDirError::LengthMismatch(_, _))));
+    }
+
+    #[test]
+    fn test_rewrites_mapped_references() {
+        let mut remap = StoreDirRemap::new(
+            StoreDir::new("/nix/store").unwrap(),
+            StoreDir::new("/gnu/store").unwrap(),
+        )
+        .unwrap();
+        let old = StorePath::new_from_base_name("7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-konsole-18.12.3")
+            .unwrap();
+        let new = StorePath::new_from_base_name("ldhh7c134ap5swsm86rqnc0i7cinqvrc-konsole-18.12.3")
+            .unwrap();
+        remap.insert(old.clone(), new).unwrap();
+
+        let mut nar = b"/nix/store/7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-konsole-18.12.3/bin".to_vec();
+        remap.rewrites(&[old].into_iter().collect()).apply(&mut nar);
+        assert_eq!(
+            nar,
+            b"/gnu/store/ldhh7c134ap5swsm86rqnc0i7cinqvrc-konsole-18.12.3/bin"
+        );
+    }
+}
@@ -117,14 +117,36 @@ impl StoreDir {
         path_type
     }
 
+    /// The string whose SHA-256 is compressed into the hash of a store
+    /// path, i.e. `<type>:<algo>:<base16 hash>:<store dir>:<name>`.
+    ///
+    /// `path_type` is `source`, `text` or `output:<id>`, optionally
+    /// followed by the references of the path.
+    ///
+    /// ```
+    /// # use nixrs::store_path::StoreDir;
+    /// let store = StoreDir::new("/nix/store").unwrap();
+    /// let hash = "sha256:248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1".parse().unwrap();
+    /// assert_eq!(
+    ///     "source:sha256:248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1:/nix/store:konsole-18.12.3",
+    ///     store.fingerprint("source", &hash, "konsole-18.12.3"),
+    /// );
+    /// ```
+    pub fn fingerprint(&self, path_type: &str, hash: &hash::Hash, name: &str) -> String {
+        self.fingerprint_str(path_type, &format!("{:x}", hash), name)
+    }
+
+    fn fingerprint_str(&self, path_type: &str, hash: &str, name: &str) -> String {
+        format!("{}:{}:{}:{}", path_type, hash, self, name)
+    }
+
     pub fn make_store_path_str(
         &self,
         path_type: &str,
         hash: &str,
         name: &str,
     ) -> Result<StorePath, ParseStorePathError> {
-        let s = format!("{}:{}:{}:{}", path_type, hash, self, name);
-        StorePath::from_hash(&hash::digest(hash::Algorithm::SHA256, s), name)
+        StorePath::from_fingerprint(&self.fingerprint_str(path_type, hash, name), name)
     }
 
     pub fn make_store_path(
@@ -394,7 +416,8 @@ impl<T: ?Sized + StoreDirProvider> StoreDirProvider for &mut T {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{hash, store_path::StorePathSet};
+    use crate::store_path::{compress_hash, StorePathSet, STORE_PATH_HASH_BYTES};
+    use crate::{base32, hash};
     use ::proptest::{arbitrary::any, prop_assert_eq, proptest};
     use pretty_assertions::assert_eq;
 
@@ -483,6 +506,32 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_compress_hash() {
+        let d = hash::digest(hash::Algorithm::SHA256, "source:sha256:248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1:/nix/store:konsole-18.12.3");
+        let compressed: [u8; STORE_PATH_HASH_BYTES] = compress_hash(d.as_ref());
+        assert_eq!(
+            base32::encode(&compressed),
+            "1w01xxn8f7s9s4n65ry6rwd7x9awf04s"
+        );
+        assert_eq!(compress_hash::<4>(&[1, 2, 3, 4, 5, 6]), [4, 4, 3, 4]);
+    }
+
+    #[test]
+    fn test_store_dir_fingerprint() {
+        let store_dir = StoreDir::new("/gnu/store").unwrap();
+        let hash = hash::digest(hash::Algorithm::SHA256, "abc");
+        let fingerprint = store_dir.fingerprint("text", &hash, "foo.txt");
+        assert_eq!(
+            fingerprint,
+            "text:sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad:/gnu/store:foo.txt"
+        );
+        assert_eq!(
+            StorePath::from_fingerprint(&fingerprint, "foo.txt").unwrap(),
+            store_dir.make_store_path("text", hash, "foo.txt").unwrap()
+        );
+    }
+
     #[test]
     fn test_store_dir_make_fixed_output_path() {
         let store_dir = StoreDir::new("/nix/store").unwrap();